@@ -18,6 +18,7 @@ if which systemctl &> /dev/null; then
     # the user might've disabled or stopped the service themselves already
     systemctl stop mullvad-daemon.service || true
     systemctl disable mullvad-daemon.service || true
+    systemctl disable mullvad-early-boot-blocking.service || true
 elif /sbin/init --version | grep upstart &> /dev/null; then
     stop mullvad-daemon
     rm -f /etc/init/mullvad-daemon.conf
@@ -25,5 +26,6 @@ fi
 
 pkill -x "mullvad-gui" || true
 
+/opt/Mullvad\ VPN/resources/mullvad-setup disable-lockdown || echo "Failed to disable lockdown mode"
 /opt/Mullvad\ VPN/resources/mullvad-setup reset-firewall || echo "Failed to reset firewall"
 /opt/Mullvad\ VPN/resources/mullvad-setup remove-device || echo "Failed to remove device from account"
@@ -3,4 +3,5 @@
 # during an upgrade on Fedora.
 set -eu
 systemctl enable "/opt/Mullvad VPN/resources/mullvad-daemon.service" || true
+systemctl enable "/opt/Mullvad VPN/resources/mullvad-early-boot-blocking.service" || true
 systemctl start mullvad-daemon.service || true
@@ -1214,6 +1214,11 @@ function convertFromDaemonEvent(data: grpcTypes.DaemonEvent): DaemonEvent {
     return { appVersionInfo: versionInfo.toObject() };
   }
 
+  const dnsWarning = data.getDnsWarning();
+  if (dnsWarning !== undefined) {
+    return { dnsWarning: dnsWarning.toObject() };
+  }
+
   // Handle unknown daemon events
   const keys = Object.entries(data.toObject())
     .filter(([, value]) => value !== undefined)
@@ -839,6 +839,13 @@ class ApplicationMain {
               daemonEvent.deviceRemoval,
             );
           }
+        } else if ('dnsWarning' in daemonEvent) {
+          const { ignoredBy, fallback, details } = daemonEvent.dnsWarning;
+          log.warn(
+            `${ignoredBy} did not apply the DNS settings, ${
+              fallback ? `using ${fallback} instead` : 'and no other DNS manager did either'
+            }: ${details}`,
+          );
         }
       },
       (error: Error) => {
@@ -121,7 +121,14 @@ export type DaemonEvent =
   | { relayList: IRelayList }
   | { appVersionInfo: IAppVersionInfo }
   | { device: DeviceEvent }
-  | { deviceRemoval: Array<IDevice> };
+  | { deviceRemoval: Array<IDevice> }
+  | { dnsWarning: IDnsWarning };
+
+export interface IDnsWarning {
+  ignoredBy: string;
+  fallback: string;
+  details: string;
+}
 
 export interface ITunnelStateRelayInfo {
   endpoint: ITunnelEndpoint;
@@ -163,6 +163,7 @@ const config = {
       { from: distAssets('binaries/x86_64-unknown-linux-gnu/openvpn'), to: '.' },
       { from: distAssets('linux/mullvad-daemon.conf'), to: '.' },
       { from: distAssets('linux/mullvad-daemon.service'), to: '.' },
+      { from: distAssets('linux/mullvad-early-boot-blocking.service'), to: '.' },
     ],
   },
 
@@ -178,6 +179,8 @@ const config = {
       '--config-files',
       '/opt/Mullvad VPN/resources/mullvad-daemon.service',
       '--config-files',
+      '/opt/Mullvad VPN/resources/mullvad-early-boot-blocking.service',
+      '--config-files',
       '/opt/Mullvad VPN/resources/mullvad-daemon.conf',
       distAssets('mullvad') + '=/usr/bin/',
       distAssets('mullvad-exclude') + '=/usr/bin/',
@@ -203,6 +206,8 @@ const config = {
       '--config-files',
       '/opt/Mullvad VPN/resources/mullvad-daemon.service',
       '--config-files',
+      '/opt/Mullvad VPN/resources/mullvad-early-boot-blocking.service',
+      '--config-files',
       '/opt/Mullvad VPN/resources/mullvad-daemon.conf',
       distAssets('mullvad') + '=/usr/bin/',
       distAssets('mullvad-exclude') + '=/usr/bin/',
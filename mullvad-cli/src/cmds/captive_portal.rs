@@ -0,0 +1,80 @@
+use crate::{new_rpc_client, Command, Result};
+use mullvad_management_interface::types::{self, captive_portal_status::Detection};
+
+pub struct CaptivePortal;
+
+#[mullvad_management_interface::async_trait]
+impl Command for CaptivePortal {
+    fn name(&self) -> &'static str {
+        "captive-portal"
+    }
+
+    fn clap_subcommand(&self) -> clap::App<'static> {
+        clap::App::new(self.name())
+            .about("Log in to networks that require it before allowing internet access")
+            .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(
+                clap::App::new("unlock")
+                    .about(
+                        "Temporarily allow DNS, HTTP and HTTPS to the default gateways and any \
+                         captive portal found, while all other traffic is blocked",
+                    )
+                    .arg(
+                        clap::Arg::new("duration")
+                            .help(
+                                "Number of seconds to allow the traffic for. Defaults to 300, \
+                                 and is at most 900",
+                            )
+                            .long("duration")
+                            .takes_value(true),
+                    )
+                    .arg(
+                        clap::Arg::new("probe-url")
+                            .help(
+                                "http:// URL to request to detect the portal. It must respond \
+                                 with 204 No Content or with the body \"success\" when there is \
+                                 no portal",
+                            )
+                            .long("probe-url")
+                            .takes_value(true),
+                    ),
+            )
+    }
+
+    async fn run(&self, matches: &clap::ArgMatches) -> Result<()> {
+        match matches.subcommand() {
+            Some(("unlock", matches)) => {
+                let duration = if matches.is_present("duration") {
+                    matches.value_of_t_or_exit("duration")
+                } else {
+                    0
+                };
+                let probe_url = matches.value_of("probe-url").unwrap_or("").to_owned();
+                let status = new_rpc_client()
+                    .await?
+                    .unlock_captive_portal(types::CaptivePortalUnlockRequest {
+                        duration,
+                        probe_url,
+                    })
+                    .await?
+                    .into_inner();
+
+                match Detection::from_i32(status.detection) {
+                    Some(Detection::Portal) if !status.location.is_empty() => {
+                        println!("Captive portal found. Log in at {}", status.location)
+                    }
+                    Some(Detection::Portal) => println!("Captive portal found"),
+                    Some(Detection::NoPortal) => println!("No captive portal found"),
+                    _ => println!("Failed to detect captive portal"),
+                }
+                println!(
+                    "Allowing HTTP and HTTPS to {} for {} seconds",
+                    status.hosts.join(", "),
+                    status.duration
+                );
+                Ok(())
+            }
+            _ => unreachable!("unhandled command"),
+        }
+    }
+}
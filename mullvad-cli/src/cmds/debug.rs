@@ -0,0 +1,182 @@
+use crate::{new_rpc_client, Command, Error, Result};
+use mullvad_management_interface::types::{
+    self, firewall_rules_request::Policy, DnsLeakTestRequest, FirewallRulesRequest,
+};
+
+pub struct Debug;
+
+#[mullvad_management_interface::async_trait]
+impl Command for Debug {
+    fn name(&self) -> &'static str {
+        "debug"
+    }
+
+    fn clap_subcommand(&self) -> clap::App<'static> {
+        clap::App::new(self.name())
+            .about("Inspect the internal state of the daemon")
+            .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(
+                clap::App::new("firewall")
+                    .about(
+                        "Display the firewall rules for the active policy, or for the given \
+                         policy without applying it",
+                    )
+                    .arg(
+                        clap::Arg::new("policy")
+                            .long("policy")
+                            .takes_value(true)
+                            .possible_values(&["connected", "blocked"]),
+                    ),
+            )
+            .subcommand(
+                clap::App::new("blocked").about(
+                    "Display the destinations that the firewall recently blocked traffic to",
+                ),
+            )
+            .subcommand(
+                clap::App::new("dns-leak-test")
+                    .about(
+                        "Check that DNS queries are answered by the expected resolvers while \
+                         connected",
+                    )
+                    .arg(
+                        clap::Arg::new("domain")
+                            .long("domain")
+                            .takes_value(true)
+                            .help(
+                                "Domain to query. It should resolve to the address of the \
+                                 resolver that looks it up",
+                            ),
+                    ),
+            )
+    }
+
+    async fn run(&self, matches: &clap::ArgMatches) -> Result<()> {
+        if let Some(firewall_matches) = matches.subcommand_matches("firewall") {
+            let policy = match firewall_matches.value_of("policy") {
+                Some("connected") => Policy::Connected,
+                Some("blocked") => Policy::Blocked,
+                _ => Policy::Active,
+            };
+            Self::firewall(policy).await
+        } else if matches.subcommand_matches("blocked").is_some() {
+            Self::blocked().await
+        } else if let Some(leak_test_matches) = matches.subcommand_matches("dns-leak-test") {
+            Self::dns_leak_test(leak_test_matches.value_of("domain").unwrap_or_default()).await
+        } else {
+            unreachable!("No debug command given");
+        }
+    }
+}
+
+impl Debug {
+    async fn firewall(policy: Policy) -> Result<()> {
+        let mut rpc = new_rpc_client().await?;
+        let rules = rpc
+            .get_firewall_rules(FirewallRulesRequest {
+                policy: policy as i32,
+            })
+            .await;
+        let rules = match rules {
+            Ok(rules) => rules.into_inner(),
+            Err(status) if status.code() == mullvad_management_interface::Code::NotFound => {
+                return Err(Error::CommandFailed(match policy {
+                    Policy::Connected => "The tunnel is not connected",
+                    _ => "No firewall policy is applied",
+                }));
+            }
+            Err(status) => {
+                return Err(Error::RpcFailedExt(
+                    "Failed to render firewall rules",
+                    status,
+                ))
+            }
+        };
+
+        println!("Policy: {}", rules.policy);
+        println!("Rules:");
+        for rule in &rules.rules {
+            println!("\t{}", rule);
+        }
+        if !rules.nftables.is_empty() {
+            println!();
+            print!("{}", rules.nftables);
+        }
+        Ok(())
+    }
+
+    async fn blocked() -> Result<()> {
+        let connections = new_rpc_client()
+            .await?
+            .get_blocked_connections(())
+            .await?
+            .into_inner()
+            .connections;
+        if connections.is_empty() {
+            println!("No blocked traffic has been logged");
+            return Ok(());
+        }
+
+        println!("Recently blocked traffic, most recent first:");
+        for connection in &connections {
+            println!("\t{}", format_blocked_connection(connection));
+        }
+        Ok(())
+    }
+
+    async fn dns_leak_test(test_domain: &str) -> Result<()> {
+        let mut rpc = new_rpc_client().await?;
+        let report = rpc
+            .run_dns_leak_test(DnsLeakTestRequest {
+                test_domain: test_domain.to_owned(),
+            })
+            .await;
+        let report = match report {
+            Ok(report) => report.into_inner(),
+            Err(status) if status.code() == mullvad_management_interface::Code::NotFound => {
+                return Err(Error::CommandFailed("The tunnel is not connected"));
+            }
+            Err(status) => return Err(Error::RpcFailedExt("Failed to test DNS", status)),
+        };
+
+        println!("Test domain: {}", report.test_domain);
+        for check in &report.checks {
+            let result = if check.passed { "PASS" } else { "FAIL" };
+            println!("[{}] {}: {}", result, check.name, check.details);
+        }
+        if report.passed {
+            println!("No DNS leaks were detected");
+            Ok(())
+        } else {
+            Err(Error::CommandFailed("DNS leak test failed"))
+        }
+    }
+}
+
+fn format_blocked_connection(connection: &types::BlockedConnection) -> String {
+    let protocol = connection
+        .protocol
+        .as_ref()
+        .and_then(|constraint| types::TransportProtocol::from_i32(constraint.protocol));
+    let mut description = match protocol {
+        Some(types::TransportProtocol::Tcp) => format!("TCP to {}", connection.destination),
+        Some(types::TransportProtocol::Udp) => format!("UDP to {}", connection.destination),
+        _ => connection.destination.clone(),
+    };
+    if protocol.is_some() {
+        description.push_str(&format!(" port {}", connection.port));
+    }
+    if !connection.process.is_empty() {
+        description.push_str(&format!(" from {}", connection.process));
+    }
+    if let Some(uid) = connection.uid {
+        description.push_str(&format!(" (uid {})", uid));
+    }
+    description.push_str(&format!(", {} packet(s)", connection.packets));
+    if let Some(last_blocked) = &connection.last_blocked {
+        let ndt = chrono::NaiveDateTime::from_timestamp(last_blocked.seconds, 0);
+        let utc = chrono::DateTime::<chrono::Utc>::from_utc(ndt, chrono::Utc);
+        description.push_str(&format!(", last at {}", utc.with_timezone(&chrono::Local)));
+    }
+    description
+}
@@ -1,6 +1,7 @@
 use crate::{format, new_rpc_client, state, Command, Error, Result};
 use futures::StreamExt;
-use mullvad_management_interface::types::tunnel_state::State::Disconnected;
+use mullvad_management_interface::types::{self, tunnel_state::State::Disconnected};
+use std::time::Duration;
 
 pub struct Disconnect;
 
@@ -19,6 +20,13 @@ impl Command for Disconnect {
                     .short('w')
                     .help("Wait until disconnected before exiting"),
             )
+            .arg(
+                clap::Arg::new("for")
+                    .long("for")
+                    .takes_value(true)
+                    .value_name("DURATION")
+                    .help("Connect again automatically after this long, e.g. 30s, 10m or 1h30m"),
+            )
     }
 
     async fn run(&self, matches: &clap::ArgMatches) -> Result<()> {
@@ -30,7 +38,22 @@ impl Command for Disconnect {
             None
         };
 
-        if rpc.disconnect_tunnel(()).await?.into_inner() {
+        let disconnect_issued = if let Some(duration) = matches.value_of("for") {
+            let duration = parse_duration(duration).ok_or(Error::InvalidCommand(
+                "Invalid duration. Use e.g. 30s, 10m or 1h30m",
+            ))?;
+            rpc.pause_tunnel(types::Duration::from(duration)).await?;
+            println!(
+                "Disconnecting for {}",
+                format::format_duration(duration.as_secs())
+            );
+            let state = rpc.get_tunnel_state(()).await?.into_inner();
+            !matches!(state.state, Some(Disconnected(_)))
+        } else {
+            rpc.disconnect_tunnel(()).await?.into_inner()
+        };
+
+        if disconnect_issued {
             if let Some(mut receiver) = receiver_option {
                 while let Some(state) = receiver.next().await {
                     let state = state?;
@@ -47,3 +70,28 @@ impl Command for Disconnect {
         Ok(())
     }
 }
+
+/// Parses durations such as `90s`, `10m` and `1h30m`.
+fn parse_duration(value: &str) -> Option<Duration> {
+    let mut seconds = 0u64;
+    let mut number = String::new();
+    for c in value.trim().chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let unit = match c {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            _ => return None,
+        };
+        seconds = seconds.checked_add(number.parse::<u64>().ok()?.checked_mul(unit)?)?;
+        number.clear();
+    }
+    if !number.is_empty() || seconds == 0 {
+        return None;
+    }
+    Some(Duration::from_secs(seconds))
+}
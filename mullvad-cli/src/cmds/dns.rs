@@ -1,7 +1,13 @@
+#[cfg(target_os = "linux")]
+use crate::Error;
 use crate::{new_rpc_client, Command, Result};
 use mullvad_management_interface::types;
 use mullvad_types::settings::{DnsOptions, DnsState};
+#[cfg(target_os = "linux")]
+use std::path::PathBuf;
 use std::{convert::TryInto, net::IpAddr};
+#[cfg(target_os = "linux")]
+use talpid_types::net::dns::{DnsBlocklist, EncryptedDnsServer, SplitDnsRule};
 
 pub struct Dns;
 
@@ -12,59 +18,75 @@ impl Command for Dns {
     }
 
     fn clap_subcommand(&self) -> clap::App<'static> {
-        clap::App::new(self.name())
-            .about("Configure DNS servers to use when connected")
+        #[cfg_attr(not(target_os = "linux"), allow(unused_mut))]
+        let mut set_subcommand = clap::App::new("set")
+            .about("Set DNS servers to use")
             .setting(clap::AppSettings::SubcommandRequiredElseHelp)
-            .subcommand(clap::App::new("get").about("Display the current DNS settings"))
             .subcommand(
-                clap::App::new("set")
-                    .about("Set DNS servers to use")
-                    .setting(clap::AppSettings::SubcommandRequiredElseHelp)
-                    .subcommand(
-                        clap::App::new("default")
-                            .about("Use default DNS servers")
-                            .arg(
-                                clap::Arg::new("block ads")
-                                    .long("block-ads")
-                                    .takes_value(false)
-                                    .help("Block domain names used for ads"),
-                            )
-                            .arg(
-                                clap::Arg::new("block trackers")
-                                    .long("block-trackers")
-                                    .takes_value(false)
-                                    .help("Block domain names used for tracking"),
-                            )
-                            .arg(
-                                clap::Arg::new("block malware")
-                                    .long("block-malware")
-                                    .takes_value(false)
-                                    .help("Block domains known to be used by malware"),
-                            )
-                            .arg(
-                                clap::Arg::new("block adult content")
-                                    .long("block-adult-content")
-                                    .takes_value(false)
-                                    .help("Block domains known to be used for adult content"),
-                            )
-                            .arg(
-                                clap::Arg::new("block gambling")
-                                    .long("block-gambling")
-                                    .takes_value(false)
-                                    .help("Block domains known to be used for gambling"),
-                            ),
+                clap::App::new("default")
+                    .about("Use default DNS servers")
+                    .arg(
+                        clap::Arg::new("block ads")
+                            .long("block-ads")
+                            .takes_value(false)
+                            .help("Block domain names used for ads"),
+                    )
+                    .arg(
+                        clap::Arg::new("block trackers")
+                            .long("block-trackers")
+                            .takes_value(false)
+                            .help("Block domain names used for tracking"),
                     )
-                    .subcommand(
-                        clap::App::new("custom")
-                            .about("Set a list of custom DNS servers")
-                            .arg(
-                                clap::Arg::new("servers")
-                                    .multiple_occurrences(true)
-                                    .help("One or more IP addresses pointing to DNS resolvers.")
-                                    .required(true),
-                            ),
+                    .arg(
+                        clap::Arg::new("block malware")
+                            .long("block-malware")
+                            .takes_value(false)
+                            .help("Block domains known to be used by malware"),
+                    )
+                    .arg(
+                        clap::Arg::new("block adult content")
+                            .long("block-adult-content")
+                            .takes_value(false)
+                            .help("Block domains known to be used for adult content"),
+                    )
+                    .arg(
+                        clap::Arg::new("block gambling")
+                            .long("block-gambling")
+                            .takes_value(false)
+                            .help("Block domains known to be used for gambling"),
                     ),
             )
+            .subcommand(
+                clap::App::new("custom")
+                    .about("Set a list of custom DNS servers")
+                    .arg(
+                        clap::Arg::new("servers")
+                            .multiple_occurrences(true)
+                            .help("One or more IP addresses pointing to DNS resolvers.")
+                            .required(true),
+                    ),
+            );
+
+        #[cfg(target_os = "linux")]
+        {
+            set_subcommand = set_subcommand.subcommand(create_encrypted_subcommand());
+        }
+
+        #[cfg_attr(not(target_os = "linux"), allow(unused_mut))]
+        let mut subcommand = clap::App::new(self.name())
+            .about("Configure DNS servers to use when connected")
+            .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(clap::App::new("get").about("Display the current DNS settings"))
+            .subcommand(set_subcommand);
+
+        #[cfg(target_os = "linux")]
+        {
+            subcommand = subcommand
+                .subcommand(create_blocklist_subcommand())
+                .subcommand(create_split_subcommand());
+        }
+
+        subcommand
     }
 
     async fn run(&self, matches: &clap::ArgMatches) -> Result<()> {
@@ -90,9 +112,27 @@ impl Command for Dns {
                     };
                     self.set_custom(servers).await
                 }
+                #[cfg(target_os = "linux")]
+                Some(("encrypted", matches)) => {
+                    let server = matches
+                        .value_of_t::<EncryptedDnsServer>("server")
+                        .unwrap_or_else(|e| e.exit());
+                    let addresses = match matches.values_of_t::<IpAddr>("address") {
+                        Ok(addresses) => addresses,
+                        Err(e) => match e.kind {
+                            clap::ErrorKind::ArgumentNotFound => vec![],
+                            _ => e.exit(),
+                        },
+                    };
+                    Self::set_encrypted(server, addresses).await
+                }
                 _ => unreachable!("No custom-dns server command given"),
             },
             Some(("get", _)) => self.get().await,
+            #[cfg(target_os = "linux")]
+            Some(("blocklist", matches)) => Self::handle_blocklist_cmd(matches).await,
+            #[cfg(target_os = "linux")]
+            Some(("split", matches)) => Self::handle_split_cmd(matches).await,
             _ => unreachable!("No custom-dns command given"),
         }
     }
@@ -136,6 +176,7 @@ impl Dns {
                     .into_iter()
                     .map(|a| a.to_string())
                     .collect(),
+                encrypted_servers: vec![],
             }),
             ..settings.tunnel_options.unwrap().dns_options.unwrap()
         })
@@ -174,9 +215,329 @@ impl Dns {
                 for server in &options.custom_options.addresses {
                     println!("{}", server);
                 }
+                #[cfg(target_os = "linux")]
+                for server in &options.custom_options.encrypted_servers {
+                    println!("{}", format_encrypted_server(server));
+                }
+            }
+        }
+
+        #[cfg(target_os = "linux")]
+        if !options.blocklists.is_empty() {
+            println!("Blocklists:");
+            for blocklist in &options.blocklists {
+                println!("{}", format_blocklist(blocklist));
+            }
+        }
+
+        #[cfg(target_os = "linux")]
+        if !options.split_dns.is_empty() {
+            println!("Split DNS:");
+            for rule in &options.split_dns {
+                println!("{}", format_split_dns_rule(rule));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn create_encrypted_subcommand() -> clap::App<'static> {
+    clap::App::new("encrypted")
+        .about("Use a DNS-over-HTTPS or DNS-over-TLS server")
+        .arg(
+            clap::Arg::new("server")
+                .help(
+                    "A DNS-over-HTTPS URL, such as https://dns.example/dns-query, or a \
+                     DNS-over-TLS server, such as tls://dns.example",
+                )
+                .required(true),
+        )
+        .arg(
+            clap::Arg::new("address")
+                .long("address")
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .help(
+                    "IP address of the server. If not given, the hostname is looked up once \
+                     and the result is saved",
+                ),
+        )
+}
+
+#[cfg(target_os = "linux")]
+fn create_blocklist_subcommand() -> clap::App<'static> {
+    let path_arg = clap::Arg::new("path")
+        .help("Absolute path to the blocklist file")
+        .required(true);
+    clap::App::new("blocklist")
+        .about(
+            "Manage lists of domains that are not resolved while connected. Lists may be hosts \
+             files or contain a single domain per line",
+        )
+        .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(clap::App::new("list").about("Display the blocklists and their hit counters"))
+        .subcommand(
+            clap::App::new("add")
+                .about("Add a blocklist")
+                .arg(path_arg.clone()),
+        )
+        .subcommand(
+            clap::App::new("remove")
+                .about("Remove a blocklist")
+                .arg(path_arg.clone()),
+        )
+        .subcommand(
+            clap::App::new("enable")
+                .about("Enable a blocklist")
+                .arg(path_arg.clone()),
+        )
+        .subcommand(
+            clap::App::new("disable")
+                .about("Disable a blocklist without removing it")
+                .arg(path_arg),
+        )
+        .subcommand(clap::App::new("reload").about("Read all blocklists from disk again"))
+}
+
+#[cfg(target_os = "linux")]
+fn create_split_subcommand() -> clap::App<'static> {
+    let domain_arg = clap::Arg::new("domain")
+        .help("Domain to resolve using the given resolvers, including its subdomains")
+        .required(true);
+    clap::App::new("split")
+        .about(
+            "Manage domains that are resolved using other DNS servers than the tunnel DNS, \
+             such as a LAN or office resolver. These servers are reached outside the tunnel",
+        )
+        .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(clap::App::new("list").about("Display the split DNS rules"))
+        .subcommand(
+            clap::App::new("add")
+                .about("Resolve a domain using the given DNS servers")
+                .arg(domain_arg.clone())
+                .arg(
+                    clap::Arg::new("resolvers")
+                        .multiple_occurrences(true)
+                        .help("One or more IP addresses of DNS servers to use for the domain")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            clap::App::new("remove")
+                .about("Remove the rule for a domain")
+                .arg(domain_arg),
+        )
+}
+
+#[cfg(target_os = "linux")]
+impl Dns {
+    async fn set_encrypted(mut server: EncryptedDnsServer, addresses: Vec<IpAddr>) -> Result<()> {
+        server.addresses = if addresses.is_empty() {
+            lookup_server_addresses(&server)?
+        } else {
+            addresses
+        };
+
+        let mut rpc = new_rpc_client().await?;
+        let settings = rpc.get_settings(()).await?.into_inner();
+        rpc.set_dns_options(types::DnsOptions {
+            state: types::dns_options::DnsState::Custom as i32,
+            custom_options: Some(types::CustomDnsOptions {
+                addresses: vec![],
+                encrypted_servers: vec![types::EncryptedDnsServer::from(&server)],
+            }),
+            ..settings.tunnel_options.unwrap().dns_options.unwrap()
+        })
+        .await?;
+        println!("Updated DNS settings");
+        println!("Server: {}", format_encrypted_server(&server));
+        Ok(())
+    }
+
+    async fn handle_blocklist_cmd(matches: &clap::ArgMatches) -> Result<()> {
+        match matches.subcommand() {
+            Some(("list", _)) => Self::list_blocklists().await,
+            Some(("add", matches)) | Some(("enable", matches)) => {
+                let path = blocklist_path(matches)?;
+                Self::set_blocklist(DnsBlocklist {
+                    path,
+                    enabled: true,
+                })
+                .await
+            }
+            Some(("disable", matches)) => {
+                let path = blocklist_path(matches)?;
+                Self::set_blocklist(DnsBlocklist {
+                    path,
+                    enabled: false,
+                })
+                .await
+            }
+            Some(("remove", matches)) => {
+                let path = blocklist_path(matches)?;
+                new_rpc_client()
+                    .await?
+                    .remove_dns_blocklist(path.to_string_lossy().into_owned())
+                    .await?;
+                println!("Removed blocklist {}", path.display());
+                Ok(())
+            }
+            Some(("reload", _)) => {
+                new_rpc_client().await?.reload_dns_blocklists(()).await?;
+                println!("Reloaded DNS blocklists");
+                Ok(())
+            }
+            _ => unreachable!("unhandled command"),
+        }
+    }
+
+    async fn handle_split_cmd(matches: &clap::ArgMatches) -> Result<()> {
+        match matches.subcommand() {
+            Some(("list", _)) => {
+                let options: DnsOptions = new_rpc_client()
+                    .await?
+                    .get_settings(())
+                    .await?
+                    .into_inner()
+                    .tunnel_options
+                    .unwrap()
+                    .dns_options
+                    .unwrap()
+                    .try_into()
+                    .unwrap();
+                if options.split_dns.is_empty() {
+                    println!("No split DNS rules");
+                }
+                for rule in &options.split_dns {
+                    println!("{}", format_split_dns_rule(rule));
+                }
+                Ok(())
+            }
+            Some(("add", matches)) => {
+                let resolvers = matches
+                    .values_of_t::<IpAddr>("resolvers")
+                    .unwrap_or_else(|e| e.exit());
+                let rule = SplitDnsRule::new(matches.value_of("domain").unwrap(), resolvers)
+                    .map_err(|_| Error::InvalidCommand("Invalid domain"))?;
+                new_rpc_client()
+                    .await?
+                    .set_split_dns_rule(types::SplitDnsRule::from(&rule))
+                    .await?;
+                println!("Updated rule {}", format_split_dns_rule(&rule));
+                Ok(())
             }
+            Some(("remove", matches)) => {
+                let domain = matches.value_of("domain").unwrap();
+                new_rpc_client()
+                    .await?
+                    .remove_split_dns_rule(domain.to_owned())
+                    .await?;
+                println!("Removed rule for {}", domain);
+                Ok(())
+            }
+            _ => unreachable!("unhandled command"),
         }
+    }
 
+    async fn set_blocklist(blocklist: DnsBlocklist) -> Result<()> {
+        new_rpc_client()
+            .await?
+            .set_dns_blocklist(types::DnsBlocklist::from(&blocklist))
+            .await?;
+        println!("Updated blocklist {}", format_blocklist(&blocklist));
         Ok(())
     }
+
+    async fn list_blocklists() -> Result<()> {
+        let stats = new_rpc_client()
+            .await?
+            .get_dns_blocklist_stats(())
+            .await?
+            .into_inner()
+            .blocklists;
+        if stats.is_empty() {
+            println!("No blocklists");
+        }
+        for list in stats {
+            let blocklist = DnsBlocklist {
+                path: PathBuf::from(&list.path),
+                enabled: list.enabled,
+            };
+            println!("{}", format_blocklist(&blocklist));
+            if list.error.is_empty() {
+                println!("    Domains: {}", list.domains);
+            } else {
+                println!("    Failed to load: {}", list.error);
+            }
+            println!("    Blocked queries: {}", list.hits);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn blocklist_path(matches: &clap::ArgMatches) -> Result<PathBuf> {
+    let path = PathBuf::from(matches.value_of("path").unwrap());
+    // The daemon requires absolute paths
+    if path.is_absolute() {
+        return Ok(path);
+    }
+    std::env::current_dir()
+        .map(|dir| dir.join(path))
+        .map_err(|_| Error::Other("Failed to resolve relative path"))
+}
+
+#[cfg(target_os = "linux")]
+fn format_blocklist(blocklist: &DnsBlocklist) -> String {
+    format!(
+        "{} ({})",
+        blocklist.path.display(),
+        if blocklist.enabled {
+            "enabled"
+        } else {
+            "disabled"
+        }
+    )
+}
+
+#[cfg(target_os = "linux")]
+fn format_split_dns_rule(rule: &SplitDnsRule) -> String {
+    let resolvers: Vec<String> = rule
+        .resolvers
+        .iter()
+        .map(|resolver| resolver.to_string())
+        .collect();
+    format!("{}: {}", rule.domain, resolvers.join(", "))
+}
+
+/// Resolves the hostname of the server using the system resolver. The daemon never looks up the
+/// addresses itself, since that would require sending a plain DNS query.
+#[cfg(target_os = "linux")]
+fn lookup_server_addresses(server: &EncryptedDnsServer) -> Result<Vec<IpAddr>> {
+    use std::net::ToSocketAddrs;
+
+    let mut addresses: Vec<IpAddr> = (server.hostname.as_str(), server.port)
+        .to_socket_addrs()
+        .map_err(|_| Error::CommandFailed("Failed to look up the address of the DNS server"))?
+        .map(|addr| addr.ip())
+        .collect();
+    addresses.dedup();
+    if addresses.is_empty() {
+        return Err(Error::CommandFailed(
+            "The hostname of the DNS server has no addresses",
+        ));
+    }
+    Ok(addresses)
+}
+
+#[cfg(target_os = "linux")]
+fn format_encrypted_server(server: &EncryptedDnsServer) -> String {
+    let addresses: Vec<String> = server
+        .addresses
+        .iter()
+        .map(|addr| addr.to_string())
+        .collect();
+    format!("{} ({})", server, addresses.join(", "))
 }
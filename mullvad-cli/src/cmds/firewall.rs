@@ -0,0 +1,149 @@
+use crate::{new_rpc_client, Command, Error, Result};
+use mullvad_management_interface::types;
+use std::convert::TryFrom;
+use talpid_types::net::{AllowedDirection, FirewallAllowRule, TransportProtocol};
+
+pub struct Firewall;
+
+#[mullvad_management_interface::async_trait]
+impl Command for Firewall {
+    fn name(&self) -> &'static str {
+        "firewall"
+    }
+
+    fn clap_subcommand(&self) -> clap::App<'static> {
+        clap::App::new(self.name())
+            .about("Manage exceptions to the firewall policy")
+            .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(create_allow_subcommand())
+    }
+
+    async fn run(&self, matches: &clap::ArgMatches) -> Result<()> {
+        match matches.subcommand() {
+            Some(("allow", allow_matches)) => Self::handle_allow_cmd(allow_matches).await,
+            _ => unreachable!("unhandled command"),
+        }
+    }
+}
+
+fn create_allow_subcommand() -> clap::App<'static> {
+    clap::App::new("allow")
+        .about(
+            "Manage traffic that is allowed in all tunnel states, including when the \
+             firewall blocks all other traffic",
+        )
+        .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(
+            clap::App::new("add")
+                .about("Allow traffic matching a rule")
+                .args(rule_args()),
+        )
+        .subcommand(
+            clap::App::new("delete")
+                .about("Remove a rule added with 'add'")
+                .args(rule_args()),
+        )
+        .subcommand(clap::App::new("clear").about("Remove all rules"))
+        .subcommand(clap::App::new("list").about("Display all rules"))
+}
+
+fn rule_args() -> Vec<clap::Arg<'static>> {
+    vec![
+        clap::Arg::new("network")
+            .help("The remote network, in CIDR notation. E.g. 192.0.2.0/24")
+            .required(true),
+        clap::Arg::new("protocol")
+            .help("Only match this transport protocol")
+            .long("protocol")
+            .takes_value(true)
+            .possible_values(&["udp", "tcp"]),
+        clap::Arg::new("port")
+            .help(
+                "Only match this port. This is the remote port for outbound traffic, and \
+                 the local port for inbound traffic",
+            )
+            .long("port")
+            .takes_value(true)
+            .requires("protocol"),
+        clap::Arg::new("inbound")
+            .help("Allow connections initiated by the remote network instead of by this device")
+            .long("inbound"),
+        clap::Arg::new("outside-tunnel")
+            .help("Route matching traffic outside the tunnel")
+            .long("outside-tunnel"),
+    ]
+}
+
+impl Firewall {
+    async fn handle_allow_cmd(matches: &clap::ArgMatches) -> Result<()> {
+        match matches.subcommand() {
+            Some(("add", matches)) => {
+                let rule = parse_rule(matches)?;
+                new_rpc_client()
+                    .await?
+                    .add_firewall_allow_rule(types::FirewallAllowRule::from(&rule))
+                    .await?;
+                println!("Allowed traffic {}", rule);
+                Ok(())
+            }
+            Some(("delete", matches)) => {
+                let rule = parse_rule(matches)?;
+                new_rpc_client()
+                    .await?
+                    .remove_firewall_allow_rule(types::FirewallAllowRule::from(&rule))
+                    .await?;
+                Ok(())
+            }
+            Some(("clear", _)) => {
+                new_rpc_client()
+                    .await?
+                    .clear_firewall_allow_rules(())
+                    .await?;
+                Ok(())
+            }
+            Some(("list", _)) => {
+                let settings = new_rpc_client().await?.get_settings(()).await?.into_inner();
+                println!("Allowed traffic:");
+                for rule in settings.firewall_allow_rules {
+                    match FirewallAllowRule::try_from(rule) {
+                        Ok(rule) => println!("    {}", rule),
+                        Err(_) => return Err(Error::Other("Received invalid firewall rule")),
+                    }
+                }
+                Ok(())
+            }
+            _ => unreachable!("unhandled command"),
+        }
+    }
+}
+
+fn parse_rule(matches: &clap::ArgMatches) -> Result<FirewallAllowRule> {
+    let network = matches
+        .value_of("network")
+        .unwrap()
+        .parse()
+        .map_err(|_| Error::InvalidCommand("invalid network"))?;
+    let protocol = match matches.value_of("protocol") {
+        Some("udp") => Some(TransportProtocol::Udp),
+        Some("tcp") => Some(TransportProtocol::Tcp),
+        _ => None,
+    };
+    let port = if matches.is_present("port") {
+        Some(matches.value_of_t_or_exit("port"))
+    } else {
+        None
+    };
+    let direction = if matches.is_present("inbound") {
+        AllowedDirection::Inbound
+    } else {
+        AllowedDirection::Outbound
+    };
+
+    Ok(FirewallAllowRule {
+        network,
+        protocol,
+        port,
+        direction,
+        outside_tunnel: matches.is_present("outside-tunnel"),
+    })
+}
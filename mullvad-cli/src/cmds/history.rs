@@ -0,0 +1,179 @@
+use crate::{new_rpc_client, Command, Result};
+use mullvad_management_interface::types::{ConnectionHistoryEntry, Timestamp, TunnelType};
+
+pub struct History;
+
+#[mullvad_management_interface::async_trait]
+impl Command for History {
+    fn name(&self) -> &'static str {
+        "history"
+    }
+
+    fn clap_subcommand(&self) -> clap::App<'static> {
+        clap::App::new(self.name())
+            .about("Display and manage the history of connections made")
+            .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(
+                clap::App::new("list")
+                    .about("Display all connections, oldest first")
+                    .arg(
+                        clap::Arg::new("json")
+                            .long("json")
+                            .help("Print the connections as JSON"),
+                    ),
+            )
+            .subcommand(clap::App::new("clear").about("Forget all connections"))
+            .subcommand(
+                clap::App::new("set")
+                    .about("Enable or disable the connection history. Disabling it clears it")
+                    .arg(
+                        clap::Arg::new("policy")
+                            .required(true)
+                            .possible_values(&["on", "off"]),
+                    ),
+            )
+            .subcommand(
+                clap::App::new("get").about("Display whether the connection history is enabled"),
+            )
+    }
+
+    async fn run(&self, matches: &clap::ArgMatches) -> Result<()> {
+        match matches.subcommand() {
+            Some(("list", matches)) => {
+                let entries = new_rpc_client()
+                    .await?
+                    .get_connection_history(())
+                    .await?
+                    .into_inner()
+                    .entries;
+                if matches.is_present("json") {
+                    let entries: Vec<_> = entries.iter().map(entry_to_json).collect();
+                    println!(
+                        "{}",
+                        serde_json::to_string_pretty(&entries).expect("failed to serialize JSON")
+                    );
+                } else {
+                    for entry in &entries {
+                        print_entry(entry);
+                    }
+                }
+                Ok(())
+            }
+            Some(("clear", _)) => {
+                new_rpc_client().await?.clear_connection_history(()).await?;
+                println!("Cleared the connection history");
+                Ok(())
+            }
+            Some(("set", matches)) => {
+                let enabled = matches.value_of("policy").expect("missing policy") == "on";
+                new_rpc_client()
+                    .await?
+                    .set_connection_history(enabled)
+                    .await?;
+                println!("Changed connection history setting");
+                Ok(())
+            }
+            Some(("get", _)) => {
+                let enabled = new_rpc_client()
+                    .await?
+                    .get_settings(())
+                    .await?
+                    .into_inner()
+                    .connection_history;
+                println!("Connection history: {}", if enabled { "on" } else { "off" });
+                Ok(())
+            }
+            _ => unreachable!("unhandled command"),
+        }
+    }
+}
+
+fn print_entry(entry: &ConnectionHistoryEntry) {
+    let started = entry
+        .started
+        .as_ref()
+        .map(format_timestamp)
+        .unwrap_or_default();
+    let ended = match &entry.ended {
+        Some(ended) => format_timestamp(ended),
+        None => "unknown".to_owned(),
+    };
+    let mut relays = vec![];
+    if !entry.bridge_hostname.is_empty() {
+        relays.push(format!("bridge {}", entry.bridge_hostname));
+    }
+    if !entry.obfuscator_hostname.is_empty() {
+        relays.push(format!("obfuscator {}", entry.obfuscator_hostname));
+    }
+    if !entry.entry_hostname.is_empty() {
+        relays.push(format!("entry {}", entry.entry_hostname));
+    }
+    if !entry.exit_hostname.is_empty() {
+        relays.push(entry.exit_hostname.clone());
+    }
+    if relays.is_empty() {
+        relays.push("unknown relay".to_owned());
+    }
+
+    println!("{} - {}", started, ended);
+    println!(
+        "    {} over {}",
+        relays.join(" -> "),
+        tunnel_type_name(entry.tunnel_type)
+    );
+    if let Some(traffic) = &entry.traffic {
+        println!(
+            "    {} bytes received, {} bytes sent",
+            traffic.rx_bytes, traffic.tx_bytes
+        );
+    }
+    if !entry.error_cause.is_empty() {
+        println!("    Ended with error: {}", entry.error_cause);
+    }
+}
+
+fn entry_to_json(entry: &ConnectionHistoryEntry) -> serde_json::Value {
+    let optional_string = |value: &str| {
+        if value.is_empty() {
+            serde_json::Value::Null
+        } else {
+            serde_json::Value::from(value)
+        }
+    };
+    serde_json::json!({
+        "started": entry.started.as_ref().map(format_rfc3339),
+        "ended": entry.ended.as_ref().map(format_rfc3339),
+        "tunnel_type": tunnel_type_name(entry.tunnel_type),
+        "exit_hostname": optional_string(&entry.exit_hostname),
+        "entry_hostname": optional_string(&entry.entry_hostname),
+        "bridge_hostname": optional_string(&entry.bridge_hostname),
+        "obfuscator_hostname": optional_string(&entry.obfuscator_hostname),
+        "rx_bytes": entry.traffic.as_ref().map(|traffic| traffic.rx_bytes),
+        "tx_bytes": entry.traffic.as_ref().map(|traffic| traffic.tx_bytes),
+        "error_cause": optional_string(&entry.error_cause),
+    })
+}
+
+fn tunnel_type_name(tunnel_type: i32) -> &'static str {
+    match TunnelType::from_i32(tunnel_type) {
+        Some(TunnelType::Wireguard) => "WireGuard",
+        Some(TunnelType::Openvpn) => "OpenVPN",
+        None => "unknown",
+    }
+}
+
+fn to_datetime(timestamp: &Timestamp) -> chrono::DateTime<chrono::Utc> {
+    let ndt = chrono::NaiveDateTime::from_timestamp(timestamp.seconds, timestamp.nanos as u32);
+    chrono::DateTime::<chrono::Utc>::from_utc(ndt, chrono::Utc)
+}
+
+fn format_timestamp(timestamp: &Timestamp) -> String {
+    to_datetime(timestamp)
+        .with_timezone(&chrono::Local)
+        .format("%Y-%m-%d %H:%M:%S")
+        .to_string()
+}
+
+fn format_rfc3339(timestamp: &Timestamp) -> String {
+    to_datetime(timestamp).to_rfc3339()
+}
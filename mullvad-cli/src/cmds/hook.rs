@@ -0,0 +1,145 @@
+use crate::{new_rpc_client, Command, Error, Result};
+use mullvad_management_interface::types::{self, hook::Event};
+use mullvad_types::hooks::{Hook, HookEvent};
+use std::{convert::TryFrom, path::Path};
+
+pub struct Hooks;
+
+#[mullvad_management_interface::async_trait]
+impl Command for Hooks {
+    fn name(&self) -> &'static str {
+        "hook"
+    }
+
+    fn clap_subcommand(&self) -> clap::App<'static> {
+        clap::App::new(self.name())
+            .about("Manage executables that are run when the tunnel state changes")
+            .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(
+                clap::App::new("add")
+                    .about(
+                        "Run an executable when the tunnel state changes. Must be run as \
+                         root. The executable and the directories containing it must be owned \
+                         by root and only be writable by root",
+                    )
+                    .arg(
+                        clap::Arg::new("path")
+                            .help("Absolute path to the executable")
+                            .required(true),
+                    )
+                    .arg(
+                        clap::Arg::new("args")
+                            .help("Arguments to pass to the executable")
+                            .multiple_values(true)
+                            .last(true),
+                    )
+                    .arg(
+                        clap::Arg::new("event")
+                            .help("Tunnel state to run the executable on. Defaults to every state")
+                            .long("event")
+                            .takes_value(true)
+                            .multiple_occurrences(true)
+                            .possible_values(&[
+                                "connecting",
+                                "connected",
+                                "disconnecting",
+                                "disconnected",
+                                "error",
+                            ]),
+                    )
+                    .arg(
+                        clap::Arg::new("timeout")
+                            .help(
+                                "Number of seconds after which the executable is killed. \
+                                 Defaults to 10, and is at most 300",
+                            )
+                            .long("timeout")
+                            .takes_value(true),
+                    ),
+            )
+            .subcommand(
+                clap::App::new("remove")
+                    .about("Remove all hooks that run the given executable")
+                    .arg(clap::Arg::new("path").required(true)),
+            )
+            .subcommand(clap::App::new("list").about("Display all hooks"))
+    }
+
+    async fn run(&self, matches: &clap::ArgMatches) -> Result<()> {
+        match matches.subcommand() {
+            Some(("add", matches)) => {
+                let path = matches.value_of("path").unwrap();
+                if !Path::new(path).is_absolute() {
+                    return Err(Error::InvalidCommand("the path must be absolute"));
+                }
+                let args = matches
+                    .values_of("args")
+                    .map(|args| args.map(str::to_owned).collect())
+                    .unwrap_or_default();
+                let events = matches
+                    .values_of("event")
+                    .map(|events| events.map(|event| i32::from(parse_event(event))).collect())
+                    .unwrap_or_default();
+                let timeout = if matches.is_present("timeout") {
+                    matches.value_of_t_or_exit("timeout")
+                } else {
+                    0
+                };
+                new_rpc_client()
+                    .await?
+                    .add_hook(types::Hook {
+                        path: path.to_owned(),
+                        args,
+                        timeout,
+                        events,
+                    })
+                    .await?;
+                Ok(())
+            }
+            Some(("remove", matches)) => {
+                let path = matches.value_of("path").unwrap();
+                new_rpc_client().await?.remove_hook(path.to_owned()).await?;
+                Ok(())
+            }
+            Some(("list", _)) => {
+                let settings = new_rpc_client().await?.get_settings(()).await?.into_inner();
+                println!("Hooks:");
+                for hook in settings.hooks {
+                    match Hook::try_from(hook) {
+                        Ok(hook) => print_hook(&hook),
+                        Err(_) => return Err(Error::Other("Received invalid hook")),
+                    }
+                }
+                Ok(())
+            }
+            _ => unreachable!("unhandled command"),
+        }
+    }
+}
+
+fn parse_event(event: &str) -> Event {
+    match event {
+        "connecting" => Event::Connecting,
+        "connected" => Event::Connected,
+        "disconnecting" => Event::Disconnecting,
+        "disconnected" => Event::Disconnected,
+        "error" => Event::Error,
+        _ => unreachable!("invalid event"),
+    }
+}
+
+fn print_hook(hook: &Hook) {
+    let events = if hook.events.is_empty() {
+        "all events".to_owned()
+    } else {
+        hook.events
+            .iter()
+            .map(HookEvent::to_string)
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    println!(
+        "    {} (on {}, timeout {}s)",
+        hook, events, hook.timeout_secs
+    );
+}
@@ -1,4 +1,12 @@
+#[cfg(unix)]
+use crate::Error;
 use crate::{new_rpc_client, Command, Result};
+#[cfg(unix)]
+use mullvad_management_interface::types;
+#[cfg(unix)]
+use std::convert::TryFrom;
+#[cfg(unix)]
+use talpid_types::net::lan::LanNetworks;
 
 pub struct Lan;
 
@@ -9,7 +17,7 @@ impl Command for Lan {
     }
 
     fn clap_subcommand(&self) -> clap::App<'static> {
-        clap::App::new(self.name())
+        let app = clap::App::new(self.name())
             .about("Control the allow local network sharing setting")
             .setting(clap::AppSettings::SubcommandRequiredElseHelp)
             .subcommand(
@@ -21,7 +29,12 @@ impl Command for Lan {
             )
             .subcommand(
                 clap::App::new("get").about("Display the current local network sharing setting"),
-            )
+            );
+        if cfg!(unix) {
+            app.subcommand(create_networks_subcommand())
+        } else {
+            app
+        }
     }
 
     async fn run(&self, matches: &clap::ArgMatches) -> Result<()> {
@@ -31,6 +44,10 @@ impl Command for Lan {
         } else if let Some(_matches) = matches.subcommand_matches("get") {
             self.get().await
         } else {
+            #[cfg(unix)]
+            if let Some(networks_matches) = matches.subcommand_matches("networks") {
+                return self.handle_networks_cmd(networks_matches).await;
+            }
             unreachable!("No lan command given");
         }
     }
@@ -53,4 +70,111 @@ impl Lan {
         );
         Ok(())
     }
+
+    #[cfg(unix)]
+    async fn handle_networks_cmd(&self, matches: &clap::ArgMatches) -> Result<()> {
+        match matches.subcommand() {
+            Some(("list", _)) => {
+                let lan_networks = Self::get_lan_networks().await?;
+                println!("Local networks:");
+                for net in &lan_networks.networks {
+                    println!("    {}", net);
+                }
+                println!("Multicast networks:");
+                for net in &lan_networks.multicast_networks {
+                    println!("    {}", net);
+                }
+                Ok(())
+            }
+            Some(("add", matches)) => {
+                let net = parse_network(matches)?;
+                let mut lan_networks = Self::get_lan_networks().await?;
+                let list = select_list(&mut lan_networks, matches);
+                if !list.contains(&net) {
+                    list.push(net);
+                }
+                Self::set_lan_networks(&lan_networks).await
+            }
+            Some(("remove", matches)) => {
+                let net = parse_network(matches)?;
+                let mut lan_networks = Self::get_lan_networks().await?;
+                select_list(&mut lan_networks, matches).retain(|existing| existing != &net);
+                Self::set_lan_networks(&lan_networks).await
+            }
+            Some(("reset", _)) => Self::set_lan_networks(&LanNetworks::default()).await,
+            _ => unreachable!("unhandled command"),
+        }
+    }
+
+    #[cfg(unix)]
+    async fn get_lan_networks() -> Result<LanNetworks> {
+        let mut rpc = new_rpc_client().await?;
+        let settings = rpc.get_settings(()).await?.into_inner();
+        let lan_networks = settings
+            .lan_networks
+            .ok_or(Error::Other("Missing LAN networks"))?;
+        LanNetworks::try_from(lan_networks).map_err(|_| Error::Other("Invalid LAN networks"))
+    }
+
+    #[cfg(unix)]
+    async fn set_lan_networks(lan_networks: &LanNetworks) -> Result<()> {
+        let mut rpc = new_rpc_client().await?;
+        rpc.set_lan_networks(types::LanNetworks::from(lan_networks))
+            .await
+            .map_err(|status| Error::RpcFailedExt("Failed to set LAN networks", status))?;
+        println!("Updated local networks");
+        Ok(())
+    }
+}
+
+fn create_networks_subcommand() -> clap::App<'static> {
+    let network_args = || {
+        [
+            clap::Arg::new("network")
+                .help("Network in CIDR notation. E.g. 192.168.1.0/24")
+                .required(true),
+            clap::Arg::new("multicast")
+                .help("Edit the list of multicast and broadcast networks")
+                .long("multicast"),
+        ]
+    };
+    clap::App::new("networks")
+        .about(
+            "Manage the networks that are reachable when local network sharing is allowed. \
+             Only private networks can be added",
+        )
+        .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(clap::App::new("list").about("Display the local networks"))
+        .subcommand(
+            clap::App::new("add")
+                .about("Add a local network")
+                .args(network_args()),
+        )
+        .subcommand(
+            clap::App::new("remove")
+                .about("Remove a local network")
+                .args(network_args()),
+        )
+        .subcommand(clap::App::new("reset").about("Restore the default local networks"))
+}
+
+#[cfg(unix)]
+fn parse_network(matches: &clap::ArgMatches) -> Result<ipnetwork::IpNetwork> {
+    matches
+        .value_of("network")
+        .unwrap()
+        .parse()
+        .map_err(|_| Error::InvalidCommand("invalid network"))
+}
+
+#[cfg(unix)]
+fn select_list<'a>(
+    lan_networks: &'a mut LanNetworks,
+    matches: &clap::ArgMatches,
+) -> &'a mut Vec<ipnetwork::IpNetwork> {
+    if matches.is_present("multicast") {
+        &mut lan_networks.multicast_networks
+    } else {
+        &mut lan_networks.networks
+    }
 }
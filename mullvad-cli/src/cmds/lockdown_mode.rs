@@ -0,0 +1,56 @@
+use crate::{new_rpc_client, Command, Result};
+
+pub struct LockdownMode;
+
+#[mullvad_management_interface::async_trait]
+impl Command for LockdownMode {
+    fn name(&self) -> &'static str {
+        "lockdown-mode"
+    }
+
+    fn clap_subcommand(&self) -> clap::App<'static> {
+        clap::App::new(self.name())
+            .about("Control if network access should be blocked unless connected to VPN, also while the system service is not running")
+            .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(
+                clap::App::new("set")
+                    .about("Change the lockdown mode setting")
+                    .arg(
+                        clap::Arg::new("policy")
+                            .required(true)
+                            .possible_values(&["on", "off"]),
+                    ),
+            )
+            .subcommand(clap::App::new("get").about("Display the current lockdown mode setting"))
+    }
+
+    async fn run(&self, matches: &clap::ArgMatches) -> Result<()> {
+        if let Some(set_matches) = matches.subcommand_matches("set") {
+            let lockdown_mode = set_matches.value_of("policy").expect("missing policy");
+            self.set(lockdown_mode == "on").await
+        } else if let Some(_matches) = matches.subcommand_matches("get") {
+            self.get().await
+        } else {
+            unreachable!("No lockdown-mode command given");
+        }
+    }
+}
+
+impl LockdownMode {
+    async fn set(&self, lockdown_mode: bool) -> Result<()> {
+        let mut rpc = new_rpc_client().await?;
+        rpc.set_lockdown_mode(lockdown_mode).await?;
+        println!("Changed lockdown mode setting");
+        Ok(())
+    }
+
+    async fn get(&self) -> Result<()> {
+        let mut rpc = new_rpc_client().await?;
+        let lockdown_mode = rpc.get_settings(()).await?.into_inner().lockdown_mode;
+        println!(
+            "Lockdown mode: {}",
+            if lockdown_mode { "on" } else { "off" }
+        );
+        Ok(())
+    }
+}
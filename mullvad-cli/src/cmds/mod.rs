@@ -16,18 +16,44 @@ pub use self::block_when_disconnected::BlockWhenDisconnected;
 mod bridge;
 pub use self::bridge::Bridge;
 
+#[cfg(target_os = "linux")]
+mod captive_portal;
+#[cfg(target_os = "linux")]
+pub use self::captive_portal::CaptivePortal;
+
 mod connect;
 pub use self::connect::Connect;
 
+mod debug;
+pub use self::debug::Debug;
+
 mod disconnect;
 pub use self::disconnect::Disconnect;
 
 mod dns;
 pub use self::dns::Dns;
 
+#[cfg(target_os = "linux")]
+mod firewall;
+#[cfg(target_os = "linux")]
+pub use self::firewall::Firewall;
+
+mod history;
+pub use self::history::History;
+
+#[cfg(target_os = "linux")]
+mod hook;
+#[cfg(target_os = "linux")]
+pub use self::hook::Hooks;
+
 mod lan;
 pub use self::lan::Lan;
 
+#[cfg(target_os = "linux")]
+mod lockdown_mode;
+#[cfg(target_os = "linux")]
+pub use self::lockdown_mode::LockdownMode;
+
 mod obfuscation;
 pub use self::obfuscation::Obfuscation;
 
@@ -48,6 +74,11 @@ pub use self::split_tunnel::SplitTunnel;
 mod status;
 pub use self::status::Status;
 
+#[cfg(target_os = "linux")]
+mod trusted_network;
+#[cfg(target_os = "linux")]
+pub use self::trusted_network::TrustedNetworks;
+
 mod tunnel;
 pub use self::tunnel::Tunnel;
 
@@ -62,17 +93,29 @@ pub fn get_commands() -> HashMap<&'static str, Box<dyn Command>> {
         Box::new(BetaProgram),
         Box::new(BlockWhenDisconnected),
         Box::new(Bridge),
+        #[cfg(target_os = "linux")]
+        Box::new(CaptivePortal),
         Box::new(Connect),
+        Box::new(Debug),
         Box::new(Disconnect),
         Box::new(Dns),
+        #[cfg(target_os = "linux")]
+        Box::new(Firewall),
+        Box::new(History),
+        #[cfg(target_os = "linux")]
+        Box::new(Hooks),
         Box::new(Reconnect),
         Box::new(Lan),
+        #[cfg(target_os = "linux")]
+        Box::new(LockdownMode),
         Box::new(Obfuscation),
         Box::new(Relay),
         Box::new(Reset),
         #[cfg(any(target_os = "linux", windows))]
         Box::new(SplitTunnel),
         Box::new(Status),
+        #[cfg(target_os = "linux")]
+        Box::new(TrustedNetworks),
         Box::new(Tunnel),
         Box::new(Version),
     ];
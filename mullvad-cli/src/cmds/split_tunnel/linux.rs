@@ -1,4 +1,6 @@
 use crate::{new_rpc_client, Command, Result};
+use mullvad_management_interface::types::{self, exclusion_profile, split_tunnel_mode};
+use std::collections::{HashMap, HashSet};
 
 pub struct SplitTunnel;
 
@@ -16,21 +18,126 @@ impl Command for SplitTunnel {
             )
             .setting(clap::AppSettings::SubcommandRequiredElseHelp)
             .subcommand(create_pid_subcommand())
+            .subcommand(create_app_subcommand())
+            .subcommand(create_destination_subcommand())
+            .subcommand(create_profile_subcommand())
+            .subcommand(
+                clap::App::new("set")
+                    .about("Enable or disable excluding applications from the tunnel")
+                    .arg(
+                        clap::Arg::new("policy")
+                            .required(true)
+                            .possible_values(&["on", "off"]),
+                    ),
+            )
+            .subcommand(
+                clap::App::new("mode")
+                    .about(
+                        "Set whether the processes and applications managed by this command are \
+                            excluded from the tunnel, or the only ones that use it",
+                    )
+                    .arg(
+                        clap::Arg::new("mode")
+                            .required(true)
+                            .possible_values(&["exclude", "include-only"]),
+                    ),
+            )
+            .subcommand(clap::App::new("get").about("Display the split tunnel status"))
     }
 
     async fn run(&self, matches: &clap::ArgMatches) -> Result<()> {
         match matches.subcommand() {
             Some(("pid", pid_matches)) => Self::handle_pid_cmd(pid_matches).await,
+            Some(("app", app_matches)) => Self::handle_app_cmd(app_matches).await,
+            Some(("destination", matches)) => Self::handle_destination_cmd(matches).await,
+            Some(("profile", matches)) => Self::handle_profile_cmd(matches).await,
+            Some(("get", _)) => self.get().await,
+            Some(("set", matches)) => {
+                let enabled = matches.value_of("policy").expect("missing policy");
+                self.set(enabled == "on").await
+            }
+            Some(("mode", matches)) => {
+                let mode = matches.value_of("mode").expect("missing mode");
+                self.set_mode(mode == "include-only").await
+            }
             _ => unreachable!("unhandled comand"),
         }
     }
 }
 
+fn create_app_subcommand() -> clap::App<'static> {
+    clap::App::new("app")
+        .about(
+            "Manage applications to exclude from the tunnel. Processes started from these \
+                executables are excluded automatically while split tunneling is enabled. Only root \
+                can change the applications",
+        )
+        .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(clap::App::new("list"))
+        .subcommand(clap::App::new("add").arg(clap::Arg::new("path").required(true)))
+        .subcommand(clap::App::new("remove").arg(clap::Arg::new("path").required(true)))
+        .subcommand(clap::App::new("clear"))
+}
+
+fn create_destination_subcommand() -> clap::App<'static> {
+    clap::App::new("destination")
+        .about(
+            "Manage networks and domains to exclude from the tunnel while split tunneling is \
+                enabled. Domains are matched against the DNS responses received by this device",
+        )
+        .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(clap::App::new("list"))
+        .subcommand(
+            clap::App::new("add").arg(
+                clap::Arg::new("destination")
+                    .help("IP network in CIDR notation, IP address, or domain name")
+                    .required(true),
+            ),
+        )
+        .subcommand(clap::App::new("remove").arg(clap::Arg::new("destination").required(true)))
+        .subcommand(clap::App::new("clear"))
+}
+
+fn create_profile_subcommand() -> clap::App<'static> {
+    clap::App::new("profile")
+        .about(
+            "Manage exclusion profiles. Programs are launched in a profile using \
+                'mullvad-exclude --profile <name>'",
+        )
+        .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(clap::App::new("list"))
+        .subcommand(
+            clap::App::new("set")
+                .about("Add a profile, or change an existing one")
+                .arg(clap::Arg::new("name").required(true))
+                .arg(
+                    clap::Arg::new("dns")
+                        .long("dns")
+                        .help(
+                            "Whether DNS requests to the DNS servers of the tunnel are sent in \
+                                the tunnel, or excluded along with all other traffic",
+                        )
+                        .takes_value(true)
+                        .default_value("tunnel")
+                        .possible_values(&["tunnel", "bypass"]),
+                ),
+        )
+        .subcommand(clap::App::new("remove").arg(clap::Arg::new("name").required(true)))
+}
+
 fn create_pid_subcommand() -> clap::App<'static> {
     clap::App::new("pid")
         .about("Manage processes to exclude from the tunnel")
         .setting(clap::AppSettings::SubcommandRequiredElseHelp)
-        .subcommand(clap::App::new("add").arg(clap::Arg::new("pid").required(true)))
+        .subcommand(
+            clap::App::new("add")
+                .arg(clap::Arg::new("pid").required(true))
+                .arg(
+                    clap::Arg::new("children")
+                        .long("children")
+                        .help("Also exclude all current descendants of the process"),
+                ),
+        )
         .subcommand(clap::App::new("delete").arg(clap::Arg::new("pid").required(true)))
         .subcommand(clap::App::new("clear"))
         .subcommand(clap::App::new("list"))
@@ -41,10 +148,12 @@ impl SplitTunnel {
         match matches.subcommand() {
             Some(("add", matches)) => {
                 let pid: i32 = matches.value_of_t_or_exit("pid");
-                new_rpc_client()
-                    .await?
-                    .add_split_tunnel_process(pid)
-                    .await?;
+                let mut rpc = new_rpc_client().await?;
+                if matches.is_present("children") {
+                    rpc.add_split_tunnel_process_tree(pid).await?;
+                } else {
+                    rpc.add_split_tunnel_process(pid).await?;
+                }
                 Ok(())
             }
             Some(("delete", matches)) => {
@@ -63,20 +172,296 @@ impl SplitTunnel {
                 Ok(())
             }
             Some(("list", _)) => {
-                let mut pids_stream = new_rpc_client()
+                let mut processes_stream = new_rpc_client()
                     .await?
                     .get_split_tunnel_processes(())
                     .await?
                     .into_inner();
-                println!("Excluded PIDs:");
+                let mut processes = vec![];
+                while let Some(process) = processes_stream.message().await? {
+                    processes.push(process);
+                }
 
-                while let Some(pid) = pids_stream.message().await? {
-                    println!("    {}", pid);
+                println!("Excluded processes:");
+                print_process_trees(&processes);
+
+                Ok(())
+            }
+            _ => unreachable!("unhandled command"),
+        }
+    }
+
+    async fn handle_app_cmd(matches: &clap::ArgMatches) -> Result<()> {
+        match matches.subcommand() {
+            Some(("list", _)) => {
+                let paths = new_rpc_client()
+                    .await?
+                    .get_settings(())
+                    .await?
+                    .into_inner()
+                    .split_tunnel
+                    .unwrap()
+                    .apps;
+
+                println!("Excluded applications:");
+                for path in &paths {
+                    println!("    {}", path);
                 }
 
                 Ok(())
             }
+            Some(("add", matches)) => {
+                let path: String = matches.value_of_t_or_exit("path");
+                new_rpc_client().await?.add_split_tunnel_app(path).await?;
+                Ok(())
+            }
+            Some(("remove", matches)) => {
+                let path: String = matches.value_of_t_or_exit("path");
+                new_rpc_client()
+                    .await?
+                    .remove_split_tunnel_app(path)
+                    .await?;
+                Ok(())
+            }
+            Some(("clear", _)) => {
+                new_rpc_client().await?.clear_split_tunnel_apps(()).await?;
+                Ok(())
+            }
             _ => unreachable!("unhandled command"),
         }
     }
+
+    async fn handle_destination_cmd(matches: &clap::ArgMatches) -> Result<()> {
+        let mut rpc = new_rpc_client().await?;
+        let mut destinations = rpc
+            .get_settings(())
+            .await?
+            .into_inner()
+            .split_tunnel
+            .unwrap()
+            .excluded_destinations
+            .unwrap_or_default();
+
+        match matches.subcommand() {
+            Some(("list", _)) => {
+                print_destinations(&destinations);
+                return Ok(());
+            }
+            Some(("add", matches)) => {
+                match parse_destination(matches.value_of("destination").unwrap()) {
+                    Destination::Network(network) => {
+                        if !destinations.networks.contains(&network) {
+                            destinations.networks.push(network);
+                        }
+                    }
+                    Destination::Domain(domain) => {
+                        if !destinations.domains.contains(&domain) {
+                            destinations.domains.push(domain);
+                        }
+                    }
+                }
+            }
+            Some(("remove", matches)) => {
+                match parse_destination(matches.value_of("destination").unwrap()) {
+                    Destination::Network(network) => {
+                        destinations.networks.retain(|other| other != &network)
+                    }
+                    Destination::Domain(domain) => {
+                        destinations.domains.retain(|other| other != &domain)
+                    }
+                }
+            }
+            Some(("clear", _)) => {
+                destinations = types::ExcludedDestinations::default();
+            }
+            _ => unreachable!("unhandled command"),
+        }
+
+        rpc.set_split_tunnel_destinations(destinations).await?;
+        println!("Updated excluded destinations");
+        Ok(())
+    }
+
+    async fn handle_profile_cmd(matches: &clap::ArgMatches) -> Result<()> {
+        match matches.subcommand() {
+            Some(("list", _)) => {
+                let profiles = new_rpc_client()
+                    .await?
+                    .get_settings(())
+                    .await?
+                    .into_inner()
+                    .split_tunnel
+                    .unwrap()
+                    .exclusion_profiles;
+
+                println!("Exclusion profiles:");
+                for profile in &profiles {
+                    let dns = match exclusion_profile::Dns::from_i32(profile.dns) {
+                        Some(exclusion_profile::Dns::Bypass) => "bypass",
+                        _ => "tunnel",
+                    };
+                    println!("    {} (DNS: {})", profile.name, dns);
+                }
+                Ok(())
+            }
+            Some(("set", matches)) => {
+                let dns = match matches.value_of("dns").unwrap() {
+                    "bypass" => exclusion_profile::Dns::Bypass,
+                    _ => exclusion_profile::Dns::Tunnel,
+                };
+                new_rpc_client()
+                    .await?
+                    .set_exclusion_profile(types::ExclusionProfile {
+                        name: matches.value_of_t_or_exit("name"),
+                        dns: i32::from(dns),
+                    })
+                    .await?;
+                println!("Updated exclusion profile");
+                Ok(())
+            }
+            Some(("remove", matches)) => {
+                let name: String = matches.value_of_t_or_exit("name");
+                new_rpc_client()
+                    .await?
+                    .remove_exclusion_profile(name)
+                    .await?;
+                println!("Removed exclusion profile");
+                Ok(())
+            }
+            _ => unreachable!("unhandled command"),
+        }
+    }
+
+    async fn set(&self, enabled: bool) -> Result<()> {
+        let mut rpc = new_rpc_client().await?;
+        rpc.set_split_tunnel_state(enabled).await?;
+        println!("Changed split tunnel setting");
+        Ok(())
+    }
+
+    async fn set_mode(&self, include_only: bool) -> Result<()> {
+        let mode = if include_only {
+            split_tunnel_mode::Mode::IncludeOnly
+        } else {
+            split_tunnel_mode::Mode::Exclude
+        };
+        let mut rpc = new_rpc_client().await?;
+        rpc.set_split_tunnel_mode(types::SplitTunnelMode {
+            mode: i32::from(mode),
+        })
+        .await?;
+        println!("Changed split tunnel mode");
+        if include_only {
+            println!(
+                "WARNING: While split tunneling is on and the tunnel is connecting or connected, \
+                 all traffic except that of the added processes and applications is sent \
+                 outside the tunnel and is not blocked by the kill switch. This does not apply \
+                 while traffic is blocked."
+            );
+        }
+        Ok(())
+    }
+
+    async fn get(&self) -> Result<()> {
+        let mut rpc = new_rpc_client().await?;
+        let settings = rpc
+            .get_settings(())
+            .await?
+            .into_inner()
+            .split_tunnel
+            .unwrap();
+        println!(
+            "Split tunnel status: {}",
+            if settings.enable_exclusions {
+                "on"
+            } else {
+                "off"
+            }
+        );
+        let mode = settings
+            .mode
+            .and_then(|mode| split_tunnel_mode::Mode::from_i32(mode.mode))
+            .unwrap_or(split_tunnel_mode::Mode::Exclude);
+        println!(
+            "Split tunnel mode: {}",
+            match mode {
+                split_tunnel_mode::Mode::Exclude => "exclude",
+                split_tunnel_mode::Mode::IncludeOnly => "include only",
+            }
+        );
+        print_destinations(&settings.excluded_destinations.unwrap_or_default());
+        Ok(())
+    }
+}
+
+enum Destination {
+    Network(String),
+    Domain(String),
+}
+
+/// Interprets the argument as a network if it is an IP address or a network in CIDR notation.
+/// Anything else is treated as a domain name, which the daemon validates.
+fn parse_destination(destination: &str) -> Destination {
+    match destination.parse::<ipnetwork::IpNetwork>() {
+        Ok(network) => Destination::Network(network.to_string()),
+        Err(_) => Destination::Domain(destination.to_lowercase()),
+    }
+}
+
+fn print_destinations(destinations: &types::ExcludedDestinations) {
+    println!("Excluded networks:");
+    for network in &destinations.networks {
+        println!("    {}", network);
+    }
+    println!("Excluded domains:");
+    for domain in &destinations.domains {
+        println!("    {}", domain);
+    }
+}
+
+/// Prints excluded processes indented below their parent, if the parent is also excluded.
+fn print_process_trees(processes: &[types::ExcludedProcess]) {
+    let pids: HashSet<i32> = processes.iter().map(|process| process.pid).collect();
+    let mut children: HashMap<i32, Vec<&types::ExcludedProcess>> = HashMap::new();
+    let mut roots = vec![];
+    for process in processes {
+        if process.parent_pid != process.pid && pids.contains(&process.parent_pid) {
+            children
+                .entry(process.parent_pid)
+                .or_default()
+                .push(process);
+        } else {
+            roots.push(process);
+        }
+    }
+
+    let mut stack: Vec<_> = roots
+        .into_iter()
+        .rev()
+        .map(|process| (process, 1))
+        .collect();
+    while let Some((process, depth)) = stack.pop() {
+        let exe = if process.exe_path.is_empty() {
+            "unknown executable"
+        } else {
+            &process.exe_path
+        };
+        println!(
+            "{:indent$}{} {} (uid {}, {})",
+            "",
+            process.pid,
+            process.name,
+            process.uid,
+            exe,
+            indent = depth * 4,
+        );
+        if let Some(process_children) = children.get(&process.pid) {
+            stack.extend(
+                process_children
+                    .iter()
+                    .rev()
+                    .map(|child| (*child, depth + 1)),
+            );
+        }
+    }
 }
@@ -1,6 +1,10 @@
 use crate::{format, new_rpc_client, Command, Error, Result};
 use mullvad_management_interface::{
-    types::daemon_event::Event as EventType, ManagementServiceClient,
+    types::{
+        daemon_event::Event as EventType,
+        tunnel_state::State::{Connected, Disconnected},
+    },
+    ManagementServiceClient,
 };
 
 pub struct Status;
@@ -47,6 +51,9 @@ impl Command for Status {
         } else {
             format::print_state(&state, verbose);
         }
+        if let Some(Disconnected(_)) = state.state {
+            print_tunnel_pause(&mut rpc).await?;
+        }
 
         if show_full_location {
             print_location(&mut rpc).await?;
@@ -64,9 +71,14 @@ impl Command for Status {
                             format::print_state(&new_state, verbose);
                         }
 
-                        use mullvad_management_interface::types::tunnel_state::State::*;
                         match new_state.state.unwrap() {
-                            Connected(..) | Disconnected(..) => {
+                            Connected(..) => {
+                                if show_full_location {
+                                    print_location(&mut rpc).await?;
+                                }
+                            }
+                            Disconnected(..) => {
+                                print_tunnel_pause(&mut rpc).await?;
                                 if show_full_location {
                                     print_location(&mut rpc).await?;
                                 }
@@ -99,6 +111,13 @@ impl Command for Status {
                             println!("Remove device event: {:#?}", device);
                         }
                     }
+                    EventType::DnsWarning(warning) => {
+                        if debug {
+                            println!("DNS warning: {:#?}", warning);
+                        } else {
+                            format::print_dns_warning(&warning);
+                        }
+                    }
                 }
             }
         }
@@ -107,6 +126,17 @@ impl Command for Status {
     }
 }
 
+async fn print_tunnel_pause(rpc: &mut ManagementServiceClient) -> Result<()> {
+    let pause = rpc.get_tunnel_pause(()).await?.into_inner();
+    if let Some(resume_in) = pause.resume_in {
+        println!(
+            "Connecting again in {}",
+            format::format_duration(u64::try_from(resume_in.seconds).unwrap_or(0))
+        );
+    }
+    Ok(())
+}
+
 async fn print_location(rpc: &mut ManagementServiceClient) -> Result<()> {
     let location = rpc.get_current_location(()).await;
     let location = match location {
@@ -0,0 +1,122 @@
+use crate::{new_rpc_client, Command, Error, Result};
+use mullvad_management_interface::types;
+use std::convert::TryFrom;
+use talpid_types::net::trusted_network::{NetworkIdentity, TrustedNetwork};
+
+pub struct TrustedNetworks;
+
+#[mullvad_management_interface::async_trait]
+impl Command for TrustedNetworks {
+    fn name(&self) -> &'static str {
+        "trusted-network"
+    }
+
+    fn clap_subcommand(&self) -> clap::App<'static> {
+        clap::App::new(self.name())
+            .about(
+                "Manage networks on which the tunnel is disconnected automatically. The \
+                 tunnel is connected automatically on all other networks",
+            )
+            .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(
+                clap::App::new("add")
+                    .about("Trust a network")
+                    .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+                    .subcommands(network_subcommands()),
+            )
+            .subcommand(
+                clap::App::new("remove")
+                    .about("Stop trusting a network added with 'add'")
+                    .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+                    .subcommands(network_subcommands()),
+            )
+            .subcommand(clap::App::new("list").about("Display all trusted networks"))
+            .subcommand(
+                clap::App::new("current").about("Display the networks that are currently used"),
+            )
+    }
+
+    async fn run(&self, matches: &clap::ArgMatches) -> Result<()> {
+        match matches.subcommand() {
+            Some(("add", matches)) => {
+                let network = parse_network(matches)?;
+                new_rpc_client()
+                    .await?
+                    .add_trusted_network(types::TrustedNetwork::from(&network))
+                    .await?;
+                println!("Trusted {}", network);
+                Ok(())
+            }
+            Some(("remove", matches)) => {
+                let network = parse_network(matches)?;
+                new_rpc_client()
+                    .await?
+                    .remove_trusted_network(types::TrustedNetwork::from(&network))
+                    .await?;
+                Ok(())
+            }
+            Some(("list", _)) => {
+                let settings = new_rpc_client().await?.get_settings(()).await?.into_inner();
+                println!("Trusted networks:");
+                for network in settings.trusted_networks {
+                    match TrustedNetwork::try_from(network) {
+                        Ok(network) => println!("    {}", network),
+                        Err(_) => return Err(Error::Other("Received invalid trusted network")),
+                    }
+                }
+                Ok(())
+            }
+            Some(("current", _)) => {
+                let identity = NetworkIdentity::from(
+                    new_rpc_client()
+                        .await?
+                        .get_network_identity(())
+                        .await?
+                        .into_inner(),
+                );
+                if identity.connections.is_empty() {
+                    println!("Not connected to any network");
+                }
+                for connection in identity.connections {
+                    println!("{}", connection);
+                }
+                Ok(())
+            }
+            _ => unreachable!("unhandled command"),
+        }
+    }
+}
+
+fn network_subcommands() -> Vec<clap::App<'static>> {
+    vec![
+        clap::App::new("gateway-mac")
+            .about("Match networks whose default gateway has this hardware address")
+            .arg(
+                clap::Arg::new("value")
+                    .help("MAC address, e.g. 01:23:45:67:89:ab")
+                    .required(true),
+            ),
+        clap::App::new("ssid")
+            .about("Match wireless networks with this name")
+            .arg(clap::Arg::new("value").required(true)),
+        clap::App::new("interface")
+            .about("Match any network reached through this interface")
+            .arg(clap::Arg::new("value").required(true)),
+    ]
+}
+
+fn parse_network(matches: &clap::ArgMatches) -> Result<TrustedNetwork> {
+    match matches.subcommand() {
+        Some(("gateway-mac", matches)) => {
+            TrustedNetwork::gateway_mac(matches.value_of("value").unwrap())
+                .ok_or(Error::InvalidCommand("invalid MAC address"))
+        }
+        Some(("ssid", matches)) => Ok(TrustedNetwork::Ssid(
+            matches.value_of("value").unwrap().to_owned(),
+        )),
+        Some(("interface", matches)) => Ok(TrustedNetwork::Interface(
+            matches.value_of("value").unwrap().to_owned(),
+        )),
+        _ => unreachable!("unhandled network type"),
+    }
+}
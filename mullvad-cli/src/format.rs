@@ -5,8 +5,8 @@ use mullvad_management_interface::types::{
     },
     tunnel_state,
     tunnel_state::State::*,
-    ErrorState, ObfuscationType, ProxyType, TransportProtocol, TunnelState, TunnelStateRelayInfo,
-    TunnelType,
+    DnsWarning, ErrorState, ObfuscationType, ProxyType, TransportProtocol, TunnelState,
+    TunnelStateRelayInfo, TunnelType,
 };
 use mullvad_types::auth_failed::AuthFailed;
 
@@ -31,6 +31,36 @@ pub fn print_state(state: &TunnelState, verbose: bool) {
     }
 }
 
+/// Formats a number of seconds as hours, minutes and seconds, leaving out units that are zero.
+pub fn format_duration(seconds: u64) -> String {
+    let (hours, minutes, seconds) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+    let mut parts = vec![];
+    if hours > 0 {
+        parts.push(format!("{}h", hours));
+    }
+    if minutes > 0 {
+        parts.push(format!("{}m", minutes));
+    }
+    if seconds > 0 || parts.is_empty() {
+        parts.push(format!("{}s", seconds));
+    }
+    parts.join(" ")
+}
+
+pub fn print_dns_warning(warning: &DnsWarning) {
+    if warning.fallback.is_empty() {
+        eprintln!(
+            "Warning: {} did not apply the DNS settings, and no other DNS manager did either: {}",
+            warning.ignored_by, warning.details
+        );
+    } else {
+        eprintln!(
+            "Warning: {} did not apply the DNS settings, using {} instead: {}",
+            warning.ignored_by, warning.fallback, warning.details
+        );
+    }
+}
+
 fn format_relay_connection(relay_info: &TunnelStateRelayInfo, verbose: bool) -> String {
     let endpoint = relay_info.tunnel_endpoint.as_ref().unwrap();
     let location = &relay_info.location.as_ref().unwrap();
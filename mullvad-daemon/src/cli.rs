@@ -10,6 +10,7 @@ pub struct Config {
     pub run_as_service: bool,
     pub register_service: bool,
     pub restart_service: bool,
+    pub initialize_early_boot_firewall: bool,
 }
 
 pub fn get_config() -> &'static Config {
@@ -34,6 +35,8 @@ pub fn create_config() -> Config {
     let run_as_service = cfg!(windows) && matches.is_present("run_as_service");
     let register_service = cfg!(windows) && matches.is_present("register_service");
     let restart_service = cfg!(windows) && matches.is_present("restart_service");
+    let initialize_early_boot_firewall =
+        cfg!(target_os = "linux") && matches.is_present("initialize_early_boot_firewall");
 
     Config {
         log_level,
@@ -42,6 +45,7 @@ pub fn create_config() -> Config {
         run_as_service,
         register_service,
         restart_service,
+        initialize_early_boot_firewall,
     }
 }
 
@@ -106,5 +110,13 @@ fn create_app() -> App<'static> {
                 .help("Restarts the existing system service"),
         )
     }
+
+    if cfg!(target_os = "linux") {
+        app = app.arg(
+            Arg::new("initialize_early_boot_firewall")
+                .long("initialize-early-boot-firewall")
+                .help("Block all traffic if lockdown mode is enabled, and exit. Used during early boot, before the network is up"),
+        )
+    }
     app
 }
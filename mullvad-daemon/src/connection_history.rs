@@ -0,0 +1,390 @@
+use chrono::{DateTime, Utc};
+use mullvad_types::{connection_history::ConnectionEntry, states::TunnelState};
+use std::{
+    collections::VecDeque,
+    path::{Path, PathBuf},
+};
+use talpid_types::{net::TrafficStats, tunnel::ActionAfterDisconnect, ErrorExt};
+use tokio::{
+    fs,
+    io::{self, AsyncWriteExt},
+};
+
+const CONNECTION_HISTORY_FILE: &str = "connection-history.json";
+/// Maximum number of connections to remember. The oldest ones are forgotten first.
+const MAX_ENTRIES: usize = 500;
+
+#[derive(err_derive::Error, Debug)]
+#[error(no_from)]
+pub enum Error {
+    #[error(display = "Failed to serialize connection history")]
+    Serialize(#[error(source)] serde_json::Error),
+
+    #[error(display = "Unable to write connection history file")]
+    Write(#[error(source)] io::Error),
+
+    #[error(display = "Unable to remove connection history file")]
+    Remove(#[error(source)] io::Error),
+}
+
+/// State of the most recent entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LastEntry {
+    /// The entry is complete, or there is none.
+    Closed,
+    /// The entry is the current connection.
+    Connected,
+    /// The connection ended and the tunnel is about to enter the error state, which holds the
+    /// cause of the connection ending.
+    AwaitingError,
+}
+
+/// Records connections in a size-bounded history file in the cache directory.
+pub struct ConnectionHistory {
+    path: PathBuf,
+    enabled: bool,
+    entries: VecDeque<ConnectionEntry>,
+    last_entry: LastEntry,
+}
+
+impl ConnectionHistory {
+    /// Loads the history. If `enabled` is false, any existing history is removed instead.
+    pub async fn new(cache_dir: &Path, enabled: bool) -> Self {
+        let mut history = ConnectionHistory {
+            path: cache_dir.join(CONNECTION_HISTORY_FILE),
+            enabled,
+            entries: VecDeque::new(),
+            last_entry: LastEntry::Closed,
+        };
+
+        if !enabled {
+            if let Err(error) = history.remove_file().await {
+                log::error!("{}", error.display_chain());
+            }
+            return history;
+        }
+
+        match fs::read_to_string(&history.path).await {
+            Ok(content) => match serde_json::from_str(&content) {
+                Ok(entries) => history.entries = entries,
+                Err(error) => log::error!(
+                    "{}",
+                    error.display_chain_with_msg("Failed to parse connection history")
+                ),
+            },
+            Err(error) if error.kind() == io::ErrorKind::NotFound => (),
+            Err(error) => log::error!(
+                "{}",
+                error.display_chain_with_msg("Failed to read connection history")
+            ),
+        }
+        history
+    }
+
+    /// Returns all entries, oldest first.
+    pub fn entries(&self) -> Vec<ConnectionEntry> {
+        self.entries.iter().cloned().collect()
+    }
+
+    /// Enables or disables the history. Disabling it removes all entries.
+    pub async fn set_enabled(&mut self, enabled: bool) -> Result<(), Error> {
+        if self.enabled == enabled {
+            return Ok(());
+        }
+        self.enabled = enabled;
+        if enabled {
+            Ok(())
+        } else {
+            self.clear().await
+        }
+    }
+
+    /// Removes all entries.
+    pub async fn clear(&mut self) -> Result<(), Error> {
+        self.entries.clear();
+        self.last_entry = LastEntry::Closed;
+        self.remove_file().await
+    }
+
+    /// Records the start or end of a connection. If a connection ended, the time it started is
+    /// returned, which identifies the entry in [`Self::set_traffic`].
+    pub async fn handle_transition(&mut self, tunnel_state: &TunnelState) -> Option<DateTime<Utc>> {
+        let (changed, ended) = self.update(tunnel_state, Utc::now());
+        if changed {
+            self.save().await;
+        }
+        ended
+    }
+
+    /// Sets the bytes transferred during the connection that started at `started`.
+    pub async fn set_traffic(&mut self, started: DateTime<Utc>, traffic: TrafficStats) {
+        if let Some(entry) = self
+            .entries
+            .iter_mut()
+            .rev()
+            .find(|entry| entry.started == started)
+        {
+            entry.traffic = Some(traffic);
+            self.save().await;
+        }
+    }
+
+    /// Updates the entries for the new tunnel state. Returns whether the entries changed and the
+    /// start time of the connection that ended, if any.
+    fn update(
+        &mut self,
+        tunnel_state: &TunnelState,
+        now: DateTime<Utc>,
+    ) -> (bool, Option<DateTime<Utc>>) {
+        if !self.enabled {
+            return (false, None);
+        }
+
+        match (tunnel_state, self.last_entry) {
+            (TunnelState::Connected { endpoint, location }, last_entry) => {
+                // The relay was switched without disconnecting. The traffic of the previous
+                // connection is not counted separately, so it is not requested.
+                if last_entry == LastEntry::Connected {
+                    self.end_last_entry(now);
+                }
+                self.entries.push_back(ConnectionEntry::new(
+                    now,
+                    endpoint.tunnel_type,
+                    location.as_ref(),
+                ));
+                while self.entries.len() > MAX_ENTRIES {
+                    self.entries.pop_front();
+                }
+                self.last_entry = LastEntry::Connected;
+                (true, None)
+            }
+            (TunnelState::Disconnecting(after_disconnect), LastEntry::Connected) => {
+                self.last_entry = if *after_disconnect == ActionAfterDisconnect::Block {
+                    LastEntry::AwaitingError
+                } else {
+                    LastEntry::Closed
+                };
+                (true, self.end_last_entry(now))
+            }
+            (TunnelState::Connecting { .. }, LastEntry::Connected) => {
+                self.last_entry = LastEntry::Closed;
+                (true, self.end_last_entry(now))
+            }
+            (TunnelState::Error(error_state), LastEntry::Connected) => {
+                self.last_entry = LastEntry::Closed;
+                let ended = self.end_last_entry(now);
+                if let Some(entry) = self.entries.back_mut() {
+                    entry.error_cause = Some(error_state.cause().clone());
+                }
+                (true, ended)
+            }
+            (TunnelState::Error(error_state), LastEntry::AwaitingError) => {
+                self.last_entry = LastEntry::Closed;
+                if let Some(entry) = self.entries.back_mut() {
+                    entry.error_cause = Some(error_state.cause().clone());
+                }
+                (true, None)
+            }
+            (_, LastEntry::AwaitingError) => {
+                self.last_entry = LastEntry::Closed;
+                (false, None)
+            }
+            _ => (false, None),
+        }
+    }
+
+    fn end_last_entry(&mut self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let entry = self.entries.back_mut()?;
+        entry.ended = Some(now);
+        Some(entry.started)
+    }
+
+    async fn save(&self) {
+        if let Err(error) = self.write_file().await {
+            log::error!("{}", error.display_chain());
+        }
+    }
+
+    /// Writes the history to a file that only root can read, since it reveals which relays were
+    /// used and when.
+    async fn write_file(&self) -> Result<(), Error> {
+        let data = serde_json::to_string(&self.entries).map_err(Error::Serialize)?;
+        let mut options = fs::OpenOptions::new();
+        #[cfg(unix)]
+        {
+            options.mode(0o600);
+        }
+        let mut file = options
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&self.path)
+            .await
+            .map_err(Error::Write)?;
+
+        // The mode only applies to new files
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mut permissions = file.metadata().await.map_err(Error::Write)?.permissions();
+            if permissions.mode() & 0o777 != 0o600 {
+                permissions.set_mode(0o600);
+                file.set_permissions(permissions)
+                    .await
+                    .map_err(Error::Write)?;
+            }
+        }
+
+        file.write_all(data.as_bytes())
+            .await
+            .map_err(Error::Write)?;
+        file.sync_all().await.map_err(Error::Write)
+    }
+
+    async fn remove_file(&self) -> Result<(), Error> {
+        match fs::remove_file(&self.path).await {
+            Err(error) if error.kind() != io::ErrorKind::NotFound => Err(Error::Remove(error)),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use mullvad_types::location::GeoIpLocation;
+    use std::net::Ipv4Addr;
+    use talpid_types::{
+        net::{Endpoint, TransportProtocol, TunnelEndpoint, TunnelType},
+        tunnel::{ErrorState, ErrorStateCause},
+    };
+
+    fn history() -> ConnectionHistory {
+        ConnectionHistory {
+            path: PathBuf::new(),
+            enabled: true,
+            entries: VecDeque::new(),
+            last_entry: LastEntry::Closed,
+        }
+    }
+
+    fn endpoint() -> TunnelEndpoint {
+        TunnelEndpoint {
+            endpoint: Endpoint::new(Ipv4Addr::new(10, 0, 0, 1), 51820, TransportProtocol::Udp),
+            tunnel_type: TunnelType::Wireguard,
+            proxy: None,
+            obfuscation: None,
+            entry_endpoint: None,
+        }
+    }
+
+    fn connected() -> TunnelState {
+        TunnelState::Connected {
+            endpoint: endpoint(),
+            location: Some(GeoIpLocation {
+                ipv4: None,
+                ipv6: None,
+                country: "Sweden".to_owned(),
+                city: None,
+                latitude: 0.0,
+                longitude: 0.0,
+                mullvad_exit_ip: false,
+                hostname: Some("se-got-wg-001".to_owned()),
+                bridge_hostname: None,
+                entry_hostname: None,
+                obfuscator_hostname: None,
+            }),
+        }
+    }
+
+    #[test]
+    fn test_disconnect() {
+        let mut history = history();
+        let started = Utc::now();
+        let ended = started + chrono::Duration::seconds(10);
+
+        assert_eq!(history.update(&connected(), started), (true, None));
+        assert_eq!(
+            history.update(
+                &TunnelState::Disconnecting(ActionAfterDisconnect::Nothing),
+                ended
+            ),
+            (true, Some(started))
+        );
+        assert_eq!(
+            history.update(&TunnelState::Disconnected, ended),
+            (false, None)
+        );
+
+        let entries = history.entries();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].started, started);
+        assert_eq!(entries[0].ended, Some(ended));
+        assert_eq!(entries[0].exit_hostname.as_deref(), Some("se-got-wg-001"));
+        assert_eq!(entries[0].error_cause, None);
+    }
+
+    #[test]
+    fn test_error_cause() {
+        let mut history = history();
+        let now = Utc::now();
+
+        history.update(&connected(), now);
+        history.update(
+            &TunnelState::Disconnecting(ActionAfterDisconnect::Block),
+            now,
+        );
+        assert_eq!(
+            history.update(
+                &TunnelState::Error(ErrorState::new(ErrorStateCause::IsOffline, None)),
+                now
+            ),
+            (true, None)
+        );
+        assert_eq!(
+            history.entries()[0].error_cause,
+            Some(ErrorStateCause::IsOffline)
+        );
+
+        // An error state that is not caused by losing the connection is not recorded
+        history.update(
+            &TunnelState::Error(ErrorState::new(ErrorStateCause::SetDnsError, None)),
+            now,
+        );
+        assert_eq!(
+            history.entries()[0].error_cause,
+            Some(ErrorStateCause::IsOffline)
+        );
+    }
+
+    #[test]
+    fn test_switch_relay() {
+        let mut history = history();
+        let started = Utc::now();
+        let switched = started + chrono::Duration::seconds(10);
+
+        history.update(&connected(), started);
+        assert_eq!(history.update(&connected(), switched), (true, None));
+
+        let entries = history.entries();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].ended, Some(switched));
+        assert_eq!(entries[1].started, switched);
+        assert_eq!(entries[1].ended, None);
+    }
+
+    #[test]
+    fn test_max_entries() {
+        let mut history = history();
+        let now = Utc::now();
+        for _ in 0..MAX_ENTRIES + 1 {
+            history.update(&connected(), now);
+            let connecting = TunnelState::Connecting {
+                endpoint: endpoint(),
+                location: None,
+            };
+            history.update(&connecting, now);
+        }
+        assert_eq!(history.entries().len(), MAX_ENTRIES);
+    }
+}
@@ -1,5 +1,7 @@
 use mullvad_types::settings::{DnsOptions, DnsState};
 use std::net::{IpAddr, Ipv4Addr};
+#[cfg(target_os = "linux")]
+use talpid_types::net::dns::EncryptedDnsServer;
 
 /// When we want to block certain contents with the help of DNS server side,
 /// we compute the resolver IP to use based on these constants. The last
@@ -53,3 +55,13 @@ pub fn addresses_from_options(options: &DnsOptions) -> Option<Vec<IpAddr>> {
         }
     }
 }
+
+/// Return the DNS-over-HTTPS and DNS-over-TLS servers to use. These are only used when custom DNS
+/// is enabled.
+#[cfg(target_os = "linux")]
+pub fn encrypted_servers_from_options(options: &DnsOptions) -> Vec<EncryptedDnsServer> {
+    match options.state {
+        DnsState::Default => vec![],
+        DnsState::Custom => options.custom_options.encrypted_servers.clone(),
+    }
+}
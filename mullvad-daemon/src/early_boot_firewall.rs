@@ -0,0 +1,105 @@
+//! Blocks all traffic during early boot, before the daemon has started, if lockdown mode is
+//! enabled. The daemon replaces the policy once it enters its initial state.
+//!
+//! The nftables tables do not need to be marked as persistent for the policy to outlive this
+//! process or a crashed daemon. Only tables created with the owner flag are removed along with
+//! the netlink socket that created them, and the firewall never sets that flag. The tables stay
+//! until a new policy replaces them or they are removed explicitly.
+
+use crate::{
+    api,
+    settings::{self, SettingsPersister},
+};
+use mullvad_types::settings::Settings;
+use std::net::{Ipv4Addr, SocketAddr};
+use talpid_core::firewall::{self, Firewall, FirewallPolicy};
+use talpid_types::{net::AllowedEndpoint, ErrorExt};
+
+#[derive(err_derive::Error, Debug)]
+#[error(no_from)]
+pub enum Error {
+    #[error(display = "Failed to obtain settings directory path")]
+    SettingsPathError(#[error(source)] mullvad_paths::Error),
+
+    #[error(display = "Failed to read the settings")]
+    ReadSettingsError(#[error(source)] settings::Error),
+
+    #[error(display = "Failed to obtain cache directory path")]
+    CachePathError(#[error(source)] mullvad_paths::Error),
+
+    #[error(display = "Failed to load the API address")]
+    ApiAddressError(#[error(source)] mullvad_api::Error),
+
+    #[error(display = "Failed to apply the blocking firewall policy")]
+    FirewallError(#[error(source)] firewall::Error),
+}
+
+/// Applies the blocking firewall policy if lockdown mode is enabled. If the settings cannot be
+/// read, the policy is applied regardless, since the daemon would also block in that case. Only
+/// failing to apply the policy is an error.
+pub async fn initialize_firewall() -> Result<(), Error> {
+    let settings = match read_settings().await {
+        Ok(settings) if !settings.lockdown_mode => {
+            log::info!("Lockdown mode is disabled. Not blocking traffic");
+            return Ok(());
+        }
+        Ok(settings) => settings,
+        Err(error) => {
+            log::error!(
+                "{}",
+                error.display_chain_with_msg("Failed to read settings. Blocking traffic")
+            );
+            Settings::default()
+        }
+    };
+
+    let api_address = match read_api_address().await {
+        Ok(address) => address,
+        Err(error) => {
+            log::error!(
+                "{}",
+                error.display_chain_with_msg(
+                    "Failed to load the API address. Blocking the API as well"
+                )
+            );
+            // Nothing can be sent to this address, so no traffic at all is allowed
+            SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0)
+        }
+    };
+    let allowed_endpoint = api::get_allowed_endpoint(api_address);
+
+    log::info!("Lockdown mode is enabled. Blocking traffic");
+    Firewall::new()
+        .map_err(Error::FirewallError)?
+        .apply_policy(blocked_policy(&settings, allowed_endpoint))
+        .map_err(Error::FirewallError)
+}
+
+async fn read_settings() -> Result<Settings, Error> {
+    let settings_dir = mullvad_paths::settings_dir().map_err(Error::SettingsPathError)?;
+    SettingsPersister::read(&settings_dir)
+        .await
+        .map_err(Error::ReadSettingsError)
+}
+
+/// Returns the cached API address, or the bundled one if the cache cannot be read.
+async fn read_api_address() -> Result<SocketAddr, Error> {
+    let cache_dir = mullvad_paths::cache_dir().map_err(Error::CachePathError)?;
+    let api_runtime = mullvad_api::Runtime::with_cache(&cache_dir, false)
+        .await
+        .map_err(Error::ApiAddressError)?;
+    Ok(api_runtime.address_cache.get_address().await)
+}
+
+/// Returns the policy that the daemon would apply in the disconnected state.
+fn blocked_policy(settings: &Settings, allowed_endpoint: AllowedEndpoint) -> FirewallPolicy {
+    FirewallPolicy::Blocked {
+        allow_lan: settings.allow_lan,
+        lan_networks: settings.lan_networks.clone(),
+        allowed_endpoint,
+        allow_rules: settings.firewall_allow_rules.clone(),
+        split_tunnel_mode: crate::split_tunnel_mode(settings),
+        exclusion_profiles: settings.split_tunnel.exclusion_profiles.clone(),
+        excluded_destinations: crate::excluded_destinations(settings),
+    }
+}
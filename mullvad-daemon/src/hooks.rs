@@ -0,0 +1,313 @@
+use futures::{
+    channel::{mpsc, oneshot},
+    StreamExt,
+};
+use mullvad_types::{
+    hooks::{Hook, HookEvent, MAX_HOOK_TIMEOUT_SECS},
+    states::TunnelState,
+};
+use std::{
+    fs, io,
+    net::IpAddr,
+    os::unix::fs::{MetadataExt, PermissionsExt},
+    path::{Path, PathBuf},
+    process::{ExitStatus, Stdio},
+};
+use talpid_core::tunnel::TunnelMetadata;
+use talpid_types::{tunnel::ActionAfterDisconnect, ErrorExt};
+use tokio::process::Command;
+
+/// `PATH` that hooks are run with. Hooks do not inherit the environment of the daemon.
+const HOOK_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+#[derive(err_derive::Error, Debug)]
+#[error(no_from)]
+pub enum Error {
+    #[error(display = "Hook path is not absolute: {}", _0)]
+    RelativePath(String),
+
+    #[error(display = "Failed to read metadata of {}", _0)]
+    Metadata(String, #[error(source)] io::Error),
+
+    #[error(display = "{} is not owned by root", _0)]
+    NotOwnedByRoot(String),
+
+    #[error(display = "{} is writable by users other than root", _0)]
+    WritableByOthers(String),
+
+    #[error(display = "{} is not an executable file", _0)]
+    NotExecutable(String),
+
+    #[error(display = "Hook timeout must be between 1 and {} seconds", _0)]
+    InvalidTimeout(u64),
+
+    #[error(display = "Failed to start hook")]
+    Spawn(#[error(source)] io::Error),
+
+    #[error(display = "Failed to wait for hook to exit")]
+    Wait(#[error(source)] io::Error),
+
+    #[error(display = "Hook was killed after {} seconds", _0)]
+    Timeout(u64),
+
+    #[error(display = "Hook exited with {}", _0)]
+    Failed(ExitStatus),
+}
+
+/// Resolves the path of a hook and checks that only root can modify it. The executable and
+/// every directory leading up to it must be owned by root, and must not be writable by its
+/// group or by other users.
+pub fn validate(path: &Path) -> Result<PathBuf, Error> {
+    if !path.is_absolute() {
+        return Err(Error::RelativePath(path.display().to_string()));
+    }
+    let path = fs::canonicalize(path)
+        .map_err(|error| Error::Metadata(path.display().to_string(), error))?;
+
+    let metadata =
+        fs::metadata(&path).map_err(|error| Error::Metadata(path.display().to_string(), error))?;
+    if !metadata.is_file() || metadata.permissions().mode() & 0o111 == 0 {
+        return Err(Error::NotExecutable(path.display().to_string()));
+    }
+
+    for ancestor in path.ancestors() {
+        let metadata = fs::metadata(ancestor)
+            .map_err(|error| Error::Metadata(ancestor.display().to_string(), error))?;
+        check_ownership(ancestor, metadata.uid(), metadata.mode())?;
+    }
+
+    Ok(path)
+}
+
+/// Checks that a hook timeout is neither zero nor longer than `MAX_HOOK_TIMEOUT_SECS`.
+pub fn validate_timeout(timeout_secs: u64) -> Result<(), Error> {
+    if (1..=MAX_HOOK_TIMEOUT_SECS).contains(&timeout_secs) {
+        Ok(())
+    } else {
+        Err(Error::InvalidTimeout(MAX_HOOK_TIMEOUT_SECS))
+    }
+}
+
+fn check_ownership(path: &Path, uid: u32, mode: u32) -> Result<(), Error> {
+    if uid != 0 {
+        return Err(Error::NotOwnedByRoot(path.display().to_string()));
+    }
+    if mode & 0o022 != 0 {
+        return Err(Error::WritableByOthers(path.display().to_string()));
+    }
+    Ok(())
+}
+
+/// Returns the hook event that corresponds to a tunnel state.
+pub fn event(tunnel_state: &TunnelState) -> HookEvent {
+    match tunnel_state {
+        TunnelState::Disconnected => HookEvent::Disconnected,
+        TunnelState::Connecting { .. } => HookEvent::Connecting,
+        TunnelState::Connected { .. } => HookEvent::Connected,
+        TunnelState::Disconnecting(_) => HookEvent::Disconnecting,
+        TunnelState::Error(_) => HookEvent::Error,
+    }
+}
+
+/// Returns the environment variables that describe a tunnel state to hooks.
+pub fn environment(tunnel_state: &TunnelState) -> Vec<(&'static str, String)> {
+    let mut environment = vec![("MULLVAD_EVENT", event(tunnel_state).to_string())];
+
+    match tunnel_state {
+        TunnelState::Connecting { endpoint, location }
+        | TunnelState::Connected { endpoint, location } => {
+            environment.push(("MULLVAD_TUNNEL_TYPE", endpoint.tunnel_type.to_string()));
+            environment.push((
+                "MULLVAD_ENDPOINT_ADDRESS",
+                endpoint.endpoint.address.ip().to_string(),
+            ));
+            environment.push((
+                "MULLVAD_ENDPOINT_PORT",
+                endpoint.endpoint.address.port().to_string(),
+            ));
+            environment.push((
+                "MULLVAD_ENDPOINT_PROTOCOL",
+                endpoint.endpoint.protocol.to_string(),
+            ));
+            if let Some(hostname) = location
+                .as_ref()
+                .and_then(|location| location.hostname.as_ref())
+            {
+                environment.push(("MULLVAD_RELAY_HOSTNAME", hostname.clone()));
+            }
+        }
+        TunnelState::Disconnecting(after_disconnect) => {
+            let after_disconnect = match after_disconnect {
+                ActionAfterDisconnect::Nothing => "nothing",
+                ActionAfterDisconnect::Block => "block",
+                ActionAfterDisconnect::Reconnect => "reconnect",
+            };
+            environment.push(("MULLVAD_AFTER_DISCONNECT", after_disconnect.to_owned()));
+        }
+        TunnelState::Error(error_state) => {
+            environment.push(("MULLVAD_ERROR_CAUSE", error_state.cause().to_string()));
+        }
+        TunnelState::Disconnected => (),
+    }
+
+    environment
+}
+
+fn metadata_environment(metadata: &TunnelMetadata) -> Vec<(&'static str, String)> {
+    let mut environment = vec![("MULLVAD_TUNNEL_INTERFACE", metadata.interface.clone())];
+    let join_ips = |is_ipv4: bool| {
+        metadata
+            .ips
+            .iter()
+            .filter(|ip| ip.is_ipv4() == is_ipv4)
+            .map(IpAddr::to_string)
+            .collect::<Vec<_>>()
+            .join(" ")
+    };
+    let ipv4 = join_ips(true);
+    if !ipv4.is_empty() {
+        environment.push(("MULLVAD_TUNNEL_IPV4", ipv4));
+    }
+    let ipv6 = join_ips(false);
+    if !ipv6.is_empty() {
+        environment.push(("MULLVAD_TUNNEL_IPV6", ipv6));
+    }
+    environment
+}
+
+struct HookJob {
+    hooks: Vec<Hook>,
+    environment: Vec<(&'static str, String)>,
+    metadata_rx: Option<oneshot::Receiver<Option<TunnelMetadata>>>,
+}
+
+/// Runs hooks in the background, in the order that the tunnel states were entered.
+pub struct HookRunner {
+    tx: mpsc::UnboundedSender<HookJob>,
+}
+
+impl HookRunner {
+    pub fn new() -> Self {
+        let (tx, mut rx) = mpsc::unbounded::<HookJob>();
+        tokio::spawn(async move {
+            while let Some(job) = rx.next().await {
+                let mut environment = job.environment;
+                if let Some(metadata_rx) = job.metadata_rx {
+                    if let Ok(Some(metadata)) = metadata_rx.await {
+                        environment.extend(metadata_environment(&metadata));
+                    }
+                }
+                for hook in &job.hooks {
+                    if let Err(error) = run_hook(hook, &environment).await {
+                        log::error!(
+                            "{}",
+                            error.display_chain_with_msg(&format!(
+                                "Hook {} failed",
+                                hook.path.display()
+                            ))
+                        );
+                    }
+                }
+            }
+        });
+        HookRunner { tx }
+    }
+
+    /// Queues the hooks to run. The tunnel interface and addresses are added to the environment
+    /// if `metadata_rx` receives them.
+    pub fn run(
+        &self,
+        hooks: Vec<Hook>,
+        environment: Vec<(&'static str, String)>,
+        metadata_rx: Option<oneshot::Receiver<Option<TunnelMetadata>>>,
+    ) {
+        let job = HookJob {
+            hooks,
+            environment,
+            metadata_rx,
+        };
+        if self.tx.unbounded_send(job).is_err() {
+            log::error!("Hook runner has stopped");
+        }
+    }
+}
+
+async fn run_hook(hook: &Hook, environment: &[(&'static str, String)]) -> Result<(), Error> {
+    let path = validate(&hook.path)?;
+    log::debug!("Running hook {}", hook);
+
+    let child = Command::new(&path)
+        .args(&hook.args)
+        .env_clear()
+        .env("PATH", HOOK_PATH)
+        .envs(environment.iter().map(|(key, value)| (*key, value)))
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(Error::Spawn)?;
+
+    let output = tokio::time::timeout(hook.timeout(), child.wait_with_output())
+        .await
+        .map_err(|_| Error::Timeout(hook.timeout().as_secs()))?
+        .map_err(Error::Wait)?;
+
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        log::info!("[{}] {}", path.display(), line);
+    }
+    for line in String::from_utf8_lossy(&output.stderr).lines() {
+        log::warn!("[{}] {}", path.display(), line);
+    }
+
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(Error::Failed(output.status))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_check_ownership() {
+        let path = Path::new("/usr/local/bin/hook");
+        assert!(check_ownership(path, 0, 0o100755).is_ok());
+        assert!(check_ownership(path, 0, 0o040700).is_ok());
+        assert!(matches!(
+            check_ownership(path, 1000, 0o100755),
+            Err(Error::NotOwnedByRoot(_))
+        ));
+        assert!(matches!(
+            check_ownership(path, 0, 0o100775),
+            Err(Error::WritableByOthers(_))
+        ));
+        assert!(matches!(
+            check_ownership(path, 0, 0o041777),
+            Err(Error::WritableByOthers(_))
+        ));
+    }
+
+    #[test]
+    fn test_metadata_environment() {
+        let metadata = TunnelMetadata {
+            interface: "wg-mullvad".to_owned(),
+            ips: vec![
+                "10.64.0.2".parse().unwrap(),
+                "fc00:bbbb:bbbb:bb01::2".parse().unwrap(),
+            ],
+            ipv4_gateway: "10.64.0.1".parse().unwrap(),
+            ipv6_gateway: None,
+        };
+        assert_eq!(
+            metadata_environment(&metadata),
+            vec![
+                ("MULLVAD_TUNNEL_INTERFACE", "wg-mullvad".to_owned()),
+                ("MULLVAD_TUNNEL_IPV4", "10.64.0.2".to_owned()),
+                ("MULLVAD_TUNNEL_IPV6", "fc00:bbbb:bbbb:bb01::2".to_owned()),
+            ]
+        );
+    }
+}
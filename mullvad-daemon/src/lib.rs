@@ -8,10 +8,15 @@ pub mod account_history;
 mod api;
 #[cfg(not(target_os = "android"))]
 mod cleanup;
+mod connection_history;
 pub mod device;
 mod dns;
+#[cfg(target_os = "linux")]
+pub mod early_boot_firewall;
 pub mod exception_logging;
 mod geoip;
+#[cfg(target_os = "linux")]
+mod hooks;
 pub mod logging;
 #[cfg(target_os = "macos")]
 mod macos;
@@ -23,11 +28,15 @@ pub mod rpc_uniqueness_check;
 pub mod runtime;
 pub mod settings;
 mod target_state;
+#[cfg(target_os = "linux")]
+mod trusted_networks;
 mod tunnel;
 pub mod version;
 mod version_check;
 
 use crate::target_state::PersistentTargetState;
+#[cfg(target_os = "linux")]
+use chrono::{DateTime, Utc};
 use device::{PrivateAccountAndDevice, PrivateDeviceEvent};
 use futures::{
     channel::{mpsc, oneshot},
@@ -38,8 +47,11 @@ use mullvad_relay_selector::{
     updater::{RelayListUpdater, RelayListUpdaterHandle},
     RelaySelector, SelectorConfig,
 };
+#[cfg(target_os = "linux")]
+use mullvad_types::hooks::Hook;
 use mullvad_types::{
     account::{AccountData, AccountToken, VoucherSubmission},
+    connection_history::ConnectionEntry,
     device::{Device, DeviceEvent, DeviceEventCause, DeviceId, DeviceState, RemoveDeviceEvent},
     location::GeoIpLocation,
     relay_constraints::{BridgeSettings, BridgeState, ObfuscationSettings, RelaySettingsUpdate},
@@ -50,10 +62,12 @@ use mullvad_types::{
     wireguard::{PublicKey, RotationInterval},
 };
 use settings::SettingsPersister;
+#[cfg(any(windows, target_os = "linux"))]
+use std::collections::HashSet;
+#[cfg(target_os = "windows")]
+use std::ffi::OsString;
 #[cfg(target_os = "android")]
 use std::os::unix::io::RawFd;
-#[cfg(target_os = "windows")]
-use std::{collections::HashSet, ffi::OsString};
 use std::{
     marker::PhantomData,
     mem,
@@ -64,12 +78,33 @@ use std::{
 };
 #[cfg(any(target_os = "linux", windows))]
 use talpid_core::split_tunnel;
+#[cfg(target_os = "linux")]
+use talpid_core::{
+    captive_portal,
+    dns::{
+        leak_test::{self, LeakTestReport},
+        BlocklistStats, DnsFilter, DnsWarning,
+    },
+    firewall::BlockedConnection,
+};
 use talpid_core::{
+    firewall::{Firewall, RenderedPolicy},
     mpsc::Sender,
-    tunnel_state_machine::{self, TunnelCommand},
+    tunnel_state_machine::{self, FirewallPolicyQuery, TunnelCommand},
 };
 #[cfg(target_os = "android")]
 use talpid_types::android::AndroidContext;
+#[cfg(unix)]
+use talpid_types::net::lan::LanNetworks;
+#[cfg(target_os = "linux")]
+use talpid_types::{
+    cgroup::{ExclusionProfile, SplitTunnelMode},
+    net::{
+        dns::{DnsBlocklist, SplitDnsRule},
+        trusted_network::{NetworkIdentity, TrustedNetwork},
+        ExcludedDestinations, FirewallAllowRule, TrafficStats,
+    },
+};
 use talpid_types::{
     net::{TunnelEndpoint, TunnelType},
     tunnel::{ErrorStateCause, TunnelStateTransition},
@@ -130,7 +165,7 @@ pub enum Error {
     #[error(display = "Unable to initialize split tunneling")]
     InitSplitTunneling(#[error(source)] split_tunnel::Error),
 
-    #[cfg(windows)]
+    #[cfg(any(windows, target_os = "linux"))]
     #[error(display = "Split tunneling error")]
     SplitTunnelError(#[error(source)] split_tunnel::Error),
 
@@ -156,15 +191,43 @@ pub enum Error {
     #[error(display = "Tunnel state machine error")]
     TunnelError(#[error(source)] tunnel_state_machine::Error),
 
+    #[error(display = "No firewall policy matches the query in the current tunnel state")]
+    NoFirewallPolicy,
+
+    #[cfg(target_os = "linux")]
+    #[error(display = "Captive portals can only be unlocked while all traffic is blocked")]
+    NotBlocking,
+
+    #[cfg(target_os = "linux")]
+    #[error(display = "Failed to unlock captive portal")]
+    CaptivePortalError(#[error(source)] captive_portal::Error),
+
+    #[cfg(target_os = "linux")]
+    #[error(display = "Invalid hook")]
+    HookError(#[error(source)] hooks::Error),
+
     #[cfg(target_os = "macos")]
     #[error(display = "Failed to set exclusion group")]
     GroupIdError(#[error(source)] io::Error),
+
+    #[error(display = "Connection history error")]
+    ConnectionHistoryError(#[error(source)] connection_history::Error),
 }
 
 /// Enum representing commands that can be sent to the daemon.
 pub enum DaemonCommand {
     /// Set target state. Does nothing if the daemon already has the state that is being set.
     SetTargetState(oneshot::Sender<bool>, TargetState),
+    /// Disconnect the tunnel, and connect it again after the given duration.
+    PauseTunnel(oneshot::Sender<()>, Duration),
+    /// Return the time left until a paused tunnel is connected again, if it is paused.
+    GetTunnelPause(oneshot::Sender<Option<Duration>>),
+    /// Return the connections that have been made, oldest first.
+    GetConnectionHistory(oneshot::Sender<Vec<ConnectionEntry>>),
+    /// Forget all connections that have been made.
+    ClearConnectionHistory(ResponseTx<(), Error>),
+    /// Enable or disable the connection history. Disabling it clears it.
+    SetConnectionHistory(ResponseTx<(), Error>, bool),
     /// Reconnect the tunnel, if one is connecting/connected.
     Reconnect(oneshot::Sender<bool>),
     /// Request the current state.
@@ -206,10 +269,16 @@ pub enum DaemonCommand {
     UpdateRelaySettings(ResponseTx<(), settings::Error>, RelaySettingsUpdate),
     /// Set the allow LAN setting.
     SetAllowLan(ResponseTx<(), settings::Error>, bool),
+    /// Set the networks that are reachable when LAN access is allowed.
+    #[cfg(unix)]
+    SetLanNetworks(ResponseTx<(), settings::Error>, LanNetworks),
     /// Set the beta program setting.
     SetShowBetaReleases(ResponseTx<(), settings::Error>, bool),
     /// Set the block_when_disconnected setting.
     SetBlockWhenDisconnected(ResponseTx<(), settings::Error>, bool),
+    /// Set the lockdown mode setting.
+    #[cfg(target_os = "linux")]
+    SetLockdownMode(ResponseTx<(), settings::Error>, bool),
     /// Set the auto-connect setting.
     SetAutoConnect(ResponseTx<(), settings::Error>, bool),
     /// Set the mssfix argument for OpenVPN
@@ -222,6 +291,24 @@ pub enum DaemonCommand {
     SetEnableIpv6(ResponseTx<(), settings::Error>, bool),
     /// Set DNS options or servers to use
     SetDnsOptions(ResponseTx<(), settings::Error>, DnsOptions),
+    /// Add a DNS blocklist, or replace the blocklist with the same path
+    #[cfg(target_os = "linux")]
+    SetDnsBlocklist(ResponseTx<(), settings::Error>, DnsBlocklist),
+    /// Remove the DNS blocklist with the given path
+    #[cfg(target_os = "linux")]
+    RemoveDnsBlocklist(ResponseTx<(), settings::Error>, PathBuf),
+    /// Read all DNS blocklists from disk again
+    #[cfg(target_os = "linux")]
+    ReloadDnsBlocklists(oneshot::Sender<()>),
+    /// Return the number of domains and hits of each DNS blocklist
+    #[cfg(target_os = "linux")]
+    GetDnsBlocklistStats(oneshot::Sender<Vec<BlocklistStats>>),
+    /// Add a split DNS rule, or replace the rule for the same domain
+    #[cfg(target_os = "linux")]
+    SetSplitDnsRule(ResponseTx<(), settings::Error>, SplitDnsRule),
+    /// Remove the split DNS rule for the given domain
+    #[cfg(target_os = "linux")]
+    RemoveSplitDnsRule(ResponseTx<(), settings::Error>, String),
     /// Toggle macOS network check leak
     /// Set MTU for wireguard tunnels
     SetWireguardMtu(ResponseTx<(), settings::Error>, Option<u16>),
@@ -244,10 +331,11 @@ pub enum DaemonCommand {
     FactoryReset(ResponseTx<(), Error>),
     /// Request list of processes excluded from the tunnel
     #[cfg(target_os = "linux")]
-    GetSplitTunnelProcesses(ResponseTx<Vec<i32>, split_tunnel::Error>),
-    /// Exclude traffic of a process (PID) from the tunnel
+    GetSplitTunnelProcesses(ResponseTx<Vec<split_tunnel::ProcessInfo>, split_tunnel::Error>),
+    /// Exclude traffic of a process (PID) from the tunnel, optionally along with its descendants,
+    /// on behalf of the user with the given UID
     #[cfg(target_os = "linux")]
-    AddSplitTunnelProcess(ResponseTx<(), split_tunnel::Error>, i32),
+    AddSplitTunnelProcess(ResponseTx<(), split_tunnel::Error>, i32, bool, u32),
     /// Remove process (PID) from list of processes excluded from the tunnel
     #[cfg(target_os = "linux")]
     RemoveSplitTunnelProcess(ResponseTx<(), split_tunnel::Error>, i32),
@@ -255,17 +343,30 @@ pub enum DaemonCommand {
     #[cfg(target_os = "linux")]
     ClearSplitTunnelProcesses(ResponseTx<(), split_tunnel::Error>),
     /// Exclude traffic of an application from the tunnel
-    #[cfg(windows)]
+    #[cfg(any(windows, target_os = "linux"))]
     AddSplitTunnelApp(ResponseTx<(), Error>, PathBuf),
     /// Remove application from list of apps to exclude from the tunnel
-    #[cfg(windows)]
+    #[cfg(any(windows, target_os = "linux"))]
     RemoveSplitTunnelApp(ResponseTx<(), Error>, PathBuf),
     /// Clear list of apps to exclude from the tunnel
-    #[cfg(windows)]
+    #[cfg(any(windows, target_os = "linux"))]
     ClearSplitTunnelApps(ResponseTx<(), Error>),
     /// Disable split tunnel
-    #[cfg(windows)]
+    #[cfg(any(windows, target_os = "linux"))]
     SetSplitTunnelState(ResponseTx<(), Error>, bool),
+    /// Set whether split tunneled processes are excluded from or the only ones included in the
+    /// tunnel
+    #[cfg(target_os = "linux")]
+    SetSplitTunnelMode(ResponseTx<(), settings::Error>, SplitTunnelMode),
+    /// Set the destinations that are excluded from the tunnel
+    #[cfg(target_os = "linux")]
+    SetSplitTunnelDestinations(ResponseTx<(), settings::Error>, ExcludedDestinations),
+    /// Add an exclusion profile, or replace the profile with the same name
+    #[cfg(target_os = "linux")]
+    SetExclusionProfile(ResponseTx<(), Error>, ExclusionProfile),
+    /// Remove the exclusion profile with the given name
+    #[cfg(target_os = "linux")]
+    RemoveExclusionProfile(ResponseTx<(), Error>, String),
     /// Toggle wireguard-nt on or off
     #[cfg(target_os = "windows")]
     UseWireGuardNt(ResponseTx<(), Error>, bool),
@@ -274,6 +375,47 @@ pub enum DaemonCommand {
     CheckVolumes(ResponseTx<(), Error>),
     /// Register settings for WireGuard obfuscator
     SetObfuscationSettings(ResponseTx<(), settings::Error>, ObfuscationSettings),
+    /// Allow traffic matching a rule regardless of the tunnel state
+    #[cfg(target_os = "linux")]
+    AddFirewallAllowRule(ResponseTx<(), settings::Error>, FirewallAllowRule),
+    /// Remove a rule from the list of allowed traffic
+    #[cfg(target_os = "linux")]
+    RemoveFirewallAllowRule(ResponseTx<(), settings::Error>, FirewallAllowRule),
+    /// Clear the list of allowed traffic
+    #[cfg(target_os = "linux")]
+    ClearFirewallAllowRules(ResponseTx<(), settings::Error>),
+    /// Render the rules of a firewall policy without applying them
+    GetFirewallRules(ResponseTx<RenderedPolicy, Error>, FirewallPolicyQuery),
+    /// Return the destinations that the firewall most recently blocked traffic to
+    #[cfg(target_os = "linux")]
+    GetBlockedConnections(oneshot::Sender<Vec<BlockedConnection>>),
+    /// Check that DNS queries are answered by the expected resolvers, using the given test
+    /// domain. Returns `None` unless DNS is set by the daemon.
+    #[cfg(target_os = "linux")]
+    RunDnsLeakTest(oneshot::Sender<Option<LeakTestReport>>, String),
+    /// Disconnect the tunnel automatically while on a network matching this rule
+    #[cfg(target_os = "linux")]
+    AddTrustedNetwork(ResponseTx<(), settings::Error>, TrustedNetwork),
+    /// Remove a rule from the list of trusted networks
+    #[cfg(target_os = "linux")]
+    RemoveTrustedNetwork(ResponseTx<(), settings::Error>, TrustedNetwork),
+    /// Return the identity of the networks that the host is connected to, if it is known
+    #[cfg(target_os = "linux")]
+    GetNetworkIdentity(oneshot::Sender<Option<NetworkIdentity>>),
+    /// Run an executable when the tunnel state changes
+    #[cfg(target_os = "linux")]
+    AddHook(ResponseTx<(), Error>, Hook),
+    /// Remove all hooks that run the executable at the given path
+    #[cfg(target_os = "linux")]
+    RemoveHook(ResponseTx<(), Error>, PathBuf),
+    /// Allow traffic to any captive portal for the given duration, while all other traffic is
+    /// blocked
+    #[cfg(target_os = "linux")]
+    UnlockCaptivePortal(
+        ResponseTx<captive_portal::UnlockStatus, Error>,
+        Duration,
+        Option<String>,
+    ),
     /// Makes the daemon exit the main loop and quit.
     Shutdown,
     /// Saves the target tunnel state and enters a blocking state. The state is restored
@@ -300,9 +442,17 @@ pub(crate) enum InternalDaemonEvent {
     /// The split tunnel paths or state were updated.
     #[cfg(target_os = "windows")]
     ExcludedPathsEvent(ExcludedPathsUpdate, oneshot::Sender<Result<(), Error>>),
+    /// The networks that the host is connected to changed.
+    #[cfg(target_os = "linux")]
+    NetworkIdentityChanged(NetworkIdentity),
+    /// The time that the tunnel was paused for has passed.
+    PauseEnded,
+    /// The number of bytes transferred during the connection that started at the given time.
+    #[cfg(target_os = "linux")]
+    ConnectionTraffic(DateTime<Utc>, TrafficStats),
 }
 
-#[cfg(target_os = "windows")]
+#[cfg(any(windows, target_os = "linux"))]
 pub(crate) enum ExcludedPathsUpdate {
     SetState(bool),
     SetPaths(HashSet<PathBuf>),
@@ -332,6 +482,13 @@ impl From<PrivateDeviceEvent> for InternalDaemonEvent {
     }
 }
 
+#[cfg(target_os = "linux")]
+impl From<NetworkIdentity> for InternalDaemonEvent {
+    fn from(identity: NetworkIdentity) -> Self {
+        InternalDaemonEvent::NetworkIdentityChanged(identity)
+    }
+}
+
 #[derive(Clone, Debug, Eq, PartialEq)]
 enum DaemonExecutionState {
     Running,
@@ -498,18 +655,34 @@ pub trait EventListener {
 
     /// Notify that a device was revoked using `RemoveDevice`.
     fn notify_remove_device_event(&self, event: RemoveDeviceEvent);
+
+    /// Notify that the system DNS manager did not apply the DNS settings.
+    #[cfg(target_os = "linux")]
+    fn notify_dns_warning(&self, warning: DnsWarning);
 }
 
 pub struct Daemon<L: EventListener> {
     tunnel_command_tx: Arc<mpsc::UnboundedSender<TunnelCommand>>,
     tunnel_state: TunnelState,
     target_state: PersistentTargetState,
+    connection_history: connection_history::ConnectionHistory,
     state: DaemonExecutionState,
     #[cfg(target_os = "linux")]
     exclude_pids: split_tunnel::PidManager,
+    #[cfg(target_os = "linux")]
+    exclude_apps: split_tunnel::AppMonitor,
+    #[cfg(target_os = "linux")]
+    exclude_destinations: split_tunnel::DestinationMonitor,
+    #[cfg(target_os = "linux")]
+    dns_filter: DnsFilter,
+    #[cfg(target_os = "linux")]
+    trusted_networks: trusted_networks::TrustedNetworkPolicy,
+    #[cfg(target_os = "linux")]
+    hook_runner: hooks::HookRunner,
     rx: mpsc::UnboundedReceiver<InternalDaemonEvent>,
     tx: DaemonEventSender,
     reconnection_job: Option<AbortHandle>,
+    resume_job: Option<AbortHandle>,
     event_listener: L,
     migration_complete: migrations::MigrationComplete,
     settings: SettingsPersister,
@@ -623,6 +796,10 @@ where
             PersistentTargetState::new(&cache_dir).await
         };
 
+        let connection_history =
+            connection_history::ConnectionHistory::new(&cache_dir, settings.connection_history)
+                .await;
+
         #[cfg(windows)]
         let exclude_paths = if settings.split_tunnel.enable_exclusions {
             settings
@@ -643,23 +820,71 @@ where
             settings.tunnel_options.clone(),
         );
         let (offline_state_tx, offline_state_rx) = mpsc::unbounded();
+        #[cfg(target_os = "linux")]
+        let (dns_warning_tx, mut dns_warning_rx) = mpsc::unbounded();
+        #[cfg(target_os = "linux")]
+        let (network_identity_tx, mut network_identity_rx) = mpsc::unbounded();
         #[cfg(target_os = "windows")]
         let (volume_update_tx, volume_update_rx) = mpsc::unbounded();
+        // The split tunneling cgroup must exist before the firewall policy refers to it
+        #[cfg(target_os = "linux")]
+        let exclude_pids = split_tunnel::PidManager::new().map_err(Error::InitSplitTunneling)?;
+        #[cfg(target_os = "linux")]
+        if let Err(error) = exclude_pids.set_profiles(&settings.split_tunnel.exclusion_profiles) {
+            log::error!(
+                "{}",
+                error.display_chain_with_msg("Failed to set up exclusion profiles")
+            );
+        }
+        #[cfg(target_os = "linux")]
+        let exclude_apps = split_tunnel::AppMonitor::new(exclude_pids.clone());
+        #[cfg(target_os = "linux")]
+        if settings.split_tunnel.enable_exclusions {
+            exclude_apps.set_paths(&settings.split_tunnel.apps);
+        }
+        #[cfg(target_os = "linux")]
+        let dns_filter = DnsFilter::new();
+        #[cfg(target_os = "linux")]
+        dns_filter.set_blocklists(settings.tunnel_options.dns_options.blocklists.clone());
+        #[cfg(target_os = "linux")]
+        dns_filter.set_encrypted_servers(dns::encrypted_servers_from_options(
+            &settings.tunnel_options.dns_options,
+        ));
+        #[cfg(target_os = "linux")]
+        dns_filter.set_split_dns(settings.tunnel_options.dns_options.split_dns.clone());
         let (tunnel_command_tx, tunnel_state_machine_handle) = tunnel_state_machine::spawn(
             tunnel_state_machine::InitialTunnelState {
                 allow_lan: settings.allow_lan,
-                block_when_disconnected: settings.block_when_disconnected,
+                #[cfg(unix)]
+                lan_networks: settings.lan_networks.clone(),
+                block_when_disconnected: block_when_disconnected(&settings),
                 dns_servers: dns::addresses_from_options(&settings.tunnel_options.dns_options),
                 allowed_endpoint: initial_api_endpoint,
                 reset_firewall: *target_state != TargetState::Secured,
                 #[cfg(windows)]
                 exclude_paths,
+                #[cfg(target_os = "linux")]
+                allow_rules: settings.firewall_allow_rules.clone(),
+                #[cfg(target_os = "linux")]
+                split_tunnel_mode: split_tunnel_mode(&settings),
+                #[cfg(target_os = "linux")]
+                exclusion_profiles: settings.split_tunnel.exclusion_profiles.clone(),
+                #[cfg(target_os = "linux")]
+                excluded_destinations: excluded_destinations(&settings),
+                #[cfg(target_os = "linux")]
+                dns_filter: dns_filter.clone(),
+                #[cfg(target_os = "linux")]
+                split_dns: settings.tunnel_options.dns_options.split_dns.clone(),
             },
             parameters_generator.clone(),
             log_dir,
             resource_dir.clone(),
             internal_event_tx.to_specialized_sender(),
             offline_state_tx,
+            #[cfg(target_os = "linux")]
+            dns_warning_tx,
+            #[cfg(target_os = "linux")]
+            network_identity_tx,
             #[cfg(target_os = "windows")]
             volume_update_rx,
             #[cfg(target_os = "macos")]
@@ -672,8 +897,41 @@ where
 
         endpoint_updater.set_tunnel_command_tx(Arc::downgrade(&tunnel_command_tx));
 
+        #[cfg(target_os = "linux")]
+        let exclude_destinations = {
+            let tunnel_command_tx = Arc::downgrade(&tunnel_command_tx);
+            split_tunnel::DestinationMonitor::new(
+                excluded_destinations(&settings),
+                move |destinations| {
+                    if let Some(tunnel_command_tx) = tunnel_command_tx.upgrade() {
+                        let _ = tunnel_command_tx
+                            .unbounded_send(TunnelCommand::SetExcludedDestinations(destinations));
+                    }
+                },
+            )
+        };
+
         api::forward_offline_state(api_availability.clone(), offline_state_rx);
 
+        #[cfg(target_os = "linux")]
+        {
+            let dns_warning_listener = event_listener.clone();
+            tokio::spawn(async move {
+                while let Some(warning) = dns_warning_rx.next().await {
+                    dns_warning_listener.notify_dns_warning(warning);
+                }
+            });
+
+            let network_identity_sender = internal_event_tx.to_specialized_sender();
+            tokio::spawn(async move {
+                while let Some(identity) = network_identity_rx.next().await {
+                    if network_identity_sender.send(identity).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+
         let relay_list_listener = event_listener.clone();
         let on_relay_list_update = move |relay_list: &RelayList| {
             relay_list_listener.notify_relay_list(relay_list.clone());
@@ -704,12 +962,24 @@ where
             tunnel_command_tx,
             tunnel_state: TunnelState::Disconnected,
             target_state,
+            connection_history,
             state: DaemonExecutionState::Running,
             #[cfg(target_os = "linux")]
-            exclude_pids: split_tunnel::PidManager::new().map_err(Error::InitSplitTunneling)?,
+            exclude_pids,
+            #[cfg(target_os = "linux")]
+            exclude_apps,
+            #[cfg(target_os = "linux")]
+            exclude_destinations,
+            #[cfg(target_os = "linux")]
+            dns_filter,
+            #[cfg(target_os = "linux")]
+            trusted_networks: trusted_networks::TrustedNetworkPolicy::default(),
+            #[cfg(target_os = "linux")]
+            hook_runner: hooks::HookRunner::new(),
             rx: internal_event_rx,
             tx: internal_event_tx,
             reconnection_job: None,
+            resume_job: None,
             event_listener,
             migration_complete,
             settings,
@@ -740,6 +1010,9 @@ where
         if *self.target_state == TargetState::Secured {
             self.connect_tunnel();
         }
+        if let Some(remaining) = self.target_state.pause_remaining() {
+            self.schedule_resume(remaining);
+        }
 
         while let Some(event) = self.rx.next().await {
             self.handle_event(event).await;
@@ -825,6 +1098,13 @@ where
             DeviceMigrationEvent(event) => self.handle_device_migration_event(event).await,
             #[cfg(windows)]
             ExcludedPathsEvent(update, tx) => self.handle_new_excluded_paths(update, tx).await,
+            #[cfg(target_os = "linux")]
+            NetworkIdentityChanged(identity) => self.handle_network_identity_change(identity).await,
+            PauseEnded => self.handle_pause_ended().await,
+            #[cfg(target_os = "linux")]
+            ConnectionTraffic(started, traffic) => {
+                self.connection_history.set_traffic(started, traffic).await
+            }
         }
     }
 
@@ -862,6 +1142,19 @@ where
 
         log::debug!("New tunnel state: {:?}", tunnel_state);
 
+        #[cfg_attr(not(target_os = "linux"), allow(unused_variables))]
+        let ended_connection = self
+            .connection_history
+            .handle_transition(&tunnel_state)
+            .await;
+        #[cfg(target_os = "linux")]
+        if let Some(started) = ended_connection {
+            self.request_connection_traffic(started);
+        }
+
+        #[cfg(target_os = "linux")]
+        self.run_hooks(&tunnel_state);
+
         match tunnel_state {
             TunnelState::Disconnected => {
                 self.api_handle.availability.reset_inactivity_timer();
@@ -933,6 +1226,25 @@ where
         }
     }
 
+    fn schedule_resume(&mut self, delay: Duration) {
+        self.unschedule_resume();
+
+        let event_tx = self.tx.clone();
+        let (future, abort_handle) = abortable(Box::pin(async move {
+            talpid_time::sleep(delay).await;
+            let _ = event_tx.send(InternalDaemonEvent::PauseEnded);
+        }));
+
+        tokio::spawn(future);
+        self.resume_job = Some(abort_handle);
+    }
+
+    fn unschedule_resume(&mut self) {
+        if let Some(job) = self.resume_job.take() {
+            job.abort();
+        }
+    }
+
     async fn handle_command(&mut self, command: DaemonCommand) {
         use self::DaemonCommand::*;
         if !self.state.is_running() {
@@ -946,6 +1258,15 @@ where
 
         match command {
             SetTargetState(tx, state) => self.on_set_target_state(tx, state).await,
+            PauseTunnel(tx, duration) => self.on_pause_tunnel(tx, duration).await,
+            GetTunnelPause(tx) => {
+                Self::oneshot_send(tx, self.target_state.pause_remaining(), "tunnel pause")
+            }
+            GetConnectionHistory(tx) => {
+                Self::oneshot_send(tx, self.connection_history.entries(), "connection history")
+            }
+            ClearConnectionHistory(tx) => self.on_clear_connection_history(tx).await,
+            SetConnectionHistory(tx, enabled) => self.on_set_connection_history(tx, enabled).await,
             Reconnect(tx) => self.on_reconnect(tx),
             GetState(tx) => self.on_get_state(tx),
             GetCurrentLocation(tx) => self.on_get_current_location(tx).await,
@@ -967,11 +1288,17 @@ where
             ClearAccountHistory(tx) => self.on_clear_account_history(tx).await,
             UpdateRelaySettings(tx, update) => self.on_update_relay_settings(tx, update).await,
             SetAllowLan(tx, allow_lan) => self.on_set_allow_lan(tx, allow_lan).await,
+            #[cfg(unix)]
+            SetLanNetworks(tx, lan_networks) => self.on_set_lan_networks(tx, lan_networks).await,
             SetShowBetaReleases(tx, enabled) => self.on_set_show_beta_releases(tx, enabled).await,
             SetBlockWhenDisconnected(tx, block_when_disconnected) => {
                 self.on_set_block_when_disconnected(tx, block_when_disconnected)
                     .await
             }
+            #[cfg(target_os = "linux")]
+            SetLockdownMode(tx, lockdown_mode) => {
+                self.on_set_lockdown_mode(tx, lockdown_mode).await
+            }
             SetAutoConnect(tx, auto_connect) => self.on_set_auto_connect(tx, auto_connect).await,
             SetOpenVpnMssfix(tx, mssfix_arg) => self.on_set_openvpn_mssfix(tx, mssfix_arg).await,
             SetBridgeSettings(tx, bridge_settings) => {
@@ -980,6 +1307,22 @@ where
             SetBridgeState(tx, bridge_state) => self.on_set_bridge_state(tx, bridge_state).await,
             SetEnableIpv6(tx, enable_ipv6) => self.on_set_enable_ipv6(tx, enable_ipv6).await,
             SetDnsOptions(tx, dns_servers) => self.on_set_dns_options(tx, dns_servers).await,
+            #[cfg(target_os = "linux")]
+            SetDnsBlocklist(tx, blocklist) => self.on_set_dns_blocklist(tx, blocklist).await,
+            #[cfg(target_os = "linux")]
+            RemoveDnsBlocklist(tx, path) => self.on_remove_dns_blocklist(tx, path).await,
+            #[cfg(target_os = "linux")]
+            ReloadDnsBlocklists(tx) => self.on_reload_dns_blocklists(tx),
+            #[cfg(target_os = "linux")]
+            GetDnsBlocklistStats(tx) => Self::oneshot_send(
+                tx,
+                self.dns_filter.stats(),
+                "get_dns_blocklist_stats response",
+            ),
+            #[cfg(target_os = "linux")]
+            SetSplitDnsRule(tx, rule) => self.on_set_split_dns_rule(tx, rule).await,
+            #[cfg(target_os = "linux")]
+            RemoveSplitDnsRule(tx, domain) => self.on_remove_split_dns_rule(tx, domain).await,
             SetWireguardMtu(tx, mtu) => self.on_set_wireguard_mtu(tx, mtu).await,
             SetWireguardRotationInterval(tx, interval) => {
                 self.on_set_wireguard_rotation_interval(tx, interval).await
@@ -995,19 +1338,32 @@ where
             #[cfg(target_os = "linux")]
             GetSplitTunnelProcesses(tx) => self.on_get_split_tunnel_processes(tx),
             #[cfg(target_os = "linux")]
-            AddSplitTunnelProcess(tx, pid) => self.on_add_split_tunnel_process(tx, pid),
+            AddSplitTunnelProcess(tx, pid, with_descendants, uid) => {
+                self.on_add_split_tunnel_process(tx, pid, with_descendants, uid)
+            }
             #[cfg(target_os = "linux")]
             RemoveSplitTunnelProcess(tx, pid) => self.on_remove_split_tunnel_process(tx, pid),
             #[cfg(target_os = "linux")]
             ClearSplitTunnelProcesses(tx) => self.on_clear_split_tunnel_processes(tx),
-            #[cfg(windows)]
+            #[cfg(any(windows, target_os = "linux"))]
             AddSplitTunnelApp(tx, path) => self.on_add_split_tunnel_app(tx, path).await,
-            #[cfg(windows)]
+            #[cfg(any(windows, target_os = "linux"))]
             RemoveSplitTunnelApp(tx, path) => self.on_remove_split_tunnel_app(tx, path).await,
-            #[cfg(windows)]
+            #[cfg(any(windows, target_os = "linux"))]
             ClearSplitTunnelApps(tx) => self.on_clear_split_tunnel_apps(tx).await,
-            #[cfg(windows)]
+            #[cfg(any(windows, target_os = "linux"))]
             SetSplitTunnelState(tx, enabled) => self.on_set_split_tunnel_state(tx, enabled).await,
+            #[cfg(target_os = "linux")]
+            SetSplitTunnelMode(tx, mode) => self.on_set_split_tunnel_mode(tx, mode).await,
+            #[cfg(target_os = "linux")]
+            SetSplitTunnelDestinations(tx, destinations) => {
+                self.on_set_split_tunnel_destinations(tx, destinations)
+                    .await
+            }
+            #[cfg(target_os = "linux")]
+            SetExclusionProfile(tx, profile) => self.on_set_exclusion_profile(tx, profile).await,
+            #[cfg(target_os = "linux")]
+            RemoveExclusionProfile(tx, name) => self.on_remove_exclusion_profile(tx, name).await,
             #[cfg(target_os = "windows")]
             UseWireGuardNt(tx, state) => self.on_use_wireguard_nt(tx, state).await,
             #[cfg(target_os = "windows")]
@@ -1015,6 +1371,37 @@ where
             SetObfuscationSettings(tx, settings) => {
                 self.on_set_obfuscation_settings(tx, settings).await
             }
+            #[cfg(target_os = "linux")]
+            AddFirewallAllowRule(tx, rule) => self.on_add_firewall_allow_rule(tx, rule).await,
+            #[cfg(target_os = "linux")]
+            RemoveFirewallAllowRule(tx, rule) => self.on_remove_firewall_allow_rule(tx, rule).await,
+            #[cfg(target_os = "linux")]
+            ClearFirewallAllowRules(tx) => self.on_clear_firewall_allow_rules(tx).await,
+            GetFirewallRules(tx, query) => self.on_get_firewall_rules(tx, query),
+            #[cfg(target_os = "linux")]
+            GetBlockedConnections(tx) => {
+                self.send_tunnel_command(TunnelCommand::GetBlockedConnections(tx))
+            }
+            #[cfg(target_os = "linux")]
+            RunDnsLeakTest(tx, test_domain) => self.on_run_dns_leak_test(tx, test_domain),
+            #[cfg(target_os = "linux")]
+            AddTrustedNetwork(tx, network) => self.on_add_trusted_network(tx, network).await,
+            #[cfg(target_os = "linux")]
+            RemoveTrustedNetwork(tx, network) => self.on_remove_trusted_network(tx, network).await,
+            #[cfg(target_os = "linux")]
+            GetNetworkIdentity(tx) => Self::oneshot_send(
+                tx,
+                self.trusted_networks.identity().cloned(),
+                "network identity",
+            ),
+            #[cfg(target_os = "linux")]
+            AddHook(tx, hook) => self.on_add_hook(tx, hook).await,
+            #[cfg(target_os = "linux")]
+            RemoveHook(tx, path) => self.on_remove_hook(tx, path).await,
+            #[cfg(target_os = "linux")]
+            UnlockCaptivePortal(tx, duration, probe_url) => {
+                self.on_unlock_captive_portal(tx, duration, probe_url)
+            }
             Shutdown => self.trigger_shutdown_event(),
             PrepareRestart => self.on_prepare_restart(),
             #[cfg(target_os = "android")]
@@ -1102,7 +1489,7 @@ where
         });
     }
 
-    #[cfg(windows)]
+    #[cfg(any(windows, target_os = "linux"))]
     async fn handle_new_excluded_paths(
         &mut self,
         update: ExcludedPathsUpdate,
@@ -1123,6 +1510,20 @@ where
         let changed = *save_result.as_ref().unwrap_or(&false);
         let _ = tx.send(save_result.map(|_| ()));
         if changed {
+            #[cfg(target_os = "linux")]
+            {
+                let settings = self.settings.to_settings();
+                if settings.split_tunnel.enable_exclusions {
+                    self.exclude_apps.set_paths(&settings.split_tunnel.apps);
+                } else {
+                    self.exclude_apps.set_paths(&HashSet::<PathBuf>::new());
+                }
+                self.exclude_destinations
+                    .set_destinations(excluded_destinations(&settings));
+                self.send_tunnel_command(TunnelCommand::SetSplitTunnelMode(split_tunnel_mode(
+                    &settings,
+                )));
+            }
             self.event_listener
                 .notify_settings(self.settings.to_settings());
         }
@@ -1134,6 +1535,8 @@ where
         new_target_state: TargetState,
     ) {
         if self.state.is_running() {
+            #[cfg(target_os = "linux")]
+            self.trusted_networks.user_override(new_target_state);
             let state_change_initated = self.set_target_state(new_target_state).await;
             Self::oneshot_send(tx, state_change_initated, "state change initiated");
         } else {
@@ -1141,6 +1544,76 @@ where
         }
     }
 
+    async fn on_pause_tunnel(&mut self, tx: oneshot::Sender<()>, duration: Duration) {
+        if self.state.is_running() {
+            #[cfg(target_os = "linux")]
+            self.trusted_networks.user_override(TargetState::Unsecured);
+            self.set_target_state(TargetState::Unsecured).await;
+            self.target_state.pause(duration).await;
+            self.schedule_resume(duration);
+            log::info!("Pausing the tunnel for {} seconds", duration.as_secs());
+            Self::oneshot_send(tx, (), "pause_tunnel response");
+        } else {
+            log::warn!("Ignoring pause request due to shutdown");
+        }
+    }
+
+    async fn handle_pause_ended(&mut self) {
+        self.resume_job = None;
+        if !self.state.is_running() || self.target_state.pause_remaining().is_none() {
+            return;
+        }
+        log::info!("Connecting since the tunnel pause ended");
+        self.set_target_state(TargetState::Secured).await;
+    }
+
+    /// Adds the bytes transferred to the history entry of a connection that just ended, once the
+    /// tunnel state machine has reported them.
+    #[cfg(target_os = "linux")]
+    fn request_connection_traffic(&mut self, started: DateTime<Utc>) {
+        let (traffic_tx, traffic_rx) = oneshot::channel();
+        self.send_tunnel_command(TunnelCommand::GetTrafficStats(traffic_tx));
+
+        let event_tx = self.tx.clone();
+        tokio::spawn(async move {
+            if let Ok(Some(traffic)) = traffic_rx.await {
+                let _ = event_tx.send(InternalDaemonEvent::ConnectionTraffic(started, traffic));
+            }
+        });
+    }
+
+    async fn on_clear_connection_history(&mut self, tx: ResponseTx<(), Error>) {
+        let result = self
+            .connection_history
+            .clear()
+            .await
+            .map_err(Error::ConnectionHistoryError);
+        if let Err(error) = &result {
+            log::error!("{}", error.display_chain());
+        }
+        Self::oneshot_send(tx, result, "clear_connection_history response");
+    }
+
+    async fn on_set_connection_history(&mut self, tx: ResponseTx<(), Error>, enabled: bool) {
+        let result = match self.settings.set_connection_history(enabled).await {
+            Ok(settings_changed) => {
+                if settings_changed {
+                    self.event_listener
+                        .notify_settings(self.settings.to_settings());
+                }
+                self.connection_history
+                    .set_enabled(enabled)
+                    .await
+                    .map_err(Error::ConnectionHistoryError)
+            }
+            Err(error) => Err(Error::SettingsError(error)),
+        };
+        if let Err(error) = &result {
+            log::error!("{}", error.display_chain());
+        }
+        Self::oneshot_send(tx, result, "set_connection_history response");
+    }
+
     fn on_reconnect(&mut self, tx: oneshot::Sender<bool>) {
         if *self.target_state == TargetState::Secured || self.tunnel_state.is_in_error_state() {
             self.connect_tunnel();
@@ -1504,8 +1977,11 @@ where
     }
 
     #[cfg(target_os = "linux")]
-    fn on_get_split_tunnel_processes(&mut self, tx: ResponseTx<Vec<i32>, split_tunnel::Error>) {
-        let result = self.exclude_pids.list().map_err(|error| {
+    fn on_get_split_tunnel_processes(
+        &mut self,
+        tx: ResponseTx<Vec<split_tunnel::ProcessInfo>, split_tunnel::Error>,
+    ) {
+        let result = self.exclude_pids.list_processes().map_err(|error| {
             log::error!("{}", error.display_chain_with_msg("Unable to obtain PIDs"));
             error
         });
@@ -1513,8 +1989,19 @@ where
     }
 
     #[cfg(target_os = "linux")]
-    fn on_add_split_tunnel_process(&mut self, tx: ResponseTx<(), split_tunnel::Error>, pid: i32) {
-        let result = self.exclude_pids.add(pid).map_err(|error| {
+    fn on_add_split_tunnel_process(
+        &mut self,
+        tx: ResponseTx<(), split_tunnel::Error>,
+        pid: i32,
+        with_descendants: bool,
+        uid: u32,
+    ) {
+        let result = if with_descendants {
+            self.exclude_pids.add_with_descendants(pid, uid)
+        } else {
+            self.exclude_pids.add_for_user(pid, uid)
+        };
+        let result = result.map_err(|error| {
             log::error!("{}", error.display_chain_with_msg("Unable to add PID"));
             error
         });
@@ -1610,13 +2097,14 @@ where
         }
     }
 
-    #[cfg(windows)]
+    #[cfg(any(windows, target_os = "linux"))]
     async fn on_add_split_tunnel_app(&mut self, tx: ResponseTx<(), Error>, path: PathBuf) {
         let settings = self.settings.to_settings();
 
         let mut new_list = settings.split_tunnel.apps.clone();
         new_list.insert(path);
 
+        #[cfg(windows)]
         self.set_split_tunnel_paths(
             tx,
             "add_split_tunnel_app response",
@@ -1624,15 +2112,19 @@ where
             ExcludedPathsUpdate::SetPaths(new_list),
         )
         .await;
+        #[cfg(target_os = "linux")]
+        self.handle_new_excluded_paths(ExcludedPathsUpdate::SetPaths(new_list), tx)
+            .await;
     }
 
-    #[cfg(windows)]
+    #[cfg(any(windows, target_os = "linux"))]
     async fn on_remove_split_tunnel_app(&mut self, tx: ResponseTx<(), Error>, path: PathBuf) {
         let settings = self.settings.to_settings();
 
         let mut new_list = settings.split_tunnel.apps.clone();
         new_list.remove(&path);
 
+        #[cfg(windows)]
         self.set_split_tunnel_paths(
             tx,
             "remove_split_tunnel_app response",
@@ -1640,79 +2132,197 @@ where
             ExcludedPathsUpdate::SetPaths(new_list),
         )
         .await;
+        #[cfg(target_os = "linux")]
+        self.handle_new_excluded_paths(ExcludedPathsUpdate::SetPaths(new_list), tx)
+            .await;
     }
 
-    #[cfg(windows)]
+    #[cfg(any(windows, target_os = "linux"))]
     async fn on_clear_split_tunnel_apps(&mut self, tx: ResponseTx<(), Error>) {
-        let settings = self.settings.to_settings();
         let new_list = HashSet::new();
+        #[cfg(windows)]
         self.set_split_tunnel_paths(
             tx,
             "clear_split_tunnel_apps response",
-            settings,
+            self.settings.to_settings(),
             ExcludedPathsUpdate::SetPaths(new_list),
         )
         .await;
+        #[cfg(target_os = "linux")]
+        self.handle_new_excluded_paths(ExcludedPathsUpdate::SetPaths(new_list), tx)
+            .await;
     }
 
-    #[cfg(windows)]
+    #[cfg(any(windows, target_os = "linux"))]
     async fn on_set_split_tunnel_state(&mut self, tx: ResponseTx<(), Error>, state: bool) {
-        let settings = self.settings.to_settings();
+        #[cfg(windows)]
         self.set_split_tunnel_paths(
             tx,
             "set_split_tunnel_state response",
-            settings,
+            self.settings.to_settings(),
             ExcludedPathsUpdate::SetState(state),
         )
         .await;
+        #[cfg(target_os = "linux")]
+        self.handle_new_excluded_paths(ExcludedPathsUpdate::SetState(state), tx)
+            .await;
     }
 
-    #[cfg(windows)]
-    async fn on_use_wireguard_nt(&mut self, tx: ResponseTx<(), Error>, state: bool) {
-        let save_result = self
-            .settings
-            .set_use_wireguard_nt(state)
-            .await
-            .map_err(Error::SettingsError);
-        match save_result {
+    #[cfg(target_os = "linux")]
+    async fn on_set_split_tunnel_mode(
+        &mut self,
+        tx: ResponseTx<(), settings::Error>,
+        mode: SplitTunnelMode,
+    ) {
+        match self.settings.set_split_tunnel_mode(mode).await {
             Ok(settings_changed) => {
-                Self::oneshot_send(tx, Ok(()), "use_wireguard_nt response");
+                Self::oneshot_send(tx, Ok(()), "set_split_tunnel_mode response");
                 if settings_changed {
-                    self.parameters_generator
-                        .set_tunnel_options(&self.settings.tunnel_options);
-                    self.event_listener
-                        .notify_settings(self.settings.to_settings());
-                    if let Some(TunnelType::Wireguard) = self.get_connected_tunnel_type() {
-                        log::info!("Initiating tunnel restart");
-                        self.reconnect_tunnel();
-                    }
+                    let settings = self.settings.to_settings();
+                    self.send_tunnel_command(TunnelCommand::SetSplitTunnelMode(split_tunnel_mode(
+                        &settings,
+                    )));
+                    self.event_listener.notify_settings(settings);
                 }
             }
-            Err(error) => {
-                log::error!(
-                    "{}",
-                    error.display_chain_with_msg("Unable to save settings")
-                );
-                Self::oneshot_send(tx, Err(error), "use_wireguard_nt response");
+            Err(e) => {
+                log::error!("{}", e.display_chain_with_msg("Unable to save settings"));
+                Self::oneshot_send(tx, Err(e), "set_split_tunnel_mode response");
             }
         }
     }
 
-    #[cfg(windows)]
-    async fn on_check_volumes(&mut self, tx: ResponseTx<(), Error>) {
-        if self.volume_update_tx.unbounded_send(()).is_ok() {
-            let _ = tx.send(Ok(()));
+    #[cfg(target_os = "linux")]
+    async fn on_set_exclusion_profile(
+        &mut self,
+        tx: ResponseTx<(), Error>,
+        profile: ExclusionProfile,
+    ) {
+        let mut profiles = self.settings.split_tunnel.exclusion_profiles.clone();
+        match profiles
+            .iter_mut()
+            .find(|existing| existing.name == profile.name)
+        {
+            Some(existing) => *existing = profile,
+            None => profiles.push(profile),
         }
+        let result = self.set_exclusion_profiles(profiles).await;
+        Self::oneshot_send(tx, result, "set_exclusion_profile response");
     }
 
-    async fn on_update_relay_settings(
+    #[cfg(target_os = "linux")]
+    async fn on_remove_exclusion_profile(&mut self, tx: ResponseTx<(), Error>, name: String) {
+        let mut profiles = self.settings.split_tunnel.exclusion_profiles.clone();
+        profiles.retain(|profile| profile.name != name);
+        let result = self.set_exclusion_profiles(profiles).await;
+        Self::oneshot_send(tx, result, "remove_exclusion_profile response");
+    }
+
+    /// Creates the cgroups of the profiles before saving them, since the firewall can only
+    /// refer to existing cgroups.
+    #[cfg(target_os = "linux")]
+    async fn set_exclusion_profiles(
         &mut self,
-        tx: ResponseTx<(), settings::Error>,
-        update: RelaySettingsUpdate,
-    ) {
-        let save_result = self.settings.update_relay_settings(update).await;
-        match save_result {
-            Ok(settings_changed) => {
+        profiles: Vec<ExclusionProfile>,
+    ) -> Result<(), Error> {
+        if profiles == self.settings.split_tunnel.exclusion_profiles {
+            return Ok(());
+        }
+        self.exclude_pids.set_profiles(&profiles).map_err(|error| {
+            log::error!(
+                "{}",
+                error.display_chain_with_msg("Unable to set exclusion profiles")
+            );
+            Error::SplitTunnelError(error)
+        })?;
+
+        if let Err(error) = self.settings.set_exclusion_profiles(profiles.clone()).await {
+            log::error!(
+                "{}",
+                error.display_chain_with_msg("Unable to save settings")
+            );
+            return Err(Error::SettingsError(error));
+        }
+
+        self.event_listener
+            .notify_settings(self.settings.to_settings());
+        self.send_tunnel_command(TunnelCommand::SetExclusionProfiles(profiles));
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    async fn on_set_split_tunnel_destinations(
+        &mut self,
+        tx: ResponseTx<(), settings::Error>,
+        destinations: ExcludedDestinations,
+    ) {
+        match self
+            .settings
+            .set_split_tunnel_destinations(destinations)
+            .await
+        {
+            Ok(settings_changed) => {
+                Self::oneshot_send(tx, Ok(()), "set_split_tunnel_destinations response");
+                if settings_changed {
+                    let settings = self.settings.to_settings();
+                    self.exclude_destinations
+                        .set_destinations(excluded_destinations(&settings));
+                    self.event_listener.notify_settings(settings);
+                }
+            }
+            Err(e) => {
+                log::error!("{}", e.display_chain_with_msg("Unable to save settings"));
+                Self::oneshot_send(tx, Err(e), "set_split_tunnel_destinations response");
+            }
+        }
+    }
+
+    #[cfg(windows)]
+    async fn on_use_wireguard_nt(&mut self, tx: ResponseTx<(), Error>, state: bool) {
+        let save_result = self
+            .settings
+            .set_use_wireguard_nt(state)
+            .await
+            .map_err(Error::SettingsError);
+        match save_result {
+            Ok(settings_changed) => {
+                Self::oneshot_send(tx, Ok(()), "use_wireguard_nt response");
+                if settings_changed {
+                    self.parameters_generator
+                        .set_tunnel_options(&self.settings.tunnel_options);
+                    self.event_listener
+                        .notify_settings(self.settings.to_settings());
+                    if let Some(TunnelType::Wireguard) = self.get_connected_tunnel_type() {
+                        log::info!("Initiating tunnel restart");
+                        self.reconnect_tunnel();
+                    }
+                }
+            }
+            Err(error) => {
+                log::error!(
+                    "{}",
+                    error.display_chain_with_msg("Unable to save settings")
+                );
+                Self::oneshot_send(tx, Err(error), "use_wireguard_nt response");
+            }
+        }
+    }
+
+    #[cfg(windows)]
+    async fn on_check_volumes(&mut self, tx: ResponseTx<(), Error>) {
+        if self.volume_update_tx.unbounded_send(()).is_ok() {
+            let _ = tx.send(Ok(()));
+        }
+    }
+
+    async fn on_update_relay_settings(
+        &mut self,
+        tx: ResponseTx<(), settings::Error>,
+        update: RelaySettingsUpdate,
+    ) {
+        let save_result = self.settings.update_relay_settings(update).await;
+        match save_result {
+            Ok(settings_changed) => {
                 Self::oneshot_send(tx, Ok(()), "update_relay_settings response");
                 if settings_changed {
                     self.event_listener
@@ -1748,6 +2358,29 @@ where
         }
     }
 
+    #[cfg(unix)]
+    async fn on_set_lan_networks(
+        &mut self,
+        tx: ResponseTx<(), settings::Error>,
+        lan_networks: LanNetworks,
+    ) {
+        let save_result = self.settings.set_lan_networks(lan_networks.clone()).await;
+        match save_result {
+            Ok(settings_changed) => {
+                Self::oneshot_send(tx, Ok(()), "set_lan_networks response");
+                if settings_changed {
+                    self.event_listener
+                        .notify_settings(self.settings.to_settings());
+                    self.send_tunnel_command(TunnelCommand::SetLanNetworks(lan_networks));
+                }
+            }
+            Err(e) => {
+                log::error!("{}", e.display_chain_with_msg("Unable to save settings"));
+                Self::oneshot_send(tx, Err(e), "set_lan_networks response");
+            }
+        }
+    }
+
     async fn on_set_show_beta_releases(
         &mut self,
         tx: ResponseTx<(), settings::Error>,
@@ -1787,7 +2420,7 @@ where
                     self.event_listener
                         .notify_settings(self.settings.to_settings());
                     self.send_tunnel_command(TunnelCommand::BlockWhenDisconnected(
-                        block_when_disconnected,
+                        block_when_disconnected(&self.settings),
                     ));
                 }
             }
@@ -1798,6 +2431,31 @@ where
         }
     }
 
+    #[cfg(target_os = "linux")]
+    async fn on_set_lockdown_mode(
+        &mut self,
+        tx: ResponseTx<(), settings::Error>,
+        lockdown_mode: bool,
+    ) {
+        let save_result = self.settings.set_lockdown_mode(lockdown_mode).await;
+        match save_result {
+            Ok(settings_changed) => {
+                Self::oneshot_send(tx, Ok(()), "set_lockdown_mode response");
+                if settings_changed {
+                    self.event_listener
+                        .notify_settings(self.settings.to_settings());
+                    self.send_tunnel_command(TunnelCommand::BlockWhenDisconnected(
+                        block_when_disconnected(&self.settings),
+                    ));
+                }
+            }
+            Err(e) => {
+                log::error!("{}", e.display_chain_with_msg("Unable to save settings"));
+                Self::oneshot_send(tx, Err(e), "set_lockdown_mode response");
+            }
+        }
+    }
+
     async fn on_set_auto_connect(
         &mut self,
         tx: ResponseTx<(), settings::Error>,
@@ -1904,6 +2562,240 @@ where
         }
     }
 
+    #[cfg(target_os = "linux")]
+    async fn on_add_firewall_allow_rule(
+        &mut self,
+        tx: ResponseTx<(), settings::Error>,
+        rule: FirewallAllowRule,
+    ) {
+        let mut rules = self.settings.firewall_allow_rules.clone();
+        if !rules.contains(&rule) {
+            rules.push(rule);
+        }
+        self.set_firewall_allow_rules(tx, "add_firewall_allow_rule response", rules)
+            .await;
+    }
+
+    #[cfg(target_os = "linux")]
+    async fn on_remove_firewall_allow_rule(
+        &mut self,
+        tx: ResponseTx<(), settings::Error>,
+        rule: FirewallAllowRule,
+    ) {
+        let mut rules = self.settings.firewall_allow_rules.clone();
+        rules.retain(|existing_rule| existing_rule != &rule);
+        self.set_firewall_allow_rules(tx, "remove_firewall_allow_rule response", rules)
+            .await;
+    }
+
+    #[cfg(target_os = "linux")]
+    async fn on_clear_firewall_allow_rules(&mut self, tx: ResponseTx<(), settings::Error>) {
+        self.set_firewall_allow_rules(tx, "clear_firewall_allow_rules response", vec![])
+            .await;
+    }
+
+    #[cfg(target_os = "linux")]
+    async fn set_firewall_allow_rules(
+        &mut self,
+        tx: ResponseTx<(), settings::Error>,
+        response_msg: &'static str,
+        rules: Vec<FirewallAllowRule>,
+    ) {
+        match self.settings.set_firewall_allow_rules(rules.clone()).await {
+            Ok(settings_changed) => {
+                Self::oneshot_send(tx, Ok(()), response_msg);
+                if settings_changed {
+                    self.event_listener
+                        .notify_settings(self.settings.to_settings());
+                    self.send_tunnel_command(TunnelCommand::SetAllowRules(rules));
+                }
+            }
+            Err(e) => {
+                log::error!("{}", e.display_chain_with_msg("Unable to save settings"));
+                Self::oneshot_send(tx, Err(e), response_msg);
+            }
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    async fn on_add_trusted_network(
+        &mut self,
+        tx: ResponseTx<(), settings::Error>,
+        network: TrustedNetwork,
+    ) {
+        let mut networks = self.settings.trusted_networks.clone();
+        if !networks.contains(&network) {
+            networks.push(network);
+        }
+        self.set_trusted_networks(tx, "add_trusted_network response", networks)
+            .await;
+    }
+
+    #[cfg(target_os = "linux")]
+    async fn on_remove_trusted_network(
+        &mut self,
+        tx: ResponseTx<(), settings::Error>,
+        network: TrustedNetwork,
+    ) {
+        let mut networks = self.settings.trusted_networks.clone();
+        networks.retain(|existing_network| existing_network != &network);
+        self.set_trusted_networks(tx, "remove_trusted_network response", networks)
+            .await;
+    }
+
+    #[cfg(target_os = "linux")]
+    async fn set_trusted_networks(
+        &mut self,
+        tx: ResponseTx<(), settings::Error>,
+        response_msg: &'static str,
+        networks: Vec<TrustedNetwork>,
+    ) {
+        match self.settings.set_trusted_networks(networks).await {
+            Ok(settings_changed) => {
+                Self::oneshot_send(tx, Ok(()), response_msg);
+                if settings_changed {
+                    self.event_listener
+                        .notify_settings(self.settings.to_settings());
+                    let target_state = self
+                        .trusted_networks
+                        .rules_changed(&self.settings.trusted_networks);
+                    self.apply_trusted_network_target_state(target_state).await;
+                }
+            }
+            Err(e) => {
+                log::error!("{}", e.display_chain_with_msg("Unable to save settings"));
+                Self::oneshot_send(tx, Err(e), response_msg);
+            }
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    async fn on_add_hook(&mut self, tx: ResponseTx<(), Error>, hook: Hook) {
+        let result = match hooks::validate_timeout(hook.timeout_secs)
+            .and_then(|()| hooks::validate(&hook.path))
+        {
+            Ok(_) => {
+                let mut hooks = self.settings.hooks.clone();
+                if !hooks.contains(&hook) {
+                    hooks.push(hook);
+                }
+                self.set_hooks(hooks).await
+            }
+            Err(error) => Err(Error::HookError(error)),
+        };
+        Self::oneshot_send(tx, result, "add_hook response");
+    }
+
+    #[cfg(target_os = "linux")]
+    async fn on_remove_hook(&mut self, tx: ResponseTx<(), Error>, path: PathBuf) {
+        let mut hooks = self.settings.hooks.clone();
+        hooks.retain(|hook| hook.path != path);
+        let result = self.set_hooks(hooks).await;
+        Self::oneshot_send(tx, result, "remove_hook response");
+    }
+
+    #[cfg(target_os = "linux")]
+    async fn set_hooks(&mut self, hooks: Vec<Hook>) -> Result<(), Error> {
+        match self.settings.set_hooks(hooks).await {
+            Ok(settings_changed) => {
+                if settings_changed {
+                    self.event_listener
+                        .notify_settings(self.settings.to_settings());
+                }
+                Ok(())
+            }
+            Err(e) => {
+                log::error!("{}", e.display_chain_with_msg("Unable to save settings"));
+                Err(Error::SettingsError(e))
+            }
+        }
+    }
+
+    /// Runs the hooks that are configured for the new tunnel state, without waiting for them.
+    #[cfg(target_os = "linux")]
+    fn run_hooks(&mut self, tunnel_state: &TunnelState) {
+        let event = hooks::event(tunnel_state);
+        let hooks: Vec<Hook> = self
+            .settings
+            .hooks
+            .iter()
+            .filter(|hook| hook.runs_on(event))
+            .cloned()
+            .collect();
+        if hooks.is_empty() {
+            return;
+        }
+
+        let metadata_rx = match tunnel_state {
+            TunnelState::Connecting { .. } | TunnelState::Connected { .. } => {
+                let (tx, rx) = oneshot::channel();
+                self.send_tunnel_command(TunnelCommand::GetTunnelMetadata(tx));
+                Some(rx)
+            }
+            _ => None,
+        };
+        self.hook_runner
+            .run(hooks, hooks::environment(tunnel_state), metadata_rx);
+    }
+
+    #[cfg(target_os = "linux")]
+    async fn handle_network_identity_change(&mut self, identity: NetworkIdentity) {
+        log::debug!("Network identity: {:?}", identity);
+        let target_state = self
+            .trusted_networks
+            .network_changed(identity, &self.settings.trusted_networks);
+        self.apply_trusted_network_target_state(target_state).await;
+    }
+
+    #[cfg(target_os = "linux")]
+    async fn apply_trusted_network_target_state(&mut self, target_state: Option<TargetState>) {
+        let target_state = match target_state {
+            Some(target_state) if self.state.is_running() => target_state,
+            _ => return,
+        };
+        if target_state == TargetState::Secured
+            && !matches!(
+                self.account_manager.data().await.map(|s| s.into_device()),
+                Ok(Some(_))
+            )
+        {
+            log::debug!("Not connecting on untrusted network since there is no account");
+            return;
+        }
+        if self.set_target_state(target_state).await {
+            log::info!(
+                "Setting target state to {} since the network is {}",
+                target_state,
+                if target_state == TargetState::Unsecured {
+                    "trusted"
+                } else {
+                    "untrusted"
+                }
+            );
+        }
+    }
+
+    fn on_get_firewall_rules(
+        &mut self,
+        tx: ResponseTx<RenderedPolicy, Error>,
+        query: FirewallPolicyQuery,
+    ) {
+        let (policy_tx, policy_rx) = oneshot::channel();
+        self.send_tunnel_command(TunnelCommand::GetFirewallPolicy(query, policy_tx));
+
+        tokio::spawn(async move {
+            let result = match policy_rx.await {
+                Ok(Some(policy)) => Ok(Firewall::render_policy(&policy)),
+                Ok(None) => Err(Error::NoFirewallPolicy),
+                Err(_) => {
+                    log::error!("The tunnel state machine dropped the firewall policy request");
+                    Err(Error::NoFirewallPolicy)
+                }
+            };
+            Self::oneshot_send(tx, result, "get_firewall_rules response");
+        });
+    }
+
     async fn on_set_bridge_state(
         &mut self,
         tx: ResponseTx<(), settings::Error>,
@@ -1958,6 +2850,13 @@ where
         tx: ResponseTx<(), settings::Error>,
         dns_options: DnsOptions,
     ) {
+        // Blocklists and split DNS rules are managed separately
+        #[cfg(target_os = "linux")]
+        let dns_options = DnsOptions {
+            blocklists: self.settings.tunnel_options.dns_options.blocklists.clone(),
+            split_dns: self.settings.tunnel_options.dns_options.split_dns.clone(),
+            ..dns_options
+        };
         let save_result = self.settings.set_dns_options(dns_options.clone()).await;
         match save_result {
             Ok(settings_changed) => {
@@ -1968,7 +2867,12 @@ where
                         dns::addresses_from_options(&settings.tunnel_options.dns_options);
                     self.parameters_generator
                         .set_tunnel_options(&settings.tunnel_options);
+                    #[cfg(target_os = "linux")]
+                    let encrypted_servers =
+                        dns::encrypted_servers_from_options(&settings.tunnel_options.dns_options);
                     self.event_listener.notify_settings(settings);
+                    #[cfg(target_os = "linux")]
+                    self.send_tunnel_command(TunnelCommand::SetEncryptedDns(encrypted_servers));
                     self.send_tunnel_command(TunnelCommand::Dns(resolvers));
                 }
             }
@@ -1979,6 +2883,182 @@ where
         }
     }
 
+    #[cfg(target_os = "linux")]
+    async fn on_set_dns_blocklist(
+        &mut self,
+        tx: ResponseTx<(), settings::Error>,
+        blocklist: DnsBlocklist,
+    ) {
+        let mut blocklists = self.settings.tunnel_options.dns_options.blocklists.clone();
+        match blocklists
+            .iter_mut()
+            .find(|existing| existing.path == blocklist.path)
+        {
+            Some(existing) => *existing = blocklist,
+            None => blocklists.push(blocklist),
+        }
+        self.set_dns_blocklists(tx, "set_dns_blocklist response", blocklists)
+            .await;
+    }
+
+    #[cfg(target_os = "linux")]
+    async fn on_remove_dns_blocklist(
+        &mut self,
+        tx: ResponseTx<(), settings::Error>,
+        path: PathBuf,
+    ) {
+        let mut blocklists = self.settings.tunnel_options.dns_options.blocklists.clone();
+        blocklists.retain(|blocklist| blocklist.path != path);
+        self.set_dns_blocklists(tx, "remove_dns_blocklist response", blocklists)
+            .await;
+    }
+
+    #[cfg(target_os = "linux")]
+    async fn set_dns_blocklists(
+        &mut self,
+        tx: ResponseTx<(), settings::Error>,
+        response_msg: &'static str,
+        blocklists: Vec<DnsBlocklist>,
+    ) {
+        match self.settings.set_dns_blocklists(blocklists.clone()).await {
+            Ok(settings_changed) => {
+                Self::oneshot_send(tx, Ok(()), response_msg);
+                if settings_changed {
+                    self.event_listener
+                        .notify_settings(self.settings.to_settings());
+                    self.send_tunnel_command(TunnelCommand::SetDnsBlocklists(blocklists));
+                }
+            }
+            Err(e) => {
+                log::error!("{}", e.display_chain_with_msg("Unable to save settings"));
+                Self::oneshot_send(tx, Err(e), response_msg);
+            }
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    async fn on_set_split_dns_rule(
+        &mut self,
+        tx: ResponseTx<(), settings::Error>,
+        rule: SplitDnsRule,
+    ) {
+        let mut rules = self.settings.tunnel_options.dns_options.split_dns.clone();
+        match rules
+            .iter_mut()
+            .find(|existing| existing.domain == rule.domain)
+        {
+            Some(existing) => *existing = rule,
+            None => rules.push(rule),
+        }
+        self.set_split_dns(tx, "set_split_dns_rule response", rules)
+            .await;
+    }
+
+    #[cfg(target_os = "linux")]
+    async fn on_remove_split_dns_rule(
+        &mut self,
+        tx: ResponseTx<(), settings::Error>,
+        domain: String,
+    ) {
+        let domain = SplitDnsRule::normalize_domain(&domain);
+        let mut rules = self.settings.tunnel_options.dns_options.split_dns.clone();
+        rules.retain(|rule| rule.domain != domain);
+        self.set_split_dns(tx, "remove_split_dns_rule response", rules)
+            .await;
+    }
+
+    #[cfg(target_os = "linux")]
+    async fn set_split_dns(
+        &mut self,
+        tx: ResponseTx<(), settings::Error>,
+        response_msg: &'static str,
+        rules: Vec<SplitDnsRule>,
+    ) {
+        match self.settings.set_split_dns(rules.clone()).await {
+            Ok(settings_changed) => {
+                Self::oneshot_send(tx, Ok(()), response_msg);
+                if settings_changed {
+                    self.event_listener
+                        .notify_settings(self.settings.to_settings());
+                    self.send_tunnel_command(TunnelCommand::SetSplitDns(rules));
+                }
+            }
+            Err(e) => {
+                log::error!("{}", e.display_chain_with_msg("Unable to save settings"));
+                Self::oneshot_send(tx, Err(e), response_msg);
+            }
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn on_reload_dns_blocklists(&self, tx: oneshot::Sender<()>) {
+        let dns_filter = self.dns_filter.clone();
+        tokio::spawn(async move {
+            if let Err(error) = tokio::task::spawn_blocking(move || dns_filter.reload()).await {
+                log::error!("Failed to reload DNS blocklists: {}", error);
+            }
+            Self::oneshot_send(tx, (), "reload_dns_blocklists response");
+        });
+    }
+
+    #[cfg(target_os = "linux")]
+    fn on_run_dns_leak_test(
+        &self,
+        tx: oneshot::Sender<Option<LeakTestReport>>,
+        test_domain: String,
+    ) {
+        let (config_tx, config_rx) = oneshot::channel();
+        self.send_tunnel_command(TunnelCommand::GetDnsConfig(config_tx));
+        tokio::spawn(async move {
+            let report = match config_rx.await.ok().flatten() {
+                Some(config) => {
+                    tokio::task::spawn_blocking(move || leak_test::run(&config, &test_domain))
+                        .await
+                        .map_err(|error| log::error!("DNS leak test failed: {}", error))
+                        .ok()
+                }
+                None => None,
+            };
+            Self::oneshot_send(tx, report, "run_dns_leak_test response");
+        });
+    }
+
+    #[cfg(target_os = "linux")]
+    fn on_unlock_captive_portal(
+        &self,
+        tx: ResponseTx<captive_portal::UnlockStatus, Error>,
+        duration: Duration,
+        probe_url: Option<String>,
+    ) {
+        let blocking = match self.tunnel_state {
+            TunnelState::Error(_) => true,
+            TunnelState::Disconnected => block_when_disconnected(&self.settings),
+            _ => false,
+        };
+        if !blocking {
+            Self::oneshot_send(
+                tx,
+                Err(Error::NotBlocking),
+                "unlock_captive_portal response",
+            );
+            return;
+        }
+
+        let (status_tx, status_rx) = oneshot::channel();
+        self.send_tunnel_command(TunnelCommand::UnlockCaptivePortal(
+            duration, probe_url, status_tx,
+        ));
+        tokio::spawn(async move {
+            let result = match status_rx.await {
+                Ok(result) => result.map_err(Error::CaptivePortalError),
+                Err(_) => Err(Error::CaptivePortalError(
+                    captive_portal::Error::StateMachineDown,
+                )),
+            };
+            Self::oneshot_send(tx, result, "unlock_captive_portal response");
+        });
+    }
+
     async fn on_set_wireguard_mtu(
         &mut self,
         tx: ResponseTx<(), settings::Error>,
@@ -2105,7 +3185,9 @@ where
     /// Set the target state of the client. If it changed trigger the operations needed to
     /// progress towards that state.
     /// Returns a bool representing whether or not a state change was initiated.
+    /// Any pause ends, even if the target state does not change.
     async fn set_target_state(&mut self, new_state: TargetState) -> bool {
+        self.unschedule_resume();
         if new_state != *self.target_state || self.tunnel_state.is_in_error_state() {
             log::debug!("Target state {:?} => {:?}", *self.target_state, new_state);
 
@@ -2117,6 +3199,7 @@ where
             }
             true
         } else {
+            self.target_state.set(new_state).await;
             false
         }
     }
@@ -2193,3 +3276,35 @@ fn new_selector_config(settings: &Settings) -> SelectorConfig {
         obfuscation_settings: settings.obfuscation_settings.clone(),
     }
 }
+
+/// Returns whether traffic should be blocked in the disconnected state, which lockdown mode
+/// implies.
+fn block_when_disconnected(settings: &Settings) -> bool {
+    #[cfg(target_os = "linux")]
+    if settings.lockdown_mode {
+        return true;
+    }
+    settings.block_when_disconnected
+}
+
+/// Returns the split tunneling mode to enforce. Include-only mode routes all other traffic outside
+/// the tunnel, so it only takes effect while split tunneling is enabled.
+#[cfg(target_os = "linux")]
+fn split_tunnel_mode(settings: &Settings) -> SplitTunnelMode {
+    if settings.split_tunnel.enable_exclusions {
+        settings.split_tunnel.mode
+    } else {
+        SplitTunnelMode::Exclude
+    }
+}
+
+/// Returns the destinations to exclude from the tunnel. Like excluded apps, these only take effect
+/// while split tunneling is enabled.
+#[cfg(target_os = "linux")]
+fn excluded_destinations(settings: &Settings) -> ExcludedDestinations {
+    if settings.split_tunnel.enable_exclusions {
+        settings.split_tunnel.excluded_destinations.clone()
+    } else {
+        ExcludedDestinations::default()
+    }
+}
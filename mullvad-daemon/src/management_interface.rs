@@ -21,7 +21,7 @@ use mullvad_types::{
     wireguard::{RotationInterval, RotationIntervalError},
 };
 use parking_lot::RwLock;
-#[cfg(windows)]
+#[cfg(any(windows, target_os = "linux"))]
 use std::path::PathBuf;
 use std::{
     cmp,
@@ -29,7 +29,24 @@ use std::{
     sync::Arc,
     time::Duration,
 };
+use talpid_core::tunnel_state_machine::FirewallPolicyQuery;
+#[cfg(target_os = "linux")]
+use talpid_core::{
+    captive_portal,
+    dns::{leak_test, DnsWarning},
+};
+#[cfg(unix)]
+use talpid_types::net::lan::LanNetworks;
 use talpid_types::ErrorExt;
+#[cfg(target_os = "linux")]
+use talpid_types::{
+    cgroup::{ExclusionProfile, SplitTunnelMode},
+    net::{
+        dns::{is_valid_hostname, DnsBlocklist, SplitDnsRule},
+        trusted_network::TrustedNetwork,
+        ExcludedDestinations, FirewallAllowRule,
+    },
+};
 use tokio_stream::wrappers::{ReceiverStream, UnboundedReceiverStream};
 
 #[derive(err_derive::Error, Debug)]
@@ -55,7 +72,8 @@ const USED_VOUCHER_MESSAGE: &str = "This voucher code has already been used";
 #[mullvad_management_interface::async_trait]
 impl ManagementService for ManagementServiceImpl {
     type GetRelayLocationsStream = ReceiverStream<Result<types::RelayListCountry, Status>>;
-    type GetSplitTunnelProcessesStream = UnboundedReceiverStream<Result<i32, Status>>;
+    type GetSplitTunnelProcessesStream =
+        UnboundedReceiverStream<Result<types::ExcludedProcess, Status>>;
     type EventsListenStream = EventsListenerReceiver;
 
     // Control and get the tunnel state
@@ -79,6 +97,69 @@ impl ManagementService for ManagementServiceImpl {
         Ok(Response::new(disconnect_issued))
     }
 
+    async fn pause_tunnel(&self, request: Request<types::Duration>) -> ServiceResult<()> {
+        let duration = Duration::try_from(request.into_inner())
+            .map_err(|_| Status::invalid_argument("unexpected negative pause duration"))?;
+        if duration.is_zero() {
+            return Err(Status::invalid_argument(
+                "the pause duration must not be zero",
+            ));
+        }
+        log::debug!("pause_tunnel({:?})", duration);
+
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::PauseTunnel(tx, duration))?;
+        self.wait_for_result(rx).await?;
+        Ok(Response::new(()))
+    }
+
+    async fn get_tunnel_pause(&self, _: Request<()>) -> ServiceResult<types::TunnelPause> {
+        log::debug!("get_tunnel_pause");
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::GetTunnelPause(tx))?;
+        let remaining = self.wait_for_result(rx).await?;
+        Ok(Response::new(types::TunnelPause {
+            resume_in: remaining.map(types::Duration::from),
+        }))
+    }
+
+    async fn get_connection_history(
+        &self,
+        _: Request<()>,
+    ) -> ServiceResult<types::ConnectionHistory> {
+        log::debug!("get_connection_history");
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::GetConnectionHistory(tx))?;
+        let entries = self.wait_for_result(rx).await?;
+        Ok(Response::new(types::ConnectionHistory {
+            entries: entries
+                .into_iter()
+                .map(types::ConnectionHistoryEntry::from)
+                .collect(),
+        }))
+    }
+
+    async fn clear_connection_history(&self, _: Request<()>) -> ServiceResult<()> {
+        log::debug!("clear_connection_history");
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::ClearConnectionHistory(tx))?;
+        self.wait_for_result(rx)
+            .await?
+            .map(Response::new)
+            .map_err(map_daemon_error)
+    }
+
+    async fn set_connection_history(&self, request: Request<bool>) -> ServiceResult<()> {
+        let enabled = request.into_inner();
+        log::debug!("set_connection_history({})", enabled);
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::SetConnectionHistory(tx, enabled))?;
+        self.wait_for_result(rx)
+            .await?
+            .map(Response::new)
+            .map_err(map_daemon_error)
+    }
+
     async fn reconnect_tunnel(&self, _: Request<()>) -> ServiceResult<bool> {
         log::debug!("reconnect_tunnel");
         let (tx, rx) = oneshot::channel();
@@ -298,6 +379,26 @@ impl ManagementService for ManagementServiceImpl {
             .map_err(map_settings_error)
     }
 
+    #[cfg(unix)]
+    async fn set_lan_networks(&self, request: Request<types::LanNetworks>) -> ServiceResult<()> {
+        let lan_networks =
+            LanNetworks::try_from(request.into_inner()).map_err(map_protobuf_type_err)?;
+        lan_networks
+            .validate()
+            .map_err(|error| Status::invalid_argument(error.to_string()))?;
+        log::debug!("set_lan_networks({:?})", lan_networks);
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::SetLanNetworks(tx, lan_networks))?;
+        self.wait_for_result(rx)
+            .await?
+            .map(Response::new)
+            .map_err(map_settings_error)
+    }
+    #[cfg(not(unix))]
+    async fn set_lan_networks(&self, _: Request<types::LanNetworks>) -> ServiceResult<()> {
+        Ok(Response::new(()))
+    }
+
     async fn set_show_beta_releases(&self, request: Request<bool>) -> ServiceResult<()> {
         let enabled = request.into_inner();
         log::debug!("set_show_beta_releases({})", enabled);
@@ -323,6 +424,24 @@ impl ManagementService for ManagementServiceImpl {
             .map_err(map_settings_error)
     }
 
+    #[cfg(target_os = "linux")]
+    async fn set_lockdown_mode(&self, request: Request<bool>) -> ServiceResult<()> {
+        let lockdown_mode = request.into_inner();
+        log::debug!("set_lockdown_mode({})", lockdown_mode);
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::SetLockdownMode(tx, lockdown_mode))?;
+        self.wait_for_result(rx)
+            .await?
+            .map(Response::new)
+            .map_err(map_settings_error)
+    }
+    #[cfg(not(target_os = "linux"))]
+    async fn set_lockdown_mode(&self, _: Request<bool>) -> ServiceResult<()> {
+        Err(Status::unimplemented(
+            "Lockdown mode is only supported on Linux",
+        ))
+    }
+
     async fn set_auto_connect(&self, request: Request<bool>) -> ServiceResult<()> {
         let auto_connect = request.into_inner();
         log::debug!("set_auto_connect({})", auto_connect);
@@ -391,6 +510,113 @@ impl ManagementService for ManagementServiceImpl {
         Ok(Response::new(()))
     }
 
+    #[cfg(target_os = "linux")]
+    async fn set_dns_blocklist(&self, request: Request<types::DnsBlocklist>) -> ServiceResult<()> {
+        let blocklist =
+            DnsBlocklist::try_from(request.into_inner()).map_err(map_protobuf_type_err)?;
+        log::debug!("set_dns_blocklist({:?})", blocklist);
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::SetDnsBlocklist(tx, blocklist))?;
+        self.wait_for_result(rx)
+            .await?
+            .map(Response::new)
+            .map_err(map_settings_error)
+    }
+    #[cfg(not(target_os = "linux"))]
+    async fn set_dns_blocklist(&self, _: Request<types::DnsBlocklist>) -> ServiceResult<()> {
+        Ok(Response::new(()))
+    }
+
+    #[cfg(target_os = "linux")]
+    async fn remove_dns_blocklist(&self, request: Request<String>) -> ServiceResult<()> {
+        let path = PathBuf::from(request.into_inner());
+        log::debug!("remove_dns_blocklist({})", path.display());
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::RemoveDnsBlocklist(tx, path))?;
+        self.wait_for_result(rx)
+            .await?
+            .map(Response::new)
+            .map_err(map_settings_error)
+    }
+    #[cfg(not(target_os = "linux"))]
+    async fn remove_dns_blocklist(&self, _: Request<String>) -> ServiceResult<()> {
+        Ok(Response::new(()))
+    }
+
+    #[cfg(target_os = "linux")]
+    async fn reload_dns_blocklists(&self, _: Request<()>) -> ServiceResult<()> {
+        log::debug!("reload_dns_blocklists");
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::ReloadDnsBlocklists(tx))?;
+        self.wait_for_result(rx).await.map(Response::new)
+    }
+    #[cfg(not(target_os = "linux"))]
+    async fn reload_dns_blocklists(&self, _: Request<()>) -> ServiceResult<()> {
+        Ok(Response::new(()))
+    }
+
+    #[cfg(target_os = "linux")]
+    async fn get_dns_blocklist_stats(
+        &self,
+        _: Request<()>,
+    ) -> ServiceResult<types::DnsBlocklistStatsList> {
+        log::debug!("get_dns_blocklist_stats");
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::GetDnsBlocklistStats(tx))?;
+        let stats = self.wait_for_result(rx).await?;
+        Ok(Response::new(types::DnsBlocklistStatsList {
+            blocklists: stats
+                .into_iter()
+                .map(|stats| types::DnsBlocklistStats {
+                    path: stats.path.to_string_lossy().into_owned(),
+                    enabled: stats.enabled,
+                    domains: stats.domains as u64,
+                    hits: stats.hits,
+                    error: stats.error.unwrap_or_default(),
+                })
+                .collect(),
+        }))
+    }
+    #[cfg(not(target_os = "linux"))]
+    async fn get_dns_blocklist_stats(
+        &self,
+        _: Request<()>,
+    ) -> ServiceResult<types::DnsBlocklistStatsList> {
+        Ok(Response::new(types::DnsBlocklistStatsList::default()))
+    }
+
+    #[cfg(target_os = "linux")]
+    async fn set_split_dns_rule(&self, request: Request<types::SplitDnsRule>) -> ServiceResult<()> {
+        let rule = SplitDnsRule::try_from(request.into_inner()).map_err(map_protobuf_type_err)?;
+        log::debug!("set_split_dns_rule({:?})", rule);
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::SetSplitDnsRule(tx, rule))?;
+        self.wait_for_result(rx)
+            .await?
+            .map(Response::new)
+            .map_err(map_settings_error)
+    }
+    #[cfg(not(target_os = "linux"))]
+    async fn set_split_dns_rule(&self, _: Request<types::SplitDnsRule>) -> ServiceResult<()> {
+        Ok(Response::new(()))
+    }
+
+    #[cfg(target_os = "linux")]
+    async fn remove_split_dns_rule(&self, request: Request<String>) -> ServiceResult<()> {
+        let domain = request.into_inner();
+        log::debug!("remove_split_dns_rule({})", domain);
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::RemoveSplitDnsRule(tx, domain))?;
+        self.wait_for_result(rx)
+            .await?
+            .map(Response::new)
+            .map_err(map_settings_error)
+    }
+    #[cfg(not(target_os = "linux"))]
+    async fn remove_split_dns_rule(&self, _: Request<String>) -> ServiceResult<()> {
+        Ok(Response::new(()))
+    }
+
     // Account management
     //
 
@@ -623,15 +849,24 @@ impl ManagementService for ManagementServiceImpl {
             log::debug!("get_split_tunnel_processes");
             let (tx, rx) = oneshot::channel();
             self.send_command_to_daemon(DaemonCommand::GetSplitTunnelProcesses(tx))?;
-            let pids = self
+            let processes = self
                 .wait_for_result(rx)
                 .await?
                 .map_err(|error| Status::failed_precondition(error.to_string()))?;
 
             let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
             tokio::spawn(async move {
-                for pid in pids {
-                    let _ = tx.send(Ok(pid));
+                for process in processes {
+                    let _ = tx.send(Ok(types::ExcludedProcess {
+                        pid: process.pid,
+                        parent_pid: process.parent_pid,
+                        name: process.name,
+                        exe_path: process
+                            .exe
+                            .map(|exe| exe.to_string_lossy().into_owned())
+                            .unwrap_or_default(),
+                        uid: process.uid,
+                    }));
                 }
             });
 
@@ -646,10 +881,11 @@ impl ManagementService for ManagementServiceImpl {
 
     #[cfg(target_os = "linux")]
     async fn add_split_tunnel_process(&self, request: Request<i32>) -> ServiceResult<()> {
+        let uid = client_uid(&request)?;
         let pid = request.into_inner();
         log::debug!("add_split_tunnel_process");
         let (tx, rx) = oneshot::channel();
-        self.send_command_to_daemon(DaemonCommand::AddSplitTunnelProcess(tx, pid))?;
+        self.send_command_to_daemon(DaemonCommand::AddSplitTunnelProcess(tx, pid, false, uid))?;
         self.wait_for_result(rx)
             .await?
             .map_err(|error| Status::failed_precondition(error.to_string()))?;
@@ -660,6 +896,23 @@ impl ManagementService for ManagementServiceImpl {
         Ok(Response::new(()))
     }
 
+    #[cfg(target_os = "linux")]
+    async fn add_split_tunnel_process_tree(&self, request: Request<i32>) -> ServiceResult<()> {
+        let uid = client_uid(&request)?;
+        let pid = request.into_inner();
+        log::debug!("add_split_tunnel_process_tree");
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::AddSplitTunnelProcess(tx, pid, true, uid))?;
+        self.wait_for_result(rx)
+            .await?
+            .map_err(|error| Status::failed_precondition(error.to_string()))?;
+        Ok(Response::new(()))
+    }
+    #[cfg(not(target_os = "linux"))]
+    async fn add_split_tunnel_process_tree(&self, _: Request<i32>) -> ServiceResult<()> {
+        Ok(Response::new(()))
+    }
+
     #[cfg(target_os = "linux")]
     async fn remove_split_tunnel_process(&self, request: Request<i32>) -> ServiceResult<()> {
         let pid = request.into_inner();
@@ -693,9 +946,12 @@ impl ManagementService for ManagementServiceImpl {
         }
     }
 
-    #[cfg(windows)]
+    #[cfg(any(windows, target_os = "linux"))]
     async fn add_split_tunnel_app(&self, request: Request<String>) -> ServiceResult<()> {
         log::debug!("add_split_tunnel_app");
+        // Excluded apps apply to the processes of all users
+        #[cfg(target_os = "linux")]
+        require_root(&request)?;
         let path = PathBuf::from(request.into_inner());
         let (tx, rx) = oneshot::channel();
         self.send_command_to_daemon(DaemonCommand::AddSplitTunnelApp(tx, path))?;
@@ -704,14 +960,17 @@ impl ManagementService for ManagementServiceImpl {
             .map_err(map_daemon_error)
             .map(Response::new)
     }
-    #[cfg(not(windows))]
+    #[cfg(not(any(windows, target_os = "linux")))]
     async fn add_split_tunnel_app(&self, _: Request<String>) -> ServiceResult<()> {
         Ok(Response::new(()))
     }
 
-    #[cfg(windows)]
+    #[cfg(any(windows, target_os = "linux"))]
     async fn remove_split_tunnel_app(&self, request: Request<String>) -> ServiceResult<()> {
         log::debug!("remove_split_tunnel_app");
+        // Excluded apps apply to the processes of all users
+        #[cfg(target_os = "linux")]
+        require_root(&request)?;
         let path = PathBuf::from(request.into_inner());
         let (tx, rx) = oneshot::channel();
         self.send_command_to_daemon(DaemonCommand::RemoveSplitTunnelApp(tx, path))?;
@@ -720,7 +979,7 @@ impl ManagementService for ManagementServiceImpl {
             .map_err(map_daemon_error)
             .map(Response::new)
     }
-    #[cfg(not(windows))]
+    #[cfg(not(any(windows, target_os = "linux")))]
     async fn remove_split_tunnel_app(&self, _: Request<String>) -> ServiceResult<()> {
         Ok(Response::new(()))
     }
@@ -735,12 +994,24 @@ impl ManagementService for ManagementServiceImpl {
             .map_err(map_daemon_error)
             .map(Response::new)
     }
-    #[cfg(not(windows))]
+    #[cfg(target_os = "linux")]
+    async fn clear_split_tunnel_apps(&self, request: Request<()>) -> ServiceResult<()> {
+        log::debug!("clear_split_tunnel_apps");
+        // Excluded apps apply to the processes of all users
+        require_root(&request)?;
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::ClearSplitTunnelApps(tx))?;
+        self.wait_for_result(rx)
+            .await?
+            .map_err(map_daemon_error)
+            .map(Response::new)
+    }
+    #[cfg(not(any(windows, target_os = "linux")))]
     async fn clear_split_tunnel_apps(&self, _: Request<()>) -> ServiceResult<()> {
         Ok(Response::new(()))
     }
 
-    #[cfg(windows)]
+    #[cfg(any(windows, target_os = "linux"))]
     async fn set_split_tunnel_state(&self, request: Request<bool>) -> ServiceResult<()> {
         log::debug!("set_split_tunnel_state");
         let enabled = request.into_inner();
@@ -751,11 +1022,103 @@ impl ManagementService for ManagementServiceImpl {
             .map_err(map_daemon_error)
             .map(Response::new)
     }
-    #[cfg(not(windows))]
+    #[cfg(not(any(windows, target_os = "linux")))]
     async fn set_split_tunnel_state(&self, _: Request<bool>) -> ServiceResult<()> {
         Ok(Response::new(()))
     }
 
+    #[cfg(target_os = "linux")]
+    async fn set_split_tunnel_mode(
+        &self,
+        request: Request<types::SplitTunnelMode>,
+    ) -> ServiceResult<()> {
+        let mode =
+            SplitTunnelMode::try_from(request.into_inner()).map_err(map_protobuf_type_err)?;
+        log::debug!("set_split_tunnel_mode({:?})", mode);
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::SetSplitTunnelMode(tx, mode))?;
+        self.wait_for_result(rx)
+            .await?
+            .map_err(map_settings_error)
+            .map(Response::new)
+    }
+    #[cfg(not(target_os = "linux"))]
+    async fn set_split_tunnel_mode(&self, _: Request<types::SplitTunnelMode>) -> ServiceResult<()> {
+        Ok(Response::new(()))
+    }
+
+    #[cfg(target_os = "linux")]
+    async fn set_exclusion_profile(
+        &self,
+        request: Request<types::ExclusionProfile>,
+    ) -> ServiceResult<()> {
+        let profile =
+            ExclusionProfile::try_from(request.into_inner()).map_err(map_protobuf_type_err)?;
+        log::debug!("set_exclusion_profile({:?})", profile);
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::SetExclusionProfile(tx, profile))?;
+        self.wait_for_result(rx)
+            .await?
+            .map_err(map_daemon_error)
+            .map(Response::new)
+    }
+    #[cfg(not(target_os = "linux"))]
+    async fn set_exclusion_profile(
+        &self,
+        _: Request<types::ExclusionProfile>,
+    ) -> ServiceResult<()> {
+        Ok(Response::new(()))
+    }
+
+    #[cfg(target_os = "linux")]
+    async fn remove_exclusion_profile(&self, request: Request<String>) -> ServiceResult<()> {
+        let name = request.into_inner();
+        log::debug!("remove_exclusion_profile({})", name);
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::RemoveExclusionProfile(tx, name))?;
+        self.wait_for_result(rx)
+            .await?
+            .map_err(map_daemon_error)
+            .map(Response::new)
+    }
+    #[cfg(not(target_os = "linux"))]
+    async fn remove_exclusion_profile(&self, _: Request<String>) -> ServiceResult<()> {
+        Ok(Response::new(()))
+    }
+
+    #[cfg(target_os = "linux")]
+    async fn set_split_tunnel_destinations(
+        &self,
+        request: Request<types::ExcludedDestinations>,
+    ) -> ServiceResult<()> {
+        let destinations =
+            ExcludedDestinations::try_from(request.into_inner()).map_err(map_protobuf_type_err)?;
+        if let Some(domain) = destinations
+            .domains
+            .iter()
+            .find(|domain| !is_valid_domain(domain))
+        {
+            return Err(Status::invalid_argument(format!(
+                "invalid domain name: {}",
+                domain
+            )));
+        }
+        log::debug!("set_split_tunnel_destinations({:?})", destinations);
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::SetSplitTunnelDestinations(tx, destinations))?;
+        self.wait_for_result(rx)
+            .await?
+            .map_err(map_settings_error)
+            .map(Response::new)
+    }
+    #[cfg(not(target_os = "linux"))]
+    async fn set_split_tunnel_destinations(
+        &self,
+        _: Request<types::ExcludedDestinations>,
+    ) -> ServiceResult<()> {
+        Ok(Response::new(()))
+    }
+
     #[cfg(windows)]
     async fn set_use_wireguard_nt(&self, request: Request<bool>) -> ServiceResult<()> {
         log::debug!("set_use_wireguard_nt");
@@ -787,6 +1150,330 @@ impl ManagementService for ManagementServiceImpl {
     async fn check_volumes(&self, _: Request<()>) -> ServiceResult<()> {
         Ok(Response::new(()))
     }
+
+    // Firewall allow rules
+    //
+
+    #[cfg(target_os = "linux")]
+    async fn add_firewall_allow_rule(
+        &self,
+        request: Request<types::FirewallAllowRule>,
+    ) -> ServiceResult<()> {
+        let rule =
+            FirewallAllowRule::try_from(request.into_inner()).map_err(map_protobuf_type_err)?;
+        rule.validate()
+            .map_err(|error| Status::invalid_argument(error.to_string()))?;
+        log::debug!("add_firewall_allow_rule({:?})", rule);
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::AddFirewallAllowRule(tx, rule))?;
+        self.wait_for_result(rx)
+            .await?
+            .map(Response::new)
+            .map_err(map_settings_error)
+    }
+    #[cfg(not(target_os = "linux"))]
+    async fn add_firewall_allow_rule(
+        &self,
+        _: Request<types::FirewallAllowRule>,
+    ) -> ServiceResult<()> {
+        Ok(Response::new(()))
+    }
+
+    #[cfg(target_os = "linux")]
+    async fn remove_firewall_allow_rule(
+        &self,
+        request: Request<types::FirewallAllowRule>,
+    ) -> ServiceResult<()> {
+        let rule =
+            FirewallAllowRule::try_from(request.into_inner()).map_err(map_protobuf_type_err)?;
+        log::debug!("remove_firewall_allow_rule({:?})", rule);
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::RemoveFirewallAllowRule(tx, rule))?;
+        self.wait_for_result(rx)
+            .await?
+            .map(Response::new)
+            .map_err(map_settings_error)
+    }
+    #[cfg(not(target_os = "linux"))]
+    async fn remove_firewall_allow_rule(
+        &self,
+        _: Request<types::FirewallAllowRule>,
+    ) -> ServiceResult<()> {
+        Ok(Response::new(()))
+    }
+
+    async fn clear_firewall_allow_rules(&self, _: Request<()>) -> ServiceResult<()> {
+        #[cfg(target_os = "linux")]
+        {
+            log::debug!("clear_firewall_allow_rules");
+            let (tx, rx) = oneshot::channel();
+            self.send_command_to_daemon(DaemonCommand::ClearFirewallAllowRules(tx))?;
+            self.wait_for_result(rx)
+                .await?
+                .map(Response::new)
+                .map_err(map_settings_error)
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            Ok(Response::new(()))
+        }
+    }
+
+    // Trusted networks
+    //
+
+    #[cfg(target_os = "linux")]
+    async fn add_trusted_network(
+        &self,
+        request: Request<types::TrustedNetwork>,
+    ) -> ServiceResult<()> {
+        let network =
+            TrustedNetwork::try_from(request.into_inner()).map_err(map_protobuf_type_err)?;
+        log::debug!("add_trusted_network({})", network);
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::AddTrustedNetwork(tx, network))?;
+        self.wait_for_result(rx)
+            .await?
+            .map(Response::new)
+            .map_err(map_settings_error)
+    }
+    #[cfg(not(target_os = "linux"))]
+    async fn add_trusted_network(&self, _: Request<types::TrustedNetwork>) -> ServiceResult<()> {
+        Err(Status::unimplemented(
+            "Trusted networks are only supported on Linux",
+        ))
+    }
+
+    #[cfg(target_os = "linux")]
+    async fn remove_trusted_network(
+        &self,
+        request: Request<types::TrustedNetwork>,
+    ) -> ServiceResult<()> {
+        let network =
+            TrustedNetwork::try_from(request.into_inner()).map_err(map_protobuf_type_err)?;
+        log::debug!("remove_trusted_network({})", network);
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::RemoveTrustedNetwork(tx, network))?;
+        self.wait_for_result(rx)
+            .await?
+            .map(Response::new)
+            .map_err(map_settings_error)
+    }
+    #[cfg(not(target_os = "linux"))]
+    async fn remove_trusted_network(&self, _: Request<types::TrustedNetwork>) -> ServiceResult<()> {
+        Err(Status::unimplemented(
+            "Trusted networks are only supported on Linux",
+        ))
+    }
+
+    #[cfg(target_os = "linux")]
+    async fn get_network_identity(&self, _: Request<()>) -> ServiceResult<types::NetworkIdentity> {
+        log::debug!("get_network_identity");
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::GetNetworkIdentity(tx))?;
+        let identity = self.wait_for_result(rx).await?.unwrap_or_default();
+        Ok(Response::new(types::NetworkIdentity::from(identity)))
+    }
+    #[cfg(not(target_os = "linux"))]
+    async fn get_network_identity(&self, _: Request<()>) -> ServiceResult<types::NetworkIdentity> {
+        Err(Status::unimplemented(
+            "Trusted networks are only supported on Linux",
+        ))
+    }
+
+    // Captive portals
+    //
+
+    #[cfg(target_os = "linux")]
+    async fn unlock_captive_portal(
+        &self,
+        request: Request<types::CaptivePortalUnlockRequest>,
+    ) -> ServiceResult<types::CaptivePortalStatus> {
+        use types::captive_portal_status::Detection;
+
+        let request = request.into_inner();
+        let duration = match request.duration {
+            0 => captive_portal::DEFAULT_UNLOCK_DURATION,
+            seconds => Duration::from_secs(u64::from(seconds)),
+        };
+        let probe_url = Some(request.probe_url).filter(|url| !url.is_empty());
+        log::debug!(
+            "unlock_captive_portal({}s, {:?})",
+            duration.as_secs(),
+            probe_url
+        );
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::UnlockCaptivePortal(tx, duration, probe_url))?;
+        let status = self.wait_for_result(rx).await?.map_err(map_daemon_error)?;
+
+        let (detection, location) = match status.detection {
+            captive_portal::Detection::NoPortal => (Detection::NoPortal, None),
+            captive_portal::Detection::Portal { location } => (Detection::Portal, location),
+            captive_portal::Detection::Unknown => (Detection::Unknown, None),
+        };
+        Ok(Response::new(types::CaptivePortalStatus {
+            detection: i32::from(detection),
+            location: location.unwrap_or_default(),
+            hosts: status.hosts.iter().map(|host| host.to_string()).collect(),
+            duration: u32::try_from(status.duration.as_secs()).unwrap_or(u32::MAX),
+        }))
+    }
+    #[cfg(not(target_os = "linux"))]
+    async fn unlock_captive_portal(
+        &self,
+        _: Request<types::CaptivePortalUnlockRequest>,
+    ) -> ServiceResult<types::CaptivePortalStatus> {
+        Err(Status::unimplemented(
+            "Captive portal unlocking is only supported on Linux",
+        ))
+    }
+
+    // Hooks
+    //
+
+    #[cfg(target_os = "linux")]
+    async fn add_hook(&self, request: Request<types::Hook>) -> ServiceResult<()> {
+        require_root(&request)?;
+        let hook = mullvad_types::hooks::Hook::try_from(request.into_inner())
+            .map_err(map_protobuf_type_err)?;
+        log::debug!("add_hook({})", hook);
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::AddHook(tx, hook))?;
+        self.wait_for_result(rx)
+            .await?
+            .map(Response::new)
+            .map_err(map_daemon_error)
+    }
+    #[cfg(not(target_os = "linux"))]
+    async fn add_hook(&self, _: Request<types::Hook>) -> ServiceResult<()> {
+        Err(Status::unimplemented("Hooks are only supported on Linux"))
+    }
+
+    #[cfg(target_os = "linux")]
+    async fn remove_hook(&self, request: Request<String>) -> ServiceResult<()> {
+        require_root(&request)?;
+        let path = request.into_inner();
+        log::debug!("remove_hook({})", path);
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::RemoveHook(tx, PathBuf::from(path)))?;
+        self.wait_for_result(rx)
+            .await?
+            .map(Response::new)
+            .map_err(map_daemon_error)
+    }
+    #[cfg(not(target_os = "linux"))]
+    async fn remove_hook(&self, _: Request<String>) -> ServiceResult<()> {
+        Err(Status::unimplemented("Hooks are only supported on Linux"))
+    }
+
+    // Debugging
+    //
+
+    async fn get_firewall_rules(
+        &self,
+        request: Request<types::FirewallRulesRequest>,
+    ) -> ServiceResult<types::FirewallRules> {
+        use types::firewall_rules_request::Policy;
+
+        let query = match Policy::from_i32(request.into_inner().policy) {
+            Some(Policy::Active) => FirewallPolicyQuery::Active,
+            Some(Policy::Connected) => FirewallPolicyQuery::Connected,
+            Some(Policy::Blocked) => FirewallPolicyQuery::Blocked,
+            None => return Err(Status::invalid_argument("unknown firewall policy")),
+        };
+        log::debug!("get_firewall_rules({:?})", query);
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::GetFirewallRules(tx, query))?;
+        let rendered = self.wait_for_result(rx).await?.map_err(map_daemon_error)?;
+        Ok(Response::new(types::FirewallRules {
+            policy: rendered.policy.to_string(),
+            rules: rendered.rules,
+            #[cfg(target_os = "linux")]
+            nftables: rendered.nftables,
+            #[cfg(not(target_os = "linux"))]
+            nftables: String::new(),
+        }))
+    }
+
+    #[cfg(target_os = "linux")]
+    async fn get_blocked_connections(
+        &self,
+        _: Request<()>,
+    ) -> ServiceResult<types::BlockedConnections> {
+        log::debug!("get_blocked_connections");
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::GetBlockedConnections(tx))?;
+        let connections = self.wait_for_result(rx).await?;
+        Ok(Response::new(types::BlockedConnections {
+            connections: connections
+                .into_iter()
+                .map(|connection| types::BlockedConnection {
+                    destination: connection.destination.to_string(),
+                    protocol: connection.protocol.map(|protocol| {
+                        types::TransportProtocolConstraint {
+                            protocol: i32::from(types::TransportProtocol::from(protocol)),
+                        }
+                    }),
+                    port: connection.port.map(u32::from).unwrap_or(0),
+                    uid: connection.uid,
+                    process: connection.process.unwrap_or_default(),
+                    packets: connection.packets,
+                    last_blocked: Some(types::Timestamp::from(connection.last_blocked)),
+                })
+                .collect(),
+        }))
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    async fn get_blocked_connections(
+        &self,
+        _: Request<()>,
+    ) -> ServiceResult<types::BlockedConnections> {
+        Ok(Response::new(types::BlockedConnections::default()))
+    }
+
+    #[cfg(target_os = "linux")]
+    async fn run_dns_leak_test(
+        &self,
+        request: Request<types::DnsLeakTestRequest>,
+    ) -> ServiceResult<types::DnsLeakTestReport> {
+        let mut test_domain = request.into_inner().test_domain;
+        if test_domain.is_empty() {
+            test_domain = leak_test::DEFAULT_TEST_DOMAIN.to_owned();
+        } else if !is_valid_hostname(test_domain.trim_end_matches('.')) {
+            return Err(Status::invalid_argument("invalid test domain"));
+        }
+        log::debug!("run_dns_leak_test({})", test_domain);
+        let (tx, rx) = oneshot::channel();
+        self.send_command_to_daemon(DaemonCommand::RunDnsLeakTest(tx, test_domain))?;
+        let report = self
+            .wait_for_result(rx)
+            .await?
+            .ok_or_else(|| Status::not_found("DNS is not set by the daemon"))?;
+        Ok(Response::new(types::DnsLeakTestReport {
+            passed: report.passed(),
+            test_domain: report.test_domain,
+            checks: report
+                .checks
+                .into_iter()
+                .map(|check| types::DnsLeakTestCheck {
+                    name: check.name,
+                    passed: check.passed,
+                    details: check.details,
+                })
+                .collect(),
+        }))
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    async fn run_dns_leak_test(
+        &self,
+        _: Request<types::DnsLeakTestRequest>,
+    ) -> ServiceResult<types::DnsLeakTestReport> {
+        Err(Status::unimplemented(
+            "DNS leak tests are only supported on Linux",
+        ))
+    }
 }
 
 impl ManagementServiceImpl {
@@ -912,6 +1599,21 @@ impl EventListener for ManagementInterfaceEventBroadcaster {
             )),
         })
     }
+
+    #[cfg(target_os = "linux")]
+    fn notify_dns_warning(&self, warning: DnsWarning) {
+        log::debug!("Broadcasting DNS warning");
+        self.notify(types::DaemonEvent {
+            event: Some(daemon_event::Event::DnsWarning(types::DnsWarning {
+                ignored_by: warning.ignored_by.to_string(),
+                fallback: warning
+                    .fallback
+                    .map(|fallback| fallback.to_string())
+                    .unwrap_or_default(),
+                details: warning.details,
+            })),
+        })
+    }
 }
 
 impl ManagementInterfaceEventBroadcaster {
@@ -938,10 +1640,19 @@ fn map_daemon_error(error: crate::Error) -> Status {
         DaemonError::UpdateDeviceError(error) => map_device_error(error),
         #[cfg(windows)]
         DaemonError::SplitTunnelError(error) => map_split_tunnel_error(error),
+        #[cfg(target_os = "linux")]
+        DaemonError::SplitTunnelError(error) => Status::failed_precondition(error.to_string()),
         DaemonError::AccountHistory(error) => map_account_history_error(error),
         DaemonError::NoAccountToken | DaemonError::NoAccountTokenHistory => {
             Status::unauthenticated(error.to_string())
         }
+        DaemonError::NoFirewallPolicy => Status::not_found(error.to_string()),
+        #[cfg(target_os = "linux")]
+        DaemonError::NotBlocking => Status::failed_precondition(error.to_string()),
+        #[cfg(target_os = "linux")]
+        DaemonError::CaptivePortalError(error) => Status::unavailable(error.to_string()),
+        #[cfg(target_os = "linux")]
+        DaemonError::HookError(error) => Status::invalid_argument(error.to_string()),
         error => Status::unknown(error.to_string()),
     }
 }
@@ -1041,3 +1752,39 @@ fn map_protobuf_type_err(err: types::FromProtobufTypeError) -> Status {
         types::FromProtobufTypeError::InvalidArgument(err) => Status::invalid_argument(err),
     }
 }
+
+/// Returns the user ID of the client that sent `request`.
+#[cfg(target_os = "linux")]
+fn client_uid<T>(request: &Request<T>) -> Result<u32, Status> {
+    mullvad_management_interface::PeerCredentials::of(request)
+        .map(|credentials| credentials.uid)
+        .ok_or_else(|| Status::permission_denied("Failed to identify the client"))
+}
+
+/// Fails unless `request` was sent by a client running as root. This protects operations that
+/// would let other users run code as root.
+#[cfg(target_os = "linux")]
+fn require_root<T>(request: &Request<T>) -> Result<(), Status> {
+    if client_uid(request)? == 0 {
+        Ok(())
+    } else {
+        Err(Status::permission_denied("Only root can do this"))
+    }
+}
+
+/// Returns whether `domain` is a syntactically valid domain name.
+#[cfg(target_os = "linux")]
+fn is_valid_domain(domain: &str) -> bool {
+    let domain = domain.strip_suffix('.').unwrap_or(domain);
+    !domain.is_empty()
+        && domain.len() <= 253
+        && domain.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        })
+}
@@ -1,17 +1,30 @@
 #[cfg(not(target_os = "android"))]
 use futures::TryFutureExt;
+#[cfg(target_os = "linux")]
+use mullvad_types::hooks::Hook;
 use mullvad_types::{
     relay_constraints::{BridgeSettings, BridgeState, ObfuscationSettings, RelaySettingsUpdate},
     settings::{DnsOptions, Settings},
     wireguard::RotationInterval,
 };
-#[cfg(target_os = "windows")]
+#[cfg(any(windows, target_os = "linux"))]
 use std::collections::HashSet;
 use std::{
     ops::Deref,
     path::{Path, PathBuf},
 };
+#[cfg(unix)]
+use talpid_types::net::lan::LanNetworks;
 use talpid_types::ErrorExt;
+#[cfg(target_os = "linux")]
+use talpid_types::{
+    cgroup::{ExclusionProfile, SplitTunnelMode},
+    net::{
+        dns::{DnsBlocklist, SplitDnsRule},
+        trusted_network::TrustedNetwork,
+        ExcludedDestinations, FirewallAllowRule,
+    },
+};
 use tokio::{
     fs,
     io::{self, AsyncWriteExt},
@@ -92,6 +105,15 @@ impl SettingsPersister {
         persister
     }
 
+    /// Reads user settings from file, without saving any changes or falling back on the defaults
+    /// if the file cannot be read.
+    #[cfg(target_os = "linux")]
+    pub async fn read(settings_dir: &Path) -> Result<Settings, Error> {
+        Self::load_from_file(&settings_dir.join(SETTINGS_FILE))
+            .await
+            .map(|(settings, _)| settings)
+    }
+
     async fn load_from_file(path: &Path) -> Result<(Settings, bool), Error> {
         log::info!("Loading settings from {}", path.display());
 
@@ -204,6 +226,12 @@ impl SettingsPersister {
         self.update(should_save).await
     }
 
+    #[cfg(unix)]
+    pub async fn set_lan_networks(&mut self, lan_networks: LanNetworks) -> Result<bool, Error> {
+        let should_save = Self::update_field(&mut self.settings.lan_networks, lan_networks);
+        self.update(should_save).await
+    }
+
     pub async fn set_block_when_disconnected(
         &mut self,
         block_when_disconnected: bool,
@@ -215,6 +243,12 @@ impl SettingsPersister {
         self.update(should_save).await
     }
 
+    #[cfg(target_os = "linux")]
+    pub async fn set_lockdown_mode(&mut self, lockdown_mode: bool) -> Result<bool, Error> {
+        let should_save = Self::update_field(&mut self.settings.lockdown_mode, lockdown_mode);
+        self.update(should_save).await
+    }
+
     pub async fn set_auto_connect(&mut self, auto_connect: bool) -> Result<bool, Error> {
         let should_save = Self::update_field(&mut self.settings.auto_connect, auto_connect);
         self.update(should_save).await
@@ -242,6 +276,27 @@ impl SettingsPersister {
         self.update(should_save).await
     }
 
+    #[cfg(target_os = "linux")]
+    pub async fn set_dns_blocklists(
+        &mut self,
+        blocklists: Vec<DnsBlocklist>,
+    ) -> Result<bool, Error> {
+        let should_save = Self::update_field(
+            &mut self.settings.tunnel_options.dns_options.blocklists,
+            blocklists,
+        );
+        self.update(should_save).await
+    }
+
+    #[cfg(target_os = "linux")]
+    pub async fn set_split_dns(&mut self, rules: Vec<SplitDnsRule>) -> Result<bool, Error> {
+        let should_save = Self::update_field(
+            &mut self.settings.tunnel_options.dns_options.split_dns,
+            rules,
+        );
+        self.update(should_save).await
+    }
+
     pub async fn set_wireguard_mtu(&mut self, mtu: Option<u16>) -> Result<bool, Error> {
         let should_save =
             Self::update_field(&mut self.settings.tunnel_options.wireguard.options.mtu, mtu);
@@ -268,6 +323,11 @@ impl SettingsPersister {
         self.update(should_save).await
     }
 
+    pub async fn set_connection_history(&mut self, enabled: bool) -> Result<bool, Error> {
+        let should_save = Self::update_field(&mut self.settings.connection_history, enabled);
+        self.update(should_save).await
+    }
+
     pub async fn set_bridge_settings(
         &mut self,
         bridge_settings: BridgeSettings,
@@ -281,7 +341,31 @@ impl SettingsPersister {
         self.update(should_save).await
     }
 
-    #[cfg(windows)]
+    #[cfg(target_os = "linux")]
+    pub async fn set_firewall_allow_rules(
+        &mut self,
+        rules: Vec<FirewallAllowRule>,
+    ) -> Result<bool, Error> {
+        let should_save = Self::update_field(&mut self.settings.firewall_allow_rules, rules);
+        self.update(should_save).await
+    }
+
+    #[cfg(target_os = "linux")]
+    pub async fn set_trusted_networks(
+        &mut self,
+        networks: Vec<TrustedNetwork>,
+    ) -> Result<bool, Error> {
+        let should_save = Self::update_field(&mut self.settings.trusted_networks, networks);
+        self.update(should_save).await
+    }
+
+    #[cfg(target_os = "linux")]
+    pub async fn set_hooks(&mut self, hooks: Vec<Hook>) -> Result<bool, Error> {
+        let should_save = Self::update_field(&mut self.settings.hooks, hooks);
+        self.update(should_save).await
+    }
+
+    #[cfg(any(windows, target_os = "linux"))]
     pub async fn set_split_tunnel_apps(&mut self, paths: HashSet<PathBuf>) -> Result<bool, Error> {
         let should_save = paths != self.settings.split_tunnel.apps;
         if should_save {
@@ -290,13 +374,41 @@ impl SettingsPersister {
         self.update(should_save).await
     }
 
-    #[cfg(windows)]
+    #[cfg(any(windows, target_os = "linux"))]
     pub async fn set_split_tunnel_state(&mut self, enabled: bool) -> Result<bool, Error> {
         let should_save =
             Self::update_field(&mut self.settings.split_tunnel.enable_exclusions, enabled);
         self.update(should_save).await
     }
 
+    #[cfg(target_os = "linux")]
+    pub async fn set_split_tunnel_mode(&mut self, mode: SplitTunnelMode) -> Result<bool, Error> {
+        let should_save = Self::update_field(&mut self.settings.split_tunnel.mode, mode);
+        self.update(should_save).await
+    }
+
+    #[cfg(target_os = "linux")]
+    pub async fn set_exclusion_profiles(
+        &mut self,
+        profiles: Vec<ExclusionProfile>,
+    ) -> Result<bool, Error> {
+        let should_save =
+            Self::update_field(&mut self.settings.split_tunnel.exclusion_profiles, profiles);
+        self.update(should_save).await
+    }
+
+    #[cfg(target_os = "linux")]
+    pub async fn set_split_tunnel_destinations(
+        &mut self,
+        destinations: ExcludedDestinations,
+    ) -> Result<bool, Error> {
+        let should_save = Self::update_field(
+            &mut self.settings.split_tunnel.excluded_destinations,
+            destinations,
+        );
+        self.update(should_save).await
+    }
+
     #[cfg(windows)]
     pub async fn set_use_wireguard_nt(&mut self, state: bool) -> Result<bool, Error> {
         let should_save = Self::update_field(
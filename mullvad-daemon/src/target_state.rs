@@ -1,7 +1,10 @@
+use chrono::{DateTime, Utc};
 use mullvad_types::states::TargetState;
+use serde::{Deserialize, Serialize};
 use std::{
     ops::Deref,
     path::{Path, PathBuf},
+    time::Duration,
 };
 use talpid_types::ErrorExt;
 use tokio::{fs, io};
@@ -10,9 +13,51 @@ use tokio::{fs, io};
 const DEFAULT_TARGET_STATE: TargetState = TargetState::Unsecured;
 const TARGET_START_STATE_FILE: &str = "target-start-state.json";
 
-/// Persists the target state to a file, which is only removed if the instance is dropped cleanly.
+/// Contents of the cache file.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+enum CachedTargetState {
+    State(TargetState),
+    /// The tunnel is disconnected until the given time, and is then connected again.
+    Paused {
+        paused_until: DateTime<Utc>,
+    },
+}
+
+/// The tunnel is disconnected for a limited time.
+#[derive(Clone, Copy)]
+struct Pause {
+    /// Counts time spent in suspend, unlike `std::time::Instant`.
+    started: talpid_time::Instant,
+    duration: Duration,
+    /// Wall-clock time when the pause ends. Only used to resume the pause after a restart.
+    paused_until: DateTime<Utc>,
+}
+
+impl Pause {
+    fn new(duration: Duration) -> Self {
+        let paused_until = chrono::Duration::from_std(duration)
+            .ok()
+            .and_then(|duration| Utc::now().checked_add_signed(duration))
+            .unwrap_or(chrono::MAX_DATETIME);
+        Pause {
+            started: talpid_time::Instant::now(),
+            duration,
+            paused_until,
+        }
+    }
+
+    fn remaining(&self) -> Duration {
+        self.duration
+            .saturating_sub(talpid_time::Instant::now().duration_since(self.started))
+    }
+}
+
+/// Persists the target state to a file, which is only removed if the instance is dropped cleanly
+/// while the tunnel is not paused.
 pub struct PersistentTargetState {
     state: TargetState,
+    pause: Option<Pause>,
     cache_path: PathBuf,
     locked: bool,
 }
@@ -22,24 +67,44 @@ impl PersistentTargetState {
     pub async fn new(cache_dir: &Path) -> Self {
         let cache_path = cache_dir.join(TARGET_START_STATE_FILE);
         let mut update_cache = false;
+        let mut pause = None;
         let state = match fs::read_to_string(&cache_path).await {
-            Ok(content) => serde_json::from_str(&content)
-                .map(|state| {
+            Ok(content) => match serde_json::from_str(&content) {
+                Ok(CachedTargetState::State(state)) => {
                     log::info!(
                         "Loaded cached target state \"{}\" from {}",
                         state,
                         cache_path.display()
                     );
                     state
-                })
-                .unwrap_or_else(|error| {
+                }
+                Ok(CachedTargetState::Paused { paused_until }) => {
+                    match (paused_until - Utc::now()).to_std() {
+                        Ok(remaining) if !remaining.is_zero() => {
+                            log::info!("Loaded tunnel pause until {}", paused_until);
+                            pause = Some(Pause {
+                                started: talpid_time::Instant::now(),
+                                duration: remaining,
+                                paused_until,
+                            });
+                            TargetState::Unsecured
+                        }
+                        _ => {
+                            log::info!("The tunnel pause ended while the daemon was not running");
+                            update_cache = true;
+                            TargetState::Secured
+                        }
+                    }
+                }
+                Err(error) => {
                     log::error!(
                         "{}",
                         error.display_chain_with_msg("Failed to parse cached target tunnel state")
                     );
                     update_cache = true;
                     TargetState::Secured
-                }),
+                }
+            },
             Err(error) => {
                 if error.kind() == io::ErrorKind::NotFound {
                     log::debug!("No cached target state to load");
@@ -56,6 +121,7 @@ impl PersistentTargetState {
         };
         let state = PersistentTargetState {
             state,
+            pause,
             cache_path,
             locked: false,
         };
@@ -70,6 +136,7 @@ impl PersistentTargetState {
         let cache_path = cache_dir.join(TARGET_START_STATE_FILE);
         let state = PersistentTargetState {
             state,
+            pause: None,
             cache_path,
             locked: false,
         };
@@ -77,13 +144,28 @@ impl PersistentTargetState {
         state
     }
 
+    /// Set the target state. This ends any pause.
     pub async fn set(&mut self, new_state: TargetState) {
-        if new_state != self.state {
+        if new_state != self.state || self.pause.is_some() {
             self.state = new_state;
+            self.pause = None;
             self.save().await;
         }
     }
 
+    /// Set the target state to unsecured for `duration`. The pause is remembered across
+    /// restarts, but it is up to the caller to set the target state to secured when it ends.
+    pub async fn pause(&mut self, duration: Duration) {
+        self.state = TargetState::Unsecured;
+        self.pause = Some(Pause::new(duration));
+        self.save().await;
+    }
+
+    /// Returns the time left until the pause ends, or `None` if the tunnel is not paused.
+    pub fn pause_remaining(&self) -> Option<Duration> {
+        self.pause.as_ref().map(Pause::remaining)
+    }
+
     /// Prevent the file from being removed when the instance is dropped.
     pub fn lock(&mut self) {
         self.locked = true;
@@ -91,7 +173,7 @@ impl PersistentTargetState {
 
     /// Async destructor
     pub async fn finalize(mut self) {
-        if self.locked {
+        if self.locked || self.pause.is_some() {
             return;
         }
         let _ = fs::remove_file(&self.cache_path).await.map_err(|error| {
@@ -111,7 +193,13 @@ impl PersistentTargetState {
             "Saving tunnel target state to {}",
             self.cache_path.display()
         );
-        match serde_json::to_string(&self.state) {
+        let cached = match self.pause {
+            Some(pause) => CachedTargetState::Paused {
+                paused_until: pause.paused_until,
+            },
+            None => CachedTargetState::State(self.state),
+        };
+        match serde_json::to_string(&cached) {
             Ok(data) => {
                 if let Err(error) = fs::write(&self.cache_path, data).await {
                     log::error!(
@@ -132,7 +220,7 @@ impl PersistentTargetState {
 
 impl Drop for PersistentTargetState {
     fn drop(&mut self) {
-        if self.locked {
+        if self.locked || self.pause.is_some() {
             return;
         }
         let _ = std::fs::remove_file(&self.cache_path).map_err(|error| {
@@ -153,3 +241,27 @@ impl Deref for PersistentTargetState {
         &self.state
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_cached_target_state_format() {
+        // Caches written before pauses were added must still be readable
+        assert_eq!(
+            serde_json::from_str::<CachedTargetState>("\"secured\"").unwrap(),
+            CachedTargetState::State(TargetState::Secured)
+        );
+
+        let paused = CachedTargetState::Paused {
+            paused_until: "2022-03-01T12:00:00Z".parse().unwrap(),
+        };
+        let serialized = serde_json::to_string(&paused).unwrap();
+        assert_eq!(serialized, r#"{"paused_until":"2022-03-01T12:00:00Z"}"#);
+        assert_eq!(
+            serde_json::from_str::<CachedTargetState>(&serialized).unwrap(),
+            paused
+        );
+    }
+}
@@ -0,0 +1,175 @@
+use mullvad_types::states::TargetState;
+use talpid_types::net::trusted_network::{NetworkIdentity, TrustedNetwork};
+
+/// Decides the target state from the networks that the host is connected to. The tunnel is
+/// disconnected on trusted networks and connected on all other networks.
+///
+/// A target state explicitly requested by the user always wins. An explicit disconnect is
+/// released once the host is on a trusted network, where the policy agrees with it, but an
+/// explicit connect is kept until the user requests another target state, so that the tunnel is
+/// never disconnected against the will of the user.
+#[derive(Debug, Default)]
+pub struct TrustedNetworkPolicy {
+    identity: Option<NetworkIdentity>,
+    user_target_state: Option<TargetState>,
+}
+
+impl TrustedNetworkPolicy {
+    /// Returns the identity of the networks that the host is connected to, if it is known.
+    pub fn identity(&self) -> Option<&NetworkIdentity> {
+        self.identity.as_ref()
+    }
+
+    /// Updates the current network identity and returns the target state that should be
+    /// applied, if any. Going offline is not considered a network change, so it does not affect
+    /// the target state.
+    pub fn network_changed(
+        &mut self,
+        identity: NetworkIdentity,
+        trusted_networks: &[TrustedNetwork],
+    ) -> Option<TargetState> {
+        if identity.connections.is_empty() || self.identity.as_ref() == Some(&identity) {
+            return None;
+        }
+        self.identity = Some(identity);
+        self.evaluate(trusted_networks)
+    }
+
+    /// Returns the target state that should be applied after the trusted networks changed, if
+    /// any.
+    pub fn rules_changed(&mut self, trusted_networks: &[TrustedNetwork]) -> Option<TargetState> {
+        self.evaluate(trusted_networks)
+    }
+
+    /// Registers that the user explicitly requested `target_state`. No other target state is
+    /// suggested while the request is in effect.
+    pub fn user_override(&mut self, target_state: TargetState) {
+        self.user_target_state = Some(target_state);
+    }
+
+    fn evaluate(&mut self, trusted_networks: &[TrustedNetwork]) -> Option<TargetState> {
+        if trusted_networks.is_empty() {
+            return None;
+        }
+        let target_state = if self.identity.as_ref()?.is_trusted(trusted_networks) {
+            TargetState::Unsecured
+        } else {
+            TargetState::Secured
+        };
+        match self.user_target_state {
+            Some(TargetState::Unsecured) if target_state == TargetState::Unsecured => {
+                self.user_target_state = None;
+                None
+            }
+            Some(_) => None,
+            None => Some(target_state),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use talpid_types::net::trusted_network::NetworkConnection;
+
+    fn identity(interface: &str, ssid: Option<&str>) -> NetworkIdentity {
+        NetworkIdentity {
+            connections: vec![NetworkConnection {
+                interface: interface.to_owned(),
+                gateway_mac: None,
+                ssid: ssid.map(str::to_owned),
+            }],
+        }
+    }
+
+    #[test]
+    fn test_trusted_network_disconnects() {
+        let rules = [TrustedNetwork::Ssid("Home".to_owned())];
+        let mut policy = TrustedNetworkPolicy::default();
+
+        assert_eq!(
+            policy.network_changed(identity("wlan0", Some("Home")), &rules),
+            Some(TargetState::Unsecured)
+        );
+        assert_eq!(
+            policy.network_changed(identity("wlan0", Some("Cafe")), &rules),
+            Some(TargetState::Secured)
+        );
+        assert_eq!(policy.rules_changed(&[]), None);
+    }
+
+    #[test]
+    fn test_user_connect_is_never_overridden() {
+        let rules = [TrustedNetwork::Interface("eth0".to_owned())];
+        let mut policy = TrustedNetworkPolicy::default();
+
+        assert_eq!(
+            policy.network_changed(identity("eth0", None), &rules),
+            Some(TargetState::Unsecured)
+        );
+
+        policy.user_override(TargetState::Secured);
+        assert_eq!(policy.rules_changed(&rules), None);
+        assert_eq!(policy.network_changed(identity("eth0", None), &rules), None);
+
+        // Going offline or moving to another network does not clear the override
+        assert_eq!(
+            policy.network_changed(NetworkIdentity::default(), &rules),
+            None
+        );
+        assert_eq!(
+            policy.network_changed(identity("wlan0", None), &rules),
+            None
+        );
+        assert_eq!(policy.network_changed(identity("eth0", None), &rules), None);
+
+        policy.user_override(TargetState::Unsecured);
+        assert_eq!(
+            policy.network_changed(identity("wlan0", None), &rules),
+            None
+        );
+    }
+
+    #[test]
+    fn test_user_override_survives_roaming() {
+        let rules = [TrustedNetwork::Ssid("Home".to_owned())];
+        let mut policy = TrustedNetworkPolicy::default();
+
+        assert_eq!(
+            policy.network_changed(identity("wlan0", Some("Home")), &rules),
+            Some(TargetState::Unsecured)
+        );
+        policy.user_override(TargetState::Secured);
+
+        // Roaming to another access point of the same network changes the gateway MAC address
+        let mut roamed = identity("wlan0", Some("Home"));
+        roamed.connections[0].gateway_mac = Some("02:00:00:00:00:01".to_owned());
+        assert_eq!(policy.network_changed(roamed, &rules), None);
+    }
+
+    #[test]
+    fn test_user_disconnect_is_released_on_trusted_network() {
+        let rules = [TrustedNetwork::Ssid("Home".to_owned())];
+        let mut policy = TrustedNetworkPolicy::default();
+
+        assert_eq!(
+            policy.network_changed(identity("wlan0", Some("Cafe")), &rules),
+            Some(TargetState::Secured)
+        );
+        policy.user_override(TargetState::Unsecured);
+        assert_eq!(
+            policy.network_changed(identity("wlan0", Some("Airport")), &rules),
+            None
+        );
+
+        // The policy agrees with the user on a trusted network, so the override ends there
+        assert_eq!(
+            policy.network_changed(identity("wlan0", Some("Home")), &rules),
+            None
+        );
+        assert_eq!(
+            policy.network_changed(identity("wlan0", Some("Cafe")), &rules),
+            Some(TargetState::Secured)
+        );
+    }
+}
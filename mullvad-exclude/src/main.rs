@@ -5,14 +5,14 @@ use std::{
     convert::Infallible,
     env,
     error::Error as StdError,
-    ffi::{CString, NulError},
+    ffi::{CString, NulError, OsString},
     fs,
     io::{self, BufWriter, Write},
     os::unix::ffi::OsStrExt,
 };
 
 #[cfg(target_os = "linux")]
-use talpid_types::cgroup::{find_net_cls_mount, SPLIT_TUNNEL_CGROUP_NAME};
+use talpid_types::cgroup::{find_exclusion_cgroup, ExclusionProfile};
 
 #[cfg(target_os = "linux")]
 const PROGRAM_NAME: &str = "mullvad-exclude";
@@ -39,11 +39,14 @@ enum Error {
     #[error(display = "An argument contains interior nul bytes")]
     ArgumentNulError(#[error(source)] NulError),
 
-    #[error(display = "Failed to find net_cls controller")]
-    FindNetClsController(#[error(source)] io::Error),
+    #[error(display = "Failed to find the split tunneling cgroup")]
+    FindCgroup(#[error(source)] io::Error),
 
-    #[error(display = "No net_cls controller")]
-    NoNetClsController,
+    #[error(display = "No net_cls controller or cgroup v2 hierarchy is mounted")]
+    NoCgroup,
+
+    #[error(display = "There is no exclusion profile named \"{}\"", _0)]
+    UnknownProfile(String),
 }
 
 fn main() {
@@ -52,7 +55,7 @@ fn main() {
         Err(Error::InvalidArguments) => {
             let mut args = env::args();
             let program = args.next().unwrap_or(PROGRAM_NAME.to_string());
-            eprintln!("Usage: {} COMMAND [ARGS]", program);
+            eprintln!("Usage: {} [--profile NAME] COMMAND [ARGS]", program);
             std::process::exit(1);
         }
         Err(e) => {
@@ -72,23 +75,42 @@ fn main() {
 
 #[cfg(target_os = "linux")]
 fn run() -> Result<Infallible, Error> {
-    let mut args_iter = env::args_os().skip(1);
-    let program = args_iter.next().ok_or(Error::InvalidArguments)?;
+    let mut args: Vec<OsString> = env::args_os().skip(1).collect();
+    let profile = if args.first().map(|arg| arg == "--profile").unwrap_or(false) {
+        if args.len() < 2 {
+            return Err(Error::InvalidArguments);
+        }
+        let name = args.drain(..2).nth(1).unwrap();
+        Some(name.into_string().map_err(|_| Error::InvalidArguments)?)
+    } else {
+        None
+    };
+
+    let program = args.first().ok_or(Error::InvalidArguments)?;
     let program = CString::new(program.as_bytes()).map_err(Error::ArgumentNulError)?;
 
-    let args: Vec<CString> = env::args_os()
-        .skip(1)
+    let args: Vec<CString> = args
+        .iter()
         .map(|arg| CString::new(arg.as_bytes()))
         .collect::<Result<Vec<CString>, NulError>>()
         .map_err(Error::ArgumentNulError)?;
 
-    let cgroup_dir = find_net_cls_mount()
-        .map_err(Error::FindNetClsController)?
-        .ok_or(Error::NoNetClsController)?;
+    let cgroup = find_exclusion_cgroup()
+        .map_err(Error::FindCgroup)?
+        .ok_or(Error::NoCgroup)?;
 
-    let procs_path = cgroup_dir
-        .join(SPLIT_TUNNEL_CGROUP_NAME)
-        .join("cgroup.procs");
+    let cgroup_path = match profile {
+        Some(name) => {
+            let path = cgroup.profile_path(&name);
+            // Profile cgroups are created by the daemon
+            if !ExclusionProfile::is_valid_name(&name) || !path.exists() {
+                return Err(Error::UnknownProfile(name));
+            }
+            path
+        }
+        None => cgroup.exclusions_path(),
+    };
+    let procs_path = cgroup_path.join("cgroup.procs");
 
     let file = fs::OpenOptions::new()
         .write(true)
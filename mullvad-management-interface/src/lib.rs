@@ -69,23 +69,78 @@ pub async fn new_rpc_client() -> Result<ManagementServiceClient, Error> {
 
 pub type ServerJoinHandle = tokio::task::JoinHandle<Result<(), Error>>;
 
+/// Credentials of the process that a request to the management interface was received from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerCredentials {
+    /// Effective user ID of the process that connected to the socket.
+    pub uid: u32,
+}
+
+impl PeerCredentials {
+    /// Returns the credentials of the client that sent `request`. Returns `None` if they are not
+    /// known, which is always the case on Windows.
+    pub fn of<T>(request: &Request<T>) -> Option<PeerCredentials> {
+        request
+            .extensions()
+            .get::<Option<PeerCredentials>>()
+            .copied()
+            .flatten()
+    }
+
+    /// Returns whether the client is running as root.
+    pub fn is_root(&self) -> bool {
+        self.uid == 0
+    }
+}
+
 pub async fn spawn_rpc_server<T: ManagementService, F: Future<Output = ()> + Send + 'static>(
     service: T,
     abort_rx: F,
 ) -> std::result::Result<ServerJoinHandle, Error> {
+    #[cfg(windows)]
     use futures::stream::TryStreamExt;
+    #[cfg(windows)]
     use parity_tokio_ipc::SecurityAttributes;
 
     let socket_path = mullvad_paths::get_rpc_socket_path();
 
-    let mut endpoint = IpcEndpoint::new(socket_path.to_string_lossy().to_string());
-    endpoint.set_security_attributes(
-        SecurityAttributes::allow_everyone_create()
-            .map_err(Error::SecurityAttributes)?
-            .set_mode(0o766)
-            .map_err(Error::SecurityAttributes)?,
-    );
-    let incoming = endpoint.incoming().map_err(Error::StartServerError)?;
+    // The socket is bound directly on Unix, since the IPC endpoint does not expose the
+    // credentials of its clients
+    #[cfg(unix)]
+    let incoming = {
+        let _ = fs::remove_file(&socket_path);
+        let listener =
+            tokio::net::UnixListener::bind(&socket_path).map_err(Error::StartServerError)?;
+        fs::set_permissions(&socket_path, PermissionsExt::from_mode(0o766))
+            .map_err(Error::PermissionsError)?;
+        futures::stream::poll_fn(move |cx| {
+            listener.poll_accept(cx).map(|result| {
+                Some(result.map(|(stream, _)| {
+                    let credentials = stream
+                        .peer_cred()
+                        .map(|credentials| PeerCredentials {
+                            uid: credentials.uid(),
+                        })
+                        .ok();
+                    StreamBox(stream, credentials)
+                }))
+            })
+        })
+    };
+    #[cfg(windows)]
+    let incoming = {
+        let mut endpoint = IpcEndpoint::new(socket_path.to_string_lossy().to_string());
+        endpoint.set_security_attributes(
+            SecurityAttributes::allow_everyone_create()
+                .map_err(Error::SecurityAttributes)?
+                .set_mode(0o766)
+                .map_err(Error::SecurityAttributes)?,
+        );
+        endpoint
+            .incoming()
+            .map_err(Error::StartServerError)?
+            .map_ok(|stream| StreamBox(stream, None))
+    };
 
     #[cfg(unix)]
     if let Some(group_name) = &*MULLVAD_MANAGEMENT_SOCKET_GROUP {
@@ -100,19 +155,19 @@ pub async fn spawn_rpc_server<T: ManagementService, F: Future<Output = ()> + Sen
     Ok(tokio::spawn(async move {
         Server::builder()
             .add_service(ManagementServiceServer::new(service))
-            .serve_with_incoming_shutdown(incoming.map_ok(StreamBox), abort_rx)
+            .serve_with_incoming_shutdown(incoming, abort_rx)
             .await
             .map_err(Error::GrpcTransportError)
     }))
 }
 
 #[derive(Debug)]
-struct StreamBox<T: AsyncRead + AsyncWrite>(pub T);
+struct StreamBox<T: AsyncRead + AsyncWrite>(pub T, Option<PeerCredentials>);
 impl<T: AsyncRead + AsyncWrite> Connected for StreamBox<T> {
-    type ConnectInfo = Option<()>;
+    type ConnectInfo = Option<PeerCredentials>;
 
     fn connect_info(&self) -> Self::ConnectInfo {
-        None
+        self.1
     }
 }
 impl<T: AsyncRead + AsyncWrite + Unpin> AsyncRead for StreamBox<T> {
@@ -210,6 +210,35 @@ impl From<mullvad_types::states::TunnelState> for TunnelState {
     }
 }
 
+impl From<mullvad_types::connection_history::ConnectionEntry> for ConnectionHistoryEntry {
+    fn from(entry: mullvad_types::connection_history::ConnectionEntry) -> Self {
+        use std::time::SystemTime;
+
+        ConnectionHistoryEntry {
+            started: Some(Timestamp::from(SystemTime::from(entry.started))),
+            ended: entry
+                .ended
+                .map(|ended| Timestamp::from(SystemTime::from(ended))),
+            tunnel_type: match entry.tunnel_type {
+                talpid_types::net::TunnelType::Wireguard => i32::from(TunnelType::Wireguard),
+                talpid_types::net::TunnelType::OpenVpn => i32::from(TunnelType::Openvpn),
+            },
+            exit_hostname: entry.exit_hostname.unwrap_or_default(),
+            entry_hostname: entry.entry_hostname.unwrap_or_default(),
+            bridge_hostname: entry.bridge_hostname.unwrap_or_default(),
+            obfuscator_hostname: entry.obfuscator_hostname.unwrap_or_default(),
+            traffic: entry.traffic.map(|traffic| TrafficStats {
+                rx_bytes: traffic.rx_bytes,
+                tx_bytes: traffic.tx_bytes,
+            }),
+            error_cause: entry
+                .error_cause
+                .map(|cause| cause.to_string())
+                .unwrap_or_default(),
+        }
+    }
+}
+
 impl From<mullvad_types::device::Device> for Device {
     fn from(device: mullvad_types::device::Device) -> Self {
         Device {
@@ -377,6 +406,99 @@ impl From<talpid_types::net::TransportProtocol> for TransportProtocol {
     }
 }
 
+impl From<&talpid_types::net::lan::LanNetworks> for LanNetworks {
+    fn from(lan_networks: &talpid_types::net::lan::LanNetworks) -> Self {
+        Self {
+            networks: lan_networks
+                .networks
+                .iter()
+                .map(|net| net.to_string())
+                .collect(),
+            multicast_networks: lan_networks
+                .multicast_networks
+                .iter()
+                .map(|net| net.to_string())
+                .collect(),
+        }
+    }
+}
+
+impl From<&talpid_types::net::FirewallAllowRule> for FirewallAllowRule {
+    fn from(rule: &talpid_types::net::FirewallAllowRule) -> Self {
+        use talpid_types::net::AllowedDirection;
+        Self {
+            network: rule.network.to_string(),
+            protocol: rule.protocol.map(|protocol| TransportProtocolConstraint {
+                protocol: i32::from(TransportProtocol::from(protocol)),
+            }),
+            port: u32::from(rule.port.unwrap_or(0)),
+            direction: i32::from(match rule.direction {
+                AllowedDirection::Outbound => firewall_allow_rule::Direction::Outbound,
+                AllowedDirection::Inbound => firewall_allow_rule::Direction::Inbound,
+            }),
+            outside_tunnel: rule.outside_tunnel,
+        }
+    }
+}
+
+impl From<&talpid_types::net::trusted_network::TrustedNetwork> for TrustedNetwork {
+    fn from(network: &talpid_types::net::trusted_network::TrustedNetwork) -> Self {
+        use talpid_types::net::trusted_network::TrustedNetwork as Network;
+        Self {
+            network: Some(match network {
+                Network::GatewayMac(address) => {
+                    trusted_network::Network::GatewayMac(address.clone())
+                }
+                Network::Ssid(ssid) => trusted_network::Network::Ssid(ssid.clone()),
+                Network::Interface(interface) => {
+                    trusted_network::Network::Interface(interface.clone())
+                }
+            }),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl From<&mullvad_types::hooks::Hook> for Hook {
+    fn from(hook: &mullvad_types::hooks::Hook) -> Self {
+        use mullvad_types::hooks::HookEvent;
+        Self {
+            path: hook.path.to_string_lossy().into_owned(),
+            args: hook.args.clone(),
+            timeout: hook.timeout_secs,
+            events: hook
+                .events
+                .iter()
+                .map(|event| {
+                    i32::from(match event {
+                        HookEvent::Connecting => hook::Event::Connecting,
+                        HookEvent::Connected => hook::Event::Connected,
+                        HookEvent::Disconnecting => hook::Event::Disconnecting,
+                        HookEvent::Disconnected => hook::Event::Disconnected,
+                        HookEvent::Error => hook::Event::Error,
+                    })
+                })
+                .collect(),
+        }
+    }
+}
+
+impl From<talpid_types::net::trusted_network::NetworkIdentity> for NetworkIdentity {
+    fn from(identity: talpid_types::net::trusted_network::NetworkIdentity) -> Self {
+        Self {
+            connections: identity
+                .connections
+                .into_iter()
+                .map(|connection| NetworkConnection {
+                    interface: connection.interface,
+                    gateway_mac: connection.gateway_mac.unwrap_or_default(),
+                    ssid: connection.ssid.unwrap_or_default(),
+                })
+                .collect(),
+        }
+    }
+}
+
 impl From<talpid_types::net::IpVersion> for IpVersion {
     fn from(version: talpid_types::net::IpVersion) -> Self {
         match version {
@@ -447,7 +569,7 @@ impl From<mullvad_types::relay_constraints::LocationConstraint> for RelayLocatio
 
 impl From<&mullvad_types::settings::Settings> for Settings {
     fn from(settings: &mullvad_types::settings::Settings) -> Self {
-        #[cfg(windows)]
+        #[cfg(any(windows, target_os = "linux"))]
         let split_tunnel = {
             let mut converted_list = vec![];
             for path in settings.split_tunnel.apps.clone().iter() {
@@ -462,11 +584,63 @@ impl From<&mullvad_types::settings::Settings> for Settings {
             Some(SplitTunnelSettings {
                 enable_exclusions: settings.split_tunnel.enable_exclusions,
                 apps: converted_list,
+                #[cfg(target_os = "linux")]
+                mode: Some(SplitTunnelMode::from(settings.split_tunnel.mode)),
+                #[cfg(not(target_os = "linux"))]
+                mode: None,
+                #[cfg(target_os = "linux")]
+                excluded_destinations: Some(ExcludedDestinations::from(
+                    &settings.split_tunnel.excluded_destinations,
+                )),
+                #[cfg(not(target_os = "linux"))]
+                excluded_destinations: None,
+                #[cfg(target_os = "linux")]
+                exclusion_profiles: settings
+                    .split_tunnel
+                    .exclusion_profiles
+                    .iter()
+                    .map(ExclusionProfile::from)
+                    .collect(),
+                #[cfg(not(target_os = "linux"))]
+                exclusion_profiles: vec![],
             })
         };
-        #[cfg(not(windows))]
+        #[cfg(not(any(windows, target_os = "linux")))]
         let split_tunnel = None;
 
+        #[cfg(target_os = "linux")]
+        let firewall_allow_rules = settings
+            .firewall_allow_rules
+            .iter()
+            .map(FirewallAllowRule::from)
+            .collect();
+        #[cfg(not(target_os = "linux"))]
+        let firewall_allow_rules = vec![];
+
+        #[cfg(target_os = "linux")]
+        let trusted_networks = settings
+            .trusted_networks
+            .iter()
+            .map(TrustedNetwork::from)
+            .collect();
+        #[cfg(not(target_os = "linux"))]
+        let trusted_networks = vec![];
+
+        #[cfg(target_os = "linux")]
+        let hooks = settings.hooks.iter().map(Hook::from).collect();
+        #[cfg(not(target_os = "linux"))]
+        let hooks = vec![];
+
+        #[cfg(target_os = "linux")]
+        let lockdown_mode = settings.lockdown_mode;
+        #[cfg(not(target_os = "linux"))]
+        let lockdown_mode = false;
+
+        #[cfg(unix)]
+        let lan_networks = Some(LanNetworks::from(&settings.lan_networks));
+        #[cfg(not(unix))]
+        let lan_networks = None;
+
         Self {
             relay_settings: Some(RelaySettings::from(settings.get_relay_settings())),
             bridge_settings: Some(BridgeSettings::from(settings.bridge_settings.clone())),
@@ -478,6 +652,12 @@ impl From<&mullvad_types::settings::Settings> for Settings {
             show_beta_releases: settings.show_beta_releases,
             obfuscation_settings: Some(ObfuscationSettings::from(&settings.obfuscation_settings)),
             split_tunnel,
+            firewall_allow_rules,
+            lan_networks,
+            trusted_networks,
+            hooks,
+            connection_history: settings.connection_history,
+            lockdown_mode,
         }
     }
 }
@@ -495,6 +675,46 @@ impl From<mullvad_types::relay_constraints::BridgeState> for BridgeState {
     }
 }
 
+#[cfg(target_os = "linux")]
+impl From<talpid_types::cgroup::SplitTunnelMode> for SplitTunnelMode {
+    fn from(mode: talpid_types::cgroup::SplitTunnelMode) -> Self {
+        use talpid_types::cgroup::SplitTunnelMode;
+        Self {
+            mode: i32::from(match mode {
+                SplitTunnelMode::Exclude => split_tunnel_mode::Mode::Exclude,
+                SplitTunnelMode::IncludeOnly => split_tunnel_mode::Mode::IncludeOnly,
+            }),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl From<&talpid_types::cgroup::ExclusionProfile> for ExclusionProfile {
+    fn from(profile: &talpid_types::cgroup::ExclusionProfile) -> Self {
+        use talpid_types::cgroup::ProfileDns;
+        Self {
+            name: profile.name.clone(),
+            dns: i32::from(match profile.dns {
+                ProfileDns::Tunnel => exclusion_profile::Dns::Tunnel,
+                ProfileDns::Bypass => exclusion_profile::Dns::Bypass,
+            }),
+        }
+    }
+}
+
+impl From<&talpid_types::net::ExcludedDestinations> for ExcludedDestinations {
+    fn from(destinations: &talpid_types::net::ExcludedDestinations) -> Self {
+        Self {
+            networks: destinations
+                .networks
+                .iter()
+                .map(|net| net.to_string())
+                .collect(),
+            domains: destinations.domains.clone(),
+        }
+    }
+}
+
 impl From<&mullvad_types::relay_constraints::ObfuscationSettings> for ObfuscationSettings {
     fn from(settings: &mullvad_types::relay_constraints::ObfuscationSettings) -> Self {
         use mullvad_types::relay_constraints::SelectedObfuscation;
@@ -655,7 +875,67 @@ impl From<&mullvad_types::settings::DnsOptions> for DnsOptions {
                     .iter()
                     .map(|addr| addr.to_string())
                     .collect(),
+                #[cfg(target_os = "linux")]
+                encrypted_servers: options
+                    .custom_options
+                    .encrypted_servers
+                    .iter()
+                    .map(EncryptedDnsServer::from)
+                    .collect(),
+                #[cfg(not(target_os = "linux"))]
+                encrypted_servers: vec![],
             }),
+            #[cfg(target_os = "linux")]
+            blocklists: options.blocklists.iter().map(DnsBlocklist::from).collect(),
+            #[cfg(not(target_os = "linux"))]
+            blocklists: vec![],
+            #[cfg(target_os = "linux")]
+            split_dns: options.split_dns.iter().map(SplitDnsRule::from).collect(),
+            #[cfg(not(target_os = "linux"))]
+            split_dns: vec![],
+        }
+    }
+}
+
+impl From<&talpid_types::net::dns::EncryptedDnsServer> for EncryptedDnsServer {
+    fn from(server: &talpid_types::net::dns::EncryptedDnsServer) -> Self {
+        use talpid_types::net::dns::EncryptedDnsProtocol;
+
+        Self {
+            protocol: match server.protocol {
+                EncryptedDnsProtocol::Https => encrypted_dns_server::Protocol::Https as i32,
+                EncryptedDnsProtocol::Tls => encrypted_dns_server::Protocol::Tls as i32,
+            },
+            hostname: server.hostname.clone(),
+            port: u32::from(server.port),
+            path: server.path.clone(),
+            addresses: server
+                .addresses
+                .iter()
+                .map(|addr| addr.to_string())
+                .collect(),
+        }
+    }
+}
+
+impl From<&talpid_types::net::dns::DnsBlocklist> for DnsBlocklist {
+    fn from(blocklist: &talpid_types::net::dns::DnsBlocklist) -> Self {
+        Self {
+            path: blocklist.path.to_string_lossy().into_owned(),
+            enabled: blocklist.enabled,
+        }
+    }
+}
+
+impl From<&talpid_types::net::dns::SplitDnsRule> for SplitDnsRule {
+    fn from(rule: &talpid_types::net::dns::SplitDnsRule) -> Self {
+        Self {
+            domain: rule.domain.clone(),
+            resolvers: rule
+                .resolvers
+                .iter()
+                .map(|resolver| resolver.to_string())
+                .collect(),
         }
     }
 }
@@ -1357,6 +1637,53 @@ impl TryFrom<BridgeState> for mullvad_types::relay_constraints::BridgeState {
     }
 }
 
+#[cfg(target_os = "linux")]
+impl TryFrom<ExclusionProfile> for talpid_types::cgroup::ExclusionProfile {
+    type Error = FromProtobufTypeError;
+
+    fn try_from(profile: ExclusionProfile) -> Result<Self, Self::Error> {
+        use talpid_types::cgroup::ProfileDns;
+
+        if !talpid_types::cgroup::ExclusionProfile::is_valid_name(&profile.name) {
+            return Err(FromProtobufTypeError::InvalidArgument(
+                "invalid exclusion profile name",
+            ));
+        }
+        let dns = match exclusion_profile::Dns::from_i32(profile.dns) {
+            Some(exclusion_profile::Dns::Tunnel) => ProfileDns::Tunnel,
+            Some(exclusion_profile::Dns::Bypass) => ProfileDns::Bypass,
+            None => {
+                return Err(FromProtobufTypeError::InvalidArgument(
+                    "invalid exclusion profile DNS option",
+                ))
+            }
+        };
+        Ok(talpid_types::cgroup::ExclusionProfile {
+            name: profile.name,
+            dns,
+        })
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl TryFrom<SplitTunnelMode> for talpid_types::cgroup::SplitTunnelMode {
+    type Error = FromProtobufTypeError;
+
+    fn try_from(mode: SplitTunnelMode) -> Result<Self, Self::Error> {
+        match split_tunnel_mode::Mode::from_i32(mode.mode) {
+            Some(split_tunnel_mode::Mode::Exclude) => {
+                Ok(talpid_types::cgroup::SplitTunnelMode::Exclude)
+            }
+            Some(split_tunnel_mode::Mode::IncludeOnly) => {
+                Ok(talpid_types::cgroup::SplitTunnelMode::IncludeOnly)
+            }
+            None => Err(FromProtobufTypeError::InvalidArgument(
+                "invalid split tunnel mode",
+            )),
+        }
+    }
+}
+
 impl TryFrom<TunnelOptions> for mullvad_types::settings::TunnelOptions {
     type Error = FromProtobufTypeError;
 
@@ -1478,11 +1805,119 @@ impl TryFrom<DnsOptions> for mullvad_types::settings::DnsOptions {
                         })
                     })
                     .collect::<Result<Vec<_>, _>>()?,
+                #[cfg(target_os = "linux")]
+                encrypted_servers: custom_options
+                    .encrypted_servers
+                    .into_iter()
+                    .map(talpid_types::net::dns::EncryptedDnsServer::try_from)
+                    .collect::<Result<Vec<_>, _>>()?,
             },
+            #[cfg(target_os = "linux")]
+            blocklists: options
+                .blocklists
+                .into_iter()
+                .map(talpid_types::net::dns::DnsBlocklist::try_from)
+                .collect::<Result<Vec<_>, _>>()?,
+            #[cfg(target_os = "linux")]
+            split_dns: options
+                .split_dns
+                .into_iter()
+                .map(talpid_types::net::dns::SplitDnsRule::try_from)
+                .collect::<Result<Vec<_>, _>>()?,
+        })
+    }
+}
+
+impl TryFrom<EncryptedDnsServer> for talpid_types::net::dns::EncryptedDnsServer {
+    type Error = FromProtobufTypeError;
+
+    fn try_from(server: EncryptedDnsServer) -> Result<Self, Self::Error> {
+        use talpid_types::net::dns::{is_valid_hostname, EncryptedDnsProtocol};
+
+        let protocol = match encrypted_dns_server::Protocol::from_i32(server.protocol) {
+            Some(encrypted_dns_server::Protocol::Https) => EncryptedDnsProtocol::Https,
+            Some(encrypted_dns_server::Protocol::Tls) => EncryptedDnsProtocol::Tls,
+            None => {
+                return Err(FromProtobufTypeError::InvalidArgument(
+                    "invalid encrypted DNS protocol",
+                ))
+            }
+        };
+        if !is_valid_hostname(&server.hostname) {
+            return Err(FromProtobufTypeError::InvalidArgument(
+                "invalid encrypted DNS server hostname",
+            ));
+        }
+        let port = u16::try_from(server.port)
+            .ok()
+            .filter(|port| *port != 0)
+            .ok_or(FromProtobufTypeError::InvalidArgument(
+                "invalid encrypted DNS server port",
+            ))?;
+        let path = match protocol {
+            EncryptedDnsProtocol::Https
+                if !server.path.starts_with('/') || server.path.contains(char::is_whitespace) =>
+            {
+                return Err(FromProtobufTypeError::InvalidArgument(
+                    "invalid DNS-over-HTTPS path",
+                ))
+            }
+            EncryptedDnsProtocol::Https => server.path,
+            EncryptedDnsProtocol::Tls => String::new(),
+        };
+        let addresses = server
+            .addresses
+            .iter()
+            .map(|addr| addr.parse())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| FromProtobufTypeError::InvalidArgument("invalid IP address"))?;
+        if addresses.is_empty() {
+            return Err(FromProtobufTypeError::InvalidArgument(
+                "encrypted DNS server has no addresses",
+            ));
+        }
+        Ok(talpid_types::net::dns::EncryptedDnsServer {
+            protocol,
+            hostname: server.hostname.to_ascii_lowercase(),
+            port,
+            path,
+            addresses,
         })
     }
 }
 
+impl TryFrom<DnsBlocklist> for talpid_types::net::dns::DnsBlocklist {
+    type Error = FromProtobufTypeError;
+
+    fn try_from(blocklist: DnsBlocklist) -> Result<Self, Self::Error> {
+        let path = std::path::PathBuf::from(blocklist.path);
+        if !path.is_absolute() {
+            return Err(FromProtobufTypeError::InvalidArgument(
+                "DNS blocklist path must be absolute",
+            ));
+        }
+        Ok(talpid_types::net::dns::DnsBlocklist {
+            path,
+            enabled: blocklist.enabled,
+        })
+    }
+}
+
+impl TryFrom<SplitDnsRule> for talpid_types::net::dns::SplitDnsRule {
+    type Error = FromProtobufTypeError;
+
+    fn try_from(rule: SplitDnsRule) -> Result<Self, Self::Error> {
+        let resolvers = rule
+            .resolvers
+            .iter()
+            .map(|resolver| resolver.parse())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| FromProtobufTypeError::InvalidArgument("invalid IP address"))?;
+        talpid_types::net::dns::SplitDnsRule::new(&rule.domain, resolvers)
+            .map_err(|_| FromProtobufTypeError::InvalidArgument("invalid split DNS rule"))
+    }
+}
+
 impl TryFrom<TransportPort> for mullvad_types::relay_constraints::TransportPort {
     type Error = FromProtobufTypeError;
 
@@ -1498,6 +1933,160 @@ impl TryFrom<TransportPort> for mullvad_types::relay_constraints::TransportPort
     }
 }
 
+impl TryFrom<LanNetworks> for talpid_types::net::lan::LanNetworks {
+    type Error = FromProtobufTypeError;
+
+    fn try_from(lan_networks: LanNetworks) -> Result<Self, Self::Error> {
+        let parse_networks = |networks: Vec<String>| {
+            networks
+                .iter()
+                .map(|net| net.parse())
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|_| FromProtobufTypeError::InvalidArgument("invalid network"))
+        };
+        Ok(talpid_types::net::lan::LanNetworks {
+            networks: parse_networks(lan_networks.networks)?,
+            multicast_networks: parse_networks(lan_networks.multicast_networks)?,
+        })
+    }
+}
+
+impl TryFrom<ExcludedDestinations> for talpid_types::net::ExcludedDestinations {
+    type Error = FromProtobufTypeError;
+
+    fn try_from(destinations: ExcludedDestinations) -> Result<Self, Self::Error> {
+        let networks = destinations
+            .networks
+            .iter()
+            .map(|net| net.parse())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| FromProtobufTypeError::InvalidArgument("invalid network"))?;
+        Ok(talpid_types::net::ExcludedDestinations {
+            networks,
+            domains: destinations.domains,
+        })
+    }
+}
+
+impl TryFrom<FirewallAllowRule> for talpid_types::net::FirewallAllowRule {
+    type Error = FromProtobufTypeError;
+
+    fn try_from(rule: FirewallAllowRule) -> Result<Self, Self::Error> {
+        use talpid_types::net::AllowedDirection;
+
+        let network = rule
+            .network
+            .parse()
+            .map_err(|_| FromProtobufTypeError::InvalidArgument("invalid network"))?;
+        let protocol = match rule.protocol {
+            Some(constraint) => Some(try_transport_protocol_from_i32(constraint.protocol)?),
+            None => None,
+        };
+        let port = if rule.port == 0 {
+            None
+        } else {
+            Some(
+                u16::try_from(rule.port)
+                    .map_err(|_| FromProtobufTypeError::InvalidArgument("invalid port"))?,
+            )
+        };
+        let direction = match firewall_allow_rule::Direction::from_i32(rule.direction) {
+            Some(firewall_allow_rule::Direction::Outbound) => AllowedDirection::Outbound,
+            Some(firewall_allow_rule::Direction::Inbound) => AllowedDirection::Inbound,
+            None => {
+                return Err(FromProtobufTypeError::InvalidArgument(
+                    "invalid traffic direction",
+                ))
+            }
+        };
+
+        Ok(talpid_types::net::FirewallAllowRule {
+            network,
+            protocol,
+            port,
+            direction,
+            outside_tunnel: rule.outside_tunnel,
+        })
+    }
+}
+
+impl TryFrom<TrustedNetwork> for talpid_types::net::trusted_network::TrustedNetwork {
+    type Error = FromProtobufTypeError;
+
+    fn try_from(network: TrustedNetwork) -> Result<Self, Self::Error> {
+        use talpid_types::net::trusted_network::TrustedNetwork as Network;
+
+        match network.network {
+            Some(trusted_network::Network::GatewayMac(address)) => Network::gateway_mac(&address)
+                .ok_or(FromProtobufTypeError::InvalidArgument(
+                    "invalid MAC address",
+                )),
+            Some(trusted_network::Network::Ssid(ssid)) if !ssid.is_empty() => {
+                Ok(Network::Ssid(ssid))
+            }
+            Some(trusted_network::Network::Interface(interface)) if !interface.is_empty() => {
+                Ok(Network::Interface(interface))
+            }
+            _ => Err(FromProtobufTypeError::InvalidArgument(
+                "missing trusted network",
+            )),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl TryFrom<Hook> for mullvad_types::hooks::Hook {
+    type Error = FromProtobufTypeError;
+
+    fn try_from(hook: Hook) -> Result<Self, Self::Error> {
+        use mullvad_types::hooks::{HookEvent, DEFAULT_HOOK_TIMEOUT_SECS};
+
+        if hook.path.is_empty() {
+            return Err(FromProtobufTypeError::InvalidArgument("missing hook path"));
+        }
+        let events = hook
+            .events
+            .into_iter()
+            .map(|event| match hook::Event::from_i32(event) {
+                Some(hook::Event::Connecting) => Ok(HookEvent::Connecting),
+                Some(hook::Event::Connected) => Ok(HookEvent::Connected),
+                Some(hook::Event::Disconnecting) => Ok(HookEvent::Disconnecting),
+                Some(hook::Event::Disconnected) => Ok(HookEvent::Disconnected),
+                Some(hook::Event::Error) => Ok(HookEvent::Error),
+                None => Err(FromProtobufTypeError::InvalidArgument("invalid hook event")),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(mullvad_types::hooks::Hook {
+            path: std::path::PathBuf::from(hook.path),
+            args: hook.args,
+            timeout_secs: if hook.timeout == 0 {
+                DEFAULT_HOOK_TIMEOUT_SECS
+            } else {
+                hook.timeout
+            },
+            events,
+        })
+    }
+}
+
+impl From<NetworkIdentity> for talpid_types::net::trusted_network::NetworkIdentity {
+    fn from(identity: NetworkIdentity) -> Self {
+        use talpid_types::net::trusted_network::NetworkConnection as Connection;
+        Self {
+            connections: identity
+                .connections
+                .into_iter()
+                .map(|connection| Connection {
+                    interface: connection.interface,
+                    gateway_mac: Some(connection.gateway_mac).filter(|mac| !mac.is_empty()),
+                    ssid: Some(connection.ssid).filter(|ssid| !ssid.is_empty()),
+                })
+                .collect(),
+        }
+    }
+}
+
 fn try_transport_protocol_from_i32(
     protocol: i32,
 ) -> Result<talpid_types::net::TransportProtocol, FromProtobufTypeError> {
@@ -1,5 +1,7 @@
 use clap::{crate_authors, crate_description, crate_name, App};
 use mullvad_api::{self, proxy::ApiConnectionMode};
+#[cfg(target_os = "linux")]
+use mullvad_daemon::settings::SettingsPersister;
 use mullvad_management_interface::new_rpc_client;
 use mullvad_types::version::ParsedAppVersion;
 use std::{path::PathBuf, process, time::Duration};
@@ -80,6 +82,16 @@ pub enum Error {
 
     #[error(display = "Cannot parse the version string")]
     ParseVersionStringError,
+
+    #[cfg(target_os = "linux")]
+    #[error(
+        display = "Lockdown mode is enabled. Run 'mullvad-setup disable-lockdown' to disable it and remove the firewall rules"
+    )]
+    LockdownModeEnabled,
+
+    #[cfg(target_os = "linux")]
+    #[error(display = "Failed to update the settings")]
+    WriteSettingsError(#[error(source)] mullvad_daemon::settings::Error),
 }
 
 #[tokio::main]
@@ -91,6 +103,9 @@ async fn main() {
             .about("Move a running daemon into a blocking state and save its target state"),
         App::new("reset-firewall").about("Remove any firewall rules introduced by the daemon"),
         App::new("remove-device").about("Remove the current device from the active account"),
+        #[cfg(target_os = "linux")]
+        App::new("disable-lockdown")
+            .about("Disable lockdown mode and remove the firewall rules introduced by the daemon"),
         App::new("is-older-version")
             .about("Checks whether the given version is older than the current version")
             .arg(
@@ -114,6 +129,8 @@ async fn main() {
         Some(("prepare-restart", _)) => prepare_restart().await,
         Some(("reset-firewall", _)) => reset_firewall().await,
         Some(("remove-device", _)) => remove_device().await,
+        #[cfg(target_os = "linux")]
+        Some(("disable-lockdown", _)) => disable_lockdown().await,
         Some(("is-older-version", sub_matches)) => {
             let old_version = sub_matches.value_of("OLDVERSION").unwrap();
             match is_older_version(old_version).await {
@@ -156,12 +173,54 @@ async fn reset_firewall() -> Result<(), Error> {
         return Err(Error::DaemonIsRunning);
     }
 
+    // The rules must stay in place in lockdown mode, until the daemon replaces them
+    #[cfg(target_os = "linux")]
+    if is_lockdown_mode_enabled().await? {
+        return Err(Error::LockdownModeEnabled);
+    }
+
     Firewall::new()
         .map_err(Error::FirewallError)?
         .reset_policy()
         .map_err(Error::FirewallError)
 }
 
+/// Disables lockdown mode, so that traffic is no longer blocked while the daemon is not running.
+/// This is a way to recover from the daemon being unable to start.
+#[cfg(target_os = "linux")]
+async fn disable_lockdown() -> Result<(), Error> {
+    if let Ok(_) = new_rpc_client().await {
+        return Err(Error::DaemonIsRunning);
+    }
+
+    let (_, settings_path) = get_paths()?;
+    let mut settings = SettingsPersister::load(&settings_path).await;
+    settings
+        .set_lockdown_mode(false)
+        .await
+        .map_err(Error::WriteSettingsError)?;
+    println!("Disabled lockdown mode");
+
+    reset_firewall().await
+}
+
+/// Returns whether lockdown mode is enabled. If the settings cannot be read, the daemon would not
+/// use them either, so it is assumed to be disabled.
+#[cfg(target_os = "linux")]
+async fn is_lockdown_mode_enabled() -> Result<bool, Error> {
+    let (_, settings_path) = get_paths()?;
+    match SettingsPersister::read(&settings_path).await {
+        Ok(settings) => Ok(settings.lockdown_mode),
+        Err(error) => {
+            eprintln!(
+                "{}",
+                error.display_chain_with_msg("Failed to read settings. Ignoring lockdown mode")
+            );
+            Ok(false)
+        }
+    }
+}
+
 async fn remove_device() -> Result<(), Error> {
     let (cache_path, settings_path) = get_paths()?;
     let (cacher, state) = mullvad_daemon::device::DeviceCacher::new(&settings_path)
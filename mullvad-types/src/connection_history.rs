@@ -0,0 +1,48 @@
+use crate::location::GeoIpLocation;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use talpid_types::{
+    net::{TrafficStats, TunnelType},
+    tunnel::ErrorStateCause,
+};
+
+/// A connection to a relay, from entering the connected state until leaving it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ConnectionEntry {
+    pub started: DateTime<Utc>,
+    /// When the connection ended. `None` while connected, or if the daemon stopped before the
+    /// connection ended.
+    pub ended: Option<DateTime<Utc>>,
+    pub tunnel_type: TunnelType,
+    pub exit_hostname: Option<String>,
+    pub entry_hostname: Option<String>,
+    pub bridge_hostname: Option<String>,
+    pub obfuscator_hostname: Option<String>,
+    /// Bytes transferred through the tunnel, if they could be read before it was closed.
+    pub traffic: Option<TrafficStats>,
+    /// Why the connection was lost, if it ended in the error state.
+    pub error_cause: Option<ErrorStateCause>,
+}
+
+impl ConnectionEntry {
+    pub fn new(
+        started: DateTime<Utc>,
+        tunnel_type: TunnelType,
+        location: Option<&GeoIpLocation>,
+    ) -> Self {
+        let hostname = |select: fn(&GeoIpLocation) -> &Option<String>| {
+            location.and_then(|location| select(location).clone())
+        };
+        ConnectionEntry {
+            started,
+            ended: None,
+            tunnel_type,
+            exit_hostname: hostname(|location| &location.hostname),
+            entry_hostname: hostname(|location| &location.entry_hostname),
+            bridge_hostname: hostname(|location| &location.bridge_hostname),
+            obfuscator_hostname: hostname(|location| &location.obfuscator_hostname),
+            traffic: None,
+            error_cause: None,
+        }
+    }
+}
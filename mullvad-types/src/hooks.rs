@@ -0,0 +1,80 @@
+use serde::{Deserialize, Serialize};
+use std::{fmt, path::PathBuf, time::Duration};
+
+/// Number of seconds that a hook may run before it is killed, unless otherwise specified.
+pub const DEFAULT_HOOK_TIMEOUT_SECS: u64 = 10;
+
+/// The longest timeout that a hook can have, in seconds. Hooks run one at a time, so a hook that
+/// hangs delays all hooks after it.
+pub const MAX_HOOK_TIMEOUT_SECS: u64 = 5 * 60;
+
+/// An executable that is run by the daemon when the tunnel state changes.
+#[derive(Debug, Clone, Eq, PartialEq, Deserialize, Serialize)]
+pub struct Hook {
+    /// Absolute path to the executable. It must be owned by root and only be writable by root.
+    pub path: PathBuf,
+    /// Arguments to pass to the executable.
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// Number of seconds after which the hook is killed.
+    #[serde(default = "default_timeout_secs")]
+    pub timeout_secs: u64,
+    /// Tunnel states that the hook is run on. It is run on every state if this is empty.
+    #[serde(default)]
+    pub events: Vec<HookEvent>,
+}
+
+fn default_timeout_secs() -> u64 {
+    DEFAULT_HOOK_TIMEOUT_SECS
+}
+
+impl Hook {
+    /// Returns the timeout of the hook, which is at most [`MAX_HOOK_TIMEOUT_SECS`].
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_secs.min(MAX_HOOK_TIMEOUT_SECS))
+    }
+
+    /// Returns whether the hook should run when the tunnel enters the state given by `event`.
+    pub fn runs_on(&self, event: HookEvent) -> bool {
+        self.events.is_empty() || self.events.contains(&event)
+    }
+}
+
+impl fmt::Display for Hook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.path.display())?;
+        for arg in &self.args {
+            write!(f, " {}", arg)?;
+        }
+        Ok(())
+    }
+}
+
+/// Tunnel state that a hook can be run on.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HookEvent {
+    Connecting,
+    Connected,
+    Disconnecting,
+    Disconnected,
+    Error,
+}
+
+impl HookEvent {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HookEvent::Connecting => "connecting",
+            HookEvent::Connected => "connected",
+            HookEvent::Disconnecting => "disconnecting",
+            HookEvent::Disconnected => "disconnected",
+            HookEvent::Error => "error",
+        }
+    }
+}
+
+impl fmt::Display for HookEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
@@ -2,8 +2,11 @@
 
 pub mod account;
 pub mod auth_failed;
+pub mod connection_history;
 pub mod device;
 pub mod endpoint;
+#[cfg(target_os = "linux")]
+pub mod hooks;
 pub mod location;
 pub mod relay_constraints;
 pub mod relay_list;
@@ -2,6 +2,8 @@
 use jnix::{jni::objects::JObject, FromJava, IntoJava, JnixEnv};
 use serde::{Deserialize, Serialize};
 use std::net::IpAddr;
+#[cfg(target_os = "linux")]
+use talpid_types::net::dns::{DnsBlocklist, EncryptedDnsServer, SplitDnsRule};
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Hash)]
 #[serde(rename_all = "snake_case")]
@@ -28,6 +30,12 @@ pub struct DnsOptions {
     pub default_options: DefaultDnsOptions,
     #[cfg_attr(target_os = "android", jnix(map = "|opts| opts.addresses"))]
     pub custom_options: CustomDnsOptions,
+    /// Blocklists applied by the local filtering resolver.
+    #[cfg(target_os = "linux")]
+    pub blocklists: Vec<DnsBlocklist>,
+    /// Domains that are resolved using other resolvers than the tunnel DNS servers.
+    #[cfg(target_os = "linux")]
+    pub split_dns: Vec<SplitDnsRule>,
 }
 
 #[cfg(target_os = "android")]
@@ -86,4 +94,9 @@ pub struct DefaultDnsOptions {
 #[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize, Hash)]
 pub struct CustomDnsOptions {
     pub addresses: Vec<IpAddr>,
+    /// DNS-over-HTTPS and DNS-over-TLS servers, in order of preference. These are used instead of
+    /// `addresses` when set.
+    #[cfg(target_os = "linux")]
+    #[serde(default)]
+    pub encrypted_servers: Vec<EncryptedDnsServer>,
 }
@@ -9,9 +9,16 @@ use crate::{
 #[cfg(target_os = "android")]
 use jnix::IntoJava;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
-#[cfg(target_os = "windows")]
+#[cfg(any(windows, target_os = "linux"))]
 use std::{collections::HashSet, path::PathBuf};
+#[cfg(unix)]
+use talpid_types::net::lan::LanNetworks;
 use talpid_types::net::{self, openvpn, GenericTunnelOptions};
+#[cfg(target_os = "linux")]
+use talpid_types::{
+    cgroup::{ExclusionProfile, SplitTunnelMode},
+    net::ExcludedDestinations,
+};
 
 mod dns;
 
@@ -74,10 +81,18 @@ pub struct Settings {
     bridge_state: BridgeState,
     /// If the daemon should allow communication with private (LAN) networks.
     pub allow_lan: bool,
+    /// Networks that are considered local when `allow_lan` is set.
+    #[cfg(unix)]
+    #[cfg_attr(target_os = "android", jnix(skip))]
+    pub lan_networks: LanNetworks,
     /// Extra level of kill switch. When this setting is on, the disconnected state will block
     /// the firewall to not allow any traffic in or out.
     #[cfg_attr(target_os = "android", jnix(skip))]
     pub block_when_disconnected: bool,
+    /// Block traffic unless connected, also while the daemon is not running. The blocking
+    /// firewall rules are kept when the daemon stops, and are installed during early boot.
+    #[cfg(target_os = "linux")]
+    pub lockdown_mode: bool,
     /// If the daemon should connect the VPN tunnel directly on start or not.
     pub auto_connect: bool,
     /// Options that should be applied to tunnels of a specific type regardless of where the relays
@@ -85,21 +100,48 @@ pub struct Settings {
     pub tunnel_options: TunnelOptions,
     /// Whether to notify users of beta updates.
     pub show_beta_releases: bool,
+    /// Whether to keep a history of the connections made.
+    #[cfg_attr(target_os = "android", jnix(skip))]
+    pub connection_history: bool,
     /// Split tunneling settings
-    #[cfg(windows)]
+    #[cfg(any(windows, target_os = "linux"))]
     pub split_tunnel: SplitTunnelSettings,
+    /// Traffic that the firewall should allow regardless of the tunnel state.
+    #[cfg(target_os = "linux")]
+    pub firewall_allow_rules: Vec<net::FirewallAllowRule>,
+    /// Networks on which the tunnel is disconnected automatically. The tunnel is connected
+    /// automatically on any other network.
+    #[cfg(target_os = "linux")]
+    pub trusted_networks: Vec<net::trusted_network::TrustedNetwork>,
+    /// Executables that are run when the tunnel state changes.
+    #[cfg(target_os = "linux")]
+    pub hooks: Vec<crate::hooks::Hook>,
     /// Specifies settings schema version
     #[cfg_attr(target_os = "android", jnix(skip))]
     settings_version: SettingsVersion,
 }
 
-#[cfg(windows)]
+#[cfg(any(windows, target_os = "linux"))]
 #[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq)]
 pub struct SplitTunnelSettings {
     /// Toggles split tunneling on or off
     pub enable_exclusions: bool,
     /// List of applications to exclude from the tunnel.
     pub apps: HashSet<PathBuf>,
+    /// Whether the split tunneled processes are excluded from the tunnel, or the only ones
+    /// included in it.
+    #[cfg(target_os = "linux")]
+    #[serde(default)]
+    pub mode: SplitTunnelMode,
+    /// Named groups of processes that are excluded from the tunnel, each with its own cgroup.
+    #[cfg(target_os = "linux")]
+    #[serde(default)]
+    pub exclusion_profiles: Vec<ExclusionProfile>,
+    /// Destinations to exclude from the tunnel, regardless of which process the traffic
+    /// belongs to.
+    #[cfg(target_os = "linux")]
+    #[serde(default)]
+    pub excluded_destinations: ExcludedDestinations,
 }
 
 impl Default for Settings {
@@ -116,12 +158,23 @@ impl Default for Settings {
             },
             bridge_state: BridgeState::Auto,
             allow_lan: false,
+            #[cfg(unix)]
+            lan_networks: LanNetworks::default(),
             block_when_disconnected: false,
+            #[cfg(target_os = "linux")]
+            lockdown_mode: false,
             auto_connect: false,
             tunnel_options: TunnelOptions::default(),
             show_beta_releases: false,
-            #[cfg(windows)]
+            connection_history: false,
+            #[cfg(any(windows, target_os = "linux"))]
             split_tunnel: SplitTunnelSettings::default(),
+            #[cfg(target_os = "linux")]
+            firewall_allow_rules: vec![],
+            #[cfg(target_os = "linux")]
+            trusted_networks: vec![],
+            #[cfg(target_os = "linux")]
+            hooks: vec![],
             settings_version: CURRENT_SETTINGS_VERSION,
         }
     }
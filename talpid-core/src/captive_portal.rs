@@ -0,0 +1,482 @@
+//! Detects captive portals, and temporarily lets traffic through to them while the firewall
+//! blocks everything else, so that the user can log in to the network.
+//!
+//! Unlocking allows DNS, HTTP and HTTPS to the default gateways. The probe URL is then resolved
+//! and requested. Unless the response is the expected one, there is a portal, and if the response
+//! redirects somewhere else, the portal that it points to is allowed as well. Everything is blocked
+//! again when the unlock expires.
+
+use crate::{
+    offline::network_identity::default_connections, routing::RouteManagerHandle,
+    tunnel_state_machine::TunnelCommand,
+};
+use futures::{
+    channel::{mpsc, oneshot},
+    future::{abortable, AbortHandle},
+};
+use std::{
+    fmt,
+    io::{self, Read, Write},
+    net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs},
+    sync::Weak,
+    time::Duration,
+};
+use talpid_types::ErrorExt;
+
+/// URL that is requested to find out whether there is a captive portal. A portal intercepts the
+/// request and redirects it to, or responds with, a login page.
+pub const DEFAULT_PROBE_URL: &str = "http://detectportal.firefox.com/success.txt";
+
+/// Body that probe URLs respond with when there is no captive portal. Responses without a body
+/// (`204 No Content`) are accepted as well.
+pub const EXPECTED_PROBE_BODY: &str = "success";
+
+/// How long captive portals are unlocked for unless another duration is requested.
+pub const DEFAULT_UNLOCK_DURATION: Duration = Duration::from_secs(5 * 60);
+
+/// The longest time that captive portals can be unlocked for. Longer durations are shortened to
+/// this.
+pub const MAX_UNLOCK_DURATION: Duration = Duration::from_secs(15 * 60);
+
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_RESPONSE_SIZE: usize = 16 * 1024;
+const HTTP_PORT: u16 = 80;
+
+/// Errors that can occur while unlocking a captive portal.
+#[derive(err_derive::Error, Debug)]
+#[error(no_from)]
+pub enum Error {
+    /// The probe URL is not a valid `http://` URL.
+    #[error(display = "Invalid captive portal probe URL: {}", _0)]
+    InvalidUrl(String),
+
+    /// There is no default route with a gateway.
+    #[error(display = "No default gateway was found")]
+    NoGateway,
+
+    /// The tunnel state machine is not running.
+    #[error(display = "The tunnel state machine is not running")]
+    StateMachineDown,
+}
+
+/// Result of requesting the probe URL.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Detection {
+    /// The probe URL responded as expected.
+    NoPortal,
+    /// The request was intercepted, since the response was not the expected one. `location` is
+    /// where the portal redirected to, if anywhere.
+    Portal {
+        /// Value of the `Location` header in the response.
+        location: Option<String>,
+    },
+    /// The probe URL could not be reached, so it is not known whether there is a portal.
+    Unknown,
+}
+
+impl fmt::Display for Detection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Detection::NoPortal => f.write_str("no captive portal detected"),
+            Detection::Portal {
+                location: Some(location),
+            } => write!(f, "captive portal at {}", location),
+            Detection::Portal { location: None } => f.write_str("captive portal detected"),
+            Detection::Unknown => f.write_str("captive portal detection failed"),
+        }
+    }
+}
+
+/// Describes an unlock that is in effect.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnlockStatus {
+    /// Hosts that can be reached using DNS, HTTP and HTTPS.
+    pub hosts: Vec<IpAddr>,
+    /// Whether a captive portal was found.
+    pub detection: Detection,
+    /// How long the hosts remain reachable.
+    pub duration: Duration,
+}
+
+/// An `http://` URL, split into the parts needed to request it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct HttpUrl {
+    host: String,
+    port: u16,
+    path: String,
+}
+
+impl HttpUrl {
+    fn parse(url: &str) -> Option<Self> {
+        let rest = url.strip_prefix("http://")?;
+        let (authority, path) = match rest.find(['/', '?']) {
+            Some(index) if rest[index..].starts_with('/') => rest.split_at(index),
+            Some(index) => (&rest[..index], ""),
+            None => (rest, ""),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => (host, port.parse().ok()?),
+            _ => (authority, HTTP_PORT),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() || host.contains('@') {
+            return None;
+        }
+        Some(HttpUrl {
+            host: host.to_owned(),
+            port,
+            path: if path.is_empty() { "/" } else { path }.to_owned(),
+        })
+    }
+}
+
+/// Returns the host part of any URL, if it has one.
+fn url_host(url: &str) -> Option<String> {
+    let (_, rest) = url.split_once("://")?;
+    let authority = rest.split(['/', '?', '#']).next()?;
+    let authority = authority.rsplit('@').next()?;
+    let host = match authority.strip_prefix('[') {
+        Some(address) => address.split(']').next()?,
+        None => authority.split(':').next()?,
+    };
+    if host.is_empty() {
+        None
+    } else {
+        Some(host.to_owned())
+    }
+}
+
+/// Interprets an HTTP response to the probe request. There is no portal only if the response is
+/// empty or has the expected body, since portals often serve their login page with status 200.
+fn parse_response(response: &str) -> Option<Detection> {
+    let (head, body) = response.split_once("\r\n\r\n").unwrap_or((response, ""));
+    let mut lines = head.split("\r\n");
+    let status: u16 = lines.next()?.split_whitespace().nth(1)?.parse().ok()?;
+    if status == 204 || (status == 200 && body.trim() == EXPECTED_PROBE_BODY) {
+        return Some(Detection::NoPortal);
+    }
+    let location = lines
+        .take_while(|line| !line.is_empty())
+        .find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            if name.trim().eq_ignore_ascii_case("location") {
+                Some(value.trim().to_owned())
+            } else {
+                None
+            }
+        })
+        .filter(|_| (300..400).contains(&status));
+    Some(Detection::Portal { location })
+}
+
+/// Resolves `host` using the system resolver. This blocks.
+fn resolve(host: &str, port: u16) -> Vec<SocketAddr> {
+    match (host, port).to_socket_addrs() {
+        Ok(addrs) => addrs.collect(),
+        Err(error) => {
+            log::warn!("Failed to resolve {}: {}", host, error);
+            vec![]
+        }
+    }
+}
+
+/// Requests the probe URL from `addr`. This blocks.
+fn probe(url: &HttpUrl, addr: SocketAddr) -> io::Result<Detection> {
+    let mut stream = TcpStream::connect_timeout(&addr, PROBE_TIMEOUT)?;
+    stream.set_read_timeout(Some(PROBE_TIMEOUT))?;
+    stream.set_write_timeout(Some(PROBE_TIMEOUT))?;
+    // HTTP/1.0 keeps the server from sending a chunked body
+    write!(
+        stream,
+        "GET {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: talpid\r\nAccept: */*\r\nConnection: close\r\n\r\n",
+        url.path, url.host
+    )?;
+
+    let mut response = Vec::new();
+    let mut buffer = [0u8; 1024];
+    while response.len() < MAX_RESPONSE_SIZE {
+        let read = match stream.read(&mut buffer) {
+            Ok(read) => read,
+            // Make do with what was received if the server does not close the connection
+            Err(error)
+                if !response.is_empty()
+                    && matches!(
+                        error.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+            {
+                break
+            }
+            Err(error) => return Err(error),
+        };
+        if read == 0 {
+            break;
+        }
+        response.extend_from_slice(&buffer[..read]);
+    }
+
+    parse_response(&String::from_utf8_lossy(&response))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Invalid HTTP response"))
+}
+
+/// Temporarily allows traffic to captive portals, by asking the tunnel state machine to apply
+/// [`FirewallPolicy::CaptivePortal`](crate::firewall::FirewallPolicy::CaptivePortal) in blocking
+/// states.
+pub struct Unlocker {
+    runtime: tokio::runtime::Handle,
+    route_manager: RouteManagerHandle,
+    command_tx: Weak<mpsc::UnboundedSender<TunnelCommand>>,
+    unlock_task: Option<AbortHandle>,
+}
+
+impl Unlocker {
+    /// Creates an unlocker which sends commands to the tunnel state machine using `command_tx`.
+    pub fn new(
+        runtime: tokio::runtime::Handle,
+        route_manager: RouteManagerHandle,
+        command_tx: Weak<mpsc::UnboundedSender<TunnelCommand>>,
+    ) -> Self {
+        Self {
+            runtime,
+            route_manager,
+            command_tx,
+            unlock_task: None,
+        }
+    }
+
+    /// Allows traffic to the captive portal for `duration`, but at most [`MAX_UNLOCK_DURATION`],
+    /// and sends the result to `result_tx` once the portal has been probed. `probe_url` replaces
+    /// [`DEFAULT_PROBE_URL`] if given. Any previous unlock is replaced.
+    pub fn unlock(
+        &mut self,
+        duration: Duration,
+        probe_url: Option<String>,
+        result_tx: oneshot::Sender<Result<UnlockStatus, Error>>,
+    ) {
+        if let Some(unlock_task) = self.unlock_task.take() {
+            unlock_task.abort();
+        }
+        let duration = duration.min(MAX_UNLOCK_DURATION);
+
+        let (task, abort_handle) = abortable(unlock(
+            self.route_manager.clone(),
+            self.command_tx.clone(),
+            duration,
+            probe_url.unwrap_or_else(|| DEFAULT_PROBE_URL.to_owned()),
+            result_tx,
+        ));
+        self.unlock_task = Some(abort_handle);
+        self.runtime.spawn(task);
+    }
+}
+
+impl Drop for Unlocker {
+    fn drop(&mut self) {
+        if let Some(unlock_task) = self.unlock_task.take() {
+            unlock_task.abort();
+        }
+    }
+}
+
+async fn set_hosts(
+    command_tx: &Weak<mpsc::UnboundedSender<TunnelCommand>>,
+    hosts: Vec<IpAddr>,
+) -> Result<(), Error> {
+    let (done_tx, done_rx) = oneshot::channel();
+    command_tx
+        .upgrade()
+        .ok_or(Error::StateMachineDown)?
+        .unbounded_send(TunnelCommand::SetCaptivePortalHosts(hosts, done_tx))
+        .map_err(|_| Error::StateMachineDown)?;
+    done_rx.await.map_err(|_| Error::StateMachineDown)
+}
+
+async fn add_resolved_hosts(
+    command_tx: &Weak<mpsc::UnboundedSender<TunnelCommand>>,
+    hosts: &mut Vec<IpAddr>,
+    host: String,
+    port: u16,
+) -> Result<Vec<SocketAddr>, Error> {
+    let addrs = tokio::task::spawn_blocking(move || resolve(&host, port))
+        .await
+        .unwrap_or_default();
+    let mut changed = false;
+    for addr in &addrs {
+        if !hosts.contains(&addr.ip()) {
+            hosts.push(addr.ip());
+            changed = true;
+        }
+    }
+    if changed {
+        set_hosts(command_tx, hosts.clone()).await?;
+    }
+    Ok(addrs)
+}
+
+async fn unlock(
+    route_manager: RouteManagerHandle,
+    command_tx: Weak<mpsc::UnboundedSender<TunnelCommand>>,
+    duration: Duration,
+    url: String,
+    result_tx: oneshot::Sender<Result<UnlockStatus, Error>>,
+) {
+    let result = allow_portal(&route_manager, &command_tx, duration, url).await;
+    let unlocked = result.is_ok();
+    let _ = result_tx.send(result);
+    if !unlocked {
+        return;
+    }
+
+    // Time spent suspended counts, so that the portal is not left unlocked after resuming
+    talpid_time::sleep(duration).await;
+    log::info!("Captive portal unlock expired");
+    if let Err(error) = set_hosts(&command_tx, vec![]).await {
+        log::error!(
+            "{}",
+            error.display_chain_with_msg("Failed to block captive portal")
+        );
+    }
+}
+
+async fn allow_portal(
+    route_manager: &RouteManagerHandle,
+    command_tx: &Weak<mpsc::UnboundedSender<TunnelCommand>>,
+    duration: Duration,
+    url: String,
+) -> Result<UnlockStatus, Error> {
+    let parsed_url = HttpUrl::parse(&url).ok_or_else(|| Error::InvalidUrl(url.clone()))?;
+
+    let mut hosts: Vec<IpAddr> = default_connections(route_manager)
+        .await
+        .into_iter()
+        .filter_map(|(_, gateway)| gateway)
+        .collect();
+    hosts.sort();
+    hosts.dedup();
+    if hosts.is_empty() {
+        return Err(Error::NoGateway);
+    }
+    set_hosts(command_tx, hosts.clone()).await?;
+
+    let probe_addrs = add_resolved_hosts(
+        command_tx,
+        &mut hosts,
+        parsed_url.host.clone(),
+        parsed_url.port,
+    )
+    .await?;
+
+    let detection = match probe_addrs.first() {
+        Some(addr) => {
+            let addr = *addr;
+            let probe_url = parsed_url.clone();
+            let result = tokio::task::spawn_blocking(move || probe(&probe_url, addr)).await;
+            match result {
+                Ok(Ok(detection)) => detection,
+                Ok(Err(error)) => {
+                    log::warn!("Captive portal probe to {} failed: {}", url, error);
+                    Detection::Unknown
+                }
+                Err(_) => Detection::Unknown,
+            }
+        }
+        None => Detection::Unknown,
+    };
+
+    if let Detection::Portal {
+        location: Some(location),
+    } = &detection
+    {
+        if let Some(host) = url_host(location) {
+            if host != parsed_url.host {
+                add_resolved_hosts(command_tx, &mut hosts, host, HTTP_PORT).await?;
+            }
+        }
+    }
+
+    log::info!(
+        "Unlocked captive portal for {} seconds: {}",
+        duration.as_secs(),
+        detection
+    );
+
+    Ok(UnlockStatus {
+        hosts,
+        detection,
+        duration,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_url() {
+        assert_eq!(
+            HttpUrl::parse(DEFAULT_PROBE_URL),
+            Some(HttpUrl {
+                host: "detectportal.firefox.com".to_owned(),
+                port: 80,
+                path: "/success.txt".to_owned(),
+            })
+        );
+        assert_eq!(
+            HttpUrl::parse("http://192.0.2.1:8080"),
+            Some(HttpUrl {
+                host: "192.0.2.1".to_owned(),
+                port: 8080,
+                path: "/".to_owned(),
+            })
+        );
+        assert_eq!(
+            HttpUrl::parse("http://[2001:db8::1]/generate_204"),
+            Some(HttpUrl {
+                host: "2001:db8::1".to_owned(),
+                port: 80,
+                path: "/generate_204".to_owned(),
+            })
+        );
+        assert_eq!(HttpUrl::parse("https://example.com/"), None);
+        assert_eq!(HttpUrl::parse("http://:80/"), None);
+
+        assert_eq!(
+            url_host("https://user@portal.example.net:8443/login?next=/").as_deref(),
+            Some("portal.example.net")
+        );
+        assert_eq!(url_host("/login"), None);
+    }
+
+    #[test]
+    fn test_parse_response() {
+        assert_eq!(
+            parse_response("HTTP/1.1 200 OK\r\nContent-Length: 8\r\n\r\nsuccess\n"),
+            Some(Detection::NoPortal)
+        );
+        assert_eq!(
+            parse_response("HTTP/1.1 204 No Content\r\n\r\n"),
+            Some(Detection::NoPortal)
+        );
+        assert_eq!(
+            parse_response("HTTP/1.1 200 OK\r\n\r\n<html><body>Log in</body></html>"),
+            Some(Detection::Portal { location: None })
+        );
+        assert_eq!(
+            parse_response("HTTP/1.1 200 OK\r\nContent-Length: 90\r\n"),
+            Some(Detection::Portal { location: None })
+        );
+        assert_eq!(
+            parse_response(
+                "HTTP/1.1 302 Found\r\nlocation: http://10.0.0.1/login\r\n\r\nLocation: nope\r\n"
+            ),
+            Some(Detection::Portal {
+                location: Some("http://10.0.0.1/login".to_owned())
+            })
+        );
+        assert_eq!(
+            parse_response("HTTP/1.1 511 Network Authentication Required\r\n\r\n"),
+            Some(Detection::Portal { location: None })
+        );
+        assert_eq!(parse_response("garbage"), None);
+    }
+}
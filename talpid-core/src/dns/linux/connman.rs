@@ -0,0 +1,111 @@
+use super::RESOLV_CONF_PATH;
+use std::{fs, io, net::IpAddr};
+use talpid_dbus::connman::{self, Connman as DBus, ServiceNameservers};
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(err_derive::Error, Debug)]
+pub enum Error {
+    #[error(display = "Error in ConnMan DBus connection")]
+    Dbus(#[error(source)] connman::Error),
+
+    #[error(display = "Current /etc/resolv.conf is not generated by ConnMan")]
+    ConnmanNotInUseError,
+
+    #[error(display = "ConnMan has no connected services to set DNS for")]
+    NoConnectedServices,
+}
+
+pub struct Connman {
+    connection: DBus,
+    services_backup: Vec<ServiceNameservers>,
+}
+
+impl Connman {
+    pub fn new() -> Result<Self> {
+        let connection = DBus::new()?;
+        connection.ensure_connman_exists()?;
+        if !(Self::check_if_resolv_conf_is_symlinked_correctly()
+            || Self::check_if_resolv_conf_was_generated())
+        {
+            return Err(Error::ConnmanNotInUseError);
+        }
+
+        Ok(Connman {
+            connection,
+            services_backup: vec![],
+        })
+    }
+
+    pub fn set_dns(&mut self, servers: &[IpAddr]) -> Result<()> {
+        let services = self.connection.connected_services()?;
+        if services.is_empty() && self.services_backup.is_empty() {
+            return Err(Error::NoConnectedServices);
+        }
+
+        // Services that are already using our nameservers keep their original backup
+        for service in services {
+            if !self
+                .services_backup
+                .iter()
+                .any(|backup| backup.service == service.service)
+            {
+                self.services_backup.push(service);
+            }
+        }
+
+        let nameservers: Vec<String> = servers.iter().map(|server| server.to_string()).collect();
+        for backup in &self.services_backup {
+            self.connection
+                .set_nameservers(&backup.service, &nameservers)?;
+        }
+        Ok(())
+    }
+
+    pub fn reset(&mut self) -> Result<()> {
+        if self.services_backup.is_empty() {
+            log::trace!("No DNS settings to reset");
+            return Ok(());
+        }
+
+        let mut result = Ok(());
+        for backup in self.services_backup.drain(..) {
+            if let Err(error) = self
+                .connection
+                .set_nameservers(&backup.service, &backup.nameservers)
+            {
+                log::error!(
+                    "Failed to restore nameservers of ConnMan service {}: {}",
+                    backup.service,
+                    error
+                );
+                result = Err(Error::Dbus(error));
+            }
+        }
+        result
+    }
+
+    // Returns true if /etc/resolv.conf contents indicate that they've been generated by ConnMan
+    fn check_if_resolv_conf_was_generated() -> bool {
+        match fs::read_to_string(RESOLV_CONF_PATH) {
+            Ok(contents) => contents.contains("Generated by Connection Manager"),
+            Err(err) => {
+                log::error!("Couldn't read /etc/resolv.conf: {}", err);
+                false
+            }
+        }
+    }
+
+    // Returns true if /etc/resolv.conf is symlinked to ConnMan's runtime directory
+    fn check_if_resolv_conf_is_symlinked_correctly() -> bool {
+        match fs::canonicalize(RESOLV_CONF_PATH) {
+            Err(err) => {
+                if err.kind() != io::ErrorKind::NotFound {
+                    log::error!("Failed to canonicalize /etc/resolv.conf: {}", err);
+                }
+                false
+            }
+            Ok(path) => path.starts_with("/run/connman") || path.starts_with("/var/run/connman"),
+        }
+    }
+}
@@ -0,0 +1,435 @@
+use super::RESOLV_CONF_PATH;
+use nix::{
+    sys::signal::{self, Signal},
+    unistd::Pid,
+};
+use resolv_conf::{Config, ScopedIp};
+use std::{
+    collections::HashSet,
+    ffi::OsString,
+    fs, io,
+    net::IpAddr,
+    path::{Path, PathBuf},
+};
+
+const DNSMASQ_PID_PATHS: &[&str] = &[
+    "/run/dnsmasq/dnsmasq.pid",
+    "/var/run/dnsmasq/dnsmasq.pid",
+    "/run/dnsmasq.pid",
+    "/var/run/dnsmasq.pid",
+];
+const DNSMASQ_CONFIG_PATH: &str = "/etc/dnsmasq.conf";
+/// Instances of dnsmasq started by these programs only serve their own networks.
+const IGNORED_DNSMASQ_OWNERS: &[&str] = &["NetworkManager", "libvirt", "lxc", "lxd"];
+/// Limits how many configuration files are read, in case they include each other.
+const MAX_CONFIG_FILES: usize = 64;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(err_derive::Error, Debug)]
+pub enum Error {
+    #[error(display = "dnsmasq is not running")]
+    NotRunning,
+
+    #[error(display = "Current /etc/resolv.conf does not point at a local dnsmasq")]
+    DnsmasqNotInUseError,
+
+    #[error(display = "dnsmasq is configured to ignore resolv files")]
+    NoResolvError,
+
+    #[error(display = "dnsmasq reads its upstream servers from /etc/resolv.conf")]
+    ResolvFileIsResolvConfError,
+
+    #[error(display = "Failed to write to {}", _0)]
+    WriteResolvFile(String, #[error(source)] io::Error),
+
+    #[error(display = "Failed to read from {}", _0)]
+    ReadResolvFile(String, #[error(source)] io::Error),
+
+    #[error(display = "Resolv file at {} could not be parsed", _0)]
+    ParseError(String, #[error(source)] resolv_conf::ParseError),
+
+    #[error(display = "Failed to remove stale resolv file backup at {}", _0)]
+    RemoveBackup(String, #[error(source)] io::Error),
+
+    #[error(display = "Failed to signal dnsmasq to reload its upstream servers")]
+    ReloadDnsmasq(#[error(source)] nix::Error),
+}
+
+/// Sets DNS by rewriting the resolv file that a local dnsmasq reads its upstream servers from.
+pub struct Dnsmasq {
+    pid: Pid,
+    resolv_file: PathBuf,
+    backup_file: PathBuf,
+    backup: Option<Config>,
+}
+
+impl Dnsmasq {
+    pub fn new() -> Result<Self> {
+        let pid = find_dnsmasq().ok_or(Error::NotRunning)?;
+        if !resolv_conf_uses_local_resolver() {
+            return Err(Error::DnsmasqNotInUseError);
+        }
+
+        let options = DnsmasqOptions::load(pid);
+        if options.no_resolv {
+            return Err(Error::NoResolvError);
+        }
+        let resolv_file = options
+            .resolv_file
+            .unwrap_or_else(|| PathBuf::from(RESOLV_CONF_PATH));
+        if resolv_file == Path::new(RESOLV_CONF_PATH) {
+            return Err(Error::ResolvFileIsResolvConfError);
+        }
+
+        let mut backup_file = OsString::from(resolv_file.as_os_str());
+        backup_file.push(".mullvadbackup");
+
+        let dnsmasq = Dnsmasq {
+            pid,
+            resolv_file,
+            backup_file: PathBuf::from(backup_file),
+            backup: None,
+        };
+        dnsmasq.restore_from_backup()?;
+        Ok(dnsmasq)
+    }
+
+    pub fn set_dns(&mut self, servers: &[IpAddr]) -> Result<()> {
+        let backup = match self.backup.take() {
+            Some(backup) => backup,
+            None => {
+                let backup = self.read_config(&self.resolv_file)?;
+                self.write_config(&self.backup_file, &backup)?;
+                backup
+            }
+        };
+
+        let mut config = backup.clone();
+        config.nameservers = servers
+            .iter()
+            .map(|&address| ScopedIp::from(address))
+            .collect();
+        self.backup = Some(backup);
+
+        self.write_config(&self.resolv_file, &config)?;
+        self.reload()
+    }
+
+    pub fn reset(&mut self) -> Result<()> {
+        if let Some(backup) = self.backup.take() {
+            self.write_config(&self.resolv_file, &backup)?;
+            let _ = fs::remove_file(&self.backup_file);
+            self.reload()?;
+        }
+        Ok(())
+    }
+
+    /// Makes dnsmasq clear its cache, so that no answers from the previous upstream servers are
+    /// used. Changes to the resolv file itself are picked up by dnsmasq automatically, or on
+    /// `SIGHUP` if it runs with `no-poll`.
+    fn reload(&self) -> Result<()> {
+        signal::kill(self.pid, Signal::SIGHUP).map_err(Error::ReloadDnsmasq)
+    }
+
+    fn restore_from_backup(&self) -> Result<()> {
+        match fs::read_to_string(&self.backup_file) {
+            Ok(backup) => {
+                log::info!("Restoring dnsmasq resolv file from backup");
+                let config = Config::parse(&backup)
+                    .map_err(|e| Error::ParseError(self.backup_file.display().to_string(), e))?;
+
+                self.write_config(&self.resolv_file, &config)?;
+
+                fs::remove_file(&self.backup_file)
+                    .map_err(|e| Error::RemoveBackup(self.backup_file.display().to_string(), e))?;
+                self.reload()
+            }
+            Err(ref error) if error.kind() == io::ErrorKind::NotFound => {
+                log::debug!("No dnsmasq resolv file backup to restore");
+                Ok(())
+            }
+            Err(error) => Err(Error::ReadResolvFile(
+                self.backup_file.display().to_string(),
+                error,
+            )),
+        }
+    }
+
+    fn read_config(&self, path: &Path) -> Result<Config> {
+        if !path.exists() {
+            return Ok(Config::new());
+        }
+        let contents = fs::read_to_string(path)
+            .map_err(|e| Error::ReadResolvFile(path.display().to_string(), e))?;
+        Config::parse(&contents).map_err(|e| Error::ParseError(path.display().to_string(), e))
+    }
+
+    fn write_config(&self, path: &Path, config: &Config) -> Result<()> {
+        fs::write(path, config.to_string().as_bytes())
+            .map_err(|e| Error::WriteResolvFile(path.display().to_string(), e))
+    }
+}
+
+/// Returns the PID of the dnsmasq instance that serves the system, if one is running.
+fn find_dnsmasq() -> Option<Pid> {
+    let from_pid_file = DNSMASQ_PID_PATHS.iter().find_map(|path| {
+        let pid = fs::read_to_string(path).ok()?.trim().parse::<i32>().ok()?;
+        Path::new(&format!("/proc/{}/", pid))
+            .exists()
+            .then(|| Pid::from_raw(pid))
+    });
+    if from_pid_file.is_some() {
+        return from_pid_file;
+    }
+
+    // Not all distributions let dnsmasq write a PID file
+    fs::read_dir("/proc")
+        .ok()?
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse::<i32>().ok())
+        .find(|&pid| {
+            let is_dnsmasq = fs::read_to_string(format!("/proc/{}/comm", pid))
+                .map(|comm| comm.trim() == "dnsmasq")
+                .unwrap_or(false);
+            is_dnsmasq
+                && !read_cmdline(pid).iter().any(|arg| {
+                    IGNORED_DNSMASQ_OWNERS
+                        .iter()
+                        .any(|owner| arg.contains(owner))
+                })
+        })
+        .map(Pid::from_raw)
+}
+
+fn read_cmdline(pid: i32) -> Vec<String> {
+    fs::read(format!("/proc/{}/cmdline", pid))
+        .map(|cmdline| {
+            cmdline
+                .split(|&byte| byte == 0)
+                .filter(|arg| !arg.is_empty())
+                .map(|arg| String::from_utf8_lossy(arg).into_owned())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+// Returns true if /etc/resolv.conf only lists loopback nameservers, i.e. a local resolver
+fn resolv_conf_uses_local_resolver() -> bool {
+    let config = match fs::read_to_string(RESOLV_CONF_PATH)
+        .ok()
+        .and_then(|contents| Config::parse(&contents).ok())
+    {
+        Some(config) => config,
+        None => return false,
+    };
+    !config.nameservers.is_empty()
+        && config
+            .nameservers
+            .into_iter()
+            .all(|nameserver| Into::<IpAddr>::into(nameserver).is_loopback())
+}
+
+/// The dnsmasq options that decide where it reads its upstream servers from.
+#[derive(Debug, Default, PartialEq)]
+struct DnsmasqOptions {
+    resolv_file: Option<PathBuf>,
+    no_resolv: bool,
+}
+
+impl DnsmasqOptions {
+    /// Reads the options from the command line of the running dnsmasq and the configuration
+    /// files it uses.
+    fn load(pid: Pid) -> Self {
+        let mut options = Self::default();
+        let args = read_cmdline(pid.as_raw());
+        let mut pending = options.apply_args(args.get(1..).unwrap_or_default());
+        let mut visited = HashSet::new();
+
+        while let Some(path) = pending.pop() {
+            if visited.len() >= MAX_CONFIG_FILES || !visited.insert(path.clone()) {
+                continue;
+            }
+            match fs::read_to_string(&path) {
+                Ok(contents) => pending.extend(options.apply_config(&contents)),
+                Err(error) => log::trace!(
+                    "Failed to read dnsmasq config {}: {}",
+                    path.display(),
+                    error
+                ),
+            }
+        }
+        options
+    }
+
+    /// Applies command line arguments and returns the configuration files to read.
+    fn apply_args(&mut self, args: &[String]) -> Vec<PathBuf> {
+        let mut config_files = vec![];
+        let mut uses_default_config = true;
+        let mut args = args.iter();
+
+        while let Some(arg) = args.next() {
+            let (name, inline_value) = match arg.strip_prefix("--") {
+                Some(long) => match long.split_once('=') {
+                    Some((name, value)) => (name, Some(value.to_owned())),
+                    None => (long, None),
+                },
+                None if arg.len() > 1 && arg.starts_with('-') => {
+                    let (name, value) = arg[1..].split_at(1);
+                    (
+                        name,
+                        Some(value.to_owned()).filter(|value| !value.is_empty()),
+                    )
+                }
+                None => continue,
+            };
+
+            match name {
+                "R" | "no-resolv" => self.no_resolv = true,
+                "r" | "resolv-file" | "C" | "conf-file" | "7" | "conf-dir" => {
+                    let value = match inline_value.or_else(|| args.next().cloned()) {
+                        Some(value) => value,
+                        None => continue,
+                    };
+                    match name {
+                        "r" | "resolv-file" => self.set_resolv_file(&value),
+                        "C" | "conf-file" => {
+                            uses_default_config = false;
+                            config_files.push(PathBuf::from(value));
+                        }
+                        _ => config_files.extend(conf_dir_files(&value)),
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        if uses_default_config {
+            config_files.push(PathBuf::from(DNSMASQ_CONFIG_PATH));
+        }
+        config_files
+    }
+
+    /// Applies the contents of a configuration file and returns the files that it includes.
+    fn apply_config(&mut self, contents: &str) -> Vec<PathBuf> {
+        let mut config_files = vec![];
+
+        for line in contents.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (name, value) = match line.split_once('=') {
+                Some((name, value)) => (name.trim(), Some(value.trim())),
+                None => (line, None),
+            };
+            match (name, value) {
+                ("no-resolv", _) => self.no_resolv = true,
+                ("resolv-file", Some(value)) => self.set_resolv_file(value),
+                ("conf-file", Some(value)) => config_files.push(PathBuf::from(value)),
+                ("conf-dir", Some(value)) => config_files.extend(conf_dir_files(value)),
+                _ => (),
+            }
+        }
+        config_files
+    }
+
+    fn set_resolv_file(&mut self, value: &str) {
+        // dnsmasq polls every given resolv file, but only one is expected in practice
+        if self.resolv_file.is_none() {
+            self.resolv_file = Some(PathBuf::from(value));
+        } else {
+            log::debug!("Ignoring additional dnsmasq resolv file {}", value);
+        }
+    }
+}
+
+/// Lists the files in a `conf-dir`. Like dnsmasq, this accepts a comma separated list of
+/// extensions to skip, or of `*.extension` patterns to only include.
+fn conf_dir_files(value: &str) -> Vec<PathBuf> {
+    let mut parts = value.split(',');
+    let dir = parts.next().unwrap_or_default();
+    let (included, excluded): (Vec<&str>, Vec<&str>) =
+        parts.partition(|extension| extension.starts_with("*."));
+
+    let mut files: Vec<PathBuf> = match fs::read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| {
+                let name = match path.file_name().and_then(|name| name.to_str()) {
+                    Some(name) => name,
+                    None => return false,
+                };
+                !name.starts_with('.')
+                    && !name.ends_with('~')
+                    && (included.is_empty()
+                        || included.iter().any(|pattern| name.ends_with(&pattern[1..])))
+                    && !excluded.iter().any(|extension| name.ends_with(extension))
+            })
+            .collect(),
+        Err(_) => vec![],
+    };
+    files.sort();
+    files
+}
+
+#[cfg(test)]
+mod test {
+    use super::{DnsmasqOptions, DNSMASQ_CONFIG_PATH};
+    use std::path::PathBuf;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_apply_args() {
+        let mut options = DnsmasqOptions::default();
+        let config_files = options.apply_args(&args(&["-k", "--resolv-file=/etc/dnsmasq.resolv"]));
+        assert_eq!(
+            options.resolv_file,
+            Some(PathBuf::from("/etc/dnsmasq.resolv"))
+        );
+        assert!(!options.no_resolv);
+        assert_eq!(config_files, vec![PathBuf::from(DNSMASQ_CONFIG_PATH)]);
+
+        let mut options = DnsmasqOptions::default();
+        let config_files = options.apply_args(&args(&[
+            "-r",
+            "/run/dnsmasq/resolv.conf",
+            "-C",
+            "/etc/custom.conf",
+        ]));
+        assert_eq!(
+            options.resolv_file,
+            Some(PathBuf::from("/run/dnsmasq/resolv.conf"))
+        );
+        assert_eq!(config_files, vec![PathBuf::from("/etc/custom.conf")]);
+
+        let mut options = DnsmasqOptions::default();
+        options.apply_args(&args(&["-R"]));
+        assert!(options.no_resolv);
+    }
+
+    #[test]
+    fn test_apply_config() {
+        let mut options = DnsmasqOptions::default();
+        let config_files = options.apply_config(
+            "# resolv-file=/etc/commented.resolv\n\
+             listen-address=127.0.0.1\n\
+             resolv-file = /etc/dnsmasq.resolv\n\
+             conf-file=/etc/dnsmasq.more.conf\n",
+        );
+        assert_eq!(
+            options,
+            DnsmasqOptions {
+                resolv_file: Some(PathBuf::from("/etc/dnsmasq.resolv")),
+                no_resolv: false,
+            }
+        );
+        assert_eq!(config_files, vec![PathBuf::from("/etc/dnsmasq.more.conf")]);
+
+        let mut options = DnsmasqOptions::default();
+        options.apply_config("no-resolv\nserver=9.9.9.9\n");
+        assert!(options.no_resolv);
+        assert_eq!(options.resolv_file, None);
+    }
+}
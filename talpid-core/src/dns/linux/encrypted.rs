@@ -0,0 +1,507 @@
+//! Clients for DNS-over-HTTPS (RFC 8484) and DNS-over-TLS (RFC 7858) resolvers, used by the local
+//! resolver to forward queries.
+
+use rustls::{ClientConfig, ClientConnection, RootCertStore, ServerName, StreamOwned};
+use std::{
+    convert::TryFrom,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpStream},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use talpid_types::{
+    net::dns::{EncryptedDnsProtocol, EncryptedDnsServer},
+    ErrorExt,
+};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const IO_TIMEOUT: Duration = Duration::from_secs(5);
+/// Maximum number of open connections to keep for reuse, per resolver.
+const MAX_IDLE_CONNECTIONS: usize = 4;
+const MAX_HEADER_LINE_LEN: u64 = 8 * 1024;
+const MAX_MESSAGE_LEN: usize = u16::MAX as usize;
+
+const DNS_MESSAGE_MIME: &str = "application/dns-message";
+
+/// Errors that can happen when setting up an encrypted resolver.
+#[derive(err_derive::Error, Debug)]
+#[error(no_from)]
+pub enum Error {
+    /// The hostname cannot be used to validate certificates
+    #[error(display = "Invalid resolver hostname: {}", _0)]
+    InvalidHostname(String),
+
+    /// No addresses to connect to
+    #[error(display = "No addresses for resolver {}", _0)]
+    NoAddresses(String),
+}
+
+type Connection = BufReader<StreamOwned<ClientConnection, TcpStream>>;
+
+/// Forwards DNS queries to a DNS-over-HTTPS or DNS-over-TLS server. Connections are kept open and
+/// reused between queries.
+pub struct EncryptedResolver {
+    server: EncryptedDnsServer,
+    server_name: ServerName,
+    tls_config: Arc<ClientConfig>,
+    idle_connections: Mutex<Vec<Connection>>,
+}
+
+impl EncryptedResolver {
+    pub fn new(server: EncryptedDnsServer, roots: RootCertStore) -> Result<Self, Error> {
+        let server_name = ServerName::try_from(server.hostname.as_str())
+            .map_err(|_| Error::InvalidHostname(server.hostname.clone()))?;
+        if server.addresses.is_empty() {
+            return Err(Error::NoAddresses(server.to_string()));
+        }
+
+        let mut tls_config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        if server.protocol == EncryptedDnsProtocol::Https {
+            tls_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        }
+
+        Ok(EncryptedResolver {
+            server,
+            server_name,
+            tls_config: Arc::new(tls_config),
+            idle_connections: Mutex::new(vec![]),
+        })
+    }
+
+    pub fn server(&self) -> &EncryptedDnsServer {
+        &self.server
+    }
+
+    /// Sends a DNS query to the server and returns the response.
+    pub fn query(&self, query: &[u8]) -> io::Result<Vec<u8>> {
+        // The server may have closed an idle connection, so fall back on a new connection
+        let idle_connection = self.idle_connections.lock().unwrap().pop();
+        if let Some(mut connection) = idle_connection {
+            if let Ok((response, reusable)) = self.exchange(&mut connection, query) {
+                if reusable {
+                    self.release(connection);
+                }
+                return Ok(response);
+            }
+        }
+
+        let mut connection = self.connect()?;
+        let (response, reusable) = self.exchange(&mut connection, query)?;
+        if reusable {
+            self.release(connection);
+        }
+        Ok(response)
+    }
+
+    fn release(&self, connection: Connection) {
+        let mut idle_connections = self.idle_connections.lock().unwrap();
+        if idle_connections.len() < MAX_IDLE_CONNECTIONS {
+            idle_connections.push(connection);
+        }
+    }
+
+    fn connect(&self) -> io::Result<Connection> {
+        let mut last_error = None;
+        for address in &self.server.addresses {
+            let address = SocketAddr::new(*address, self.server.port);
+            match TcpStream::connect_timeout(&address, CONNECT_TIMEOUT) {
+                Ok(stream) => {
+                    stream.set_read_timeout(Some(IO_TIMEOUT))?;
+                    stream.set_write_timeout(Some(IO_TIMEOUT))?;
+                    stream.set_nodelay(true)?;
+                    let tls =
+                        ClientConnection::new(self.tls_config.clone(), self.server_name.clone())
+                            .map_err(|error| io::Error::new(io::ErrorKind::Other, error))?;
+                    return Ok(BufReader::new(StreamOwned::new(tls, stream)));
+                }
+                Err(error) => {
+                    log::trace!(
+                        "{}",
+                        error.display_chain_with_msg(&format!(
+                            "Failed to connect to {} at {}",
+                            self.server, address
+                        ))
+                    );
+                    last_error = Some(error);
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| io::Error::from(io::ErrorKind::NotFound)))
+    }
+
+    /// Returns the response, and whether the connection can be reused.
+    fn exchange(&self, connection: &mut Connection, query: &[u8]) -> io::Result<(Vec<u8>, bool)> {
+        match self.server.protocol {
+            EncryptedDnsProtocol::Tls => {
+                write_message(connection.get_mut(), query)?;
+                Ok((read_message(connection)?, true))
+            }
+            EncryptedDnsProtocol::Https => self.https_exchange(connection, query),
+        }
+    }
+
+    fn https_exchange(
+        &self,
+        connection: &mut Connection,
+        query: &[u8],
+    ) -> io::Result<(Vec<u8>, bool)> {
+        if query.len() < 2 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "DNS query too short",
+            ));
+        }
+        // RFC 8484 recommends an ID of zero, which makes responses easier to cache
+        let id = [query[0], query[1]];
+        let mut request = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: {}\r\nAccept: {}\r\n\
+             Content-Length: {}\r\n\r\n",
+            self.server.path,
+            self.server.hostname,
+            DNS_MESSAGE_MIME,
+            DNS_MESSAGE_MIME,
+            query.len()
+        )
+        .into_bytes();
+        request.extend_from_slice(&[0, 0]);
+        request.extend_from_slice(&query[2..]);
+        connection.get_mut().write_all(&request)?;
+        connection.get_mut().flush()?;
+
+        let (mut response, reusable) = read_http_response(connection)?;
+        if response.len() < 2 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "DNS response too short",
+            ));
+        }
+        response[0..2].copy_from_slice(&id);
+        Ok((response, reusable))
+    }
+}
+
+/// Writes a DNS message prefixed by its length, as done over TCP.
+pub fn write_message(stream: &mut impl Write, message: &[u8]) -> io::Result<()> {
+    let len = u16::try_from(message.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "DNS message too long"))?;
+    let mut buffer = Vec::with_capacity(message.len() + 2);
+    buffer.extend_from_slice(&len.to_be_bytes());
+    buffer.extend_from_slice(message);
+    stream.write_all(&buffer)?;
+    stream.flush()
+}
+
+/// Reads a DNS message prefixed by its length, as done over TCP.
+pub fn read_message(stream: &mut impl Read) -> io::Result<Vec<u8>> {
+    let mut len = [0u8; 2];
+    stream.read_exact(&mut len)?;
+    let mut message = vec![0u8; usize::from(u16::from_be_bytes(len))];
+    stream.read_exact(&mut message)?;
+    Ok(message)
+}
+
+/// Reads an HTTP/1.1 response. Returns the body, and whether the connection can be reused.
+fn read_http_response(reader: &mut impl BufRead) -> io::Result<(Vec<u8>, bool)> {
+    let status_line = read_header_line(reader)?;
+    let mut parts = status_line.split_whitespace();
+    let version = parts.next().unwrap_or_default();
+    let status = parts.next().unwrap_or_default();
+    if !version.starts_with("HTTP/1.") {
+        return Err(invalid_data("Invalid HTTP status line"));
+    }
+
+    let mut keep_alive = version == "HTTP/1.1";
+    let mut content_length = None;
+    let mut chunked = false;
+    loop {
+        let line = read_header_line(reader)?;
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| invalid_data("Invalid HTTP header"))?;
+        let value = value.trim();
+        match name.trim().to_ascii_lowercase().as_str() {
+            "content-length" => {
+                let len = value
+                    .parse::<usize>()
+                    .map_err(|_| invalid_data("Invalid Content-Length header"))?;
+                if len > MAX_MESSAGE_LEN {
+                    return Err(invalid_data("HTTP response too long"));
+                }
+                content_length = Some(len);
+            }
+            "transfer-encoding" => chunked = value.eq_ignore_ascii_case("chunked"),
+            "connection" => {
+                if value.eq_ignore_ascii_case("close") {
+                    keep_alive = false;
+                } else if value.eq_ignore_ascii_case("keep-alive") {
+                    keep_alive = true;
+                }
+            }
+            _ => (),
+        }
+    }
+
+    let body = if chunked {
+        read_chunked_body(reader)?
+    } else if let Some(len) = content_length {
+        let mut body = vec![0u8; len];
+        reader.read_exact(&mut body)?;
+        body
+    } else {
+        keep_alive = false;
+        let mut body = vec![];
+        reader
+            .take(MAX_MESSAGE_LEN as u64 + 1)
+            .read_to_end(&mut body)?;
+        body
+    };
+    if body.len() > MAX_MESSAGE_LEN {
+        return Err(invalid_data("HTTP response too long"));
+    }
+
+    if status != "200" {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("DNS-over-HTTPS server responded with status {}", status),
+        ));
+    }
+    Ok((body, keep_alive))
+}
+
+fn read_chunked_body(reader: &mut impl BufRead) -> io::Result<Vec<u8>> {
+    let mut body = vec![];
+    loop {
+        let line = read_header_line(reader)?;
+        let size = line.split(';').next().unwrap_or_default().trim();
+        let size =
+            usize::from_str_radix(size, 16).map_err(|_| invalid_data("Invalid HTTP chunk size"))?;
+        if size == 0 {
+            // Skip trailers
+            while !read_header_line(reader)?.is_empty() {}
+            return Ok(body);
+        }
+        if body.len() + size > MAX_MESSAGE_LEN {
+            return Err(invalid_data("HTTP response too long"));
+        }
+        let start = body.len();
+        body.resize(start + size, 0);
+        reader.read_exact(&mut body[start..])?;
+        if !read_header_line(reader)?.is_empty() {
+            return Err(invalid_data("Invalid HTTP chunk"));
+        }
+    }
+}
+
+/// Reads a line without the trailing CRLF.
+fn read_header_line(reader: &mut impl BufRead) -> io::Result<String> {
+    let mut line = String::new();
+    reader.take(MAX_HEADER_LINE_LEN).read_line(&mut line)?;
+    if !line.ends_with('\n') {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+    }
+    Ok(line.trim_end_matches(&['\r', '\n'][..]).to_owned())
+}
+
+fn invalid_data(message: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+pub(super) mod test {
+    use super::*;
+    use rustls::{Certificate, PrivateKey, ServerConfig, ServerConnection};
+    use std::{net::TcpListener, thread};
+
+    const CA_CERT: &[u8] = include_bytes!("test-certs/ca.pem");
+    const SERVER_CERT: &[u8] = include_bytes!("test-certs/server.pem");
+    const SERVER_KEY: &[u8] = include_bytes!("test-certs/server-key.pem");
+
+    /// Hostname that the test server certificate is valid for.
+    pub const TEST_HOSTNAME: &str = "dns.test";
+
+    pub fn test_roots() -> RootCertStore {
+        let mut roots = RootCertStore::empty();
+        for cert in rustls_pemfile::certs(&mut &CA_CERT[..]).unwrap() {
+            roots.add(&Certificate(cert)).unwrap();
+        }
+        roots
+    }
+
+    fn server_config() -> Arc<ServerConfig> {
+        let certs = rustls_pemfile::certs(&mut &SERVER_CERT[..])
+            .unwrap()
+            .into_iter()
+            .map(Certificate)
+            .collect();
+        let key = rustls_pemfile::pkcs8_private_keys(&mut &SERVER_KEY[..])
+            .unwrap()
+            .remove(0);
+        Arc::new(
+            ServerConfig::builder()
+                .with_safe_defaults()
+                .with_no_client_auth()
+                .with_single_cert(certs, PrivateKey(key))
+                .unwrap(),
+        )
+    }
+
+    /// Answers a query by echoing it back as a response.
+    pub fn response_to(query: &[u8]) -> Vec<u8> {
+        let mut response = query.to_vec();
+        response[2] |= 0x80;
+        response[3] = 0x80;
+        response
+    }
+
+    /// Starts a local stand-in for an encrypted resolver. It answers queries on a single
+    /// connection using [`response_to`], and returns the number of queries it has answered when
+    /// the connection is closed.
+    pub fn spawn_server(
+        protocol: EncryptedDnsProtocol,
+    ) -> (EncryptedDnsServer, thread::JoinHandle<usize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let config = server_config();
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let connection = ServerConnection::new(config).unwrap();
+            let mut stream = BufReader::new(StreamOwned::new(connection, stream));
+            let mut answered = 0;
+            loop {
+                let result = match protocol {
+                    EncryptedDnsProtocol::Tls => serve_tls_query(&mut stream),
+                    EncryptedDnsProtocol::Https => serve_https_query(&mut stream),
+                };
+                if result.is_err() {
+                    return answered;
+                }
+                answered += 1;
+            }
+        });
+        let server = EncryptedDnsServer {
+            protocol,
+            hostname: TEST_HOSTNAME.to_owned(),
+            port: address.port(),
+            path: match protocol {
+                EncryptedDnsProtocol::Https => "/dns-query".to_owned(),
+                EncryptedDnsProtocol::Tls => String::new(),
+            },
+            addresses: vec![address.ip()],
+        };
+        (server, handle)
+    }
+
+    fn serve_tls_query(
+        stream: &mut BufReader<StreamOwned<ServerConnection, TcpStream>>,
+    ) -> io::Result<()> {
+        let query = read_message(stream)?;
+        write_message(stream.get_mut(), &response_to(&query))
+    }
+
+    fn serve_https_query(
+        stream: &mut BufReader<StreamOwned<ServerConnection, TcpStream>>,
+    ) -> io::Result<()> {
+        let request_line = read_header_line(stream)?;
+        assert_eq!(request_line, "POST /dns-query HTTP/1.1");
+        let mut content_length = 0;
+        loop {
+            let line = read_header_line(stream)?;
+            if line.is_empty() {
+                break;
+            }
+            let (name, value) = line.split_once(':').unwrap();
+            match name.to_ascii_lowercase().as_str() {
+                "content-length" => content_length = value.trim().parse().unwrap(),
+                "content-type" => assert_eq!(value.trim(), DNS_MESSAGE_MIME),
+                "host" => assert_eq!(value.trim(), TEST_HOSTNAME),
+                _ => (),
+            }
+        }
+        let mut query = vec![0u8; content_length];
+        stream.read_exact(&mut query)?;
+        assert_eq!(&query[0..2], &[0, 0]);
+
+        // Use chunked encoding to test that too
+        let response = response_to(&query);
+        let header = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nTransfer-Encoding: chunked\r\n\r\n",
+            DNS_MESSAGE_MIME
+        );
+        let stream = stream.get_mut();
+        stream.write_all(header.as_bytes())?;
+        let (first, second) = response.split_at(response.len() / 2);
+        for chunk in &[first, second] {
+            write!(stream, "{:x}\r\n", chunk.len())?;
+            stream.write_all(chunk)?;
+            stream.write_all(b"\r\n")?;
+        }
+        stream.write_all(b"0\r\n\r\n")?;
+        stream.flush()
+    }
+
+    fn query(id: u16) -> Vec<u8> {
+        let mut query = id.to_be_bytes().to_vec();
+        query.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+        query.extend_from_slice(b"\x07example\x03com\x00\x00\x01\x00\x01");
+        query
+    }
+
+    fn test_protocol(protocol: EncryptedDnsProtocol) {
+        let (server, handle) = spawn_server(protocol);
+        let resolver = EncryptedResolver::new(server, test_roots()).unwrap();
+
+        // Both queries should use the same connection
+        for id in [0x1234, 0x5678] {
+            let query = query(id);
+            assert_eq!(resolver.query(&query).unwrap(), response_to(&query));
+        }
+        drop(resolver);
+        assert_eq!(handle.join().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_dns_over_tls() {
+        test_protocol(EncryptedDnsProtocol::Tls);
+    }
+
+    #[test]
+    fn test_dns_over_https() {
+        test_protocol(EncryptedDnsProtocol::Https);
+    }
+
+    #[test]
+    fn test_untrusted_certificate() {
+        let (server, _handle) = spawn_server(EncryptedDnsProtocol::Tls);
+        let resolver = EncryptedResolver::new(server, RootCertStore::empty()).unwrap();
+        assert!(resolver.query(&query(1)).is_err());
+    }
+
+    #[test]
+    fn test_wrong_hostname() {
+        let (mut server, _handle) = spawn_server(EncryptedDnsProtocol::Https);
+        server.hostname = "other.test".to_owned();
+        let resolver = EncryptedResolver::new(server, test_roots()).unwrap();
+        assert!(resolver.query(&query(1)).is_err());
+    }
+
+    #[test]
+    fn test_http_response() {
+        let response = b"HTTP/1.1 200 OK\r\nContent-Length: 3\r\nConnection: close\r\n\r\nabc";
+        assert_eq!(
+            read_http_response(&mut &response[..]).unwrap(),
+            (b"abc".to_vec(), false)
+        );
+
+        let response = b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n";
+        assert!(read_http_response(&mut &response[..]).is_err());
+
+        let response = b"HTTP/1.1 200 OK\r\nContent-Length: 70000\r\n\r\n";
+        assert!(read_http_response(&mut &response[..]).is_err());
+    }
+}
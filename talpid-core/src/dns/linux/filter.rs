@@ -0,0 +1,1254 @@
+//! A local DNS resolver that forwards queries to the upstream resolvers, except for queries for
+//! domains that are listed in one of the enabled blocklists. Those are answered with `NXDOMAIN`.
+//!
+//! Blocklists are either hosts files, where every name mapped to an address is blocked, or plain
+//! lists with a single domain per line. Blocking a domain also blocks all of its subdomains.
+//!
+//! When DNS-over-HTTPS or DNS-over-TLS servers are configured, queries that are not blocked are
+//! sent to those instead of the plain upstream resolvers.
+//!
+//! Queries for domains that match a split DNS rule are sent to the resolvers of that rule instead.
+
+use super::encrypted::{read_message, write_message, EncryptedResolver};
+use rustls::RootCertStore;
+use std::{
+    collections::{HashMap, HashSet},
+    fs, io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream, UdpSocket},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+use talpid_types::{
+    net::dns::{DnsBlocklist, EncryptedDnsServer, SplitDnsRule},
+    ErrorExt,
+};
+
+/// Address that the filtering resolver listens on.
+pub const FILTER_ADDRESS: IpAddr = IpAddr::V4(Ipv4Addr::new(127, 77, 77, 53));
+
+const DNS_PORT: u16 = 53;
+
+/// How long to wait for an upstream resolver before forgetting about a query.
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long to wait for a TCP client to send a query.
+const TCP_CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+/// How often the UDP threads check whether the server has been stopped.
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+/// Maximum number of UDP queries waiting for an encrypted resolver at the same time.
+const MAX_ENCRYPTED_QUERIES: usize = 64;
+/// Maximum number of UDP queries waiting for a plain upstream resolver at the same time. This
+/// must be well below the number of query IDs, so that an unused ID can always be found quickly.
+const MAX_PENDING_QUERIES: usize = 1024;
+/// Maximum number of TCP clients that are served at the same time.
+const MAX_TCP_CLIENTS: usize = 64;
+
+const HEADER_LEN: usize = 12;
+const MAX_MESSAGE_LEN: usize = u16::MAX as usize;
+const RCODE_NXDOMAIN: u8 = 3;
+
+/// Names that are commonly found in hosts files but should never be blocked.
+const IGNORED_NAMES: &[&str] = &[
+    "localhost",
+    "localhost.localdomain",
+    "local",
+    "broadcasthost",
+    "ip6-localhost",
+    "ip6-loopback",
+    "ip6-localnet",
+    "ip6-mcastprefix",
+    "ip6-allnodes",
+    "ip6-allrouters",
+    "ip6-allhosts",
+];
+
+/// Errors that can happen when starting the filtering resolver.
+#[derive(err_derive::Error, Debug)]
+#[error(no_from)]
+pub enum Error {
+    /// Failed to bind the UDP socket that clients send queries to
+    #[error(display = "Failed to bind UDP socket to {}", _0)]
+    BindUdp(SocketAddr, #[error(source)] io::Error),
+
+    /// Failed to bind the TCP socket that clients send queries to
+    #[error(display = "Failed to bind TCP socket to {}", _0)]
+    BindTcp(SocketAddr, #[error(source)] io::Error),
+
+    /// Failed to bind a socket for sending queries to upstream resolvers
+    #[error(display = "Failed to bind upstream socket")]
+    BindUpstream(#[error(source)] io::Error),
+
+    /// Failed to configure a socket
+    #[error(display = "Failed to configure socket")]
+    ConfigureSocket(#[error(source)] io::Error),
+
+    /// Failed to spawn a server thread
+    #[error(display = "Failed to spawn DNS filter thread")]
+    SpawnThread(#[error(source)] io::Error),
+}
+
+/// Hit counters and load status of a blocklist.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlocklistStats {
+    pub path: PathBuf,
+    pub enabled: bool,
+    /// Number of domains loaded from the list.
+    pub domains: usize,
+    /// Number of queries blocked by the list.
+    pub hits: u64,
+    /// Why the list could not be loaded, if it could not.
+    pub error: Option<String>,
+}
+
+struct LoadedBlocklist {
+    path: PathBuf,
+    enabled: bool,
+    domains: HashSet<String>,
+    hits: AtomicU64,
+    error: Option<String>,
+}
+
+impl LoadedBlocklist {
+    fn load(list: DnsBlocklist, hits: u64) -> Self {
+        let (domains, error) = match fs::read_to_string(&list.path) {
+            Ok(contents) => (parse_blocklist(&contents), None),
+            Err(error) => {
+                log::error!(
+                    "{}",
+                    error.display_chain_with_msg(&format!(
+                        "Failed to read DNS blocklist {}",
+                        list.path.display()
+                    ))
+                );
+                (HashSet::new(), Some(error.to_string()))
+            }
+        };
+        if error.is_none() {
+            log::debug!(
+                "Loaded {} domains from DNS blocklist {}",
+                domains.len(),
+                list.path.display()
+            );
+        }
+        LoadedBlocklist {
+            path: list.path,
+            enabled: list.enabled,
+            domains,
+            hits: AtomicU64::new(hits),
+            error,
+        }
+    }
+
+    fn to_blocklist(&self) -> DnsBlocklist {
+        DnsBlocklist {
+            path: self.path.clone(),
+            enabled: self.enabled,
+        }
+    }
+}
+
+type Blocklists = Arc<RwLock<Vec<LoadedBlocklist>>>;
+
+type EncryptedResolvers = Arc<RwLock<Vec<Arc<EncryptedResolver>>>>;
+
+/// A split DNS rule along with the socket addresses of its resolvers.
+struct SplitDnsRoute {
+    rule: SplitDnsRule,
+    resolvers: Vec<SocketAddr>,
+}
+
+type SplitDnsRoutes = Arc<RwLock<Vec<SplitDnsRoute>>>;
+
+/// Settings that are shared between the handle and the server threads.
+#[derive(Clone, Default)]
+struct Config {
+    blocklists: Blocklists,
+    encrypted: EncryptedResolvers,
+    split_dns: SplitDnsRoutes,
+}
+
+/// Handle to the filtering resolver. Clones share the same settings and server.
+#[derive(Clone, Default)]
+pub struct DnsFilter {
+    config: Config,
+    server: Arc<Mutex<Option<Server>>>,
+}
+
+impl DnsFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Loads the given blocklists from disk, replacing the current ones. Hit counters are kept
+    /// for lists that were already loaded.
+    pub fn set_blocklists(&self, lists: Vec<DnsBlocklist>) {
+        let hits: HashMap<PathBuf, u64> = self
+            .config
+            .blocklists
+            .read()
+            .unwrap()
+            .iter()
+            .map(|list| (list.path.clone(), list.hits.load(Ordering::Relaxed)))
+            .collect();
+        let loaded = lists
+            .into_iter()
+            .map(|list| {
+                let hits = hits.get(&list.path).copied().unwrap_or(0);
+                LoadedBlocklist::load(list, hits)
+            })
+            .collect();
+        *self.config.blocklists.write().unwrap() = loaded;
+    }
+
+    /// Reads all blocklists from disk again.
+    pub fn reload(&self) {
+        let lists = self
+            .config
+            .blocklists
+            .read()
+            .unwrap()
+            .iter()
+            .map(LoadedBlocklist::to_blocklist)
+            .collect();
+        self.set_blocklists(lists);
+    }
+
+    /// Sets the DNS-over-HTTPS and DNS-over-TLS servers to forward queries to, in order of
+    /// preference. Server certificates are verified against the system's root certificates.
+    pub fn set_encrypted_servers(&self, servers: Vec<EncryptedDnsServer>) {
+        let current: Vec<EncryptedDnsServer> = self
+            .config
+            .encrypted
+            .read()
+            .unwrap()
+            .iter()
+            .map(|resolver| resolver.server().clone())
+            .collect();
+        if current == servers {
+            return;
+        }
+        let roots = if servers.is_empty() {
+            RootCertStore::empty()
+        } else {
+            system_roots()
+        };
+        self.set_encrypted_servers_with_roots(servers, roots);
+    }
+
+    fn set_encrypted_servers_with_roots(
+        &self,
+        servers: Vec<EncryptedDnsServer>,
+        roots: RootCertStore,
+    ) {
+        let resolvers = servers
+            .into_iter()
+            .filter_map(
+                |server| match EncryptedResolver::new(server, roots.clone()) {
+                    Ok(resolver) => Some(Arc::new(resolver)),
+                    Err(error) => {
+                        log::error!(
+                            "{}",
+                            error.display_chain_with_msg("Ignoring encrypted DNS server")
+                        );
+                        None
+                    }
+                },
+            )
+            .collect();
+        *self.config.encrypted.write().unwrap() = resolvers;
+    }
+
+    /// Sets the rules for sending queries for specific domains to other resolvers.
+    pub fn set_split_dns(&self, rules: Vec<SplitDnsRule>) {
+        let routes = rules
+            .into_iter()
+            .map(|rule| SplitDnsRoute {
+                resolvers: rule
+                    .resolvers
+                    .iter()
+                    .map(|resolver| SocketAddr::new(*resolver, DNS_PORT))
+                    .collect(),
+                rule,
+            })
+            .collect();
+        *self.config.split_dns.write().unwrap() = routes;
+    }
+
+    /// Returns whether queries are sent to encrypted resolvers rather than the plain upstream
+    /// resolvers.
+    pub fn is_encrypted(&self) -> bool {
+        !self.config.encrypted.read().unwrap().is_empty()
+    }
+
+    /// Returns whether any blocklist is enabled or any encrypted resolver is set, i.e. whether
+    /// DNS should go through the local resolver.
+    pub fn is_enabled(&self) -> bool {
+        self.is_encrypted()
+            || self
+                .config
+                .blocklists
+                .read()
+                .unwrap()
+                .iter()
+                .any(|list| list.enabled)
+    }
+
+    pub fn stats(&self) -> Vec<BlocklistStats> {
+        self.config
+            .blocklists
+            .read()
+            .unwrap()
+            .iter()
+            .map(|list| BlocklistStats {
+                path: list.path.clone(),
+                enabled: list.enabled,
+                domains: list.domains.len(),
+                hits: list.hits.load(Ordering::Relaxed),
+                error: list.error.clone(),
+            })
+            .collect()
+    }
+
+    /// Starts the resolver, or points the running resolver at new upstream servers. Returns the
+    /// address that the system should use as its resolver.
+    pub fn start(&self, upstream: &[IpAddr]) -> Result<IpAddr, Error> {
+        let upstream = upstream
+            .iter()
+            .map(|server| SocketAddr::new(*server, DNS_PORT))
+            .collect();
+        self.start_on(SocketAddr::new(FILTER_ADDRESS, DNS_PORT), upstream)
+            .map(|addr| addr.ip())
+    }
+
+    fn start_on(
+        &self,
+        address: SocketAddr,
+        upstream: Vec<SocketAddr>,
+    ) -> Result<SocketAddr, Error> {
+        let mut server = self.server.lock().unwrap();
+        if let Some(server) = &*server {
+            *server.upstream.write().unwrap() = upstream;
+            return Ok(server.address);
+        }
+        let new_server = Server::start(address, upstream, self.config.clone())?;
+        log::debug!("Started DNS filter on {}", new_server.address);
+        let address = new_server.address;
+        *server = Some(new_server);
+        Ok(address)
+    }
+
+    /// Returns the servers that the resolver forwards plain queries to, if it is running.
+    pub fn upstream(&self) -> Option<Vec<IpAddr>> {
+        self.server.lock().unwrap().as_ref().map(|server| {
+            server
+                .upstream
+                .read()
+                .unwrap()
+                .iter()
+                .map(|addr| addr.ip())
+                .collect()
+        })
+    }
+
+    /// Stops the resolver if it is running.
+    pub fn stop(&self) {
+        if self.server.lock().unwrap().take().is_some() {
+            log::debug!("Stopped DNS filter");
+        }
+    }
+}
+
+fn system_roots() -> RootCertStore {
+    let mut roots = RootCertStore::empty();
+    match rustls_native_certs::load_native_certs() {
+        Ok(certs) => {
+            let certs: Vec<Vec<u8>> = certs.into_iter().map(|cert| cert.0).collect();
+            let (_, invalid) = roots.add_parsable_certificates(&certs);
+            if invalid > 0 {
+                log::warn!("Ignoring {} invalid root certificates", invalid);
+            }
+        }
+        Err(error) => {
+            log::error!(
+                "{}",
+                error.display_chain_with_msg("Failed to load root certificates")
+            );
+        }
+    }
+    roots
+}
+
+/// Returns whether `name` or any of its parent domains is blocked by an enabled list, and
+/// counts the hit.
+fn is_blocked(blocklists: &Blocklists, name: &str) -> bool {
+    let name = normalize_name(name);
+    let lists = blocklists.read().unwrap();
+    for list in lists.iter().filter(|list| list.enabled) {
+        let mut domain = name.as_str();
+        loop {
+            if list.domains.contains(domain) {
+                list.hits.fetch_add(1, Ordering::Relaxed);
+                return true;
+            }
+            match domain.split_once('.') {
+                Some((_, parent)) => domain = parent,
+                None => break,
+            }
+        }
+    }
+    false
+}
+
+fn normalize_name(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// Parses a hosts file or a list of domains. Lines starting with an IP address are treated as
+/// hosts file entries, any other line as a single domain.
+fn parse_blocklist(contents: &str) -> HashSet<String> {
+    let mut domains = HashSet::new();
+    for line in contents.lines() {
+        let line = line.split('#').next().unwrap_or_default();
+        let mut tokens = line.split_whitespace();
+        let first = match tokens.next() {
+            Some(first) => first,
+            None => continue,
+        };
+        let names: Vec<&str> = if first.parse::<IpAddr>().is_ok() {
+            tokens.collect()
+        } else {
+            vec![first]
+        };
+        for name in names {
+            let name = normalize_name(name);
+            if name.is_empty()
+                || name.parse::<IpAddr>().is_ok()
+                || IGNORED_NAMES.contains(&name.as_str())
+            {
+                continue;
+            }
+            domains.insert(name);
+        }
+    }
+    domains
+}
+
+/// Returns the name in the question section of a DNS query, and the offset where the question
+/// ends. Returns `None` for anything but a standard query with a single question.
+fn parse_question(message: &[u8]) -> Option<(String, usize)> {
+    if message.len() < HEADER_LEN {
+        return None;
+    }
+    let is_response = message[2] & 0x80 != 0;
+    let opcode = (message[2] >> 3) & 0x0f;
+    let question_count = u16::from_be_bytes([message[4], message[5]]);
+    if is_response || opcode != 0 || question_count != 1 {
+        return None;
+    }
+
+    let mut labels = vec![];
+    let mut offset = HEADER_LEN;
+    loop {
+        let len = usize::from(*message.get(offset)?);
+        offset += 1;
+        if len == 0 {
+            break;
+        }
+        // Compression pointers and extended label types are not expected in questions
+        if len & 0xc0 != 0 {
+            return None;
+        }
+        let label = message.get(offset..offset + len)?;
+        labels.push(String::from_utf8_lossy(label).into_owned());
+        offset += len;
+    }
+    // QTYPE and QCLASS
+    let end = offset + 4;
+    if message.len() < end {
+        return None;
+    }
+    Some((labels.join("."), end))
+}
+
+/// Builds an `NXDOMAIN` response to a query whose question ends at `question_end`.
+fn blocked_response(query: &[u8], question_end: usize) -> Vec<u8> {
+    let mut response = Vec::with_capacity(question_end);
+    // ID
+    response.extend_from_slice(&query[0..2]);
+    // QR, opcode and RD from the query, RA and RCODE
+    response.push(0x80 | (query[2] & 0x79));
+    response.push(0x80 | RCODE_NXDOMAIN);
+    // One question, no other records
+    response.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+    response.extend_from_slice(&query[HEADER_LEN..question_end]);
+    response
+}
+
+/// How a query should be answered.
+#[derive(Debug, PartialEq)]
+enum QueryAction {
+    /// Answer with the given response.
+    Respond(Vec<u8>),
+    /// Forward the query to the resolvers of a split DNS rule.
+    Forward(Vec<SocketAddr>),
+    /// Forward the query to the encrypted resolvers, or to the upstream resolvers.
+    Default,
+}
+
+/// Answers queries for blocked domains with `NXDOMAIN`, and looks up split DNS rules for other
+/// queries.
+fn route_query(config: &Config, query: &[u8]) -> QueryAction {
+    let (name, question_end) = match parse_question(query) {
+        Some(question) => question,
+        None => return QueryAction::Default,
+    };
+    if is_blocked(&config.blocklists, &name) {
+        log::trace!("Blocked DNS query for {}", name);
+        return QueryAction::Respond(blocked_response(query, question_end));
+    }
+    config
+        .split_dns
+        .read()
+        .unwrap()
+        .iter()
+        .filter(|route| route.rule.matches(&name))
+        .max_by_key(|route| route.rule.domain.len())
+        .map(|route| QueryAction::Forward(route.resolvers.clone()))
+        .unwrap_or(QueryAction::Default)
+}
+
+struct PendingQuery {
+    client: SocketAddr,
+    id: [u8; 2],
+    sent: Instant,
+    /// Resolvers that the query was sent to.
+    servers: Vec<SocketAddr>,
+}
+
+type Upstream = Arc<RwLock<Vec<SocketAddr>>>;
+
+type PendingQueries = Arc<Mutex<HashMap<u16, PendingQuery>>>;
+
+/// Sockets for sending queries to plain upstream resolvers.
+struct UpstreamSockets {
+    v4: UdpSocket,
+    v6: Option<UdpSocket>,
+}
+
+impl UpstreamSockets {
+    fn bind() -> Result<Self, Error> {
+        let v4 = UdpSocket::bind(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0))
+            .map_err(Error::BindUpstream)?;
+        let v6 = match UdpSocket::bind(SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0)) {
+            Ok(socket) => Some(socket),
+            Err(error) => {
+                log::warn!(
+                    "{}",
+                    error.display_chain_with_msg("DNS filter cannot forward queries over IPv6")
+                );
+                None
+            }
+        };
+        Ok(UpstreamSockets { v4, v6 })
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &UdpSocket> {
+        std::iter::once(&self.v4).chain(self.v6.iter())
+    }
+
+    fn for_server(&self, server: &SocketAddr) -> Option<&UdpSocket> {
+        match server {
+            SocketAddr::V4(_) => Some(&self.v4),
+            SocketAddr::V6(_) => self.v6.as_ref(),
+        }
+    }
+}
+
+struct Server {
+    address: SocketAddr,
+    upstream: Upstream,
+    stop: Arc<AtomicBool>,
+    threads: Vec<thread::JoinHandle<()>>,
+}
+
+impl Server {
+    fn start(
+        address: SocketAddr,
+        upstream: Vec<SocketAddr>,
+        config: Config,
+    ) -> Result<Self, Error> {
+        let client_socket =
+            UdpSocket::bind(address).map_err(|error| Error::BindUdp(address, error))?;
+        let address = client_socket.local_addr().map_err(Error::ConfigureSocket)?;
+        let listener =
+            TcpListener::bind(address).map_err(|error| Error::BindTcp(address, error))?;
+        client_socket
+            .set_read_timeout(Some(POLL_INTERVAL))
+            .map_err(Error::ConfigureSocket)?;
+
+        let upstream_sockets = UpstreamSockets::bind()?;
+
+        let mut server = Server {
+            address,
+            upstream: Arc::new(RwLock::new(upstream)),
+            stop: Arc::new(AtomicBool::new(false)),
+            threads: vec![],
+        };
+        let pending = PendingQueries::default();
+
+        for socket in upstream_sockets.iter() {
+            socket
+                .set_read_timeout(Some(POLL_INTERVAL))
+                .map_err(Error::ConfigureSocket)?;
+            let socket = socket.try_clone().map_err(Error::ConfigureSocket)?;
+            let client_socket = client_socket.try_clone().map_err(Error::ConfigureSocket)?;
+            let pending = pending.clone();
+            let stop = server.stop.clone();
+            server.spawn("dns-filter-upstream", move || {
+                run_upstream_receiver(socket, client_socket, pending, stop)
+            })?;
+        }
+
+        let upstream = server.upstream.clone();
+        let stop = server.stop.clone();
+        let udp_config = config.clone();
+        server.spawn("dns-filter-udp", move || {
+            run_udp_server(
+                Arc::new(client_socket),
+                upstream_sockets,
+                upstream,
+                udp_config,
+                pending,
+                stop,
+            )
+        })?;
+
+        let upstream = server.upstream.clone();
+        let stop = server.stop.clone();
+        server.spawn("dns-filter-tcp", move || {
+            run_tcp_server(listener, upstream, config, stop)
+        })?;
+
+        Ok(server)
+    }
+
+    fn spawn(&mut self, name: &str, f: impl FnOnce() + Send + 'static) -> Result<(), Error> {
+        let handle = thread::Builder::new()
+            .name(name.to_owned())
+            .spawn(f)
+            .map_err(Error::SpawnThread)?;
+        self.threads.push(handle);
+        Ok(())
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        // Wake up the TCP listener so that it notices that it should stop
+        let _ = TcpStream::connect_timeout(&self.address, POLL_INTERVAL);
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn run_udp_server(
+    client_socket: Arc<UdpSocket>,
+    upstream_sockets: UpstreamSockets,
+    upstream: Upstream,
+    config: Config,
+    pending: PendingQueries,
+    stop: Arc<AtomicBool>,
+) {
+    let encrypted_queries = Arc::new(AtomicUsize::new(0));
+    let mut buffer = vec![0u8; MAX_MESSAGE_LEN];
+    while !stop.load(Ordering::SeqCst) {
+        let (len, client) = match client_socket.recv_from(&mut buffer) {
+            Ok(result) => result,
+            Err(error)
+                if error.kind() == io::ErrorKind::WouldBlock
+                    || error.kind() == io::ErrorKind::TimedOut =>
+            {
+                continue
+            }
+            Err(error) => {
+                log::error!(
+                    "{}",
+                    error.display_chain_with_msg("Failed to receive DNS query")
+                );
+                continue;
+            }
+        };
+        let query = &mut buffer[..len];
+        if query.len() < HEADER_LEN {
+            continue;
+        }
+
+        let servers = match route_query(&config, query) {
+            QueryAction::Respond(response) => {
+                let _ = client_socket.send_to(&response, client);
+                continue;
+            }
+            QueryAction::Forward(servers) => servers,
+            QueryAction::Default => {
+                let resolvers = config.encrypted.read().unwrap().clone();
+                if !resolvers.is_empty() {
+                    spawn_encrypted_query(
+                        resolvers,
+                        query.to_vec(),
+                        client,
+                        client_socket.clone(),
+                        encrypted_queries.clone(),
+                    );
+                    continue;
+                }
+                upstream.read().unwrap().clone()
+            }
+        };
+
+        let id = match add_pending_query(
+            &mut pending.lock().unwrap(),
+            PendingQuery {
+                client,
+                id: [query[0], query[1]],
+                sent: Instant::now(),
+                servers: servers.clone(),
+            },
+        ) {
+            Some(id) => id,
+            None => {
+                log::trace!("Dropping DNS query since too many queries are in flight");
+                continue;
+            }
+        };
+        query[0..2].copy_from_slice(&id.to_be_bytes());
+
+        // Send the query to all resolvers and use whichever answer arrives first
+        for server in &servers {
+            if let Some(socket) = upstream_sockets.for_server(server) {
+                if let Err(error) = socket.send_to(query, server) {
+                    log::trace!("Failed to forward DNS query to {}: {}", server, error);
+                }
+            }
+        }
+    }
+}
+
+/// Assigns a random unused ID to a query that is about to be forwarded, and remembers it until it
+/// is answered or times out. Returns `None` if too many queries are already waiting.
+fn add_pending_query(pending: &mut HashMap<u16, PendingQuery>, query: PendingQuery) -> Option<u16> {
+    pending.retain(|_, query| query.sent.elapsed() < UPSTREAM_TIMEOUT);
+    if pending.len() >= MAX_PENDING_QUERIES {
+        return None;
+    }
+    let mut id = rand::random::<u16>();
+    while pending.contains_key(&id) {
+        id = rand::random();
+    }
+    pending.insert(id, query);
+    Some(id)
+}
+
+/// Resolves a UDP query on a separate thread, since encrypted resolvers answer over a stream
+/// one query at a time. Queries are dropped if too many are already in flight.
+fn spawn_encrypted_query(
+    resolvers: Vec<Arc<EncryptedResolver>>,
+    query: Vec<u8>,
+    client: SocketAddr,
+    client_socket: Arc<UdpSocket>,
+    in_flight: Arc<AtomicUsize>,
+) {
+    if in_flight.fetch_add(1, Ordering::SeqCst) >= MAX_ENCRYPTED_QUERIES {
+        in_flight.fetch_sub(1, Ordering::SeqCst);
+        log::trace!("Dropping DNS query since too many queries are in flight");
+        return;
+    }
+    let thread_in_flight = in_flight.clone();
+    let result = thread::Builder::new()
+        .name("dns-filter-encrypted".to_owned())
+        .spawn(move || {
+            match resolve_encrypted(&resolvers, &query) {
+                Ok(response) => {
+                    let _ = client_socket.send_to(&response, client);
+                }
+                Err(error) => log::trace!("Encrypted DNS query failed: {}", error),
+            }
+            thread_in_flight.fetch_sub(1, Ordering::SeqCst);
+        });
+    if let Err(error) = result {
+        in_flight.fetch_sub(1, Ordering::SeqCst);
+        log::error!(
+            "{}",
+            error.display_chain_with_msg("Failed to spawn encrypted DNS query thread")
+        );
+    }
+}
+
+/// Sends a query to the first encrypted resolver that answers it.
+fn resolve_encrypted(resolvers: &[Arc<EncryptedResolver>], query: &[u8]) -> io::Result<Vec<u8>> {
+    let mut last_error = io::Error::new(io::ErrorKind::NotFound, "No encrypted DNS servers");
+    for resolver in resolvers {
+        match resolver.query(query) {
+            Ok(response) => return Ok(response),
+            Err(error) => {
+                log::trace!(
+                    "Encrypted DNS server {} failed: {}",
+                    resolver.server(),
+                    error
+                );
+                last_error = error;
+            }
+        }
+    }
+    Err(last_error)
+}
+
+fn run_upstream_receiver(
+    socket: UdpSocket,
+    client_socket: UdpSocket,
+    pending: PendingQueries,
+    stop: Arc<AtomicBool>,
+) {
+    let mut buffer = vec![0u8; MAX_MESSAGE_LEN];
+    while !stop.load(Ordering::SeqCst) {
+        let (len, source) = match socket.recv_from(&mut buffer) {
+            Ok(result) => result,
+            Err(_) => continue,
+        };
+        if len < HEADER_LEN {
+            continue;
+        }
+        let response = &mut buffer[..len];
+        let id = u16::from_be_bytes([response[0], response[1]]);
+        let query = {
+            let mut pending = pending.lock().unwrap();
+            // Ignore responses from anyone but the resolvers that the query was sent to
+            match pending.get(&id) {
+                Some(query) if query.servers.contains(&source) => pending.remove(&id).unwrap(),
+                _ => continue,
+            }
+        };
+        response[0..2].copy_from_slice(&query.id);
+        let _ = client_socket.send_to(response, query.client);
+    }
+}
+
+fn run_tcp_server(
+    listener: TcpListener,
+    upstream: Upstream,
+    config: Config,
+    stop: Arc<AtomicBool>,
+) {
+    let clients = Arc::new(AtomicUsize::new(0));
+    for stream in listener.incoming() {
+        if stop.load(Ordering::SeqCst) {
+            break;
+        }
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(_) => continue,
+        };
+        // Connections are closed right away if too many clients are already being served
+        if clients.fetch_add(1, Ordering::SeqCst) >= MAX_TCP_CLIENTS {
+            clients.fetch_sub(1, Ordering::SeqCst);
+            log::trace!("Dropping DNS filter TCP client since too many clients are connected");
+            continue;
+        }
+        let upstream = upstream.clone();
+        let config = config.clone();
+        let thread_clients = clients.clone();
+        let result = thread::Builder::new()
+            .name("dns-filter-tcp-client".to_owned())
+            .spawn(move || {
+                if let Err(error) = handle_tcp_client(stream, &upstream, &config) {
+                    log::trace!("DNS filter TCP client error: {}", error);
+                }
+                thread_clients.fetch_sub(1, Ordering::SeqCst);
+            });
+        if let Err(error) = result {
+            clients.fetch_sub(1, Ordering::SeqCst);
+            log::error!(
+                "{}",
+                error.display_chain_with_msg("Failed to spawn DNS filter TCP client thread")
+            );
+        }
+    }
+}
+
+fn handle_tcp_client(
+    mut client: TcpStream,
+    upstream: &RwLock<Vec<SocketAddr>>,
+    config: &Config,
+) -> io::Result<()> {
+    client.set_read_timeout(Some(TCP_CLIENT_TIMEOUT))?;
+    loop {
+        let query = match read_message(&mut client) {
+            Ok(query) => query,
+            Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(error) => return Err(error),
+        };
+        let response = match route_query(config, &query) {
+            QueryAction::Respond(response) => response,
+            QueryAction::Forward(servers) => forward_tcp_query(&query, &servers)?,
+            QueryAction::Default => {
+                let resolvers = config.encrypted.read().unwrap().clone();
+                if resolvers.is_empty() {
+                    forward_tcp_query(&query, &upstream.read().unwrap().clone())?
+                } else {
+                    resolve_encrypted(&resolvers, &query)?
+                }
+            }
+        };
+        write_message(&mut client, &response)?;
+    }
+    let _ = client.shutdown(Shutdown::Both);
+    Ok(())
+}
+
+/// Sends a query to the first upstream resolver that accepts a TCP connection.
+fn forward_tcp_query(query: &[u8], upstream: &[SocketAddr]) -> io::Result<Vec<u8>> {
+    let mut last_error = io::Error::new(io::ErrorKind::NotFound, "No upstream DNS servers");
+    for server in upstream {
+        let result = TcpStream::connect_timeout(server, UPSTREAM_TIMEOUT).and_then(|mut stream| {
+            stream.set_read_timeout(Some(UPSTREAM_TIMEOUT))?;
+            write_message(&mut stream, query)?;
+            read_message(&mut stream)
+        });
+        match result {
+            Ok(response) => return Ok(response),
+            Err(error) => last_error = error,
+        }
+    }
+    Err(last_error)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const HOSTS_FILE: &str = "\
+# Comment
+127.0.0.1 localhost
+::1 localhost ip6-localhost ip6-loopback
+0.0.0.0 ads.example.com tracker.example.net # trailing comment
+0.0.0.0 0.0.0.0
+";
+
+    const DOMAIN_LIST: &str = "\
+# Comment
+
+Malware.Example.org.
+  phishing.example
+";
+
+    fn query(id: u16, name: &str) -> Vec<u8> {
+        let mut query = id.to_be_bytes().to_vec();
+        // RD, one question
+        query.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+        for label in name.split('.') {
+            query.push(label.len() as u8);
+            query.extend_from_slice(label.as_bytes());
+        }
+        // Root label, QTYPE A, QCLASS IN
+        query.extend_from_slice(&[0, 0, 1, 0, 1]);
+        query
+    }
+
+    fn response_to(query: &[u8]) -> Vec<u8> {
+        let mut response = query.to_vec();
+        response[2] |= 0x80;
+        response[3] = 0x80;
+        response
+    }
+
+    fn blocklists(contents: &[(&str, bool)]) -> Blocklists {
+        Arc::new(RwLock::new(
+            contents
+                .iter()
+                .map(|(contents, enabled)| LoadedBlocklist {
+                    path: PathBuf::new(),
+                    enabled: *enabled,
+                    domains: parse_blocklist(contents),
+                    hits: AtomicU64::new(0),
+                    error: None,
+                })
+                .collect(),
+        ))
+    }
+
+    fn filter(contents: &[(&str, bool)]) -> DnsFilter {
+        DnsFilter {
+            config: Config {
+                blocklists: blocklists(contents),
+                ..Config::default()
+            },
+            server: Arc::default(),
+        }
+    }
+
+    /// Starts a fake upstream resolver on the loopback address that answers every UDP query by
+    /// echoing it back as a response.
+    fn spawn_udp_upstream() -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = socket.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buffer = [0u8; 512];
+            while let Ok((len, source)) = socket.recv_from(&mut buffer) {
+                let _ = socket.send_to(&response_to(&buffer[..len]), source);
+            }
+        });
+        address
+    }
+
+    #[test]
+    fn test_parse_hosts_file() {
+        let domains = parse_blocklist(HOSTS_FILE);
+        let expected: HashSet<String> = ["ads.example.com", "tracker.example.net"]
+            .iter()
+            .map(|name| name.to_string())
+            .collect();
+        assert_eq!(domains, expected);
+    }
+
+    #[test]
+    fn test_parse_domain_list() {
+        let domains = parse_blocklist(DOMAIN_LIST);
+        let expected: HashSet<String> = ["malware.example.org", "phishing.example"]
+            .iter()
+            .map(|name| name.to_string())
+            .collect();
+        assert_eq!(domains, expected);
+    }
+
+    #[test]
+    fn test_is_blocked() {
+        let lists = blocklists(&[(HOSTS_FILE, true), (DOMAIN_LIST, false)]);
+        assert!(is_blocked(&lists, "ads.example.com"));
+        assert!(is_blocked(&lists, "ADS.example.com."));
+        assert!(is_blocked(&lists, "cdn.ads.example.com"));
+        assert!(!is_blocked(&lists, "example.com"));
+        assert!(!is_blocked(&lists, "bads.example.com"));
+        // Disabled list
+        assert!(!is_blocked(&lists, "malware.example.org"));
+
+        let lists = lists.read().unwrap();
+        assert_eq!(lists[0].hits.load(Ordering::Relaxed), 3);
+        assert_eq!(lists[1].hits.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn test_parse_question() {
+        let query = query(1, "www.example.com");
+        assert_eq!(
+            parse_question(&query),
+            Some(("www.example.com".to_owned(), query.len()))
+        );
+        assert_eq!(parse_question(&response_to(&query)), None);
+        assert_eq!(parse_question(&query[..query.len() - 1]), None);
+    }
+
+    #[test]
+    fn test_blocked_response() {
+        let query = query(0x1234, "ads.example.com");
+        let response = blocked_response(&query, query.len());
+        assert_eq!(&response[0..2], &[0x12, 0x34]);
+        // QR and RD set
+        assert_eq!(response[2], 0x81);
+        // RA set, NXDOMAIN
+        assert_eq!(response[3], 0x83);
+        assert_eq!(&response[4..HEADER_LEN], &[0, 1, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(&response[HEADER_LEN..], &query[HEADER_LEN..]);
+    }
+
+    fn split_dns_route(domain: &str, resolver: SocketAddr) -> SplitDnsRoute {
+        SplitDnsRoute {
+            rule: SplitDnsRule::new(domain, vec![resolver.ip()]).unwrap(),
+            resolvers: vec![resolver],
+        }
+    }
+
+    #[test]
+    fn test_route_query() {
+        let corp: SocketAddr = "192.168.1.53:53".parse().unwrap();
+        let lab: SocketAddr = "10.1.0.53:53".parse().unwrap();
+        let filter = filter(&[(HOSTS_FILE, true)]);
+        *filter.config.split_dns.write().unwrap() = vec![
+            split_dns_route("corp.example", corp),
+            split_dns_route("lab.corp.example", lab),
+            split_dns_route("ads.example.com", corp),
+        ];
+        let config = &filter.config;
+
+        assert_eq!(
+            route_query(config, &query(1, "www.corp.example")),
+            QueryAction::Forward(vec![corp])
+        );
+        assert_eq!(
+            route_query(config, &query(2, "host.lab.corp.example")),
+            QueryAction::Forward(vec![lab])
+        );
+        assert_eq!(
+            route_query(config, &query(3, "www.example.com")),
+            QueryAction::Default
+        );
+        // Blocklists take precedence
+        let blocked = query(4, "ads.example.com");
+        assert_eq!(
+            route_query(config, &blocked),
+            QueryAction::Respond(blocked_response(&blocked, blocked.len()))
+        );
+    }
+
+    #[test]
+    fn test_pending_queries_are_bounded() {
+        let query = |sent| PendingQuery {
+            client: "127.0.0.1:5353".parse().unwrap(),
+            id: [0x12, 0x34],
+            sent,
+            servers: vec![],
+        };
+        let mut pending = HashMap::new();
+        for _ in 0..MAX_PENDING_QUERIES {
+            assert!(add_pending_query(&mut pending, query(Instant::now())).is_some());
+        }
+        assert_eq!(pending.len(), MAX_PENDING_QUERIES);
+        assert_eq!(add_pending_query(&mut pending, query(Instant::now())), None);
+
+        // Timed out queries make room for new ones
+        for pending_query in pending.values_mut().take(1) {
+            pending_query.sent -= UPSTREAM_TIMEOUT;
+        }
+        assert!(add_pending_query(&mut pending, query(Instant::now())).is_some());
+        assert_eq!(pending.len(), MAX_PENDING_QUERIES);
+    }
+
+    #[test]
+    fn test_udp_split_dns() {
+        let resolver = spawn_udp_upstream();
+        let filter = filter(&[]);
+        *filter.config.split_dns.write().unwrap() = vec![split_dns_route("corp.example", resolver)];
+
+        // There is no default upstream resolver, so only split DNS queries can be answered
+        let address = filter
+            .start_on("127.0.0.1:0".parse().unwrap(), vec![])
+            .unwrap();
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut buffer = [0u8; 512];
+
+        let split = query(0x3333, "intranet.corp.example");
+        client.send_to(&split, address).unwrap();
+        let (len, _) = client.recv_from(&mut buffer).unwrap();
+        assert_eq!(&buffer[..len], &response_to(&split)[..]);
+        filter.stop();
+    }
+
+    #[test]
+    fn test_udp_forward_and_block() {
+        let upstream = spawn_udp_upstream();
+
+        let filter = filter(&[(HOSTS_FILE, true)]);
+        let address = filter
+            .start_on("127.0.0.1:0".parse().unwrap(), vec![upstream])
+            .unwrap();
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut buffer = [0u8; 512];
+
+        let allowed = query(0xabcd, "www.example.com");
+        client.send_to(&allowed, address).unwrap();
+        let (len, _) = client.recv_from(&mut buffer).unwrap();
+        assert_eq!(&buffer[..len], &response_to(&allowed)[..]);
+
+        let blocked = query(0xbcde, "ads.example.com");
+        client.send_to(&blocked, address).unwrap();
+        let (len, _) = client.recv_from(&mut buffer).unwrap();
+        assert_eq!(
+            &buffer[..len],
+            &blocked_response(&blocked, blocked.len())[..]
+        );
+
+        assert_eq!(filter.stats()[0].hits, 1);
+        filter.stop();
+    }
+
+    #[test]
+    fn test_tcp_forward() {
+        let upstream = TcpListener::bind("127.0.0.1:0").unwrap();
+        let upstream_address = upstream.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = upstream.accept().unwrap();
+            let query = read_message(&mut stream).unwrap();
+            write_message(&mut stream, &response_to(&query)).unwrap();
+        });
+
+        let filter = filter(&[(HOSTS_FILE, true)]);
+        let address = filter
+            .start_on("127.0.0.1:0".parse().unwrap(), vec![upstream_address])
+            .unwrap();
+
+        let mut client = TcpStream::connect(address).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+
+        let allowed = query(0x0102, "www.example.com");
+        write_message(&mut client, &allowed).unwrap();
+        assert_eq!(read_message(&mut client).unwrap(), response_to(&allowed));
+
+        let blocked = query(0x0304, "tracker.example.net");
+        write_message(&mut client, &blocked).unwrap();
+        assert_eq!(
+            read_message(&mut client).unwrap(),
+            blocked_response(&blocked, blocked.len())
+        );
+        filter.stop();
+    }
+
+    #[test]
+    fn test_udp_encrypted() {
+        use super::super::encrypted::test::{spawn_server, test_roots};
+        use talpid_types::net::dns::EncryptedDnsProtocol;
+
+        let (server, _) = spawn_server(EncryptedDnsProtocol::Tls);
+        let filter = filter(&[(HOSTS_FILE, true)]);
+        filter.set_encrypted_servers_with_roots(vec![server], test_roots());
+        assert!(filter.is_encrypted());
+
+        // The plain upstream resolver should not be used
+        let address = filter
+            .start_on("127.0.0.1:0".parse().unwrap(), vec![])
+            .unwrap();
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut buffer = [0u8; 512];
+
+        let allowed = query(0x1111, "www.example.com");
+        client.send_to(&allowed, address).unwrap();
+        let (len, _) = client.recv_from(&mut buffer).unwrap();
+        assert_eq!(&buffer[..len], &response_to(&allowed)[..]);
+
+        let blocked = query(0x2222, "ads.example.com");
+        client.send_to(&blocked, address).unwrap();
+        let (len, _) = client.recv_from(&mut buffer).unwrap();
+        assert_eq!(
+            &buffer[..len],
+            &blocked_response(&blocked, blocked.len())[..]
+        );
+        filter.stop();
+    }
+}
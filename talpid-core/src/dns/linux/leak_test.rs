@@ -0,0 +1,625 @@
+//! Checks that DNS queries made while connected are answered by the expected resolvers.
+//!
+//! The test inspects how DNS is configured on the system, queries each configured resolver
+//! directly, and then queries the same domain through the system resolver. The test domain should
+//! resolve to an address that identifies the resolver that looked it up, such as the default
+//! [`DEFAULT_TEST_DOMAIN`]. If the system resolver returns an address that none of the configured
+//! resolvers returned, the query was answered by some other resolver.
+
+use super::{DnsBackend, RESOLV_CONF_PATH};
+use crate::linux::iface_index;
+use std::{
+    fmt, fs, io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    time::{Duration, Instant},
+};
+use talpid_dbus::systemd_resolved::SystemdResolved;
+use talpid_types::ErrorExt;
+
+/// Domain that resolves to the address of the resolver that looks it up.
+pub const DEFAULT_TEST_DOMAIN: &str = "whoami.akamai.net";
+
+/// Addresses of the systemd-resolved stub resolvers.
+const RESOLVED_STUB_ADDRESSES: [IpAddr; 2] = [
+    IpAddr::V4(Ipv4Addr::new(127, 0, 0, 53)),
+    IpAddr::V4(Ipv4Addr::new(127, 0, 0, 54)),
+];
+
+/// Addresses that a local resolver such as dnsmasq usually listens on. resolvconf lists them when
+/// it passes the servers on to a local resolver.
+const LOCAL_RESOLVER_ADDRESSES: [IpAddr; 2] = [
+    IpAddr::V4(Ipv4Addr::LOCALHOST),
+    IpAddr::V6(Ipv6Addr::LOCALHOST),
+];
+
+const DNS_PORT: u16 = 53;
+const QUERY_TIMEOUT: Duration = Duration::from_secs(3);
+const HEADER_LEN: usize = 12;
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+
+/// The DNS configuration applied by the daemon while connected.
+#[derive(Debug, Clone)]
+pub struct DnsConfig {
+    /// The DNS manager that the servers were set through.
+    pub backend: DnsBackend,
+    /// Name of the tunnel interface.
+    pub interface: String,
+    /// Servers that the system resolver was pointed at.
+    pub system_servers: Vec<IpAddr>,
+    /// Servers that plain queries are eventually sent to. These are the same as `system_servers`
+    /// unless the local resolver is used.
+    pub upstream_servers: Vec<IpAddr>,
+    /// Whether the local resolver sends queries to DNS-over-HTTPS or DNS-over-TLS servers.
+    pub encrypted: bool,
+}
+
+/// Result of a single check.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LeakTestCheck {
+    pub name: String,
+    pub passed: bool,
+    pub details: String,
+}
+
+impl LeakTestCheck {
+    fn pass(name: impl Into<String>, details: impl Into<String>) -> Self {
+        LeakTestCheck {
+            name: name.into(),
+            passed: true,
+            details: details.into(),
+        }
+    }
+
+    fn fail(name: impl Into<String>, details: impl Into<String>) -> Self {
+        LeakTestCheck {
+            name: name.into(),
+            passed: false,
+            details: details.into(),
+        }
+    }
+}
+
+/// Results of all checks of a DNS leak test.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LeakTestReport {
+    pub test_domain: String,
+    pub checks: Vec<LeakTestCheck>,
+}
+
+impl LeakTestReport {
+    /// Returns whether all checks passed.
+    pub fn passed(&self) -> bool {
+        self.checks.iter().all(|check| check.passed)
+    }
+}
+
+/// Runs all checks against the applied configuration. This blocks while waiting for responses
+/// from the resolvers.
+pub fn run(config: &DnsConfig, test_domain: &str) -> LeakTestReport {
+    let mut checks = vec![LeakTestCheck::pass(
+        "DNS backend",
+        format!(
+            "DNS is managed via {} on {}",
+            config.backend, config.interface
+        ),
+    )];
+
+    let resolv_conf = fs::read_to_string(RESOLV_CONF_PATH);
+    let system_resolver = match &resolv_conf {
+        Ok(contents) => {
+            let (check, nameservers) = check_resolv_conf(config, contents);
+            checks.push(check);
+            nameservers.first().copied()
+        }
+        Err(error) => {
+            checks.push(LeakTestCheck::fail(
+                RESOLV_CONF_PATH,
+                format!("Failed to read the file: {}", error),
+            ));
+            None
+        }
+    };
+
+    if config.backend == DnsBackend::SystemdResolved {
+        checks.push(check_systemd_resolved(config));
+    }
+
+    let mut expected_answers = vec![];
+    if config.encrypted {
+        checks.push(LeakTestCheck::pass(
+            "Resolvers",
+            "Queries are sent to encrypted DNS servers, which cannot be queried directly",
+        ));
+    } else {
+        for server in &config.upstream_servers {
+            let (check, answers) = check_resolver(SocketAddr::new(*server, DNS_PORT), test_domain);
+            checks.push(check);
+            expected_answers.extend(answers);
+        }
+    }
+
+    checks.push(match system_resolver {
+        Some(resolver) => check_system_resolver(
+            SocketAddr::new(resolver, DNS_PORT),
+            (!config.encrypted).then(|| &expected_answers[..]),
+            test_domain,
+        ),
+        None => LeakTestCheck::fail("System resolver", "No system resolver is configured"),
+    });
+
+    LeakTestReport {
+        test_domain: test_domain.to_owned(),
+        checks,
+    }
+}
+
+/// Checks that the system resolver prefers the servers that were set, without sending any
+/// queries. Unlike [`run`], other servers may be listed after them in `resolv.conf`. Returns why
+/// the servers are not used if the check fails.
+pub fn check_applied(config: &DnsConfig) -> Result<(), String> {
+    let contents = fs::read_to_string(RESOLV_CONF_PATH)
+        .map_err(|error| format!("Failed to read {}: {}", RESOLV_CONF_PATH, error))?;
+    check_preferred_nameserver(config, &contents)?;
+
+    if config.backend == DnsBackend::SystemdResolved {
+        let check = check_systemd_resolved(config);
+        if !check.passed {
+            return Err(check.details);
+        }
+    }
+    Ok(())
+}
+
+/// Checks that the first nameserver in `resolv.conf` is one of the servers that were set, or the
+/// local resolver that they were set through.
+fn check_preferred_nameserver(config: &DnsConfig, contents: &str) -> Result<(), String> {
+    let config_file = resolv_conf::Config::parse(contents)
+        .map_err(|error| format!("Failed to parse {}: {}", RESOLV_CONF_PATH, error))?;
+    match config_file.nameservers.into_iter().next() {
+        Some(nameserver) => {
+            let nameserver: IpAddr = nameserver.into();
+            if is_expected_nameserver(config, &nameserver) {
+                Ok(())
+            } else {
+                Err(format!(
+                    "{} lists {} before {}",
+                    RESOLV_CONF_PATH,
+                    nameserver,
+                    AddressList(&config.system_servers)
+                ))
+            }
+        }
+        None => Err(format!("{} lists no nameservers", RESOLV_CONF_PATH)),
+    }
+}
+
+/// Checks that `resolv.conf` only lists the servers that were set, or the local resolver that they
+/// were passed on to. Returns the listed nameservers.
+fn check_resolv_conf(config: &DnsConfig, contents: &str) -> (LeakTestCheck, Vec<IpAddr>) {
+    let nameservers: Vec<IpAddr> = match resolv_conf::Config::parse(contents) {
+        Ok(parsed) => parsed
+            .nameservers
+            .into_iter()
+            .map(|nameserver| nameserver.into())
+            .collect(),
+        Err(error) => {
+            return (
+                LeakTestCheck::fail(
+                    RESOLV_CONF_PATH,
+                    format!("Failed to parse the file: {}", error),
+                ),
+                vec![],
+            )
+        }
+    };
+
+    let unexpected: Vec<&IpAddr> = nameservers
+        .iter()
+        .filter(|nameserver| !is_expected_nameserver(config, nameserver))
+        .collect();
+
+    let check = if nameservers.is_empty() {
+        LeakTestCheck::fail(RESOLV_CONF_PATH, "No nameservers are listed")
+    } else if unexpected.is_empty() {
+        LeakTestCheck::pass(
+            RESOLV_CONF_PATH,
+            format!("Nameservers: {}", AddressList(&nameservers)),
+        )
+    } else {
+        LeakTestCheck::fail(
+            RESOLV_CONF_PATH,
+            format!(
+                "Unexpected nameservers: {}",
+                AddressList(&unexpected.into_iter().copied().collect::<Vec<_>>())
+            ),
+        )
+    };
+    (check, nameservers)
+}
+
+fn is_expected_nameserver(config: &DnsConfig, nameserver: &IpAddr) -> bool {
+    config.system_servers.contains(nameserver)
+        || (config.backend == DnsBackend::SystemdResolved
+            && RESOLVED_STUB_ADDRESSES.contains(nameserver))
+        || (matches!(config.backend, DnsBackend::Connman | DnsBackend::Dnsmasq)
+            && nameserver.is_loopback())
+        || (config.backend == DnsBackend::Resolvconf
+            && LOCAL_RESOLVER_ADDRESSES.contains(nameserver))
+}
+
+/// Checks that the tunnel link uses the servers that were set, and that it is the default route
+/// for all domains.
+fn check_systemd_resolved(config: &DnsConfig) -> LeakTestCheck {
+    const NAME: &str = "systemd-resolved";
+
+    let result = (|| {
+        let index = iface_index(&config.interface).map_err(|error| error.display_chain())?;
+        let resolved = SystemdResolved::new().map_err(|error| error.display_chain())?;
+        let servers = resolved
+            .get_dns(index)
+            .map_err(|error| error.display_chain())?
+            .set_servers;
+        let domains = resolved
+            .get_domains(index)
+            .map_err(|error| error.display_chain())?;
+        Ok::<_, String>((servers, domains))
+    })();
+
+    match result {
+        Ok((servers, domains)) => {
+            if servers != config.system_servers {
+                LeakTestCheck::fail(
+                    NAME,
+                    format!(
+                        "{} uses {} instead of {}",
+                        config.interface,
+                        AddressList(&servers),
+                        AddressList(&config.system_servers)
+                    ),
+                )
+            } else if !domains.contains(&(".".to_owned(), true)) {
+                LeakTestCheck::fail(
+                    NAME,
+                    format!("{} is not the default route for DNS", config.interface),
+                )
+            } else {
+                LeakTestCheck::pass(
+                    NAME,
+                    format!(
+                        "{} uses {} for all domains",
+                        config.interface,
+                        AddressList(&servers)
+                    ),
+                )
+            }
+        }
+        Err(error) => {
+            LeakTestCheck::fail(NAME, format!("Failed to read the link settings: {}", error))
+        }
+    }
+}
+
+/// Queries `server` directly, and checks that the response comes from it. Returns the addresses
+/// in the response.
+fn check_resolver(server: SocketAddr, domain: &str) -> (LeakTestCheck, Vec<IpAddr>) {
+    let name = format!("Resolver {}", server.ip());
+    match query(server, domain) {
+        Ok(answers) => {
+            let check =
+                LeakTestCheck::pass(name, format!("Answered with {}", AddressList(&answers)));
+            (check, answers)
+        }
+        Err(error) => (LeakTestCheck::fail(name, error.to_string()), vec![]),
+    }
+}
+
+/// Queries `resolver` and checks that the addresses in the response are among
+/// `expected_answers`, if given.
+fn check_system_resolver(
+    resolver: SocketAddr,
+    expected_answers: Option<&[IpAddr]>,
+    domain: &str,
+) -> LeakTestCheck {
+    const NAME: &str = "System resolver";
+
+    let answers = match query(resolver, domain) {
+        Ok(answers) => answers,
+        Err(error) => return LeakTestCheck::fail(NAME, error.to_string()),
+    };
+    match expected_answers {
+        Some([]) => LeakTestCheck::fail(
+            NAME,
+            format!(
+                "Answered with {}, but no configured resolver answered",
+                AddressList(&answers)
+            ),
+        ),
+        Some(expected) if answers.is_empty() || answers.iter().any(|a| !expected.contains(a)) => {
+            LeakTestCheck::fail(
+                NAME,
+                format!(
+                    "Answered with {}, while the configured resolvers answered with {}. The \
+                     query was likely answered by another resolver",
+                    AddressList(&answers),
+                    AddressList(expected)
+                ),
+            )
+        }
+        _ => LeakTestCheck::pass(
+            NAME,
+            format!("{} answered with {}", resolver.ip(), AddressList(&answers)),
+        ),
+    }
+}
+
+#[derive(err_derive::Error, Debug, PartialEq)]
+enum QueryError {
+    #[error(display = "Failed to send the query: {}", _0)]
+    Send(String),
+    #[error(display = "No response")]
+    Timeout,
+    #[error(display = "Response from unexpected address {}", _0)]
+    UnexpectedSource(SocketAddr),
+    #[error(display = "Invalid response")]
+    InvalidResponse,
+    #[error(display = "Response code {}", _0)]
+    ResponseCode(u8),
+}
+
+/// Sends an `A` query for `domain` to `server` over UDP and returns the addresses in the answer.
+fn query(server: SocketAddr, domain: &str) -> Result<Vec<IpAddr>, QueryError> {
+    let bind_address: IpAddr = match server {
+        SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+    let send_error = |error: io::Error| QueryError::Send(error.to_string());
+    let socket = UdpSocket::bind(SocketAddr::new(bind_address, 0)).map_err(send_error)?;
+    let id = rand::random::<u16>();
+    socket
+        .send_to(&build_query(id, domain), server)
+        .map_err(send_error)?;
+
+    let deadline = Instant::now() + QUERY_TIMEOUT;
+    let mut buffer = [0u8; 4096];
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(QueryError::Timeout);
+        }
+        socket
+            .set_read_timeout(Some(remaining))
+            .map_err(send_error)?;
+        let (len, source) = match socket.recv_from(&mut buffer) {
+            Ok(received) => received,
+            Err(error)
+                if error.kind() == io::ErrorKind::WouldBlock
+                    || error.kind() == io::ErrorKind::TimedOut =>
+            {
+                return Err(QueryError::Timeout)
+            }
+            Err(error) => return Err(send_error(error)),
+        };
+        let response = &buffer[..len];
+        if response.len() < 2 || u16::from_be_bytes([response[0], response[1]]) != id {
+            continue;
+        }
+        if source != server {
+            return Err(QueryError::UnexpectedSource(source));
+        }
+        return parse_answers(response);
+    }
+}
+
+fn build_query(id: u16, domain: &str) -> Vec<u8> {
+    let mut query = Vec::with_capacity(HEADER_LEN + domain.len() + 6);
+    query.extend_from_slice(&id.to_be_bytes());
+    // Standard query with recursion desired, and a single question
+    query.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in domain.trim_end_matches('.').split('.') {
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&TYPE_A.to_be_bytes());
+    // Class IN
+    query.extend_from_slice(&[0, 1]);
+    query
+}
+
+/// Returns the `A` and `AAAA` addresses in the answer section of a response.
+fn parse_answers(response: &[u8]) -> Result<Vec<IpAddr>, QueryError> {
+    let header = response
+        .get(..HEADER_LEN)
+        .ok_or(QueryError::InvalidResponse)?;
+    if header[2] & 0x80 == 0 {
+        return Err(QueryError::InvalidResponse);
+    }
+    let rcode = header[3] & 0x0f;
+    if rcode != 0 {
+        return Err(QueryError::ResponseCode(rcode));
+    }
+    let question_count = u16::from_be_bytes([header[4], header[5]]);
+    let answer_count = u16::from_be_bytes([header[6], header[7]]);
+
+    let mut offset = HEADER_LEN;
+    for _ in 0..question_count {
+        // Name, type and class
+        offset = skip_name(response, offset).ok_or(QueryError::InvalidResponse)? + 4;
+    }
+
+    let mut addresses = vec![];
+    for _ in 0..answer_count {
+        offset = skip_name(response, offset).ok_or(QueryError::InvalidResponse)?;
+        let record = response
+            .get(offset..offset + 10)
+            .ok_or(QueryError::InvalidResponse)?;
+        let record_type = u16::from_be_bytes([record[0], record[1]]);
+        let data_len = usize::from(u16::from_be_bytes([record[8], record[9]]));
+        offset += 10;
+        let data = response
+            .get(offset..offset + data_len)
+            .ok_or(QueryError::InvalidResponse)?;
+        match (record_type, data_len) {
+            (TYPE_A, 4) => addresses.push(IpAddr::from(<[u8; 4]>::try_from(data).unwrap())),
+            (TYPE_AAAA, 16) => addresses.push(IpAddr::from(<[u8; 16]>::try_from(data).unwrap())),
+            _ => (),
+        }
+        offset += data_len;
+    }
+    Ok(addresses)
+}
+
+/// Returns the offset after the name that starts at `offset`.
+fn skip_name(message: &[u8], mut offset: usize) -> Option<usize> {
+    loop {
+        let len = *message.get(offset)?;
+        if len == 0 {
+            return Some(offset + 1);
+        }
+        // A compression pointer ends the name
+        if len & 0xc0 == 0xc0 {
+            return Some(offset + 2);
+        }
+        offset += 1 + usize::from(len);
+    }
+}
+
+struct AddressList<'a>(&'a [IpAddr]);
+
+impl fmt::Display for AddressList<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0.is_empty() {
+            return f.write_str("no addresses");
+        }
+        let addresses: Vec<String> = self.0.iter().map(|address| address.to_string()).collect();
+        f.write_str(&addresses.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::thread;
+
+    fn config(backend: DnsBackend, system_servers: Vec<IpAddr>) -> DnsConfig {
+        DnsConfig {
+            backend,
+            interface: "wg-mullvad".to_owned(),
+            upstream_servers: system_servers.clone(),
+            system_servers,
+            encrypted: false,
+        }
+    }
+
+    /// Answers a single query with `answer`. The response is sent from `reply_socket` if given,
+    /// which lets the test simulate a resolver that is not the one the query was sent to.
+    fn spawn_stand_in(answer: Ipv4Addr, reply_socket: Option<UdpSocket>) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = socket.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buffer = [0u8; 512];
+            let (len, client) = socket.recv_from(&mut buffer).unwrap();
+            let query = &buffer[..len];
+            let mut response = query[..2].to_vec();
+            response.extend_from_slice(&[0x81, 0x80, 0, 1, 0, 1, 0, 0, 0, 0]);
+            response.extend_from_slice(&query[HEADER_LEN..]);
+            // Pointer to the name in the question, type A, class IN, TTL and address
+            response.extend_from_slice(&[0xc0, 0x0c, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4]);
+            response.extend_from_slice(&answer.octets());
+            reply_socket
+                .as_ref()
+                .unwrap_or(&socket)
+                .send_to(&response, client)
+                .unwrap();
+        });
+        address
+    }
+
+    #[test]
+    fn test_query_stand_in() {
+        let answer = Ipv4Addr::new(192, 0, 2, 1);
+        let resolver = spawn_stand_in(answer, None);
+        assert_eq!(
+            query(resolver, DEFAULT_TEST_DOMAIN),
+            Ok(vec![IpAddr::V4(answer)])
+        );
+
+        let other_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let other_address = other_socket.local_addr().unwrap();
+        let resolver = spawn_stand_in(answer, Some(other_socket));
+        assert_eq!(
+            query(resolver, DEFAULT_TEST_DOMAIN),
+            Err(QueryError::UnexpectedSource(other_address))
+        );
+    }
+
+    #[test]
+    fn test_system_resolver_check() {
+        let tunnel_answer = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let isp_answer = Ipv4Addr::new(198, 51, 100, 1);
+
+        let resolver = spawn_stand_in(Ipv4Addr::new(192, 0, 2, 1), None);
+        let check = check_system_resolver(resolver, Some(&[tunnel_answer]), "test.example");
+        assert!(check.passed, "{}", check.details);
+
+        let resolver = spawn_stand_in(isp_answer, None);
+        let check = check_system_resolver(resolver, Some(&[tunnel_answer]), "test.example");
+        assert!(!check.passed);
+
+        let resolver = spawn_stand_in(isp_answer, None);
+        let check = check_system_resolver(resolver, None, "test.example");
+        assert!(check.passed, "{}", check.details);
+    }
+
+    #[test]
+    fn test_resolv_conf_check() {
+        let tunnel_dns = IpAddr::V4(Ipv4Addr::new(10, 64, 0, 1));
+        let contents = "search lan\nnameserver 10.64.0.1\n";
+
+        let (check, nameservers) =
+            check_resolv_conf(&config(DnsBackend::Resolvconf, vec![tunnel_dns]), contents);
+        assert!(check.passed);
+        assert_eq!(nameservers, vec![tunnel_dns]);
+
+        let contents = "nameserver 10.64.0.1\nnameserver 192.168.1.1\n";
+        let (check, _) =
+            check_resolv_conf(&config(DnsBackend::Resolvconf, vec![tunnel_dns]), contents);
+        assert!(!check.passed);
+
+        let contents = "nameserver 127.0.0.53\n";
+        let (check, _) =
+            check_resolv_conf(&config(DnsBackend::Resolvconf, vec![tunnel_dns]), contents);
+        assert!(!check.passed);
+        let (check, _) = check_resolv_conf(
+            &config(DnsBackend::SystemdResolved, vec![tunnel_dns]),
+            contents,
+        );
+        assert!(check.passed);
+
+        let contents = "nameserver 127.0.0.1\n";
+        let (check, _) =
+            check_resolv_conf(&config(DnsBackend::Dnsmasq, vec![tunnel_dns]), contents);
+        assert!(check.passed);
+
+        let (check, _) = check_resolv_conf(&config(DnsBackend::Resolvconf, vec![tunnel_dns]), "");
+        assert!(!check.passed);
+    }
+
+    #[test]
+    fn test_preferred_nameserver_check() {
+        let tunnel_dns = IpAddr::V4(Ipv4Addr::new(10, 64, 0, 1));
+        let config = config(DnsBackend::Resolvconf, vec![tunnel_dns]);
+
+        let contents = "nameserver 10.64.0.1\nnameserver 192.168.1.1\n";
+        assert!(check_preferred_nameserver(&config, contents).is_ok());
+
+        let contents = "nameserver 192.168.1.1\nnameserver 10.64.0.1\n";
+        assert!(check_preferred_nameserver(&config, contents).is_err());
+
+        assert!(check_preferred_nameserver(&config, "search lan\n").is_err());
+
+        // resolvconf may pass the servers on to dnsmasq
+        assert!(check_preferred_nameserver(&config, "nameserver 127.0.0.1\n").is_ok());
+    }
+}
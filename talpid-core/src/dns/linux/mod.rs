@@ -1,17 +1,49 @@
+mod connman;
+mod dnsmasq;
+mod encrypted;
+pub mod filter;
+pub mod leak_test;
+mod netconfig;
 mod network_manager;
 mod resolvconf;
 mod static_resolv_conf;
 pub(self) mod systemd_resolved;
 
 use self::{
-    network_manager::NetworkManager, resolvconf::Resolvconf, static_resolv_conf::StaticResolvConf,
+    connman::Connman, dnsmasq::Dnsmasq, netconfig::Netconfig, network_manager::NetworkManager,
+    resolvconf::Resolvconf, static_resolv_conf::StaticResolvConf,
     systemd_resolved::SystemdResolved,
 };
 use crate::routing::RouteManagerHandle;
-use std::{env, fmt, net::IpAddr};
+use futures::channel::mpsc;
+use std::{
+    env, fmt,
+    net::IpAddr,
+    thread,
+    time::{Duration, Instant},
+};
+use talpid_types::{net::dns::SplitDnsRule, ErrorExt};
 
 const RESOLV_CONF_PATH: &str = "/etc/resolv.conf";
 
+/// How long to wait for a DNS manager to apply the DNS settings after accepting them.
+const VERIFY_TIMEOUT: Duration = Duration::from_secs(1);
+/// How long to spend verifying DNS managers in total, including any fallbacks. DNS is set on the
+/// tunnel state machine thread, so no more DNS managers are tried once this has passed.
+const MAX_VERIFY_DURATION: Duration = Duration::from_secs(3);
+const VERIFY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The order in which DNS managers are tried when none is selected using `TALPID_DNS_MODULE`.
+const DETECTION_ORDER: [DnsBackend; 7] = [
+    DnsBackend::SystemdResolved,
+    DnsBackend::NetworkManager,
+    DnsBackend::Connman,
+    DnsBackend::Netconfig,
+    DnsBackend::Resolvconf,
+    DnsBackend::Dnsmasq,
+    DnsBackend::StaticResolvConf,
+];
+
 pub type Result<T> = std::result::Result<T, Error>;
 
 /// Errors that can happen in the Linux DNS monitor
@@ -29,6 +61,18 @@ pub enum Error {
     #[error(display = "Error in resolvconf DNS monitor")]
     Resolvconf(#[error(source)] resolvconf::Error),
 
+    /// Error in ConnMan DNS monitor
+    #[error(display = "Error in ConnMan DNS monitor")]
+    Connman(#[error(source)] connman::Error),
+
+    /// Error in netconfig DNS monitor
+    #[error(display = "Error in netconfig DNS monitor")]
+    Netconfig(#[error(source)] netconfig::Error),
+
+    /// Error in dnsmasq DNS monitor
+    #[error(display = "Error in dnsmasq DNS monitor")]
+    Dnsmasq(#[error(source)] dnsmasq::Error),
+
     /// Error in static /etc/resolv.conf DNS monitor
     #[error(display = "Error in static /etc/resolv.conf DNS monitor")]
     StaticResolvConf(#[error(source)] static_resolv_conf::Error),
@@ -38,30 +82,215 @@ pub enum Error {
     NoDnsMonitor,
 }
 
+/// Warning about a DNS manager accepting the DNS settings without applying them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DnsWarning {
+    /// The DNS manager that ignored the settings.
+    pub ignored_by: DnsBackend,
+    /// The DNS manager that was used instead, if any other one applied the settings.
+    pub fallback: Option<DnsBackend>,
+    /// Why the settings are considered to be ignored.
+    pub details: String,
+}
+
+impl fmt::Display for DnsWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} did not apply the DNS settings", self.ignored_by)?;
+        match self.fallback {
+            Some(fallback) => write!(f, ", using {} instead", fallback)?,
+            None => write!(f, " and no other DNS manager did either")?,
+        }
+        write!(f, ": {}", self.details)
+    }
+}
+
 pub struct DnsMonitor {
     route_manager: RouteManagerHandle,
     handle: tokio::runtime::Handle,
+    warning_listener: mpsc::UnboundedSender<DnsWarning>,
     inner: Option<DnsMonitorHolder>,
+    split_dns: Vec<SplitDnsRule>,
+    interface: String,
+    servers: Vec<IpAddr>,
+}
+
+impl DnsMonitor {
+    /// Sets the split DNS rules to apply the next time DNS is set. They are only applied when
+    /// DNS is managed by systemd-resolved.
+    pub fn set_split_dns(&mut self, rules: Vec<SplitDnsRule>) {
+        self.split_dns = rules;
+    }
+
+    /// Returns whether the DNS manager that would be used can send queries for split DNS
+    /// domains to other resolvers by itself.
+    pub fn supports_split_dns(&self) -> bool {
+        matches!(
+            DnsMonitorHolder::new(),
+            Ok(DnsMonitorHolder::SystemdResolved(_))
+        )
+    }
+
+    /// Returns the DNS manager, interface and servers that DNS is currently set through, if it
+    /// is set.
+    pub fn current(&self) -> Option<(DnsBackend, &str, &[IpAddr])> {
+        self.inner
+            .as_ref()
+            .map(|inner| (inner.backend(), self.interface.as_str(), &self.servers[..]))
+    }
+
+    /// Sets DNS through the detected DNS manager, and checks that the system actually uses the
+    /// servers. If it does not, the other usable DNS managers are tried in detection order, and
+    /// a warning is sent to the warning listener. Fallbacks are only tried until
+    /// `MAX_VERIFY_DURATION` has passed.
+    fn set_verified(&mut self, interface: &str, servers: &[IpAddr]) -> Result<DnsMonitorHolder> {
+        let deadline = Instant::now() + MAX_VERIFY_DURATION;
+        let mut inner = DnsMonitorHolder::new()?;
+        let details = match self.set_and_verify(&mut inner, interface, servers, deadline)? {
+            Ok(()) => return Ok(inner),
+            Err(details) => details,
+        };
+        let ignored_by = inner.backend();
+        log::warn!(
+            "{} accepted the DNS settings but did not apply them: {}",
+            ignored_by,
+            details
+        );
+
+        if DnsMonitorHolder::selected_backend().is_some() {
+            self.warn(ignored_by, None, details);
+            return Ok(inner);
+        }
+        self.reset_holder(&mut inner);
+
+        let mut skipped = vec![ignored_by];
+        while Instant::now() < deadline {
+            let mut fallback = match DnsMonitorHolder::with_detected_dns_manager(&skipped) {
+                Ok(fallback) => fallback,
+                Err(_) => break,
+            };
+            let backend = fallback.backend();
+            log::debug!("Falling back to managing DNS via {}", backend);
+            match self.set_and_verify(&mut fallback, interface, servers, deadline) {
+                Ok(Ok(())) => {
+                    self.warn(ignored_by, Some(backend), details);
+                    return Ok(fallback);
+                }
+                Ok(Err(fallback_details)) => {
+                    log::warn!(
+                        "{} did not apply the DNS settings either: {}",
+                        backend,
+                        fallback_details
+                    );
+                    self.reset_holder(&mut fallback);
+                }
+                Err(error) => {
+                    log::warn!(
+                        "{}",
+                        error.display_chain_with_msg(&format!("Failed to set DNS via {}", backend))
+                    );
+                    self.reset_holder(&mut fallback);
+                }
+            }
+            skipped.push(backend);
+        }
+
+        // None of the other DNS managers work any better, so stick with the detected one
+        let mut inner = DnsMonitorHolder::new()?;
+        inner.set(
+            &self.handle,
+            &self.route_manager,
+            interface,
+            servers,
+            &self.split_dns,
+        )?;
+        self.warn(ignored_by, None, details);
+        Ok(inner)
+    }
+
+    /// Sets DNS via `inner` and waits up to `VERIFY_TIMEOUT`, but not past `deadline`, for the
+    /// system to use the servers. The inner result describes why the servers are not used if they
+    /// never are.
+    fn set_and_verify(
+        &self,
+        inner: &mut DnsMonitorHolder,
+        interface: &str,
+        servers: &[IpAddr],
+        deadline: Instant,
+    ) -> Result<std::result::Result<(), String>> {
+        inner.set(
+            &self.handle,
+            &self.route_manager,
+            interface,
+            servers,
+            &self.split_dns,
+        )?;
+
+        let config = leak_test::DnsConfig {
+            backend: inner.backend(),
+            interface: interface.to_owned(),
+            system_servers: servers.to_vec(),
+            upstream_servers: servers.to_vec(),
+            encrypted: false,
+        };
+        // Some DNS managers apply the settings asynchronously
+        let timeout = std::cmp::min(Instant::now() + VERIFY_TIMEOUT, deadline);
+        loop {
+            match leak_test::check_applied(&config) {
+                Ok(()) => return Ok(Ok(())),
+                Err(details) if Instant::now() >= timeout => return Ok(Err(details)),
+                Err(_) => thread::sleep(VERIFY_INTERVAL),
+            }
+        }
+    }
+
+    fn reset_holder(&self, inner: &mut DnsMonitorHolder) {
+        if let Err(error) = inner.reset(&self.handle) {
+            log::error!(
+                "{}",
+                error.display_chain_with_msg(&format!("Failed to reset DNS via {}", inner))
+            );
+        }
+    }
+
+    fn warn(&self, ignored_by: DnsBackend, fallback: Option<DnsBackend>, details: String) {
+        let warning = DnsWarning {
+            ignored_by,
+            fallback,
+            details,
+        };
+        log::warn!("{}", warning);
+        let _ = self.warning_listener.unbounded_send(warning);
+    }
 }
 
 impl super::DnsMonitorT for DnsMonitor {
     type Error = Error;
 
-    fn new(handle: tokio::runtime::Handle, route_manager: RouteManagerHandle) -> Result<Self> {
+    fn new(
+        handle: tokio::runtime::Handle,
+        route_manager: RouteManagerHandle,
+        warning_listener: mpsc::UnboundedSender<DnsWarning>,
+    ) -> Result<Self> {
         Ok(DnsMonitor {
             route_manager,
             handle,
+            warning_listener,
             inner: None,
+            split_dns: vec![],
+            interface: String::new(),
+            servers: vec![],
         })
     }
 
     fn set(&mut self, interface: &str, servers: &[IpAddr]) -> Result<()> {
         self.reset()?;
-        // Creating a new DNS monitor for each set, in case the system changed how it manages DNS.
-        let mut inner = DnsMonitorHolder::new()?;
         if !servers.is_empty() {
-            inner.set(&self.handle, &self.route_manager, interface, servers)?;
+            // Creating a new DNS monitor for each set, in case the system changed how it manages
+            // DNS.
+            let inner = self.set_verified(interface, servers)?;
             self.inner = Some(inner);
+            self.interface = interface.to_owned();
+            self.servers = servers.to_vec();
         }
         Ok(())
     }
@@ -77,55 +306,128 @@ impl super::DnsMonitorT for DnsMonitor {
 pub enum DnsMonitorHolder {
     SystemdResolved(SystemdResolved),
     NetworkManager(NetworkManager),
+    Connman(Connman),
+    Netconfig(Netconfig),
     Resolvconf(Resolvconf),
+    Dnsmasq(Dnsmasq),
     StaticResolvConf(StaticResolvConf),
 }
 
 impl fmt::Display for DnsMonitorHolder {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        use self::DnsMonitorHolder::*;
+        self.backend().fmt(f)
+    }
+}
+
+/// The system components that DNS can be managed through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DnsBackend {
+    SystemdResolved,
+    NetworkManager,
+    Connman,
+    Netconfig,
+    Resolvconf,
+    Dnsmasq,
+    StaticResolvConf,
+}
+
+impl fmt::Display for DnsBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let name = match self {
-            Resolvconf(..) => "resolvconf",
-            StaticResolvConf(..) => "/etc/resolv.conf",
-            SystemdResolved(..) => "systemd-resolved",
-            NetworkManager(..) => "network manager",
+            DnsBackend::Resolvconf => "resolvconf",
+            DnsBackend::StaticResolvConf => "/etc/resolv.conf",
+            DnsBackend::SystemdResolved => "systemd-resolved",
+            DnsBackend::NetworkManager => "network manager",
+            DnsBackend::Connman => "connman",
+            DnsBackend::Netconfig => "netconfig",
+            DnsBackend::Dnsmasq => "dnsmasq",
         };
         f.write_str(name)
     }
 }
 
 impl DnsMonitorHolder {
+    fn backend(&self) -> DnsBackend {
+        match self {
+            DnsMonitorHolder::SystemdResolved(..) => DnsBackend::SystemdResolved,
+            DnsMonitorHolder::NetworkManager(..) => DnsBackend::NetworkManager,
+            DnsMonitorHolder::Connman(..) => DnsBackend::Connman,
+            DnsMonitorHolder::Netconfig(..) => DnsBackend::Netconfig,
+            DnsMonitorHolder::Resolvconf(..) => DnsBackend::Resolvconf,
+            DnsMonitorHolder::Dnsmasq(..) => DnsBackend::Dnsmasq,
+            DnsMonitorHolder::StaticResolvConf(..) => DnsBackend::StaticResolvConf,
+        }
+    }
+
     fn new() -> Result<Self> {
+        let manager = match Self::selected_backend() {
+            Some(backend) => Self::with_backend(backend)?,
+            None => Self::with_detected_dns_manager(&[])?,
+        };
+        log::debug!("Managing DNS via {}", manager);
+        Ok(manager)
+    }
+
+    /// Returns the DNS manager selected using `TALPID_DNS_MODULE`, if any.
+    fn selected_backend() -> Option<DnsBackend> {
         let dns_module = env::var_os("TALPID_DNS_MODULE");
+        match dns_module.as_ref().and_then(|value| value.to_str()) {
+            Some("static-file") => Some(DnsBackend::StaticResolvConf),
+            Some("resolvconf") => Some(DnsBackend::Resolvconf),
+            Some("systemd") => Some(DnsBackend::SystemdResolved),
+            Some("network-manager") => Some(DnsBackend::NetworkManager),
+            Some("connman") => Some(DnsBackend::Connman),
+            Some("netconfig") => Some(DnsBackend::Netconfig),
+            Some("dnsmasq") => Some(DnsBackend::Dnsmasq),
+            Some(_) | None => None,
+        }
+    }
 
-        let manager = match dns_module.as_ref().and_then(|value| value.to_str()) {
-            Some("static-file") => DnsMonitorHolder::StaticResolvConf(StaticResolvConf::new()?),
-            Some("resolvconf") => DnsMonitorHolder::Resolvconf(Resolvconf::new()?),
-            Some("systemd") => DnsMonitorHolder::SystemdResolved(SystemdResolved::new()?),
-            Some("network-manager") => DnsMonitorHolder::NetworkManager(NetworkManager::new()?),
-            Some(_) | None => Self::with_detected_dns_manager()?,
+    fn with_backend(backend: DnsBackend) -> Result<Self> {
+        let manager = match backend {
+            DnsBackend::SystemdResolved => {
+                DnsMonitorHolder::SystemdResolved(SystemdResolved::new()?)
+            }
+            DnsBackend::NetworkManager => DnsMonitorHolder::NetworkManager(NetworkManager::new()?),
+            DnsBackend::Connman => DnsMonitorHolder::Connman(Connman::new()?),
+            DnsBackend::Netconfig => DnsMonitorHolder::Netconfig(Netconfig::new()?),
+            DnsBackend::Resolvconf => DnsMonitorHolder::Resolvconf(Resolvconf::new()?),
+            DnsBackend::Dnsmasq => DnsMonitorHolder::Dnsmasq(Dnsmasq::new()?),
+            DnsBackend::StaticResolvConf => {
+                DnsMonitorHolder::StaticResolvConf(StaticResolvConf::new()?)
+            }
         };
-        log::debug!("Managing DNS via {}", manager);
         Ok(manager)
     }
 
-    fn with_detected_dns_manager() -> Result<Self> {
-        SystemdResolved::new()
-            .map(DnsMonitorHolder::SystemdResolved)
-            .or_else(|err| {
-                match err {
-                    systemd_resolved::Error::SystemdResolvedError(
-                        systemd_resolved::SystemdDbusError::NoSystemdResolved(_),
-                    ) => (),
-                    other_error => {
-                        log::debug!("NetworkManager is being used because {}", other_error)
-                    }
+    /// Returns the first DNS manager in the detection order that is usable, skipping `skip`.
+    fn with_detected_dns_manager(skip: &[DnsBackend]) -> Result<Self> {
+        DETECTION_ORDER
+            .iter()
+            .filter(|backend| !skip.contains(backend))
+            .find_map(|&backend| match Self::with_backend(backend) {
+                Ok(manager) => Some(manager),
+                Err(Error::SystemdResolved(systemd_resolved::Error::SystemdResolvedError(
+                    systemd_resolved::SystemdDbusError::NoSystemdResolved(_),
+                ))) => None,
+                Err(error @ Error::SystemdResolved(_)) => {
+                    log::debug!(
+                        "Not managing DNS via {} because {}",
+                        backend,
+                        error.display_chain()
+                    );
+                    None
+                }
+                Err(error) => {
+                    log::trace!(
+                        "Not managing DNS via {} because {}",
+                        backend,
+                        error.display_chain()
+                    );
+                    None
                 }
-                NetworkManager::new().map(DnsMonitorHolder::NetworkManager)
             })
-            .or_else(|_| Resolvconf::new().map(DnsMonitorHolder::Resolvconf))
-            .or_else(|_| StaticResolvConf::new().map(DnsMonitorHolder::StaticResolvConf))
-            .map_err(|_| Error::NoDnsMonitor)
+            .ok_or(Error::NoDnsMonitor)
     }
 
     fn set(
@@ -134,6 +436,7 @@ impl DnsMonitorHolder {
         route_manager: &RouteManagerHandle,
         interface: &str,
         servers: &[IpAddr],
+        split_dns: &[SplitDnsRule],
     ) -> Result<()> {
         use self::DnsMonitorHolder::*;
         match self {
@@ -141,11 +444,15 @@ impl DnsMonitorHolder {
             StaticResolvConf(ref mut static_resolv_conf) => {
                 static_resolv_conf.set_dns(servers.to_vec())?
             }
-            SystemdResolved(ref mut systemd_resolved) => handle
-                .block_on(systemd_resolved.set_dns(route_manager.clone(), interface, &servers))?,
+            SystemdResolved(ref mut systemd_resolved) => handle.block_on(
+                systemd_resolved.set_dns(route_manager.clone(), interface, &servers, split_dns),
+            )?,
             NetworkManager(ref mut network_manager) => {
                 network_manager.set_dns(interface, servers)?
             }
+            Connman(ref mut connman) => connman.set_dns(servers)?,
+            Netconfig(ref mut netconfig) => netconfig.set_dns(interface, servers)?,
+            Dnsmasq(ref mut dnsmasq) => dnsmasq.set_dns(servers)?,
         }
         Ok(())
     }
@@ -159,6 +466,9 @@ impl DnsMonitorHolder {
                 handle.block_on(systemd_resolved.reset())?
             }
             NetworkManager(ref mut network_manager) => network_manager.reset()?,
+            Connman(ref mut connman) => connman.reset()?,
+            Netconfig(ref mut netconfig) => netconfig.reset()?,
+            Dnsmasq(ref mut dnsmasq) => dnsmasq.reset()?,
         }
         Ok(())
     }
@@ -0,0 +1,169 @@
+use super::RESOLV_CONF_PATH;
+use std::{
+    fs, io,
+    net::IpAddr,
+    path::{Path, PathBuf},
+};
+use which::which;
+
+/// Name of the service that our DNS settings are registered under in netconfig.
+const NETCONFIG_SERVICE: &str = "mullvad";
+/// netconfig usually lives in `/sbin`, which is not always in the daemon's `PATH`.
+const NETCONFIG_FALLBACK_PATH: &str = "/sbin/netconfig";
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(err_derive::Error, Debug)]
+pub enum Error {
+    #[error(display = "Failed to detect 'netconfig' program")]
+    NoNetconfig,
+
+    #[error(display = "Current /etc/resolv.conf is not generated by netconfig")]
+    NetconfigNotInUseError,
+
+    #[error(display = "Failed to execute 'netconfig' program")]
+    RunNetconfig(#[error(source)] io::Error),
+
+    #[error(display = "Using 'netconfig' to add DNS settings failed: {}", stderr)]
+    ModifyError { stderr: String },
+
+    #[error(display = "Using 'netconfig' to remove DNS settings failed")]
+    RemoveError,
+}
+
+pub struct Netconfig {
+    netconfig: PathBuf,
+    interface: Option<String>,
+}
+
+impl Netconfig {
+    pub fn new() -> Result<Self> {
+        let netconfig = which("netconfig")
+            .ok()
+            .or_else(|| {
+                let fallback = Path::new(NETCONFIG_FALLBACK_PATH);
+                fallback.exists().then(|| fallback.to_path_buf())
+            })
+            .ok_or(Error::NoNetconfig)?;
+
+        if !(Self::check_if_resolv_conf_is_symlinked_correctly()
+            || Self::check_if_resolv_conf_was_generated())
+        {
+            return Err(Error::NetconfigNotInUseError);
+        }
+
+        Ok(Netconfig {
+            netconfig,
+            interface: None,
+        })
+    }
+
+    pub fn set_dns(&mut self, interface: &str, servers: &[IpAddr]) -> Result<()> {
+        let output = duct::cmd!(
+            &self.netconfig,
+            "modify",
+            "--service",
+            NETCONFIG_SERVICE,
+            "--interface",
+            interface
+        )
+        .stdin_bytes(settings_contents(interface, servers))
+        .stderr_capture()
+        .unchecked()
+        .run()
+        .map_err(Error::RunNetconfig)?;
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+            return Err(Error::ModifyError { stderr });
+        }
+
+        self.interface = Some(interface.to_owned());
+
+        Ok(())
+    }
+
+    pub fn reset(&mut self) -> Result<()> {
+        let interface = match self.interface.take() {
+            Some(interface) => interface,
+            None => return Ok(()),
+        };
+
+        let output = duct::cmd!(
+            &self.netconfig,
+            "remove",
+            "--service",
+            NETCONFIG_SERVICE,
+            "--interface",
+            &interface
+        )
+        .stderr_capture()
+        .unchecked()
+        .run()
+        .map_err(Error::RunNetconfig)?;
+
+        if !output.status.success() {
+            log::error!(
+                "Failed to remove 'netconfig' settings for '{}':\n{}",
+                interface,
+                String::from_utf8_lossy(&output.stderr)
+            );
+            return Err(Error::RemoveError);
+        }
+
+        Ok(())
+    }
+
+    // Returns true if /etc/resolv.conf contents indicate that they've been generated by netconfig
+    fn check_if_resolv_conf_was_generated() -> bool {
+        match fs::read_to_string(RESOLV_CONF_PATH) {
+            Ok(contents) => contents.contains("autogenerated by netconfig"),
+            Err(err) => {
+                log::error!("Couldn't read /etc/resolv.conf: {}", err);
+                false
+            }
+        }
+    }
+
+    // Returns true if /etc/resolv.conf is symlinked to netconfig's runtime directory
+    fn check_if_resolv_conf_is_symlinked_correctly() -> bool {
+        match fs::canonicalize(RESOLV_CONF_PATH) {
+            Err(err) => {
+                if err.kind() != io::ErrorKind::NotFound {
+                    log::error!("Failed to canonicalize /etc/resolv.conf: {}", err);
+                }
+                false
+            }
+            Ok(path) => {
+                path.starts_with("/run/netconfig") || path.starts_with("/var/run/netconfig")
+            }
+        }
+    }
+}
+
+/// Formats the settings that are passed to `netconfig modify` on stdin.
+fn settings_contents(interface: &str, servers: &[IpAddr]) -> String {
+    let servers: Vec<String> = servers.iter().map(|server| server.to_string()).collect();
+    format!(
+        "INTERFACE='{}'\nDNSSERVERS='{}'\n",
+        interface,
+        servers.join(" ")
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::settings_contents;
+
+    #[test]
+    fn test_settings_contents() {
+        let servers = [
+            "10.64.0.1".parse().unwrap(),
+            "fc00:bbbb:bbbb:bb01::1".parse().unwrap(),
+        ];
+        assert_eq!(
+            settings_contents("wg-mullvad", &servers),
+            "INTERFACE='wg-mullvad'\nDNSSERVERS='10.64.0.1 fc00:bbbb:bbbb:bb01::1'\n"
+        );
+    }
+}
@@ -2,9 +2,9 @@ use crate::{
     linux::{iface_index, IfaceIndexLookupError},
     routing::RouteManagerHandle,
 };
-use std::net::IpAddr;
-use talpid_dbus::systemd_resolved::{AsyncHandle, SystemdResolved as DbusInterface};
-use talpid_types::ErrorExt;
+use std::{collections::BTreeMap, net::IpAddr};
+use talpid_dbus::systemd_resolved::{AsyncHandle, DnsState, SystemdResolved as DbusInterface};
+use talpid_types::{net::dns::SplitDnsRule, ErrorExt};
 
 pub(crate) use talpid_dbus::systemd_resolved::Error as SystemdDbusError;
 
@@ -19,9 +19,17 @@ pub enum Error {
     InterfaceNameError(#[error(source)] IfaceIndexLookupError),
 }
 
+/// DNS settings of a link that were replaced in order to send queries for split DNS domains to
+/// their resolvers.
+struct SplitDnsLink {
+    state: DnsState,
+    domains: Vec<(String, bool)>,
+}
+
 pub struct SystemdResolved {
     pub dbus_interface: AsyncHandle,
     tunnel_index: u32,
+    split_dns_links: Vec<SplitDnsLink>,
 }
 
 impl SystemdResolved {
@@ -31,6 +39,7 @@ impl SystemdResolved {
         let systemd_resolved = SystemdResolved {
             dbus_interface,
             tunnel_index: 0,
+            split_dns_links: vec![],
         };
 
         Ok(systemd_resolved)
@@ -38,9 +47,10 @@ impl SystemdResolved {
 
     pub async fn set_dns(
         &mut self,
-        _route_manager: RouteManagerHandle,
+        route_manager: RouteManagerHandle,
         interface_name: &str,
         servers: &[IpAddr],
+        split_dns: &[SplitDnsRule],
     ) -> Result<()> {
         let tunnel_index = iface_index(interface_name)?;
         self.tunnel_index = tunnel_index;
@@ -62,10 +72,118 @@ impl SystemdResolved {
             .set_dns(self.tunnel_index, servers.to_vec())
             .await?;
 
+        self.set_split_dns(&route_manager, split_dns).await
+    }
+
+    /// Adds the domains of the rules as routing domains to the links that their resolvers are
+    /// reachable on outside the tunnel, and uses the resolvers as the DNS servers of those links.
+    /// systemd-resolved then sends queries for the domains to those links rather than the tunnel.
+    async fn set_split_dns(
+        &mut self,
+        route_manager: &RouteManagerHandle,
+        rules: &[SplitDnsRule],
+    ) -> Result<()> {
+        let mut links: BTreeMap<u32, (Vec<IpAddr>, Vec<&str>)> = BTreeMap::new();
+        for rule in rules {
+            let index = match Self::find_resolver_link(route_manager, &rule.resolvers).await {
+                Some(index) if index != self.tunnel_index => index,
+                _ => {
+                    log::warn!(
+                        "Ignoring split DNS rule for {}: no route to its resolvers outside the tunnel",
+                        rule.domain
+                    );
+                    continue;
+                }
+            };
+            let (servers, domains) = links.entry(index).or_default();
+            for resolver in &rule.resolvers {
+                if !servers.contains(resolver) {
+                    servers.push(*resolver);
+                }
+            }
+            domains.push(&rule.domain);
+        }
+
+        for (index, (servers, split_domains)) in links {
+            let state = self.dbus_interface.get_dns(index).await?;
+            let previous_domains = self.dbus_interface.get_domains(index).await?;
+
+            let mut domains: Vec<(&str, bool)> = previous_domains
+                .iter()
+                .map(|(domain, routing_only)| (domain.as_str(), *routing_only))
+                .collect();
+            domains.extend(split_domains.iter().map(|domain| (*domain, true)));
+
+            log::debug!(
+                "Routing DNS queries for {} to link {}",
+                split_domains.join(", "),
+                index
+            );
+            // Remember the previous settings first, so that partial changes are reverted
+            let domains_result = self.dbus_interface.set_domains(index, &domains).await;
+            self.split_dns_links.push(SplitDnsLink {
+                state,
+                domains: previous_domains,
+            });
+            domains_result?;
+            self.dbus_interface.set_dns(index, servers).await?;
+        }
         Ok(())
     }
 
+    /// Returns the index of the interface that the first reachable resolver is routed through,
+    /// ignoring the tunnel.
+    async fn find_resolver_link(
+        route_manager: &RouteManagerHandle,
+        resolvers: &[IpAddr],
+    ) -> Option<u32> {
+        for resolver in resolvers {
+            match route_manager.get_destination_route(*resolver, true).await {
+                Ok(Some(route)) => {
+                    if let Some(index) = route
+                        .get_node()
+                        .get_device()
+                        .and_then(|device| iface_index(device).ok())
+                    {
+                        return Some(index);
+                    }
+                }
+                Ok(None) => (),
+                Err(error) => log::error!(
+                    "{}",
+                    error.display_chain_with_msg(&format!("Failed to find route to {}", resolver))
+                ),
+            }
+        }
+        None
+    }
+
+    async fn reset_split_dns(&mut self) {
+        for link in self.split_dns_links.drain(..) {
+            let index = link.state.interface_index;
+            let domains: Vec<(&str, bool)> = link
+                .domains
+                .iter()
+                .map(|(domain, routing_only)| (domain.as_str(), *routing_only))
+                .collect();
+            if let Err(error) = self.dbus_interface.set_domains(index, &domains).await {
+                log::error!(
+                    "{}",
+                    error.display_chain_with_msg("Failed to restore search domains")
+                );
+            }
+            if let Err(error) = self.dbus_interface.set_dns_state(link.state).await {
+                log::error!(
+                    "{}",
+                    error.display_chain_with_msg("Failed to restore DNS servers")
+                );
+            }
+        }
+    }
+
     pub async fn reset(&mut self) -> Result<()> {
+        self.reset_split_dns().await;
+
         if let Err(error) = self
             .dbus_interface
             .set_domains(self.tunnel_index, &[])
@@ -1,6 +1,6 @@
-#[cfg(target_os = "linux")]
-use crate::routing::RouteManagerHandle;
 use std::net::IpAddr;
+#[cfg(target_os = "linux")]
+use {crate::routing::RouteManagerHandle, futures::channel::mpsc::UnboundedSender};
 
 #[cfg(target_os = "macos")]
 use {
@@ -17,7 +17,10 @@ mod imp;
 mod imp;
 
 #[cfg(target_os = "linux")]
-pub use imp::will_use_nm;
+pub use imp::{
+    filter::{BlocklistStats, DnsFilter, Error as DnsFilterError},
+    leak_test, will_use_nm, DnsBackend, DnsWarning,
+};
 
 #[cfg(windows)]
 #[path = "windows/mod.rs"]
@@ -39,6 +42,7 @@ impl DnsMonitor {
     pub fn new(
         #[cfg(target_os = "linux")] handle: tokio::runtime::Handle,
         #[cfg(target_os = "linux")] route_manager: RouteManagerHandle,
+        #[cfg(target_os = "linux")] warning_listener: UnboundedSender<DnsWarning>,
         #[cfg(target_os = "macos")] tx: Weak<UnboundedSender<TunnelCommand>>,
     ) -> Result<Self, Error> {
         Ok(DnsMonitor {
@@ -47,6 +51,8 @@ impl DnsMonitor {
                 handle,
                 #[cfg(target_os = "linux")]
                 route_manager,
+                #[cfg(target_os = "linux")]
+                warning_listener,
                 #[cfg(target_os = "macos")]
                 tx,
             )?,
@@ -60,7 +66,32 @@ impl DnsMonitor {
         self.inner.get_system_config()
     }
 
+    /// Set the rules for sending queries for specific domains to other resolvers. They take
+    /// effect the next time DNS is set.
+    #[cfg(target_os = "linux")]
+    pub fn set_split_dns(&mut self, rules: Vec<talpid_types::net::dns::SplitDnsRule>) {
+        self.inner.set_split_dns(rules)
+    }
+
+    /// Returns whether split DNS rules are applied by the system DNS manager. If they are not,
+    /// queries have to go through the local forwarding resolver for the rules to apply.
+    #[cfg(target_os = "linux")]
+    pub fn supports_split_dns(&self) -> bool {
+        self.inner.supports_split_dns()
+    }
+
+    /// Returns the DNS manager, interface and servers that DNS is currently set through, if it
+    /// is set.
+    #[cfg(target_os = "linux")]
+    pub fn current(&self) -> Option<(DnsBackend, &str, &[IpAddr])> {
+        self.inner.current()
+    }
+
     /// Set DNS to the given servers. And start monitoring the system for changes.
+    ///
+    /// On Linux, the servers are checked to actually be used by the system. If the DNS manager
+    /// ignores them, other DNS managers are tried and a [`DnsWarning`] is sent to the warning
+    /// listener rather than failing.
     pub fn set(&mut self, interface: &str, servers: &[IpAddr]) -> Result<(), Error> {
         log::info!(
             "Setting DNS servers to {}",
@@ -87,6 +118,7 @@ trait DnsMonitorT: Sized {
     fn new(
         #[cfg(target_os = "linux")] handle: tokio::runtime::Handle,
         #[cfg(target_os = "linux")] route_manager: RouteManagerHandle,
+        #[cfg(target_os = "linux")] warning_listener: UnboundedSender<DnsWarning>,
         #[cfg(target_os = "macos")] tx: Weak<UnboundedSender<TunnelCommand>>,
     ) -> Result<Self, Self::Error>;
 
@@ -1,25 +1,59 @@
-use super::{FirewallArguments, FirewallPolicy};
+use super::{
+    nflog::{self, BlockedConnection, DNS_NFLOG_GROUP, NFLOG_GROUP},
+    FirewallArguments, FirewallPolicy,
+};
 use crate::{split_tunnel, tunnel};
 use ipnetwork::IpNetwork;
 use lazy_static::lazy_static;
 use libc;
 use nftnl::{
     self,
-    expr::{self, IcmpCode, Payload, RejectionType, Verdict},
-    nft_expr, table, Batch, Chain, FinalizedBatch, ProtoFamily, Rule, Table,
+    expr::{self, IcmpCode, Payload, RejectionType},
+    nft_expr, nftnl_sys as sys, table, Batch, Chain, FinalizedBatch, ProtoFamily, Rule, Table,
 };
 use std::{
     env,
     ffi::{CStr, CString},
-    io,
+    fmt, fs, io,
     net::{IpAddr, Ipv4Addr},
+    os::{raw::c_char, unix::fs::MetadataExt},
+    path::PathBuf,
+};
+use talpid_types::{
+    cgroup::{
+        find_exclusion_cgroup, ExclusionCgroup, ExclusionProfile, ProfileDns, SplitTunnelMode,
+        SPLIT_TUNNEL_CGROUP_NAME,
+    },
+    net::{lan::LanNetworks, AllowedDirection, Endpoint, FirewallAllowRule, TransportProtocol},
+    ErrorExt,
 };
-use talpid_types::net::{Endpoint, TransportProtocol};
 
 /// Priority for rules that tag split tunneling packets. Equals NF_IP_PRI_MANGLE.
 const MANGLE_CHAIN_PRIORITY: i32 = libc::NF_IP_PRI_MANGLE;
 const PREROUTING_CHAIN_PRIORITY: i32 = libc::NF_IP_PRI_CONNTRACK + 1;
 
+/// The maximum number of blocked packets per second, per chain, that are logged to NFLOG.
+const BLOCKED_LOG_RATE: u64 = 10;
+/// The number of packets that may exceed `BLOCKED_LOG_RATE` in a burst. Equals the default of
+/// `nft`, so that it does not have to be rendered.
+const BLOCKED_LOG_BURST: u32 = 5;
+/// Limits the number of packets rather than bytes. Equals NFT_LIMIT_PKTS.
+const NFT_LIMIT_PKTS: u32 = 0;
+
+/// Attributes of the libnftnl socket expression, which are missing from the `nftnl` bindings.
+const NFTNL_EXPR_SOCKET_KEY: u16 = 1;
+const NFTNL_EXPR_SOCKET_DREG: u16 = 2;
+const NFTNL_EXPR_SOCKET_LEVEL: u16 = 3;
+/// Loads the ID of the cgroup v2 ancestor of the socket, at a given level. Equals
+/// NFT_SOCKET_CGROUPV2.
+const NFT_SOCKET_CGROUPV2: u32 = 2;
+/// The level of the split tunneling cgroup in the cgroup v2 hierarchy.
+const EXCLUSION_CGROUP_LEVEL: u32 = 1;
+/// Plain text output format of `nftnl_rule_snprintf`. Equals NFTNL_OUTPUT_DEFAULT.
+const NFTNL_OUTPUT_DEFAULT: u32 = 0;
+/// Size of the buffer that rules are printed to when they are compared.
+const RULE_PRINT_BUFFER_SIZE: usize = 8192;
+
 pub type Result<T> = std::result::Result<T, Error>;
 
 /// Errors that can happen when interacting with Linux netfilter.
@@ -47,12 +81,21 @@ pub enum Error {
     #[error(display = "Failed to set firewall rules")]
     NetfilterTableNotSetError,
 
+    /// The rules in our tables differ from the rules that were last applied. Either another
+    /// program has flushed or edited them, or they were not applied correctly.
+    #[error(display = "The firewall rules have been modified")]
+    NetfilterRulesModifiedError,
+
     /// Unable to translate network interface name into index.
     #[error(
         display = "Unable to translate network interface name \"{}\" into index",
         _0
     )]
     LookupIfaceIndexError(String, #[error(source)] crate::linux::IfaceIndexLookupError),
+
+    /// Unable to find the ID of a cgroup.
+    #[error(display = "Unable to find the ID of cgroup \"{}\"", _0)]
+    LookupCgroupError(String, #[error(source)] io::Error),
 }
 
 lazy_static! {
@@ -73,7 +116,8 @@ lazy_static! {
     static ref NAT_CHAIN_NAME: CString = CString::new("nat").unwrap();
 
     /// Allows controlling whether firewall rules should have packet counters or not from an env
-    /// variable. Useful for debugging the rules.
+    /// variable. Useful for debugging the rules. This is a developer-only switch; blocked packets
+    /// are logged to NFLOG regardless.
     static ref ADD_COUNTERS: bool = env::var("TALPID_FIREWALL_DEBUG")
         .map(|v| v != "0")
         .unwrap_or(false);
@@ -96,7 +140,12 @@ enum End {
 }
 
 /// The Linux implementation for the firewall and DNS.
-pub struct Firewall(());
+pub struct Firewall {
+    blocked_monitor: Option<nflog::Monitor>,
+    /// The rules in our tables as listed by the kernel right after the policy was last applied,
+    /// or `None` if no policy has been applied.
+    applied_rules: Option<Vec<String>>,
+}
 
 struct FirewallTables {
     main: Table,
@@ -105,12 +154,30 @@ struct FirewallTables {
 }
 
 impl Firewall {
+    /// Creates the firewall used by the tunnel state machine, which also keeps track of blocked
+    /// packets.
     pub fn from_args(_args: FirewallArguments) -> Result<Self> {
-        Ok(Firewall(()))
+        let blocked_monitor = nflog::Monitor::spawn()
+            .map_err(|error| {
+                log::warn!(
+                    "{}",
+                    error.display_chain_with_msg("Failed to listen for blocked packets")
+                );
+            })
+            .ok();
+        Ok(Firewall {
+            blocked_monitor,
+            applied_rules: None,
+        })
     }
 
+    /// Creates a firewall that does not keep track of blocked packets, for applying or resetting
+    /// a policy outside of the tunnel state machine.
     pub fn new() -> Result<Self> {
-        Ok(Firewall(()))
+        Ok(Firewall {
+            blocked_monitor: None,
+            applied_rules: None,
+        })
     }
 
     pub fn apply_policy(&mut self, policy: FirewallPolicy) -> Result<()> {
@@ -119,13 +186,94 @@ impl Firewall {
             mangle_v4: Table::new(&*MANGLE_TABLE_NAME_V4, ProtoFamily::Ipv4),
             mangle_v6: Table::new(&*MANGLE_TABLE_NAME_V6, ProtoFamily::Ipv6),
         };
-        let batch = PolicyBatch::new(&tables).finalize(&policy)?;
+        let rules = PolicyBatch::new(Self::create_exclusion_cgroup()).finalize(&policy);
+        let batch = NftBatch::new(&tables).finalize(&rules)?;
+        self.applied_rules = None;
         Self::send_and_process(&batch)?;
         Self::apply_kernel_config(&policy);
-        self.verify_tables(&[&TABLE_NAME, &MANGLE_TABLE_NAME_V4, &MANGLE_TABLE_NAME_V6])
+        self.verify_tables(&TableId::ALL.map(TableId::name))?;
+
+        let applied_rules = Self::list_rules()?;
+        if applied_rules.len() != rules.len() {
+            log::error!(
+                "Expected {} firewall rules to be set, but found {}",
+                rules.len(),
+                applied_rules.len()
+            );
+            return Err(Error::NetfilterRulesModifiedError);
+        }
+        self.applied_rules = Some(applied_rules);
+        Ok(())
+    }
+
+    /// Returns the destinations that traffic was most recently blocked to, most recent first.
+    pub fn blocked_connections(&self) -> Vec<BlockedConnection> {
+        self.blocked_monitor
+            .as_ref()
+            .map(nflog::Monitor::blocked_connections)
+            .unwrap_or_default()
+    }
+
+    /// Checks that all tables created by `apply_policy` are still present, and that the rules in
+    /// them are the same as right after the policy was applied. This detects flushed chains and
+    /// rules that have been added, removed or replaced, but not changes to chain definitions.
+    /// Every chain that enforces the policy ends with a rule that drops or rejects traffic, so a
+    /// changed chain policy has no effect.
+    pub fn verify_policy(&self) -> Result<()> {
+        self.verify_tables(&TableId::ALL.map(TableId::name))?;
+        if let Some(ref applied_rules) = self.applied_rules {
+            if Self::list_rules()? != *applied_rules {
+                log::error!("The rules in the netfilter tables have been modified");
+                return Err(Error::NetfilterRulesModifiedError);
+            }
+        }
+        Ok(())
+    }
+
+    /// Renders the rules that would be applied for `policy` in the format used by
+    /// `nft list ruleset`. This does not touch the kernel.
+    pub fn render_policy(policy: &FirewallPolicy) -> String {
+        RenderedRuleset(&PolicyBatch::new(Self::exclusion_cgroup()).finalize(policy)).to_string()
+    }
+
+    /// Returns the cgroup hierarchy that processes excluded from the tunnel are tracked in. If
+    /// none is mounted yet, a `net_cls` hierarchy will be mounted by the split tunneling code.
+    fn exclusion_cgroup() -> Option<ExclusionCgroup> {
+        match find_exclusion_cgroup() {
+            Ok(cgroup) => cgroup,
+            Err(error) => {
+                log::error!(
+                    "{}",
+                    error.display_chain_with_msg("Failed to find the split tunneling cgroup")
+                );
+                None
+            }
+        }
+    }
+
+    /// Like `exclusion_cgroup`, but also creates the split tunneling cgroup in a cgroup v2
+    /// hierarchy if it does not exist yet, since it must exist for sockets to be matched against
+    /// it. Matching by class ID instead would never match on a host without the `net_cls`
+    /// controller.
+    fn create_exclusion_cgroup() -> Option<ExclusionCgroup> {
+        let cgroup = Self::exclusion_cgroup()?;
+        if let ExclusionCgroup::V2(_) = cgroup {
+            let exclusions_path = cgroup.exclusions_path();
+            if !exclusions_path.exists() {
+                if let Err(error) = fs::create_dir(&exclusions_path) {
+                    log::error!(
+                        "{}",
+                        error.display_chain_with_msg("Failed to create the split tunneling cgroup")
+                    );
+                    return None;
+                }
+            }
+        }
+        Some(cgroup)
     }
 
     pub fn reset_policy(&mut self) -> Result<()> {
+        self.applied_rules = None;
         let tables = [
             Table::new(&*TABLE_NAME, ProtoFamily::Inet),
             Table::new(&*MANGLE_TABLE_NAME_V4, ProtoFamily::Ipv4),
@@ -215,6 +363,34 @@ impl Firewall {
         Ok(())
     }
 
+    /// Lists the rules in our tables, printed with their handles and positions, in the order that
+    /// the kernel returns them. Counters are left out, since they change as packets are matched.
+    fn list_rules() -> Result<Vec<String>> {
+        let socket = mnl::Socket::new(mnl::Bus::Netfilter).map_err(Error::NetlinkOpenError)?;
+        let portid = socket.portid();
+        let seq = 0;
+
+        socket
+            .send(&get_rules_nlmsg(seq))
+            .map_err(Error::NetlinkSendError)?;
+
+        let mut rules = Vec::new();
+        let mut msg_buffer = vec![0; nftnl::nft_nlmsg_maxsize() as usize];
+
+        while let Some(message) = Self::socket_recv(&socket, &mut msg_buffer)? {
+            match mnl::cb_run2(message, seq, portid, get_rules_cb, &mut rules)
+                .map_err(Error::ProcessNetlinkError)?
+            {
+                mnl::CbResult::Stop => {
+                    log::trace!("cb_run STOP");
+                    break;
+                }
+                mnl::CbResult::Ok => log::trace!("cb_run OK"),
+            }
+        }
+        Ok(rules)
+    }
+
     fn socket_recv<'a>(socket: &mnl::Socket, buf: &'a mut [u8]) -> Result<Option<&'a [u8]>> {
         let ret = socket.recv(buf).map_err(Error::NetlinkRecvError)?;
         log::trace!("Read {} bytes from netlink", ret);
@@ -226,8 +402,632 @@ impl Firewall {
     }
 }
 
-struct PolicyBatch<'a> {
-    batch: Batch,
+/// Creates a netlink message that requests all rules in all tables.
+fn get_rules_nlmsg(seq: u32) -> Vec<u8> {
+    let mut buffer = vec![0; nftnl::nft_nlmsg_maxsize() as usize];
+    let len = unsafe {
+        let header = sys::nftnl_nlmsg_build_hdr(
+            buffer.as_mut_ptr() as *mut c_char,
+            libc::NFT_MSG_GETRULE as u16,
+            ProtoFamily::Unspec as u16,
+            libc::NLM_F_DUMP as u16,
+            seq,
+        );
+        (*header).nlmsg_len as usize
+    };
+    buffer.truncate(len);
+    buffer
+}
+
+/// Adds the rule in `header` to `rules`, if it belongs to one of our tables.
+fn get_rules_cb(header: &libc::nlmsghdr, rules: &mut Vec<String>) -> libc::c_int {
+    unsafe {
+        let rule = sys::nftnl_rule_alloc();
+        if rule.is_null() {
+            return mnl::mnl_sys::MNL_CB_ERROR;
+        }
+        if sys::nftnl_rule_nlmsg_parse(header, rule) < 0 {
+            sys::nftnl_rule_free(rule);
+            return mnl::mnl_sys::MNL_CB_ERROR;
+        }
+
+        let table = sys::nftnl_rule_get_str(rule, sys::NFTNL_RULE_TABLE as u16);
+        let family = sys::nftnl_rule_get_u32(rule, sys::NFTNL_RULE_FAMILY as u16);
+        let is_ours = !table.is_null()
+            && TableId::ALL
+                .iter()
+                .any(|id| id.name() == CStr::from_ptr(table) && id.proto_family() as u32 == family);
+
+        if is_ours {
+            let mut buffer = vec![0u8; RULE_PRINT_BUFFER_SIZE];
+            sys::nftnl_rule_snprintf(
+                buffer.as_mut_ptr() as *mut c_char,
+                buffer.len() as _,
+                rule,
+                NFTNL_OUTPUT_DEFAULT,
+                0,
+            );
+            let printed = CStr::from_ptr(buffer.as_ptr() as *const c_char).to_string_lossy();
+            rules.push(
+                printed
+                    .lines()
+                    .filter(|line| !line.trim_start().starts_with("[ counter "))
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+            );
+        }
+        sys::nftnl_rule_free(rule);
+    }
+    mnl::mnl_sys::MNL_CB_OK
+}
+
+/// Identifies the tables created by the firewall.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+enum TableId {
+    Main,
+    MangleV4,
+    MangleV6,
+}
+
+impl TableId {
+    const ALL: [TableId; 3] = [TableId::Main, TableId::MangleV4, TableId::MangleV6];
+
+    fn name(self) -> &'static CStr {
+        match self {
+            TableId::Main => &*TABLE_NAME,
+            TableId::MangleV4 => &*MANGLE_TABLE_NAME_V4,
+            TableId::MangleV6 => &*MANGLE_TABLE_NAME_V6,
+        }
+    }
+
+    fn family(self) -> &'static str {
+        match self {
+            TableId::Main => "inet",
+            TableId::MangleV4 => "ip",
+            TableId::MangleV6 => "ip6",
+        }
+    }
+
+    fn proto_family(self) -> ProtoFamily {
+        match self {
+            TableId::Main => ProtoFamily::Inet,
+            TableId::MangleV4 => ProtoFamily::Ipv4,
+            TableId::MangleV6 => ProtoFamily::Ipv6,
+        }
+    }
+
+    /// The chains of the table, in the order they are created.
+    fn chains(self) -> &'static [ChainId] {
+        match self {
+            TableId::Main => &[
+                ChainId::Prerouting,
+                ChainId::Out,
+                ChainId::In,
+                ChainId::Forward,
+            ],
+            TableId::MangleV4 => &[ChainId::MangleV4, ChainId::NatV4],
+            TableId::MangleV6 => &[ChainId::MangleV6, ChainId::NatV6],
+        }
+    }
+}
+
+/// Identifies the chains created by the firewall.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+enum ChainId {
+    Prerouting,
+    Out,
+    In,
+    Forward,
+    MangleV4,
+    MangleV6,
+    NatV4,
+    NatV6,
+}
+
+impl ChainId {
+    fn name(self) -> &'static CStr {
+        match self {
+            ChainId::Prerouting => &*PREROUTING_CHAIN_NAME,
+            ChainId::Out => &*OUT_CHAIN_NAME,
+            ChainId::In => &*IN_CHAIN_NAME,
+            ChainId::Forward => &*FORWARD_CHAIN_NAME,
+            ChainId::MangleV4 | ChainId::MangleV6 => &*MANGLE_CHAIN_NAME,
+            ChainId::NatV4 | ChainId::NatV6 => &*NAT_CHAIN_NAME,
+        }
+    }
+
+    /// Returns the chain definition as rendered by `nft list ruleset`. Must match the chains
+    /// created by `NftChains::new`.
+    fn definition(self) -> String {
+        let (chain_type, hook, priority, policy) = match self {
+            ChainId::Prerouting => ("filter", "prerouting", PREROUTING_CHAIN_PRIORITY, "accept"),
+            ChainId::Out => ("filter", "output", 0, "drop"),
+            ChainId::In => ("filter", "input", 0, "drop"),
+            ChainId::Forward => ("filter", "forward", 0, "drop"),
+            ChainId::MangleV4 | ChainId::MangleV6 => {
+                ("route", "output", MANGLE_CHAIN_PRIORITY, "accept")
+            }
+            ChainId::NatV4 | ChainId::NatV6 => {
+                ("nat", "postrouting", libc::NF_IP_PRI_NAT_SRC, "accept")
+            }
+        };
+        format!(
+            "type {} hook {} priority {}; policy {};",
+            chain_type, hook, priority, policy
+        )
+    }
+}
+
+/// A single firewall rule, described independently of netfilter so that it can be rendered
+/// without talking to the kernel.
+#[derive(Debug, Clone, Eq, PartialEq)]
+struct RuleSpec {
+    chain: ChainId,
+    matches: Vec<Match>,
+    statements: Vec<Statement>,
+    counter: bool,
+    verdict: Option<Verdict>,
+}
+
+/// Conditions that a packet must satisfy for a rule to apply.
+#[derive(Debug, Clone, Eq, PartialEq)]
+enum Match {
+    Iface(Direction, String),
+    NotIface(Direction, String),
+    Ip(End, IpAddr),
+    Net(End, IpNetwork),
+    Port(TransportProtocol, End, u16),
+    L4proto(TransportProtocol),
+    Icmpv6 {
+        r#type: u8,
+        code: u8,
+    },
+    Established,
+    CtMark(u32),
+    MetaMark(u32),
+    Skuid(u32),
+    Cgroup(u32),
+    /// Matches sockets in the cgroup with the given name, in the cgroup v2 hierarchy mounted at
+    /// the given path.
+    SocketCgroupV2(PathBuf, String),
+    /// Matches at most the given number of packets per second.
+    Limit(u64),
+}
+
+/// Non-terminal actions taken on packets matching a rule.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Statement {
+    SetCtMark(u32),
+    SetMetaMark(u32),
+    Masquerade,
+    /// Sends the packet to the given NFLOG group.
+    Log(u16),
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Verdict {
+    Accept,
+    Drop,
+    RejectPortUnreach,
+    RejectTcpReset,
+}
+
+impl RuleSpec {
+    fn new(chain: ChainId) -> Self {
+        RuleSpec {
+            chain,
+            matches: Vec::new(),
+            statements: Vec::new(),
+            counter: false,
+            verdict: None,
+        }
+    }
+
+    /// Translates the rule into netfilter expressions, in the order matches, statements, counter
+    /// and verdict.
+    fn to_nftnl<'a>(&self, chain: &'a Chain<'_>) -> Result<Rule<'a>> {
+        let mut rule = Rule::new(chain);
+        let is_inet = chain.get_table().get_family() == ProtoFamily::Inet;
+        for condition in &self.matches {
+            condition.add_exprs(&mut rule, is_inet)?;
+        }
+        for statement in &self.statements {
+            statement.add_exprs(&mut rule);
+        }
+        if self.counter {
+            rule.add_expr(&nft_expr!(counter));
+        }
+        if let Some(verdict) = self.verdict {
+            rule.add_expr(&verdict.to_nftnl());
+        }
+        Ok(rule)
+    }
+}
+
+impl Match {
+    fn add_exprs(&self, rule: &mut Rule<'_>, is_inet: bool) -> Result<()> {
+        match self {
+            Match::Iface(direction, iface) | Match::NotIface(direction, iface) => {
+                let iface_index = crate::linux::iface_index(iface)
+                    .map_err(|e| Error::LookupIfaceIndexError(iface.to_owned(), e))?;
+                rule.add_expr(&match direction {
+                    Direction::In => nft_expr!(meta iif),
+                    Direction::Out => nft_expr!(meta oif),
+                });
+                if let Match::Iface(..) = self {
+                    rule.add_expr(&nft_expr!(cmp == iface_index));
+                } else {
+                    rule.add_expr(&nft_expr!(cmp != iface_index));
+                }
+            }
+            Match::Ip(end, ip) => {
+                // Must check network layer protocol before loading network layer payload
+                if is_inet {
+                    add_l3proto_exprs(rule, *ip);
+                }
+                rule.add_expr(&match (ip, end) {
+                    (IpAddr::V4(..), End::Src) => nft_expr!(payload ipv4 saddr),
+                    (IpAddr::V4(..), End::Dst) => nft_expr!(payload ipv4 daddr),
+                    (IpAddr::V6(..), End::Src) => nft_expr!(payload ipv6 saddr),
+                    (IpAddr::V6(..), End::Dst) => nft_expr!(payload ipv6 daddr),
+                });
+                match ip {
+                    IpAddr::V4(addr) => rule.add_expr(&nft_expr!(cmp == *addr)),
+                    IpAddr::V6(addr) => rule.add_expr(&nft_expr!(cmp == *addr)),
+                }
+            }
+            Match::Net(end, net) => {
+                // Must check network layer protocol before loading network layer payload
+                if is_inet {
+                    add_l3proto_exprs(rule, net.ip());
+                }
+                rule.add_expr(&match (net, end) {
+                    (IpNetwork::V4(_), End::Src) => nft_expr!(payload ipv4 saddr),
+                    (IpNetwork::V4(_), End::Dst) => nft_expr!(payload ipv4 daddr),
+                    (IpNetwork::V6(_), End::Src) => nft_expr!(payload ipv6 saddr),
+                    (IpNetwork::V6(_), End::Dst) => nft_expr!(payload ipv6 daddr),
+                });
+                match net {
+                    IpNetwork::V4(_) => {
+                        rule.add_expr(&nft_expr!(bitwise mask net.mask(), xor 0u32))
+                    }
+                    IpNetwork::V6(_) => {
+                        rule.add_expr(&nft_expr!(bitwise mask net.mask(), xor &[0u16; 8][..]))
+                    }
+                };
+                rule.add_expr(&nft_expr!(cmp == net.ip()));
+            }
+            Match::Port(protocol, end, port) => {
+                // Must check transport layer protocol before loading transport layer payload
+                add_l4proto_exprs(rule, *protocol);
+
+                rule.add_expr(&match (protocol, end) {
+                    (TransportProtocol::Udp, End::Src) => nft_expr!(payload udp sport),
+                    (TransportProtocol::Udp, End::Dst) => nft_expr!(payload udp dport),
+                    (TransportProtocol::Tcp, End::Src) => nft_expr!(payload tcp sport),
+                    (TransportProtocol::Tcp, End::Dst) => nft_expr!(payload tcp dport),
+                });
+                rule.add_expr(&nft_expr!(cmp == port.to_be()));
+            }
+            Match::L4proto(protocol) => add_l4proto_exprs(rule, *protocol),
+            Match::Icmpv6 { r#type, code } => {
+                rule.add_expr(&nft_expr!(meta l4proto));
+                rule.add_expr(&nft_expr!(cmp == libc::IPPROTO_ICMPV6 as u8));
+
+                rule.add_expr(&Payload::Transport(
+                    nftnl::expr::TransportHeaderField::Icmpv6(nftnl::expr::Icmpv6HeaderField::Type),
+                ));
+                rule.add_expr(&nft_expr!(cmp == *r#type));
+                rule.add_expr(&nftnl::expr::Payload::Transport(
+                    nftnl::expr::TransportHeaderField::Icmpv6(nftnl::expr::Icmpv6HeaderField::Code),
+                ));
+                rule.add_expr(&nft_expr!(cmp == *code));
+            }
+            Match::Established => {
+                rule.add_expr(&nft_expr!(ct state));
+                let allowed_states = nftnl::expr::ct::States::ESTABLISHED.bits();
+                rule.add_expr(&nft_expr!(bitwise mask allowed_states, xor 0u32));
+                rule.add_expr(&nft_expr!(cmp != 0u32));
+            }
+            Match::CtMark(mark) => {
+                rule.add_expr(&nft_expr!(ct mark));
+                rule.add_expr(&nft_expr!(cmp == *mark));
+            }
+            Match::MetaMark(mark) => {
+                rule.add_expr(&nft_expr!(meta mark));
+                rule.add_expr(&nft_expr!(cmp == *mark));
+            }
+            Match::Skuid(uid) => {
+                rule.add_expr(&nft_expr!(meta skuid));
+                rule.add_expr(&nft_expr!(cmp == *uid));
+            }
+            Match::Cgroup(classid) => {
+                rule.add_expr(&nft_expr!(meta cgroup));
+                rule.add_expr(&nft_expr!(cmp == *classid));
+            }
+            Match::SocketCgroupV2(mount_path, name) => {
+                let path = mount_path.join(name);
+                // The ID of a cgroup is the inode number of its directory
+                let cgroup_id = fs::metadata(&path)
+                    .map_err(|e| Error::LookupCgroupError(path.display().to_string(), e))?
+                    .ino()
+                    .to_ne_bytes();
+                rule.add_expr(&SocketCgroupV2Expr(EXCLUSION_CGROUP_LEVEL));
+                rule.add_expr(&nft_expr!(cmp == &cgroup_id[..]));
+            }
+            Match::Limit(rate) => rule.add_expr(&LimitExpr(*rate)),
+        }
+        Ok(())
+    }
+}
+
+impl Statement {
+    fn add_exprs(&self, rule: &mut Rule<'_>) {
+        match *self {
+            Statement::SetCtMark(mark) => {
+                rule.add_expr(&nft_expr!(immediate data mark));
+                rule.add_expr(&nft_expr!(ct mark set));
+            }
+            Statement::SetMetaMark(mark) => {
+                rule.add_expr(&nft_expr!(immediate data mark));
+                rule.add_expr(&nft_expr!(meta mark set));
+            }
+            Statement::Masquerade => rule.add_expr(&nft_expr!(masquerade)),
+            Statement::Log(group) => rule.add_expr(&LogExpr(group)),
+        }
+    }
+}
+
+/// The `limit` expression, which is not provided by `nftnl`. Limits the number of packets per
+/// second.
+struct LimitExpr(u64);
+
+impl expr::Expression for LimitExpr {
+    fn to_expr(&self, _rule: &Rule<'_>) -> *mut sys::nftnl_expr {
+        unsafe {
+            let expr = sys::nftnl_expr_alloc(b"limit\0" as *const _ as *const c_char);
+            assert!(!expr.is_null(), "Failed to allocate limit expression");
+            sys::nftnl_expr_set_u64(expr, sys::NFTNL_EXPR_LIMIT_RATE as u16, self.0);
+            sys::nftnl_expr_set_u64(expr, sys::NFTNL_EXPR_LIMIT_UNIT as u16, 1);
+            sys::nftnl_expr_set_u32(expr, sys::NFTNL_EXPR_LIMIT_BURST as u16, BLOCKED_LOG_BURST);
+            sys::nftnl_expr_set_u32(expr, sys::NFTNL_EXPR_LIMIT_TYPE as u16, NFT_LIMIT_PKTS);
+            expr
+        }
+    }
+}
+
+/// The `socket cgroupv2` expression, which is not provided by `nftnl`. Loads the ID of the cgroup
+/// that the socket of the packet belongs to, or of its ancestor at the given level.
+struct SocketCgroupV2Expr(u32);
+
+impl expr::Expression for SocketCgroupV2Expr {
+    fn to_expr(&self, _rule: &Rule<'_>) -> *mut sys::nftnl_expr {
+        unsafe {
+            let expr = sys::nftnl_expr_alloc(b"socket\0" as *const _ as *const c_char);
+            assert!(!expr.is_null(), "Failed to allocate socket expression");
+            sys::nftnl_expr_set_u32(expr, NFTNL_EXPR_SOCKET_KEY, NFT_SOCKET_CGROUPV2);
+            sys::nftnl_expr_set_u32(expr, NFTNL_EXPR_SOCKET_DREG, libc::NFT_REG_1 as u32);
+            sys::nftnl_expr_set_u32(expr, NFTNL_EXPR_SOCKET_LEVEL, self.0);
+            expr
+        }
+    }
+}
+
+/// The `log` expression, which is not provided by `nftnl`. Sends packets to an NFLOG group.
+struct LogExpr(u16);
+
+impl expr::Expression for LogExpr {
+    fn to_expr(&self, _rule: &Rule<'_>) -> *mut sys::nftnl_expr {
+        unsafe {
+            let expr = sys::nftnl_expr_alloc(b"log\0" as *const _ as *const c_char);
+            assert!(!expr.is_null(), "Failed to allocate log expression");
+            sys::nftnl_expr_set_u16(expr, sys::NFTNL_EXPR_LOG_GROUP as u16, self.0);
+            expr
+        }
+    }
+}
+
+impl Verdict {
+    fn to_nftnl(self) -> expr::Verdict {
+        match self {
+            Verdict::Accept => expr::Verdict::Accept,
+            Verdict::Drop => expr::Verdict::Drop,
+            Verdict::RejectPortUnreach => {
+                expr::Verdict::Reject(RejectionType::Icmp(IcmpCode::PortUnreach))
+            }
+            Verdict::RejectTcpReset => expr::Verdict::Reject(RejectionType::TcpRst),
+        }
+    }
+}
+
+fn add_l3proto_exprs(rule: &mut Rule<'_>, ip: IpAddr) {
+    rule.add_expr(&nft_expr!(meta nfproto));
+    rule.add_expr(&nft_expr!(cmp == l3proto(ip)));
+}
+
+fn l3proto(addr: IpAddr) -> u8 {
+    match addr {
+        IpAddr::V4(_) => libc::NFPROTO_IPV4 as u8,
+        IpAddr::V6(_) => libc::NFPROTO_IPV6 as u8,
+    }
+}
+
+fn add_l4proto_exprs(rule: &mut Rule<'_>, protocol: TransportProtocol) {
+    rule.add_expr(&nft_expr!(meta l4proto));
+    rule.add_expr(&nft_expr!(cmp == l4proto(protocol)));
+}
+
+fn l4proto(protocol: TransportProtocol) -> u8 {
+    match protocol {
+        TransportProtocol::Udp => libc::IPPROTO_UDP as u8,
+        TransportProtocol::Tcp => libc::IPPROTO_TCP as u8,
+    }
+}
+
+impl fmt::Display for RuleSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts: Vec<String> = self.matches.iter().map(Match::to_string).collect();
+        parts.extend(self.statements.iter().map(Statement::to_string));
+        if self.counter {
+            parts.push("counter".to_owned());
+        }
+        if let Some(verdict) = self.verdict {
+            parts.push(verdict.to_string());
+        }
+        f.write_str(&parts.join(" "))
+    }
+}
+
+impl fmt::Display for Match {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Match::Iface(direction, iface) => write!(f, "{} \"{}\"", direction.iface_key(), iface),
+            Match::NotIface(direction, iface) => {
+                write!(f, "{} != \"{}\"", direction.iface_key(), iface)
+            }
+            Match::Ip(end, ip) => write!(f, "{} {} {}", ip_family(*ip), end.addr_key(), ip),
+            Match::Net(end, net) => {
+                write!(f, "{} {} {}", ip_family(net.ip()), end.addr_key(), net)
+            }
+            Match::Port(protocol, end, port) => {
+                write!(
+                    f,
+                    "{} {} {}",
+                    protocol_name(*protocol),
+                    end.port_key(),
+                    port
+                )
+            }
+            Match::L4proto(protocol) => write!(f, "meta l4proto {}", protocol_name(*protocol)),
+            Match::Icmpv6 { r#type, code } => {
+                match icmpv6_type_name(*r#type) {
+                    Some(name) => write!(f, "icmpv6 type {}", name)?,
+                    None => write!(f, "icmpv6 type {}", r#type)?,
+                }
+                write!(f, " icmpv6 code {}", code)
+            }
+            Match::Established => f.write_str("ct state established"),
+            Match::CtMark(mark) => write!(f, "ct mark {:#010x}", mark),
+            Match::MetaMark(mark) => write!(f, "meta mark {:#010x}", mark),
+            Match::Skuid(uid) => write!(f, "meta skuid {}", uid),
+            Match::Cgroup(classid) => write!(f, "meta cgroup {}", classid),
+            Match::SocketCgroupV2(_, name) => write!(
+                f,
+                "socket cgroupv2 level {} \"{}\"",
+                EXCLUSION_CGROUP_LEVEL, name
+            ),
+            Match::Limit(rate) => write!(f, "limit rate {}/second", rate),
+        }
+    }
+}
+
+impl fmt::Display for Statement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Statement::SetCtMark(mark) => write!(f, "ct mark set {:#010x}", mark),
+            Statement::SetMetaMark(mark) => write!(f, "meta mark set {:#010x}", mark),
+            Statement::Masquerade => f.write_str("masquerade"),
+            Statement::Log(group) => write!(f, "log group {}", group),
+        }
+    }
+}
+
+impl fmt::Display for Verdict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Verdict::Accept => "accept",
+            Verdict::Drop => "drop",
+            Verdict::RejectPortUnreach => "reject with icmpx type port-unreachable",
+            Verdict::RejectTcpReset => "reject with tcp reset",
+        })
+    }
+}
+
+impl Direction {
+    fn iface_key(self) -> &'static str {
+        match self {
+            Direction::In => "iif",
+            Direction::Out => "oif",
+        }
+    }
+}
+
+impl End {
+    fn opposite(self) -> Self {
+        match self {
+            End::Src => End::Dst,
+            End::Dst => End::Src,
+        }
+    }
+
+    fn addr_key(self) -> &'static str {
+        match self {
+            End::Src => "saddr",
+            End::Dst => "daddr",
+        }
+    }
+
+    fn port_key(self) -> &'static str {
+        match self {
+            End::Src => "sport",
+            End::Dst => "dport",
+        }
+    }
+}
+
+fn protocol_name(protocol: TransportProtocol) -> &'static str {
+    match protocol {
+        TransportProtocol::Udp => "udp",
+        TransportProtocol::Tcp => "tcp",
+    }
+}
+
+fn ip_family(ip: IpAddr) -> &'static str {
+    match ip {
+        IpAddr::V4(_) => "ip",
+        IpAddr::V6(_) => "ip6",
+    }
+}
+
+fn icmpv6_type_name(r#type: u8) -> Option<&'static str> {
+    match r#type {
+        133 => Some("nd-router-solicit"),
+        134 => Some("nd-router-advert"),
+        135 => Some("nd-neighbor-solicit"),
+        136 => Some("nd-neighbor-advert"),
+        137 => Some("nd-redirect"),
+        _ => None,
+    }
+}
+
+/// Renders a list of rules in the format used by `nft list ruleset`.
+struct RenderedRuleset<'a>(&'a [RuleSpec]);
+
+impl fmt::Display for RenderedRuleset<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for table in &TableId::ALL {
+            writeln!(
+                f,
+                "table {} {} {{",
+                table.family(),
+                table.name().to_string_lossy()
+            )?;
+            for chain in table.chains() {
+                writeln!(f, "\tchain {} {{", chain.name().to_string_lossy())?;
+                writeln!(f, "\t\t{}", chain.definition())?;
+                for rule in self.0.iter().filter(|rule| rule.chain == *chain) {
+                    writeln!(f, "\t\t{}", rule)?;
+                }
+                writeln!(f, "\t}}")?;
+            }
+            writeln!(f, "}}")?;
+        }
+        Ok(())
+    }
+}
+
+/// The netfilter chains that rules are added to.
+struct NftChains<'a> {
     in_chain: Chain<'a>,
     out_chain: Chain<'a>,
     forward_chain: Chain<'a>,
@@ -238,7 +1038,28 @@ struct PolicyBatch<'a> {
     nat_chain_v6: Chain<'a>,
 }
 
-impl<'a> PolicyBatch<'a> {
+impl<'a> NftChains<'a> {
+    fn get(&self, id: ChainId) -> &Chain<'a> {
+        match id {
+            ChainId::Prerouting => &self.prerouting_chain,
+            ChainId::Out => &self.out_chain,
+            ChainId::In => &self.in_chain,
+            ChainId::Forward => &self.forward_chain,
+            ChainId::MangleV4 => &self.mangle_chain_v4,
+            ChainId::MangleV6 => &self.mangle_chain_v6,
+            ChainId::NatV4 => &self.nat_chain_v4,
+            ChainId::NatV6 => &self.nat_chain_v6,
+        }
+    }
+}
+
+/// Translates rules into an nftnl message batch.
+struct NftBatch<'a> {
+    batch: Batch,
+    chains: NftChains<'a>,
+}
+
+impl<'a> NftBatch<'a> {
     /// Bootstrap a new nftnl message batch object and add the initial messages creating the
     /// table and chains.
     pub fn new(tables: &'a FirewallTables) -> Self {
@@ -292,16 +1113,18 @@ impl<'a> PolicyBatch<'a> {
         let nat_chain_v4 = add_nat_chain(&tables.mangle_v4);
         let nat_chain_v6 = add_nat_chain(&tables.mangle_v6);
 
-        PolicyBatch {
+        NftBatch {
             batch,
-            in_chain,
-            out_chain,
-            forward_chain,
-            prerouting_chain,
-            mangle_chain_v4,
-            mangle_chain_v6,
-            nat_chain_v4,
-            nat_chain_v6,
+            chains: NftChains {
+                in_chain,
+                out_chain,
+                forward_chain,
+                prerouting_chain,
+                mangle_chain_v4,
+                mangle_chain_v6,
+                nat_chain_v4,
+                nat_chain_v6,
+            },
         }
     }
 
@@ -312,19 +1135,85 @@ impl<'a> PolicyBatch<'a> {
         batch.add(table, nftnl::MsgType::Add);
     }
 
-    /// Finalize the nftnl message batch by adding every firewall rule needed to satisfy the given
+    /// Finalize the nftnl message batch by adding the given rules to their chains.
+    pub fn finalize(mut self, rules: &[RuleSpec]) -> Result<FinalizedBatch> {
+        for spec in rules {
+            let rule = spec.to_nftnl(self.chains.get(spec.chain))?;
+            self.batch.add(&rule, nftnl::MsgType::Add);
+        }
+        Ok(self.batch.finalize())
+    }
+}
+
+/// Builds the list of rules needed to satisfy a policy.
+struct PolicyBatch {
+    rules: Vec<RuleSpec>,
+    exclusion_cgroup: Option<ExclusionCgroup>,
+}
+
+impl PolicyBatch {
+    pub fn new(exclusion_cgroup: Option<ExclusionCgroup>) -> Self {
+        PolicyBatch {
+            rules: Vec::new(),
+            exclusion_cgroup,
+        }
+    }
+
+    /// Finalize the rule list by adding every firewall rule needed to satisfy the given
     /// policy.
-    pub fn finalize(mut self, policy: &FirewallPolicy) -> Result<FinalizedBatch> {
-        self.add_loopback_rules()?;
-        self.add_split_tunneling_rules(policy)?;
+    pub fn finalize(mut self, policy: &FirewallPolicy) -> Vec<RuleSpec> {
+        self.add_log_dns_rules(policy);
+        self.add_loopback_rules();
+        self.add_split_tunneling_rules(policy);
         self.add_dhcp_client_rules();
         self.add_ndp_rules();
-        self.add_policy_specific_rules(policy)?;
+        self.add_policy_specific_rules(policy);
 
-        Ok(self.batch.finalize())
+        self.rules
+    }
+
+    /// Returns a match for the packets of processes in the exclusion profile at position `index`.
+    /// Returns `None` if the cgroup of the profile does not exist, since sockets cannot be matched
+    /// against it in the cgroup v2 hierarchy.
+    fn profile_match(&self, index: usize, profile: &ExclusionProfile) -> Option<Match> {
+        match self.exclusion_cgroup {
+            Some(ref cgroup @ ExclusionCgroup::V2(ref mount_path)) => {
+                if !cgroup.profile_path(&profile.name).exists() {
+                    log::warn!("Missing cgroup for exclusion profile \"{}\"", profile.name);
+                    return None;
+                }
+                Some(Match::SocketCgroupV2(
+                    mount_path.clone(),
+                    ExclusionProfile::cgroup_name(&profile.name),
+                ))
+            }
+            _ => Some(Match::Cgroup(split_tunnel::profile_classid(index))),
+        }
+    }
+
+    /// Excludes the packets matched by `exclusion_match` from the tunnel.
+    fn add_exclusion_rules(&mut self, exclusion_match: Match) {
+        for chain in &[ChainId::MangleV4, ChainId::MangleV6] {
+            let mut rule = RuleSpec::new(*chain);
+            rule.matches.push(exclusion_match.clone());
+            add_exclusion_marks(&mut rule);
+            self.rules.push(rule);
+        }
     }
 
-    fn add_split_tunneling_rules(&mut self, policy: &FirewallPolicy) -> Result<()> {
+    fn add_split_tunneling_rules(&mut self, policy: &FirewallPolicy) {
+        let (bypass_dns_profiles, tunnel_dns_profiles): (Vec<_>, Vec<_>) = policy
+            .exclusion_profiles()
+            .iter()
+            .enumerate()
+            .filter_map(|(index, profile)| Some((self.profile_match(index, profile)?, profile.dns)))
+            .partition(|(_, dns)| *dns == ProfileDns::Bypass);
+
+        // Exclude all traffic of these profiles, before DNS requests are kept in the tunnel
+        for (profile_match, _) in bypass_dns_profiles {
+            self.add_exclusion_rules(profile_match);
+        }
+
         // Send select DNS requests in the tunnel
         if let FirewallPolicy::Connected {
             tunnel,
@@ -334,230 +1223,380 @@ impl<'a> PolicyBatch<'a> {
         {
             for server in dns_servers
                 .iter()
-                .filter(|server| !is_local_dns_address(&tunnel, server))
+                .filter(|server| !is_local_dns_address(&tunnel, policy.lan_networks(), server))
             {
                 let chain = if server.is_ipv4() {
-                    &self.mangle_chain_v4
+                    ChainId::MangleV4
                 } else {
-                    &self.mangle_chain_v6
+                    ChainId::MangleV6
                 };
-                let allow_rule = allow_tunnel_dns_rule(
+                self.rules.push(allow_tunnel_dns_rule(
                     chain,
                     &tunnel.interface,
                     TransportProtocol::Udp,
                     *server,
-                )?;
-                self.batch.add(&allow_rule, nftnl::MsgType::Add);
-                let allow_rule = allow_tunnel_dns_rule(
+                ));
+                self.rules.push(allow_tunnel_dns_rule(
                     chain,
                     &tunnel.interface,
                     TransportProtocol::Tcp,
                     *server,
-                )?;
-                self.batch.add(&allow_rule, nftnl::MsgType::Add);
+                ));
             }
         }
 
-        let mangle_chains = [&self.mangle_chain_v4, &self.mangle_chain_v6];
-        for chain in &mangle_chains {
-            let mut rule = Rule::new(chain);
-            rule.add_expr(&nft_expr!(meta cgroup));
-            rule.add_expr(&nft_expr!(cmp == split_tunnel::NET_CLS_CLASSID));
-            rule.add_expr(&nft_expr!(immediate data split_tunnel::MARK));
-            rule.add_expr(&nft_expr!(ct mark set));
-            rule.add_expr(&nft_expr!(immediate data crate::linux::TUNNEL_FW_MARK));
-            rule.add_expr(&nft_expr!(meta mark set));
-            self.batch.add(&rule, nftnl::MsgType::Add);
+        // Route traffic to excluded destinations outside the tunnel, regardless of which process
+        // sends it
+        for network in &policy.excluded_destinations().networks {
+            let chain = if network.is_ipv4() {
+                ChainId::MangleV4
+            } else {
+                ChainId::MangleV6
+            };
+            let mut rule = RuleSpec::new(chain);
+            check_net(&mut rule, End::Dst, *network);
+            add_exclusion_marks(&mut rule);
+            self.rules.push(rule);
         }
 
-        for chain in &[&self.in_chain, &self.out_chain] {
-            let mut rule = Rule::new(chain);
-            rule.add_expr(&nft_expr!(ct mark));
-            rule.add_expr(&nft_expr!(cmp == split_tunnel::MARK));
-            add_verdict(&mut rule, &Verdict::Accept);
-            self.batch.add(&rule, nftnl::MsgType::Add);
+        for (profile_match, _) in tunnel_dns_profiles {
+            self.add_exclusion_rules(profile_match);
         }
 
-        let nat_chains = [&self.nat_chain_v4, &self.nat_chain_v6];
-        for chain in &nat_chains {
+        let cgroup_match = match self.exclusion_cgroup {
+            Some(ExclusionCgroup::V2(ref mount_path)) => {
+                Match::SocketCgroupV2(mount_path.clone(), SPLIT_TUNNEL_CGROUP_NAME.to_owned())
+            }
+            _ => Match::Cgroup(split_tunnel::NET_CLS_CLASSID),
+        };
+        // Include-only mode lets all other traffic bypass the kill switch, so it is only applied
+        // while there is a tunnel to include traffic in. When blocking, only processes that are
+        // explicitly excluded may bypass the firewall.
+        let split_tunnel_mode = match policy {
+            FirewallPolicy::Connecting { .. } | FirewallPolicy::Connected { .. } => {
+                policy.split_tunnel_mode()
+            }
+            FirewallPolicy::Blocked { .. } | FirewallPolicy::CaptivePortal { .. } => {
+                SplitTunnelMode::Exclude
+            }
+        };
+        for chain in &[ChainId::MangleV4, ChainId::MangleV6] {
+            match split_tunnel_mode {
+                SplitTunnelMode::Exclude => {
+                    let mut rule = RuleSpec::new(*chain);
+                    rule.matches.push(cgroup_match.clone());
+                    add_exclusion_marks(&mut rule);
+                    self.rules.push(rule);
+                }
+                // Only processes in the cgroup are subject to the kill switch. Their packets
+                // are left unmarked, and all other packets are marked and treated like those
+                // of excluded processes. That is, they are routed outside the tunnel and
+                // accepted regardless of the tunnel state.
+                SplitTunnelMode::IncludeOnly => {
+                    let mut include_rule = RuleSpec::new(*chain);
+                    include_rule.matches.push(cgroup_match.clone());
+                    add_verdict(&mut include_rule, Verdict::Accept);
+                    self.rules.push(include_rule);
+
+                    // Traffic owned by root is never excluded. This includes the API and DoH
+                    // traffic of the daemon, which runs as root, as well as traffic from kernel
+                    // sockets and system services.
+                    let mut root_rule = RuleSpec::new(*chain);
+                    root_rule.matches.push(Match::Skuid(super::ROOT_UID));
+                    add_verdict(&mut root_rule, Verdict::Accept);
+                    self.rules.push(root_rule);
+
+                    let mut exclude_rule = RuleSpec::new(*chain);
+                    add_exclusion_marks(&mut exclude_rule);
+                    self.rules.push(exclude_rule);
+                }
+            }
+        }
+
+        for chain in &[ChainId::In, ChainId::Out] {
+            let mut rule = RuleSpec::new(*chain);
+            rule.matches.push(Match::CtMark(split_tunnel::MARK as u32));
+            add_verdict(&mut rule, Verdict::Accept);
+            self.rules.push(rule);
+        }
+
+        for chain in &[ChainId::NatV4, ChainId::NatV6] {
             // Block remaining marked outgoing in-tunnel traffic
             if let FirewallPolicy::Connected { tunnel, .. } = policy {
-                let mut block_tunnel_rule = Rule::new(chain);
-                check_iface(&mut block_tunnel_rule, Direction::Out, &tunnel.interface)?;
-                block_tunnel_rule.add_expr(&nft_expr!(ct mark));
-                block_tunnel_rule.add_expr(&nft_expr!(cmp == split_tunnel::MARK));
-                add_verdict(&mut block_tunnel_rule, &Verdict::Drop);
-                self.batch.add(&block_tunnel_rule, nftnl::MsgType::Add);
+                let mut block_tunnel_rule = RuleSpec::new(*chain);
+                check_iface(&mut block_tunnel_rule, Direction::Out, &tunnel.interface);
+                block_tunnel_rule
+                    .matches
+                    .push(Match::CtMark(split_tunnel::MARK as u32));
+                add_verdict(&mut block_tunnel_rule, Verdict::Drop);
+                self.rules.push(block_tunnel_rule);
             }
 
             // Replace source IP address in rerouted packets.
             // Don't masquerade packets on the loopback device.
-            let mut rule = Rule::new(chain);
-
-            let iface_index = crate::linux::iface_index("lo")
-                .map_err(|e| Error::LookupIfaceIndexError("lo".to_string(), e))?;
-            rule.add_expr(&nft_expr!(meta oif));
-            rule.add_expr(&nft_expr!(cmp != iface_index));
-
-            rule.add_expr(&nft_expr!(ct mark));
-            rule.add_expr(&nft_expr!(cmp == split_tunnel::MARK));
-
-            rule.add_expr(&nft_expr!(masquerade));
-            if *ADD_COUNTERS {
-                rule.add_expr(&nft_expr!(counter));
-            }
-            self.batch.add(&rule, nftnl::MsgType::Add);
+            let mut rule = RuleSpec::new(*chain);
+            check_not_iface(&mut rule, Direction::Out, "lo");
+            rule.matches.push(Match::CtMark(split_tunnel::MARK as u32));
+            rule.statements.push(Statement::Masquerade);
+            add_counter(&mut rule);
+            self.rules.push(rule);
         }
 
         // Route incoming traffic correctly to prevent strict rpf from rejecting packets
         // for excluded processes
         if let FirewallPolicy::Connected { tunnel, .. } = policy {
-            let mut prerouting_rule = Rule::new(&self.prerouting_chain);
-            check_not_iface(&mut prerouting_rule, Direction::In, &tunnel.interface)?;
-            prerouting_rule.add_expr(&nft_expr!(ct mark));
-            prerouting_rule.add_expr(&nft_expr!(cmp == split_tunnel::MARK));
-            prerouting_rule.add_expr(&nft_expr!(immediate data crate::linux::TUNNEL_FW_MARK));
-            prerouting_rule.add_expr(&nft_expr!(meta mark set));
-            if *ADD_COUNTERS {
-                prerouting_rule.add_expr(&nft_expr!(counter));
-            }
-            self.batch.add(&prerouting_rule, nftnl::MsgType::Add);
+            let mut prerouting_rule = RuleSpec::new(ChainId::Prerouting);
+            check_not_iface(&mut prerouting_rule, Direction::In, &tunnel.interface);
+            prerouting_rule
+                .matches
+                .push(Match::CtMark(split_tunnel::MARK as u32));
+            prerouting_rule
+                .statements
+                .push(Statement::SetMetaMark(crate::linux::TUNNEL_FW_MARK));
+            add_counter(&mut prerouting_rule);
+            self.rules.push(prerouting_rule);
         }
+    }
 
-        Ok(())
+    /// Logs DNS queries to and responses from the DNS servers in the tunnel to NFLOG while there
+    /// are excluded domains, so that the addresses they resolve to can be excluded from the tunnel
+    /// as well. Only traffic on the tunnel interface is logged, so that responses cannot be
+    /// spoofed by other hosts on the local network. This comes first, since the packets must be
+    /// logged before they are accepted.
+    fn add_log_dns_rules(&mut self, policy: &FirewallPolicy) {
+        let (tunnel, dns_servers) = match policy {
+            FirewallPolicy::Connected {
+                tunnel,
+                dns_servers,
+                ..
+            } if !policy.excluded_destinations().domains.is_empty() => (tunnel, dns_servers),
+            _ => return,
+        };
+        for server in dns_servers
+            .iter()
+            .filter(|server| !is_local_dns_address(tunnel, policy.lan_networks(), server))
+        {
+            let mut query_rule = RuleSpec::new(ChainId::Out);
+            check_iface(&mut query_rule, Direction::Out, &tunnel.interface);
+            check_port(&mut query_rule, TransportProtocol::Udp, End::Dst, 53);
+            check_ip(&mut query_rule, End::Dst, *server);
+            query_rule.statements.push(Statement::Log(DNS_NFLOG_GROUP));
+            self.rules.push(query_rule);
+
+            let mut response_rule = RuleSpec::new(ChainId::In);
+            check_iface(&mut response_rule, Direction::In, &tunnel.interface);
+            check_port(&mut response_rule, TransportProtocol::Udp, End::Src, 53);
+            check_ip(&mut response_rule, End::Src, *server);
+            response_rule
+                .statements
+                .push(Statement::Log(DNS_NFLOG_GROUP));
+            self.rules.push(response_rule);
+        }
     }
 
-    fn add_loopback_rules(&mut self) -> Result<()> {
+    fn add_loopback_rules(&mut self) {
         const LOOPBACK_IFACE_NAME: &str = "lo";
-        self.batch.add(
-            &allow_interface_rule(&self.out_chain, Direction::Out, LOOPBACK_IFACE_NAME)?,
-            nftnl::MsgType::Add,
-        );
-        self.batch.add(
-            &allow_interface_rule(&self.in_chain, Direction::In, LOOPBACK_IFACE_NAME)?,
-            nftnl::MsgType::Add,
-        );
-        Ok(())
+        self.rules.push(allow_interface_rule(
+            ChainId::Out,
+            Direction::Out,
+            LOOPBACK_IFACE_NAME,
+        ));
+        self.rules.push(allow_interface_rule(
+            ChainId::In,
+            Direction::In,
+            LOOPBACK_IFACE_NAME,
+        ));
     }
 
     fn add_dhcp_client_rules(&mut self) {
         use self::TransportProtocol::Udp;
         // Outgoing DHCPv4 request
-        for chain in &[&self.out_chain, &self.forward_chain] {
-            let mut out_v4 = Rule::new(chain);
+        for chain in &[ChainId::Out, ChainId::Forward] {
+            let mut out_v4 = RuleSpec::new(*chain);
             check_port(&mut out_v4, Udp, End::Src, super::DHCPV4_CLIENT_PORT);
             check_ip(&mut out_v4, End::Dst, IpAddr::V4(Ipv4Addr::BROADCAST));
             check_port(&mut out_v4, Udp, End::Dst, super::DHCPV4_SERVER_PORT);
-            add_verdict(&mut out_v4, &Verdict::Accept);
-            self.batch.add(&out_v4, nftnl::MsgType::Add);
+            add_verdict(&mut out_v4, Verdict::Accept);
+            self.rules.push(out_v4);
         }
         // Incoming DHCPv4 response
-        for chain in &[&self.in_chain, &self.forward_chain] {
-            let mut in_v4 = Rule::new(chain);
+        for chain in &[ChainId::In, ChainId::Forward] {
+            let mut in_v4 = RuleSpec::new(*chain);
             check_port(&mut in_v4, Udp, End::Src, super::DHCPV4_SERVER_PORT);
             check_port(&mut in_v4, Udp, End::Dst, super::DHCPV4_CLIENT_PORT);
-            add_verdict(&mut in_v4, &Verdict::Accept);
-            self.batch.add(&in_v4, nftnl::MsgType::Add);
+            add_verdict(&mut in_v4, Verdict::Accept);
+            self.rules.push(in_v4);
         }
 
-        for chain in &[&self.out_chain, &self.forward_chain] {
+        for chain in &[ChainId::Out, ChainId::Forward] {
             for dhcpv6_server in &*super::DHCPV6_SERVER_ADDRS {
-                let mut out_v6 = Rule::new(chain);
+                let mut out_v6 = RuleSpec::new(*chain);
                 check_net(&mut out_v6, End::Src, *super::IPV6_LINK_LOCAL);
                 check_port(&mut out_v6, Udp, End::Src, super::DHCPV6_CLIENT_PORT);
                 check_ip(&mut out_v6, End::Dst, *dhcpv6_server);
                 check_port(&mut out_v6, Udp, End::Dst, super::DHCPV6_SERVER_PORT);
-                add_verdict(&mut out_v6, &Verdict::Accept);
-                self.batch.add(&out_v6, nftnl::MsgType::Add);
+                add_verdict(&mut out_v6, Verdict::Accept);
+                self.rules.push(out_v6);
             }
         }
-        for chain in &[&self.in_chain, &self.forward_chain] {
-            let mut in_v6 = Rule::new(chain);
+        for chain in &[ChainId::In, ChainId::Forward] {
+            let mut in_v6 = RuleSpec::new(*chain);
             check_net(&mut in_v6, End::Src, *super::IPV6_LINK_LOCAL);
             check_port(&mut in_v6, Udp, End::Src, super::DHCPV6_SERVER_PORT);
             check_net(&mut in_v6, End::Dst, *super::IPV6_LINK_LOCAL);
             check_port(&mut in_v6, Udp, End::Dst, super::DHCPV6_CLIENT_PORT);
-            add_verdict(&mut in_v6, &Verdict::Accept);
-            self.batch.add(&in_v6, nftnl::MsgType::Add);
+            add_verdict(&mut in_v6, Verdict::Accept);
+            self.rules.push(in_v6);
         }
     }
 
     fn add_ndp_rules(&mut self) {
         // Outgoing Router solicitation (part of NDP)
-        for chain in &[&self.out_chain, &self.forward_chain] {
-            let mut rule = Rule::new(chain);
+        for chain in &[ChainId::Out, ChainId::Forward] {
+            let mut rule = RuleSpec::new(*chain);
             check_ip(
                 &mut rule,
                 End::Dst,
                 *super::ROUTER_SOLICITATION_OUT_DST_ADDR,
             );
             check_icmpv6(&mut rule, 133, 0);
-            add_verdict(&mut rule, &Verdict::Accept);
-            self.batch.add(&rule, nftnl::MsgType::Add);
+            add_verdict(&mut rule, Verdict::Accept);
+            self.rules.push(rule);
         }
         // Incoming Router advertisement (part of NDP)
-        for chain in &[&self.in_chain, &self.forward_chain] {
-            let mut rule = Rule::new(chain);
+        for chain in &[ChainId::In, ChainId::Forward] {
+            let mut rule = RuleSpec::new(*chain);
             check_net(&mut rule, End::Src, *super::IPV6_LINK_LOCAL);
             check_icmpv6(&mut rule, 134, 0);
-            add_verdict(&mut rule, &Verdict::Accept);
-            self.batch.add(&rule, nftnl::MsgType::Add);
+            add_verdict(&mut rule, Verdict::Accept);
+            self.rules.push(rule);
         }
         // Incoming Redirect (part of NDP)
-        for chain in &[&self.in_chain, &self.forward_chain] {
-            let mut rule = Rule::new(chain);
+        for chain in &[ChainId::In, ChainId::Forward] {
+            let mut rule = RuleSpec::new(*chain);
             check_net(&mut rule, End::Src, *super::IPV6_LINK_LOCAL);
             check_icmpv6(&mut rule, 137, 0);
-            add_verdict(&mut rule, &Verdict::Accept);
-            self.batch.add(&rule, nftnl::MsgType::Add);
+            add_verdict(&mut rule, Verdict::Accept);
+            self.rules.push(rule);
         }
         // Outgoing Neighbor solicitation (part of NDP)
-        for chain in &[&self.out_chain, &self.forward_chain] {
-            let mut rule = Rule::new(chain);
+        for chain in &[ChainId::Out, ChainId::Forward] {
+            let mut rule = RuleSpec::new(*chain);
             check_net(&mut rule, End::Dst, *super::SOLICITED_NODE_MULTICAST);
             check_icmpv6(&mut rule, 135, 0);
-            add_verdict(&mut rule, &Verdict::Accept);
-            self.batch.add(&rule, nftnl::MsgType::Add);
+            add_verdict(&mut rule, Verdict::Accept);
+            self.rules.push(rule);
         }
-        for chain in &[&self.out_chain, &self.forward_chain] {
-            let mut rule = Rule::new(chain);
+        for chain in &[ChainId::Out, ChainId::Forward] {
+            let mut rule = RuleSpec::new(*chain);
             check_net(&mut rule, End::Dst, *super::IPV6_LINK_LOCAL);
             check_icmpv6(&mut rule, 135, 0);
-            add_verdict(&mut rule, &Verdict::Accept);
-            self.batch.add(&rule, nftnl::MsgType::Add);
+            add_verdict(&mut rule, Verdict::Accept);
+            self.rules.push(rule);
         }
         // Incoming Neighbor solicitation (part of NDP)
-        for chain in &[&self.in_chain, &self.forward_chain] {
-            let mut rule = Rule::new(chain);
+        for chain in &[ChainId::In, ChainId::Forward] {
+            let mut rule = RuleSpec::new(*chain);
             check_net(&mut rule, End::Src, *super::IPV6_LINK_LOCAL);
             check_icmpv6(&mut rule, 135, 0);
-            add_verdict(&mut rule, &Verdict::Accept);
-            self.batch.add(&rule, nftnl::MsgType::Add);
+            add_verdict(&mut rule, Verdict::Accept);
+            self.rules.push(rule);
         }
         // Outgoing Neighbor advertisement (part of NDP)
-        for chain in &[&self.out_chain, &self.forward_chain] {
-            let mut rule = Rule::new(chain);
+        for chain in &[ChainId::Out, ChainId::Forward] {
+            let mut rule = RuleSpec::new(*chain);
             check_net(&mut rule, End::Dst, *super::IPV6_LINK_LOCAL);
             check_icmpv6(&mut rule, 136, 0);
-            add_verdict(&mut rule, &Verdict::Accept);
-            self.batch.add(&rule, nftnl::MsgType::Add);
+            add_verdict(&mut rule, Verdict::Accept);
+            self.rules.push(rule);
         }
         // Incoming Neighbor advertisement (part of NDP)
-        for chain in &[&self.in_chain, &self.forward_chain] {
-            let mut rule = Rule::new(chain);
+        for chain in &[ChainId::In, ChainId::Forward] {
+            let mut rule = RuleSpec::new(*chain);
             check_icmpv6(&mut rule, 136, 0);
-            add_verdict(&mut rule, &Verdict::Accept);
-            self.batch.add(&rule, nftnl::MsgType::Add);
+            add_verdict(&mut rule, Verdict::Accept);
+            self.rules.push(rule);
         }
     }
 
-    fn add_policy_specific_rules(&mut self, policy: &FirewallPolicy) -> Result<()> {
+    /// Adds the user-defined exceptions to the policy. These come after the rules blocking DNS,
+    /// so that DNS cannot leak to the allowed networks, but before the rules blocking all
+    /// remaining traffic.
+    fn add_allow_rules(&mut self, policy: &FirewallPolicy) {
+        for allow_rule in policy.allow_rules() {
+            if let Err(error) = allow_rule.validate() {
+                log::warn!("Ignoring firewall allow rule {}: {}", allow_rule, error);
+                continue;
+            }
+
+            // The network is matched on the remote end, and the port on the remote end for
+            // outbound rules and on the local end for inbound rules.
+            let (request_chains, reply_chains, remote_end): (&[ChainId], &[ChainId], End) =
+                match allow_rule.direction {
+                    AllowedDirection::Outbound => (
+                        &[ChainId::Out, ChainId::Forward],
+                        &[ChainId::In, ChainId::Forward],
+                        End::Dst,
+                    ),
+                    AllowedDirection::Inbound => (&[ChainId::In], &[ChainId::Out], End::Src),
+                };
+
+            for chain in request_chains {
+                let mut rule = RuleSpec::new(*chain);
+                check_allow_rule(&mut rule, allow_rule, remote_end, End::Dst);
+                add_verdict(&mut rule, Verdict::Accept);
+                self.rules.push(rule);
+            }
+            for chain in reply_chains {
+                let mut rule = RuleSpec::new(*chain);
+                check_allow_rule(&mut rule, allow_rule, remote_end.opposite(), End::Src);
+                rule.matches.push(Match::Established);
+                add_verdict(&mut rule, Verdict::Accept);
+                self.rules.push(rule);
+            }
+
+            // Treat outgoing packets like those of excluded processes, so that they are routed
+            // outside the tunnel and have their source address replaced. DNS requests are left
+            // unmarked, so that they are still subject to the rules blocking DNS.
+            if allow_rule.outside_tunnel {
+                let chain = if allow_rule.network.is_ipv4() {
+                    ChainId::MangleV4
+                } else {
+                    ChainId::MangleV6
+                };
+                let may_match_dns = match (allow_rule.direction, allow_rule.port) {
+                    (AllowedDirection::Outbound, Some(port)) => port == 53,
+                    _ => true,
+                };
+                for protocol in [TransportProtocol::Udp, TransportProtocol::Tcp] {
+                    if may_match_dns && allow_rule.protocol.map_or(true, |p| p == protocol) {
+                        let mut dns_rule = RuleSpec::new(chain);
+                        check_net(&mut dns_rule, End::Dst, allow_rule.network);
+                        check_port(&mut dns_rule, protocol, End::Dst, 53);
+                        add_verdict(&mut dns_rule, Verdict::Accept);
+                        self.rules.push(dns_rule);
+                    }
+                }
+                let port_end = match allow_rule.direction {
+                    AllowedDirection::Outbound => End::Dst,
+                    AllowedDirection::Inbound => End::Src,
+                };
+                let mut rule = RuleSpec::new(chain);
+                check_allow_rule(&mut rule, allow_rule, End::Dst, port_end);
+                add_exclusion_marks(&mut rule);
+                self.rules.push(rule);
+            }
+        }
+    }
+
+    fn add_policy_specific_rules(&mut self, policy: &FirewallPolicy) {
         let allow_lan = match policy {
             FirewallPolicy::Connecting {
                 peer_endpoint,
                 tunnel,
                 allow_lan,
                 allowed_endpoint,
+                ..
             } => {
                 self.add_allow_tunnel_endpoint_rules(peer_endpoint);
                 self.add_allow_endpoint_rules(&allowed_endpoint.endpoint);
@@ -567,7 +1606,7 @@ impl<'a> PolicyBatch<'a> {
                 self.add_drop_dns_rule();
 
                 if let Some(tunnel) = tunnel {
-                    self.add_allow_tunnel_rules(&tunnel.interface)?;
+                    self.add_allow_tunnel_rules(&tunnel.interface);
                     if *allow_lan {
                         self.add_block_cve_2019_14899(tunnel);
                     }
@@ -579,14 +1618,32 @@ impl<'a> PolicyBatch<'a> {
                 tunnel,
                 allow_lan,
                 dns_servers,
+                split_dns_resolvers,
+                ..
             } => {
                 self.add_allow_tunnel_endpoint_rules(peer_endpoint);
-                self.add_allow_dns_rules(tunnel, &dns_servers, TransportProtocol::Udp)?;
-                self.add_allow_dns_rules(tunnel, &dns_servers, TransportProtocol::Tcp)?;
+                let lan_networks = policy.lan_networks();
+                self.add_allow_dns_rules(
+                    tunnel,
+                    lan_networks,
+                    &dns_servers,
+                    TransportProtocol::Udp,
+                );
+                self.add_allow_dns_rules(
+                    tunnel,
+                    lan_networks,
+                    &dns_servers,
+                    TransportProtocol::Tcp,
+                );
+                for resolver in split_dns_resolvers {
+                    for protocol in [TransportProtocol::Udp, TransportProtocol::Tcp] {
+                        self.add_allow_local_dns_rule(&tunnel.interface, protocol, *resolver);
+                    }
+                }
                 // Important to block DNS *before* we allow the tunnel and allow LAN. So DNS
                 // can't leak to the wrong IPs in the tunnel or on the LAN.
                 self.add_drop_dns_rule();
-                self.add_allow_tunnel_rules(&tunnel.interface)?;
+                self.add_allow_tunnel_rules(&tunnel.interface);
                 if *allow_lan {
                     self.add_block_cve_2019_14899(tunnel);
                 }
@@ -595,6 +1652,7 @@ impl<'a> PolicyBatch<'a> {
             FirewallPolicy::Blocked {
                 allow_lan,
                 allowed_endpoint,
+                ..
             } => {
                 self.add_allow_endpoint_rules(&allowed_endpoint.endpoint);
 
@@ -602,101 +1660,140 @@ impl<'a> PolicyBatch<'a> {
                 self.add_drop_dns_rule();
                 *allow_lan
             }
+            FirewallPolicy::CaptivePortal {
+                allow_lan,
+                allowed_endpoint,
+                portal_hosts,
+                ..
+            } => {
+                self.add_allow_endpoint_rules(&allowed_endpoint.endpoint);
+                self.add_allow_captive_portal_rules(portal_hosts);
+
+                self.add_drop_dns_rule();
+                *allow_lan
+            }
         };
 
+        self.add_allow_rules(policy);
+
         if allow_lan {
-            self.add_allow_lan_rules();
+            self.add_allow_lan_rules(policy.lan_networks());
         }
 
+        self.add_log_blocked_rules();
+
         // Reject any remaining outgoing traffic
-        for chain in &[&self.out_chain, &self.forward_chain] {
-            let mut reject_rule = Rule::new(chain);
-            add_verdict(
-                &mut reject_rule,
-                &Verdict::Reject(RejectionType::Icmp(IcmpCode::PortUnreach)),
-            );
-            self.batch.add(&reject_rule, nftnl::MsgType::Add);
+        for chain in &[ChainId::Out, ChainId::Forward] {
+            let mut reject_rule = RuleSpec::new(*chain);
+            add_verdict(&mut reject_rule, Verdict::RejectPortUnreach);
+            self.rules.push(reject_rule);
         }
+    }
 
-        Ok(())
+    /// Logs a limited number of the outgoing packets that are about to be rejected to NFLOG, so
+    /// that the daemon can report what is being blocked. Also drops incoming packets explicitly,
+    /// rather than relying on the chain policy, so that they can be counted.
+    fn add_log_blocked_rules(&mut self) {
+        for chain in &[ChainId::Out, ChainId::Forward] {
+            let mut log_rule = RuleSpec::new(*chain);
+            log_rule.matches.push(Match::Limit(BLOCKED_LOG_RATE));
+            log_rule.statements.push(Statement::Log(NFLOG_GROUP));
+            add_counter(&mut log_rule);
+            self.rules.push(log_rule);
+        }
+
+        let mut drop_rule = RuleSpec::new(ChainId::In);
+        add_verdict(&mut drop_rule, Verdict::Drop);
+        self.rules.push(drop_rule);
     }
 
     fn add_allow_tunnel_endpoint_rules(&mut self, endpoint: &Endpoint) {
-        let mut prerouting_rule = Rule::new(&self.prerouting_chain);
+        let mut prerouting_rule = RuleSpec::new(ChainId::Prerouting);
         check_endpoint(&mut prerouting_rule, End::Src, endpoint);
-        prerouting_rule.add_expr(&nft_expr!(immediate data crate::linux::TUNNEL_FW_MARK));
-        prerouting_rule.add_expr(&nft_expr!(meta mark set));
-
-        if *ADD_COUNTERS {
-            prerouting_rule.add_expr(&nft_expr!(counter));
-        }
+        prerouting_rule
+            .statements
+            .push(Statement::SetMetaMark(crate::linux::TUNNEL_FW_MARK));
+        add_counter(&mut prerouting_rule);
+        self.rules.push(prerouting_rule);
 
-        self.batch.add(&prerouting_rule, nftnl::MsgType::Add);
-
-        let mut in_rule = Rule::new(&self.in_chain);
+        let mut in_rule = RuleSpec::new(ChainId::In);
         check_endpoint(&mut in_rule, End::Src, endpoint);
+        in_rule.matches.push(Match::Established);
+        add_verdict(&mut in_rule, Verdict::Accept);
+        self.rules.push(in_rule);
 
-        in_rule.add_expr(&nft_expr!(ct state));
-        let allowed_states = nftnl::expr::ct::States::ESTABLISHED.bits();
-        in_rule.add_expr(&nft_expr!(bitwise mask allowed_states, xor 0u32));
-        in_rule.add_expr(&nft_expr!(cmp != 0u32));
-        add_verdict(&mut in_rule, &Verdict::Accept);
-
-        self.batch.add(&in_rule, nftnl::MsgType::Add);
-
-        let mut out_rule = Rule::new(&self.out_chain);
+        let mut out_rule = RuleSpec::new(ChainId::Out);
         check_endpoint(&mut out_rule, End::Dst, endpoint);
-        out_rule.add_expr(&nft_expr!(meta mark));
-        out_rule.add_expr(&nft_expr!(cmp == crate::linux::TUNNEL_FW_MARK));
-        add_verdict(&mut out_rule, &Verdict::Accept);
-
-        self.batch.add(&out_rule, nftnl::MsgType::Add);
+        out_rule
+            .matches
+            .push(Match::MetaMark(crate::linux::TUNNEL_FW_MARK));
+        add_verdict(&mut out_rule, Verdict::Accept);
+        self.rules.push(out_rule);
     }
 
     /// Adds firewall rules allow traffic to flow to the API. Allows the app to reach the API in
     /// blocked states.
     fn add_allow_endpoint_rules(&mut self, endpoint: &Endpoint) {
-        let mut in_rule = Rule::new(&self.in_chain);
+        let mut in_rule = RuleSpec::new(ChainId::In);
         check_endpoint(&mut in_rule, End::Src, endpoint);
-        let allowed_states = nftnl::expr::ct::States::ESTABLISHED.bits();
-        in_rule.add_expr(&nft_expr!(ct state));
-        in_rule.add_expr(&nft_expr!(bitwise mask allowed_states, xor 0u32));
-        in_rule.add_expr(&nft_expr!(cmp != 0u32));
-        in_rule.add_expr(&nft_expr!(meta skuid));
-        in_rule.add_expr(&nft_expr!(cmp == super::ROOT_UID));
+        in_rule.matches.push(Match::Established);
+        in_rule.matches.push(Match::Skuid(super::ROOT_UID));
+        add_verdict(&mut in_rule, Verdict::Accept);
+        self.rules.push(in_rule);
 
-        add_verdict(&mut in_rule, &Verdict::Accept);
-
-        self.batch.add(&in_rule, nftnl::MsgType::Add);
-
-        let mut out_rule = Rule::new(&self.out_chain);
+        let mut out_rule = RuleSpec::new(ChainId::Out);
         check_endpoint(&mut out_rule, End::Dst, endpoint);
-        out_rule.add_expr(&nft_expr!(meta skuid));
-        out_rule.add_expr(&nft_expr!(cmp == super::ROOT_UID));
-        add_verdict(&mut out_rule, &Verdict::Accept);
+        out_rule.matches.push(Match::Skuid(super::ROOT_UID));
+        add_verdict(&mut out_rule, Verdict::Accept);
+        self.rules.push(out_rule);
+    }
 
-        self.batch.add(&out_rule, nftnl::MsgType::Add);
+    /// Adds firewall rules that allow DNS, HTTP and HTTPS traffic to the given hosts, so that the
+    /// user can log in to a captive portal.
+    fn add_allow_captive_portal_rules(&mut self, hosts: &[IpAddr]) {
+        const PORTAL_PORTS: [(TransportProtocol, u16); 4] = [
+            (TransportProtocol::Udp, 53),
+            (TransportProtocol::Tcp, 53),
+            (TransportProtocol::Tcp, 80),
+            (TransportProtocol::Tcp, 443),
+        ];
+
+        for host in hosts {
+            for (protocol, port) in &PORTAL_PORTS {
+                let mut out_rule = RuleSpec::new(ChainId::Out);
+                check_ip(&mut out_rule, End::Dst, *host);
+                check_port(&mut out_rule, *protocol, End::Dst, *port);
+                add_verdict(&mut out_rule, Verdict::Accept);
+                self.rules.push(out_rule);
+
+                let mut in_rule = RuleSpec::new(ChainId::In);
+                check_ip(&mut in_rule, End::Src, *host);
+                check_port(&mut in_rule, *protocol, End::Src, *port);
+                in_rule.matches.push(Match::Established);
+                add_verdict(&mut in_rule, Verdict::Accept);
+                self.rules.push(in_rule);
+            }
+        }
     }
 
     fn add_allow_dns_rules(
         &mut self,
         tunnel: &tunnel::TunnelMetadata,
+        lan_networks: &LanNetworks,
         dns_servers: &[IpAddr],
         protocol: TransportProtocol,
-    ) -> Result<()> {
+    ) {
         let (local_resolvers, remote_resolvers): (Vec<IpAddr>, Vec<IpAddr>) = dns_servers
             .iter()
-            .partition(|server| is_local_dns_address(tunnel, server));
+            .partition(|server| is_local_dns_address(tunnel, lan_networks, server));
 
         for resolver in &local_resolvers {
-            self.add_allow_local_dns_rule(&tunnel.interface, protocol, *resolver)?;
+            self.add_allow_local_dns_rule(&tunnel.interface, protocol, *resolver);
         }
 
         for resolver in &remote_resolvers {
-            self.add_allow_tunnel_dns_rule(&tunnel.interface, protocol, *resolver)?;
+            self.add_allow_tunnel_dns_rule(&tunnel.interface, protocol, *resolver);
         }
-
-        Ok(())
     }
 
     fn add_allow_tunnel_dns_rule(
@@ -704,12 +1801,11 @@ impl<'a> PolicyBatch<'a> {
         interface: &str,
         protocol: TransportProtocol,
         host: IpAddr,
-    ) -> Result<()> {
-        for chain in &[&self.out_chain, &self.forward_chain] {
-            let allow_rule = allow_tunnel_dns_rule(chain, interface, protocol, host)?;
-            self.batch.add(&allow_rule, nftnl::MsgType::Add);
+    ) {
+        for chain in &[ChainId::Out, ChainId::Forward] {
+            self.rules
+                .push(allow_tunnel_dns_rule(*chain, interface, protocol, host));
         }
-        Ok(())
     }
 
     fn add_allow_local_dns_rule(
@@ -717,84 +1813,67 @@ impl<'a> PolicyBatch<'a> {
         tunnel_interface: &str,
         protocol: TransportProtocol,
         host: IpAddr,
-    ) -> Result<()> {
+    ) {
         let chains = [
-            (&self.out_chain, Direction::Out),
-            (&self.forward_chain, Direction::Out),
-            (&self.in_chain, Direction::In),
-            (&self.forward_chain, Direction::In),
+            (ChainId::Out, Direction::Out),
+            (ChainId::Forward, Direction::Out),
+            (ChainId::In, Direction::In),
+            (ChainId::Forward, Direction::In),
         ];
 
         for (chain, direction) in &chains {
-            let mut allow_rule = Rule::new(chain);
-            let addr = match (host, direction) {
-                (IpAddr::V4(_), Direction::Out) => nft_expr!(payload ipv4 daddr),
-                (IpAddr::V6(_), Direction::Out) => nft_expr!(payload ipv6 daddr),
-                (IpAddr::V4(_), Direction::In) => nft_expr!(payload ipv4 saddr),
-                (IpAddr::V6(_), Direction::In) => nft_expr!(payload ipv6 saddr),
-            };
-
-            let port_dir = match direction {
+            let mut allow_rule = RuleSpec::new(*chain);
+            let end = match direction {
                 Direction::In => End::Src,
                 Direction::Out => End::Dst,
             };
 
-            check_not_iface(&mut allow_rule, *direction, tunnel_interface)?;
-            check_port(&mut allow_rule, protocol, port_dir, 53);
-            check_l3proto(&mut allow_rule, host);
+            check_not_iface(&mut allow_rule, *direction, tunnel_interface);
+            check_port(&mut allow_rule, protocol, end, 53);
+            check_ip(&mut allow_rule, end, host);
+            add_verdict(&mut allow_rule, Verdict::Accept);
 
-            allow_rule.add_expr(&addr);
-            allow_rule.add_expr(&nft_expr!(cmp == host));
-            add_verdict(&mut allow_rule, &Verdict::Accept);
-
-            self.batch.add(&allow_rule, nftnl::MsgType::Add);
+            self.rules.push(allow_rule);
         }
-
-        Ok(())
     }
 
     /// Blocks all outgoing DNS (port 53) on both TCP and UDP
     fn add_drop_dns_rule(&mut self) {
-        for chain in &[&self.out_chain, &self.forward_chain] {
-            let mut block_udp_rule = Rule::new(chain);
+        for chain in &[ChainId::Out, ChainId::Forward] {
+            let mut block_udp_rule = RuleSpec::new(*chain);
             check_port(&mut block_udp_rule, TransportProtocol::Udp, End::Dst, 53);
-            add_verdict(
-                &mut block_udp_rule,
-                &Verdict::Reject(RejectionType::Icmp(IcmpCode::PortUnreach)),
-            );
-            self.batch.add(&block_udp_rule, nftnl::MsgType::Add);
+            add_verdict(&mut block_udp_rule, Verdict::RejectPortUnreach);
+            self.rules.push(block_udp_rule);
 
-            let mut block_tcp_rule = Rule::new(chain);
+            let mut block_tcp_rule = RuleSpec::new(*chain);
             check_port(&mut block_tcp_rule, TransportProtocol::Tcp, End::Dst, 53);
-            add_verdict(&mut block_tcp_rule, &Verdict::Reject(RejectionType::TcpRst));
-            self.batch.add(&block_tcp_rule, nftnl::MsgType::Add);
+            add_verdict(&mut block_tcp_rule, Verdict::RejectTcpReset);
+            self.rules.push(block_tcp_rule);
         }
     }
 
-    fn add_allow_tunnel_rules(&mut self, tunnel_interface: &str) -> Result<()> {
-        self.batch.add(
-            &allow_interface_rule(&self.out_chain, Direction::Out, tunnel_interface)?,
-            nftnl::MsgType::Add,
-        );
-        self.batch.add(
-            &allow_interface_rule(&self.forward_chain, Direction::Out, tunnel_interface)?,
-            nftnl::MsgType::Add,
-        );
-        self.batch.add(
-            &allow_interface_rule(&self.in_chain, Direction::In, tunnel_interface)?,
-            nftnl::MsgType::Add,
-        );
-
-        let mut interface_rule = Rule::new(&self.forward_chain);
-        check_iface(&mut interface_rule, Direction::In, tunnel_interface)?;
-        interface_rule.add_expr(&nft_expr!(ct state));
-        let allowed_states = nftnl::expr::ct::States::ESTABLISHED.bits();
-        interface_rule.add_expr(&nft_expr!(bitwise mask allowed_states, xor 0u32));
-        interface_rule.add_expr(&nft_expr!(cmp != 0u32));
-        add_verdict(&mut interface_rule, &Verdict::Accept);
-        self.batch.add(&interface_rule, nftnl::MsgType::Add);
-
-        Ok(())
+    fn add_allow_tunnel_rules(&mut self, tunnel_interface: &str) {
+        self.rules.push(allow_interface_rule(
+            ChainId::Out,
+            Direction::Out,
+            tunnel_interface,
+        ));
+        self.rules.push(allow_interface_rule(
+            ChainId::Forward,
+            Direction::Out,
+            tunnel_interface,
+        ));
+        self.rules.push(allow_interface_rule(
+            ChainId::In,
+            Direction::In,
+            tunnel_interface,
+        ));
+
+        let mut interface_rule = RuleSpec::new(ChainId::Forward);
+        check_iface(&mut interface_rule, Direction::In, tunnel_interface);
+        interface_rule.matches.push(Match::Established);
+        add_verdict(&mut interface_rule, Verdict::Accept);
+        self.rules.push(interface_rule);
     }
 
     /// Adds rules for stopping [CVE-2019-14899](https://seclists.org/oss-sec/2019/q4/122).
@@ -804,40 +1883,40 @@ impl<'a> PolicyBatch<'a> {
     /// after the rule allowing the tunnel, otherwise even the tunnel can't talk to that IP.
     fn add_block_cve_2019_14899(&mut self, tunnel: &tunnel::TunnelMetadata) {
         for tunnel_ip in &tunnel.ips {
-            let mut rule = Rule::new(&self.in_chain);
+            let mut rule = RuleSpec::new(ChainId::In);
             check_ip(&mut rule, End::Dst, *tunnel_ip);
-            add_verdict(&mut rule, &Verdict::Drop);
-            self.batch.add(&rule, nftnl::MsgType::Add);
+            add_verdict(&mut rule, Verdict::Drop);
+            self.rules.push(rule);
         }
     }
 
-    fn add_allow_lan_rules(&mut self) {
+    fn add_allow_lan_rules(&mut self, lan_networks: &LanNetworks) {
         // Output and forward chains
-        for chain in &[&self.out_chain, &self.forward_chain] {
+        for chain in &[ChainId::Out, ChainId::Forward] {
             // LAN -> LAN
-            for net in &*super::ALLOWED_LAN_NETS {
-                let mut out_rule = Rule::new(chain);
+            for net in &lan_networks.networks {
+                let mut out_rule = RuleSpec::new(*chain);
                 check_net(&mut out_rule, End::Dst, *net);
-                add_verdict(&mut out_rule, &Verdict::Accept);
-                self.batch.add(&out_rule, nftnl::MsgType::Add);
+                add_verdict(&mut out_rule, Verdict::Accept);
+                self.rules.push(out_rule);
             }
 
             // LAN -> Multicast
-            for net in &*super::ALLOWED_LAN_MULTICAST_NETS {
-                let mut rule = Rule::new(chain);
+            for net in &lan_networks.multicast_networks {
+                let mut rule = RuleSpec::new(*chain);
                 check_net(&mut rule, End::Dst, *net);
-                add_verdict(&mut rule, &Verdict::Accept);
-                self.batch.add(&rule, nftnl::MsgType::Add);
+                add_verdict(&mut rule, Verdict::Accept);
+                self.rules.push(rule);
             }
         }
 
         // Input chain
         // LAN -> LAN
-        for net in &*super::ALLOWED_LAN_NETS {
-            let mut in_rule = Rule::new(&self.in_chain);
+        for net in &lan_networks.networks {
+            let mut in_rule = RuleSpec::new(ChainId::In);
             check_net(&mut in_rule, End::Src, *net);
-            add_verdict(&mut in_rule, &Verdict::Accept);
-            self.batch.add(&in_rule, nftnl::MsgType::Add);
+            add_verdict(&mut in_rule, Verdict::Accept);
+            self.rules.push(in_rule);
         }
         self.add_dhcp_server_rules();
     }
@@ -846,186 +1925,683 @@ impl<'a> PolicyBatch<'a> {
         use TransportProtocol::Udp;
         // Outgoing DHCPv4 response
         {
-            let mut out_v4 = Rule::new(&self.out_chain);
+            let mut out_v4 = RuleSpec::new(ChainId::Out);
             check_port(&mut out_v4, Udp, End::Src, super::DHCPV4_SERVER_PORT);
             check_port(&mut out_v4, Udp, End::Dst, super::DHCPV4_CLIENT_PORT);
-            add_verdict(&mut out_v4, &Verdict::Accept);
-            self.batch.add(&out_v4, nftnl::MsgType::Add);
+            add_verdict(&mut out_v4, Verdict::Accept);
+            self.rules.push(out_v4);
         }
         // Incoming DHCPv4 request
         {
-            let mut in_v4 = Rule::new(&self.in_chain);
+            let mut in_v4 = RuleSpec::new(ChainId::In);
             check_port(&mut in_v4, Udp, End::Src, super::DHCPV4_CLIENT_PORT);
             check_endpoint(
                 &mut in_v4,
                 End::Dst,
                 &Endpoint::new(Ipv4Addr::BROADCAST, super::DHCPV4_SERVER_PORT, Udp),
             );
-            add_verdict(&mut in_v4, &Verdict::Accept);
-            self.batch.add(&in_v4, nftnl::MsgType::Add);
+            add_verdict(&mut in_v4, Verdict::Accept);
+            self.rules.push(in_v4);
         }
     }
 }
 
-fn is_local_dns_address(tunnel: &tunnel::TunnelMetadata, server: &IpAddr) -> bool {
-    super::is_local_address(server)
+fn is_local_dns_address(
+    tunnel: &tunnel::TunnelMetadata,
+    lan_networks: &LanNetworks,
+    server: &IpAddr,
+) -> bool {
+    super::is_local_address(server, lan_networks)
         && server != &tunnel.ipv4_gateway
         && Some(server) != tunnel.ipv6_gateway.map(IpAddr::from).as_ref()
 }
 
-fn allow_tunnel_dns_rule<'a>(
-    chain: &'a Chain<'_>,
+fn allow_tunnel_dns_rule(
+    chain: ChainId,
     iface: &str,
     protocol: TransportProtocol,
     host: IpAddr,
-) -> Result<Rule<'a>> {
-    let mut rule = Rule::new(chain);
-    check_iface(&mut rule, Direction::Out, iface)?;
+) -> RuleSpec {
+    let mut rule = RuleSpec::new(chain);
+    check_iface(&mut rule, Direction::Out, iface);
     check_port(&mut rule, protocol, End::Dst, 53);
+    check_ip(&mut rule, End::Dst, host);
+    add_verdict(&mut rule, Verdict::Accept);
+    rule
+}
 
-    let daddr = match host {
-        IpAddr::V4(_) => nft_expr!(payload ipv4 daddr),
-        IpAddr::V6(_) => nft_expr!(payload ipv6 daddr),
-    };
-    if chain.get_table().get_family() == ProtoFamily::Inet {
-        check_l3proto(&mut rule, host);
-    }
-
-    rule.add_expr(&daddr);
-    rule.add_expr(&nft_expr!(cmp == host));
-    add_verdict(&mut rule, &Verdict::Accept);
-
-    Ok(rule)
+/// Marks the connection as excluded from the tunnel, and the packet so that it is routed outside
+/// the tunnel.
+fn add_exclusion_marks(rule: &mut RuleSpec) {
+    rule.statements
+        .push(Statement::SetCtMark(split_tunnel::MARK as u32));
+    rule.statements
+        .push(Statement::SetMetaMark(crate::linux::TUNNEL_FW_MARK));
 }
 
-fn allow_interface_rule<'a>(
-    chain: &'a Chain<'_>,
-    direction: Direction,
-    iface: &str,
-) -> Result<Rule<'a>> {
-    let mut rule = Rule::new(&chain);
-    check_iface(&mut rule, direction, iface)?;
-    add_verdict(&mut rule, &Verdict::Accept);
+fn allow_interface_rule(chain: ChainId, direction: Direction, iface: &str) -> RuleSpec {
+    let mut rule = RuleSpec::new(chain);
+    check_iface(&mut rule, direction, iface);
+    add_verdict(&mut rule, Verdict::Accept);
+    rule
+}
 
-    Ok(rule)
+fn check_iface(rule: &mut RuleSpec, direction: Direction, iface: &str) {
+    rule.matches.push(Match::Iface(direction, iface.to_owned()));
 }
 
-fn check_iface(rule: &mut Rule<'_>, direction: Direction, iface: &str) -> Result<()> {
-    let iface_index = crate::linux::iface_index(iface)
-        .map_err(|e| Error::LookupIfaceIndexError(iface.to_owned(), e))?;
-    rule.add_expr(&match direction {
-        Direction::In => nft_expr!(meta iif),
-        Direction::Out => nft_expr!(meta oif),
-    });
-    rule.add_expr(&nft_expr!(cmp == iface_index));
-    Ok(())
+fn check_not_iface(rule: &mut RuleSpec, direction: Direction, iface: &str) {
+    rule.matches
+        .push(Match::NotIface(direction, iface.to_owned()));
 }
 
-fn check_not_iface(rule: &mut Rule<'_>, direction: Direction, iface: &str) -> Result<()> {
-    let iface_index = crate::linux::iface_index(iface)
-        .map_err(|e| Error::LookupIfaceIndexError(iface.to_owned(), e))?;
-    rule.add_expr(&match direction {
-        Direction::In => nft_expr!(meta iif),
-        Direction::Out => nft_expr!(meta oif),
-    });
-    rule.add_expr(&nft_expr!(cmp != iface_index));
-    Ok(())
+fn check_net(rule: &mut RuleSpec, end: End, net: impl Into<IpNetwork>) {
+    rule.matches.push(Match::Net(end, net.into()));
 }
 
-fn check_net(rule: &mut Rule<'_>, end: End, net: impl Into<IpNetwork>) {
-    let net = net.into();
-    // Must check network layer protocol before loading network layer payload
-    check_l3proto(rule, net.ip());
-
-    rule.add_expr(&match (net, end) {
-        (IpNetwork::V4(_), End::Src) => nft_expr!(payload ipv4 saddr),
-        (IpNetwork::V4(_), End::Dst) => nft_expr!(payload ipv4 daddr),
-        (IpNetwork::V6(_), End::Src) => nft_expr!(payload ipv6 saddr),
-        (IpNetwork::V6(_), End::Dst) => nft_expr!(payload ipv6 daddr),
-    });
-    match net {
-        IpNetwork::V4(_) => rule.add_expr(&nft_expr!(bitwise mask net.mask(), xor 0u32)),
-        IpNetwork::V6(_) => rule.add_expr(&nft_expr!(bitwise mask net.mask(), xor &[0u16; 8][..])),
-    };
-    rule.add_expr(&nft_expr!(cmp == net.ip()));
+fn check_allow_rule(
+    rule: &mut RuleSpec,
+    allow_rule: &FirewallAllowRule,
+    network_end: End,
+    port_end: End,
+) {
+    check_net(rule, network_end, allow_rule.network);
+    if let Some(protocol) = allow_rule.protocol {
+        match allow_rule.port {
+            Some(port) => check_port(rule, protocol, port_end, port),
+            None => rule.matches.push(Match::L4proto(protocol)),
+        }
+    }
 }
 
-fn check_icmpv6(rule: &mut Rule<'_>, r#type: u8, code: u8) {
-    rule.add_expr(&nft_expr!(meta l4proto));
-    rule.add_expr(&nft_expr!(cmp == libc::IPPROTO_ICMPV6 as u8));
-
-    rule.add_expr(&Payload::Transport(
-        nftnl::expr::TransportHeaderField::Icmpv6(nftnl::expr::Icmpv6HeaderField::Type),
-    ));
-    rule.add_expr(&nft_expr!(cmp == r#type));
-    rule.add_expr(&nftnl::expr::Payload::Transport(
-        nftnl::expr::TransportHeaderField::Icmpv6(nftnl::expr::Icmpv6HeaderField::Code),
-    ));
-    rule.add_expr(&nft_expr!(cmp == code));
+fn check_icmpv6(rule: &mut RuleSpec, r#type: u8, code: u8) {
+    rule.matches.push(Match::Icmpv6 { r#type, code });
 }
 
-fn check_endpoint(rule: &mut Rule<'_>, end: End, endpoint: &Endpoint) {
+fn check_endpoint(rule: &mut RuleSpec, end: End, endpoint: &Endpoint) {
     check_ip(rule, end, endpoint.address.ip());
     check_port(rule, endpoint.protocol, end, endpoint.address.port());
 }
 
-fn check_ip(rule: &mut Rule<'_>, end: End, ip: impl Into<IpAddr>) {
-    let ip = ip.into();
-    // Must check network layer protocol before loading network layer payload
-    check_l3proto(rule, ip);
-
-    rule.add_expr(&match (ip, end) {
-        (IpAddr::V4(..), End::Src) => nft_expr!(payload ipv4 saddr),
-        (IpAddr::V4(..), End::Dst) => nft_expr!(payload ipv4 daddr),
-        (IpAddr::V6(..), End::Src) => nft_expr!(payload ipv6 saddr),
-        (IpAddr::V6(..), End::Dst) => nft_expr!(payload ipv6 daddr),
-    });
-    match ip {
-        IpAddr::V4(addr) => rule.add_expr(&nft_expr!(cmp == addr)),
-        IpAddr::V6(addr) => rule.add_expr(&nft_expr!(cmp == addr)),
-    }
+fn check_ip(rule: &mut RuleSpec, end: End, ip: impl Into<IpAddr>) {
+    rule.matches.push(Match::Ip(end, ip.into()));
 }
 
-fn check_port(rule: &mut Rule<'_>, protocol: TransportProtocol, end: End, port: u16) {
-    // Must check transport layer protocol before loading transport layer payload
-    check_l4proto(rule, protocol);
-
-    rule.add_expr(&match (protocol, end) {
-        (TransportProtocol::Udp, End::Src) => nft_expr!(payload udp sport),
-        (TransportProtocol::Udp, End::Dst) => nft_expr!(payload udp dport),
-        (TransportProtocol::Tcp, End::Src) => nft_expr!(payload tcp sport),
-        (TransportProtocol::Tcp, End::Dst) => nft_expr!(payload tcp dport),
-    });
-    rule.add_expr(&nft_expr!(cmp == port.to_be()));
+fn check_port(rule: &mut RuleSpec, protocol: TransportProtocol, end: End, port: u16) {
+    rule.matches.push(Match::Port(protocol, end, port));
 }
 
-fn check_l3proto(rule: &mut Rule<'_>, ip: IpAddr) {
-    rule.add_expr(&nft_expr!(meta nfproto));
-    rule.add_expr(&nft_expr!(cmp == l3proto(ip)));
+fn add_counter(rule: &mut RuleSpec) {
+    rule.counter = *ADD_COUNTERS;
 }
 
-fn l3proto(addr: IpAddr) -> u8 {
-    match addr {
-        IpAddr::V4(_) => libc::NFPROTO_IPV4 as u8,
-        IpAddr::V6(_) => libc::NFPROTO_IPV6 as u8,
-    }
+fn add_verdict(rule: &mut RuleSpec, verdict: Verdict) {
+    add_counter(rule);
+    rule.verdict = Some(verdict);
 }
 
-fn check_l4proto(rule: &mut Rule<'_>, protocol: TransportProtocol) {
-    rule.add_expr(&nft_expr!(meta l4proto));
-    rule.add_expr(&nft_expr!(cmp == l4proto(protocol)));
-}
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::{fs, net::Ipv6Addr, path::PathBuf};
+    use talpid_types::net::{AllowedEndpoint, ExcludedDestinations};
 
-fn l4proto(protocol: TransportProtocol) -> u8 {
-    match protocol {
-        TransportProtocol::Udp => libc::IPPROTO_UDP as u8,
-        TransportProtocol::Tcp => libc::IPPROTO_TCP as u8,
+    /// Set this variable to overwrite the snapshots with the current output instead of comparing
+    /// against them.
+    const UPDATE_SNAPSHOTS_ENV_VAR: &str = "TALPID_UPDATE_SNAPSHOTS";
+
+    fn assert_snapshot(name: &str, policy: &FirewallPolicy) {
+        assert_snapshot_with_cgroup(name, policy, None);
+    }
+
+    fn assert_snapshot_with_cgroup(
+        name: &str,
+        policy: &FirewallPolicy,
+        exclusion_cgroup: Option<ExclusionCgroup>,
+    ) {
+        let rendered =
+            RenderedRuleset(&PolicyBatch::new(exclusion_cgroup).finalize(policy)).to_string();
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("src/firewall/snapshots")
+            .join(format!("{}.nft", name));
+
+        if env::var(UPDATE_SNAPSHOTS_ENV_VAR).is_ok() {
+            fs::write(&path, rendered).expect("Failed to write snapshot");
+            return;
+        }
+
+        let expected = fs::read_to_string(&path).expect("Failed to read snapshot");
+        assert!(
+            rendered == expected,
+            "Rendered rules do not match {}. Set {} to update the snapshot.\n{}",
+            path.display(),
+            UPDATE_SNAPSHOTS_ENV_VAR,
+            rendered,
+        );
+    }
+
+    fn peer_endpoint() -> Endpoint {
+        Endpoint::new(
+            Ipv4Addr::new(185, 213, 154, 68),
+            51820,
+            TransportProtocol::Udp,
+        )
+    }
+
+    fn allowed_endpoint() -> AllowedEndpoint {
+        AllowedEndpoint {
+            endpoint: Endpoint::new(
+                Ipv4Addr::new(193, 138, 218, 78),
+                443,
+                TransportProtocol::Tcp,
+            ),
+        }
+    }
+
+    fn tunnel() -> tunnel::TunnelMetadata {
+        tunnel::TunnelMetadata {
+            interface: "wg-mullvad".to_owned(),
+            ips: vec![
+                IpAddr::V4(Ipv4Addr::new(10, 99, 0, 2)),
+                IpAddr::V6(Ipv6Addr::new(0xfc00, 0xbbbb, 0xbbbb, 0xbb01, 0, 0, 0, 2)),
+            ],
+            ipv4_gateway: Ipv4Addr::new(10, 64, 0, 1),
+            ipv6_gateway: Some(Ipv6Addr::new(0xfc00, 0xbbbb, 0xbbbb, 0xbb01, 0, 0, 0, 1)),
+        }
+    }
+
+    #[test]
+    fn test_render_connecting() {
+        assert_snapshot(
+            "connecting",
+            &FirewallPolicy::Connecting {
+                peer_endpoint: peer_endpoint(),
+                tunnel: None,
+                allow_lan: false,
+                lan_networks: LanNetworks::default(),
+                allowed_endpoint: allowed_endpoint(),
+                allow_rules: vec![],
+                split_tunnel_mode: SplitTunnelMode::Exclude,
+                exclusion_profiles: vec![],
+                excluded_destinations: ExcludedDestinations::default(),
+            },
+        );
+    }
+
+    #[test]
+    fn test_render_connecting_with_tunnel() {
+        assert_snapshot(
+            "connecting_tunnel_lan",
+            &FirewallPolicy::Connecting {
+                peer_endpoint: peer_endpoint(),
+                tunnel: Some(tunnel()),
+                allow_lan: true,
+                lan_networks: LanNetworks::default(),
+                allowed_endpoint: allowed_endpoint(),
+                allow_rules: vec![],
+                split_tunnel_mode: SplitTunnelMode::Exclude,
+                exclusion_profiles: vec![],
+                excluded_destinations: ExcludedDestinations::default(),
+            },
+        );
+    }
+
+    #[test]
+    fn test_render_connected() {
+        let tunnel = tunnel();
+        assert_snapshot(
+            "connected",
+            &FirewallPolicy::Connected {
+                peer_endpoint: peer_endpoint(),
+                dns_servers: vec![IpAddr::V4(tunnel.ipv4_gateway)],
+                split_dns_resolvers: vec![],
+                tunnel,
+                allow_lan: false,
+                lan_networks: LanNetworks::default(),
+                allow_rules: vec![],
+                split_tunnel_mode: SplitTunnelMode::Exclude,
+                exclusion_profiles: vec![],
+                excluded_destinations: ExcludedDestinations::default(),
+            },
+        );
+    }
+
+    #[test]
+    fn test_render_connected_with_custom_dns() {
+        assert_snapshot(
+            "connected_custom_dns_lan",
+            &FirewallPolicy::Connected {
+                peer_endpoint: peer_endpoint(),
+                tunnel: tunnel(),
+                allow_lan: true,
+                lan_networks: LanNetworks::default(),
+                dns_servers: vec![
+                    IpAddr::V4(Ipv4Addr::new(192, 168, 1, 1)),
+                    IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1)),
+                ],
+                split_dns_resolvers: vec![],
+                allow_rules: vec![],
+                split_tunnel_mode: SplitTunnelMode::Exclude,
+                exclusion_profiles: vec![],
+                excluded_destinations: ExcludedDestinations::default(),
+            },
+        );
+    }
+
+    #[test]
+    fn test_render_connected_with_split_dns() {
+        let tunnel = tunnel();
+        assert_snapshot(
+            "connected_split_dns",
+            &FirewallPolicy::Connected {
+                peer_endpoint: peer_endpoint(),
+                dns_servers: vec![IpAddr::V4(tunnel.ipv4_gateway)],
+                split_dns_resolvers: vec![IpAddr::V4(Ipv4Addr::new(10, 1, 2, 3))],
+                tunnel,
+                allow_lan: false,
+                lan_networks: LanNetworks::default(),
+                allow_rules: vec![],
+                split_tunnel_mode: SplitTunnelMode::Exclude,
+                exclusion_profiles: vec![],
+                excluded_destinations: ExcludedDestinations::default(),
+            },
+        );
+    }
+
+    #[test]
+    fn test_render_blocked() {
+        assert_snapshot(
+            "blocked",
+            &FirewallPolicy::Blocked {
+                allow_lan: false,
+                lan_networks: LanNetworks::default(),
+                allowed_endpoint: allowed_endpoint(),
+                allow_rules: vec![],
+                split_tunnel_mode: SplitTunnelMode::Exclude,
+                exclusion_profiles: vec![],
+                excluded_destinations: ExcludedDestinations::default(),
+            },
+        );
+    }
+
+    #[test]
+    fn test_render_blocked_with_lan() {
+        assert_snapshot(
+            "blocked_lan",
+            &FirewallPolicy::Blocked {
+                allow_lan: true,
+                lan_networks: LanNetworks::default(),
+                allowed_endpoint: allowed_endpoint(),
+                allow_rules: vec![],
+                split_tunnel_mode: SplitTunnelMode::Exclude,
+                exclusion_profiles: vec![],
+                excluded_destinations: ExcludedDestinations::default(),
+            },
+        );
+    }
+
+    #[test]
+    fn test_render_captive_portal() {
+        assert_snapshot(
+            "captive_portal",
+            &FirewallPolicy::CaptivePortal {
+                allow_lan: false,
+                lan_networks: LanNetworks::default(),
+                allowed_endpoint: allowed_endpoint(),
+                allow_rules: vec![],
+                split_tunnel_mode: SplitTunnelMode::Exclude,
+                exclusion_profiles: vec![],
+                excluded_destinations: ExcludedDestinations::default(),
+                portal_hosts: vec![
+                    "192.168.1.1".parse().unwrap(),
+                    "203.0.113.80".parse().unwrap(),
+                ],
+            },
+        );
+    }
+
+    fn allow_rules() -> Vec<FirewallAllowRule> {
+        vec![
+            FirewallAllowRule {
+                network: "203.0.113.7/32".parse().unwrap(),
+                protocol: Some(TransportProtocol::Tcp),
+                port: Some(22),
+                direction: AllowedDirection::Outbound,
+                outside_tunnel: true,
+            },
+            FirewallAllowRule {
+                network: "198.51.100.0/24".parse().unwrap(),
+                protocol: Some(TransportProtocol::Udp),
+                port: None,
+                direction: AllowedDirection::Outbound,
+                outside_tunnel: false,
+            },
+            FirewallAllowRule {
+                network: "2001:db8::/32".parse().unwrap(),
+                protocol: Some(TransportProtocol::Tcp),
+                port: Some(9100),
+                direction: AllowedDirection::Inbound,
+                outside_tunnel: false,
+            },
+        ]
+    }
+
+    #[test]
+    fn test_render_connected_with_allow_rules() {
+        let tunnel = tunnel();
+        assert_snapshot(
+            "connected_allow_rules",
+            &FirewallPolicy::Connected {
+                peer_endpoint: peer_endpoint(),
+                dns_servers: vec![IpAddr::V4(tunnel.ipv4_gateway)],
+                split_dns_resolvers: vec![],
+                tunnel,
+                allow_lan: false,
+                lan_networks: LanNetworks::default(),
+                allow_rules: allow_rules(),
+                split_tunnel_mode: SplitTunnelMode::Exclude,
+                exclusion_profiles: vec![],
+                excluded_destinations: ExcludedDestinations::default(),
+            },
+        );
+    }
+
+    #[test]
+    fn test_render_blocked_with_allow_rules() {
+        assert_snapshot(
+            "blocked_allow_rules",
+            &FirewallPolicy::Blocked {
+                allow_lan: false,
+                lan_networks: LanNetworks::default(),
+                allowed_endpoint: allowed_endpoint(),
+                allow_rules: allow_rules(),
+                split_tunnel_mode: SplitTunnelMode::Exclude,
+                exclusion_profiles: vec![],
+                excluded_destinations: ExcludedDestinations::default(),
+            },
+        );
+    }
+
+    #[test]
+    fn test_render_connected_custom_lan_networks() {
+        let tunnel = tunnel();
+        assert_snapshot(
+            "connected_custom_lan_networks",
+            &FirewallPolicy::Connected {
+                peer_endpoint: peer_endpoint(),
+                dns_servers: vec![
+                    IpAddr::V4(Ipv4Addr::new(100, 64, 0, 53)),
+                    IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+                ],
+                tunnel,
+                allow_lan: true,
+                lan_networks: LanNetworks {
+                    networks: vec![
+                        "192.168.1.0/24".parse().unwrap(),
+                        "100.64.0.0/10".parse().unwrap(),
+                    ],
+                    multicast_networks: vec!["224.0.0.0/24".parse().unwrap()],
+                },
+                split_dns_resolvers: vec![],
+                allow_rules: vec![],
+                split_tunnel_mode: SplitTunnelMode::Exclude,
+                exclusion_profiles: vec![],
+                excluded_destinations: ExcludedDestinations::default(),
+            },
+        );
+    }
+
+    #[test]
+    fn test_render_connected_cgroup_v2() {
+        let tunnel = tunnel();
+        assert_snapshot_with_cgroup(
+            "connected_cgroup_v2",
+            &FirewallPolicy::Connected {
+                peer_endpoint: peer_endpoint(),
+                dns_servers: vec![IpAddr::V4(tunnel.ipv4_gateway)],
+                split_dns_resolvers: vec![],
+                tunnel,
+                allow_lan: false,
+                lan_networks: LanNetworks::default(),
+                allow_rules: vec![],
+                split_tunnel_mode: SplitTunnelMode::Exclude,
+                exclusion_profiles: vec![],
+                excluded_destinations: ExcludedDestinations::default(),
+            },
+            Some(ExclusionCgroup::V2(PathBuf::from("/sys/fs/cgroup"))),
+        );
+    }
+
+    #[test]
+    fn test_render_connected_include_only() {
+        let tunnel = tunnel();
+        assert_snapshot(
+            "connected_include_only",
+            &FirewallPolicy::Connected {
+                peer_endpoint: peer_endpoint(),
+                dns_servers: vec![IpAddr::V4(tunnel.ipv4_gateway)],
+                split_dns_resolvers: vec![],
+                tunnel,
+                allow_lan: false,
+                lan_networks: LanNetworks::default(),
+                allow_rules: vec![],
+                split_tunnel_mode: SplitTunnelMode::IncludeOnly,
+                exclusion_profiles: vec![],
+                excluded_destinations: ExcludedDestinations::default(),
+            },
+        );
+    }
+
+    /// Include-only mode also applies while connecting. Traffic owned by root, such as that of
+    /// the daemon, is not excluded.
+    #[test]
+    fn test_render_connecting_include_only() {
+        assert_snapshot(
+            "connecting_include_only",
+            &FirewallPolicy::Connecting {
+                peer_endpoint: peer_endpoint(),
+                tunnel: None,
+                allow_lan: false,
+                lan_networks: LanNetworks::default(),
+                allowed_endpoint: allowed_endpoint(),
+                allow_rules: vec![],
+                split_tunnel_mode: SplitTunnelMode::IncludeOnly,
+                exclusion_profiles: vec![],
+                excluded_destinations: ExcludedDestinations::default(),
+            },
+        );
+    }
+
+    /// Include-only mode must not let traffic bypass the blocked state, so the rules are the same
+    /// as in exclusion mode.
+    #[test]
+    fn test_render_blocked_include_only() {
+        assert_snapshot(
+            "blocked",
+            &FirewallPolicy::Blocked {
+                allow_lan: false,
+                lan_networks: LanNetworks::default(),
+                allowed_endpoint: allowed_endpoint(),
+                allow_rules: vec![],
+                split_tunnel_mode: SplitTunnelMode::IncludeOnly,
+                exclusion_profiles: vec![],
+                excluded_destinations: ExcludedDestinations::default(),
+            },
+        );
+    }
+
+    #[test]
+    fn test_render_connected_excluded_destinations() {
+        let tunnel = tunnel();
+        assert_snapshot(
+            "connected_excluded_destinations",
+            &FirewallPolicy::Connected {
+                peer_endpoint: peer_endpoint(),
+                dns_servers: vec![IpAddr::V4(tunnel.ipv4_gateway)],
+                tunnel,
+                allow_lan: false,
+                lan_networks: LanNetworks::default(),
+                split_dns_resolvers: vec![],
+                allow_rules: vec![],
+                split_tunnel_mode: SplitTunnelMode::Exclude,
+                exclusion_profiles: vec![],
+                excluded_destinations: ExcludedDestinations {
+                    networks: vec![
+                        "198.51.100.0/24".parse().unwrap(),
+                        "2001:db8::/32".parse().unwrap(),
+                    ],
+                    domains: vec!["example.com".to_owned()],
+                },
+            },
+        );
+    }
+
+    #[test]
+    fn test_render_connected_exclusion_profiles() {
+        let tunnel = tunnel();
+        assert_snapshot(
+            "connected_exclusion_profiles",
+            &FirewallPolicy::Connected {
+                peer_endpoint: peer_endpoint(),
+                dns_servers: vec![IpAddr::V4(tunnel.ipv4_gateway)],
+                split_dns_resolvers: vec![],
+                tunnel,
+                allow_lan: false,
+                lan_networks: LanNetworks::default(),
+                allow_rules: vec![],
+                split_tunnel_mode: SplitTunnelMode::Exclude,
+                exclusion_profiles: vec![
+                    ExclusionProfile {
+                        name: "tools".to_owned(),
+                        dns: ProfileDns::Tunnel,
+                    },
+                    ExclusionProfile {
+                        name: "scanners".to_owned(),
+                        dns: ProfileDns::Bypass,
+                    },
+                ],
+                excluded_destinations: ExcludedDestinations::default(),
+            },
+        );
+    }
+
+    /// Runs `f` on a new thread in a network namespace of its own, so that the firewall of the
+    /// host is left untouched.
+    fn in_network_namespace(f: impl FnOnce() + Send + 'static) {
+        std::thread::spawn(move || {
+            // This only moves the calling thread into the new namespace
+            if unsafe { libc::unshare(libc::CLONE_NEWNET) } != 0 {
+                panic!(
+                    "Failed to create network namespace: {}",
+                    io::Error::last_os_error()
+                );
+            }
+            f()
+        })
+        .join()
+        .unwrap()
+    }
+
+    /// Requires CAP_SYS_ADMIN and CAP_NET_ADMIN. Run as root with `cargo test -- --ignored`.
+    #[test]
+    #[ignore]
+    fn test_restore_removed_tables() {
+        in_network_namespace(|| {
+            let policy = FirewallPolicy::Blocked {
+                allow_lan: false,
+                lan_networks: LanNetworks::default(),
+                allowed_endpoint: allowed_endpoint(),
+                allow_rules: vec![],
+                split_tunnel_mode: SplitTunnelMode::Exclude,
+                exclusion_profiles: vec![],
+                excluded_destinations: ExcludedDestinations::default(),
+            };
+            let mut firewall = crate::firewall::Firewall::new().unwrap();
+            firewall.apply_policy(policy.clone()).unwrap();
+            assert!(!firewall.restore_policy().unwrap());
+
+            let mut batch = Batch::new();
+            batch.add(
+                &Table::new(&*MANGLE_TABLE_NAME_V4, ProtoFamily::Ipv4),
+                nftnl::MsgType::Del,
+            );
+            Firewall::send_and_process(&batch.finalize()).unwrap();
+            assert!(Firewall::new().unwrap().verify_policy().is_err());
+
+            assert!(firewall.restore_policy().unwrap());
+            assert!(Firewall::new().unwrap().verify_policy().is_ok());
+            assert_eq!(firewall.active_policy(), Some(&policy));
+        });
+    }
+
+    fn blocked_policy() -> FirewallPolicy {
+        FirewallPolicy::Blocked {
+            allow_lan: false,
+            lan_networks: LanNetworks::default(),
+            allowed_endpoint: allowed_endpoint(),
+            allow_rules: vec![],
+            split_tunnel_mode: SplitTunnelMode::Exclude,
+            exclusion_profiles: vec![],
+            excluded_destinations: ExcludedDestinations::default(),
+        }
+    }
+
+    #[test]
+    #[ignore]
+    fn test_restore_flushed_chain() {
+        in_network_namespace(|| {
+            let mut firewall = crate::firewall::Firewall::new().unwrap();
+            firewall.apply_policy(blocked_policy()).unwrap();
+            assert!(firewall.inner.verify_policy().is_ok());
+
+            // Deleting a rule without a handle deletes all rules in the chain
+            let table = Table::new(&*TABLE_NAME, ProtoFamily::Inet);
+            let chain = Chain::new(&*OUT_CHAIN_NAME, &table);
+            let mut batch = Batch::new();
+            batch.add(&Rule::new(&chain), nftnl::MsgType::Del);
+            Firewall::send_and_process(&batch.finalize()).unwrap();
+            assert!(matches!(
+                firewall.inner.verify_policy(),
+                Err(Error::NetfilterRulesModifiedError)
+            ));
+
+            assert!(firewall.restore_policy().unwrap());
+            assert!(firewall.inner.verify_policy().is_ok());
+            assert!(!firewall.restore_policy().unwrap());
+        });
+    }
+
+    #[test]
+    #[ignore]
+    fn test_restore_added_rule() {
+        in_network_namespace(|| {
+            let mut firewall = crate::firewall::Firewall::new().unwrap();
+            firewall.apply_policy(blocked_policy()).unwrap();
+
+            let table = Table::new(&*TABLE_NAME, ProtoFamily::Inet);
+            let chain = Chain::new(&*OUT_CHAIN_NAME, &table);
+            let mut rule = Rule::new(&chain);
+            rule.add_expr(&expr::Verdict::Accept);
+            let mut batch = Batch::new();
+            batch.add(&rule, nftnl::MsgType::Add);
+            Firewall::send_and_process(&batch.finalize()).unwrap();
+            assert!(matches!(
+                firewall.inner.verify_policy(),
+                Err(Error::NetfilterRulesModifiedError)
+            ));
+
+            assert!(firewall.restore_policy().unwrap());
+            assert!(firewall.inner.verify_policy().is_ok());
+        });
     }
-}
 
-fn add_verdict(rule: &mut Rule<'_>, verdict: &expr::Verdict) {
-    if *ADD_COUNTERS {
-        rule.add_expr(&nft_expr!(counter));
+    #[test]
+    #[ignore]
+    fn test_no_restore_without_policy() {
+        in_network_namespace(|| {
+            let mut firewall = crate::firewall::Firewall::new().unwrap();
+            assert!(!firewall.restore_policy().unwrap());
+            assert!(Firewall::new().unwrap().verify_policy().is_err());
+        });
     }
-    rule.add_expr(verdict);
 }
@@ -6,7 +6,7 @@ use std::{
     net::{IpAddr, Ipv4Addr},
 };
 use subslice::SubsliceExt;
-use talpid_types::net;
+use talpid_types::net::{self, lan::LanNetworks};
 
 pub use pfctl::Error;
 
@@ -118,6 +118,7 @@ impl Firewall {
                 peer_endpoint,
                 tunnel,
                 allow_lan,
+                lan_networks,
                 allowed_endpoint,
             } => {
                 let mut rules = vec![self.get_allow_relay_rule(*peer_endpoint)?];
@@ -132,7 +133,7 @@ impl Firewall {
                 }
 
                 if *allow_lan {
-                    rules.append(&mut self.get_allow_lan_rules()?);
+                    rules.append(&mut self.get_allow_lan_rules(lan_networks)?);
                 }
                 Ok(rules)
             }
@@ -140,12 +141,17 @@ impl Firewall {
                 peer_endpoint,
                 tunnel,
                 allow_lan,
+                lan_networks,
                 dns_servers,
             } => {
                 let mut rules = vec![];
 
                 for server in dns_servers.iter() {
-                    rules.append(&mut self.get_allow_dns_rules_when_connected(&tunnel, *server)?);
+                    rules.append(&mut self.get_allow_dns_rules_when_connected(
+                        &tunnel,
+                        lan_networks,
+                        *server,
+                    )?);
                 }
 
                 rules.push(self.get_allow_relay_rule(*peer_endpoint)?);
@@ -157,13 +163,14 @@ impl Firewall {
                 rules.push(self.get_allow_tunnel_rule(tunnel.interface.as_str())?);
 
                 if *allow_lan {
-                    rules.append(&mut self.get_allow_lan_rules()?);
+                    rules.append(&mut self.get_allow_lan_rules(lan_networks)?);
                 }
 
                 Ok(rules)
             }
             FirewallPolicy::Blocked {
                 allow_lan,
+                lan_networks,
                 allowed_endpoint,
                 ..
             } => {
@@ -173,7 +180,7 @@ impl Firewall {
                 if *allow_lan {
                     // Important to block DNS before allow LAN (so DNS does not leak to the LAN)
                     rules.append(&mut self.get_block_dns_rules()?);
-                    rules.append(&mut self.get_allow_lan_rules()?);
+                    rules.append(&mut self.get_allow_lan_rules(lan_networks)?);
                 }
 
                 Ok(rules)
@@ -184,11 +191,12 @@ impl Firewall {
     fn get_allow_dns_rules_when_connected(
         &self,
         tunnel: &crate::tunnel::TunnelMetadata,
+        lan_networks: &LanNetworks,
         server: IpAddr,
     ) -> Result<Vec<pfctl::FilterRule>> {
         let mut rules = Vec::with_capacity(4);
 
-        let is_local = super::is_local_address(&server)
+        let is_local = super::is_local_address(&server, lan_networks)
             && server != tunnel.ipv4_gateway
             && !tunnel
                 .ipv6_gateway
@@ -338,9 +346,9 @@ impl Firewall {
         Ok(vec![lo0_rule])
     }
 
-    fn get_allow_lan_rules(&self) -> Result<Vec<pfctl::FilterRule>> {
+    fn get_allow_lan_rules(&self, lan_networks: &LanNetworks) -> Result<Vec<pfctl::FilterRule>> {
         let mut rules = vec![];
-        for net in &*super::ALLOWED_LAN_NETS {
+        for net in &lan_networks.networks {
             let mut rule_builder = self.create_rule_builder(FilterRuleAction::Pass);
             rule_builder.quick(true);
             let allow_out = rule_builder
@@ -356,7 +364,7 @@ impl Firewall {
             rules.push(allow_out);
             rules.push(allow_in);
         }
-        for multicast_net in &*super::ALLOWED_LAN_MULTICAST_NETS {
+        for multicast_net in &lan_networks.multicast_networks {
             let allow_multicast_out = self
                 .create_rule_builder(FilterRuleAction::Pass)
                 .quick(true)
@@ -1,5 +1,5 @@
 #[cfg(unix)]
-use ipnetwork::{IpNetwork, Ipv4Network, Ipv6Network};
+use ipnetwork::{IpNetwork, Ipv6Network};
 #[cfg(unix)]
 use lazy_static::lazy_static;
 use std::fmt;
@@ -9,7 +9,14 @@ use std::net::IpAddr;
 use std::net::{Ipv4Addr, Ipv6Addr};
 #[cfg(windows)]
 use std::path::PathBuf;
+#[cfg(unix)]
+use talpid_types::net::lan::LanNetworks;
 use talpid_types::net::{AllowedEndpoint, Endpoint};
+#[cfg(target_os = "linux")]
+use talpid_types::{
+    cgroup::{ExclusionProfile, ProfileDns, SplitTunnelMode},
+    net::{ExcludedDestinations, FirewallAllowRule},
+};
 
 #[cfg(target_os = "macos")]
 #[path = "macos.rs"]
@@ -27,38 +34,15 @@ mod imp;
 #[path = "android.rs"]
 mod imp;
 
+#[cfg(target_os = "linux")]
+pub(crate) mod nflog;
+
 pub use self::imp::Error;
+#[cfg(target_os = "linux")]
+pub use self::nflog::BlockedConnection;
 
 #[cfg(unix)]
 lazy_static! {
-    /// When "allow local network" is enabled the app will allow traffic to and from these networks.
-    pub(crate) static ref ALLOWED_LAN_NETS: [IpNetwork; 6] = [
-        IpNetwork::V4(Ipv4Network::new(Ipv4Addr::new(10, 0, 0, 0), 8).unwrap()),
-        IpNetwork::V4(Ipv4Network::new(Ipv4Addr::new(172, 16, 0, 0), 12).unwrap()),
-        IpNetwork::V4(Ipv4Network::new(Ipv4Addr::new(192, 168, 0, 0), 16).unwrap()),
-        IpNetwork::V4(Ipv4Network::new(Ipv4Addr::new(169, 254, 0, 0), 16).unwrap()),
-        IpNetwork::V6(Ipv6Network::new(Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 0), 10).unwrap()),
-        IpNetwork::V6(Ipv6Network::new(Ipv6Addr::new(0xfc00, 0, 0, 0, 0, 0, 0, 0), 7).unwrap()),
-    ];
-    /// When "allow local network" is enabled the app will allow traffic to these networks.
-    pub(crate) static ref ALLOWED_LAN_MULTICAST_NETS: [IpNetwork; 8] = [
-        // Local network broadcast. Not routable
-        IpNetwork::V4(Ipv4Network::new(Ipv4Addr::new(255, 255, 255, 255), 32).unwrap()),
-        // Local subnetwork multicast. Not routable
-        IpNetwork::V4(Ipv4Network::new(Ipv4Addr::new(224, 0, 0, 0), 24).unwrap()),
-        // Local scope (mDNS and SSDP) address
-        IpNetwork::V4(Ipv4Network::new(Ipv4Addr::new(239, 255, 0, 0), 16).unwrap()),
-        // Interface-local IPv6 multicast.
-        IpNetwork::V6(Ipv6Network::new(Ipv6Addr::new(0xff01, 0, 0, 0, 0, 0, 0, 0), 16).unwrap()),
-        // Link-local IPv6 multicast. IPv6 equivalent of 224.0.0.0/24
-        IpNetwork::V6(Ipv6Network::new(Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0), 16).unwrap()),
-        // Realm-local IPv6 multicast.
-        IpNetwork::V6(Ipv6Network::new(Ipv6Addr::new(0xff03, 0, 0, 0, 0, 0, 0, 0), 16).unwrap()),
-        // Admin-local IPv6 multicast.
-        IpNetwork::V6(Ipv6Network::new(Ipv6Addr::new(0xff04, 0, 0, 0, 0, 0, 0, 0), 16).unwrap()),
-        // Site-local IPv6 multicast.
-        IpNetwork::V6(Ipv6Network::new(Ipv6Addr::new(0xff05, 0, 0, 0, 0, 0, 0, 0), 16).unwrap()),
-    ];
     static ref IPV6_LINK_LOCAL: Ipv6Network = Ipv6Network::new(Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 0), 10).unwrap();
     /// The allowed target addresses of outbound DHCPv6 requests
     static ref DHCPV6_SERVER_ADDRS: [Ipv6Addr; 2] = [
@@ -84,13 +68,9 @@ const DHCPV6_CLIENT_PORT: u16 = 546;
 const ROOT_UID: u32 = 0;
 
 #[cfg(all(unix, not(target_os = "android")))]
-/// Returns whether an address belongs to a private subnet.
-pub fn is_local_address(address: &IpAddr) -> bool {
-    let address = address.clone();
-    (&*ALLOWED_LAN_NETS)
-        .iter()
-        .chain(&*LOOPBACK_NETS)
-        .any(|net| net.contains(address))
+/// Returns whether an address belongs to one of the local networks or to a loopback network.
+pub fn is_local_address(address: &IpAddr, lan_networks: &LanNetworks) -> bool {
+    lan_networks.contains(*address) || LOOPBACK_NETS.iter().any(|net| net.contains(*address))
 }
 
 /// A enum that describes network security strategy
@@ -109,8 +89,25 @@ pub enum FirewallPolicy {
         tunnel: Option<crate::tunnel::TunnelMetadata>,
         /// Flag setting if communication with LAN networks should be possible.
         allow_lan: bool,
+        /// Networks that are reachable when `allow_lan` is set.
+        #[cfg(unix)]
+        lan_networks: LanNetworks,
         /// Host that should be reachable while connecting.
         allowed_endpoint: AllowedEndpoint,
+        /// User-defined exceptions to the policy.
+        #[cfg(target_os = "linux")]
+        allow_rules: Vec<FirewallAllowRule>,
+        /// Whether processes in the split tunneling cgroup are excluded from the tunnel, or the
+        /// only ones included in it.
+        #[cfg(target_os = "linux")]
+        split_tunnel_mode: SplitTunnelMode,
+        /// Named groups of excluded processes, each with its own cgroup.
+        #[cfg(target_os = "linux")]
+        exclusion_profiles: Vec<ExclusionProfile>,
+        /// Destinations that are routed outside the tunnel. The networks include the addresses
+        /// that the domains have been resolved to.
+        #[cfg(target_os = "linux")]
+        excluded_destinations: ExcludedDestinations,
         /// A process that is allowed to send packets to the relay.
         #[cfg(windows)]
         relay_client: PathBuf,
@@ -124,9 +121,29 @@ pub enum FirewallPolicy {
         tunnel: crate::tunnel::TunnelMetadata,
         /// Flag setting if communication with LAN networks should be possible.
         allow_lan: bool,
+        /// Networks that are reachable when `allow_lan` is set.
+        #[cfg(unix)]
+        lan_networks: LanNetworks,
         /// Servers that are allowed to respond to DNS requests.
         #[cfg(not(target_os = "android"))]
         dns_servers: Vec<IpAddr>,
+        /// Resolvers used for split DNS domains. These may be reached outside the tunnel.
+        #[cfg(target_os = "linux")]
+        split_dns_resolvers: Vec<IpAddr>,
+        /// User-defined exceptions to the policy.
+        #[cfg(target_os = "linux")]
+        allow_rules: Vec<FirewallAllowRule>,
+        /// Whether processes in the split tunneling cgroup are excluded from the tunnel, or the
+        /// only ones included in it.
+        #[cfg(target_os = "linux")]
+        split_tunnel_mode: SplitTunnelMode,
+        /// Named groups of excluded processes, each with its own cgroup.
+        #[cfg(target_os = "linux")]
+        exclusion_profiles: Vec<ExclusionProfile>,
+        /// Destinations that are routed outside the tunnel. The networks include the addresses
+        /// that the domains have been resolved to.
+        #[cfg(target_os = "linux")]
+        excluded_destinations: ExcludedDestinations,
         /// A process that is allowed to send packets to the relay.
         #[cfg(windows)]
         relay_client: PathBuf,
@@ -136,13 +153,55 @@ pub enum FirewallPolicy {
     Blocked {
         /// Flag setting if communication with LAN networks should be possible.
         allow_lan: bool,
+        /// Networks that are reachable when `allow_lan` is set.
+        #[cfg(unix)]
+        lan_networks: LanNetworks,
         /// Host that should be reachable while in the blocked state.
         allowed_endpoint: AllowedEndpoint,
+        /// User-defined exceptions to the policy.
+        #[cfg(target_os = "linux")]
+        allow_rules: Vec<FirewallAllowRule>,
+        /// Whether processes in the split tunneling cgroup are excluded from the tunnel, or the
+        /// only ones included in it.
+        #[cfg(target_os = "linux")]
+        split_tunnel_mode: SplitTunnelMode,
+        /// Named groups of excluded processes, each with its own cgroup.
+        #[cfg(target_os = "linux")]
+        exclusion_profiles: Vec<ExclusionProfile>,
+        /// Destinations that are routed outside the tunnel. The networks include the addresses
+        /// that the domains have been resolved to.
+        #[cfg(target_os = "linux")]
+        excluded_destinations: ExcludedDestinations,
         /// Desination port for DNS traffic redirection. Traffic destined to `127.0.0.1:53` will be
         /// redirected to `127.0.0.1:$dns_redirect_port`.
         #[cfg(target_os = "macos")]
         dns_redirect_port: u16,
     },
+
+    /// Block all network traffic like [`FirewallPolicy::Blocked`], except HTTP and HTTPS to a
+    /// captive portal, so that the user can log in to the network.
+    #[cfg(target_os = "linux")]
+    CaptivePortal {
+        /// Flag setting if communication with LAN networks should be possible.
+        allow_lan: bool,
+        /// Networks that are reachable when `allow_lan` is set.
+        lan_networks: LanNetworks,
+        /// Host that should be reachable while in the blocked state.
+        allowed_endpoint: AllowedEndpoint,
+        /// User-defined exceptions to the policy.
+        allow_rules: Vec<FirewallAllowRule>,
+        /// Whether processes in the split tunneling cgroup are excluded from the tunnel, or the
+        /// only ones included in it.
+        split_tunnel_mode: SplitTunnelMode,
+        /// Named groups of excluded processes, each with its own cgroup.
+        exclusion_profiles: Vec<ExclusionProfile>,
+        /// Destinations that are routed outside the tunnel. The networks include the addresses
+        /// that the domains have been resolved to.
+        excluded_destinations: ExcludedDestinations,
+        /// Gateways and portal servers that are reachable using HTTP and HTTPS. They may also
+        /// answer DNS requests, so that the portal can be resolved.
+        portal_hosts: Vec<IpAddr>,
+    },
 }
 
 impl fmt::Display for FirewallPolicy {
@@ -212,14 +271,292 @@ impl fmt::Display for FirewallPolicy {
                 if *allow_lan { "Allowing" } else { "Blocking" },
                 allowed_endpoint,
             ),
+            #[cfg(target_os = "linux")]
+            FirewallPolicy::CaptivePortal {
+                allow_lan,
+                allowed_endpoint,
+                portal_hosts,
+                ..
+            } => write!(
+                f,
+                "Blocked except captive portal at {}. {} LAN. Allowing endpoint {}",
+                portal_hosts
+                    .iter()
+                    .map(|ip| ip.to_string())
+                    .collect::<Vec<_>>()
+                    .join(","),
+                if *allow_lan { "Allowing" } else { "Blocking" },
+                allowed_endpoint,
+            ),
+        }
+    }
+}
+
+impl FirewallPolicy {
+    /// Returns the networks that are considered local.
+    #[cfg(unix)]
+    pub fn lan_networks(&self) -> &LanNetworks {
+        match self {
+            FirewallPolicy::Connecting { lan_networks, .. }
+            | FirewallPolicy::Connected { lan_networks, .. }
+            | FirewallPolicy::Blocked { lan_networks, .. } => lan_networks,
+            #[cfg(target_os = "linux")]
+            FirewallPolicy::CaptivePortal { lan_networks, .. } => lan_networks,
+        }
+    }
+
+    /// Returns the user-defined exceptions to the policy.
+    #[cfg(target_os = "linux")]
+    pub fn allow_rules(&self) -> &[FirewallAllowRule] {
+        match self {
+            FirewallPolicy::Connecting { allow_rules, .. }
+            | FirewallPolicy::Connected { allow_rules, .. }
+            | FirewallPolicy::Blocked { allow_rules, .. }
+            | FirewallPolicy::CaptivePortal { allow_rules, .. } => allow_rules,
+        }
+    }
+
+    /// Returns how processes in the split tunneling cgroup are treated.
+    #[cfg(target_os = "linux")]
+    pub fn split_tunnel_mode(&self) -> SplitTunnelMode {
+        match self {
+            FirewallPolicy::Connecting {
+                split_tunnel_mode, ..
+            }
+            | FirewallPolicy::Connected {
+                split_tunnel_mode, ..
+            }
+            | FirewallPolicy::Blocked {
+                split_tunnel_mode, ..
+            }
+            | FirewallPolicy::CaptivePortal {
+                split_tunnel_mode, ..
+            } => *split_tunnel_mode,
+        }
+    }
+
+    /// Returns the exclusion profiles whose processes are excluded from the tunnel.
+    #[cfg(target_os = "linux")]
+    pub fn exclusion_profiles(&self) -> &[ExclusionProfile] {
+        match self {
+            FirewallPolicy::Connecting {
+                exclusion_profiles, ..
+            }
+            | FirewallPolicy::Connected {
+                exclusion_profiles, ..
+            }
+            | FirewallPolicy::Blocked {
+                exclusion_profiles, ..
+            }
+            | FirewallPolicy::CaptivePortal {
+                exclusion_profiles, ..
+            } => exclusion_profiles,
+        }
+    }
+
+    /// Returns the destinations that are routed outside the tunnel.
+    #[cfg(target_os = "linux")]
+    pub fn excluded_destinations(&self) -> &ExcludedDestinations {
+        match self {
+            FirewallPolicy::Connecting {
+                excluded_destinations,
+                ..
+            }
+            | FirewallPolicy::Connected {
+                excluded_destinations,
+                ..
+            }
+            | FirewallPolicy::Blocked {
+                excluded_destinations,
+                ..
+            }
+            | FirewallPolicy::CaptivePortal {
+                excluded_destinations,
+                ..
+            } => excluded_destinations,
+        }
+    }
+
+    /// Returns a human-readable description of the traffic that is allowed and blocked by the
+    /// policy, in the order that the rules are evaluated.
+    pub fn describe_rules(&self) -> Vec<String> {
+        let mut rules = vec![
+            "Allow all traffic on the loopback interface".to_owned(),
+            "Allow DHCP client traffic".to_owned(),
+            "Allow IPv6 Neighbor Discovery Protocol traffic".to_owned(),
+        ];
+        #[cfg(target_os = "linux")]
+        rules.push(
+            match self.split_tunnel_mode() {
+                SplitTunnelMode::Exclude => {
+                    "Allow all traffic of excluded processes outside the tunnel"
+                }
+                SplitTunnelMode::IncludeOnly => {
+                    "Allow all traffic of processes that are not included outside the tunnel"
+                }
+            }
+            .to_owned(),
+        );
+        #[cfg(target_os = "linux")]
+        for profile in self.exclusion_profiles() {
+            rules.push(format!(
+                "Allow all traffic of processes in exclusion profile \"{}\" outside the tunnel{}",
+                profile.name,
+                match profile.dns {
+                    ProfileDns::Tunnel => "",
+                    ProfileDns::Bypass => ", including DNS requests",
+                }
+            ));
+        }
+        #[cfg(target_os = "linux")]
+        for network in &self.excluded_destinations().networks {
+            rules.push(format!(
+                "Allow all traffic to {} outside the tunnel",
+                network
+            ));
+        }
+        #[cfg(target_os = "linux")]
+        for rule in self.allow_rules() {
+            rules.push(format!("Allow traffic {}", rule));
+        }
+
+        let allow_lan = match self {
+            FirewallPolicy::Connecting {
+                peer_endpoint,
+                tunnel,
+                allow_lan,
+                allowed_endpoint,
+                ..
+            } => {
+                rules.push(format!("Allow traffic to the relay at {}", peer_endpoint));
+                rules.push(format!(
+                    "Allow traffic to {} for privileged processes",
+                    allowed_endpoint
+                ));
+                rules.push("Block DNS requests to all other hosts".to_owned());
+                if let Some(tunnel) = tunnel {
+                    Self::describe_tunnel_rules(&mut rules, tunnel, *allow_lan);
+                }
+                *allow_lan
+            }
+            FirewallPolicy::Connected {
+                peer_endpoint,
+                tunnel,
+                allow_lan,
+                #[cfg(not(target_os = "android"))]
+                dns_servers,
+                #[cfg(target_os = "linux")]
+                split_dns_resolvers,
+                ..
+            } => {
+                rules.push(format!("Allow traffic to the relay at {}", peer_endpoint));
+                #[cfg(not(target_os = "android"))]
+                for server in dns_servers {
+                    rules.push(format!("Allow DNS requests to {}", server));
+                }
+                #[cfg(target_os = "linux")]
+                for resolver in split_dns_resolvers {
+                    rules.push(format!(
+                        "Allow DNS requests to {} outside the tunnel",
+                        resolver
+                    ));
+                }
+                rules.push("Block DNS requests to all other hosts".to_owned());
+                Self::describe_tunnel_rules(&mut rules, tunnel, *allow_lan);
+                *allow_lan
+            }
+            FirewallPolicy::Blocked {
+                allow_lan,
+                allowed_endpoint,
+                ..
+            } => {
+                rules.push(format!(
+                    "Allow traffic to {} for privileged processes",
+                    allowed_endpoint
+                ));
+                rules.push("Block DNS requests to all other hosts".to_owned());
+                *allow_lan
+            }
+            #[cfg(target_os = "linux")]
+            FirewallPolicy::CaptivePortal {
+                allow_lan,
+                allowed_endpoint,
+                portal_hosts,
+                ..
+            } => {
+                rules.push(format!(
+                    "Allow traffic to {} for privileged processes",
+                    allowed_endpoint
+                ));
+                for host in portal_hosts {
+                    rules.push(format!(
+                        "Allow HTTP, HTTPS and DNS requests to captive portal host {}",
+                        host
+                    ));
+                }
+                rules.push("Block DNS requests to all other hosts".to_owned());
+                *allow_lan
+            }
+        };
+
+        if allow_lan {
+            #[cfg(unix)]
+            {
+                let lan_networks = self.lan_networks();
+                for net in &lan_networks.networks {
+                    rules.push(format!("Allow traffic to and from local network {}", net));
+                }
+                for net in &lan_networks.multicast_networks {
+                    rules.push(format!("Allow traffic to multicast network {}", net));
+                }
+            }
+            #[cfg(windows)]
+            rules.push("Allow traffic to and from local networks".to_owned());
+            rules.push("Allow DHCP server traffic".to_owned());
+        }
+
+        rules.push("Block all other traffic".to_owned());
+        rules
+    }
+
+    fn describe_tunnel_rules(
+        rules: &mut Vec<String>,
+        tunnel: &crate::tunnel::TunnelMetadata,
+        allow_lan: bool,
+    ) {
+        rules.push(format!(
+            "Allow all traffic over the tunnel interface \"{}\"",
+            tunnel.interface
+        ));
+        if allow_lan {
+            for ip in &tunnel.ips {
+                rules.push(format!(
+                    "Block incoming traffic to the tunnel IP {} from other interfaces",
+                    ip
+                ));
+            }
         }
     }
 }
 
+/// A [`FirewallPolicy`] rendered into the rules that it would be enforced with. Rendering a
+/// policy does not touch the system firewall.
+#[derive(Debug, Clone)]
+pub struct RenderedPolicy {
+    /// The rendered policy.
+    pub policy: FirewallPolicy,
+    /// Human-readable description of the rules, see [`FirewallPolicy::describe_rules`].
+    pub rules: Vec<String>,
+    /// The rules in the format used by `nft list ruleset`.
+    #[cfg(target_os = "linux")]
+    pub nftables: String,
+}
+
 /// Manages network security of the computer/device. Can apply and enforce firewall policies
 /// by manipulating the OS firewall and DNS settings.
 pub struct Firewall {
     inner: imp::Firewall,
+    active_policy: Option<FirewallPolicy>,
 }
 
 /// Arguments required when first initializing the firewall.
@@ -243,6 +580,7 @@ impl Firewall {
     pub fn from_args(args: FirewallArguments) -> Result<Self, Error> {
         Ok(Firewall {
             inner: imp::Firewall::from_args(args)?,
+            active_policy: None,
         })
     }
 
@@ -250,6 +588,7 @@ impl Firewall {
     pub fn new() -> Result<Self, Error> {
         Ok(Firewall {
             inner: imp::Firewall::new()?,
+            active_policy: None,
         })
     }
 
@@ -257,13 +596,65 @@ impl Firewall {
     /// until this method is called again with another policy, or until `reset_policy` is called.
     pub fn apply_policy(&mut self, policy: FirewallPolicy) -> Result<(), Error> {
         log::info!("Applying firewall policy: {}", policy);
-        self.inner.apply_policy(policy)
+        self.active_policy = None;
+        self.inner.apply_policy(policy.clone())?;
+        self.active_policy = Some(policy);
+        Ok(())
     }
 
     /// Resets/removes any currently enforced `FirewallPolicy`. Returns the system to the same state
     /// it had before any policy was applied through this `Firewall` instance.
     pub fn reset_policy(&mut self) -> Result<(), Error> {
         log::info!("Resetting firewall policy");
+        self.active_policy = None;
         self.inner.reset_policy()
     }
+
+    /// Checks that the rules enforcing the active policy are still in place, and re-applies the
+    /// policy if another program has removed or modified them. Returns whether the policy was
+    /// re-applied.
+    #[cfg(target_os = "linux")]
+    pub fn restore_policy(&mut self) -> Result<bool, Error> {
+        let policy = match self.active_policy {
+            Some(ref policy) => policy.clone(),
+            None => return Ok(false),
+        };
+        match self.inner.verify_policy() {
+            Ok(()) => Ok(false),
+            Err(Error::NetfilterTableNotSetError) => {
+                log::warn!("The firewall rules have been removed by another program");
+                self.apply_policy(policy)?;
+                Ok(true)
+            }
+            Err(Error::NetfilterRulesModifiedError) => {
+                log::warn!("The firewall rules have been modified by another program");
+                self.apply_policy(policy)?;
+                Ok(true)
+            }
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Returns the policy that was most recently applied successfully, unless the policy has been
+    /// reset since.
+    pub fn active_policy(&self) -> Option<&FirewallPolicy> {
+        self.active_policy.as_ref()
+    }
+
+    /// Returns the destinations that the firewall most recently blocked traffic to, most recent
+    /// first.
+    #[cfg(target_os = "linux")]
+    pub fn blocked_connections(&self) -> Vec<BlockedConnection> {
+        self.inner.blocked_connections()
+    }
+
+    /// Renders the rules that would be used to enforce `policy`, without applying them.
+    pub fn render_policy(policy: &FirewallPolicy) -> RenderedPolicy {
+        RenderedPolicy {
+            policy: policy.clone(),
+            rules: policy.describe_rules(),
+            #[cfg(target_os = "linux")]
+            nftables: imp::Firewall::render_policy(policy),
+        }
+    }
 }
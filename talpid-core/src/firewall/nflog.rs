@@ -0,0 +1,713 @@
+//! Receives the packets that the firewall logs to NFLOG before dropping or rejecting them, and
+//! keeps track of the destinations and processes that were most recently blocked.
+
+use std::{
+    collections::VecDeque,
+    fs, io, mem,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    os::unix::io::RawFd,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, SystemTime},
+};
+use talpid_types::{net::TransportProtocol, ErrorExt};
+
+/// The NFLOG group that the firewall logs blocked packets to.
+pub const NFLOG_GROUP: u16 = 1717;
+
+/// The NFLOG group that the firewall logs incoming DNS responses to, while the addresses of
+/// excluded domains are tracked.
+pub const DNS_NFLOG_GROUP: u16 = 1718;
+
+/// The maximum number of distinct blocked connections to remember.
+const MAX_BLOCKED_CONNECTIONS: usize = 100;
+
+/// Only the IP header and the ports of the transport header are needed.
+const COPY_RANGE: u32 = 64;
+
+/// How often the receiving thread checks whether it should stop.
+const RECV_TIMEOUT: Duration = Duration::from_secs(1);
+
+const NLMSG_HDRLEN: usize = mem::size_of::<libc::nlmsghdr>();
+const NFGENMSG_LEN: usize = 4;
+const NLA_HDRLEN: usize = 4;
+const NLA_TYPE_MASK: u16 = 0x3fff;
+
+const NFNETLINK_V0: u8 = 0;
+const NFNL_SUBSYS_ULOG: u16 = 4;
+const NFULNL_MSG_PACKET: u16 = 0;
+const NFULNL_MSG_CONFIG: u16 = 1;
+
+const NFULA_CFG_CMD: u16 = 1;
+const NFULA_CFG_MODE: u16 = 2;
+const NFULNL_CFG_CMD_BIND: u8 = 1;
+const NFULNL_COPY_PACKET: u8 = 2;
+
+const NFULA_PAYLOAD: u16 = 9;
+const NFULA_UID: u16 = 11;
+
+/// A destination that the firewall has blocked traffic to.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct BlockedConnection {
+    /// Destination address of the blocked packets.
+    pub destination: IpAddr,
+    /// Transport protocol of the blocked packets, if TCP or UDP.
+    pub protocol: Option<TransportProtocol>,
+    /// Destination port of the blocked packets, if TCP or UDP.
+    pub port: Option<u16>,
+    /// The user owning the socket that sent the packets. Not set for forwarded packets.
+    pub uid: Option<u32>,
+    /// Name of the process that sent the packets, if it could be determined.
+    pub process: Option<String>,
+    /// Number of logged packets. Logging is rate limited, so more packets may have been blocked.
+    pub packets: u64,
+    /// When a packet was most recently logged.
+    pub last_blocked: SystemTime,
+}
+
+/// Fields of interest in a packet received from NFLOG.
+#[derive(Debug, Clone, Eq, PartialEq)]
+struct LoggedPacket {
+    source: IpAddr,
+    source_port: Option<u16>,
+    destination: IpAddr,
+    protocol: Option<TransportProtocol>,
+    port: Option<u16>,
+    uid: Option<u32>,
+}
+
+/// Bounded list of recently blocked connections. The most recently blocked connection is last.
+#[derive(Debug, Default)]
+struct BlockedConnections(VecDeque<BlockedConnection>);
+
+impl BlockedConnections {
+    fn position(&self, packet: &LoggedPacket) -> Option<usize> {
+        self.0.iter().position(|connection| {
+            connection.destination == packet.destination
+                && connection.protocol == packet.protocol
+                && connection.port == packet.port
+                && connection.uid == packet.uid
+        })
+    }
+
+    /// Returns whether `packet` belongs to a connection that has already been blocked.
+    fn contains(&self, packet: &LoggedPacket) -> bool {
+        self.position(packet).is_some()
+    }
+
+    /// Records a blocked packet. `process` is only used if the packet belongs to a connection
+    /// that has not been blocked before.
+    fn add(&mut self, packet: LoggedPacket, process: Option<String>, now: SystemTime) {
+        let position = self.position(&packet);
+        let mut connection = match position.and_then(|position| self.0.remove(position)) {
+            Some(connection) => connection,
+            None => BlockedConnection {
+                destination: packet.destination,
+                protocol: packet.protocol,
+                port: packet.port,
+                uid: packet.uid,
+                process,
+                packets: 0,
+                last_blocked: now,
+            },
+        };
+        connection.packets += 1;
+        connection.last_blocked = now;
+
+        if self.0.len() >= MAX_BLOCKED_CONNECTIONS {
+            self.0.pop_front();
+        }
+        self.0.push_back(connection);
+    }
+}
+
+/// Listens for blocked packets on a background thread until dropped.
+pub struct Monitor {
+    connections: Arc<Mutex<BlockedConnections>>,
+    _listener: Listener,
+}
+
+impl Monitor {
+    /// Binds to [`NFLOG_GROUP`] and starts receiving logged packets.
+    pub fn spawn() -> io::Result<Self> {
+        let connections = Arc::new(Mutex::new(BlockedConnections::default()));
+        let listener = {
+            let connections = connections.clone();
+            Listener::spawn(NFLOG_GROUP, COPY_RANGE, move |attributes| {
+                if let Some(packet) = parse_attributes(attributes) {
+                    // Scanning /proc is slow, so it is done without holding the lock, and only
+                    // for new connections.
+                    let is_known = connections.lock().unwrap().contains(&packet);
+                    let process = if is_known {
+                        None
+                    } else {
+                        packet_process(&packet)
+                    };
+                    connections
+                        .lock()
+                        .unwrap()
+                        .add(packet, process, SystemTime::now());
+                }
+            })?
+        };
+        Ok(Monitor {
+            connections,
+            _listener: listener,
+        })
+    }
+
+    /// Returns the recently blocked connections, most recently blocked first.
+    pub fn blocked_connections(&self) -> Vec<BlockedConnection> {
+        self.connections
+            .lock()
+            .unwrap()
+            .0
+            .iter()
+            .rev()
+            .cloned()
+            .collect()
+    }
+}
+
+/// Receives the packets that are logged to an NFLOG group on a background thread until dropped.
+pub(crate) struct Listener {
+    shutdown: Arc<AtomicBool>,
+}
+
+impl Listener {
+    /// Binds to `group` and calls `on_packet` with the attributes of every logged packet. At most
+    /// `copy_range` bytes of each packet are copied to userspace.
+    pub fn spawn(
+        group: u16,
+        copy_range: u32,
+        on_packet: impl FnMut(&[u8]) + Send + 'static,
+    ) -> io::Result<Self> {
+        let socket = Socket::open()?;
+        socket.request(&config_message(
+            group,
+            &[(NFULA_CFG_CMD, &[NFULNL_CFG_CMD_BIND])],
+        ))?;
+
+        let mut mode = copy_range.to_be_bytes().to_vec();
+        mode.extend_from_slice(&[NFULNL_COPY_PACKET, 0]);
+        socket.request(&config_message(group, &[(NFULA_CFG_MODE, &mode)]))?;
+
+        let shutdown = Arc::new(AtomicBool::new(false));
+        {
+            let shutdown = shutdown.clone();
+            thread::spawn(move || receive_packets(socket, on_packet, shutdown));
+        }
+        Ok(Listener { shutdown })
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::Release);
+    }
+}
+
+fn receive_packets(socket: Socket, mut on_packet: impl FnMut(&[u8]), shutdown: Arc<AtomicBool>) {
+    let mut buffer = vec![0u8; 65536];
+    while !shutdown.load(Ordering::Acquire) {
+        let length = match socket.recv(&mut buffer) {
+            Ok(length) => length,
+            Err(error)
+                if error.kind() == io::ErrorKind::WouldBlock
+                    || error.kind() == io::ErrorKind::Interrupted =>
+            {
+                continue
+            }
+            // The kernel drops messages when the socket buffer is full
+            Err(error) if error.raw_os_error() == Some(libc::ENOBUFS) => continue,
+            Err(error) => {
+                log::error!(
+                    "{}",
+                    error.display_chain_with_msg("Failed to receive logged packets")
+                );
+                break;
+            }
+        };
+        for attributes in packet_messages(&buffer[..length]) {
+            on_packet(attributes);
+        }
+    }
+}
+
+/// A netlink socket connected to the netfilter subsystem.
+struct Socket(RawFd);
+
+impl Socket {
+    fn open() -> io::Result<Self> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                libc::NETLINK_NETFILTER,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let socket = Socket(fd);
+
+        let mut address: libc::sockaddr_nl = unsafe { mem::zeroed() };
+        address.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        let result = unsafe {
+            libc::bind(
+                fd,
+                &address as *const libc::sockaddr_nl as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let timeout = libc::timeval {
+            tv_sec: RECV_TIMEOUT.as_secs() as libc::time_t,
+            tv_usec: 0,
+        };
+        let result = unsafe {
+            libc::setsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                libc::SO_RCVTIMEO,
+                &timeout as *const libc::timeval as *const libc::c_void,
+                mem::size_of::<libc::timeval>() as libc::socklen_t,
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(socket)
+    }
+
+    /// Sends a request and waits for it to be acknowledged.
+    fn request(&self, message: &[u8]) -> io::Result<()> {
+        let result = unsafe { libc::send(self.0, message.as_ptr() as *const _, message.len(), 0) };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut buffer = vec![0u8; 8192];
+        loop {
+            let length = self.recv(&mut buffer)?;
+            let mut messages = &buffer[..length];
+            while let Some((kind, payload, rest)) = split_message(messages) {
+                if kind == libc::NLMSG_ERROR as u16 {
+                    let code = payload
+                        .get(..4)
+                        .map(|code| i32::from_ne_bytes([code[0], code[1], code[2], code[3]]))
+                        .unwrap_or(-libc::EINVAL);
+                    return match code {
+                        0 => Ok(()),
+                        code => Err(io::Error::from_raw_os_error(-code)),
+                    };
+                }
+                messages = rest;
+            }
+        }
+    }
+
+    fn recv(&self, buffer: &mut [u8]) -> io::Result<usize> {
+        let result = unsafe { libc::recv(self.0, buffer.as_mut_ptr() as *mut _, buffer.len(), 0) };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(result as usize)
+    }
+}
+
+impl Drop for Socket {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.0) };
+    }
+}
+
+/// Builds a configuration request for an NFLOG group with the given attributes.
+fn config_message(group: u16, attributes: &[(u16, &[u8])]) -> Vec<u8> {
+    let mut message = vec![0u8; NLMSG_HDRLEN];
+    // struct nfgenmsg
+    message.extend_from_slice(&[libc::AF_UNSPEC as u8, NFNETLINK_V0]);
+    message.extend_from_slice(&group.to_be_bytes());
+
+    for (kind, payload) in attributes {
+        message.extend_from_slice(&((NLA_HDRLEN + payload.len()) as u16).to_ne_bytes());
+        message.extend_from_slice(&kind.to_ne_bytes());
+        message.extend_from_slice(payload);
+        message.resize(align(message.len()), 0);
+    }
+
+    let length = message.len() as u32;
+    let kind = (NFNL_SUBSYS_ULOG << 8) | NFULNL_MSG_CONFIG;
+    let flags = (libc::NLM_F_REQUEST | libc::NLM_F_ACK) as u16;
+    message[0..4].copy_from_slice(&length.to_ne_bytes());
+    message[4..6].copy_from_slice(&kind.to_ne_bytes());
+    message[6..8].copy_from_slice(&flags.to_ne_bytes());
+    message
+}
+
+/// Splits off the first netlink message in `buffer`, returning its type, its payload and the
+/// remaining messages.
+fn split_message(buffer: &[u8]) -> Option<(u16, &[u8], &[u8])> {
+    if buffer.len() < NLMSG_HDRLEN {
+        return None;
+    }
+    let length = u32::from_ne_bytes([buffer[0], buffer[1], buffer[2], buffer[3]]) as usize;
+    if length < NLMSG_HDRLEN || length > buffer.len() {
+        return None;
+    }
+    let kind = u16::from_ne_bytes([buffer[4], buffer[5]]);
+    let rest = &buffer[align(length).min(buffer.len())..];
+    Some((kind, &buffer[NLMSG_HDRLEN..length], rest))
+}
+
+/// Returns the attributes of every logged packet in `buffer`.
+fn packet_messages(mut buffer: &[u8]) -> Vec<&[u8]> {
+    let mut packets = vec![];
+    while let Some((kind, payload, rest)) = split_message(buffer) {
+        if kind == (NFNL_SUBSYS_ULOG << 8) | NFULNL_MSG_PACKET {
+            if let Some(attributes) = payload.get(NFGENMSG_LEN..) {
+                packets.push(attributes);
+            }
+        }
+        buffer = rest;
+    }
+    packets
+}
+
+/// Returns the copied part of the logged packet, starting with its IP header.
+pub(crate) fn packet_payload(mut attributes: &[u8]) -> Option<&[u8]> {
+    while attributes.len() >= NLA_HDRLEN {
+        let length = u16::from_ne_bytes([attributes[0], attributes[1]]) as usize;
+        if length < NLA_HDRLEN || length > attributes.len() {
+            break;
+        }
+        let kind = u16::from_ne_bytes([attributes[2], attributes[3]]) & NLA_TYPE_MASK;
+        if kind == NFULA_PAYLOAD {
+            return Some(&attributes[NLA_HDRLEN..length]);
+        }
+        attributes = &attributes[align(length).min(attributes.len())..];
+    }
+    None
+}
+
+fn parse_attributes(mut attributes: &[u8]) -> Option<LoggedPacket> {
+    let mut packet = None;
+    let mut uid = None;
+
+    while attributes.len() >= NLA_HDRLEN {
+        let length = u16::from_ne_bytes([attributes[0], attributes[1]]) as usize;
+        if length < NLA_HDRLEN || length > attributes.len() {
+            break;
+        }
+        let kind = u16::from_ne_bytes([attributes[2], attributes[3]]) & NLA_TYPE_MASK;
+        let payload = &attributes[NLA_HDRLEN..length];
+        match kind {
+            NFULA_PAYLOAD => packet = parse_ip_packet(payload),
+            NFULA_UID if payload.len() >= 4 => {
+                uid = Some(u32::from_be_bytes([
+                    payload[0], payload[1], payload[2], payload[3],
+                ]))
+            }
+            _ => (),
+        }
+        attributes = &attributes[align(length).min(attributes.len())..];
+    }
+
+    packet.map(|packet| LoggedPacket { uid, ..packet })
+}
+
+/// Reads the addresses and ports of an IPv4 or IPv6 packet. IPv6 extension headers are not
+/// parsed, so ports are unknown for packets that have any.
+fn parse_ip_packet(packet: &[u8]) -> Option<LoggedPacket> {
+    let (source, destination, protocol, transport): (IpAddr, IpAddr, u8, &[u8]) =
+        match packet.first()? >> 4 {
+            4 if packet.len() >= 20 => {
+                let header_length = usize::from(packet[0] & 0x0f) * 4;
+                let mut source = [0u8; 4];
+                let mut destination = [0u8; 4];
+                source.copy_from_slice(&packet[12..16]);
+                destination.copy_from_slice(&packet[16..20]);
+                (
+                    Ipv4Addr::from(source).into(),
+                    Ipv4Addr::from(destination).into(),
+                    packet[9],
+                    packet.get(header_length..).unwrap_or(&[]),
+                )
+            }
+            6 if packet.len() >= 40 => {
+                let mut source = [0u8; 16];
+                let mut destination = [0u8; 16];
+                source.copy_from_slice(&packet[8..24]);
+                destination.copy_from_slice(&packet[24..40]);
+                (
+                    Ipv6Addr::from(source).into(),
+                    Ipv6Addr::from(destination).into(),
+                    packet[6],
+                    &packet[40..],
+                )
+            }
+            _ => return None,
+        };
+
+    let protocol = match i32::from(protocol) {
+        libc::IPPROTO_TCP => Some(TransportProtocol::Tcp),
+        libc::IPPROTO_UDP => Some(TransportProtocol::Udp),
+        _ => None,
+    };
+    let (source_port, port) = match (protocol, transport.get(..4)) {
+        (Some(_), Some(ports)) => (
+            Some(u16::from_be_bytes([ports[0], ports[1]])),
+            Some(u16::from_be_bytes([ports[2], ports[3]])),
+        ),
+        _ => (None, None),
+    };
+
+    Some(LoggedPacket {
+        source,
+        source_port,
+        destination,
+        protocol,
+        port,
+        uid: None,
+    })
+}
+
+fn align(length: usize) -> usize {
+    (length + 3) & !3
+}
+
+/// Returns the name of the local process that sent `packet`, if it could be determined.
+fn packet_process(packet: &LoggedPacket) -> Option<String> {
+    match (packet.uid, packet.protocol, packet.source_port) {
+        (Some(_), Some(protocol), Some(source_port)) => {
+            find_process(protocol, SocketAddr::new(packet.source, source_port))
+        }
+        _ => None,
+    }
+}
+
+/// Returns the name of the process that owns the socket bound to `source`, if any.
+fn find_process(protocol: TransportProtocol, source: SocketAddr) -> Option<String> {
+    let inode = find_socket_inode(protocol, source)?;
+    let socket_link = format!("socket:[{}]", inode);
+
+    for process in fs::read_dir("/proc").ok()?.flatten() {
+        let fds = match fs::read_dir(process.path().join("fd")) {
+            Ok(fds) => fds,
+            Err(_) => continue,
+        };
+        let owns_socket = fds.flatten().any(|fd| {
+            fs::read_link(fd.path())
+                .map(|target| target.as_os_str() == socket_link.as_str())
+                .unwrap_or(false)
+        });
+        if owns_socket {
+            return fs::read_to_string(process.path().join("comm"))
+                .ok()
+                .map(|comm| comm.trim_end().to_owned());
+        }
+    }
+    None
+}
+
+/// Looks up the inode of the socket bound to `source` in `/proc/net`.
+fn find_socket_inode(protocol: TransportProtocol, source: SocketAddr) -> Option<u64> {
+    let path = match (protocol, source) {
+        (TransportProtocol::Tcp, SocketAddr::V4(_)) => "/proc/net/tcp",
+        (TransportProtocol::Tcp, SocketAddr::V6(_)) => "/proc/net/tcp6",
+        (TransportProtocol::Udp, SocketAddr::V4(_)) => "/proc/net/udp",
+        (TransportProtocol::Udp, SocketAddr::V6(_)) => "/proc/net/udp6",
+    };
+    let table = fs::read_to_string(path).ok()?;
+
+    table.lines().skip(1).find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let local_address = parse_proc_address(fields.get(1)?)?;
+        let inode = fields.get(9)?.parse().ok()?;
+        let matches_address =
+            local_address.ip() == source.ip() || local_address.ip().is_unspecified();
+        if matches_address && local_address.port() == source.port() && inode != 0 {
+            Some(inode)
+        } else {
+            None
+        }
+    })
+}
+
+/// Parses an address of the form `0100007F:0035`, where the IP address is printed as one or four
+/// 32-bit words in host byte order.
+fn parse_proc_address(address: &str) -> Option<SocketAddr> {
+    let mut parts = address.split(':');
+    let ip = parts.next()?;
+    let port = u16::from_str_radix(parts.next()?, 16).ok()?;
+
+    let mut bytes = Vec::with_capacity(16);
+    for word in 0..ip.len() / 8 {
+        let word = u32::from_str_radix(ip.get(word * 8..word * 8 + 8)?, 16).ok()?;
+        bytes.extend_from_slice(&word.to_ne_bytes());
+    }
+    let ip: IpAddr = match bytes.len() {
+        4 => Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]).into(),
+        16 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&bytes);
+            Ipv6Addr::from(octets).into()
+        }
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, port))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn tcp_packet() -> Vec<u8> {
+        let mut packet = vec![
+            0x45, 0, 0, 40, 0, 0, 0x40, 0, 64, 6, 0,
+            0, // version, ..., TTL, protocol, checksum
+            192, 168, 1, 2, // source
+            203, 0, 113, 7, // destination
+        ];
+        packet.extend_from_slice(&54321u16.to_be_bytes());
+        packet.extend_from_slice(&443u16.to_be_bytes());
+        packet
+    }
+
+    fn attribute(kind: u16, payload: &[u8]) -> Vec<u8> {
+        let mut attribute = ((NLA_HDRLEN + payload.len()) as u16).to_ne_bytes().to_vec();
+        attribute.extend_from_slice(&kind.to_ne_bytes());
+        attribute.extend_from_slice(payload);
+        attribute.resize(align(attribute.len()), 0);
+        attribute
+    }
+
+    fn packet_message(attributes: &[u8]) -> Vec<u8> {
+        let length = (NLMSG_HDRLEN + NFGENMSG_LEN + attributes.len()) as u32;
+        let mut message = length.to_ne_bytes().to_vec();
+        message.extend_from_slice(&((NFNL_SUBSYS_ULOG << 8) | NFULNL_MSG_PACKET).to_ne_bytes());
+        message.resize(NLMSG_HDRLEN, 0);
+        message.extend_from_slice(&[libc::AF_INET as u8, NFNETLINK_V0, 0, 0]);
+        message.extend_from_slice(attributes);
+        message
+    }
+
+    fn parse_messages(buffer: &[u8]) -> Vec<LoggedPacket> {
+        packet_messages(buffer)
+            .into_iter()
+            .filter_map(parse_attributes)
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_packet_message() {
+        let mut attributes = attribute(NFULA_PAYLOAD, &tcp_packet());
+        attributes.extend(attribute(NFULA_UID, &1000u32.to_be_bytes()));
+        let mut messages = packet_message(&attributes);
+        messages.extend(packet_message(&attribute(NFULA_PAYLOAD, &tcp_packet())));
+
+        let packets = parse_messages(&messages);
+        let expected = LoggedPacket {
+            source: "192.168.1.2".parse().unwrap(),
+            source_port: Some(54321),
+            destination: "203.0.113.7".parse().unwrap(),
+            protocol: Some(TransportProtocol::Tcp),
+            port: Some(443),
+            uid: Some(1000),
+        };
+        assert_eq!(
+            packets,
+            vec![
+                expected.clone(),
+                LoggedPacket {
+                    uid: None,
+                    ..expected
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_truncated_message() {
+        let message = packet_message(&attribute(NFULA_PAYLOAD, &tcp_packet()));
+        assert_eq!(parse_messages(&message[..message.len() - 1]), vec![]);
+    }
+
+    #[test]
+    fn test_packet_payload() {
+        let mut attributes = attribute(NFULA_UID, &1000u32.to_be_bytes());
+        attributes.extend(attribute(NFULA_PAYLOAD, &tcp_packet()));
+        assert_eq!(packet_payload(&attributes), Some(&tcp_packet()[..]));
+        assert_eq!(
+            packet_payload(&attribute(NFULA_UID, &1000u32.to_be_bytes())),
+            None
+        );
+    }
+
+    #[test]
+    fn test_parse_proc_address() {
+        let address = if cfg!(target_endian = "little") {
+            "0101A8C0:0035"
+        } else {
+            "C0A80101:0035"
+        };
+        assert_eq!(
+            parse_proc_address(address),
+            Some("192.168.1.1:53".parse().unwrap())
+        );
+        assert_eq!(
+            parse_proc_address("00000000000000000000000000000000:1F90"),
+            Some("[::]:8080".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn test_blocked_connections_are_bounded() {
+        let mut connections = BlockedConnections::default();
+        let now = SystemTime::now();
+        for port in 0..=MAX_BLOCKED_CONNECTIONS as u16 {
+            connections.add(
+                LoggedPacket {
+                    source: "192.168.1.2".parse().unwrap(),
+                    source_port: None,
+                    destination: "203.0.113.7".parse().unwrap(),
+                    protocol: Some(TransportProtocol::Udp),
+                    port: Some(port),
+                    uid: None,
+                },
+                None,
+                now,
+            );
+        }
+        assert_eq!(connections.0.len(), MAX_BLOCKED_CONNECTIONS);
+        assert_eq!(connections.0.front().unwrap().port, Some(1));
+    }
+
+    #[test]
+    fn test_blocked_connections_are_aggregated() {
+        let mut connections = BlockedConnections::default();
+        let packet = |port| LoggedPacket {
+            source: "192.168.1.2".parse().unwrap(),
+            source_port: None,
+            destination: "203.0.113.7".parse().unwrap(),
+            protocol: Some(TransportProtocol::Udp),
+            port: Some(port),
+            uid: None,
+        };
+        let now = SystemTime::now();
+        connections.add(packet(53), None, now);
+        connections.add(packet(123), None, now);
+        connections.add(packet(53), None, now);
+
+        assert_eq!(connections.0.len(), 2);
+        assert_eq!(connections.0.back().unwrap().port, Some(53));
+        assert_eq!(connections.0.back().unwrap().packets, 2);
+    }
+}
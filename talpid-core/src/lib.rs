@@ -63,6 +63,10 @@ mod mktemp;
 #[cfg(target_os = "linux")]
 mod linux;
 
+/// Captive portal detection and login.
+#[cfg(target_os = "linux")]
+pub mod captive_portal;
+
 /// A pair of functions to monitor and establish connectivity with ICMP
 pub mod ping_monitor;
 
@@ -2,6 +2,7 @@ use std::{
     ffi::{self, CString},
     fs, io,
 };
+use talpid_types::net::TrafficStats;
 
 const PROC_SYS_NET_IPV4_CONF_SRC_VALID_MARK: &str = "/proc/sys/net/ipv4/conf/all/src_valid_mark";
 
@@ -32,6 +33,21 @@ pub enum IfaceIndexLookupError {
 pub const TUNNEL_FW_MARK: u32 = 0x6d6f6c65;
 pub const TUNNEL_TABLE_ID: u32 = 0x6d6f6c65;
 
+/// Returns the number of bytes received and sent through an interface since it was created.
+pub fn interface_traffic(name: &str) -> io::Result<TrafficStats> {
+    let read_counter = |counter: &str| -> io::Result<u64> {
+        let path = format!("/sys/class/net/{}/statistics/{}", name, counter);
+        fs::read_to_string(path)?
+            .trim()
+            .parse()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid interface counter"))
+    };
+    Ok(TrafficStats {
+        rx_bytes: read_counter("rx_bytes")?,
+        tx_bytes: read_counter("tx_bytes")?,
+    })
+}
+
 pub fn set_src_valid_mark_sysctl() -> io::Result<()> {
     fs::write(PROC_SYS_NET_IPV4_CONF_SRC_VALID_MARK, b"1")
 }
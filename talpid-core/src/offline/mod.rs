@@ -20,6 +20,9 @@ mod imp;
 #[path = "android.rs"]
 mod imp;
 
+#[cfg(target_os = "linux")]
+pub mod network_identity;
+
 lazy_static::lazy_static! {
     /// Disables offline monitor
     static ref FORCE_DISABLE_OFFLINE_MONITOR: bool = std::env::var("TALPID_DISABLE_OFFLINE_MONITOR")
@@ -0,0 +1,254 @@
+//! Monitors which networks the host is connected to, so that trusted network rules can be
+//! evaluated whenever the default routes change.
+use super::{
+    imp::{PUBLIC_INTERNET_ADDRESS_V4, PUBLIC_INTERNET_ADDRESS_V6},
+    Error,
+};
+use crate::routing::RouteManagerHandle;
+use futures::{channel::mpsc::UnboundedSender, Stream, StreamExt};
+use std::{fs, net::IpAddr};
+use talpid_dbus::network_manager::NetworkManager;
+use talpid_types::{
+    net::trusted_network::{parse_mac_address, NetworkConnection, NetworkIdentity},
+    ErrorExt,
+};
+
+const ARP_TABLE_PATH: &str = "/proc/net/arp";
+
+/// Describes the networks that the host is currently connected to.
+#[async_trait::async_trait]
+pub trait NetworkIdentitySource: Send + 'static {
+    /// Returns the identity of the current networks. Failures to look up any part of the
+    /// identity are logged, and the part is left out.
+    async fn identity(&mut self) -> NetworkIdentity;
+}
+
+/// Identifies networks by the default routes, the ARP table and NetworkManager.
+pub struct SystemNetworkIdentity {
+    route_manager: RouteManagerHandle,
+}
+
+impl SystemNetworkIdentity {
+    pub fn new(route_manager: RouteManagerHandle) -> Self {
+        Self { route_manager }
+    }
+}
+
+/// Returns the interfaces that carry a default route outside the tunnel, along with the
+/// gateways of the routes, if any.
+pub(crate) async fn default_connections(
+    route_manager: &RouteManagerHandle,
+) -> Vec<(String, Option<IpAddr>)> {
+    let mut connections: Vec<(String, Option<IpAddr>)> = vec![];
+    for destination in &[PUBLIC_INTERNET_ADDRESS_V4, PUBLIC_INTERNET_ADDRESS_V6] {
+        let route = match route_manager
+            .get_destination_route(*destination, true)
+            .await
+        {
+            Ok(Some(route)) => route,
+            Ok(None) => continue,
+            Err(error) => {
+                log::error!(
+                    "{}",
+                    error.display_chain_with_msg("Failed to obtain default route")
+                );
+                continue;
+            }
+        };
+        let node = route.get_node();
+        let interface = match node.get_device() {
+            Some(interface) => interface.to_owned(),
+            None => continue,
+        };
+        match connections.iter_mut().find(|(name, _)| name == &interface) {
+            Some((_, gateway)) => {
+                if gateway.is_none() {
+                    *gateway = node.get_address();
+                }
+            }
+            None => connections.push((interface, node.get_address())),
+        }
+    }
+    connections
+}
+
+#[async_trait::async_trait]
+impl NetworkIdentitySource for SystemNetworkIdentity {
+    async fn identity(&mut self) -> NetworkIdentity {
+        let connections = default_connections(&self.route_manager).await;
+        let result = tokio::task::spawn_blocking(move || {
+            let arp_table = fs::read_to_string(ARP_TABLE_PATH)
+                .map_err(|error| {
+                    log::error!("Failed to read {}: {}", ARP_TABLE_PATH, error);
+                })
+                .unwrap_or_default();
+            let network_manager = NetworkManager::new()
+                .map_err(|error| {
+                    log::debug!("Not looking up SSIDs: {}", error);
+                })
+                .ok();
+
+            connections
+                .into_iter()
+                .map(|(interface, gateway)| {
+                    let gateway_mac =
+                        gateway.and_then(|gateway| gateway_mac(&arp_table, &interface, gateway));
+                    let ssid = network_manager.as_ref().and_then(|network_manager| {
+                        network_manager
+                            .get_wireless_ssid(&interface)
+                            .map_err(|error| {
+                                log::debug!(
+                                    "{}",
+                                    error.display_chain_with_msg(&format!(
+                                        "Failed to obtain SSID for {}",
+                                        interface
+                                    ))
+                                );
+                            })
+                            .ok()
+                            .flatten()
+                    });
+                    NetworkConnection {
+                        interface,
+                        gateway_mac,
+                        ssid,
+                    }
+                })
+                .collect()
+        })
+        .await;
+
+        match result {
+            Ok(connections) => NetworkIdentity { connections },
+            Err(error) => {
+                log::error!("Failed to identify network: {}", error);
+                NetworkIdentity::default()
+            }
+        }
+    }
+}
+
+/// Finds the hardware address of a gateway in the contents of `/proc/net/arp`. Only IPv4
+/// gateways can be found there.
+fn gateway_mac(arp_table: &str, interface: &str, gateway: IpAddr) -> Option<String> {
+    let gateway = gateway.to_string();
+    arp_table.lines().skip(1).find_map(|line| {
+        let columns: Vec<&str> = line.split_whitespace().collect();
+        match columns.as_slice() {
+            [address, _hw_type, _flags, hw_address, _mask, device]
+                if *address == gateway && *device == interface =>
+            {
+                parse_mac_address(hw_address).filter(|mac| mac != "00:00:00:00:00:00")
+            }
+            _ => None,
+        }
+    })
+}
+
+/// Sends the identity of the current networks to `listener`, and then sends it again whenever it
+/// differs after an item is received from `changes`.
+pub async fn monitor(
+    mut source: impl NetworkIdentitySource,
+    mut changes: impl Stream<Item = ()> + Unpin,
+    listener: UnboundedSender<NetworkIdentity>,
+) {
+    let mut identity = source.identity().await;
+    if listener.unbounded_send(identity.clone()).is_err() {
+        return;
+    }
+    while changes.next().await.is_some() {
+        let new_identity = source.identity().await;
+        if new_identity != identity {
+            identity = new_identity;
+            if listener.unbounded_send(identity.clone()).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Spawns a task that monitors the identity of the current networks whenever routes change.
+/// Unlike the offline monitor, this cannot be disabled, since trusted networks depend on it.
+pub async fn spawn_monitor(
+    listener: UnboundedSender<NetworkIdentity>,
+    route_manager: RouteManagerHandle,
+) -> Result<(), Error> {
+    let changes = route_manager
+        .change_listener()
+        .await
+        .map_err(Error::RouteManagerError)?
+        .map(|_| ());
+    let source = SystemNetworkIdentity::new(route_manager);
+    tokio::spawn(monitor(source, Box::pin(changes), listener));
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::channel::mpsc;
+    use std::collections::VecDeque;
+
+    struct MockNetworkIdentity(VecDeque<NetworkIdentity>);
+
+    #[async_trait::async_trait]
+    impl NetworkIdentitySource for MockNetworkIdentity {
+        async fn identity(&mut self) -> NetworkIdentity {
+            self.0.pop_front().unwrap_or_default()
+        }
+    }
+
+    fn identity(interfaces: &[&str]) -> NetworkIdentity {
+        NetworkIdentity {
+            connections: interfaces
+                .iter()
+                .map(|interface| NetworkConnection {
+                    interface: interface.to_string(),
+                    gateway_mac: None,
+                    ssid: None,
+                })
+                .collect(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_monitor_sends_changed_identities() {
+        let source = MockNetworkIdentity(VecDeque::from(vec![
+            identity(&["eth0"]),
+            identity(&["eth0"]),
+            identity(&["wlan0"]),
+            identity(&[]),
+        ]));
+        let (tx, rx) = mpsc::unbounded();
+
+        monitor(source, futures::stream::iter(vec![(); 3]), tx).await;
+
+        let identities: Vec<NetworkIdentity> = rx.collect().await;
+        assert_eq!(
+            identities,
+            vec![identity(&["eth0"]), identity(&["wlan0"]), identity(&[])]
+        );
+    }
+
+    #[test]
+    fn test_gateway_mac() {
+        let arp_table = "\
+IP address       HW type     Flags       HW address            Mask     Device
+192.168.1.1      0x1         0x2         AA:BB:CC:01:02:03     *        wlan0
+10.0.0.1         0x1         0x0         00:00:00:00:00:00     *        eth0
+";
+        assert_eq!(
+            gateway_mac(arp_table, "wlan0", "192.168.1.1".parse().unwrap()).as_deref(),
+            Some("aa:bb:cc:01:02:03")
+        );
+        assert_eq!(
+            gateway_mac(arp_table, "eth0", "192.168.1.1".parse().unwrap()),
+            None
+        );
+        // Incomplete entries are ignored
+        assert_eq!(
+            gateway_mac(arp_table, "eth0", "10.0.0.1".parse().unwrap()),
+            None
+        );
+    }
+}
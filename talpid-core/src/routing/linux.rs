@@ -22,8 +22,8 @@ use netlink_packet_route::{
     route::{nlas::Nla as RouteNla, RouteHeader, RouteMessage},
     rtnl::{
         constants::{
-            RTN_UNSPEC, RTPROT_UNSPEC, RT_SCOPE_LINK, RT_SCOPE_UNIVERSE, RT_TABLE_COMPAT,
-            RT_TABLE_MAIN,
+            RTN_THROW, RTN_UNSPEC, RTPROT_UNSPEC, RT_SCOPE_LINK, RT_SCOPE_UNIVERSE,
+            RT_TABLE_COMPAT, RT_TABLE_MAIN,
         },
         RouteFlags,
     },
@@ -270,12 +270,7 @@ impl RouteManagerImpl {
         let mut required_normal_routes = HashSet::new();
 
         for route in required_routes {
-            match route.node {
-                NetNode::RealNode(node) => {
-                    required_normal_routes
-                        .insert(Route::new(node, route.prefix).table(route.table_id));
-                }
-            }
+            required_normal_routes.insert(Self::to_route(route));
         }
 
         for normal_route in required_normal_routes.into_iter() {
@@ -285,6 +280,29 @@ impl RouteManagerImpl {
         Ok(())
     }
 
+    async fn remove_required_routes(
+        &mut self,
+        required_routes: HashSet<RequiredRoute>,
+    ) -> Result<()> {
+        for route in required_routes.into_iter().map(Self::to_route) {
+            self.delete_route_if_exists(&route).await?;
+            self.added_routes.remove(&route);
+        }
+        Ok(())
+    }
+
+    fn to_route(route: RequiredRoute) -> Route {
+        match route.node {
+            NetNode::RealNode(node) => Route::new(node, route.prefix).table(route.table_id),
+            // Routing rules direct all traffic that should be tunneled to the tunnel table, and
+            // everything else to the main table. Throwing the destination out of the table makes
+            // the lookup continue with the next rule, and end up using the current default route.
+            NetNode::DefaultNode => {
+                Route::new(Node::unspecified(), route.prefix).table(route.table_id)
+            }
+        }
+    }
+
     async fn initialize_link_map(
         handle: &rtnetlink::Handle,
     ) -> Result<BTreeMap<u32, NetworkInterface>> {
@@ -351,6 +369,10 @@ impl RouteManagerImpl {
                 log::debug!("Adding routes: {:?}", routes);
                 let _ = result_tx.send(self.add_required_routes(routes.clone()).await);
             }
+            RouteManagerCommand::RemoveRoutes(routes, result_tx) => {
+                log::debug!("Removing routes: {:?}", routes);
+                let _ = result_tx.send(self.remove_required_routes(routes).await);
+            }
             RouteManagerCommand::CreateRoutingRules(enable_ipv6, result_tx) => {
                 let _ = result_tx.send(self.create_routing_rules(enable_ipv6).await);
             }
@@ -669,6 +691,10 @@ impl RouteManagerImpl {
             }
         };
 
+        if Self::is_throw_route(&route) {
+            add_message.header.kind = RTN_THROW;
+        }
+
         let compat_table = compat_table_id(route.table_id);
         add_message.header.table = compat_table;
         if compat_table == RT_TABLE_COMPAT {
@@ -697,6 +723,11 @@ impl RouteManagerImpl {
         Ok(())
     }
 
+    /// Returns whether the route is a throw route, i.e. one created for a default node.
+    fn is_throw_route(route: &Route) -> bool {
+        route.node.get_address().is_none() && route.node.get_device().is_none()
+    }
+
     async fn add_route(&mut self, route: Route) -> Result<()> {
         self.add_route_direct(route.clone()).await?;
         self.added_routes.insert(route);
@@ -101,6 +101,10 @@ impl RouteManagerImpl {
                             let result = self.add_required_routes(routes).await;
                             let _ = result_tx.send(result);
                         },
+                        Some(RouteManagerCommand::RemoveRoutes(routes, result_tx)) => {
+                            let result = self.remove_required_routes(routes).await;
+                            let _ = result_tx.send(result);
+                        },
                         Some(RouteManagerCommand::ClearRoutes) => {
                             self.cleanup_routes().await;
                         },
@@ -162,8 +166,26 @@ impl RouteManagerImpl {
             };
         }
 
-        self.default_destinations = default_destinations;
+        self.default_destinations.extend(default_destinations);
+
+        Ok(())
+    }
 
+    async fn remove_required_routes(
+        &mut self,
+        required_routes: HashSet<RequiredRoute>,
+    ) -> Result<()> {
+        for route in required_routes {
+            let applied = match route.node {
+                NetNode::DefaultNode => self.default_destinations.remove(&route.prefix),
+                NetNode::RealNode(node) => {
+                    self.applied_routes.remove(&Route::new(node, route.prefix))
+                }
+            };
+            if applied {
+                Self::delete_route(route.prefix).await?;
+            }
+        }
         Ok(())
     }
 
@@ -250,15 +272,16 @@ impl RouteManagerImpl {
         cmd.status().await.map_err(Error::FailedToAddRoute)
     }
 
-    async fn cleanup_routes(&self) -> () {
+    async fn cleanup_routes(&mut self) -> () {
         let destinations_to_remove = self
             .applied_routes
-            .iter()
-            .map(|route| &route.prefix)
-            .chain(self.default_destinations.iter());
+            .drain()
+            .map(|route| route.prefix)
+            .chain(self.default_destinations.drain())
+            .collect::<Vec<_>>();
 
         for destination in destinations_to_remove {
-            match Self::delete_route(*destination).await {
+            match Self::delete_route(destination).await {
                 Ok(status) => {
                     if !status.success() {
                         log::debug!("Failed to remove route during shutdown");
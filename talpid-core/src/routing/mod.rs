@@ -106,8 +106,8 @@ pub enum NetNode {
     /// of the RouteManager
     RealNode(Node),
     /// A default node is a symbolic node that will resolve to the network node used in the current
-    /// most preferable default route
-    #[cfg(not(target_os = "linux"))]
+    /// most preferable default route. On Linux, the destination is thrown out of the routing table
+    /// of the route, so that the lookup continues in the main table.
     DefaultNode,
 }
 
@@ -150,6 +150,16 @@ impl Node {
         }
     }
 
+    /// Construct a Node without an IP address or a network interface name. Used for routes that
+    /// do not forward packets to any node.
+    #[cfg(target_os = "linux")]
+    fn unspecified() -> Node {
+        Self {
+            ip: None,
+            device: None,
+        }
+    }
+
     /// Retrieve a node's IP address
     pub fn get_address(&self) -> Option<IpAddr> {
         self.ip
@@ -74,6 +74,19 @@ impl RouteManagerHandle {
             .map_err(Error::PlatformError)
     }
 
+    /// Removes routes previously applied with [Self::add_routes].
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    pub async fn remove_routes(&self, routes: HashSet<RequiredRoute>) -> Result<(), Error> {
+        let (response_tx, response_rx) = oneshot::channel();
+        self.tx
+            .unbounded_send(RouteManagerCommand::RemoveRoutes(routes, response_tx))
+            .map_err(|_| Error::RouteManagerDown)?;
+        response_rx
+            .await
+            .map_err(|_| Error::ManagerChannelDown)?
+            .map_err(Error::PlatformError)
+    }
+
     /// Ensure that packets are routed using the correct tables.
     #[cfg(target_os = "linux")]
     pub async fn create_routing_rules(&self, enable_ipv6: bool) -> Result<(), Error> {
@@ -133,6 +146,14 @@ impl RouteManagerHandle {
             .map_err(|_| Error::ManagerChannelDown)?
             .map_err(Error::PlatformError)
     }
+
+    /// Returns a handle that is not connected to any route manager. All requests fail with
+    /// [`Error::RouteManagerDown`].
+    #[cfg(test)]
+    pub(crate) fn disconnected() -> Self {
+        let (tx, _) = mpsc::unbounded();
+        Self { tx }
+    }
 }
 
 /// Commands for the underlying route manager object.
@@ -144,6 +165,11 @@ pub(crate) enum RouteManagerCommand {
     ),
     ClearRoutes,
     Shutdown(oneshot::Sender<()>),
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    RemoveRoutes(
+        HashSet<RequiredRoute>,
+        oneshot::Sender<Result<(), PlatformError>>,
+    ),
     #[cfg(target_os = "linux")]
     CreateRoutingRules(bool, oneshot::Sender<Result<(), PlatformError>>),
     #[cfg(target_os = "linux")]
@@ -240,6 +266,12 @@ impl RouteManager {
         }
     }
 
+    /// Removes routes previously applied with [`RouteManager::add_routes`].
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    pub async fn remove_routes(&mut self, routes: HashSet<RequiredRoute>) -> Result<(), Error> {
+        self.handle()?.remove_routes(routes).await
+    }
+
     /// Ensure that packets are routed using the correct tables.
     #[cfg(target_os = "linux")]
     pub async fn create_routing_rules(&mut self, enable_ipv6: bool) -> Result<(), Error> {
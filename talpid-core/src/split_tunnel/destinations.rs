@@ -0,0 +1,634 @@
+//! Keeps track of the addresses of destinations that are excluded from the tunnel by domain name.
+//! Domains are resolved periodically, and the answers in DNS responses received through the tunnel
+//! are inspected, so that addresses handed out to applications are excluded as they are looked up.
+//! Only responses to queries that were seen leaving this host are trusted.
+
+use crate::firewall::nflog::{self, DNS_NFLOG_GROUP};
+use ipnetwork::IpNetwork;
+use std::{
+    collections::{hash_map::Entry, HashMap, VecDeque},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, ToSocketAddrs},
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+use talpid_types::{net::ExcludedDestinations, ErrorExt};
+
+/// How often to resolve the excluded domains.
+const RESOLVE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// The maximum number of addresses to remember per domain. The oldest addresses are forgotten
+/// first.
+const MAX_ADDRESSES_PER_DOMAIN: usize = 64;
+
+/// Copy entire packets, since DNS responses may be large.
+const DNS_COPY_RANGE: u32 = 0xffff;
+
+/// The maximum number of queries for excluded domains to wait for responses to. The oldest queries
+/// are forgotten first.
+const MAX_PENDING_QUERIES: usize = 256;
+
+/// How long to wait for the response to a query.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+const DNS_HEADER_LEN: usize = 12;
+const DNS_TYPE_A: u16 = 1;
+const DNS_TYPE_CNAME: u16 = 5;
+const DNS_TYPE_AAAA: u16 = 28;
+const DNS_CLASS_IN: u16 = 1;
+/// Bounds the number of compression pointers followed when reading a name.
+const MAX_NAME_POINTERS: usize = 16;
+
+/// Resolves the domains of a set of excluded destinations and reports the networks to exclude
+/// whenever they change. The monitor stops when this is dropped.
+pub struct DestinationMonitor {
+    state: Arc<Mutex<State>>,
+    resolve_tx: mpsc::Sender<()>,
+    _listener: Option<nflog::Listener>,
+}
+
+impl DestinationMonitor {
+    /// Starts resolving `destinations` in the background. `on_change` is called with the
+    /// configured destinations, where the networks include the addresses that the domains have
+    /// been resolved to.
+    pub fn new(
+        destinations: ExcludedDestinations,
+        on_change: impl Fn(ExcludedDestinations) + Send + 'static,
+    ) -> Self {
+        let state = Arc::new(Mutex::new(State::new(destinations, Box::new(on_change))));
+
+        let listener = {
+            let state = state.clone();
+            nflog::Listener::spawn(DNS_NFLOG_GROUP, DNS_COPY_RANGE, move |attributes| {
+                if let Some(message) = nflog::packet_payload(attributes).and_then(udp_payload) {
+                    state.lock().unwrap().add_message(message, Instant::now());
+                }
+            })
+        }
+        .map_err(|error| {
+            log::warn!(
+                "{}",
+                error.display_chain_with_msg("Failed to listen for DNS responses")
+            );
+        })
+        .ok();
+
+        let (resolve_tx, resolve_rx) = mpsc::channel();
+        {
+            let state = state.clone();
+            thread::spawn(move || Self::run(state, resolve_rx));
+        }
+
+        DestinationMonitor {
+            state,
+            resolve_tx,
+            _listener: listener,
+        }
+    }
+
+    /// Replaces the excluded destinations, and resolves any domains immediately.
+    pub fn set_destinations(&self, destinations: ExcludedDestinations) {
+        self.state.lock().unwrap().set_destinations(destinations);
+        if self.resolve_tx.send(()).is_err() {
+            log::error!("The excluded destination monitor has stopped");
+        }
+    }
+
+    fn run(state: Arc<Mutex<State>>, resolve_rx: mpsc::Receiver<()>) {
+        loop {
+            let domains = state.lock().unwrap().destinations.domains.clone();
+            for domain in domains {
+                match (domain.as_str(), 0).to_socket_addrs() {
+                    Ok(addresses) => {
+                        let addresses = addresses.map(|address| address.ip()).collect::<Vec<_>>();
+                        state.lock().unwrap().add_addresses(&domain, addresses);
+                    }
+                    Err(error) => log::debug!("Failed to resolve {}: {}", domain, error),
+                }
+            }
+
+            match resolve_rx.recv_timeout(RESOLVE_INTERVAL) {
+                Ok(()) | Err(mpsc::RecvTimeoutError::Timeout) => (),
+                Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            }
+        }
+    }
+}
+
+struct State {
+    destinations: ExcludedDestinations,
+    /// Addresses that each excluded domain is known to resolve to, oldest first.
+    addresses: HashMap<String, VecDeque<IpAddr>>,
+    /// Queries for excluded domains that have not been answered yet, oldest first.
+    pending_queries: VecDeque<(Question, Instant)>,
+    on_change: Box<dyn Fn(ExcludedDestinations) + Send>,
+    reported: Option<ExcludedDestinations>,
+}
+
+impl State {
+    fn new(
+        destinations: ExcludedDestinations,
+        on_change: Box<dyn Fn(ExcludedDestinations) + Send>,
+    ) -> Self {
+        let mut state = State {
+            destinations: ExcludedDestinations::default(),
+            addresses: HashMap::new(),
+            pending_queries: VecDeque::new(),
+            on_change,
+            reported: None,
+        };
+        state.set_destinations(destinations);
+        state
+    }
+
+    fn set_destinations(&mut self, destinations: ExcludedDestinations) {
+        let domains = destinations
+            .domains
+            .iter()
+            .map(|domain| normalize_domain(domain))
+            .collect::<Vec<_>>();
+        self.addresses.retain(|domain, _| domains.contains(domain));
+        for domain in domains {
+            self.addresses.entry(domain).or_default();
+        }
+        self.destinations = destinations;
+        self.report();
+    }
+
+    fn add_addresses(&mut self, domain: &str, new_addresses: impl IntoIterator<Item = IpAddr>) {
+        let addresses = match self.addresses.get_mut(&normalize_domain(domain)) {
+            Some(addresses) => addresses,
+            None => return,
+        };
+        for address in new_addresses {
+            if addresses.contains(&address) {
+                continue;
+            }
+            if addresses.len() >= MAX_ADDRESSES_PER_DOMAIN {
+                addresses.pop_front();
+            }
+            addresses.push_back(address);
+        }
+        self.report();
+    }
+
+    /// Handles a DNS message that was sent or received by this host.
+    fn add_message(&mut self, message: &[u8], now: Instant) {
+        match parse_question(message) {
+            Some((false, question)) => self.add_query(question, now),
+            Some((true, question)) => self.add_response(question, message, now),
+            None => (),
+        }
+    }
+
+    /// Remembers a query for an excluded domain, so that its response can be recognized.
+    fn add_query(&mut self, question: Question, now: Instant) {
+        if !self
+            .addresses
+            .contains_key(&normalize_domain(&question.name))
+        {
+            return;
+        }
+        if self.pending_queries.len() >= MAX_PENDING_QUERIES {
+            self.pending_queries.pop_front();
+        }
+        self.pending_queries.push_back((question, now));
+    }
+
+    /// Excludes the addresses that a DNS response resolves an excluded domain to. The response is
+    /// ignored unless it answers a pending query.
+    fn add_response(&mut self, question: Question, message: &[u8], now: Instant) {
+        self.pending_queries
+            .retain(|(_, sent)| now.saturating_duration_since(*sent) < QUERY_TIMEOUT);
+        let position = match self
+            .pending_queries
+            .iter()
+            .position(|(pending, _)| *pending == question)
+        {
+            Some(position) => position,
+            None => return,
+        };
+        self.pending_queries.remove(position);
+
+        let records = match parse_answers(message) {
+            Some(records) => records,
+            None => return,
+        };
+
+        // Map each name in the answers to the queried domain if it is an alias of it, following
+        // chains of CNAME records
+        let domain = normalize_domain(&question.name);
+        let mut aliases: HashMap<String, String> = HashMap::new();
+        aliases.insert(domain.clone(), domain);
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for record in &records {
+                if let RecordData::Cname(target) = &record.data {
+                    let target = normalize_domain(target);
+                    if let Some(domain) = aliases.get(&normalize_domain(&record.name)).cloned() {
+                        if let Entry::Vacant(entry) = aliases.entry(target) {
+                            entry.insert(domain);
+                            changed = true;
+                        }
+                    }
+                }
+            }
+        }
+
+        let mut addresses: HashMap<String, Vec<IpAddr>> = HashMap::new();
+        for record in records {
+            let address = match record.data {
+                RecordData::Address(address) => address,
+                RecordData::Cname(_) => continue,
+            };
+            if let Some(domain) = aliases.get(&normalize_domain(&record.name)) {
+                addresses.entry(domain.clone()).or_default().push(address);
+            }
+        }
+        for (domain, addresses) in addresses {
+            self.add_addresses(&domain, addresses);
+        }
+    }
+
+    fn excluded_destinations(&self) -> ExcludedDestinations {
+        let mut networks = self.destinations.networks.clone();
+        for domain in &self.destinations.domains {
+            let addresses = match self.addresses.get(&normalize_domain(domain)) {
+                Some(addresses) => addresses,
+                None => continue,
+            };
+            for network in addresses.iter().map(|address| IpNetwork::from(*address)) {
+                if !networks.contains(&network) {
+                    networks.push(network);
+                }
+            }
+        }
+        ExcludedDestinations {
+            networks,
+            domains: self.destinations.domains.clone(),
+        }
+    }
+
+    fn report(&mut self) {
+        let destinations = self.excluded_destinations();
+        if self.reported.as_ref() != Some(&destinations) {
+            self.reported = Some(destinations.clone());
+            (self.on_change)(destinations);
+        }
+    }
+}
+
+/// Domain names are case-insensitive, and may or may not end with the root label.
+fn normalize_domain(domain: &str) -> String {
+    domain.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// Returns the payload of a UDP packet, starting with its IP header. IPv6 packets with extension
+/// headers are ignored.
+fn udp_payload(packet: &[u8]) -> Option<&[u8]> {
+    let (protocol, transport) = match packet.first()? >> 4 {
+        4 => {
+            let header_length = usize::from(packet.first()? & 0x0f) * 4;
+            (*packet.get(9)?, packet.get(header_length..)?)
+        }
+        6 => (*packet.get(6)?, packet.get(40..)?),
+        _ => return None,
+    };
+    if i32::from(protocol) != libc::IPPROTO_UDP {
+        return None;
+    }
+    transport.get(8..)
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+struct Question {
+    id: u16,
+    name: String,
+    record_type: u16,
+    class: u16,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+struct Record {
+    name: String,
+    data: RecordData,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+enum RecordData {
+    Address(IpAddr),
+    Cname(String),
+}
+
+/// Returns whether a DNS message is a response, along with its ID and question. Messages that do
+/// not contain exactly one question are ignored.
+fn parse_question(message: &[u8]) -> Option<(bool, Question)> {
+    let header = message.get(..DNS_HEADER_LEN)?;
+    let is_response = header[2] & 0x80 != 0;
+    if u16::from_be_bytes([header[4], header[5]]) != 1 {
+        return None;
+    }
+    let (name, name_end) = read_name(message, DNS_HEADER_LEN)?;
+    let fields = message.get(name_end..name_end + 4)?;
+    Some((
+        is_response,
+        Question {
+            id: u16::from_be_bytes([header[0], header[1]]),
+            name,
+            record_type: u16::from_be_bytes([fields[0], fields[1]]),
+            class: u16::from_be_bytes([fields[2], fields[3]]),
+        },
+    ))
+}
+
+/// Returns the A, AAAA and CNAME records in the answer section of a DNS response.
+fn parse_answers(message: &[u8]) -> Option<Vec<Record>> {
+    let header = message.get(..DNS_HEADER_LEN)?;
+    let is_response = header[2] & 0x80 != 0;
+    let response_code = header[3] & 0x0f;
+    if !is_response || response_code != 0 {
+        return None;
+    }
+    let question_count = u16::from_be_bytes([header[4], header[5]]);
+    let answer_count = u16::from_be_bytes([header[6], header[7]]);
+
+    let mut offset = DNS_HEADER_LEN;
+    for _ in 0..question_count {
+        let (_, name_end) = read_name(message, offset)?;
+        // Skip the type and class
+        offset = name_end + 4;
+    }
+
+    let mut records = vec![];
+    for _ in 0..answer_count {
+        let (name, name_end) = read_name(message, offset)?;
+        let fields = message.get(name_end..name_end + 10)?;
+        let record_type = u16::from_be_bytes([fields[0], fields[1]]);
+        let class = u16::from_be_bytes([fields[2], fields[3]]);
+        let data_length = usize::from(u16::from_be_bytes([fields[8], fields[9]]));
+        let data_start = name_end + 10;
+        let data = message.get(data_start..data_start + data_length)?;
+        offset = data_start + data_length;
+
+        if class != DNS_CLASS_IN {
+            continue;
+        }
+        let data = match (record_type, data.len()) {
+            (DNS_TYPE_A, 4) => {
+                RecordData::Address(Ipv4Addr::new(data[0], data[1], data[2], data[3]).into())
+            }
+            (DNS_TYPE_AAAA, 16) => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(data);
+                RecordData::Address(Ipv6Addr::from(octets).into())
+            }
+            (DNS_TYPE_CNAME, _) => RecordData::Cname(read_name(message, data_start)?.0),
+            _ => continue,
+        };
+        records.push(Record { name, data });
+    }
+    Some(records)
+}
+
+/// Reads a possibly compressed domain name starting at `offset`. Returns the name and the offset
+/// just past it.
+fn read_name(message: &[u8], mut offset: usize) -> Option<(String, usize)> {
+    let mut labels = vec![];
+    let mut end = None;
+    let mut pointers = 0;
+
+    loop {
+        let length = *message.get(offset)?;
+        match length & 0xc0 {
+            0x00 if length == 0 => {
+                end.get_or_insert(offset + 1);
+                break;
+            }
+            0x00 => {
+                let label = message.get(offset + 1..offset + 1 + usize::from(length))?;
+                labels.push(String::from_utf8_lossy(label).into_owned());
+                offset += 1 + usize::from(length);
+            }
+            0xc0 => {
+                pointers += 1;
+                if pointers > MAX_NAME_POINTERS {
+                    return None;
+                }
+                end.get_or_insert(offset + 2);
+                offset = usize::from(u16::from_be_bytes([
+                    length & 0x3f,
+                    *message.get(offset + 1)?,
+                ]));
+            }
+            _ => return None,
+        }
+    }
+
+    Some((labels.join("."), end?))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn destinations(domains: &[&str]) -> ExcludedDestinations {
+        ExcludedDestinations {
+            networks: vec!["198.51.100.0/24".parse().unwrap()],
+            domains: domains.iter().map(|domain| domain.to_string()).collect(),
+        }
+    }
+
+    fn state(domains: &[&str]) -> (State, Arc<Mutex<Vec<ExcludedDestinations>>>) {
+        let reports = Arc::new(Mutex::new(vec![]));
+        let on_change = {
+            let reports = reports.clone();
+            move |destinations| reports.lock().unwrap().push(destinations)
+        };
+        (
+            State::new(destinations(domains), Box::new(on_change)),
+            reports,
+        )
+    }
+
+    /// An A query for `www.example.com`.
+    fn query() -> Vec<u8> {
+        let mut message = vec![
+            0x12, 0x34, 0x01, 0x00, // ID, flags
+            0, 1, 0, 0, 0, 0, 0, 0, // question, answer, authority and additional counts
+        ];
+        message.extend_from_slice(b"\x03www\x07example\x03com\x00");
+        message.extend_from_slice(&[0, 1, 0, 1]);
+        message
+    }
+
+    /// A response to an A query for `www.example.com`, which is an alias of `cdn.example.net`.
+    fn response() -> Vec<u8> {
+        let mut message = vec![
+            0x12, 0x34, 0x81, 0x80, // ID, flags
+            0, 1, 0, 3, 0, 0, 0, 0, // question, answer, authority and additional counts
+        ];
+        // Question: www.example.com IN A
+        message.extend_from_slice(b"\x03www\x07example\x03com\x00");
+        message.extend_from_slice(&[0, 1, 0, 1]);
+        // www.example.com CNAME cdn.example.net
+        message.extend_from_slice(&[0xc0, 12, 0, 5, 0, 1, 0, 0, 0, 60, 0, 17]);
+        message.extend_from_slice(b"\x03cdn\x07example\x03net\x00");
+        // cdn.example.net A 203.0.113.7
+        message.extend_from_slice(&[0xc0, 45, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 203, 0, 113, 7]);
+        // cdn.example.net AAAA 2001:db8::7
+        message.extend_from_slice(&[0xc0, 45, 0, 28, 0, 1, 0, 0, 0, 60, 0, 16]);
+        message.extend_from_slice(&"2001:db8::7".parse::<Ipv6Addr>().unwrap().octets());
+        message
+    }
+
+    #[test]
+    fn test_parse_answers() {
+        assert_eq!(
+            parse_answers(&response()),
+            Some(vec![
+                Record {
+                    name: "www.example.com".to_owned(),
+                    data: RecordData::Cname("cdn.example.net".to_owned()),
+                },
+                Record {
+                    name: "cdn.example.net".to_owned(),
+                    data: RecordData::Address("203.0.113.7".parse().unwrap()),
+                },
+                Record {
+                    name: "cdn.example.net".to_owned(),
+                    data: RecordData::Address("2001:db8::7".parse().unwrap()),
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn test_parse_invalid_answers() {
+        let message = response();
+        assert_eq!(parse_answers(&message[..message.len() - 1]), None);
+
+        let mut query = response();
+        query[2] &= !0x80;
+        assert_eq!(parse_answers(&query), None);
+
+        // A name that points to itself
+        let mut looping = response();
+        looping[12..14].copy_from_slice(&[0xc0, 12]);
+        assert_eq!(parse_answers(&looping), None);
+    }
+
+    #[test]
+    fn test_parse_question() {
+        let question = Question {
+            id: 0x1234,
+            name: "www.example.com".to_owned(),
+            record_type: DNS_TYPE_A,
+            class: DNS_CLASS_IN,
+        };
+        assert_eq!(parse_question(&query()), Some((false, question.clone())));
+        assert_eq!(parse_question(&response()), Some((true, question)));
+    }
+
+    #[test]
+    fn test_udp_payload() {
+        let mut packet = vec![0x45, 0, 0, 0, 0, 0, 0, 0, 64, 17, 0, 0];
+        packet.extend_from_slice(&[10, 64, 0, 1, 10, 99, 0, 2]);
+        packet.extend_from_slice(&[0, 53, 0xd4, 0x31, 0, 0, 0, 0]);
+        packet.extend_from_slice(&response());
+        assert_eq!(udp_payload(&packet), Some(&response()[..]));
+
+        // TCP
+        packet[9] = 6;
+        assert_eq!(udp_payload(&packet), None);
+    }
+
+    #[test]
+    fn test_response_adds_aliased_addresses() {
+        let (mut state, reports) = state(&["WWW.example.com."]);
+        let now = Instant::now();
+        state.add_message(&query(), now);
+        state.add_message(&response(), now);
+
+        let expected = ExcludedDestinations {
+            networks: vec![
+                "198.51.100.0/24".parse().unwrap(),
+                "203.0.113.7/32".parse().unwrap(),
+                "2001:db8::7/128".parse().unwrap(),
+            ],
+            domains: vec!["WWW.example.com.".to_owned()],
+        };
+        assert_eq!(reports.lock().unwrap().last(), Some(&expected));
+        assert_eq!(reports.lock().unwrap().len(), 2);
+
+        // Nothing is reported if nothing changed
+        state.add_message(&query(), now);
+        state.add_message(&response(), now);
+        assert_eq!(reports.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_response_ignores_other_domains() {
+        let (mut state, reports) = state(&["example.com"]);
+        let now = Instant::now();
+        state.add_message(&query(), now);
+        state.add_message(&response(), now);
+        assert_eq!(
+            *reports.lock().unwrap(),
+            vec![destinations(&["example.com"])]
+        );
+    }
+
+    #[test]
+    fn test_unsolicited_responses_are_ignored() {
+        let (mut state, reports) = state(&["www.example.com"]);
+        let now = Instant::now();
+
+        state.add_message(&response(), now);
+
+        // A response with another ID
+        state.add_message(&query(), now);
+        let mut other_id = response();
+        other_id[1] = 0x35;
+        state.add_message(&other_id, now);
+
+        // A response after the query has timed out
+        state.add_message(&response(), now + QUERY_TIMEOUT);
+        assert_eq!(
+            *reports.lock().unwrap(),
+            vec![destinations(&["www.example.com"])]
+        );
+
+        // A second response to the same query
+        state.add_message(&query(), now);
+        state.add_message(&response(), now);
+        let mut second = response();
+        *second.last_mut().unwrap() = 8;
+        state.add_message(&second, now);
+        assert_eq!(reports.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_removed_domain_addresses_are_forgotten() {
+        let (mut state, reports) = state(&["www.example.com"]);
+        state.add_addresses("www.example.com", vec!["203.0.113.7".parse().unwrap()]);
+        state.set_destinations(destinations(&["www.example.org"]));
+        assert_eq!(
+            reports.lock().unwrap().last(),
+            Some(&destinations(&["www.example.org"]))
+        );
+    }
+
+    #[test]
+    fn test_addresses_are_bounded() {
+        let (mut state, _reports) = state(&["example.com"]);
+        let addresses = (0..=MAX_ADDRESSES_PER_DOMAIN as u32)
+            .map(|index| IpAddr::from(Ipv4Addr::from(0xcb007100 + index)))
+            .collect::<Vec<_>>();
+        state.add_addresses("example.com", addresses.clone());
+        assert_eq!(
+            state.addresses["example.com"],
+            addresses[1..].iter().cloned().collect::<VecDeque<_>>()
+        );
+    }
+}
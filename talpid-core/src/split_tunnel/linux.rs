@@ -1,16 +1,30 @@
 use std::{
+    collections::{HashMap, HashSet},
     env, fs,
     io::{self, BufRead, BufReader, Write},
-    path::PathBuf,
+    path::{Path, PathBuf},
+    sync::mpsc,
+    thread,
+    time::Duration,
 };
-use talpid_types::cgroup::{find_net_cls_mount, SPLIT_TUNNEL_CGROUP_NAME};
+use talpid_types::cgroup::{find_exclusion_cgroup, ExclusionCgroup, ExclusionProfile};
+use talpid_types::ErrorExt;
 
+const PROC_DIR: &str = "/proc";
 const DEFAULT_NET_CLS_DIR: &str = "/sys/fs/cgroup/net_cls";
 const NET_CLS_DIR_OVERRIDE_ENV_VAR: &str = "TALPID_NET_CLS_MOUNT_DIR";
 
+/// How often to look for new processes started from an excluded application.
+const APP_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
 /// Identifies packets coming from the cgroup.
 /// This should be an arbitrary but unique integer.
 pub const NET_CLS_CLASSID: u32 = 0x4d9f41;
+/// Returns the class ID of the cgroup of the exclusion profile at position `index` in the list
+/// of profiles.
+pub fn profile_classid(index: usize) -> u32 {
+    NET_CLS_CLASSID + 1 + index as u32
+}
 /// Value used to mark packets and associated connections.
 /// This should be an arbitrary but unique integer.
 pub const MARK: i32 = 0xf41;
@@ -43,33 +57,70 @@ pub enum Error {
     #[error(display = "Unable to obtain PIDs from cgroup.procs")]
     ListCGroupPids(#[error(source)] io::Error),
 
+    /// Unable to remove the cgroup of an exclusion profile.
+    #[error(display = "Unable to remove cgroup of exclusion profile")]
+    RemoveProfileCGroup(#[error(source)] io::Error),
+
     /// Unable to read /proc/mounts
     #[error(display = "Failed to read /proc/mounts")]
     ListMounts(#[error(source)] io::Error),
+
+    /// The process does not exist.
+    #[error(display = "No process with PID {}", _0)]
+    NoSuchProcess(i32),
+
+    /// The process is the init process or a kernel thread, which are never excluded.
+    #[error(display = "Process {} cannot be excluded", _0)]
+    ProtectedProcess(i32),
+
+    /// The process belongs to a different user than the one requesting to exclude it.
+    #[error(display = "Process {} is owned by another user", _0)]
+    NotOwnedByUser(i32),
+}
+
+/// Information about a running process, read from `/proc`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProcessInfo {
+    /// Process ID.
+    pub pid: i32,
+    /// Process ID of the parent process.
+    pub parent_pid: i32,
+    /// Command name of the process, as shown by `ps`.
+    pub name: String,
+    /// Path of the executable that the process was started from, if it could be determined.
+    pub exe: Option<PathBuf>,
+    /// Real user ID of the process.
+    pub uid: u32,
 }
 
 /// Manages PIDs in the Linux Cgroup excluded from the VPN tunnel.
+#[derive(Clone)]
 pub struct PidManager {
-    net_cls_path: PathBuf,
+    cgroup: ExclusionCgroup,
 }
 
 impl PidManager {
     /// Creates a new PID Cgroup manager.
     ///
-    /// Finds the corresponding Cgroup to use. Will mount a `net_cls` filesystem
-    /// if none exists.
+    /// Finds the corresponding Cgroup to use. Uses the cgroup v2 hierarchy if there is no
+    /// `net_cls` controller, and will mount a `net_cls` filesystem if neither exists.
     pub fn new() -> Result<PidManager, Error> {
         let manager = PidManager {
-            net_cls_path: Self::create_cgroup()?,
+            cgroup: Self::create_cgroup()?,
         };
         manager.setup_exclusion_group()?;
         Ok(manager)
     }
 
+    /// Returns the cgroup hierarchy that excluded processes are tracked in.
+    pub fn cgroup(&self) -> &ExclusionCgroup {
+        &self.cgroup
+    }
+
     /// Set up cgroup used to track PIDs for split tunneling.
-    fn create_cgroup() -> Result<PathBuf, Error> {
-        if let Some(net_cls_path) = find_net_cls_mount().map_err(Error::ListMounts)? {
-            return Ok(net_cls_path);
+    fn create_cgroup() -> Result<ExclusionCgroup, Error> {
+        if let Some(cgroup) = find_exclusion_cgroup().map_err(Error::ListMounts)? {
+            return Ok(cgroup);
         }
 
         let net_cls_dir = env::var(NET_CLS_DIR_OVERRIDE_ENV_VAR)
@@ -90,26 +141,73 @@ impl PidManager {
         )
         .map_err(Error::InitNetClsCGroup)?;
 
-        Ok(net_cls_dir)
+        Ok(ExclusionCgroup::NetCls(net_cls_dir))
     }
 
     fn setup_exclusion_group(&self) -> Result<(), Error> {
-        let exclusions_dir = self.net_cls_path.join(SPLIT_TUNNEL_CGROUP_NAME);
+        let exclusions_dir = self.cgroup.exclusions_path();
         if !exclusions_dir.exists() {
             fs::create_dir(exclusions_dir.clone()).map_err(Error::CreateCGroup)?;
         }
 
-        let classid_path = exclusions_dir.join("net_cls.classid");
-        fs::write(classid_path, NET_CLS_CLASSID.to_string().as_bytes())
-            .map_err(Error::SetCGroupClassId)
+        match self.cgroup {
+            ExclusionCgroup::NetCls(_) => {
+                let classid_path = exclusions_dir.join("net_cls.classid");
+                fs::write(classid_path, NET_CLS_CLASSID.to_string().as_bytes())
+                    .map_err(Error::SetCGroupClassId)
+            }
+            // The firewall matches sockets by the path of the cgroup
+            ExclusionCgroup::V2(_) => Ok(()),
+        }
+    }
+
+    /// Creates a cgroup for each exclusion profile, and removes the cgroups of profiles that no
+    /// longer exist. Processes in removed profiles are included in the tunnel again.
+    pub fn set_profiles(&self, profiles: &[ExclusionProfile]) -> Result<(), Error> {
+        let profile_prefix = ExclusionProfile::cgroup_name("");
+        let entries = fs::read_dir(self.cgroup.mount_path()).map_err(Error::CreateCGroup)?;
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            let file_name = entry.file_name();
+            let name = match file_name
+                .to_str()
+                .and_then(|n| n.strip_prefix(&profile_prefix))
+            {
+                Some(name) => name,
+                None => continue,
+            };
+            if !profiles.iter().any(|profile| profile.name == name) {
+                self.remove_profile_cgroup(&entry.path())?;
+            }
+        }
+
+        for (index, profile) in profiles.iter().enumerate() {
+            let path = self.cgroup.profile_path(&profile.name);
+            if !path.exists() {
+                fs::create_dir(&path).map_err(Error::CreateCGroup)?;
+            }
+            // Class IDs depend on the order of the profiles, so they are always rewritten
+            if let ExclusionCgroup::NetCls(_) = self.cgroup {
+                fs::write(
+                    path.join("net_cls.classid"),
+                    profile_classid(index).to_string().as_bytes(),
+                )
+                .map_err(Error::SetCGroupClassId)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn remove_profile_cgroup(&self, path: &Path) -> Result<(), Error> {
+        let procs = fs::read_to_string(path.join("cgroup.procs")).map_err(Error::ListCGroupPids)?;
+        for pid in procs.lines().filter_map(|pid| pid.parse::<i32>().ok()) {
+            self.remove(pid)?;
+        }
+        fs::remove_dir(path).map_err(Error::RemoveProfileCGroup)
     }
 
     /// Add a PID to the Cgroup to have it excluded from the tunnel.
     pub fn add(&self, pid: i32) -> Result<(), Error> {
-        let exclusions_path = self
-            .net_cls_path
-            .join(SPLIT_TUNNEL_CGROUP_NAME)
-            .join("cgroup.procs");
+        let exclusions_path = self.cgroup.exclusions_path().join("cgroup.procs");
 
         let mut file = fs::OpenOptions::new()
             .write(true)
@@ -121,11 +219,37 @@ impl PidManager {
             .map_err(Error::AddCGroupPid)
     }
 
+    /// Add a PID to the Cgroup on behalf of the user `uid`. Fails for the init process, kernel
+    /// threads and, unless `uid` is root, processes owned by other users.
+    pub fn add_for_user(&self, pid: i32, uid: u32) -> Result<(), Error> {
+        check_process(Path::new(PROC_DIR), pid, uid)?;
+        self.add(pid)
+    }
+
+    /// Add a PID and all of its current descendants to the Cgroup on behalf of the user `uid`.
+    /// Descendants that may not be excluded by the user are left in the tunnel. Processes that
+    /// are started afterwards inherit the Cgroup from their parent.
+    pub fn add_with_descendants(&self, pid: i32, uid: u32) -> Result<(), Error> {
+        let proc_dir = Path::new(PROC_DIR);
+        check_process(proc_dir, pid, uid)?;
+        self.add(pid)?;
+        for child in descendants(proc_dir, pid) {
+            // The process may have exited since it was found
+            if let Err(error) = check_process(proc_dir, child, uid).and_then(|()| self.add(child)) {
+                log::debug!(
+                    "{}",
+                    error.display_chain_with_msg(&format!("Failed to exclude process {}", child))
+                );
+            }
+        }
+        Ok(())
+    }
+
     /// Remove a PID from the Cgroup to have it included in the tunnel.
     pub fn remove(&self, pid: i32) -> Result<(), Error> {
         // FIXME: We remove PIDs from our cgroup here by adding
         //        them to the parent cgroup. This seems wrong.
-        let exclusions_path = self.net_cls_path.join("cgroup.procs");
+        let exclusions_path = self.cgroup.mount_path().join("cgroup.procs");
 
         let mut file = fs::OpenOptions::new()
             .write(true)
@@ -139,10 +263,7 @@ impl PidManager {
 
     /// Return a list of all PIDs currently in the Cgroup excluded from the tunnel.
     pub fn list(&self) -> Result<Vec<i32>, Error> {
-        let exclusions_path = self
-            .net_cls_path
-            .join(SPLIT_TUNNEL_CGROUP_NAME)
-            .join("cgroup.procs");
+        let exclusions_path = self.cgroup.exclusions_path().join("cgroup.procs");
 
         let file = fs::File::open(exclusions_path).map_err(Error::ListCGroupPids)?;
 
@@ -158,6 +279,17 @@ impl PidManager {
         result.map_err(Error::ListCGroupPids)
     }
 
+    /// Return information about all running processes in the Cgroup excluded from the tunnel.
+    /// Processes that have exited but are still listed by the Cgroup are left out.
+    pub fn list_processes(&self) -> Result<Vec<ProcessInfo>, Error> {
+        let proc_dir = Path::new(PROC_DIR);
+        Ok(self
+            .list()?
+            .into_iter()
+            .filter_map(|pid| process_info(proc_dir, pid))
+            .collect())
+    }
+
     /// Removes all PIDs from the Cgroup.
     pub fn clear(&self) -> Result<(), Error> {
         // TODO: reuse file handle
@@ -170,3 +302,418 @@ impl PidManager {
         Ok(())
     }
 }
+
+/// Excludes processes started from any of a set of executables from the tunnel, by moving them
+/// into the cgroup managed by [`PidManager`]. Processes are discovered by polling `/proc`.
+///
+/// Child processes inherit the cgroup of their parent, so they are excluded as well. The set of
+/// executables may only be changed by root, so processes of all users are excluded, except for
+/// those that can never be excluded (see [`PidManager::add_for_user`]).
+pub struct AppMonitor {
+    paths_tx: mpsc::Sender<HashSet<PathBuf>>,
+}
+
+impl AppMonitor {
+    /// Starts monitoring processes in the background. No processes are excluded until paths are
+    /// given using [`AppMonitor::set_paths`]. The monitor stops when this is dropped.
+    pub fn new(pid_manager: PidManager) -> Self {
+        let (paths_tx, paths_rx) = mpsc::channel();
+        thread::spawn(move || Self::run(pid_manager, paths_rx));
+        AppMonitor { paths_tx }
+    }
+
+    /// Replaces the set of executables to exclude. Processes started from executables that are
+    /// no longer in the set are included in the tunnel again.
+    pub fn set_paths<T: AsRef<Path>>(&self, paths: impl IntoIterator<Item = T>) {
+        let paths = paths
+            .into_iter()
+            .map(|path| canonical_path(path.as_ref()))
+            .collect();
+        if self.paths_tx.send(paths).is_err() {
+            log::error!("The split tunnel app monitor has stopped");
+        }
+    }
+
+    fn run(pid_manager: PidManager, paths_rx: mpsc::Receiver<HashSet<PathBuf>>) {
+        let proc_dir = Path::new(PROC_DIR);
+        let mut paths = HashSet::new();
+
+        loop {
+            match paths_rx.recv_timeout(APP_POLL_INTERVAL) {
+                Ok(new_paths) => {
+                    let removed_paths: HashSet<_> = paths.difference(&new_paths).cloned().collect();
+                    paths = new_paths;
+                    if !removed_paths.is_empty() {
+                        Self::include_processes(&pid_manager, proc_dir, &removed_paths);
+                    }
+                }
+                Err(mpsc::RecvTimeoutError::Timeout) => (),
+                Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            }
+
+            if !paths.is_empty() {
+                Self::exclude_processes(&pid_manager, proc_dir, &paths);
+            }
+        }
+    }
+
+    fn exclude_processes(pid_manager: &PidManager, proc_dir: &Path, paths: &HashSet<PathBuf>) {
+        let excluded_pids = match pid_manager.list() {
+            Ok(pids) => pids.into_iter().collect(),
+            Err(error) => {
+                log::error!(
+                    "{}",
+                    error.display_chain_with_msg("Failed to list excluded processes")
+                );
+                return;
+            }
+        };
+
+        for pid in find_app_pids(proc_dir, paths, &excluded_pids, 0) {
+            log::debug!("Excluding process {} from the tunnel", pid);
+            if let Err(error) = pid_manager.add(pid) {
+                log::error!(
+                    "{}",
+                    error.display_chain_with_msg(&format!("Failed to exclude process {}", pid))
+                );
+            }
+        }
+    }
+
+    fn include_processes(pid_manager: &PidManager, proc_dir: &Path, paths: &HashSet<PathBuf>) {
+        let excluded_pids = match pid_manager.list() {
+            Ok(pids) => pids,
+            Err(error) => {
+                log::error!(
+                    "{}",
+                    error.display_chain_with_msg("Failed to list excluded processes")
+                );
+                return;
+            }
+        };
+
+        for pid in excluded_pids {
+            let started_from_path = process_exe(proc_dir, pid)
+                .map(|exe| paths.contains(&exe))
+                .unwrap_or(false);
+            if !started_from_path {
+                continue;
+            }
+            log::debug!("Including process {} in the tunnel", pid);
+            if let Err(error) = pid_manager.remove(pid) {
+                log::error!(
+                    "{}",
+                    error.display_chain_with_msg(&format!("Failed to include process {}", pid))
+                );
+            }
+        }
+    }
+}
+
+/// Resolves symbolic links in `path`, since `/proc/<pid>/exe` always refers to the actual file.
+fn canonical_path(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// Returns the path of the executable that a process was started from.
+fn process_exe(proc_dir: &Path, pid: i32) -> Option<PathBuf> {
+    fs::read_link(proc_dir.join(pid.to_string()).join("exe")).ok()
+}
+
+/// Reads information about a process. Returns `None` if the process does not exist or has exited
+/// and is waiting to be reaped.
+fn process_info(proc_dir: &Path, pid: i32) -> Option<ProcessInfo> {
+    let process_dir = proc_dir.join(pid.to_string());
+    let (state, parent_pid) = parse_stat(&fs::read_to_string(process_dir.join("stat")).ok()?)?;
+    if state == 'Z' || state == 'X' {
+        return None;
+    }
+    let name = fs::read_to_string(process_dir.join("comm"))
+        .map(|name| name.trim_end_matches('\n').to_string())
+        .unwrap_or_default();
+    let uid = parse_status_uid(&fs::read_to_string(process_dir.join("status")).ok()?)?;
+
+    Some(ProcessInfo {
+        pid,
+        parent_pid,
+        name,
+        exe: process_exe(proc_dir, pid),
+        uid,
+    })
+}
+
+/// Checks that the user `uid` may exclude the process `pid` from the tunnel. The init process and
+/// kernel threads can never be excluded, and only root may exclude processes owned by other users.
+fn check_process(proc_dir: &Path, pid: i32, uid: u32) -> Result<(), Error> {
+    if pid <= 1 {
+        return Err(Error::ProtectedProcess(pid));
+    }
+    let info = process_info(proc_dir, pid).ok_or(Error::NoSuchProcess(pid))?;
+    // Kernel threads are not started from an executable
+    if info.exe.is_none() {
+        return Err(Error::ProtectedProcess(pid));
+    }
+    if uid != 0 && info.uid != uid {
+        return Err(Error::NotOwnedByUser(pid));
+    }
+    Ok(())
+}
+
+/// Returns the state and parent PID from the contents of `/proc/<pid>/stat`.
+fn parse_stat(stat: &str) -> Option<(char, i32)> {
+    // The command name is enclosed in parentheses, and may itself contain spaces and parentheses
+    let mut fields = stat.get(stat.rfind(')')? + 1..)?.split_whitespace();
+    let state = fields.next()?.chars().next()?;
+    let parent_pid = fields.next()?.parse().ok()?;
+    Some((state, parent_pid))
+}
+
+/// Returns the real user ID from the contents of `/proc/<pid>/status`.
+fn parse_status_uid(status: &str) -> Option<u32> {
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("Uid:"))?
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()
+}
+
+/// Returns the PIDs of all running descendants of `pid`, parents before their children.
+fn descendants(proc_dir: &Path, pid: i32) -> Vec<i32> {
+    let entries = match fs::read_dir(proc_dir) {
+        Ok(entries) => entries,
+        Err(error) => {
+            log::error!(
+                "{}",
+                error.display_chain_with_msg("Failed to list running processes")
+            );
+            return vec![];
+        }
+    };
+
+    let mut children: HashMap<i32, Vec<i32>> = HashMap::new();
+    for child in entries.filter_map(|entry| entry.ok()?.file_name().to_str()?.parse::<i32>().ok()) {
+        let stat = match fs::read_to_string(proc_dir.join(child.to_string()).join("stat")) {
+            Ok(stat) => stat,
+            Err(_) => continue,
+        };
+        if let Some((_, parent)) = parse_stat(&stat) {
+            children.entry(parent).or_default().push(child);
+        }
+    }
+
+    let mut result = vec![];
+    let mut queue = vec![pid];
+    while let Some(parent) = queue.pop() {
+        if let Some(parent_children) = children.remove(&parent) {
+            result.extend_from_slice(&parent_children);
+            queue.extend(parent_children);
+        }
+    }
+    result
+}
+
+/// Returns all processes in `proc_dir` that were started from any of `paths`, have not already
+/// been excluded, and may be excluded by the user `uid`.
+fn find_app_pids(
+    proc_dir: &Path,
+    paths: &HashSet<PathBuf>,
+    excluded_pids: &HashSet<i32>,
+    uid: u32,
+) -> Vec<i32> {
+    let entries = match fs::read_dir(proc_dir) {
+        Ok(entries) => entries,
+        Err(error) => {
+            log::error!(
+                "{}",
+                error.display_chain_with_msg("Failed to list running processes")
+            );
+            return vec![];
+        }
+    };
+
+    entries
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse::<i32>().ok())
+        .filter(|pid| !excluded_pids.contains(pid))
+        .filter(|pid| {
+            process_exe(proc_dir, *pid)
+                .map(|exe| paths.contains(&exe))
+                .unwrap_or(false)
+        })
+        .filter(|pid| match check_process(proc_dir, *pid, uid) {
+            Ok(()) => true,
+            Err(error) => {
+                log::debug!("Not excluding process {}: {}", pid, error);
+                false
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    fn add_process(proc_dir: &Path, pid: i32, exe: &Path) {
+        let process_dir = proc_dir.join(pid.to_string());
+        fs::create_dir_all(&process_dir).unwrap();
+        symlink(exe, process_dir.join("exe")).unwrap();
+    }
+
+    #[test]
+    fn test_find_app_pids() {
+        let proc_dir = tempfile::tempdir().unwrap();
+        for pid in 10..=13 {
+            add_process_stat(proc_dir.path(), pid, 1, 'S');
+        }
+        add_process(proc_dir.path(), 10, Path::new("/usr/bin/excluded"));
+        add_process(proc_dir.path(), 11, Path::new("/usr/bin/other"));
+        add_process(proc_dir.path(), 12, Path::new("/usr/bin/excluded"));
+        add_process(proc_dir.path(), 13, Path::new("/usr/bin/excluded"));
+        fs::create_dir(proc_dir.path().join("net")).unwrap();
+
+        let paths = [PathBuf::from("/usr/bin/excluded")].into_iter().collect();
+        let excluded_pids = [12].into_iter().collect();
+
+        let mut pids = find_app_pids(proc_dir.path(), &paths, &excluded_pids, 0);
+        pids.sort();
+        assert_eq!(pids, vec![10, 13]);
+    }
+
+    #[test]
+    fn test_find_app_pids_skips_protected_processes() {
+        let proc_dir = tempfile::tempdir().unwrap();
+        add_process_stat(proc_dir.path(), 1, 0, 'S');
+        add_process(proc_dir.path(), 1, Path::new("/usr/lib/systemd/systemd"));
+        add_process_stat_with_uid(proc_dir.path(), 10, 1, 'S', 1001);
+        add_process(proc_dir.path(), 10, Path::new("/usr/lib/systemd/systemd"));
+        add_process_stat(proc_dir.path(), 11, 1, 'S');
+        add_process(proc_dir.path(), 11, Path::new("/usr/lib/systemd/systemd"));
+
+        let paths = [PathBuf::from("/usr/lib/systemd/systemd")]
+            .into_iter()
+            .collect();
+        let excluded_pids = HashSet::new();
+
+        // PID 1 is never excluded, and other users' processes are only excluded for root
+        assert_eq!(
+            find_app_pids(proc_dir.path(), &paths, &excluded_pids, 1000),
+            vec![11]
+        );
+        let mut pids = find_app_pids(proc_dir.path(), &paths, &excluded_pids, 0);
+        pids.sort();
+        assert_eq!(pids, vec![10, 11]);
+    }
+
+    fn add_process_stat(proc_dir: &Path, pid: i32, parent_pid: i32, state: char) {
+        add_process_stat_with_uid(proc_dir, pid, parent_pid, state, 1000);
+    }
+
+    fn add_process_stat_with_uid(
+        proc_dir: &Path,
+        pid: i32,
+        parent_pid: i32,
+        state: char,
+        uid: u32,
+    ) {
+        let process_dir = proc_dir.join(pid.to_string());
+        fs::create_dir_all(&process_dir).unwrap();
+        fs::write(
+            process_dir.join("stat"),
+            format!("{} (a (b) c) {} {} 0 0 0", pid, state, parent_pid),
+        )
+        .unwrap();
+        fs::write(process_dir.join("comm"), "a (b) c\n").unwrap();
+        fs::write(
+            process_dir.join("status"),
+            format!(
+                "Name:\ta (b) c\nUid:\t{0}\t{0}\t{0}\t{0}\nGid:\t1000\t1000\t1000\t1000\n",
+                uid
+            ),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_process_info() {
+        let proc_dir = tempfile::tempdir().unwrap();
+        add_process(proc_dir.path(), 10, Path::new("/usr/bin/app"));
+        add_process_stat(proc_dir.path(), 10, 1, 'S');
+        add_process_stat(proc_dir.path(), 11, 10, 'Z');
+
+        assert_eq!(
+            process_info(proc_dir.path(), 10),
+            Some(ProcessInfo {
+                pid: 10,
+                parent_pid: 1,
+                name: "a (b) c".to_string(),
+                exe: Some(PathBuf::from("/usr/bin/app")),
+                uid: 1000,
+            })
+        );
+        assert_eq!(process_info(proc_dir.path(), 11), None);
+        assert_eq!(process_info(proc_dir.path(), 12), None);
+    }
+
+    #[test]
+    fn test_descendants() {
+        let proc_dir = tempfile::tempdir().unwrap();
+        add_process_stat(proc_dir.path(), 1, 0, 'S');
+        add_process_stat(proc_dir.path(), 10, 1, 'S');
+        add_process_stat(proc_dir.path(), 11, 10, 'S');
+        add_process_stat(proc_dir.path(), 12, 11, 'R');
+        add_process_stat(proc_dir.path(), 13, 1, 'S');
+        add_process_stat(proc_dir.path(), 14, 10, 'S');
+
+        let mut pids = descendants(proc_dir.path(), 10);
+        pids.sort();
+        assert_eq!(pids, vec![11, 12, 14]);
+        assert!(descendants(proc_dir.path(), 12).is_empty());
+    }
+
+    #[test]
+    fn test_check_process() {
+        let proc_dir = tempfile::tempdir().unwrap();
+        add_process(proc_dir.path(), 1, Path::new("/sbin/init"));
+        add_process_stat(proc_dir.path(), 1, 0, 'S');
+        add_process_stat(proc_dir.path(), 2, 0, 'S');
+        add_process(proc_dir.path(), 10, Path::new("/usr/bin/app"));
+        add_process_stat(proc_dir.path(), 10, 1, 'S');
+
+        assert!(check_process(proc_dir.path(), 10, 1000).is_ok());
+        assert!(check_process(proc_dir.path(), 10, 0).is_ok());
+        assert!(matches!(
+            check_process(proc_dir.path(), 10, 1001),
+            Err(Error::NotOwnedByUser(10))
+        ));
+        assert!(matches!(
+            check_process(proc_dir.path(), 1, 0),
+            Err(Error::ProtectedProcess(1))
+        ));
+        assert!(matches!(
+            check_process(proc_dir.path(), 2, 0),
+            Err(Error::ProtectedProcess(2))
+        ));
+        assert!(matches!(
+            check_process(proc_dir.path(), 11, 1000),
+            Err(Error::NoSuchProcess(11))
+        ));
+    }
+
+    #[test]
+    fn test_canonical_app_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let exe = dir.path().join("app");
+        fs::write(&exe, b"").unwrap();
+        let link = dir.path().join("link");
+        symlink(&exe, &link).unwrap();
+
+        assert_eq!(canonical_path(&link), canonical_path(&exe));
+        assert_eq!(
+            canonical_path(Path::new("/nonexistent/app")),
+            PathBuf::from("/nonexistent/app")
+        );
+    }
+}
@@ -11,3 +11,9 @@ mod imp;
 
 #[cfg(windows)]
 pub use imp::*;
+
+#[cfg(target_os = "linux")]
+mod destinations;
+
+#[cfg(target_os = "linux")]
+pub use destinations::DestinationMonitor;
@@ -74,6 +74,10 @@ pub enum TunnelEvent {
     Up(TunnelMetadata),
     /// Sent when the tunnel goes down.
     Down,
+    /// Sent after the tunnel has switched to new peers, once a handshake with them has
+    /// completed or timed out.
+    #[cfg(target_os = "linux")]
+    PeersUpdated { handshake_completed: bool },
 }
 
 /// Information about a VPN tunnel.
@@ -285,6 +289,16 @@ impl TunnelMonitor {
         }
     }
 
+    /// Returns a handle for switching the tunnel to new parameters while it is running. Only
+    /// WireGuard tunnels support this.
+    #[cfg(target_os = "linux")]
+    pub fn peer_updater(&self) -> Option<wireguard::PeerUpdater> {
+        match &self.monitor {
+            InternalTunnelMonitor::Wireguard(monitor) => Some(monitor.peer_updater()),
+            InternalTunnelMonitor::OpenVpn(_) => None,
+        }
+    }
+
     /// Consumes the monitor and blocks until the tunnel exits or there is an error.
     pub fn wait(self) -> Result<()> {
         self.monitor.wait().map_err(Error::from)
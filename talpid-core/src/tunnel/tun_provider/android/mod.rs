@@ -16,7 +16,7 @@ use std::{
     os::unix::io::{AsRawFd, RawFd},
     sync::Arc,
 };
-use talpid_types::{android::AndroidContext, ErrorExt};
+use talpid_types::{android::AndroidContext, net::lan::LanNetworks, ErrorExt};
 
 /// Errors that occur while setting up VpnService tunnel.
 #[derive(Debug, err_derive::Error)]
@@ -64,6 +64,7 @@ pub struct AndroidTunProvider {
     object: GlobalRef,
     last_tun_config: TunConfig,
     allow_lan: bool,
+    lan_networks: LanNetworks,
     custom_dns_servers: Option<Vec<IpAddr>>,
 }
 
@@ -72,6 +73,7 @@ impl AndroidTunProvider {
     pub fn new(
         context: AndroidContext,
         allow_lan: bool,
+        lan_networks: LanNetworks,
         custom_dns_servers: Option<Vec<IpAddr>>,
     ) -> Self {
         let env = JnixEnv::from(
@@ -88,6 +90,7 @@ impl AndroidTunProvider {
             object: context.vpn_service,
             last_tun_config: TunConfig::default(),
             allow_lan,
+            lan_networks,
             custom_dns_servers,
         }
     }
@@ -101,6 +104,17 @@ impl AndroidTunProvider {
         Ok(())
     }
 
+    pub fn set_lan_networks(&mut self, lan_networks: LanNetworks) -> Result<(), Error> {
+        if self.lan_networks != lan_networks {
+            self.lan_networks = lan_networks;
+            if self.allow_lan {
+                self.recreate_tun_if_open()?;
+            }
+        }
+
+        Ok(())
+    }
+
     pub fn set_dns_servers(&mut self, servers: Option<Vec<IpAddr>>) -> Result<(), Error> {
         if self.custom_dns_servers != servers {
             self.custom_dns_servers = servers;
@@ -234,12 +248,13 @@ impl AndroidTunProvider {
                 .cloned()
                 .partition::<Vec<_>, _>(|route| route.is_ipv4());
 
-            let (original_lan_ipv4_networks, original_lan_ipv6_networks) =
-                crate::firewall::ALLOWED_LAN_NETS
-                    .iter()
-                    .chain(crate::firewall::ALLOWED_LAN_MULTICAST_NETS.iter())
-                    .cloned()
-                    .partition::<Vec<_>, _>(|network| network.is_ipv4());
+            let (original_lan_ipv4_networks, original_lan_ipv6_networks) = self
+                .lan_networks
+                .networks
+                .iter()
+                .chain(self.lan_networks.multicast_networks.iter())
+                .cloned()
+                .partition::<Vec<_>, _>(|network| network.is_ipv4());
 
             let lan_ipv4_networks = original_lan_ipv4_networks
                 .into_iter()
@@ -6,6 +6,7 @@ use std::{
 use talpid_types::net::{obfuscation::ObfuscatorConfig, wireguard, GenericTunnelOptions};
 
 /// Config required to set up a single WireGuard tunnel
+#[derive(Clone)]
 pub struct Config {
     /// Contains tunnel endpoint specific config
     pub tunnel: wireguard::TunnelConfig,
@@ -128,6 +129,25 @@ impl Config {
         })
     }
 
+    /// Returns whether a running tunnel can switch from this config to `other` by replacing its
+    /// private key and peers, i.e. without changing its interface, addresses or routes.
+    #[cfg(target_os = "linux")]
+    pub fn can_replace_peers(&self, other: &Config) -> bool {
+        self.obfuscator_config.is_none()
+            && other.obfuscator_config.is_none()
+            && self.tunnel.addresses == other.tunnel.addresses
+            && self.ipv4_gateway == other.ipv4_gateway
+            && self.ipv6_gateway == other.ipv6_gateway
+            && self.mtu == other.mtu
+            && self.enable_ipv6 == other.enable_ipv6
+            && self.peers.len() == other.peers.len()
+            && self
+                .peers
+                .iter()
+                .zip(&other.peers)
+                .all(|(peer, other_peer)| peer.allowed_ips == other_peer.allowed_ips)
+    }
+
     /// Returns a CString with the appropriate config for WireGuard-go
     // TODO: Consider outputting both overriding and additive configs
     pub fn to_userspace_format(&self) -> CString {
@@ -1,6 +1,6 @@
 use crate::{
     ping_monitor::{new_pinger, Pinger},
-    tunnel::wireguard::stats::StatsMap,
+    tunnel::wireguard::stats::{Stats, StatsMap},
 };
 use std::{
     cmp,
@@ -314,20 +314,20 @@ impl ConnState {
                 tx_timestamp,
                 stats,
             } => {
-                let rx_incremented = stats.iter().all(|(key, peer_stats)| {
-                    new_stats
-                        .get(key)
-                        .map(|new_stats| new_stats.rx_bytes > peer_stats.rx_bytes)
-                        .unwrap_or(false)
+                // Peers that were not present before, because the peers of the tunnel were
+                // replaced, are compared against zero.
+                let previous = |key: &[u8; 32], counter: fn(&Stats) -> u64| {
+                    stats.get(key).map(counter).unwrap_or(0)
+                };
+                let rx_incremented = !new_stats.is_empty()
+                    && new_stats.iter().all(|(key, peer_stats)| {
+                        peer_stats.rx_bytes > previous(key, |stats| stats.rx_bytes)
+                    });
+                let tx_incremented = new_stats.iter().any(|(key, peer_stats)| {
+                    peer_stats.tx_bytes > previous(key, |stats| stats.tx_bytes)
                 });
                 let rx_timestamp = if rx_incremented { now } else { *rx_timestamp };
-                let tx_timestamp = if stats.values().map(|stats| stats.tx_bytes).sum::<u64>()
-                    < new_stats.values().map(|stats| stats.tx_bytes).sum()
-                {
-                    now
-                } else {
-                    *tx_timestamp
-                };
+                let tx_timestamp = if tx_incremented { now } else { *tx_timestamp };
                 *self = ConnState::Connected {
                     rx_timestamp,
                     tx_timestamp,
@@ -508,6 +508,40 @@ mod test {
         assert!(!conn_state.traffic_timed_out());
     }
 
+    /// Test if ConnState::Connected considers traffic received by a peer that replaced the
+    /// previous one as incoming traffic
+    #[test]
+    fn test_conn_state_peer_replaced() {
+        let start = Instant::now()
+            .checked_sub(BYTES_RX_TIMEOUT + Duration::from_secs(1))
+            .unwrap();
+        let mut conn_state = ConnState::new(start, Default::default());
+
+        let mut stats = StatsMap::new();
+        stats.insert(
+            [0u8; 32],
+            Stats {
+                rx_bytes: 100,
+                tx_bytes: 100,
+            },
+        );
+        conn_state.update(start, stats);
+
+        let mut stats = StatsMap::new();
+        stats.insert(
+            [1u8; 32],
+            Stats {
+                rx_bytes: 1,
+                tx_bytes: 1,
+            },
+        );
+        assert!(conn_state.update(Instant::now(), stats));
+
+        assert!(conn_state.connected());
+        assert!(!conn_state.rx_timed_out());
+        assert!(!conn_state.traffic_timed_out());
+    }
+
     #[derive(Default)]
     struct MockPinger {
         on_send_ping: Option<Box<dyn FnMut() + Send>>,
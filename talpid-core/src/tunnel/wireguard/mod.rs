@@ -19,10 +19,19 @@ use std::env;
 use std::io;
 use std::{
     convert::Infallible,
+    future::Future,
     net::IpAddr,
     path::Path,
+    pin::Pin,
     sync::{mpsc as sync_mpsc, Arc, Mutex},
 };
+#[cfg(target_os = "linux")]
+use std::{
+    net::Ipv4Addr,
+    sync::Weak,
+    thread,
+    time::{Duration, Instant},
+};
 #[cfg(windows)]
 use talpid_types::BoxedError;
 use talpid_types::{net::obfuscation::ObfuscatorConfig, ErrorExt};
@@ -45,6 +54,12 @@ use self::wireguard_go::WgGoTunnel;
 
 type Result<T> = std::result::Result<T, Error>;
 
+/// How long to wait for a handshake with new peers after switching to them.
+#[cfg(target_os = "linux")]
+const PEER_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+#[cfg(target_os = "linux")]
+const PEER_HANDSHAKE_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
 /// Errors that can happen in the Wireguard tunnel monitor.
 #[derive(err_derive::Error, Debug)]
 #[error(no_from)]
@@ -82,23 +97,164 @@ pub enum Error {
     #[cfg(target_os = "windows")]
     #[error(display = "Failed to set IP addresses on WireGuard interface")]
     SetIpAddressesError,
+
+    /// The new config cannot be applied without recreating the tunnel
+    #[cfg(target_os = "linux")]
+    #[error(display = "The new config requires the tunnel to be recreated")]
+    IncompatibleConfigError,
+
+    /// The tunnel has already been stopped
+    #[cfg(target_os = "linux")]
+    #[error(display = "The tunnel has been stopped")]
+    TunnelStoppedError,
+
+    /// Nothing was received from the new peers after switching to them
+    #[cfg(target_os = "linux")]
+    #[error(display = "No handshake with the new peers")]
+    PeerHandshakeTimeout,
+
+    /// The peers were replaced again before a handshake with them completed
+    #[cfg(target_os = "linux")]
+    #[error(display = "The peers were replaced before a handshake with them")]
+    PeersReplacedError,
 }
 
+/// Callback to signal tunnel events
+type EventCallback =
+    Arc<dyn (Fn(TunnelEvent) -> Pin<Box<dyn Future<Output = ()> + Send>>) + Send + Sync + 'static>;
+
 /// Spawns and monitors a wireguard tunnel
 pub struct WireguardMonitor {
     runtime: tokio::runtime::Handle,
     /// Tunnel implementation
     tunnel: Arc<Mutex<Option<Box<dyn Tunnel>>>>,
     /// Callback to signal tunnel events
-    event_callback: Box<
-        dyn (Fn(TunnelEvent) -> std::pin::Pin<Box<dyn std::future::Future<Output = ()> + Send>>)
-            + Send
-            + Sync
-            + 'static,
-    >,
+    event_callback: EventCallback,
     close_msg_receiver: sync_mpsc::Receiver<CloseMsg>,
     pinger_stop_sender: sync_mpsc::Sender<()>,
     _obfuscator: Option<ObfuscatorHandle>,
+    /// Config that the tunnel currently uses
+    #[cfg(target_os = "linux")]
+    config: Arc<Mutex<Config>>,
+}
+
+/// Switches a running tunnel to a new config by replacing its private key and peers, while
+/// keeping its interface, addresses and routes. Connections through the tunnel survive this.
+#[cfg(target_os = "linux")]
+pub struct PeerUpdater {
+    runtime: tokio::runtime::Handle,
+    tunnel: Weak<Mutex<Option<Box<dyn Tunnel>>>>,
+    config: Arc<Mutex<Config>>,
+    event_callback: EventCallback,
+}
+
+#[cfg(target_os = "linux")]
+impl PeerUpdater {
+    /// Applies `config` to the tunnel. Fails if it differs in anything but the private key and
+    /// peer keys and endpoints, or if the tunnel does not support changing its config. Whether the
+    /// new peers respond in time is reported afterwards as [`TunnelEvent::PeersUpdated`], unless
+    /// the peers are replaced again before that.
+    pub fn update_peers(&self, config: Config) -> Result<()> {
+        let mut current_config = self.config.lock().expect("Config lock poisoned");
+        if !current_config.can_replace_peers(&config) {
+            return Err(Error::IncompatibleConfigError);
+        }
+
+        let interface = {
+            let tunnel = self.tunnel.upgrade().ok_or(Error::TunnelStoppedError)?;
+            let mut tunnel = tunnel.lock().expect("Tunnel lock poisoned");
+            let tunnel = tunnel.as_mut().ok_or(Error::TunnelStoppedError)?;
+            tunnel.set_config(&config).map_err(Error::TunnelError)?;
+            tunnel.get_interface_name()
+        };
+
+        let peers = Self::peer_keys(&config);
+        let gateway = config.ipv4_gateway;
+        *current_config = config;
+        drop(current_config);
+
+        let runtime = self.runtime.clone();
+        let tunnel = self.tunnel.clone();
+        let config = self.config.clone();
+        let event_callback = self.event_callback.clone();
+        thread::spawn(move || {
+            let handshake_completed =
+                match Self::wait_for_handshake(&tunnel, &config, &peers, gateway, interface) {
+                    Ok(()) => true,
+                    Err(Error::PeersReplacedError) => {
+                        log::debug!("Peers were replaced before a handshake with them completed");
+                        return;
+                    }
+                    Err(error) => {
+                        log::warn!(
+                            "{}",
+                            error.display_chain_with_msg(
+                                "Failed to complete a handshake with the new peers"
+                            )
+                        );
+                        false
+                    }
+                };
+            runtime.block_on((event_callback)(TunnelEvent::PeersUpdated {
+                handshake_completed,
+            }));
+        });
+        Ok(())
+    }
+
+    fn peer_keys(config: &Config) -> Vec<[u8; 32]> {
+        config
+            .peers
+            .iter()
+            .map(|peer| *peer.public_key.as_bytes())
+            .collect()
+    }
+
+    /// Waits until anything has been received from any of `peers`, which means that a handshake
+    /// with it has completed. The gateway is pinged to make the tunnel initiate the handshake.
+    /// Gives up if `config` no longer contains `peers`.
+    fn wait_for_handshake(
+        tunnel: &Weak<Mutex<Option<Box<dyn Tunnel>>>>,
+        config: &Mutex<Config>,
+        peers: &[[u8; 32]],
+        gateway: Ipv4Addr,
+        interface: String,
+    ) -> Result<()> {
+        if let Err(error) = crate::ping_monitor::new_pinger(gateway, interface)
+            .and_then(|mut pinger| pinger.send_icmp())
+        {
+            log::debug!(
+                "{}",
+                error.display_chain_with_msg("Failed to ping the gateway of the new peers")
+            );
+        }
+
+        let deadline = Instant::now() + PEER_HANDSHAKE_TIMEOUT;
+        loop {
+            if Self::peer_keys(&config.lock().expect("Config lock poisoned")) != peers {
+                return Err(Error::PeersReplacedError);
+            }
+            let stats = tunnel
+                .upgrade()
+                .ok_or(Error::TunnelStoppedError)?
+                .lock()
+                .expect("Tunnel lock poisoned")
+                .as_ref()
+                .ok_or(Error::TunnelStoppedError)?
+                .get_tunnel_stats()
+                .map_err(Error::TunnelError)?;
+            if peers
+                .iter()
+                .any(|peer| stats.get(peer).map_or(false, |stats| stats.rx_bytes > 0))
+            {
+                return Ok(());
+            }
+            if Instant::now() >= deadline {
+                return Err(Error::PeerHandshakeTimeout);
+            }
+            thread::sleep(PEER_HANDSHAKE_POLL_INTERVAL);
+        }
+    }
 }
 
 /// Simple wrapper that automatically cancels the future which runs an obfuscator.
@@ -215,7 +371,7 @@ impl WireguardMonitor {
         )?;
         let iface_name = tunnel.get_interface_name().to_string();
 
-        let event_callback = Box::new(on_event.clone());
+        let event_callback = Arc::new(on_event.clone());
         let (pinger_tx, pinger_rx) = sync_mpsc::channel();
         let monitor = WireguardMonitor {
             runtime: runtime.clone(),
@@ -224,6 +380,8 @@ impl WireguardMonitor {
             close_msg_receiver,
             pinger_stop_sender: pinger_tx,
             _obfuscator: obfuscator,
+            #[cfg(target_os = "linux")]
+            config: Arc::new(Mutex::new(config.clone())),
         };
 
         let gateway = config.ipv4_gateway;
@@ -421,6 +579,17 @@ impl WireguardMonitor {
         ))
     }
 
+    /// Returns a handle for switching the tunnel to a new config while it is running.
+    #[cfg(target_os = "linux")]
+    pub fn peer_updater(&self) -> PeerUpdater {
+        PeerUpdater {
+            runtime: self.runtime.clone(),
+            tunnel: Arc::downgrade(&self.tunnel),
+            config: self.config.clone(),
+            event_callback: self.event_callback.clone(),
+        }
+    }
+
     /// Blocks the current thread until tunnel disconnects
     pub fn wait(mut self) -> Result<()> {
         let wait_result = match self.close_msg_receiver.recv() {
@@ -594,6 +763,12 @@ pub(crate) trait Tunnel: Send {
     fn get_interface_name(&self) -> String;
     fn stop(self: Box<Self>) -> std::result::Result<(), TunnelError>;
     fn get_tunnel_stats(&self) -> std::result::Result<stats::StatsMap, TunnelError>;
+
+    /// Replaces the private key and peers of the tunnel with those in `config`.
+    #[cfg(target_os = "linux")]
+    fn set_config(&mut self, _config: &Config) -> std::result::Result<(), TunnelError> {
+        Err(TunnelError::SetConfigUnsupported)
+    }
 }
 
 /// Errors to be returned from WireGuard implementations, namely implementers of the Tunnel trait
@@ -662,4 +837,14 @@ pub enum TunnelError {
     /// Failure to set up logging
     #[error(display = "Failed to set up logging")]
     LoggingError(#[error(source)] logging::Error),
+
+    /// The tunnel implementation cannot change its config while running
+    #[cfg(target_os = "linux")]
+    #[error(display = "Changing the config of a running tunnel is not supported")]
+    SetConfigUnsupported,
+
+    /// Failed to apply a new config to a running tunnel
+    #[cfg(target_os = "linux")]
+    #[error(display = "Failed to set the config of the WireGuard tunnel")]
+    SetConfigError(#[error(source)] wireguard_kernel::Error),
 }
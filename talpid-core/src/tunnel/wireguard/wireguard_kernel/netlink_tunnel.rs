@@ -109,4 +109,12 @@ impl Tunnel for NetlinkTunnel {
 
         result
     }
+
+    fn set_config(&mut self, config: &Config) -> std::result::Result<(), TunnelError> {
+        let mut wg = self.netlink_connections.wg_handle.clone();
+        let interface_index = self.interface_index;
+        self.tokio_handle
+            .block_on(async move { wg.set_config(interface_index, config).await })
+            .map_err(TunnelError::SetConfigError)
+    }
 }
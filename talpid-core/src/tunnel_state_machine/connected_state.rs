@@ -3,6 +3,8 @@ use super::{
     EventResult, SharedTunnelStateValues, TunnelCommand, TunnelCommandReceiver, TunnelState,
     TunnelStateTransition, TunnelStateWrapper,
 };
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+use crate::routing::{NetNode, RequiredRoute};
 use crate::{
     firewall::FirewallPolicy,
     tunnel::{TunnelEvent, TunnelMetadata},
@@ -13,7 +15,11 @@ use futures::{
     stream::Fuse,
     StreamExt,
 };
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+use std::collections::HashSet;
 use std::net::IpAddr;
+#[cfg(target_os = "linux")]
+use talpid_types::net::{ExcludedDestinations, TrafficStats};
 use talpid_types::{
     net::TunnelParameters,
     tunnel::{ErrorStateCause, FirewallPolicyError},
@@ -23,6 +29,8 @@ use talpid_types::{
 #[cfg(windows)]
 use crate::tunnel::TunnelMonitor;
 
+#[cfg(target_os = "linux")]
+use super::connecting_state::PeerUpdaterSlot;
 use super::connecting_state::TunnelCloseEvent;
 
 pub(crate) type TunnelEventsReceiver =
@@ -34,6 +42,8 @@ pub struct ConnectedStateBootstrap {
     pub tunnel_parameters: TunnelParameters,
     pub tunnel_close_event: TunnelCloseEvent,
     pub tunnel_close_tx: oneshot::Sender<()>,
+    #[cfg(target_os = "linux")]
+    pub peer_updater: PeerUpdaterSlot,
 }
 
 /// The tunnel is up and working.
@@ -43,6 +53,11 @@ pub struct ConnectedState {
     tunnel_parameters: TunnelParameters,
     tunnel_close_event: TunnelCloseEvent,
     tunnel_close_tx: oneshot::Sender<()>,
+    #[cfg(target_os = "linux")]
+    peer_updater: PeerUpdaterSlot,
+    /// Interface traffic counters when the tunnel switched to the current relay.
+    #[cfg(target_os = "linux")]
+    traffic_offset: TrafficStats,
 }
 
 impl ConnectedState {
@@ -53,6 +68,10 @@ impl ConnectedState {
             tunnel_parameters: bootstrap.tunnel_parameters,
             tunnel_close_event: bootstrap.tunnel_close_event,
             tunnel_close_tx: bootstrap.tunnel_close_tx,
+            #[cfg(target_os = "linux")]
+            peer_updater: bootstrap.peer_updater,
+            #[cfg(target_os = "linux")]
+            traffic_offset: TrafficStats::default(),
         }
     }
 
@@ -105,13 +124,36 @@ impl ConnectedState {
         }
     }
 
+    /// Returns the DNS servers that plain DNS may be sent to. When queries are forwarded to
+    /// encrypted resolvers, no plain DNS should leave the device.
+    #[cfg(not(target_os = "android"))]
+    fn get_firewall_dns_servers(&self, shared_values: &SharedTunnelStateValues) -> Vec<IpAddr> {
+        #[cfg(target_os = "linux")]
+        if shared_values.dns_filter.is_encrypted() {
+            return vec![];
+        }
+        self.get_dns_servers(shared_values)
+    }
+
     fn get_firewall_policy(&self, shared_values: &SharedTunnelStateValues) -> FirewallPolicy {
         FirewallPolicy::Connected {
             peer_endpoint: self.tunnel_parameters.get_next_hop_endpoint(),
             tunnel: self.metadata.clone(),
             allow_lan: shared_values.allow_lan,
+            #[cfg(unix)]
+            lan_networks: shared_values.lan_networks.clone(),
             #[cfg(not(target_os = "android"))]
-            dns_servers: self.get_dns_servers(shared_values),
+            dns_servers: self.get_firewall_dns_servers(shared_values),
+            #[cfg(target_os = "linux")]
+            allow_rules: shared_values.allow_rules.clone(),
+            #[cfg(target_os = "linux")]
+            split_tunnel_mode: shared_values.split_tunnel_mode,
+            #[cfg(target_os = "linux")]
+            excluded_destinations: shared_values.excluded_destinations.clone(),
+            #[cfg(target_os = "linux")]
+            exclusion_profiles: shared_values.exclusion_profiles.clone(),
+            #[cfg(target_os = "linux")]
+            split_dns_resolvers: Self::get_split_dns_resolvers(shared_values),
             #[cfg(windows)]
             relay_client: TunnelMonitor::get_relay_client(
                 &shared_values.resource_dir,
@@ -120,6 +162,21 @@ impl ConnectedState {
         }
     }
 
+    #[cfg(target_os = "linux")]
+    fn get_split_dns_resolvers(shared_values: &SharedTunnelStateValues) -> Vec<IpAddr> {
+        let mut resolvers = Vec::new();
+        for resolver in shared_values
+            .split_dns
+            .iter()
+            .flat_map(|rule| rule.resolvers.iter())
+        {
+            if !resolvers.contains(resolver) {
+                resolvers.push(*resolver);
+            }
+        }
+        resolvers
+    }
+
     fn set_dns(&self, shared_values: &mut SharedTunnelStateValues) -> Result<(), BoxedError> {
         let dns_ips = self.get_dns_servers(shared_values);
 
@@ -127,12 +184,34 @@ impl ConnectedState {
         let dns_ips = &dns_ips
             .into_iter()
             .filter(|ip| {
-                !crate::firewall::is_local_address(ip)
+                !crate::firewall::is_local_address(ip, &shared_values.lan_networks)
                     || IpAddr::V4(self.metadata.ipv4_gateway) == *ip
                     || self.metadata.ipv6_gateway.map(IpAddr::V6) == Some(*ip)
             })
             .collect::<Vec<_>>();
 
+        // Split DNS rules are handled by systemd-resolved if it is used. Otherwise, the local
+        // resolver has to forward those queries.
+        #[cfg(target_os = "linux")]
+        let forward_split_dns =
+            !shared_values.split_dns.is_empty() && !shared_values.dns_monitor.supports_split_dns();
+        #[cfg(target_os = "linux")]
+        shared_values
+            .dns_monitor
+            .set_split_dns(shared_values.split_dns.clone());
+
+        // Resolve through the local filtering resolver, which forwards to the actual servers
+        #[cfg(target_os = "linux")]
+        let dns_ips = &if shared_values.dns_filter.is_enabled() || forward_split_dns {
+            vec![shared_values
+                .dns_filter
+                .start(dns_ips)
+                .map_err(BoxedError::new)?]
+        } else {
+            shared_values.dns_filter.stop();
+            dns_ips.clone()
+        };
+
         shared_values
             .dns_monitor
             .set(&self.metadata.interface, &dns_ips)
@@ -145,6 +224,8 @@ impl ConnectedState {
         if let Err(error) = shared_values.dns_monitor.reset() {
             log::error!("{}", error.display_chain_with_msg("Unable to reset DNS"));
         }
+        #[cfg(target_os = "linux")]
+        shared_values.dns_filter.stop();
     }
 
     fn reset_routes(shared_values: &mut SharedTunnelStateValues) {
@@ -163,11 +244,169 @@ impl ConnectedState {
         }
     }
 
+    /// Routes the excluded destinations outside the tunnel, and removes the routes of those in
+    /// `previous` that are no longer excluded. The firewall marks packets to excluded destinations
+    /// so that they skip the tunnel table regardless, but the routes make sure that new sockets
+    /// get a source address that is valid outside the tunnel.
+    #[cfg(target_os = "linux")]
+    fn set_excluded_routes(
+        shared_values: &mut SharedTunnelStateValues,
+        previous: &ExcludedDestinations,
+    ) {
+        let routes = excluded_routes(&shared_values.excluded_destinations);
+        let lan_routes = lan_routes(shared_values);
+        let stale_routes = excluded_routes(previous)
+            .difference(&routes)
+            .filter(|route| !lan_routes.contains(route))
+            .cloned()
+            .collect::<HashSet<_>>();
+        Self::replace_routes(
+            shared_values,
+            routes,
+            stale_routes,
+            "Failed to set routes for excluded destinations",
+        );
+    }
+
+    /// Routes the LAN networks outside the tunnel while local network sharing is allowed, and
+    /// removes the routes in `previous` that no longer apply. The system already routes the
+    /// networks that the device is directly connected to outside the tunnel, but local networks
+    /// that are reached through a gateway, such as carrier-grade NAT ranges, would otherwise be
+    /// sent into the tunnel.
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    fn set_lan_routes(
+        shared_values: &mut SharedTunnelStateValues,
+        previous: HashSet<RequiredRoute>,
+    ) {
+        let routes = lan_routes(shared_values);
+        #[cfg_attr(not(target_os = "linux"), allow(unused_mut))]
+        let mut stale_routes = previous
+            .difference(&routes)
+            .cloned()
+            .collect::<HashSet<_>>();
+        // Keep the routes that are also needed for excluded destinations
+        #[cfg(target_os = "linux")]
+        for route in excluded_routes(&shared_values.excluded_destinations) {
+            stale_routes.remove(&route);
+        }
+        Self::replace_routes(
+            shared_values,
+            routes,
+            stale_routes,
+            "Failed to set routes for LAN networks",
+        );
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    fn replace_routes(
+        shared_values: &mut SharedTunnelStateValues,
+        routes: HashSet<RequiredRoute>,
+        stale_routes: HashSet<RequiredRoute>,
+        error_message: &str,
+    ) {
+        if routes.is_empty() && stale_routes.is_empty() {
+            return;
+        }
+
+        let route_manager = &mut shared_values.route_manager;
+        let result = shared_values.runtime.block_on(async {
+            route_manager.remove_routes(stale_routes).await?;
+            route_manager.add_routes(routes).await
+        });
+        if let Err(error) = result {
+            log::error!("{}", error.display_chain_with_msg(error_message));
+        }
+    }
+
+    /// Returns the bytes transferred through the tunnel since it switched to the current relay.
+    #[cfg(target_os = "linux")]
+    fn tunnel_traffic(&self) -> Option<TrafficStats> {
+        let traffic = crate::linux::interface_traffic(&self.metadata.interface).ok()?;
+        Some(TrafficStats {
+            rx_bytes: traffic
+                .rx_bytes
+                .saturating_sub(self.traffic_offset.rx_bytes),
+            tx_bytes: traffic
+                .tx_bytes
+                .saturating_sub(self.traffic_offset.tx_bytes),
+        })
+    }
+
+    /// Switches the tunnel to newly generated parameters by only replacing its peers and updating
+    /// the peer endpoint in the firewall. The interface, addresses, routes and DNS are kept, so
+    /// connections through the tunnel survive the relay change. The new relay must complete a
+    /// handshake before the tunnel is reported as connected to it, which is signaled by
+    /// `TunnelEvent::PeersUpdated`. Reconnects instead if the tunnel cannot be switched this way.
+    #[cfg(target_os = "linux")]
+    fn switch_relay(mut self, shared_values: &mut SharedTunnelStateValues) -> EventConsequence {
+        if !matches!(self.tunnel_parameters, TunnelParameters::Wireguard(_))
+            || self.peer_updater.lock().unwrap().is_none()
+        {
+            return self.disconnect(shared_values, AfterDisconnect::Reconnect(0));
+        }
+
+        let parameters = match shared_values
+            .runtime
+            .block_on(shared_values.tunnel_parameters_generator.generate(0))
+        {
+            Ok(TunnelParameters::Wireguard(parameters)) => parameters,
+            // Let the connecting state handle other tunnel types and errors
+            _ => return self.disconnect(shared_values, AfterDisconnect::Reconnect(0)),
+        };
+
+        self.tunnel_parameters = TunnelParameters::Wireguard(parameters.clone());
+        if let Err(error) = self.set_firewall_policy(shared_values) {
+            return self.disconnect(
+                shared_values,
+                AfterDisconnect::Block(ErrorStateCause::SetFirewallPolicyError(error)),
+            );
+        }
+
+        let result = self
+            .peer_updater
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|updater| updater.update_peers(&parameters));
+        match result {
+            Some(Ok(())) => {
+                log::debug!("Waiting for a handshake with the new relay");
+                EventConsequence::SameState(self.into())
+            }
+            Some(Err(error)) => {
+                log::info!(
+                    "{}",
+                    error.display_chain_with_msg("Failed to switch relay without reconnecting")
+                );
+                self.disconnect(shared_values, AfterDisconnect::Reconnect(0))
+            }
+            None => self.disconnect(shared_values, AfterDisconnect::Reconnect(0)),
+        }
+    }
+
+    /// Reports the tunnel as connected to the relay that it was switched to.
+    #[cfg(target_os = "linux")]
+    fn finish_relay_switch(mut self) -> EventConsequence {
+        log::info!("Switched relay without reconnecting");
+        if let Ok(traffic) = crate::linux::interface_traffic(&self.metadata.interface) {
+            self.traffic_offset = traffic;
+        }
+        let tunnel_endpoint = self.tunnel_parameters.get_tunnel_endpoint();
+        EventConsequence::NewState((
+            TunnelStateWrapper::from(self),
+            TunnelStateTransition::Connected(tunnel_endpoint),
+        ))
+    }
+
     fn disconnect(
         self,
         shared_values: &mut SharedTunnelStateValues,
         after_disconnect: AfterDisconnect,
     ) -> EventConsequence {
+        #[cfg(target_os = "linux")]
+        {
+            shared_values.last_tunnel_traffic = self.tunnel_traffic();
+        }
         Self::reset_dns(shared_values);
         Self::reset_routes(shared_values);
 
@@ -190,11 +429,15 @@ impl ConnectedState {
 
         match command {
             Some(TunnelCommand::AllowLan(allow_lan)) => {
+                #[cfg(any(target_os = "linux", target_os = "macos"))]
+                let previous_lan_routes = lan_routes(shared_values);
                 if let Err(error_cause) = shared_values.set_allow_lan(allow_lan) {
                     self.disconnect(shared_values, AfterDisconnect::Block(error_cause))
                 } else {
                     match self.set_firewall_policy(shared_values) {
                         Ok(()) => {
+                            #[cfg(any(target_os = "linux", target_os = "macos"))]
+                            Self::set_lan_routes(shared_values, previous_lan_routes);
                             cfg_if! {
                                 if #[cfg(target_os = "android")] {
                                     self.disconnect(shared_values, AfterDisconnect::Reconnect(0))
@@ -210,6 +453,47 @@ impl ConnectedState {
                     }
                 }
             }
+            #[cfg(unix)]
+            Some(TunnelCommand::SetLanNetworks(lan_networks)) => {
+                #[cfg(any(target_os = "linux", target_os = "macos"))]
+                let previous_lan_routes = lan_routes(shared_values);
+                match shared_values.set_lan_networks(lan_networks) {
+                    Ok(true) => {
+                        if let Err(error) = self.set_firewall_policy(shared_values) {
+                            return self.disconnect(
+                                shared_values,
+                                AfterDisconnect::Block(ErrorStateCause::SetFirewallPolicyError(
+                                    error,
+                                )),
+                            );
+                        }
+                        #[cfg(any(target_os = "linux", target_os = "macos"))]
+                        Self::set_lan_routes(shared_values, previous_lan_routes);
+
+                        // Whether a DNS server is considered local depends on the LAN networks
+                        match self.set_dns(shared_values) {
+                            #[cfg(target_os = "android")]
+                            Ok(()) => self.disconnect(shared_values, AfterDisconnect::Reconnect(0)),
+                            #[cfg(not(target_os = "android"))]
+                            Ok(()) => SameState(self.into()),
+                            Err(error) => {
+                                log::error!(
+                                    "{}",
+                                    error.display_chain_with_msg("Failed to set DNS")
+                                );
+                                self.disconnect(
+                                    shared_values,
+                                    AfterDisconnect::Block(ErrorStateCause::SetDnsError),
+                                )
+                            }
+                        }
+                    }
+                    Ok(false) => SameState(self.into()),
+                    Err(error_cause) => {
+                        self.disconnect(shared_values, AfterDisconnect::Block(error_cause))
+                    }
+                }
+            }
             Some(TunnelCommand::AllowEndpoint(endpoint, tx)) => {
                 shared_values.allowed_endpoint = endpoint;
                 let _ = tx.send(());
@@ -258,6 +542,9 @@ impl ConnectedState {
                     SameState(self.into())
                 }
             }
+            #[cfg(target_os = "linux")]
+            Some(TunnelCommand::Connect) => self.switch_relay(shared_values),
+            #[cfg(not(target_os = "linux"))]
             Some(TunnelCommand::Connect) => {
                 self.disconnect(shared_values, AfterDisconnect::Reconnect(0))
             }
@@ -267,6 +554,162 @@ impl ConnectedState {
             Some(TunnelCommand::Block(reason)) => {
                 self.disconnect(shared_values, AfterDisconnect::Block(reason))
             }
+            #[cfg(target_os = "linux")]
+            Some(TunnelCommand::SetAllowRules(allow_rules)) => {
+                if shared_values.allow_rules != allow_rules {
+                    shared_values.allow_rules = allow_rules;
+                    if let Err(error) = self.set_firewall_policy(shared_values) {
+                        return self.disconnect(
+                            shared_values,
+                            AfterDisconnect::Block(ErrorStateCause::SetFirewallPolicyError(error)),
+                        );
+                    }
+                }
+                SameState(self.into())
+            }
+            #[cfg(target_os = "linux")]
+            Some(TunnelCommand::SetSplitTunnelMode(split_tunnel_mode)) => {
+                if shared_values.split_tunnel_mode != split_tunnel_mode {
+                    shared_values.split_tunnel_mode = split_tunnel_mode;
+                    if let Err(error) = self.set_firewall_policy(shared_values) {
+                        return self.disconnect(
+                            shared_values,
+                            AfterDisconnect::Block(ErrorStateCause::SetFirewallPolicyError(error)),
+                        );
+                    }
+                }
+                SameState(self.into())
+            }
+            #[cfg(target_os = "linux")]
+            Some(TunnelCommand::SetExclusionProfiles(exclusion_profiles)) => {
+                if shared_values.exclusion_profiles != exclusion_profiles {
+                    shared_values.exclusion_profiles = exclusion_profiles;
+                    if let Err(error) = self.set_firewall_policy(shared_values) {
+                        return self.disconnect(
+                            shared_values,
+                            AfterDisconnect::Block(ErrorStateCause::SetFirewallPolicyError(error)),
+                        );
+                    }
+                }
+                SameState(self.into())
+            }
+            #[cfg(target_os = "linux")]
+            Some(TunnelCommand::SetExcludedDestinations(excluded_destinations)) => {
+                if shared_values.excluded_destinations != excluded_destinations {
+                    let previous = std::mem::replace(
+                        &mut shared_values.excluded_destinations,
+                        excluded_destinations,
+                    );
+                    if let Err(error) = self.set_firewall_policy(shared_values) {
+                        return self.disconnect(
+                            shared_values,
+                            AfterDisconnect::Block(ErrorStateCause::SetFirewallPolicyError(error)),
+                        );
+                    }
+                    Self::set_excluded_routes(shared_values, &previous);
+                }
+                SameState(self.into())
+            }
+            #[cfg(target_os = "linux")]
+            Some(TunnelCommand::SetDnsBlocklists(blocklists)) => {
+                shared_values.dns_filter.set_blocklists(blocklists);
+                match self.set_dns(shared_values) {
+                    Ok(()) => SameState(self.into()),
+                    Err(error) => {
+                        log::error!("{}", error.display_chain_with_msg("Failed to set DNS"));
+                        self.disconnect(
+                            shared_values,
+                            AfterDisconnect::Block(ErrorStateCause::SetDnsError),
+                        )
+                    }
+                }
+            }
+            #[cfg(target_os = "linux")]
+            Some(TunnelCommand::SetEncryptedDns(servers)) => {
+                shared_values.dns_filter.set_encrypted_servers(servers);
+                if let Err(error) = self.set_firewall_policy(shared_values) {
+                    return self.disconnect(
+                        shared_values,
+                        AfterDisconnect::Block(ErrorStateCause::SetFirewallPolicyError(error)),
+                    );
+                }
+                match self.set_dns(shared_values) {
+                    Ok(()) => SameState(self.into()),
+                    Err(error) => {
+                        log::error!("{}", error.display_chain_with_msg("Failed to set DNS"));
+                        self.disconnect(
+                            shared_values,
+                            AfterDisconnect::Block(ErrorStateCause::SetDnsError),
+                        )
+                    }
+                }
+            }
+            #[cfg(target_os = "linux")]
+            Some(TunnelCommand::SetSplitDns(rules)) => {
+                shared_values.dns_filter.set_split_dns(rules.clone());
+                shared_values.split_dns = rules;
+                if let Err(error) = self.set_firewall_policy(shared_values) {
+                    return self.disconnect(
+                        shared_values,
+                        AfterDisconnect::Block(ErrorStateCause::SetFirewallPolicyError(error)),
+                    );
+                }
+                match self.set_dns(shared_values) {
+                    Ok(()) => SameState(self.into()),
+                    Err(error) => {
+                        log::error!("{}", error.display_chain_with_msg("Failed to set DNS"));
+                        self.disconnect(
+                            shared_values,
+                            AfterDisconnect::Block(ErrorStateCause::SetDnsError),
+                        )
+                    }
+                }
+            }
+            #[cfg(target_os = "linux")]
+            Some(TunnelCommand::VerifyFirewall) => match shared_values.restore_firewall_policy() {
+                Ok(()) => SameState(self.into()),
+                Err(error) => self.disconnect(
+                    shared_values,
+                    AfterDisconnect::Block(ErrorStateCause::SetFirewallPolicyError(error)),
+                ),
+            },
+            Some(TunnelCommand::GetFirewallPolicy(query, result_tx)) => {
+                let _ = result_tx.send(shared_values.query_firewall_policy(query));
+                SameState(self.into())
+            }
+            #[cfg(target_os = "linux")]
+            Some(TunnelCommand::GetBlockedConnections(result_tx)) => {
+                let _ = result_tx.send(shared_values.firewall.blocked_connections());
+                SameState(self.into())
+            }
+            #[cfg(target_os = "linux")]
+            Some(TunnelCommand::GetDnsConfig(result_tx)) => {
+                let _ = result_tx.send(shared_values.dns_config());
+                SameState(self.into())
+            }
+            #[cfg(target_os = "linux")]
+            Some(TunnelCommand::GetTunnelMetadata(result_tx)) => {
+                let _ = result_tx.send(Some(self.metadata.clone()));
+                SameState(self.into())
+            }
+            #[cfg(target_os = "linux")]
+            Some(TunnelCommand::GetTrafficStats(result_tx)) => {
+                let _ = result_tx.send(self.tunnel_traffic());
+                SameState(self.into())
+            }
+            #[cfg(target_os = "linux")]
+            Some(TunnelCommand::SetCaptivePortalHosts(hosts, tx)) => {
+                shared_values.captive_portal_hosts = hosts;
+                let _ = tx.send(());
+                SameState(self.into())
+            }
+            #[cfg(target_os = "linux")]
+            Some(TunnelCommand::UnlockCaptivePortal(duration, probe_url, result_tx)) => {
+                shared_values
+                    .captive_portal
+                    .unlock(duration, probe_url, result_tx);
+                SameState(self.into())
+            }
             #[cfg(target_os = "android")]
             Some(TunnelCommand::BypassSocket(fd, done_tx)) => {
                 shared_values.bypass_socket(fd, done_tx);
@@ -291,6 +734,23 @@ impl ConnectedState {
             Some((TunnelEvent::Down, _)) | None => {
                 self.disconnect(shared_values, AfterDisconnect::Reconnect(0))
             }
+            #[cfg(target_os = "linux")]
+            Some((
+                TunnelEvent::PeersUpdated {
+                    handshake_completed: true,
+                },
+                _,
+            )) => self.finish_relay_switch(),
+            #[cfg(target_os = "linux")]
+            Some((
+                TunnelEvent::PeersUpdated {
+                    handshake_completed: false,
+                },
+                _,
+            )) => {
+                log::info!("The new relay did not respond. Reconnecting.");
+                self.disconnect(shared_values, AfterDisconnect::Reconnect(0))
+            }
             Some(_) => SameState(self.into()),
         }
     }
@@ -346,6 +806,13 @@ impl TunnelState for ConnectedState {
                 ),
             )
         } else {
+            #[cfg(target_os = "linux")]
+            {
+                Self::set_excluded_routes(shared_values, &ExcludedDestinations::default());
+                shared_values.last_tunnel_traffic = None;
+            }
+            #[cfg(any(target_os = "linux", target_os = "macos"))]
+            Self::set_lan_routes(shared_values, HashSet::new());
             (
                 TunnelStateWrapper::from(connected_state),
                 TunnelStateTransition::Connected(tunnel_endpoint),
@@ -380,3 +847,27 @@ impl TunnelState for ConnectedState {
         }
     }
 }
+
+/// Returns the routes that send the LAN networks outside the tunnel, if local network sharing is
+/// allowed.
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn lan_routes(shared_values: &SharedTunnelStateValues) -> HashSet<RequiredRoute> {
+    if !shared_values.allow_lan {
+        return HashSet::new();
+    }
+    shared_values
+        .lan_networks
+        .networks
+        .iter()
+        .map(|network| RequiredRoute::new(*network, NetNode::DefaultNode))
+        .collect()
+}
+
+#[cfg(target_os = "linux")]
+fn excluded_routes(excluded_destinations: &ExcludedDestinations) -> HashSet<RequiredRoute> {
+    excluded_destinations
+        .networks
+        .iter()
+        .map(|network| RequiredRoute::new(*network, NetNode::DefaultNode))
+        .collect()
+}
@@ -1,12 +1,14 @@
+#[cfg(target_os = "linux")]
+use super::platform::PeerUpdater;
 use super::{
+    platform::{Tunnel, TunnelStarter},
     AfterDisconnect, ConnectedState, ConnectedStateBootstrap, DisconnectingState, ErrorState,
     EventConsequence, EventResult, SharedTunnelStateValues, TunnelCommand, TunnelCommandReceiver,
     TunnelState, TunnelStateTransition, TunnelStateWrapper,
 };
 use crate::{
     firewall::FirewallPolicy,
-    routing::RouteManager,
-    tunnel::{self, tun_provider::TunProvider, TunnelEvent, TunnelMetadata, TunnelMonitor},
+    tunnel::{self, TunnelEvent, TunnelMetadata},
 };
 use cfg_if::cfg_if;
 use futures::{
@@ -14,9 +16,11 @@ use futures::{
     future::Fuse,
     FutureExt, StreamExt,
 };
+#[cfg(target_os = "linux")]
+use std::sync::Mutex;
 use std::{
     path::{Path, PathBuf},
-    sync::{Arc, Mutex},
+    sync::Arc,
     thread,
     time::{Duration, Instant},
 };
@@ -27,7 +31,7 @@ use talpid_types::{
 };
 
 #[cfg(windows)]
-use crate::{routing, winnet};
+use crate::{routing, tunnel::TunnelMonitor, winnet};
 
 #[cfg(target_os = "android")]
 use crate::tunnel::tun_provider;
@@ -36,6 +40,10 @@ use super::connected_state::TunnelEventsReceiver;
 
 pub(crate) type TunnelCloseEvent = Fuse<oneshot::Receiver<Option<ErrorStateCause>>>;
 
+/// Holds the peer updater of the tunnel once it has been started.
+#[cfg(target_os = "linux")]
+pub(crate) type PeerUpdaterSlot = Arc<Mutex<Option<Box<dyn PeerUpdater>>>>;
+
 #[cfg(target_os = "android")]
 const MAX_ATTEMPTS_WITH_SAME_TUN: u32 = 5;
 const MIN_TUNNEL_ALIVE_TIME: Duration = Duration::from_millis(1000);
@@ -50,6 +58,8 @@ pub struct ConnectingState {
     tunnel_close_event: TunnelCloseEvent,
     tunnel_close_tx: oneshot::Sender<()>,
     retry_attempt: u32,
+    #[cfg(target_os = "linux")]
+    peer_updater: PeerUpdaterSlot,
 }
 
 impl ConnectingState {
@@ -67,7 +77,17 @@ impl ConnectingState {
             peer_endpoint,
             tunnel: tunnel_metadata.clone(),
             allow_lan: shared_values.allow_lan,
+            #[cfg(unix)]
+            lan_networks: shared_values.lan_networks.clone(),
             allowed_endpoint: shared_values.allowed_endpoint.clone(),
+            #[cfg(target_os = "linux")]
+            allow_rules: shared_values.allow_rules.clone(),
+            #[cfg(target_os = "linux")]
+            split_tunnel_mode: shared_values.split_tunnel_mode,
+            #[cfg(target_os = "linux")]
+            excluded_destinations: shared_values.excluded_destinations.clone(),
+            #[cfg(target_os = "linux")]
+            exclusion_profiles: shared_values.exclusion_profiles.clone(),
             #[cfg(windows)]
             relay_client: TunnelMonitor::get_relay_client(&shared_values.resource_dir, &params),
         };
@@ -94,21 +114,11 @@ impl ConnectingState {
         parameters: TunnelParameters,
         log_dir: &Option<PathBuf>,
         resource_dir: &Path,
-        tun_provider: Arc<Mutex<TunProvider>>,
-        route_manager: &mut RouteManager,
+        tunnel_starter: Arc<dyn TunnelStarter>,
         retry_attempt: u32,
     ) -> Self {
         let (event_tx, event_rx) = mpsc::unbounded();
-        let on_tunnel_event =
-            move |event| -> std::pin::Pin<Box<dyn std::future::Future<Output = ()> + Send>> {
-                let (tx, rx) = oneshot::channel();
-                let _ = event_tx.unbounded_send((event, tx));
-                Box::pin(async move {
-                    let _ = rx.await;
-                })
-            };
 
-        let route_manager_handle = route_manager.handle();
         let log_dir = log_dir.clone();
         let resource_dir = resource_dir.to_path_buf();
 
@@ -117,41 +127,34 @@ impl ConnectingState {
 
         let tunnel_parameters = parameters.clone();
 
+        #[cfg(target_os = "linux")]
+        let peer_updater = PeerUpdaterSlot::default();
+        #[cfg(target_os = "linux")]
+        let tunnel_peer_updater = peer_updater.clone();
+
         tokio::task::spawn_blocking(move || {
             let start = Instant::now();
 
-            let route_manager_handle = match route_manager_handle {
-                Ok(handle) => handle,
-                Err(error) => {
-                    if tunnel_close_event_tx
-                        .send(Some(ErrorStateCause::StartTunnelError))
-                        .is_err()
-                    {
-                        log::warn!(
-                            "Tunnel state machine stopped before receiving tunnel closed event"
-                        );
-                    }
-                    log::error!(
-                        "{}",
-                        error.display_chain_with_msg("Failed to obtain route monitor handle")
-                    );
-                    return;
-                }
-            };
-
-            let block_reason = match TunnelMonitor::start(
+            let block_reason = match tunnel_starter.start(
                 runtime,
                 &tunnel_parameters,
                 &log_dir,
                 &resource_dir,
-                on_tunnel_event,
-                tun_provider,
-                route_manager_handle,
+                event_tx,
                 retry_attempt,
                 tunnel_close_rx,
             ) {
-                Ok(monitor) => {
-                    let reason = Self::wait_for_tunnel_monitor(monitor, retry_attempt);
+                Ok(tunnel) => {
+                    #[cfg(target_os = "linux")]
+                    {
+                        *tunnel_peer_updater.lock().unwrap() = tunnel.peer_updater();
+                    }
+                    let reason = Self::wait_for_tunnel(tunnel, retry_attempt);
+                    // The peer updater can send tunnel events, so it must not outlive the tunnel
+                    #[cfg(target_os = "linux")]
+                    {
+                        *tunnel_peer_updater.lock().unwrap() = None;
+                    }
                     log::debug!("Tunnel monitor exited with block reason: {:?}", reason);
                     reason
                 }
@@ -210,14 +213,13 @@ impl ConnectingState {
             tunnel_close_event: tunnel_close_event_rx.fuse(),
             tunnel_close_tx,
             retry_attempt,
+            #[cfg(target_os = "linux")]
+            peer_updater,
         }
     }
 
-    fn wait_for_tunnel_monitor(
-        tunnel_monitor: TunnelMonitor,
-        retry_attempt: u32,
-    ) -> Option<ErrorStateCause> {
-        match tunnel_monitor.wait() {
+    fn wait_for_tunnel(tunnel: Box<dyn Tunnel>, retry_attempt: u32) -> Option<ErrorStateCause> {
+        match tunnel.wait() {
             Ok(_) => None,
             Err(error) => match error {
                 tunnel::Error::WireguardTunnelMonitoringError(
@@ -253,6 +255,8 @@ impl ConnectingState {
             tunnel_parameters: self.tunnel_parameters,
             tunnel_close_event: self.tunnel_close_event,
             tunnel_close_tx: self.tunnel_close_tx,
+            #[cfg(target_os = "linux")]
+            peer_updater: self.peer_updater,
         }
     }
 
@@ -326,6 +330,16 @@ impl ConnectingState {
                     self.reset_firewall(shared_values)
                 }
             }
+            #[cfg(unix)]
+            Some(TunnelCommand::SetLanNetworks(lan_networks)) => {
+                match shared_values.set_lan_networks(lan_networks) {
+                    Ok(true) => self.reset_firewall(shared_values),
+                    Ok(false) => SameState(self.into()),
+                    Err(error_cause) => {
+                        self.disconnect(shared_values, AfterDisconnect::Block(error_cause))
+                    }
+                }
+            }
             Some(TunnelCommand::AllowEndpoint(endpoint, tx)) => {
                 if shared_values.allowed_endpoint != endpoint {
                     shared_values.allowed_endpoint = endpoint;
@@ -374,6 +388,135 @@ impl ConnectingState {
             Some(TunnelCommand::Block(reason)) => {
                 self.disconnect(shared_values, AfterDisconnect::Block(reason))
             }
+            #[cfg(target_os = "linux")]
+            Some(TunnelCommand::SetAllowRules(allow_rules)) => {
+                if shared_values.allow_rules != allow_rules {
+                    shared_values.allow_rules = allow_rules;
+                    if let Err(error) = Self::set_firewall_policy(
+                        shared_values,
+                        &self.tunnel_parameters,
+                        &self.tunnel_metadata,
+                    ) {
+                        return self.disconnect(
+                            shared_values,
+                            AfterDisconnect::Block(ErrorStateCause::SetFirewallPolicyError(error)),
+                        );
+                    }
+                }
+                SameState(self.into())
+            }
+            #[cfg(target_os = "linux")]
+            Some(TunnelCommand::SetSplitTunnelMode(split_tunnel_mode)) => {
+                if shared_values.split_tunnel_mode != split_tunnel_mode {
+                    shared_values.split_tunnel_mode = split_tunnel_mode;
+                    if let Err(error) = Self::set_firewall_policy(
+                        shared_values,
+                        &self.tunnel_parameters,
+                        &self.tunnel_metadata,
+                    ) {
+                        return self.disconnect(
+                            shared_values,
+                            AfterDisconnect::Block(ErrorStateCause::SetFirewallPolicyError(error)),
+                        );
+                    }
+                }
+                SameState(self.into())
+            }
+            #[cfg(target_os = "linux")]
+            Some(TunnelCommand::SetExclusionProfiles(exclusion_profiles)) => {
+                if shared_values.exclusion_profiles != exclusion_profiles {
+                    shared_values.exclusion_profiles = exclusion_profiles;
+                    if let Err(error) = Self::set_firewall_policy(
+                        shared_values,
+                        &self.tunnel_parameters,
+                        &self.tunnel_metadata,
+                    ) {
+                        return self.disconnect(
+                            shared_values,
+                            AfterDisconnect::Block(ErrorStateCause::SetFirewallPolicyError(error)),
+                        );
+                    }
+                }
+                SameState(self.into())
+            }
+            #[cfg(target_os = "linux")]
+            Some(TunnelCommand::SetExcludedDestinations(excluded_destinations)) => {
+                if shared_values.excluded_destinations != excluded_destinations {
+                    shared_values.excluded_destinations = excluded_destinations;
+                    if let Err(error) = Self::set_firewall_policy(
+                        shared_values,
+                        &self.tunnel_parameters,
+                        &self.tunnel_metadata,
+                    ) {
+                        return self.disconnect(
+                            shared_values,
+                            AfterDisconnect::Block(ErrorStateCause::SetFirewallPolicyError(error)),
+                        );
+                    }
+                }
+                SameState(self.into())
+            }
+            #[cfg(target_os = "linux")]
+            Some(TunnelCommand::SetDnsBlocklists(blocklists)) => {
+                shared_values.dns_filter.set_blocklists(blocklists);
+                SameState(self.into())
+            }
+            #[cfg(target_os = "linux")]
+            Some(TunnelCommand::SetEncryptedDns(servers)) => {
+                shared_values.dns_filter.set_encrypted_servers(servers);
+                SameState(self.into())
+            }
+            #[cfg(target_os = "linux")]
+            Some(TunnelCommand::SetSplitDns(rules)) => {
+                shared_values.dns_filter.set_split_dns(rules.clone());
+                shared_values.split_dns = rules;
+                SameState(self.into())
+            }
+            #[cfg(target_os = "linux")]
+            Some(TunnelCommand::VerifyFirewall) => match shared_values.restore_firewall_policy() {
+                Ok(()) => SameState(self.into()),
+                Err(error) => self.disconnect(
+                    shared_values,
+                    AfterDisconnect::Block(ErrorStateCause::SetFirewallPolicyError(error)),
+                ),
+            },
+            Some(TunnelCommand::GetFirewallPolicy(query, result_tx)) => {
+                let _ = result_tx.send(shared_values.query_firewall_policy(query));
+                SameState(self.into())
+            }
+            #[cfg(target_os = "linux")]
+            Some(TunnelCommand::GetBlockedConnections(result_tx)) => {
+                let _ = result_tx.send(shared_values.firewall.blocked_connections());
+                SameState(self.into())
+            }
+            #[cfg(target_os = "linux")]
+            Some(TunnelCommand::GetDnsConfig(result_tx)) => {
+                let _ = result_tx.send(shared_values.dns_config());
+                SameState(self.into())
+            }
+            #[cfg(target_os = "linux")]
+            Some(TunnelCommand::GetTunnelMetadata(result_tx)) => {
+                let _ = result_tx.send(self.tunnel_metadata.clone());
+                SameState(self.into())
+            }
+            #[cfg(target_os = "linux")]
+            Some(TunnelCommand::GetTrafficStats(result_tx)) => {
+                let _ = result_tx.send(shared_values.last_tunnel_traffic);
+                SameState(self.into())
+            }
+            #[cfg(target_os = "linux")]
+            Some(TunnelCommand::SetCaptivePortalHosts(hosts, tx)) => {
+                shared_values.captive_portal_hosts = hosts;
+                let _ = tx.send(());
+                SameState(self.into())
+            }
+            #[cfg(target_os = "linux")]
+            Some(TunnelCommand::UnlockCaptivePortal(duration, probe_url, result_tx)) => {
+                shared_values
+                    .captive_portal
+                    .unlock(duration, probe_url, result_tx);
+                SameState(self.into())
+            }
             #[cfg(target_os = "android")]
             Some(TunnelCommand::BypassSocket(fd, done_tx)) => {
                 shared_values.bypass_socket(fd, done_tx);
@@ -434,6 +577,8 @@ impl ConnectingState {
                 self.into_connected_state_bootstrap(metadata),
             )),
             Some((TunnelEvent::Down, _)) => SameState(self.into()),
+            #[cfg(target_os = "linux")]
+            Some((TunnelEvent::PeersUpdated { .. }, _)) => SameState(self.into()),
             None => {
                 // The channel was closed
                 log::debug!("The tunnel disconnected unexpectedly");
@@ -576,8 +721,7 @@ impl TunnelState for ConnectingState {
                         tunnel_parameters,
                         &shared_values.log_dir,
                         &shared_values.resource_dir,
-                        shared_values.tun_provider.clone(),
-                        &mut shared_values.route_manager,
+                        shared_values.tunnel_starter.clone(),
                         retry_attempt,
                     );
                     let params = connecting_state.tunnel_parameters.clone();
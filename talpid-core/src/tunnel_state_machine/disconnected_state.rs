@@ -4,7 +4,6 @@ use super::{
 };
 #[cfg(target_os = "macos")]
 use crate::dns;
-use crate::firewall::FirewallPolicy;
 use futures::StreamExt;
 #[cfg(target_os = "macos")]
 use std::net::Ipv4Addr;
@@ -21,12 +20,7 @@ impl DisconnectedState {
         should_reset_firewall: bool,
     ) {
         let result = if shared_values.block_when_disconnected {
-            let policy = FirewallPolicy::Blocked {
-                allow_lan: shared_values.allow_lan,
-                allowed_endpoint: shared_values.allowed_endpoint.clone(),
-                #[cfg(target_os = "macos")]
-                dns_redirect_port: shared_values.filtering_resolver.listening_port(),
-            };
+            let policy = shared_values.blocked_firewall_policy();
 
             let firewall_result = shared_values.firewall.apply_policy(policy).map_err(|e| {
                 e.display_chain_with_msg(
@@ -149,6 +143,17 @@ impl TunnelState for DisconnectedState {
                 }
                 SameState(self.into())
             }
+            #[cfg(unix)]
+            Some(TunnelCommand::SetLanNetworks(lan_networks)) => {
+                // Same situation as allow LAN above.
+                let changed = shared_values
+                    .set_lan_networks(lan_networks)
+                    .expect("Failed to set LAN networks");
+                if changed {
+                    Self::set_firewall_policy(shared_values, true);
+                }
+                SameState(self.into())
+            }
             Some(TunnelCommand::AllowEndpoint(endpoint, tx)) => {
                 if shared_values.allowed_endpoint != endpoint {
                     shared_values.allowed_endpoint = endpoint;
@@ -198,6 +203,104 @@ impl TunnelState for DisconnectedState {
                 Self::reset_dns(shared_values);
                 NewState(ErrorState::enter(shared_values, reason))
             }
+            #[cfg(target_os = "linux")]
+            Some(TunnelCommand::SetAllowRules(allow_rules)) => {
+                if shared_values.allow_rules != allow_rules {
+                    shared_values.allow_rules = allow_rules;
+                    Self::set_firewall_policy(shared_values, true);
+                }
+                SameState(self.into())
+            }
+            #[cfg(target_os = "linux")]
+            Some(TunnelCommand::SetSplitTunnelMode(split_tunnel_mode)) => {
+                if shared_values.split_tunnel_mode != split_tunnel_mode {
+                    shared_values.split_tunnel_mode = split_tunnel_mode;
+                    Self::set_firewall_policy(shared_values, true);
+                }
+                SameState(self.into())
+            }
+            #[cfg(target_os = "linux")]
+            Some(TunnelCommand::SetExclusionProfiles(exclusion_profiles)) => {
+                if shared_values.exclusion_profiles != exclusion_profiles {
+                    shared_values.exclusion_profiles = exclusion_profiles;
+                    Self::set_firewall_policy(shared_values, true);
+                }
+                SameState(self.into())
+            }
+            #[cfg(target_os = "linux")]
+            Some(TunnelCommand::SetExcludedDestinations(excluded_destinations)) => {
+                if shared_values.excluded_destinations != excluded_destinations {
+                    shared_values.excluded_destinations = excluded_destinations;
+                    Self::set_firewall_policy(shared_values, true);
+                }
+                SameState(self.into())
+            }
+            #[cfg(target_os = "linux")]
+            Some(TunnelCommand::SetDnsBlocklists(blocklists)) => {
+                shared_values.dns_filter.set_blocklists(blocklists);
+                SameState(self.into())
+            }
+            #[cfg(target_os = "linux")]
+            Some(TunnelCommand::SetEncryptedDns(servers)) => {
+                shared_values.dns_filter.set_encrypted_servers(servers);
+                SameState(self.into())
+            }
+            #[cfg(target_os = "linux")]
+            Some(TunnelCommand::SetSplitDns(rules)) => {
+                shared_values.dns_filter.set_split_dns(rules.clone());
+                shared_values.split_dns = rules;
+                SameState(self.into())
+            }
+            #[cfg(target_os = "linux")]
+            Some(TunnelCommand::VerifyFirewall) => match shared_values.restore_firewall_policy() {
+                Ok(()) => SameState(self.into()),
+                Err(error) => NewState(ErrorState::enter(
+                    shared_values,
+                    ErrorStateCause::SetFirewallPolicyError(error),
+                )),
+            },
+            Some(TunnelCommand::GetFirewallPolicy(query, result_tx)) => {
+                let _ = result_tx.send(shared_values.query_firewall_policy(query));
+                SameState(self.into())
+            }
+            #[cfg(target_os = "linux")]
+            Some(TunnelCommand::GetBlockedConnections(result_tx)) => {
+                let _ = result_tx.send(shared_values.firewall.blocked_connections());
+                SameState(self.into())
+            }
+            #[cfg(target_os = "linux")]
+            Some(TunnelCommand::GetDnsConfig(result_tx)) => {
+                let _ = result_tx.send(shared_values.dns_config());
+                SameState(self.into())
+            }
+            #[cfg(target_os = "linux")]
+            Some(TunnelCommand::GetTunnelMetadata(result_tx)) => {
+                let _ = result_tx.send(None);
+                SameState(self.into())
+            }
+            #[cfg(target_os = "linux")]
+            Some(TunnelCommand::GetTrafficStats(result_tx)) => {
+                let _ = result_tx.send(shared_values.last_tunnel_traffic);
+                SameState(self.into())
+            }
+            #[cfg(target_os = "linux")]
+            Some(TunnelCommand::SetCaptivePortalHosts(hosts, tx)) => {
+                if shared_values.captive_portal_hosts != hosts {
+                    shared_values.captive_portal_hosts = hosts;
+                    if shared_values.block_when_disconnected {
+                        Self::set_firewall_policy(shared_values, false);
+                    }
+                }
+                let _ = tx.send(());
+                SameState(self.into())
+            }
+            #[cfg(target_os = "linux")]
+            Some(TunnelCommand::UnlockCaptivePortal(duration, probe_url, result_tx)) => {
+                shared_values
+                    .captive_portal
+                    .unlock(duration, probe_url, result_tx);
+                SameState(self.into())
+            }
             #[cfg(target_os = "android")]
             Some(TunnelCommand::BypassSocket(fd, done_tx)) => {
                 shared_values.bypass_socket(fd, done_tx);
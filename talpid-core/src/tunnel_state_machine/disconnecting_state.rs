@@ -27,6 +27,11 @@ impl DisconnectingState {
                     let _ = shared_values.set_allow_lan(allow_lan);
                     AfterDisconnect::Nothing
                 }
+                #[cfg(unix)]
+                Some(TunnelCommand::SetLanNetworks(lan_networks)) => {
+                    let _ = shared_values.set_lan_networks(lan_networks);
+                    AfterDisconnect::Nothing
+                }
                 Some(TunnelCommand::AllowEndpoint(endpoint, tx)) => {
                     shared_values.allowed_endpoint = endpoint;
                     let _ = tx.send(());
@@ -47,6 +52,88 @@ impl DisconnectingState {
                 Some(TunnelCommand::Connect) => AfterDisconnect::Reconnect(0),
                 Some(TunnelCommand::Disconnect) | None => AfterDisconnect::Nothing,
                 Some(TunnelCommand::Block(reason)) => AfterDisconnect::Block(reason),
+                #[cfg(target_os = "linux")]
+                Some(TunnelCommand::SetAllowRules(allow_rules)) => {
+                    shared_values.allow_rules = allow_rules;
+                    AfterDisconnect::Nothing
+                }
+                #[cfg(target_os = "linux")]
+                Some(TunnelCommand::SetSplitTunnelMode(split_tunnel_mode)) => {
+                    shared_values.split_tunnel_mode = split_tunnel_mode;
+                    AfterDisconnect::Nothing
+                }
+                #[cfg(target_os = "linux")]
+                Some(TunnelCommand::SetExclusionProfiles(exclusion_profiles)) => {
+                    shared_values.exclusion_profiles = exclusion_profiles;
+                    AfterDisconnect::Nothing
+                }
+                #[cfg(target_os = "linux")]
+                Some(TunnelCommand::SetExcludedDestinations(excluded_destinations)) => {
+                    shared_values.excluded_destinations = excluded_destinations;
+                    AfterDisconnect::Nothing
+                }
+                #[cfg(target_os = "linux")]
+                Some(TunnelCommand::SetDnsBlocklists(blocklists)) => {
+                    shared_values.dns_filter.set_blocklists(blocklists);
+                    AfterDisconnect::Nothing
+                }
+                #[cfg(target_os = "linux")]
+                Some(TunnelCommand::SetEncryptedDns(servers)) => {
+                    shared_values.dns_filter.set_encrypted_servers(servers);
+                    AfterDisconnect::Nothing
+                }
+                #[cfg(target_os = "linux")]
+                Some(TunnelCommand::SetSplitDns(rules)) => {
+                    shared_values.dns_filter.set_split_dns(rules.clone());
+                    shared_values.split_dns = rules;
+                    AfterDisconnect::Nothing
+                }
+                #[cfg(target_os = "linux")]
+                Some(TunnelCommand::VerifyFirewall) => {
+                    match shared_values.restore_firewall_policy() {
+                        Ok(()) => AfterDisconnect::Nothing,
+                        Err(error) => {
+                            AfterDisconnect::Block(ErrorStateCause::SetFirewallPolicyError(error))
+                        }
+                    }
+                }
+                Some(TunnelCommand::GetFirewallPolicy(query, result_tx)) => {
+                    let _ = result_tx.send(shared_values.query_firewall_policy(query));
+                    AfterDisconnect::Nothing
+                }
+                #[cfg(target_os = "linux")]
+                Some(TunnelCommand::GetBlockedConnections(result_tx)) => {
+                    let _ = result_tx.send(shared_values.firewall.blocked_connections());
+                    AfterDisconnect::Nothing
+                }
+                #[cfg(target_os = "linux")]
+                Some(TunnelCommand::GetDnsConfig(result_tx)) => {
+                    let _ = result_tx.send(shared_values.dns_config());
+                    AfterDisconnect::Nothing
+                }
+                #[cfg(target_os = "linux")]
+                Some(TunnelCommand::GetTunnelMetadata(result_tx)) => {
+                    let _ = result_tx.send(None);
+                    AfterDisconnect::Nothing
+                }
+                #[cfg(target_os = "linux")]
+                Some(TunnelCommand::GetTrafficStats(result_tx)) => {
+                    let _ = result_tx.send(shared_values.last_tunnel_traffic);
+                    AfterDisconnect::Nothing
+                }
+                #[cfg(target_os = "linux")]
+                Some(TunnelCommand::SetCaptivePortalHosts(hosts, tx)) => {
+                    shared_values.captive_portal_hosts = hosts;
+                    let _ = tx.send(());
+                    AfterDisconnect::Nothing
+                }
+                #[cfg(target_os = "linux")]
+                Some(TunnelCommand::UnlockCaptivePortal(duration, probe_url, result_tx)) => {
+                    shared_values
+                        .captive_portal
+                        .unlock(duration, probe_url, result_tx);
+                    AfterDisconnect::Nothing
+                }
                 #[cfg(target_os = "android")]
                 Some(TunnelCommand::BypassSocket(fd, done_tx)) => {
                     shared_values.bypass_socket(fd, done_tx);
@@ -63,6 +150,11 @@ impl DisconnectingState {
                     let _ = shared_values.set_allow_lan(allow_lan);
                     AfterDisconnect::Block(reason)
                 }
+                #[cfg(unix)]
+                Some(TunnelCommand::SetLanNetworks(lan_networks)) => {
+                    let _ = shared_values.set_lan_networks(lan_networks);
+                    AfterDisconnect::Block(reason)
+                }
                 Some(TunnelCommand::AllowEndpoint(endpoint, tx)) => {
                     shared_values.allowed_endpoint = endpoint;
                     let _ = tx.send(());
@@ -87,6 +179,85 @@ impl DisconnectingState {
                 Some(TunnelCommand::Connect) => AfterDisconnect::Reconnect(0),
                 Some(TunnelCommand::Disconnect) => AfterDisconnect::Nothing,
                 Some(TunnelCommand::Block(new_reason)) => AfterDisconnect::Block(new_reason),
+                #[cfg(target_os = "linux")]
+                Some(TunnelCommand::SetAllowRules(allow_rules)) => {
+                    shared_values.allow_rules = allow_rules;
+                    AfterDisconnect::Block(reason)
+                }
+                #[cfg(target_os = "linux")]
+                Some(TunnelCommand::SetSplitTunnelMode(split_tunnel_mode)) => {
+                    shared_values.split_tunnel_mode = split_tunnel_mode;
+                    AfterDisconnect::Block(reason)
+                }
+                #[cfg(target_os = "linux")]
+                Some(TunnelCommand::SetExclusionProfiles(exclusion_profiles)) => {
+                    shared_values.exclusion_profiles = exclusion_profiles;
+                    AfterDisconnect::Block(reason)
+                }
+                #[cfg(target_os = "linux")]
+                Some(TunnelCommand::SetExcludedDestinations(excluded_destinations)) => {
+                    shared_values.excluded_destinations = excluded_destinations;
+                    AfterDisconnect::Block(reason)
+                }
+                #[cfg(target_os = "linux")]
+                Some(TunnelCommand::SetDnsBlocklists(blocklists)) => {
+                    shared_values.dns_filter.set_blocklists(blocklists);
+                    AfterDisconnect::Block(reason)
+                }
+                #[cfg(target_os = "linux")]
+                Some(TunnelCommand::SetEncryptedDns(servers)) => {
+                    shared_values.dns_filter.set_encrypted_servers(servers);
+                    AfterDisconnect::Block(reason)
+                }
+                #[cfg(target_os = "linux")]
+                Some(TunnelCommand::SetSplitDns(rules)) => {
+                    shared_values.dns_filter.set_split_dns(rules.clone());
+                    shared_values.split_dns = rules;
+                    AfterDisconnect::Block(reason)
+                }
+                #[cfg(target_os = "linux")]
+                Some(TunnelCommand::VerifyFirewall) => {
+                    // Failures are reported when the error state applies the blocking policy
+                    let _ = shared_values.restore_firewall_policy();
+                    AfterDisconnect::Block(reason)
+                }
+                Some(TunnelCommand::GetFirewallPolicy(query, result_tx)) => {
+                    let _ = result_tx.send(shared_values.query_firewall_policy(query));
+                    AfterDisconnect::Block(reason)
+                }
+                #[cfg(target_os = "linux")]
+                Some(TunnelCommand::GetBlockedConnections(result_tx)) => {
+                    let _ = result_tx.send(shared_values.firewall.blocked_connections());
+                    AfterDisconnect::Block(reason)
+                }
+                #[cfg(target_os = "linux")]
+                Some(TunnelCommand::GetDnsConfig(result_tx)) => {
+                    let _ = result_tx.send(shared_values.dns_config());
+                    AfterDisconnect::Block(reason)
+                }
+                #[cfg(target_os = "linux")]
+                Some(TunnelCommand::GetTunnelMetadata(result_tx)) => {
+                    let _ = result_tx.send(None);
+                    AfterDisconnect::Block(reason)
+                }
+                #[cfg(target_os = "linux")]
+                Some(TunnelCommand::GetTrafficStats(result_tx)) => {
+                    let _ = result_tx.send(shared_values.last_tunnel_traffic);
+                    AfterDisconnect::Block(reason)
+                }
+                #[cfg(target_os = "linux")]
+                Some(TunnelCommand::SetCaptivePortalHosts(hosts, tx)) => {
+                    shared_values.captive_portal_hosts = hosts;
+                    let _ = tx.send(());
+                    AfterDisconnect::Block(reason)
+                }
+                #[cfg(target_os = "linux")]
+                Some(TunnelCommand::UnlockCaptivePortal(duration, probe_url, result_tx)) => {
+                    shared_values
+                        .captive_portal
+                        .unlock(duration, probe_url, result_tx);
+                    AfterDisconnect::Block(reason)
+                }
                 #[cfg(target_os = "android")]
                 Some(TunnelCommand::BypassSocket(fd, done_tx)) => {
                     shared_values.bypass_socket(fd, done_tx);
@@ -104,6 +275,11 @@ impl DisconnectingState {
                     let _ = shared_values.set_allow_lan(allow_lan);
                     AfterDisconnect::Reconnect(retry_attempt)
                 }
+                #[cfg(unix)]
+                Some(TunnelCommand::SetLanNetworks(lan_networks)) => {
+                    let _ = shared_values.set_lan_networks(lan_networks);
+                    AfterDisconnect::Reconnect(retry_attempt)
+                }
                 Some(TunnelCommand::AllowEndpoint(endpoint, tx)) => {
                     shared_values.allowed_endpoint = endpoint;
                     let _ = tx.send(());
@@ -128,6 +304,88 @@ impl DisconnectingState {
                 Some(TunnelCommand::Connect) => AfterDisconnect::Reconnect(retry_attempt),
                 Some(TunnelCommand::Disconnect) | None => AfterDisconnect::Nothing,
                 Some(TunnelCommand::Block(reason)) => AfterDisconnect::Block(reason),
+                #[cfg(target_os = "linux")]
+                Some(TunnelCommand::SetAllowRules(allow_rules)) => {
+                    shared_values.allow_rules = allow_rules;
+                    AfterDisconnect::Reconnect(retry_attempt)
+                }
+                #[cfg(target_os = "linux")]
+                Some(TunnelCommand::SetSplitTunnelMode(split_tunnel_mode)) => {
+                    shared_values.split_tunnel_mode = split_tunnel_mode;
+                    AfterDisconnect::Reconnect(retry_attempt)
+                }
+                #[cfg(target_os = "linux")]
+                Some(TunnelCommand::SetExclusionProfiles(exclusion_profiles)) => {
+                    shared_values.exclusion_profiles = exclusion_profiles;
+                    AfterDisconnect::Reconnect(retry_attempt)
+                }
+                #[cfg(target_os = "linux")]
+                Some(TunnelCommand::SetExcludedDestinations(excluded_destinations)) => {
+                    shared_values.excluded_destinations = excluded_destinations;
+                    AfterDisconnect::Reconnect(retry_attempt)
+                }
+                #[cfg(target_os = "linux")]
+                Some(TunnelCommand::SetDnsBlocklists(blocklists)) => {
+                    shared_values.dns_filter.set_blocklists(blocklists);
+                    AfterDisconnect::Reconnect(retry_attempt)
+                }
+                #[cfg(target_os = "linux")]
+                Some(TunnelCommand::SetEncryptedDns(servers)) => {
+                    shared_values.dns_filter.set_encrypted_servers(servers);
+                    AfterDisconnect::Reconnect(retry_attempt)
+                }
+                #[cfg(target_os = "linux")]
+                Some(TunnelCommand::SetSplitDns(rules)) => {
+                    shared_values.dns_filter.set_split_dns(rules.clone());
+                    shared_values.split_dns = rules;
+                    AfterDisconnect::Reconnect(retry_attempt)
+                }
+                #[cfg(target_os = "linux")]
+                Some(TunnelCommand::VerifyFirewall) => {
+                    match shared_values.restore_firewall_policy() {
+                        Ok(()) => AfterDisconnect::Reconnect(retry_attempt),
+                        Err(error) => {
+                            AfterDisconnect::Block(ErrorStateCause::SetFirewallPolicyError(error))
+                        }
+                    }
+                }
+                Some(TunnelCommand::GetFirewallPolicy(query, result_tx)) => {
+                    let _ = result_tx.send(shared_values.query_firewall_policy(query));
+                    AfterDisconnect::Reconnect(retry_attempt)
+                }
+                #[cfg(target_os = "linux")]
+                Some(TunnelCommand::GetBlockedConnections(result_tx)) => {
+                    let _ = result_tx.send(shared_values.firewall.blocked_connections());
+                    AfterDisconnect::Reconnect(retry_attempt)
+                }
+                #[cfg(target_os = "linux")]
+                Some(TunnelCommand::GetDnsConfig(result_tx)) => {
+                    let _ = result_tx.send(shared_values.dns_config());
+                    AfterDisconnect::Reconnect(retry_attempt)
+                }
+                #[cfg(target_os = "linux")]
+                Some(TunnelCommand::GetTunnelMetadata(result_tx)) => {
+                    let _ = result_tx.send(None);
+                    AfterDisconnect::Reconnect(retry_attempt)
+                }
+                #[cfg(target_os = "linux")]
+                Some(TunnelCommand::GetTrafficStats(result_tx)) => {
+                    let _ = result_tx.send(shared_values.last_tunnel_traffic);
+                    AfterDisconnect::Reconnect(retry_attempt)
+                }
+                #[cfg(target_os = "linux")]
+                Some(TunnelCommand::SetCaptivePortalHosts(hosts, tx)) => {
+                    shared_values.captive_portal_hosts = hosts;
+                    let _ = tx.send(());
+                    AfterDisconnect::Reconnect(retry_attempt)
+                }
+                #[cfg(target_os = "linux")]
+                Some(TunnelCommand::UnlockCaptivePortal(duration, probe_url, result_tx)) => {
+                    shared_values
+                        .captive_portal
+                        .unlock(duration, probe_url, result_tx);
+                    AfterDisconnect::Reconnect(retry_attempt)
+                }
                 #[cfg(target_os = "android")]
                 Some(TunnelCommand::BypassSocket(fd, done_tx)) => {
                     shared_values.bypass_socket(fd, done_tx);
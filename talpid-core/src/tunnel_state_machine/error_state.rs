@@ -2,30 +2,40 @@ use super::{
     ConnectingState, DisconnectedState, EventConsequence, SharedTunnelStateValues, TunnelCommand,
     TunnelCommandReceiver, TunnelState, TunnelStateTransition, TunnelStateWrapper,
 };
-use crate::firewall::FirewallPolicy;
 use futures::StreamExt;
 #[cfg(target_os = "macos")]
 use std::net::Ipv4Addr;
+#[cfg(target_os = "linux")]
+use std::time::{Duration, Instant};
 use talpid_types::{
     tunnel::{self as talpid_tunnel, ErrorStateCause, FirewallPolicyError},
     ErrorExt,
 };
 
+/// How long to wait before retrying after failing to restore the firewall policy for the first
+/// time. The delay is doubled for every consecutive failure.
+#[cfg(target_os = "linux")]
+const MIN_RESTORE_RETRY_DELAY: Duration = Duration::from_secs(10);
+/// The longest delay between attempts to restore the firewall policy.
+#[cfg(target_os = "linux")]
+const MAX_RESTORE_RETRY_DELAY: Duration = Duration::from_secs(5 * 60);
+
 /// No tunnel is running and all network connections are blocked.
 pub struct ErrorState {
     block_reason: ErrorStateCause,
+    /// Number of consecutive failures to restore the firewall policy.
+    #[cfg(target_os = "linux")]
+    restore_failures: u32,
+    /// Restoring the firewall policy is not attempted again before this time.
+    #[cfg(target_os = "linux")]
+    next_restore_attempt: Option<Instant>,
 }
 
 impl ErrorState {
     fn set_firewall_policy(
         shared_values: &mut SharedTunnelStateValues,
     ) -> Result<(), FirewallPolicyError> {
-        let policy = FirewallPolicy::Blocked {
-            allow_lan: shared_values.allow_lan,
-            allowed_endpoint: shared_values.allowed_endpoint.clone(),
-            #[cfg(target_os = "macos")]
-            dns_redirect_port: shared_values.filtering_resolver.listening_port(),
-        };
+        let policy = shared_values.blocked_firewall_policy();
 
         #[cfg(target_os = "linux")]
         shared_values.disable_connectivity_check();
@@ -70,6 +80,48 @@ impl ErrorState {
         }
     }
 
+    /// Restores the firewall policy if another program has modified it. A failure is reported
+    /// once, after which restoring is retried with an increasing delay until it succeeds.
+    #[cfg(target_os = "linux")]
+    fn verify_firewall(mut self, shared_values: &mut SharedTunnelStateValues) -> EventConsequence {
+        if let Some(next_attempt) = self.next_restore_attempt {
+            if Instant::now() < next_attempt {
+                return EventConsequence::SameState(self.into());
+            }
+        }
+
+        match shared_values.restore_firewall_policy() {
+            Ok(()) if self.restore_failures == 0 => EventConsequence::SameState(self.into()),
+            Ok(()) => {
+                self.restore_failures = 0;
+                self.next_restore_attempt = None;
+                self.report(None)
+            }
+            Err(error) => {
+                let delay = MIN_RESTORE_RETRY_DELAY
+                    .saturating_mul(1 << self.restore_failures.min(16))
+                    .min(MAX_RESTORE_RETRY_DELAY);
+                self.restore_failures += 1;
+                self.next_restore_attempt = Some(Instant::now() + delay);
+                if self.restore_failures == 1 {
+                    self.report(Some(error))
+                } else {
+                    EventConsequence::SameState(self.into())
+                }
+            }
+        }
+    }
+
+    /// Reports whether traffic is blocked, without entering the state again.
+    #[cfg(target_os = "linux")]
+    fn report(self, block_failure: Option<FirewallPolicyError>) -> EventConsequence {
+        let transition = TunnelStateTransition::Error(talpid_tunnel::ErrorState::new(
+            self.block_reason.clone(),
+            block_failure,
+        ));
+        EventConsequence::NewState((TunnelStateWrapper::from(self), transition))
+    }
+
     fn reset_dns(shared_values: &mut SharedTunnelStateValues) {
         if let Err(error) = shared_values.dns_monitor.reset() {
             log::error!("{}", error.display_chain_with_msg("Unable to reset DNS"));
@@ -122,6 +174,10 @@ impl TunnelState for ErrorState {
         (
             TunnelStateWrapper::from(ErrorState {
                 block_reason: block_reason.clone(),
+                #[cfg(target_os = "linux")]
+                restore_failures: 0,
+                #[cfg(target_os = "linux")]
+                next_restore_attempt: None,
             }),
             TunnelStateTransition::Error(talpid_tunnel::ErrorState::new(
                 block_reason,
@@ -148,6 +204,19 @@ impl TunnelState for ErrorState {
                     SameState(self.into())
                 }
             }
+            #[cfg(unix)]
+            Some(TunnelCommand::SetLanNetworks(lan_networks)) => {
+                match shared_values.set_lan_networks(lan_networks) {
+                    Ok(true) => {
+                        let _ = Self::set_firewall_policy(shared_values);
+                        SameState(self.into())
+                    }
+                    Ok(false) => SameState(self.into()),
+                    Err(error_state_cause) => {
+                        NewState(Self::enter(shared_values, error_state_cause))
+                    }
+                }
+            }
             Some(TunnelCommand::AllowEndpoint(endpoint, tx)) => {
                 if shared_values.allowed_endpoint != endpoint {
                     shared_values.allowed_endpoint = endpoint;
@@ -199,6 +268,96 @@ impl TunnelState for ErrorState {
             Some(TunnelCommand::Block(reason)) => {
                 NewState(ErrorState::enter(shared_values, reason))
             }
+            #[cfg(target_os = "linux")]
+            Some(TunnelCommand::SetAllowRules(allow_rules)) => {
+                if shared_values.allow_rules != allow_rules {
+                    shared_values.allow_rules = allow_rules;
+                    let _ = Self::set_firewall_policy(shared_values);
+                }
+                SameState(self.into())
+            }
+            #[cfg(target_os = "linux")]
+            Some(TunnelCommand::SetSplitTunnelMode(split_tunnel_mode)) => {
+                if shared_values.split_tunnel_mode != split_tunnel_mode {
+                    shared_values.split_tunnel_mode = split_tunnel_mode;
+                    let _ = Self::set_firewall_policy(shared_values);
+                }
+                SameState(self.into())
+            }
+            #[cfg(target_os = "linux")]
+            Some(TunnelCommand::SetExclusionProfiles(exclusion_profiles)) => {
+                if shared_values.exclusion_profiles != exclusion_profiles {
+                    shared_values.exclusion_profiles = exclusion_profiles;
+                    let _ = Self::set_firewall_policy(shared_values);
+                }
+                SameState(self.into())
+            }
+            #[cfg(target_os = "linux")]
+            Some(TunnelCommand::SetExcludedDestinations(excluded_destinations)) => {
+                if shared_values.excluded_destinations != excluded_destinations {
+                    shared_values.excluded_destinations = excluded_destinations;
+                    let _ = Self::set_firewall_policy(shared_values);
+                }
+                SameState(self.into())
+            }
+            #[cfg(target_os = "linux")]
+            Some(TunnelCommand::SetDnsBlocklists(blocklists)) => {
+                shared_values.dns_filter.set_blocklists(blocklists);
+                SameState(self.into())
+            }
+            #[cfg(target_os = "linux")]
+            Some(TunnelCommand::SetEncryptedDns(servers)) => {
+                shared_values.dns_filter.set_encrypted_servers(servers);
+                SameState(self.into())
+            }
+            #[cfg(target_os = "linux")]
+            Some(TunnelCommand::SetSplitDns(rules)) => {
+                shared_values.dns_filter.set_split_dns(rules.clone());
+                shared_values.split_dns = rules;
+                SameState(self.into())
+            }
+            #[cfg(target_os = "linux")]
+            Some(TunnelCommand::VerifyFirewall) => self.verify_firewall(shared_values),
+            Some(TunnelCommand::GetFirewallPolicy(query, result_tx)) => {
+                let _ = result_tx.send(shared_values.query_firewall_policy(query));
+                SameState(self.into())
+            }
+            #[cfg(target_os = "linux")]
+            Some(TunnelCommand::GetBlockedConnections(result_tx)) => {
+                let _ = result_tx.send(shared_values.firewall.blocked_connections());
+                SameState(self.into())
+            }
+            #[cfg(target_os = "linux")]
+            Some(TunnelCommand::GetDnsConfig(result_tx)) => {
+                let _ = result_tx.send(shared_values.dns_config());
+                SameState(self.into())
+            }
+            #[cfg(target_os = "linux")]
+            Some(TunnelCommand::GetTunnelMetadata(result_tx)) => {
+                let _ = result_tx.send(None);
+                SameState(self.into())
+            }
+            #[cfg(target_os = "linux")]
+            Some(TunnelCommand::GetTrafficStats(result_tx)) => {
+                let _ = result_tx.send(shared_values.last_tunnel_traffic);
+                SameState(self.into())
+            }
+            #[cfg(target_os = "linux")]
+            Some(TunnelCommand::SetCaptivePortalHosts(hosts, tx)) => {
+                if shared_values.captive_portal_hosts != hosts {
+                    shared_values.captive_portal_hosts = hosts;
+                    let _ = Self::set_firewall_policy(shared_values);
+                }
+                let _ = tx.send(());
+                SameState(self.into())
+            }
+            #[cfg(target_os = "linux")]
+            Some(TunnelCommand::UnlockCaptivePortal(duration, probe_url, result_tx)) => {
+                shared_values
+                    .captive_portal
+                    .unlock(duration, probe_url, result_tx);
+                SameState(self.into())
+            }
             #[cfg(target_os = "android")]
             Some(TunnelCommand::BypassSocket(fd, done_tx)) => {
                 shared_values.bypass_socket(fd, done_tx);
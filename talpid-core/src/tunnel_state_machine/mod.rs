@@ -3,19 +3,32 @@ mod connecting_state;
 mod disconnected_state;
 mod disconnecting_state;
 mod error_state;
+mod platform;
 
+#[cfg(target_os = "android")]
+use self::platform::TunProviderT;
 use self::{
     connected_state::{ConnectedState, ConnectedStateBootstrap},
     connecting_state::ConnectingState,
     disconnected_state::DisconnectedState,
     disconnecting_state::{AfterDisconnect, DisconnectingState},
     error_state::ErrorState,
+    platform::{
+        DnsMonitorT, FirewallT, OfflineMonitorT, RouteManagerT, TunnelMonitorStarter, TunnelStarter,
+    },
 };
 #[cfg(windows)]
 use crate::split_tunnel;
+#[cfg(target_os = "linux")]
+use crate::{
+    captive_portal,
+    dns::{leak_test, DnsFilter, DnsWarning},
+    firewall::BlockedConnection,
+    tunnel::TunnelMetadata,
+};
 use crate::{
     dns::DnsMonitor,
-    firewall::{Firewall, FirewallArguments, InitialFirewallState},
+    firewall::{Firewall, FirewallArguments, FirewallPolicy, InitialFirewallState},
     mpsc::Sender,
     offline,
     routing::RouteManager,
@@ -23,6 +36,17 @@ use crate::{
 };
 #[cfg(windows)]
 use std::ffi::OsString;
+#[cfg(unix)]
+use talpid_types::net::lan::LanNetworks;
+#[cfg(target_os = "linux")]
+use talpid_types::{
+    cgroup::{ExclusionProfile, SplitTunnelMode},
+    net::{
+        dns::{DnsBlocklist, EncryptedDnsServer, SplitDnsRule},
+        trusted_network::NetworkIdentity,
+        ExcludedDestinations, FirewallAllowRule, TrafficStats,
+    },
+};
 
 use futures::{
     channel::{mpsc, oneshot},
@@ -41,7 +65,11 @@ use std::{
     time::Duration,
 };
 #[cfg(target_os = "android")]
-use talpid_types::{android::AndroidContext, ErrorExt};
+use talpid_types::android::AndroidContext;
+#[cfg(target_os = "linux")]
+use talpid_types::tunnel::FirewallPolicyError;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use talpid_types::ErrorExt;
 use talpid_types::{
     net::{AllowedEndpoint, TunnelParameters},
     tunnel::{ErrorStateCause, ParameterGenerationError, TunnelStateTransition},
@@ -49,6 +77,10 @@ use talpid_types::{
 
 const TUNNEL_STATE_MACHINE_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
 
+/// How often to check that the firewall rules have not been removed by another program.
+#[cfg(target_os = "linux")]
+const FIREWALL_WATCHDOG_INTERVAL: Duration = Duration::from_secs(5);
+
 /// Errors that can happen when setting up or using the state machine.
 #[derive(err_derive::Error, Debug)]
 pub enum Error {
@@ -91,6 +123,9 @@ pub enum Error {
 pub struct InitialTunnelState {
     /// Whether to allow LAN traffic when not in the (non-blocking) disconnected state.
     pub allow_lan: bool,
+    /// Networks that are reachable when LAN traffic is allowed.
+    #[cfg(unix)]
+    pub lan_networks: LanNetworks,
     /// Block traffic unless connected to the VPN.
     pub block_when_disconnected: bool,
     /// DNS servers to use. If `None`, the tunnel gateway is used.
@@ -100,6 +135,25 @@ pub struct InitialTunnelState {
     pub allowed_endpoint: AllowedEndpoint,
     /// Whether to reset any existing firewall rules when initializing the disconnected state.
     pub reset_firewall: bool,
+    /// User-defined exceptions to the firewall policy.
+    #[cfg(target_os = "linux")]
+    pub allow_rules: Vec<FirewallAllowRule>,
+    /// Whether processes in the split tunneling cgroup are excluded from the tunnel, or the only
+    /// ones included in it.
+    #[cfg(target_os = "linux")]
+    pub split_tunnel_mode: SplitTunnelMode,
+    /// Named groups of excluded processes, started using `mullvad-exclude --profile`.
+    #[cfg(target_os = "linux")]
+    pub exclusion_profiles: Vec<ExclusionProfile>,
+    /// Destinations that are routed outside the tunnel.
+    #[cfg(target_os = "linux")]
+    pub excluded_destinations: ExcludedDestinations,
+    /// Local resolver that applies DNS blocklists. Its blocklists should already be loaded.
+    #[cfg(target_os = "linux")]
+    pub dns_filter: DnsFilter,
+    /// Rules for resolving specific domains using other resolvers than the tunnel DNS servers.
+    #[cfg(target_os = "linux")]
+    pub split_dns: Vec<SplitDnsRule>,
     /// Programs to exclude from the tunnel using the split tunnel driver.
     #[cfg(windows)]
     pub exclude_paths: Vec<OsString>,
@@ -113,6 +167,8 @@ pub async fn spawn(
     resource_dir: PathBuf,
     state_change_listener: impl Sender<TunnelStateTransition> + Send + 'static,
     offline_state_listener: mpsc::UnboundedSender<bool>,
+    #[cfg(target_os = "linux")] dns_warning_listener: mpsc::UnboundedSender<DnsWarning>,
+    #[cfg(target_os = "linux")] network_identity_listener: mpsc::UnboundedSender<NetworkIdentity>,
     #[cfg(target_os = "windows")] volume_update_rx: mpsc::UnboundedReceiver<()>,
     #[cfg(target_os = "macos")] exclusion_gid: u32,
     #[cfg(target_os = "android")] android_context: AndroidContext,
@@ -126,6 +182,8 @@ pub async fn spawn(
         #[cfg(target_os = "android")]
         initial_settings.allow_lan,
         #[cfg(target_os = "android")]
+        initial_settings.lan_networks.clone(),
+        #[cfg(target_os = "android")]
         initial_settings.dns_servers.clone(),
     );
 
@@ -141,6 +199,10 @@ pub async fn spawn(
         log_dir,
         resource_dir,
         command_rx,
+        #[cfg(target_os = "linux")]
+        dns_warning_listener,
+        #[cfg(target_os = "linux")]
+        network_identity_listener,
         #[cfg(target_os = "windows")]
         volume_update_rx,
         #[cfg(target_os = "macos")]
@@ -164,10 +226,39 @@ pub async fn spawn(
 pub enum TunnelCommand {
     /// Enable or disable LAN access in the firewall.
     AllowLan(bool),
+    /// Set the networks that are reachable when LAN access is enabled.
+    #[cfg(unix)]
+    SetLanNetworks(LanNetworks),
     /// Endpoint that should never be blocked. `()` is sent to the
     /// channel after attempting to set the firewall policy, regardless
     /// of whether it succeeded.
     AllowEndpoint(AllowedEndpoint, oneshot::Sender<()>),
+    /// Set user-defined exceptions to the firewall policy.
+    #[cfg(target_os = "linux")]
+    SetAllowRules(Vec<FirewallAllowRule>),
+    /// Set whether processes in the split tunneling cgroup are excluded from or included in
+    /// the tunnel.
+    #[cfg(target_os = "linux")]
+    SetSplitTunnelMode(SplitTunnelMode),
+    /// Set the exclusion profiles. Their cgroups must exist before this is sent.
+    #[cfg(target_os = "linux")]
+    SetExclusionProfiles(Vec<ExclusionProfile>),
+    /// Set the destinations that are routed outside the tunnel. The networks should include the
+    /// addresses that the domains currently resolve to.
+    #[cfg(target_os = "linux")]
+    SetExcludedDestinations(ExcludedDestinations),
+    /// Load the given DNS blocklists. DNS is resolved through the local filtering resolver
+    /// while any blocklist is enabled.
+    #[cfg(target_os = "linux")]
+    SetDnsBlocklists(Vec<DnsBlocklist>),
+    /// Set the DNS-over-HTTPS and DNS-over-TLS servers to use. While any is set, DNS is resolved
+    /// through the local resolver, which forwards queries to them, and plain DNS is blocked.
+    #[cfg(target_os = "linux")]
+    SetEncryptedDns(Vec<EncryptedDnsServer>),
+    /// Set the rules for resolving specific domains using other resolvers, which are reached
+    /// outside the tunnel.
+    #[cfg(target_os = "linux")]
+    SetSplitDns(Vec<SplitDnsRule>),
     /// Set DNS servers to use.
     Dns(Option<Vec<IpAddr>>),
     /// Enable or disable the block_when_disconnected feature.
@@ -180,6 +271,38 @@ pub enum TunnelCommand {
     Disconnect,
     /// Disconnect any open tunnel and block all network access
     Block(ErrorStateCause),
+    /// Check that the rules enforcing the active firewall policy are still in place, and restore
+    /// them if they are not.
+    #[cfg(target_os = "linux")]
+    VerifyFirewall,
+    /// Return a firewall policy without changing the state of the firewall.
+    GetFirewallPolicy(FirewallPolicyQuery, oneshot::Sender<Option<FirewallPolicy>>),
+    /// Return the destinations that the firewall most recently blocked traffic to.
+    #[cfg(target_os = "linux")]
+    GetBlockedConnections(oneshot::Sender<Vec<BlockedConnection>>),
+    /// Return the DNS configuration that is applied, if any, for testing it for leaks.
+    #[cfg(target_os = "linux")]
+    GetDnsConfig(oneshot::Sender<Option<leak_test::DnsConfig>>),
+    /// Return the interface and addresses of the tunnel, if it is up.
+    #[cfg(target_os = "linux")]
+    GetTunnelMetadata(oneshot::Sender<Option<TunnelMetadata>>),
+    /// Return the number of bytes transferred through the tunnel while connected, or through the
+    /// most recent tunnel after disconnecting from it.
+    #[cfg(target_os = "linux")]
+    GetTrafficStats(oneshot::Sender<Option<TrafficStats>>),
+    /// Set the hosts that are reachable using DNS, HTTP and HTTPS while blocking, so that the user
+    /// can log in to a captive portal. `()` is sent to the channel after attempting to set the
+    /// firewall policy, regardless of whether it succeeded.
+    #[cfg(target_os = "linux")]
+    SetCaptivePortalHosts(Vec<IpAddr>, oneshot::Sender<()>),
+    /// Detect any captive portal, and allow traffic to it for the given duration. The probe URL
+    /// replaces the default one if given.
+    #[cfg(target_os = "linux")]
+    UnlockCaptivePortal(
+        Duration,
+        Option<String>,
+        oneshot::Sender<Result<captive_portal::UnlockStatus, captive_portal::Error>>,
+    ),
     /// Bypass a socket, allowing traffic to flow through outside the tunnel.
     #[cfg(target_os = "android")]
     BypassSocket(RawFd, oneshot::Sender<()>),
@@ -191,6 +314,17 @@ pub enum TunnelCommand {
     ),
 }
 
+/// Selects the firewall policy returned by [`TunnelCommand::GetFirewallPolicy`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FirewallPolicyQuery {
+    /// The policy currently enforced by the firewall, if any.
+    Active,
+    /// The policy enforced while connected. Only available while connected.
+    Connected,
+    /// The policy that would be enforced in the blocked state, given the current settings.
+    Blocked,
+}
+
 type TunnelCommandReceiver = stream::Fuse<mpsc::UnboundedReceiver<TunnelCommand>>;
 
 enum EventResult {
@@ -221,10 +355,15 @@ impl TunnelStateMachine {
         log_dir: Option<PathBuf>,
         resource_dir: PathBuf,
         commands_rx: mpsc::UnboundedReceiver<TunnelCommand>,
+        #[cfg(target_os = "linux")] dns_warning_listener: mpsc::UnboundedSender<DnsWarning>,
+        #[cfg(target_os = "linux")] network_identity_listener: mpsc::UnboundedSender<
+            NetworkIdentity,
+        >,
         #[cfg(target_os = "windows")] volume_update_rx: mpsc::UnboundedReceiver<()>,
         #[cfg(target_os = "macos")] exclusion_gid: u32,
         #[cfg(target_os = "android")] android_context: AndroidContext,
     ) -> Result<Self, Error> {
+        #[cfg(any(target_os = "linux", windows))]
         let runtime = tokio::runtime::Handle::current();
 
         #[cfg(target_os = "macos")]
@@ -248,6 +387,13 @@ impl TunnelStateMachine {
         let route_manager = RouteManager::new(HashSet::new())
             .await
             .map_err(Error::InitRouteManagerError)?;
+        let tun_provider = Arc::new(Mutex::new(tun_provider));
+        let tunnel_starter = TunnelMonitorStarter::new(
+            tun_provider.clone(),
+            route_manager
+                .handle()
+                .map_err(Error::InitRouteManagerError)?,
+        );
         let dns_monitor = DnsMonitor::new(
             #[cfg(target_os = "linux")]
             runtime.clone(),
@@ -255,11 +401,24 @@ impl TunnelStateMachine {
             route_manager
                 .handle()
                 .map_err(Error::InitRouteManagerError)?,
+            #[cfg(target_os = "linux")]
+            dns_warning_listener,
             #[cfg(target_os = "macos")]
             command_tx.clone(),
         )
         .map_err(Error::InitDnsMonitorError)?;
 
+        #[cfg(target_os = "linux")]
+        spawn_firewall_watchdog(command_tx.clone());
+        #[cfg(target_os = "linux")]
+        let captive_portal = captive_portal::Unlocker::new(
+            runtime.clone(),
+            route_manager
+                .handle()
+                .map_err(Error::InitRouteManagerError)?,
+            command_tx.clone(),
+        );
+
         let (offline_tx, mut offline_rx) = mpsc::unbounded();
         let initial_offline_state_tx = offline_state_tx.clone();
         tokio::spawn(async move {
@@ -272,7 +431,7 @@ impl TunnelStateMachine {
                 let _ = offline_state_tx.unbounded_send(offline);
             }
         });
-        let mut offline_monitor = offline::spawn_monitor(
+        let offline_monitor = offline::spawn_monitor(
             offline_tx,
             #[cfg(target_os = "linux")]
             route_manager
@@ -283,35 +442,111 @@ impl TunnelStateMachine {
         )
         .await
         .map_err(Error::OfflineMonitorError)?;
-        let is_offline = offline_monitor.is_offline().await;
-        let _ = initial_offline_state_tx.unbounded_send(is_offline);
+
+        #[cfg(target_os = "linux")]
+        offline::network_identity::spawn_monitor(
+            network_identity_listener,
+            route_manager
+                .handle()
+                .map_err(Error::InitRouteManagerError)?,
+        )
+        .await
+        .map_err(Error::OfflineMonitorError)?;
 
         #[cfg(windows)]
         split_tunnel
             .set_paths_sync(&settings.exclude_paths)
             .map_err(Error::InitSplitTunneling)?;
 
-        let mut shared_values = SharedTunnelStateValues {
+        let platform = Platform {
+            firewall: Box::new(firewall),
+            dns_monitor: Box::new(dns_monitor),
+            route_manager: Box::new(route_manager),
+            offline_monitor: Box::new(offline_monitor),
+            tunnel_starter: Arc::new(tunnel_starter),
+            #[cfg(target_os = "android")]
+            tun_provider,
+            #[cfg(target_os = "linux")]
+            captive_portal,
             #[cfg(windows)]
             split_tunnel,
+            #[cfg(target_os = "macos")]
+            filtering_resolver,
+        };
+
+        Self::with_platform(
+            settings,
+            platform,
+            initial_offline_state_tx,
+            tunnel_parameters_generator,
+            log_dir,
+            resource_dir,
+            commands_rx,
+            #[cfg(target_os = "macos")]
+            exclusion_gid,
+        )
+        .await
+    }
+
+    /// Creates a state machine that manages the given system components.
+    async fn with_platform(
+        settings: InitialTunnelState,
+        mut platform: Platform,
+        offline_state_tx: mpsc::UnboundedSender<bool>,
+        tunnel_parameters_generator: impl TunnelParametersGenerator,
+        log_dir: Option<PathBuf>,
+        resource_dir: PathBuf,
+        commands_rx: mpsc::UnboundedReceiver<TunnelCommand>,
+        #[cfg(target_os = "macos")] exclusion_gid: u32,
+    ) -> Result<Self, Error> {
+        let runtime = tokio::runtime::Handle::current();
+
+        let is_offline = platform.offline_monitor.is_offline().await;
+        let _ = offline_state_tx.unbounded_send(is_offline);
+
+        let mut shared_values = SharedTunnelStateValues {
+            #[cfg(windows)]
+            split_tunnel: platform.split_tunnel,
             runtime,
-            firewall,
-            dns_monitor,
-            route_manager,
-            _offline_monitor: offline_monitor,
+            firewall: platform.firewall,
+            dns_monitor: platform.dns_monitor,
+            route_manager: platform.route_manager,
+            _offline_monitor: platform.offline_monitor,
+            tunnel_starter: platform.tunnel_starter,
             allow_lan: settings.allow_lan,
+            #[cfg(unix)]
+            lan_networks: settings.lan_networks,
             block_when_disconnected: settings.block_when_disconnected,
             is_offline,
             dns_servers: settings.dns_servers,
             allowed_endpoint: settings.allowed_endpoint,
+            #[cfg(target_os = "linux")]
+            allow_rules: settings.allow_rules,
+            #[cfg(target_os = "linux")]
+            split_tunnel_mode: settings.split_tunnel_mode,
+            #[cfg(target_os = "linux")]
+            exclusion_profiles: settings.exclusion_profiles,
+            #[cfg(target_os = "linux")]
+            excluded_destinations: settings.excluded_destinations,
+            #[cfg(target_os = "linux")]
+            dns_filter: settings.dns_filter,
+            #[cfg(target_os = "linux")]
+            split_dns: settings.split_dns,
             tunnel_parameters_generator: Box::new(tunnel_parameters_generator),
-            tun_provider: Arc::new(Mutex::new(tun_provider)),
+            #[cfg(target_os = "android")]
+            tun_provider: platform.tun_provider,
             log_dir,
             resource_dir,
             #[cfg(target_os = "linux")]
             connectivity_check_was_enabled: None,
+            #[cfg(target_os = "linux")]
+            captive_portal_hosts: vec![],
+            #[cfg(target_os = "linux")]
+            captive_portal: platform.captive_portal,
+            #[cfg(target_os = "linux")]
+            last_tunnel_traffic: None,
             #[cfg(target_os = "macos")]
-            filtering_resolver,
+            filtering_resolver: platform.filtering_resolver,
             #[cfg(target_os = "macos")]
             _exclusion_gid: exclusion_gid,
         };
@@ -360,6 +595,42 @@ impl TunnelStateMachine {
     }
 }
 
+/// Periodically asks the state machine to verify the firewall rules, since other programs may
+/// flush or replace them at any time.
+#[cfg(target_os = "linux")]
+fn spawn_firewall_watchdog(command_tx: std::sync::Weak<mpsc::UnboundedSender<TunnelCommand>>) {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(FIREWALL_WATCHDOG_INTERVAL).await;
+            match command_tx.upgrade() {
+                Some(tx) => {
+                    if tx.unbounded_send(TunnelCommand::VerifyFirewall).is_err() {
+                        break;
+                    }
+                }
+                None => break,
+            }
+        }
+    });
+}
+
+/// System components that the state machine manages.
+struct Platform {
+    firewall: Box<dyn FirewallT>,
+    dns_monitor: Box<dyn DnsMonitorT>,
+    route_manager: Box<dyn RouteManagerT>,
+    offline_monitor: Box<dyn OfflineMonitorT>,
+    tunnel_starter: Arc<dyn TunnelStarter>,
+    #[cfg(target_os = "android")]
+    tun_provider: Arc<Mutex<dyn TunProviderT>>,
+    #[cfg(target_os = "linux")]
+    captive_portal: captive_portal::Unlocker,
+    #[cfg(windows)]
+    split_tunnel: split_tunnel::SplitTunnel,
+    #[cfg(target_os = "macos")]
+    filtering_resolver: crate::resolver::ResolverHandle,
+}
+
 /// Trait for any type that can provide a stream of `TunnelParameters` to the `TunnelStateMachine`.
 pub trait TunnelParametersGenerator: Send + 'static {
     /// Given the number of consecutive failed retry attempts, it should yield a `TunnelParameters`
@@ -379,12 +650,17 @@ struct SharedTunnelStateValues {
     #[cfg(windows)]
     split_tunnel: split_tunnel::SplitTunnel,
     runtime: tokio::runtime::Handle,
-    firewall: Firewall,
-    dns_monitor: DnsMonitor,
-    route_manager: RouteManager,
-    _offline_monitor: offline::MonitorHandle,
+    firewall: Box<dyn FirewallT>,
+    dns_monitor: Box<dyn DnsMonitorT>,
+    route_manager: Box<dyn RouteManagerT>,
+    _offline_monitor: Box<dyn OfflineMonitorT>,
+    /// Starts the tunnels.
+    tunnel_starter: Arc<dyn TunnelStarter>,
     /// Should LAN access be allowed outside the tunnel.
     allow_lan: bool,
+    /// Networks that are reachable outside the tunnel when LAN access is allowed.
+    #[cfg(unix)]
+    lan_networks: LanNetworks,
     /// Should network access be allowed when in the disconnected state.
     block_when_disconnected: bool,
     /// True when the computer is known to be offline.
@@ -393,10 +669,30 @@ struct SharedTunnelStateValues {
     dns_servers: Option<Vec<IpAddr>>,
     /// Endpoint that should not be blocked by the firewall.
     allowed_endpoint: AllowedEndpoint,
+    /// User-defined exceptions to the firewall policy.
+    #[cfg(target_os = "linux")]
+    allow_rules: Vec<FirewallAllowRule>,
+    /// Whether processes in the split tunneling cgroup are excluded from the tunnel, or the only
+    /// ones included in it.
+    #[cfg(target_os = "linux")]
+    split_tunnel_mode: SplitTunnelMode,
+    /// Named groups of excluded processes.
+    #[cfg(target_os = "linux")]
+    exclusion_profiles: Vec<ExclusionProfile>,
+    /// Destinations that are routed outside the tunnel.
+    #[cfg(target_os = "linux")]
+    excluded_destinations: ExcludedDestinations,
+    /// Local resolver that applies DNS blocklists.
+    #[cfg(target_os = "linux")]
+    dns_filter: DnsFilter,
+    /// Rules for resolving specific domains using other resolvers than the tunnel DNS servers.
+    #[cfg(target_os = "linux")]
+    split_dns: Vec<SplitDnsRule>,
     /// The generator of new `TunnelParameter`s
     tunnel_parameters_generator: Box<dyn TunnelParametersGenerator>,
     /// The provider of tunnel devices.
-    tun_provider: Arc<Mutex<TunProvider>>,
+    #[cfg(target_os = "android")]
+    tun_provider: Arc<Mutex<dyn TunProviderT>>,
     /// Directory to store tunnel log file.
     log_dir: Option<PathBuf>,
     /// Resource directory path.
@@ -405,6 +701,15 @@ struct SharedTunnelStateValues {
     /// NetworkManager's connecitivity check state.
     #[cfg(target_os = "linux")]
     connectivity_check_was_enabled: Option<bool>,
+    /// Hosts that are reachable while blocking, so that the user can log in to a captive portal.
+    #[cfg(target_os = "linux")]
+    captive_portal_hosts: Vec<IpAddr>,
+    /// Unlocks captive portals for a limited time.
+    #[cfg(target_os = "linux")]
+    captive_portal: captive_portal::Unlocker,
+    /// Traffic through the most recent tunnel, recorded when disconnecting from it.
+    #[cfg(target_os = "linux")]
+    last_tunnel_traffic: Option<TrafficStats>,
 
     /// Filtering resolver handle
     #[cfg(target_os = "macos")]
@@ -438,6 +743,35 @@ impl SharedTunnelStateValues {
         Ok(())
     }
 
+    #[cfg(unix)]
+    pub fn set_lan_networks(&mut self, lan_networks: LanNetworks) -> Result<bool, ErrorStateCause> {
+        if self.lan_networks != lan_networks {
+            self.lan_networks = lan_networks.clone();
+
+            #[cfg(target_os = "android")]
+            {
+                if let Err(error) = self
+                    .tun_provider
+                    .lock()
+                    .unwrap()
+                    .set_lan_networks(lan_networks)
+                {
+                    log::error!(
+                        "{}",
+                        error.display_chain_with_msg(
+                            "Failed to restart tunnel after changing LAN networks",
+                        )
+                    );
+                    return Err(ErrorStateCause::StartTunnelError);
+                }
+            }
+
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
     pub fn set_dns_servers(
         &mut self,
         dns_servers: Option<Vec<IpAddr>>,
@@ -495,6 +829,90 @@ impl SharedTunnelStateValues {
         }
     }
 
+    /// Re-applies the active firewall policy if another program has removed the rules enforcing
+    /// it.
+    #[cfg(target_os = "linux")]
+    pub fn restore_firewall_policy(&mut self) -> Result<(), FirewallPolicyError> {
+        match self.firewall.restore_policy() {
+            Ok(true) => {
+                log::info!("Restored the firewall policy");
+                Ok(())
+            }
+            Ok(false) => Ok(()),
+            Err(error) => {
+                log::error!(
+                    "{}",
+                    error.display_chain_with_msg("Failed to restore the firewall policy")
+                );
+                Err(FirewallPolicyError::Generic)
+            }
+        }
+    }
+
+    /// Returns the DNS configuration that is currently applied, if any.
+    #[cfg(target_os = "linux")]
+    pub fn dns_config(&self) -> Option<leak_test::DnsConfig> {
+        let (backend, interface, servers) = self.dns_monitor.current()?;
+        let upstream_servers = self
+            .dns_filter
+            .upstream()
+            .unwrap_or_else(|| servers.to_vec());
+        Some(leak_test::DnsConfig {
+            backend,
+            interface: interface.to_owned(),
+            system_servers: servers.to_vec(),
+            upstream_servers,
+            encrypted: self.dns_filter.is_encrypted(),
+        })
+    }
+
+    /// Returns the firewall policy selected by `query`, without applying it.
+    pub fn query_firewall_policy(&self, query: FirewallPolicyQuery) -> Option<FirewallPolicy> {
+        match query {
+            FirewallPolicyQuery::Active => self.firewall.active_policy().cloned(),
+            FirewallPolicyQuery::Connected => match self.firewall.active_policy() {
+                Some(policy @ FirewallPolicy::Connected { .. }) => Some(policy.clone()),
+                _ => None,
+            },
+            FirewallPolicyQuery::Blocked => Some(self.blocked_firewall_policy()),
+        }
+    }
+
+    /// Returns the firewall policy to enforce while blocking all traffic. It allows traffic to
+    /// captive portals while any are unlocked.
+    pub fn blocked_firewall_policy(&self) -> FirewallPolicy {
+        #[cfg(target_os = "linux")]
+        if !self.captive_portal_hosts.is_empty() {
+            return FirewallPolicy::CaptivePortal {
+                allow_lan: self.allow_lan,
+                lan_networks: self.lan_networks.clone(),
+                allowed_endpoint: self.allowed_endpoint.clone(),
+                allow_rules: self.allow_rules.clone(),
+                split_tunnel_mode: self.split_tunnel_mode,
+                excluded_destinations: self.excluded_destinations.clone(),
+                exclusion_profiles: self.exclusion_profiles.clone(),
+                portal_hosts: self.captive_portal_hosts.clone(),
+            };
+        }
+
+        FirewallPolicy::Blocked {
+            allow_lan: self.allow_lan,
+            #[cfg(unix)]
+            lan_networks: self.lan_networks.clone(),
+            allowed_endpoint: self.allowed_endpoint.clone(),
+            #[cfg(target_os = "linux")]
+            allow_rules: self.allow_rules.clone(),
+            #[cfg(target_os = "linux")]
+            split_tunnel_mode: self.split_tunnel_mode,
+            #[cfg(target_os = "linux")]
+            excluded_destinations: self.excluded_destinations.clone(),
+            #[cfg(target_os = "linux")]
+            exclusion_profiles: self.exclusion_profiles.clone(),
+            #[cfg(target_os = "macos")]
+            dns_redirect_port: self.filtering_resolver.listening_port(),
+        }
+    }
+
     #[cfg(target_os = "android")]
     pub fn bypass_socket(&mut self, fd: RawFd, tx: oneshot::Sender<()>) {
         if let Err(err) = self.tun_provider.lock().unwrap().bypass(fd) {
@@ -605,3 +1023,822 @@ impl JoinHandle {
         }
     }
 }
+
+#[cfg(all(test, target_os = "linux"))]
+mod test {
+    use super::{
+        platform::{PeerUpdater, Tunnel, TunnelEventSender},
+        *,
+    };
+    use crate::{
+        dns::DnsBackend,
+        firewall,
+        routing::{self, NetNode, RequiredRoute, RouteManagerHandle},
+        tunnel::{self, wireguard},
+    };
+    use futures::future;
+    use std::{collections::VecDeque, net::Ipv4Addr, path::Path, sync::Weak};
+    use talpid_types::{
+        net::{
+            openvpn, wireguard as wireguard_types, Endpoint, GenericTunnelOptions,
+            TransportProtocol,
+        },
+        tunnel::ActionAfterDisconnect,
+    };
+
+    const TRANSITION_TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Policy change made through [`FakeFirewall`]. `None` means that the policy was reset.
+    type PolicyLog = Arc<Mutex<Vec<Option<FirewallPolicy>>>>;
+
+    struct FakeFirewall {
+        policies: PolicyLog,
+        active_policy: Option<FirewallPolicy>,
+        fail_connecting: bool,
+        fail_restore: bool,
+    }
+
+    impl FirewallT for FakeFirewall {
+        fn apply_policy(&mut self, policy: FirewallPolicy) -> Result<(), firewall::Error> {
+            self.policies.lock().unwrap().push(Some(policy.clone()));
+            if self.fail_connecting && matches!(policy, FirewallPolicy::Connecting { .. }) {
+                return Err(firewall::Error::NetfilterTableNotSetError);
+            }
+            self.active_policy = Some(policy);
+            Ok(())
+        }
+
+        fn reset_policy(&mut self) -> Result<(), firewall::Error> {
+            self.policies.lock().unwrap().push(None);
+            self.active_policy = None;
+            Ok(())
+        }
+
+        fn restore_policy(&mut self) -> Result<bool, firewall::Error> {
+            if self.fail_restore {
+                return Err(firewall::Error::NetfilterTableNotSetError);
+            }
+            Ok(false)
+        }
+
+        fn active_policy(&self) -> Option<&FirewallPolicy> {
+            self.active_policy.as_ref()
+        }
+
+        fn blocked_connections(&self) -> Vec<BlockedConnection> {
+            vec![]
+        }
+    }
+
+    struct FakeDnsMonitor {
+        fail: bool,
+    }
+
+    impl DnsMonitorT for FakeDnsMonitor {
+        fn set(&mut self, _interface: &str, _servers: &[IpAddr]) -> Result<(), crate::dns::Error> {
+            if self.fail {
+                return Err(crate::dns::Error::NoDnsMonitor);
+            }
+            Ok(())
+        }
+
+        fn reset(&mut self) -> Result<(), crate::dns::Error> {
+            Ok(())
+        }
+
+        fn set_split_dns(&mut self, _rules: Vec<SplitDnsRule>) {}
+
+        fn supports_split_dns(&self) -> bool {
+            false
+        }
+
+        fn current(&self) -> Option<(DnsBackend, &str, &[IpAddr])> {
+            None
+        }
+    }
+
+    /// Routes currently added through [`FakeRouteManager`].
+    type RouteLog = Arc<Mutex<HashSet<RequiredRoute>>>;
+
+    struct FakeRouteManager {
+        routes: RouteLog,
+    }
+
+    impl RouteManagerT for FakeRouteManager {
+        fn clear_routes(&mut self) -> Result<(), routing::Error> {
+            self.routes.lock().unwrap().clear();
+            Ok(())
+        }
+
+        fn clear_routing_rules(&mut self) -> platform::RouteFuture<'_> {
+            Box::pin(future::ready(Ok(())))
+        }
+
+        fn add_routes(&mut self, routes: HashSet<RequiredRoute>) -> platform::RouteFuture<'_> {
+            self.routes.lock().unwrap().extend(routes);
+            Box::pin(future::ready(Ok(())))
+        }
+
+        fn remove_routes(&mut self, routes: HashSet<RequiredRoute>) -> platform::RouteFuture<'_> {
+            let mut current_routes = self.routes.lock().unwrap();
+            for route in routes {
+                current_routes.remove(&route);
+            }
+            Box::pin(future::ready(Ok(())))
+        }
+    }
+
+    struct FakeOfflineMonitor {
+        is_offline: bool,
+    }
+
+    impl OfflineMonitorT for FakeOfflineMonitor {
+        fn is_offline(&mut self) -> Pin<Box<dyn Future<Output = bool> + '_>> {
+            Box::pin(future::ready(self.is_offline))
+        }
+    }
+
+    /// What [`FakeTunnelStarter`] does when it is asked to start a tunnel.
+    #[derive(Clone, Copy)]
+    enum TunnelBehavior {
+        /// Brings the tunnel up, and keeps it up until it is closed.
+        Up,
+        /// Fails with an error that blocks the tunnel.
+        FailIpv6,
+        /// Fails with an error that the tunnel is retried after.
+        FailRecoverable,
+    }
+
+    /// How a fake tunnel responds to being switched to new peers.
+    #[derive(Clone, Copy)]
+    enum PeerUpdateBehavior {
+        /// Switches to the new peers, which complete a handshake.
+        Handshake,
+        /// Switches to the new peers, which never respond.
+        FailHandshake,
+        /// Switches to the new peers, and never reports whether they respond.
+        Pending,
+        /// Fails to switch to the new peers.
+        Fail,
+    }
+
+    impl Default for PeerUpdateBehavior {
+        fn default() -> Self {
+            PeerUpdateBehavior::Handshake
+        }
+    }
+
+    struct FakeTunnelStarter {
+        behaviors: Mutex<VecDeque<TunnelBehavior>>,
+        peer_update: PeerUpdateBehavior,
+    }
+
+    impl TunnelStarter for FakeTunnelStarter {
+        fn start(
+            &self,
+            _runtime: tokio::runtime::Handle,
+            _parameters: &TunnelParameters,
+            _log_dir: &Option<PathBuf>,
+            _resource_dir: &Path,
+            event_tx: TunnelEventSender,
+            _retry_attempt: u32,
+            tunnel_close_rx: oneshot::Receiver<()>,
+        ) -> Result<Box<dyn Tunnel>, tunnel::Error> {
+            let behavior = self
+                .behaviors
+                .lock()
+                .unwrap()
+                .pop_front()
+                .unwrap_or(TunnelBehavior::Up);
+            match behavior {
+                TunnelBehavior::Up => Ok(Box::new(FakeTunnel {
+                    event_tx,
+                    close_rx: tunnel_close_rx,
+                    peer_update: self.peer_update,
+                })),
+                TunnelBehavior::FailIpv6 => Err(tunnel::Error::EnableIpv6Error),
+                TunnelBehavior::FailRecoverable => Err(
+                    tunnel::Error::WireguardTunnelMonitoringError(wireguard::Error::TunnelError(
+                        wireguard::TunnelError::RecoverableStartWireguardError,
+                    )),
+                ),
+            }
+        }
+    }
+
+    struct FakeTunnel {
+        event_tx: TunnelEventSender,
+        close_rx: oneshot::Receiver<()>,
+        peer_update: PeerUpdateBehavior,
+    }
+
+    impl Tunnel for FakeTunnel {
+        /// Brings the tunnel up, which happens after the state machine has taken its peer
+        /// updater, and waits until the tunnel is closed.
+        fn wait(self: Box<Self>) -> Result<(), tunnel::Error> {
+            let FakeTunnel {
+                event_tx, close_rx, ..
+            } = *self;
+            for event in [
+                TunnelEvent::InterfaceUp(tunnel_metadata()),
+                TunnelEvent::Up(tunnel_metadata()),
+            ] {
+                let (done_tx, _done_rx) = oneshot::channel();
+                let _ = event_tx.unbounded_send((event, done_tx));
+            }
+            let _ = futures::executor::block_on(close_rx);
+            // Dropping the sender before this would be seen as the tunnel going down
+            drop(event_tx);
+            Ok(())
+        }
+
+        fn peer_updater(&self) -> Option<Box<dyn PeerUpdater>> {
+            Some(Box::new(FakePeerUpdater {
+                event_tx: self.event_tx.clone(),
+                behavior: self.peer_update,
+            }))
+        }
+    }
+
+    struct FakePeerUpdater {
+        event_tx: TunnelEventSender,
+        behavior: PeerUpdateBehavior,
+    }
+
+    impl PeerUpdater for FakePeerUpdater {
+        fn update_peers(
+            &self,
+            _parameters: &wireguard_types::TunnelParameters,
+        ) -> Result<(), tunnel::Error> {
+            let handshake_completed = match self.behavior {
+                PeerUpdateBehavior::Handshake => true,
+                PeerUpdateBehavior::FailHandshake => false,
+                PeerUpdateBehavior::Pending => return Ok(()),
+                PeerUpdateBehavior::Fail => {
+                    return Err(tunnel::Error::WireguardTunnelMonitoringError(
+                        wireguard::Error::IncompatibleConfigError,
+                    ))
+                }
+            };
+            let (done_tx, _done_rx) = oneshot::channel();
+            let _ = self.event_tx.unbounded_send((
+                TunnelEvent::PeersUpdated {
+                    handshake_completed,
+                },
+                done_tx,
+            ));
+            Ok(())
+        }
+    }
+
+    /// Generates OpenVPN parameters, or WireGuard parameters for a new relay on each call.
+    struct FakeParametersGenerator {
+        retry_attempts: Arc<Mutex<Vec<u32>>>,
+        wireguard: bool,
+    }
+
+    impl TunnelParametersGenerator for FakeParametersGenerator {
+        fn generate(
+            &mut self,
+            retry_attempt: u32,
+        ) -> Pin<Box<dyn Future<Output = Result<TunnelParameters, ParameterGenerationError>>>>
+        {
+            let mut retry_attempts = self.retry_attempts.lock().unwrap();
+            if self.wireguard {
+                let relay = wireguard_relay_endpoint(retry_attempts.len());
+                retry_attempts.push(retry_attempt);
+                return Box::pin(future::ready(Ok(TunnelParameters::Wireguard(
+                    wireguard_types::TunnelParameters {
+                        connection: wireguard_types::ConnectionConfig {
+                            tunnel: wireguard_types::TunnelConfig {
+                                private_key: wireguard_types::PrivateKey::from([1; 32]),
+                                addresses: tunnel_metadata().ips,
+                            },
+                            peer: wireguard_types::PeerConfig {
+                                public_key: wireguard_types::PrivateKey::new_from_random()
+                                    .public_key(),
+                                allowed_ips: vec!["0.0.0.0/0".parse().unwrap()],
+                                endpoint: relay.address,
+                            },
+                            exit_peer: None,
+                            ipv4_gateway: tunnel_metadata().ipv4_gateway,
+                            ipv6_gateway: None,
+                        },
+                        options: wireguard_types::TunnelOptions::default(),
+                        generic_options: GenericTunnelOptions { enable_ipv6: false },
+                        obfuscation: None,
+                    },
+                ))));
+            }
+            retry_attempts.push(retry_attempt);
+            Box::pin(future::ready(Ok(TunnelParameters::OpenVpn(
+                openvpn::TunnelParameters {
+                    config: openvpn::ConnectionConfig::new(
+                        relay_endpoint(),
+                        "user".to_owned(),
+                        "password".to_owned(),
+                    ),
+                    options: openvpn::TunnelOptions::default(),
+                    generic_options: GenericTunnelOptions { enable_ipv6: false },
+                    proxy: None,
+                },
+            ))))
+        }
+    }
+
+    fn relay_endpoint() -> Endpoint {
+        Endpoint::new(Ipv4Addr::new(192, 0, 2, 1), 1194, TransportProtocol::Udp)
+    }
+
+    fn wireguard_relay_endpoint(index: usize) -> Endpoint {
+        Endpoint::new(
+            Ipv4Addr::new(192, 0, 2, 10 + index as u8),
+            51820,
+            TransportProtocol::Udp,
+        )
+    }
+
+    fn tunnel_metadata() -> TunnelMetadata {
+        TunnelMetadata {
+            interface: "tun-test".to_owned(),
+            ips: vec![Ipv4Addr::new(10, 64, 0, 2).into()],
+            ipv4_gateway: Ipv4Addr::new(10, 64, 0, 1),
+            ipv6_gateway: None,
+        }
+    }
+
+    #[derive(Default)]
+    struct FakePlatformConfig {
+        block_when_disconnected: bool,
+        is_offline: bool,
+        fail_connecting_policy: bool,
+        fail_restore_policy: bool,
+        fail_dns: bool,
+        tunnels: Vec<TunnelBehavior>,
+        wireguard: bool,
+        peer_update: PeerUpdateBehavior,
+    }
+
+    /// State machine running on fake system components.
+    struct TestMachine {
+        command_tx: Option<Arc<mpsc::UnboundedSender<TunnelCommand>>>,
+        transitions: mpsc::UnboundedReceiver<TunnelStateTransition>,
+        policies: PolicyLog,
+        routes: RouteLog,
+        retry_attempts: Arc<Mutex<Vec<u32>>>,
+        runtime: tokio::runtime::Runtime,
+    }
+
+    impl TestMachine {
+        fn start(config: FakePlatformConfig) -> Self {
+            let runtime = tokio::runtime::Runtime::new().expect("failed to create runtime");
+            let policies = PolicyLog::default();
+            let routes = RouteLog::default();
+            let retry_attempts = Arc::new(Mutex::new(vec![]));
+
+            let (command_tx, command_rx) = mpsc::unbounded();
+            let command_tx = Arc::new(command_tx);
+
+            let platform = Platform {
+                firewall: Box::new(FakeFirewall {
+                    policies: policies.clone(),
+                    active_policy: None,
+                    fail_connecting: config.fail_connecting_policy,
+                    fail_restore: config.fail_restore_policy,
+                }),
+                dns_monitor: Box::new(FakeDnsMonitor {
+                    fail: config.fail_dns,
+                }),
+                route_manager: Box::new(FakeRouteManager {
+                    routes: routes.clone(),
+                }),
+                offline_monitor: Box::new(FakeOfflineMonitor {
+                    is_offline: config.is_offline,
+                }),
+                tunnel_starter: Arc::new(FakeTunnelStarter {
+                    behaviors: Mutex::new(config.tunnels.into()),
+                    peer_update: config.peer_update,
+                }),
+                captive_portal: captive_portal::Unlocker::new(
+                    runtime.handle().clone(),
+                    RouteManagerHandle::disconnected(),
+                    Weak::new(),
+                ),
+            };
+            let settings = InitialTunnelState {
+                allow_lan: false,
+                lan_networks: LanNetworks::default(),
+                block_when_disconnected: config.block_when_disconnected,
+                dns_servers: None,
+                allowed_endpoint: AllowedEndpoint {
+                    endpoint: Endpoint::new(
+                        Ipv4Addr::new(192, 0, 2, 2),
+                        443,
+                        TransportProtocol::Tcp,
+                    ),
+                },
+                reset_firewall: true,
+                allow_rules: vec![],
+                split_tunnel_mode: SplitTunnelMode::default(),
+                exclusion_profiles: vec![],
+                excluded_destinations: ExcludedDestinations::default(),
+                dns_filter: DnsFilter::new(),
+                split_dns: vec![],
+            };
+            let (offline_state_tx, _offline_state_rx) = mpsc::unbounded();
+
+            let state_machine = runtime
+                .block_on(TunnelStateMachine::with_platform(
+                    settings,
+                    platform,
+                    offline_state_tx,
+                    FakeParametersGenerator {
+                        retry_attempts: retry_attempts.clone(),
+                        wireguard: config.wireguard,
+                    },
+                    None,
+                    PathBuf::new(),
+                    command_rx,
+                ))
+                .expect("failed to create state machine");
+
+            let (transition_tx, transitions) = mpsc::unbounded();
+            runtime.spawn_blocking(move || state_machine.run(transition_tx));
+
+            TestMachine {
+                command_tx: Some(command_tx),
+                transitions,
+                policies,
+                routes,
+                retry_attempts,
+                runtime,
+            }
+        }
+
+        fn send(&self, command: TunnelCommand) {
+            self.command_tx
+                .as_ref()
+                .unwrap()
+                .unbounded_send(command)
+                .expect("state machine stopped");
+        }
+
+        /// Returns the next transition that satisfies `predicate`, skipping any before it.
+        fn wait_for(
+            &mut self,
+            predicate: impl Fn(&TunnelStateTransition) -> bool,
+        ) -> TunnelStateTransition {
+            let transitions = &mut self.transitions;
+            self.runtime.block_on(async {
+                loop {
+                    let transition = tokio::time::timeout(TRANSITION_TIMEOUT, transitions.next())
+                        .await
+                        .expect("timed out waiting for state transition")
+                        .expect("state machine stopped");
+                    if predicate(&transition) {
+                        return transition;
+                    }
+                }
+            })
+        }
+
+        fn wait_for_error(&mut self) -> ErrorStateCause {
+            match self.wait_for(|transition| matches!(transition, TunnelStateTransition::Error(_)))
+            {
+                TunnelStateTransition::Error(error_state) => error_state.cause().clone(),
+                _ => unreachable!(),
+            }
+        }
+
+        fn policies(&self) -> Vec<Option<FirewallPolicy>> {
+            self.policies.lock().unwrap().clone()
+        }
+
+        fn last_policy(&self) -> Option<FirewallPolicy> {
+            self.policies().pop().expect("no policy was applied")
+        }
+
+        /// Returns the added routes, once all commands sent before have been handled.
+        fn routes(&self) -> HashSet<RequiredRoute> {
+            let (result_tx, result_rx) = oneshot::channel();
+            self.send(TunnelCommand::GetFirewallPolicy(
+                FirewallPolicyQuery::Active,
+                result_tx,
+            ));
+            self.runtime
+                .block_on(tokio::time::timeout(TRANSITION_TIMEOUT, result_rx))
+                .expect("timed out waiting for the state machine")
+                .expect("state machine stopped");
+            self.routes.lock().unwrap().clone()
+        }
+    }
+
+    impl Drop for TestMachine {
+        fn drop(&mut self) {
+            // The state machine stops once the command channel is closed
+            self.command_tx.take();
+            let transitions = &mut self.transitions;
+            let _ = self
+                .runtime
+                .block_on(tokio::time::timeout(TRANSITION_TIMEOUT, async move {
+                    while transitions.next().await.is_some() {}
+                }));
+        }
+    }
+
+    fn is_connected(transition: &TunnelStateTransition) -> bool {
+        matches!(transition, TunnelStateTransition::Connected(_))
+    }
+
+    fn is_disconnected(transition: &TunnelStateTransition) -> bool {
+        matches!(transition, TunnelStateTransition::Disconnected)
+    }
+
+    fn is_blocked(policy: &Option<FirewallPolicy>) -> bool {
+        matches!(policy, Some(FirewallPolicy::Blocked { .. }))
+    }
+
+    #[test]
+    fn test_connect_and_disconnect() {
+        let mut machine = TestMachine::start(FakePlatformConfig::default());
+
+        machine.send(TunnelCommand::Connect);
+        machine.wait_for(is_connected);
+        machine.send(TunnelCommand::Disconnect);
+        machine.wait_for(is_disconnected);
+
+        let policies = machine.policies();
+        assert_eq!(policies.len(), 5, "unexpected policies: {:?}", policies);
+        assert!(policies[0].is_none());
+        assert!(matches!(
+            policies[1],
+            Some(FirewallPolicy::Connecting { tunnel: None, .. })
+        ));
+        assert!(matches!(
+            &policies[2],
+            Some(FirewallPolicy::Connecting { tunnel: Some(tunnel), .. })
+                if *tunnel == tunnel_metadata()
+        ));
+        assert!(matches!(
+            &policies[3],
+            Some(FirewallPolicy::Connected { tunnel, peer_endpoint, .. })
+                if *tunnel == tunnel_metadata() && *peer_endpoint == relay_endpoint()
+        ));
+        assert!(policies[4].is_none());
+        assert_eq!(*machine.retry_attempts.lock().unwrap(), vec![0]);
+    }
+
+    #[test]
+    fn test_block_when_disconnected() {
+        let mut machine = TestMachine::start(FakePlatformConfig {
+            block_when_disconnected: true,
+            ..Default::default()
+        });
+        assert!(is_blocked(&machine.last_policy()));
+
+        machine.send(TunnelCommand::Connect);
+        machine.wait_for(is_connected);
+        machine.send(TunnelCommand::Disconnect);
+        machine.wait_for(is_disconnected);
+        assert!(is_blocked(&machine.last_policy()));
+
+        machine.send(TunnelCommand::BlockWhenDisconnected(false));
+        machine.send(TunnelCommand::Connect);
+        machine.wait_for(is_connected);
+        machine.send(TunnelCommand::Disconnect);
+        machine.wait_for(is_disconnected);
+        assert!(machine.last_policy().is_none());
+    }
+
+    #[test]
+    fn test_firewall_failure_blocks() {
+        let mut machine = TestMachine::start(FakePlatformConfig {
+            fail_connecting_policy: true,
+            ..Default::default()
+        });
+
+        machine.send(TunnelCommand::Connect);
+        assert_eq!(
+            machine.wait_for_error(),
+            ErrorStateCause::SetFirewallPolicyError(FirewallPolicyError::Generic)
+        );
+        assert!(is_blocked(&machine.last_policy()));
+    }
+
+    #[test]
+    fn test_lan_routes() {
+        let mut machine = TestMachine::start(FakePlatformConfig::default());
+
+        machine.send(TunnelCommand::Connect);
+        machine.wait_for(is_connected);
+        assert!(machine.routes().is_empty());
+
+        machine.send(TunnelCommand::AllowLan(true));
+        let default_routes = LanNetworks::default()
+            .networks
+            .into_iter()
+            .map(|network| RequiredRoute::new(network, NetNode::DefaultNode))
+            .collect();
+        assert_eq!(machine.routes(), default_routes);
+
+        let cgnat_network = "100.64.0.0/10".parse().unwrap();
+        machine.send(TunnelCommand::SetLanNetworks(LanNetworks {
+            networks: vec![cgnat_network],
+            ..LanNetworks::default()
+        }));
+        assert_eq!(
+            machine.routes(),
+            [RequiredRoute::new(cgnat_network, NetNode::DefaultNode)]
+                .into_iter()
+                .collect()
+        );
+
+        machine.send(TunnelCommand::AllowLan(false));
+        assert!(machine.routes().is_empty());
+    }
+
+    #[test]
+    fn test_firewall_restore_failure_blocks() {
+        let mut machine = TestMachine::start(FakePlatformConfig {
+            fail_restore_policy: true,
+            ..Default::default()
+        });
+
+        machine.send(TunnelCommand::VerifyFirewall);
+        assert_eq!(
+            machine.wait_for_error(),
+            ErrorStateCause::SetFirewallPolicyError(FirewallPolicyError::Generic)
+        );
+        assert!(is_blocked(&machine.last_policy()));
+
+        // Repeated failures in the error state are reported once and then retried with a delay
+        for _ in 0..3 {
+            machine.send(TunnelCommand::VerifyFirewall);
+        }
+        machine.send(TunnelCommand::Disconnect);
+        let mut errors = 0;
+        loop {
+            match machine.wait_for(|_| true) {
+                TunnelStateTransition::Error(_) => errors += 1,
+                TunnelStateTransition::Disconnected => break,
+                transition => panic!("unexpected transition: {:?}", transition),
+            }
+        }
+        assert_eq!(errors, 1);
+    }
+
+    #[test]
+    fn test_offline() {
+        let mut machine = TestMachine::start(FakePlatformConfig {
+            is_offline: true,
+            ..Default::default()
+        });
+
+        machine.send(TunnelCommand::Connect);
+        assert_eq!(machine.wait_for_error(), ErrorStateCause::IsOffline);
+        assert!(is_blocked(&machine.last_policy()));
+        assert!(machine.retry_attempts.lock().unwrap().is_empty());
+
+        machine.send(TunnelCommand::IsOffline(false));
+        machine.wait_for(|transition| matches!(transition, TunnelStateTransition::Connecting(_)));
+        machine.wait_for(is_connected);
+
+        machine.send(TunnelCommand::IsOffline(true));
+        machine.wait_for(|transition| {
+            matches!(
+                transition,
+                TunnelStateTransition::Disconnecting(ActionAfterDisconnect::Block)
+            )
+        });
+        assert_eq!(machine.wait_for_error(), ErrorStateCause::IsOffline);
+        assert!(is_blocked(&machine.last_policy()));
+    }
+
+    #[test]
+    fn test_start_failure_blocks() {
+        let mut machine = TestMachine::start(FakePlatformConfig {
+            tunnels: vec![TunnelBehavior::FailIpv6],
+            ..Default::default()
+        });
+
+        machine.send(TunnelCommand::Connect);
+        assert_eq!(machine.wait_for_error(), ErrorStateCause::Ipv6Unavailable);
+        assert!(is_blocked(&machine.last_policy()));
+        assert_eq!(*machine.retry_attempts.lock().unwrap(), vec![0]);
+    }
+
+    #[test]
+    fn test_retry_after_recoverable_failure() {
+        let mut machine = TestMachine::start(FakePlatformConfig {
+            tunnels: vec![TunnelBehavior::FailRecoverable],
+            ..Default::default()
+        });
+
+        machine.send(TunnelCommand::Connect);
+        machine.wait_for(is_connected);
+        assert_eq!(*machine.retry_attempts.lock().unwrap(), vec![0, 1]);
+        assert!(matches!(
+            machine.last_policy(),
+            Some(FirewallPolicy::Connected { .. })
+        ));
+    }
+
+    #[test]
+    fn test_dns_failure_blocks() {
+        let mut machine = TestMachine::start(FakePlatformConfig {
+            fail_dns: true,
+            ..Default::default()
+        });
+
+        machine.send(TunnelCommand::Connect);
+        assert_eq!(machine.wait_for_error(), ErrorStateCause::SetDnsError);
+        assert!(is_blocked(&machine.last_policy()));
+    }
+
+    #[test]
+    fn test_switch_relay_seamlessly() {
+        let mut machine = TestMachine::start(FakePlatformConfig {
+            wireguard: true,
+            ..Default::default()
+        });
+
+        machine.send(TunnelCommand::Connect);
+        machine.wait_for(is_connected);
+        machine.send(TunnelCommand::Connect);
+
+        // The tunnel goes straight to the new relay, without disconnecting
+        match machine.wait_for(|_| true) {
+            TunnelStateTransition::Connected(tunnel_endpoint) => {
+                assert_eq!(tunnel_endpoint.endpoint, wireguard_relay_endpoint(1))
+            }
+            transition => panic!("unexpected transition: {:?}", transition),
+        }
+        assert!(matches!(
+            machine.last_policy(),
+            Some(FirewallPolicy::Connected { peer_endpoint, .. })
+                if peer_endpoint == wireguard_relay_endpoint(1)
+        ));
+        assert_eq!(*machine.retry_attempts.lock().unwrap(), vec![0, 0]);
+    }
+
+    #[test]
+    fn test_switch_relay_falls_back_to_reconnect() {
+        let mut machine = TestMachine::start(FakePlatformConfig {
+            wireguard: true,
+            peer_update: PeerUpdateBehavior::Fail,
+            ..Default::default()
+        });
+
+        machine.send(TunnelCommand::Connect);
+        machine.wait_for(is_connected);
+        machine.send(TunnelCommand::Connect);
+
+        assert!(matches!(
+            machine.wait_for(|_| true),
+            TunnelStateTransition::Disconnecting(ActionAfterDisconnect::Reconnect)
+        ));
+        machine.wait_for(is_connected);
+        assert_eq!(*machine.retry_attempts.lock().unwrap(), vec![0, 0, 0]);
+    }
+
+    #[test]
+    fn test_switch_relay_reconnects_without_handshake() {
+        let mut machine = TestMachine::start(FakePlatformConfig {
+            wireguard: true,
+            peer_update: PeerUpdateBehavior::FailHandshake,
+            ..Default::default()
+        });
+
+        machine.send(TunnelCommand::Connect);
+        machine.wait_for(is_connected);
+        machine.send(TunnelCommand::Connect);
+
+        assert!(matches!(
+            machine.wait_for(|_| true),
+            TunnelStateTransition::Disconnecting(ActionAfterDisconnect::Reconnect)
+        ));
+        machine.wait_for(is_connected);
+        assert_eq!(*machine.retry_attempts.lock().unwrap(), vec![0, 0, 0]);
+    }
+
+    #[test]
+    fn test_disconnect_while_switching_relay() {
+        let mut machine = TestMachine::start(FakePlatformConfig {
+            wireguard: true,
+            peer_update: PeerUpdateBehavior::Pending,
+            ..Default::default()
+        });
+
+        machine.send(TunnelCommand::Connect);
+        machine.wait_for(is_connected);
+        machine.send(TunnelCommand::Connect);
+        machine.send(TunnelCommand::Disconnect);
+
+        // Commands are handled while the new relay has not responded
+        assert!(matches!(
+            machine.wait_for(|_| true),
+            TunnelStateTransition::Disconnecting(ActionAfterDisconnect::Nothing)
+        ));
+        machine.wait_for(is_disconnected);
+    }
+}
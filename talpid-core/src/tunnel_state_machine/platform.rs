@@ -0,0 +1,367 @@
+//! Traits for the system components that the tunnel state machine manages. The state machine
+//! only interacts with the system through these, so that the state transitions can be tested
+//! with fake implementations.
+
+#[cfg(target_os = "linux")]
+use crate::{dns::DnsBackend, firewall::BlockedConnection, routing::RequiredRoute};
+use crate::{
+    dns::{self, DnsMonitor},
+    firewall::{self, Firewall, FirewallPolicy},
+    offline,
+    routing::{self, RouteManager, RouteManagerHandle},
+    tunnel::{self, tun_provider::TunProvider, TunnelEvent, TunnelMonitor},
+};
+use futures::channel::{mpsc, oneshot};
+#[cfg(target_os = "linux")]
+use std::collections::HashSet;
+#[cfg(target_os = "android")]
+use std::os::unix::io::RawFd;
+use std::{
+    future::Future,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{Arc, Mutex},
+};
+#[cfg(target_os = "android")]
+use talpid_types::net::lan::LanNetworks;
+use talpid_types::net::TunnelParameters;
+#[cfg(target_os = "linux")]
+use talpid_types::net::{dns::SplitDnsRule, wireguard as wireguard_types};
+
+/// Channel that tunnel events are sent to. The tunnel waits for the `oneshot::Sender` to be
+/// consumed before proceeding.
+pub type TunnelEventSender = mpsc::UnboundedSender<(TunnelEvent, oneshot::Sender<()>)>;
+
+/// Enforces firewall policies. See [`Firewall`].
+pub trait FirewallT: Send {
+    /// Applies and starts enforcing the given policy.
+    fn apply_policy(&mut self, policy: FirewallPolicy) -> Result<(), firewall::Error>;
+
+    /// Removes any policy that is enforced.
+    fn reset_policy(&mut self) -> Result<(), firewall::Error>;
+
+    /// Re-applies the active policy if its rules have been removed. Returns whether it was
+    /// re-applied.
+    #[cfg(target_os = "linux")]
+    fn restore_policy(&mut self) -> Result<bool, firewall::Error>;
+
+    /// Returns the policy that was most recently applied, unless it has been reset since.
+    fn active_policy(&self) -> Option<&FirewallPolicy>;
+
+    /// Returns the destinations that traffic was most recently blocked to.
+    #[cfg(target_os = "linux")]
+    fn blocked_connections(&self) -> Vec<BlockedConnection>;
+}
+
+impl FirewallT for Firewall {
+    fn apply_policy(&mut self, policy: FirewallPolicy) -> Result<(), firewall::Error> {
+        Firewall::apply_policy(self, policy)
+    }
+
+    fn reset_policy(&mut self) -> Result<(), firewall::Error> {
+        Firewall::reset_policy(self)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn restore_policy(&mut self) -> Result<bool, firewall::Error> {
+        Firewall::restore_policy(self)
+    }
+
+    fn active_policy(&self) -> Option<&FirewallPolicy> {
+        Firewall::active_policy(self)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn blocked_connections(&self) -> Vec<BlockedConnection> {
+        Firewall::blocked_connections(self)
+    }
+}
+
+/// Sets the system DNS servers. See [`DnsMonitor`].
+pub trait DnsMonitorT: Send {
+    /// Sets DNS to the given servers.
+    fn set(&mut self, interface: &str, servers: &[IpAddr]) -> Result<(), dns::Error>;
+
+    /// Resets DNS to what it was before it was set.
+    fn reset(&mut self) -> Result<(), dns::Error>;
+
+    /// Sets the rules for resolving specific domains using other resolvers.
+    #[cfg(target_os = "linux")]
+    fn set_split_dns(&mut self, rules: Vec<SplitDnsRule>);
+
+    /// Returns whether split DNS rules are applied by the system DNS manager.
+    #[cfg(target_os = "linux")]
+    fn supports_split_dns(&self) -> bool;
+
+    /// Returns the DNS manager, interface and servers that DNS is set through, if it is set.
+    #[cfg(target_os = "linux")]
+    fn current(&self) -> Option<(DnsBackend, &str, &[IpAddr])>;
+}
+
+impl DnsMonitorT for DnsMonitor {
+    fn set(&mut self, interface: &str, servers: &[IpAddr]) -> Result<(), dns::Error> {
+        DnsMonitor::set(self, interface, servers)
+    }
+
+    fn reset(&mut self) -> Result<(), dns::Error> {
+        DnsMonitor::reset(self)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn set_split_dns(&mut self, rules: Vec<SplitDnsRule>) {
+        DnsMonitor::set_split_dns(self, rules)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn supports_split_dns(&self) -> bool {
+        DnsMonitor::supports_split_dns(self)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn current(&self) -> Option<(DnsBackend, &str, &[IpAddr])> {
+        DnsMonitor::current(self)
+    }
+}
+
+/// Result of an asynchronous [`RouteManagerT`] operation.
+#[cfg(target_os = "linux")]
+pub type RouteFuture<'a> = Pin<Box<dyn Future<Output = Result<(), routing::Error>> + 'a>>;
+
+/// Manages the routes used by tunnels. See [`RouteManager`].
+pub trait RouteManagerT: Send {
+    /// Removes all routes added for the tunnel.
+    fn clear_routes(&mut self) -> Result<(), routing::Error>;
+
+    /// Removes the routing rules added for the tunnel.
+    #[cfg(target_os = "linux")]
+    fn clear_routing_rules(&mut self) -> RouteFuture<'_>;
+
+    /// Adds the given routes.
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    fn add_routes(&mut self, routes: HashSet<RequiredRoute>) -> RouteFuture<'_>;
+
+    /// Removes routes previously added with [`RouteManagerT::add_routes`].
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    fn remove_routes(&mut self, routes: HashSet<RequiredRoute>) -> RouteFuture<'_>;
+}
+
+impl RouteManagerT for RouteManager {
+    fn clear_routes(&mut self) -> Result<(), routing::Error> {
+        RouteManager::clear_routes(self)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn clear_routing_rules(&mut self) -> RouteFuture<'_> {
+        Box::pin(RouteManager::clear_routing_rules(self))
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    fn add_routes(&mut self, routes: HashSet<RequiredRoute>) -> RouteFuture<'_> {
+        Box::pin(RouteManager::add_routes(self, routes))
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    fn remove_routes(&mut self, routes: HashSet<RequiredRoute>) -> RouteFuture<'_> {
+        Box::pin(RouteManager::remove_routes(self, routes))
+    }
+}
+
+/// Reports whether the device is offline. Changes are sent to the state machine as
+/// [`super::TunnelCommand::IsOffline`], so this is only queried for the initial state. The
+/// monitor stops when it is dropped.
+pub trait OfflineMonitorT: Send {
+    /// Returns whether the device is known to be offline.
+    fn is_offline(&mut self) -> Pin<Box<dyn Future<Output = bool> + '_>>;
+}
+
+impl OfflineMonitorT for offline::MonitorHandle {
+    fn is_offline(&mut self) -> Pin<Box<dyn Future<Output = bool> + '_>> {
+        Box::pin(offline::MonitorHandle::is_offline(self))
+    }
+}
+
+/// Operations on the tun provider that the state machine performs outside of tunnels. See
+/// [`TunProvider`].
+#[cfg(target_os = "android")]
+pub trait TunProviderT: Send {
+    /// Sets whether LAN traffic is allowed, recreating the tunnel device if needed.
+    fn set_allow_lan(&mut self, allow_lan: bool) -> Result<(), tunnel::tun_provider::Error>;
+
+    /// Sets the networks that are reachable when LAN traffic is allowed.
+    fn set_lan_networks(
+        &mut self,
+        lan_networks: LanNetworks,
+    ) -> Result<(), tunnel::tun_provider::Error>;
+
+    /// Sets the DNS servers of the tunnel device.
+    fn set_dns_servers(
+        &mut self,
+        servers: Option<Vec<IpAddr>>,
+    ) -> Result<(), tunnel::tun_provider::Error>;
+
+    /// Opens a tunnel device that drops all traffic.
+    fn create_blocking_tun(&mut self) -> Result<(), tunnel::tun_provider::Error>;
+
+    /// Recreates the tunnel device.
+    fn create_tun(&mut self) -> Result<(), tunnel::tun_provider::Error>;
+
+    /// Closes the tunnel device.
+    fn close_tun(&mut self);
+
+    /// Lets traffic on the socket bypass the tunnel.
+    fn bypass(&mut self, socket: RawFd) -> Result<(), tunnel::tun_provider::Error>;
+}
+
+#[cfg(target_os = "android")]
+impl TunProviderT for TunProvider {
+    fn set_allow_lan(&mut self, allow_lan: bool) -> Result<(), tunnel::tun_provider::Error> {
+        TunProvider::set_allow_lan(self, allow_lan)
+    }
+
+    fn set_lan_networks(
+        &mut self,
+        lan_networks: LanNetworks,
+    ) -> Result<(), tunnel::tun_provider::Error> {
+        TunProvider::set_lan_networks(self, lan_networks)
+    }
+
+    fn set_dns_servers(
+        &mut self,
+        servers: Option<Vec<IpAddr>>,
+    ) -> Result<(), tunnel::tun_provider::Error> {
+        TunProvider::set_dns_servers(self, servers)
+    }
+
+    fn create_blocking_tun(&mut self) -> Result<(), tunnel::tun_provider::Error> {
+        TunProvider::create_blocking_tun(self)
+    }
+
+    fn create_tun(&mut self) -> Result<(), tunnel::tun_provider::Error> {
+        TunProvider::create_tun(self)
+    }
+
+    fn close_tun(&mut self) {
+        TunProvider::close_tun(self)
+    }
+
+    fn bypass(&mut self, socket: RawFd) -> Result<(), tunnel::tun_provider::Error> {
+        TunProvider::bypass(self, socket)
+    }
+}
+
+/// A running tunnel.
+pub trait Tunnel {
+    /// Blocks until the tunnel has stopped.
+    fn wait(self: Box<Self>) -> Result<(), tunnel::Error>;
+
+    /// Returns a handle for switching the tunnel to new parameters while it is running, if the
+    /// tunnel supports it.
+    #[cfg(target_os = "linux")]
+    fn peer_updater(&self) -> Option<Box<dyn PeerUpdater>> {
+        None
+    }
+}
+
+impl Tunnel for TunnelMonitor {
+    fn wait(self: Box<Self>) -> Result<(), tunnel::Error> {
+        TunnelMonitor::wait(*self)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn peer_updater(&self) -> Option<Box<dyn PeerUpdater>> {
+        let updater = TunnelMonitor::peer_updater(self)?;
+        Some(Box::new(updater))
+    }
+}
+
+/// Switches a running WireGuard tunnel to new parameters without recreating its interface. See
+/// [`tunnel::wireguard::PeerUpdater`].
+#[cfg(target_os = "linux")]
+pub trait PeerUpdater: Send {
+    /// Replaces the private key and peers of the tunnel with those in `parameters`. Fails if
+    /// anything else, such as the tunnel addresses, differs. Does not block on the new peers.
+    /// Instead, [`TunnelEvent::PeersUpdated`](tunnel::TunnelEvent::PeersUpdated) is sent once a
+    /// handshake with them has completed or timed out.
+    fn update_peers(
+        &self,
+        parameters: &wireguard_types::TunnelParameters,
+    ) -> Result<(), tunnel::Error>;
+}
+
+#[cfg(target_os = "linux")]
+impl PeerUpdater for tunnel::wireguard::PeerUpdater {
+    fn update_peers(
+        &self,
+        parameters: &wireguard_types::TunnelParameters,
+    ) -> Result<(), tunnel::Error> {
+        let config = tunnel::wireguard::config::Config::from_parameters(parameters)?;
+        tunnel::wireguard::PeerUpdater::update_peers(self, config)?;
+        Ok(())
+    }
+}
+
+/// Starts tunnels. This is called on a blocking thread, which the tunnel is waited on from.
+pub trait TunnelStarter: Send + Sync {
+    /// Starts a tunnel that sends its events to `event_tx`, and stops when `tunnel_close_rx`
+    /// receives a value or is dropped.
+    fn start(
+        &self,
+        runtime: tokio::runtime::Handle,
+        parameters: &TunnelParameters,
+        log_dir: &Option<PathBuf>,
+        resource_dir: &Path,
+        event_tx: TunnelEventSender,
+        retry_attempt: u32,
+        tunnel_close_rx: oneshot::Receiver<()>,
+    ) -> Result<Box<dyn Tunnel>, tunnel::Error>;
+}
+
+/// Starts tunnels using [`TunnelMonitor`].
+pub struct TunnelMonitorStarter {
+    tun_provider: Arc<Mutex<TunProvider>>,
+    route_manager: RouteManagerHandle,
+}
+
+impl TunnelMonitorStarter {
+    pub fn new(tun_provider: Arc<Mutex<TunProvider>>, route_manager: RouteManagerHandle) -> Self {
+        TunnelMonitorStarter {
+            tun_provider,
+            route_manager,
+        }
+    }
+}
+
+impl TunnelStarter for TunnelMonitorStarter {
+    fn start(
+        &self,
+        runtime: tokio::runtime::Handle,
+        parameters: &TunnelParameters,
+        log_dir: &Option<PathBuf>,
+        resource_dir: &Path,
+        event_tx: TunnelEventSender,
+        retry_attempt: u32,
+        tunnel_close_rx: oneshot::Receiver<()>,
+    ) -> Result<Box<dyn Tunnel>, tunnel::Error> {
+        let on_tunnel_event = move |event| -> Pin<Box<dyn Future<Output = ()> + Send>> {
+            let (tx, rx) = oneshot::channel();
+            let _ = event_tx.unbounded_send((event, tx));
+            Box::pin(async move {
+                let _ = rx.await;
+            })
+        };
+
+        let monitor = TunnelMonitor::start(
+            runtime,
+            parameters,
+            log_dir,
+            resource_dir,
+            on_tunnel_event,
+            self.tun_provider.clone(),
+            self.route_manager.clone(),
+            retry_attempt,
+            tunnel_close_rx,
+        )?;
+        Ok(Box::new(monitor))
+    }
+}
@@ -0,0 +1,117 @@
+//! ConnMan is a connection manager mainly used on embedded and mobile Linux systems.
+use dbus::{
+    arg::{RefArg, Variant},
+    blocking::{Proxy, SyncConnection},
+};
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+const CONNMAN_BUS: &str = "net.connman";
+const CONNMAN_MANAGER: &str = "net.connman.Manager";
+const CONNMAN_MANAGER_PATH: &str = "/";
+const CONNMAN_SERVICE: &str = "net.connman.Service";
+
+const STATE_KEY: &str = "State";
+const NAMESERVERS_CONFIGURATION_KEY: &str = "Nameservers.Configuration";
+
+const RPC_TIMEOUT: Duration = Duration::from_secs(3);
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(err_derive::Error, Debug)]
+pub enum Error {
+    #[error(display = "Error while communicating over Dbus")]
+    Dbus(#[error(source)] dbus::Error),
+
+    #[error(display = "ConnMan not detected")]
+    ConnmanNotDetected,
+}
+
+type PropertyMap = HashMap<String, Variant<Box<dyn RefArg>>>;
+
+/// A ConnMan service that is connected, and its user configured nameservers.
+pub struct ServiceNameservers {
+    pub service: dbus::Path<'static>,
+    pub nameservers: Vec<String>,
+}
+
+/// Implements functionality to control ConnMan over DBus.
+pub struct Connman {
+    connection: Arc<SyncConnection>,
+}
+
+impl Connman {
+    pub fn new() -> Result<Self> {
+        Ok(Self {
+            connection: crate::get_connection()?,
+        })
+    }
+
+    pub fn ensure_connman_exists(&self) -> Result<()> {
+        let _: (PropertyMap,) = self
+            .as_manager()
+            .method_call(CONNMAN_MANAGER, "GetProperties", ())
+            .map_err(|error| {
+                log::debug!("ConnMan not detected: {}", error);
+                Error::ConnmanNotDetected
+            })?;
+        Ok(())
+    }
+
+    /// Returns all services that are ready or online, together with the nameservers that have
+    /// been configured for them by the user.
+    pub fn connected_services(&self) -> Result<Vec<ServiceNameservers>> {
+        let (services,): (Vec<(dbus::Path<'static>, PropertyMap)>,) = self
+            .as_manager()
+            .method_call(CONNMAN_MANAGER, "GetServices", ())
+            .map_err(Error::Dbus)?;
+
+        Ok(services
+            .into_iter()
+            .filter(|(_, properties)| {
+                matches!(
+                    properties.get(STATE_KEY).and_then(|state| state.0.as_str()),
+                    Some("ready") | Some("online")
+                )
+            })
+            .map(|(service, properties)| {
+                let nameservers = properties
+                    .get(NAMESERVERS_CONFIGURATION_KEY)
+                    .and_then(|nameservers| nameservers.0.as_iter())
+                    .map(|iter| {
+                        iter.filter_map(|server| server.as_str().map(str::to_owned))
+                            .collect()
+                    })
+                    .unwrap_or_default();
+                ServiceNameservers {
+                    service,
+                    nameservers,
+                }
+            })
+            .collect())
+    }
+
+    /// Replaces the user configured nameservers of a service. An empty list makes ConnMan fall
+    /// back to the nameservers it obtained automatically.
+    pub fn set_nameservers(&self, service: &dbus::Path<'_>, nameservers: &[String]) -> Result<()> {
+        self.as_path(service)
+            .method_call(
+                CONNMAN_SERVICE,
+                "SetProperty",
+                (NAMESERVERS_CONFIGURATION_KEY, Variant(nameservers.to_vec())),
+            )
+            .map_err(Error::Dbus)
+    }
+
+    fn as_manager<'a>(&'a self) -> Proxy<'a, &SyncConnection> {
+        Proxy::new(
+            CONNMAN_BUS,
+            CONNMAN_MANAGER_PATH,
+            RPC_TIMEOUT,
+            &*self.connection,
+        )
+    }
+
+    fn as_path<'a>(&'a self, path: &'a dbus::Path<'a>) -> Proxy<'a, &SyncConnection> {
+        Proxy::new(CONNMAN_BUS, path, RPC_TIMEOUT, &*self.connection)
+    }
+}
@@ -27,6 +27,9 @@ const CONNECTIVITY_CHECK_KEY: &str = "ConnectivityCheckEnabled";
 const NM_DNS_MANAGER: &str = "org.freedesktop.NetworkManager.DnsManager";
 const NM_DNS_MANAGER_PATH: &str = "/org/freedesktop/NetworkManager/DnsManager";
 const NM_DEVICE: &str = "org.freedesktop.NetworkManager.Device";
+const NM_DEVICE_WIRELESS: &str = "org.freedesktop.NetworkManager.Device.Wireless";
+const NM_ACCESS_POINT: &str = "org.freedesktop.NetworkManager.AccessPoint";
+const NM_DEVICE_TYPE_WIFI: u32 = 2;
 
 const NM_IP4_CONFIG: &str = "org.freedesktop.NetworkManager.IP4Config";
 const NM_IP6_CONFIG: &str = "org.freedesktop.NetworkManager.IP6Config";
@@ -636,6 +639,34 @@ impl NetworkManager {
         Err(Error::DeviceNotFound)
     }
 
+    /// Returns the SSID of the access point that a wireless interface is associated with, or
+    /// `None` if the interface is not a wireless device or is not associated.
+    pub fn get_wireless_ssid(&self, interface_name: &str) -> Result<Option<String>> {
+        let device = self.fetch_device(interface_name)?;
+        let device_type: u32 = self
+            .as_path(&device)
+            .get(NM_DEVICE, "DeviceType")
+            .map_err(Error::Dbus)?;
+        if device_type != NM_DEVICE_TYPE_WIFI {
+            return Ok(None);
+        }
+
+        let access_point: dbus::Path<'static> = self
+            .as_path(&device)
+            .get(NM_DEVICE_WIRELESS, "ActiveAccessPoint")
+            .map_err(Error::Dbus)?;
+        // NetworkManager uses "/" when there is no active access point
+        if &*access_point == "/" {
+            return Ok(None);
+        }
+
+        let ssid: Vec<u8> = self
+            .as_path(&access_point)
+            .get(NM_ACCESS_POINT, "Ssid")
+            .map_err(Error::Dbus)?;
+        Ok(Some(String::from_utf8_lossy(&ssid).into_owned()))
+    }
+
     pub fn convert_address_to_dbus(address: &IpAddr) -> VariantMap {
         let mut map: VariantMap = HashMap::new();
         map.insert(
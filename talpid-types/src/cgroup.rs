@@ -1,7 +1,134 @@
-use std::{ffi::OsStr, fs, os::unix::ffi::OsStrExt, path::PathBuf};
+use serde::{Deserialize, Serialize};
+use std::{ffi::OsStr, fmt, fs, os::unix::ffi::OsStrExt, path::PathBuf};
 
 pub const SPLIT_TUNNEL_CGROUP_NAME: &str = "mullvad-exclusions";
 
+/// Determines how the processes in the split tunneling cgroup are treated.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SplitTunnelMode {
+    /// Processes in the cgroup are excluded from the tunnel. All other traffic uses the tunnel.
+    Exclude,
+    /// Only processes in the cgroup use the tunnel. All other traffic is excluded from it.
+    IncludeOnly,
+}
+
+impl Default for SplitTunnelMode {
+    fn default() -> Self {
+        SplitTunnelMode::Exclude
+    }
+}
+
+impl fmt::Display for SplitTunnelMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SplitTunnelMode::Exclude => f.write_str("exclude"),
+            SplitTunnelMode::IncludeOnly => f.write_str("include only"),
+        }
+    }
+}
+
+/// Determines how DNS requests from the processes in an exclusion profile are treated.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum ProfileDns {
+    /// DNS requests to the DNS servers used by the tunnel are sent in the tunnel, like those of
+    /// processes in the default cgroup.
+    Tunnel,
+    /// DNS requests are excluded from the tunnel along with all other traffic.
+    Bypass,
+}
+
+impl Default for ProfileDns {
+    fn default() -> Self {
+        ProfileDns::Tunnel
+    }
+}
+
+impl fmt::Display for ProfileDns {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProfileDns::Tunnel => f.write_str("tunnel"),
+            ProfileDns::Bypass => f.write_str("bypass"),
+        }
+    }
+}
+
+/// A named group of processes that are excluded from the tunnel. Each profile has its own
+/// cgroup, which processes are added to using `mullvad-exclude --profile <name>`.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq, Hash)]
+pub struct ExclusionProfile {
+    /// Name of the profile. See [`ExclusionProfile::is_valid_name`].
+    pub name: String,
+    /// How DNS requests from the processes in the profile are treated.
+    #[serde(default)]
+    pub dns: ProfileDns,
+}
+
+impl ExclusionProfile {
+    /// Maximum length of a profile name.
+    pub const MAX_NAME_LEN: usize = 32;
+
+    /// Returns whether `name` can be used as the name of a profile. Since the name is part of
+    /// the name of the cgroup directory, it may only contain ASCII letters, digits, `-` and `_`.
+    pub fn is_valid_name(name: &str) -> bool {
+        !name.is_empty()
+            && name.len() <= Self::MAX_NAME_LEN
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    }
+
+    /// Returns the name of the cgroup of the profile.
+    pub fn cgroup_name(name: &str) -> String {
+        format!("{}-{}", SPLIT_TUNNEL_CGROUP_NAME, name)
+    }
+}
+
+/// A cgroup hierarchy that can be used to identify processes excluded from the tunnel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExclusionCgroup {
+    /// A cgroup v1 hierarchy with the `net_cls` controller, mounted at the given path.
+    /// Packets are identified by their class ID.
+    NetCls(PathBuf),
+    /// The unified cgroup v2 hierarchy, mounted at the given path. Packets are identified by the
+    /// cgroup of their socket.
+    V2(PathBuf),
+}
+
+impl ExclusionCgroup {
+    /// Returns the mount path of the hierarchy.
+    pub fn mount_path(&self) -> &PathBuf {
+        match self {
+            ExclusionCgroup::NetCls(path) | ExclusionCgroup::V2(path) => path,
+        }
+    }
+
+    /// Returns the path of the cgroup that excluded processes are added to.
+    pub fn exclusions_path(&self) -> PathBuf {
+        self.mount_path().join(SPLIT_TUNNEL_CGROUP_NAME)
+    }
+
+    /// Returns the path of the cgroup of the exclusion profile with the given name.
+    pub fn profile_path(&self, name: &str) -> PathBuf {
+        self.mount_path().join(ExclusionProfile::cgroup_name(name))
+    }
+}
+
+/// Find the cgroup hierarchy to use for split tunneling. A mounted `net_cls` controller is
+/// preferred, since setting a class ID prevents sockets from being matched by their cgroup v2
+/// path on older kernels. Otherwise, the cgroup v2 hierarchy is used if it is mounted.
+pub fn find_exclusion_cgroup() -> std::io::Result<Option<ExclusionCgroup>> {
+    let mounts = fs::read("/proc/mounts")?;
+    Ok(find_exclusion_cgroup_inner(&mounts))
+}
+
+fn find_exclusion_cgroup_inner(mounts: &[u8]) -> Option<ExclusionCgroup> {
+    find_net_cls_mount_inner(mounts)
+        .map(ExclusionCgroup::NetCls)
+        .or_else(|| find_cgroup2_mount_inner(mounts).map(ExclusionCgroup::V2))
+}
+
 /// Find the path of the cgroup v1 net_cls controller mount if it exists
 pub fn find_net_cls_mount() -> std::io::Result<Option<PathBuf>> {
     let mounts = fs::read("/proc/mounts")?;
@@ -14,6 +141,19 @@ fn find_net_cls_mount_inner(mounts: &[u8]) -> Option<PathBuf> {
         .find_map(parse_mount_line)
 }
 
+fn find_cgroup2_mount_inner(mounts: &[u8]) -> Option<PathBuf> {
+    mounts.split(|byte| *byte == b'\n').find_map(|line| {
+        let mut parts = line.split(|byte| *byte == b' ');
+        let _device_type = parts.next()?;
+        let mount_path = parts.next()?;
+        let filesystem_type = parts.next()?;
+        if filesystem_type != b"cgroup2" {
+            return None;
+        }
+        Some(PathBuf::from(OsStr::from_bytes(mount_path)))
+    })
+}
+
 fn parse_mount_line(line: &[u8]) -> Option<PathBuf> {
     // Each line contains multiple values seperated by space.
     // `cgroup /sys/fs/cgroup/net_cls,net_prio cgroup
@@ -47,6 +187,21 @@ fn parse_mount_line(line: &[u8]) -> Option<PathBuf> {
 mod test {
     use super::*;
 
+    #[test]
+    fn test_profile_names() {
+        assert!(ExclusionProfile::is_valid_name("build-tools_2"));
+        assert!(!ExclusionProfile::is_valid_name(""));
+        assert!(!ExclusionProfile::is_valid_name("../escape"));
+        assert!(!ExclusionProfile::is_valid_name("with space"));
+        assert!(!ExclusionProfile::is_valid_name(&"a".repeat(33)));
+
+        let cgroup = ExclusionCgroup::V2(PathBuf::from("/sys/fs/cgroup"));
+        assert_eq!(
+            cgroup.profile_path("tools"),
+            PathBuf::from("/sys/fs/cgroup/mullvad-exclusions-tools")
+        );
+    }
+
     #[test]
     fn test_find_net_cls_path() {
         let input =
@@ -72,4 +227,30 @@ cgroup /nope
 
         assert_eq!(find_net_cls_mount_inner(input), None)
     }
+
+    #[test]
+    fn test_prefer_net_cls() {
+        let input = br#"cgroup2 /sys/fs/cgroup/unified cgroup2 rw,nosuid,nodev,noexec,relatime 0 0
+cgroup /sys/fs/cgroup/net_cls,net_prio cgroup rw,nosuid,nodev,noexec,relatime,net_cls,net_prio 0 0
+"#;
+
+        assert_eq!(
+            find_exclusion_cgroup_inner(input),
+            Some(ExclusionCgroup::NetCls(PathBuf::from(
+                "/sys/fs/cgroup/net_cls,net_prio"
+            )))
+        )
+    }
+
+    #[test]
+    fn test_find_cgroup2_path() {
+        let input = br#"proc /proc proc rw,nosuid,nodev,noexec,relatime 0 0
+cgroup2 /sys/fs/cgroup cgroup2 rw,nosuid,nodev,noexec,relatime,nsdelegate 0 0
+"#;
+
+        assert_eq!(
+            find_exclusion_cgroup_inner(input),
+            Some(ExclusionCgroup::V2(PathBuf::from("/sys/fs/cgroup")))
+        )
+    }
 }
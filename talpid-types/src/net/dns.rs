@@ -0,0 +1,234 @@
+use serde::{Deserialize, Serialize};
+use std::{fmt, net::IpAddr, path::PathBuf, str::FromStr};
+
+/// A file containing domains that the local filtering resolver refuses to resolve. The file may
+/// either be in hosts-file format or contain a single domain per line.
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub struct DnsBlocklist {
+    /// Path to the blocklist file.
+    pub path: PathBuf,
+    /// Whether the blocklist is applied.
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+/// Protocol used to reach an [`EncryptedDnsServer`].
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EncryptedDnsProtocol {
+    /// DNS-over-HTTPS, as specified in RFC 8484.
+    Https,
+    /// DNS-over-TLS, as specified in RFC 7858.
+    Tls,
+}
+
+impl EncryptedDnsProtocol {
+    pub fn default_port(self) -> u16 {
+        match self {
+            EncryptedDnsProtocol::Https => 443,
+            EncryptedDnsProtocol::Tls => 853,
+        }
+    }
+}
+
+/// A DNS resolver that is reached using DNS-over-HTTPS or DNS-over-TLS.
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub struct EncryptedDnsServer {
+    pub protocol: EncryptedDnsProtocol,
+    /// Name that the certificate of the server must be valid for.
+    pub hostname: String,
+    pub port: u16,
+    /// Path of the DNS-over-HTTPS endpoint. Empty for DNS-over-TLS.
+    #[serde(default)]
+    pub path: String,
+    /// Addresses of the server. These are never looked up, since doing so would require DNS.
+    pub addresses: Vec<IpAddr>,
+}
+
+/// Errors that can occur when parsing an [`EncryptedDnsServer`] or creating a [`SplitDnsRule`].
+#[derive(err_derive::Error, Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    #[error(display = "Unsupported URL scheme: {}", _0)]
+    UnsupportedScheme(String),
+
+    #[error(display = "Invalid hostname: {}", _0)]
+    InvalidHostname(String),
+
+    #[error(display = "Invalid port: {}", _0)]
+    InvalidPort(String),
+
+    #[error(display = "No resolvers given for {}", _0)]
+    NoResolvers(String),
+}
+
+impl FromStr for EncryptedDnsServer {
+    type Err = ParseError;
+
+    /// Parses a DNS-over-HTTPS URL, such as `https://dns.example/dns-query`, or a DNS-over-TLS
+    /// server given as `tls://dns.example` or only `dns.example`. The addresses are left empty.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (protocol, rest) = match s.split_once("://") {
+            Some(("https", rest)) => (EncryptedDnsProtocol::Https, rest),
+            Some(("tls", rest)) => (EncryptedDnsProtocol::Tls, rest),
+            Some((scheme, _)) => return Err(ParseError::UnsupportedScheme(scheme.to_owned())),
+            None => (EncryptedDnsProtocol::Tls, s),
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(index) => rest.split_at(index),
+            None => (rest, ""),
+        };
+        let path = match protocol {
+            EncryptedDnsProtocol::Https if path.is_empty() || path == "/" => "/dns-query",
+            EncryptedDnsProtocol::Https => path,
+            EncryptedDnsProtocol::Tls if path.is_empty() || path == "/" => "",
+            EncryptedDnsProtocol::Tls => return Err(ParseError::InvalidHostname(rest.to_owned())),
+        };
+        let (hostname, port) = match authority.split_once(':') {
+            Some((hostname, port)) => (
+                hostname,
+                port.parse()
+                    .map_err(|_| ParseError::InvalidPort(port.to_owned()))?,
+            ),
+            None => (authority, protocol.default_port()),
+        };
+        if !is_valid_hostname(hostname) {
+            return Err(ParseError::InvalidHostname(hostname.to_owned()));
+        }
+        Ok(EncryptedDnsServer {
+            protocol,
+            hostname: hostname.to_ascii_lowercase(),
+            port,
+            path: path.to_owned(),
+            addresses: vec![],
+        })
+    }
+}
+
+impl fmt::Display for EncryptedDnsServer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.protocol {
+            EncryptedDnsProtocol::Https => {
+                write!(f, "https://{}:{}{}", self.hostname, self.port, self.path)
+            }
+            EncryptedDnsProtocol::Tls => write!(f, "tls://{}:{}", self.hostname, self.port),
+        }
+    }
+}
+
+/// Sends queries for a domain and its subdomains to specific resolvers rather than the tunnel
+/// DNS servers. The resolvers are reached outside the tunnel.
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub struct SplitDnsRule {
+    /// Normalized domain name, without a leading `*.` or trailing dot.
+    pub domain: String,
+    pub resolvers: Vec<IpAddr>,
+}
+
+impl SplitDnsRule {
+    /// Creates a rule for `domain`, which may be given as `*.domain` or with a trailing dot.
+    pub fn new(domain: &str, resolvers: Vec<IpAddr>) -> Result<Self, ParseError> {
+        let normalized = Self::normalize_domain(domain);
+        if !is_valid_hostname(&normalized) {
+            return Err(ParseError::InvalidHostname(domain.to_owned()));
+        }
+        if resolvers.is_empty() {
+            return Err(ParseError::NoResolvers(normalized));
+        }
+        Ok(SplitDnsRule {
+            domain: normalized,
+            resolvers,
+        })
+    }
+
+    /// Returns `domain` in the form stored in rules, without wildcard or trailing dot.
+    pub fn normalize_domain(domain: &str) -> String {
+        domain
+            .strip_prefix("*.")
+            .unwrap_or(domain)
+            .trim_end_matches('.')
+            .to_ascii_lowercase()
+    }
+
+    /// Returns whether `name` is the domain of the rule or one of its subdomains.
+    pub fn matches(&self, name: &str) -> bool {
+        let name = name.trim_end_matches('.');
+        name.len() >= self.domain.len()
+            && name[name.len() - self.domain.len()..].eq_ignore_ascii_case(&self.domain)
+            && (name.len() == self.domain.len()
+                || name.as_bytes()[name.len() - self.domain.len() - 1] == b'.')
+    }
+}
+
+/// Returns whether `hostname` can be used as the name of an [`EncryptedDnsServer`]. Certificates
+/// are validated against the hostname, so it must be a domain name rather than an address.
+pub fn is_valid_hostname(hostname: &str) -> bool {
+    !hostname.is_empty()
+        && hostname.len() <= 253
+        && hostname.parse::<IpAddr>().is_err()
+        && hostname.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_encrypted_server() {
+        let server: EncryptedDnsServer = "https://DNS.example".parse().unwrap();
+        assert_eq!(server.protocol, EncryptedDnsProtocol::Https);
+        assert_eq!(server.hostname, "dns.example");
+        assert_eq!(server.port, 443);
+        assert_eq!(server.path, "/dns-query");
+        assert_eq!(server.to_string(), "https://dns.example:443/dns-query");
+
+        let server: EncryptedDnsServer = "https://dns.example:8443/resolve".parse().unwrap();
+        assert_eq!(server.port, 8443);
+        assert_eq!(server.path, "/resolve");
+
+        let server: EncryptedDnsServer = "dns.example".parse().unwrap();
+        assert_eq!(server.protocol, EncryptedDnsProtocol::Tls);
+        assert_eq!(server.port, 853);
+        assert_eq!(server.path, "");
+        assert_eq!(server, "tls://dns.example:853".parse().unwrap());
+
+        assert_eq!(
+            "http://dns.example".parse::<EncryptedDnsServer>(),
+            Err(ParseError::UnsupportedScheme("http".to_owned()))
+        );
+        assert!("tls://dns.example/dns-query"
+            .parse::<EncryptedDnsServer>()
+            .is_err());
+        assert!("https://1.1.1.1/dns-query"
+            .parse::<EncryptedDnsServer>()
+            .is_err());
+        assert!("dns.example:port".parse::<EncryptedDnsServer>().is_err());
+        assert!("https://".parse::<EncryptedDnsServer>().is_err());
+    }
+
+    #[test]
+    fn test_split_dns_rule() {
+        let resolvers = vec!["192.168.1.53".parse().unwrap()];
+        let rule = SplitDnsRule::new("*.Corp.Example.", resolvers.clone()).unwrap();
+        assert_eq!(rule.domain, "corp.example");
+        assert!(rule.matches("corp.example"));
+        assert!(rule.matches("intranet.CORP.example."));
+        assert!(!rule.matches("notcorp.example"));
+        assert!(!rule.matches("example"));
+
+        assert_eq!(
+            SplitDnsRule::new("corp.example", vec![]),
+            Err(ParseError::NoResolvers("corp.example".to_owned()))
+        );
+        assert!(SplitDnsRule::new("10.0.0.1", resolvers).is_err());
+    }
+}
@@ -0,0 +1,205 @@
+use ipnetwork::{IpNetwork, Ipv4Network, Ipv6Network};
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// Networks that are considered local. When local network sharing is allowed, traffic to and
+/// from these networks is let through outside the tunnel.
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LanNetworks {
+    /// Networks that traffic is allowed to and from.
+    pub networks: Vec<IpNetwork>,
+    /// Multicast and broadcast networks that outgoing traffic is allowed to.
+    pub multicast_networks: Vec<IpNetwork>,
+}
+
+impl Default for LanNetworks {
+    fn default() -> Self {
+        LanNetworks {
+            networks: vec![
+                v4_net(Ipv4Addr::new(10, 0, 0, 0), 8),
+                v4_net(Ipv4Addr::new(172, 16, 0, 0), 12),
+                v4_net(Ipv4Addr::new(192, 168, 0, 0), 16),
+                v4_net(Ipv4Addr::new(169, 254, 0, 0), 16),
+                v6_net(Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 0), 10),
+                v6_net(Ipv6Addr::new(0xfc00, 0, 0, 0, 0, 0, 0, 0), 7),
+            ],
+            multicast_networks: vec![
+                // Local network broadcast. Not routable
+                v4_net(Ipv4Addr::new(255, 255, 255, 255), 32),
+                // Local subnetwork multicast. Not routable
+                v4_net(Ipv4Addr::new(224, 0, 0, 0), 24),
+                // Local scope (mDNS and SSDP) address
+                v4_net(Ipv4Addr::new(239, 255, 0, 0), 16),
+                // Interface-local IPv6 multicast.
+                v6_net(Ipv6Addr::new(0xff01, 0, 0, 0, 0, 0, 0, 0), 16),
+                // Link-local IPv6 multicast. IPv6 equivalent of 224.0.0.0/24
+                v6_net(Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0), 16),
+                // Realm-local IPv6 multicast.
+                v6_net(Ipv6Addr::new(0xff03, 0, 0, 0, 0, 0, 0, 0), 16),
+                // Admin-local IPv6 multicast.
+                v6_net(Ipv6Addr::new(0xff04, 0, 0, 0, 0, 0, 0, 0), 16),
+                // Site-local IPv6 multicast.
+                v6_net(Ipv6Addr::new(0xff05, 0, 0, 0, 0, 0, 0, 0), 16),
+            ],
+        }
+    }
+}
+
+/// Error returned by [`LanNetworks::validate`].
+#[derive(err_derive::Error, Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// The network is, or overlaps with, a publicly routable network.
+    #[error(display = "{} is not a private network", _0)]
+    NotPrivate(IpNetwork),
+
+    /// The network is not a multicast network nor the broadcast address.
+    #[error(display = "{} is not a multicast or broadcast network", _0)]
+    NotMulticast(IpNetwork),
+
+    /// The address of the network has bits set outside of the prefix.
+    #[error(display = "{} has host bits set", _0)]
+    HostBitsSet(IpNetwork),
+}
+
+impl LanNetworks {
+    /// Ensures that `networks` only contains non-routable ranges and that `multicast_networks`
+    /// only contains multicast ranges or the broadcast address, so that public networks cannot
+    /// be exposed outside the tunnel by mistake. Every network must be given by its network
+    /// address, since the firewall compares the masked address against it.
+    pub fn validate(&self) -> Result<(), Error> {
+        if let Some(net) = self
+            .networks
+            .iter()
+            .chain(self.multicast_networks.iter())
+            .find(|net| net.ip() != net.network())
+        {
+            return Err(Error::HostBitsSet(*net));
+        }
+        if let Some(net) = self
+            .networks
+            .iter()
+            .find(|net| !is_within(net, &private_networks()))
+        {
+            return Err(Error::NotPrivate(*net));
+        }
+        if let Some(net) = self
+            .multicast_networks
+            .iter()
+            .find(|net| !is_within(net, &multicast_networks()))
+        {
+            return Err(Error::NotMulticast(*net));
+        }
+        Ok(())
+    }
+
+    /// Returns whether `address` belongs to any of the unicast networks.
+    pub fn contains(&self, address: IpAddr) -> bool {
+        self.networks.iter().any(|net| net.contains(address))
+    }
+}
+
+/// Networks that are never routed on the internet, and therefore may be added to
+/// [`LanNetworks::networks`].
+fn private_networks() -> [IpNetwork; 7] {
+    [
+        v4_net(Ipv4Addr::new(10, 0, 0, 0), 8),
+        v4_net(Ipv4Addr::new(172, 16, 0, 0), 12),
+        v4_net(Ipv4Addr::new(192, 168, 0, 0), 16),
+        v4_net(Ipv4Addr::new(169, 254, 0, 0), 16),
+        // Shared address space for carrier-grade NAT
+        v4_net(Ipv4Addr::new(100, 64, 0, 0), 10),
+        v6_net(Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 0), 10),
+        v6_net(Ipv6Addr::new(0xfc00, 0, 0, 0, 0, 0, 0, 0), 7),
+    ]
+}
+
+fn multicast_networks() -> [IpNetwork; 3] {
+    [
+        v4_net(Ipv4Addr::new(255, 255, 255, 255), 32),
+        v4_net(Ipv4Addr::new(224, 0, 0, 0), 4),
+        v6_net(Ipv6Addr::new(0xff00, 0, 0, 0, 0, 0, 0, 0), 8),
+    ]
+}
+
+/// Returns whether `net` is entirely contained in one of `allowed`.
+fn is_within(net: &IpNetwork, allowed: &[IpNetwork]) -> bool {
+    allowed
+        .iter()
+        .any(|allowed| allowed.contains(net.network()) && allowed.prefix() <= net.prefix())
+}
+
+fn v4_net(address: Ipv4Addr, prefix: u8) -> IpNetwork {
+    IpNetwork::V4(Ipv4Network::new(address, prefix).unwrap())
+}
+
+fn v6_net(address: Ipv6Addr, prefix: u8) -> IpNetwork {
+    IpNetwork::V6(Ipv6Network::new(address, prefix).unwrap())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_default_is_valid() {
+        assert_eq!(LanNetworks::default().validate(), Ok(()));
+    }
+
+    #[test]
+    fn test_private_subnets() {
+        let networks = LanNetworks {
+            networks: vec![
+                "192.168.1.0/24".parse().unwrap(),
+                "100.64.0.0/10".parse().unwrap(),
+                "fd00:1234::/32".parse().unwrap(),
+            ],
+            multicast_networks: vec!["239.255.255.250/32".parse().unwrap()],
+        };
+        assert_eq!(networks.validate(), Ok(()));
+    }
+
+    #[test]
+    fn test_public_networks() {
+        for net in &[
+            "8.8.8.0/24",
+            "10.0.0.0/7",
+            "100.128.0.0/10",
+            "2001:db8::/32",
+        ] {
+            let net: IpNetwork = net.parse().unwrap();
+            let networks = LanNetworks {
+                networks: vec![net],
+                multicast_networks: vec![],
+            };
+            assert_eq!(networks.validate(), Err(Error::NotPrivate(net)));
+        }
+    }
+
+    #[test]
+    fn test_host_bits_set() {
+        let net: IpNetwork = "192.168.1.5/24".parse().unwrap();
+        let networks = LanNetworks {
+            networks: vec![net],
+            multicast_networks: vec![],
+        };
+        assert_eq!(networks.validate(), Err(Error::HostBitsSet(net)));
+
+        let net: IpNetwork = "239.255.255.250/16".parse().unwrap();
+        let networks = LanNetworks {
+            networks: vec![],
+            multicast_networks: vec![net],
+        };
+        assert_eq!(networks.validate(), Err(Error::HostBitsSet(net)));
+    }
+
+    #[test]
+    fn test_unicast_as_multicast() {
+        let net: IpNetwork = "192.168.1.255/32".parse().unwrap();
+        let networks = LanNetworks {
+            networks: vec![],
+            multicast_networks: vec![net],
+        };
+        assert_eq!(networks.validate(), Err(Error::NotMulticast(net)));
+    }
+}
@@ -10,9 +10,12 @@ use std::{
     str::FromStr,
 };
 
+pub mod dns;
+pub mod lan;
 pub mod obfuscation;
 pub mod openvpn;
 pub mod proxy;
+pub mod trusted_network;
 pub mod wireguard;
 
 /// TunnelParameters are used to encapsulate all the data needed to start a tunnel. This is enum
@@ -215,6 +218,13 @@ impl fmt::Display for ObfuscationEndpoint {
     }
 }
 
+/// Number of bytes transferred through a tunnel interface.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TrafficStats {
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+}
+
 /// Represents a network layer IP address together with the transport layer protocol and port.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 #[cfg_attr(target_os = "android", derive(IntoJava))]
@@ -278,6 +288,122 @@ impl fmt::Display for AllowedEndpoint {
     }
 }
 
+/// User-defined exception that lets traffic to or from a network pass the firewall in every
+/// tunnel state.
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub struct FirewallAllowRule {
+    /// The remote network that traffic is allowed to or from.
+    pub network: ipnetwork::IpNetwork,
+    /// Only allow this transport protocol. Any protocol is allowed if this is `None`.
+    #[serde(default)]
+    pub protocol: Option<TransportProtocol>,
+    /// Only allow this port. This is the remote port for outbound rules and the local port for
+    /// inbound rules. Must be combined with a `protocol`.
+    #[serde(default)]
+    pub port: Option<u16>,
+    /// Which side is allowed to initiate connections.
+    pub direction: AllowedDirection,
+    /// Route matching traffic outside the tunnel, the same way as traffic from excluded
+    /// processes.
+    #[serde(default)]
+    pub outside_tunnel: bool,
+}
+
+/// Error returned by [`FirewallAllowRule::validate`].
+#[derive(err_derive::Error, Debug, Clone, PartialEq, Eq)]
+pub enum AllowRuleError {
+    /// A port was given without a transport protocol.
+    #[error(display = "A port requires a transport protocol")]
+    PortWithoutProtocol,
+
+    /// The network matches every address, which would disable the firewall entirely.
+    #[error(display = "{} matches every address", _0)]
+    AllAddresses(ipnetwork::IpNetwork),
+
+    /// The address of the network has bits set outside of the prefix.
+    #[error(display = "{} has host bits set", _0)]
+    HostBitsSet(ipnetwork::IpNetwork),
+}
+
+impl FirewallAllowRule {
+    /// Ensures that the rule can be enforced. A port can only be matched together with a
+    /// transport protocol, and the network must be given by its network address, since the
+    /// firewall compares the masked address against it.
+    pub fn validate(&self) -> Result<(), AllowRuleError> {
+        if self.port.is_some() && self.protocol.is_none() {
+            return Err(AllowRuleError::PortWithoutProtocol);
+        }
+        if self.network.prefix() == 0 {
+            return Err(AllowRuleError::AllAddresses(self.network));
+        }
+        if self.network.ip() != self.network.network() {
+            return Err(AllowRuleError::HostBitsSet(self.network));
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for FirewallAllowRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.direction {
+            AllowedDirection::Outbound => write!(f, "to {}", self.network)?,
+            AllowedDirection::Inbound => write!(f, "from {}", self.network)?,
+        }
+        if let Some(protocol) = self.protocol {
+            write!(f, " using {}", protocol)?;
+        }
+        if let Some(port) = self.port {
+            match self.direction {
+                AllowedDirection::Outbound => write!(f, " port {}", port)?,
+                AllowedDirection::Inbound => write!(f, " to local port {}", port)?,
+            }
+        }
+        if self.outside_tunnel {
+            write!(f, ", routed outside the tunnel")?;
+        }
+        Ok(())
+    }
+}
+
+/// Destinations whose traffic is routed outside the tunnel and allowed by the firewall,
+/// regardless of which process sends it.
+#[derive(Debug, Default, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ExcludedDestinations {
+    /// Networks that are excluded from the tunnel.
+    #[serde(default)]
+    pub networks: Vec<ipnetwork::IpNetwork>,
+    /// Domain names whose addresses are excluded from the tunnel.
+    #[serde(default)]
+    pub domains: Vec<String>,
+}
+
+impl ExcludedDestinations {
+    /// Returns whether there are no excluded destinations.
+    pub fn is_empty(&self) -> bool {
+        self.networks.is_empty() && self.domains.is_empty()
+    }
+}
+
+/// The side that is allowed to initiate connections matching a [`FirewallAllowRule`]. Replies
+/// are always allowed.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AllowedDirection {
+    /// Connections from this host to the remote network.
+    Outbound,
+    /// Connections from the remote network to this host.
+    Inbound,
+}
+
+impl fmt::Display for AllowedDirection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AllowedDirection::Outbound => "outbound".fmt(f),
+            AllowedDirection::Inbound => "inbound".fmt(f),
+        }
+    }
+}
+
 /// IP protocol version.
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Deserialize, Serialize)]
 #[serde(rename_all = "snake_case")]
@@ -362,3 +488,54 @@ pub fn all_of_the_internet() -> Vec<ipnetwork::IpNetwork> {
         "::0/0".parse().expect("Failed to parse ipv6 network"),
     ]
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn allow_rule(network: &str) -> FirewallAllowRule {
+        FirewallAllowRule {
+            network: network.parse().unwrap(),
+            protocol: None,
+            port: None,
+            direction: AllowedDirection::Outbound,
+            outside_tunnel: false,
+        }
+    }
+
+    #[test]
+    fn test_allow_rule_validate() {
+        assert_eq!(allow_rule("10.0.0.0/24").validate(), Ok(()));
+        assert_eq!(allow_rule("2001:db8::/32").validate(), Ok(()));
+        assert_eq!(allow_rule("192.0.2.7/32").validate(), Ok(()));
+
+        let port_only = FirewallAllowRule {
+            port: Some(22),
+            ..allow_rule("10.0.0.0/24")
+        };
+        assert_eq!(
+            port_only.validate(),
+            Err(AllowRuleError::PortWithoutProtocol)
+        );
+    }
+
+    #[test]
+    fn test_allow_rule_host_bits() {
+        let rule = allow_rule("10.0.0.5/24");
+        assert_eq!(
+            rule.validate(),
+            Err(AllowRuleError::HostBitsSet(rule.network))
+        );
+    }
+
+    #[test]
+    fn test_allow_rule_all_addresses() {
+        for network in &["0.0.0.0/0", "::/0"] {
+            let rule = allow_rule(network);
+            assert_eq!(
+                rule.validate(),
+                Err(AllowRuleError::AllAddresses(rule.network))
+            );
+        }
+    }
+}
@@ -0,0 +1,159 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Identifies the networks that the host is connected to.
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct NetworkIdentity {
+    /// The connections that carry a default route. Empty while offline.
+    pub connections: Vec<NetworkConnection>,
+}
+
+impl NetworkIdentity {
+    /// Returns whether every connection with a default route is on a trusted network. This is
+    /// never the case while offline.
+    pub fn is_trusted(&self, trusted_networks: &[TrustedNetwork]) -> bool {
+        !self.connections.is_empty()
+            && self.connections.iter().all(|connection| {
+                trusted_networks
+                    .iter()
+                    .any(|network| network.matches(connection))
+            })
+    }
+}
+
+/// A connection to a network through an interface with a default route.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct NetworkConnection {
+    /// Name of the interface.
+    pub interface: String,
+    /// Hardware address of the default gateway, formatted by [`parse_mac_address`].
+    pub gateway_mac: Option<String>,
+    /// SSID of the wireless network, if the interface is connected to one.
+    pub ssid: Option<String>,
+}
+
+impl fmt::Display for NetworkConnection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "interface {}", self.interface)?;
+        if let Some(gateway_mac) = &self.gateway_mac {
+            write!(f, ", gateway MAC {}", gateway_mac)?;
+        }
+        if let Some(ssid) = &self.ssid {
+            write!(f, ", SSID \"{}\"", ssid)?;
+        }
+        Ok(())
+    }
+}
+
+/// A network on which the tunnel is disconnected automatically.
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TrustedNetwork {
+    /// Networks whose default gateway has this hardware address.
+    GatewayMac(String),
+    /// Wireless networks with this SSID.
+    Ssid(String),
+    /// Any network reached through the interface with this name.
+    Interface(String),
+}
+
+impl TrustedNetwork {
+    /// Returns a rule matching the gateway with the given hardware address, or `None` if it is
+    /// not a valid MAC address.
+    pub fn gateway_mac(address: &str) -> Option<Self> {
+        parse_mac_address(address).map(TrustedNetwork::GatewayMac)
+    }
+
+    /// Returns whether the connection is on this network.
+    pub fn matches(&self, connection: &NetworkConnection) -> bool {
+        match self {
+            TrustedNetwork::GatewayMac(address) => connection.gateway_mac.as_ref() == Some(address),
+            TrustedNetwork::Ssid(ssid) => connection.ssid.as_ref() == Some(ssid),
+            TrustedNetwork::Interface(interface) => &connection.interface == interface,
+        }
+    }
+}
+
+impl fmt::Display for TrustedNetwork {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrustedNetwork::GatewayMac(address) => write!(f, "gateway MAC {}", address),
+            TrustedNetwork::Ssid(ssid) => write!(f, "SSID \"{}\"", ssid),
+            TrustedNetwork::Interface(interface) => write!(f, "interface {}", interface),
+        }
+    }
+}
+
+/// Parses a MAC address with octets separated by colons or dashes, and formats it as lowercase
+/// octets separated by colons.
+pub fn parse_mac_address(address: &str) -> Option<String> {
+    let octets: Vec<&str> = address.trim().split(|c| c == ':' || c == '-').collect();
+    if octets.len() != 6
+        || !octets
+            .iter()
+            .all(|octet| octet.len() == 2 && octet.chars().all(|c| c.is_ascii_hexdigit()))
+    {
+        return None;
+    }
+    Some(octets.join(":").to_ascii_lowercase())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn connection(
+        interface: &str,
+        gateway_mac: Option<&str>,
+        ssid: Option<&str>,
+    ) -> NetworkConnection {
+        NetworkConnection {
+            interface: interface.to_owned(),
+            gateway_mac: gateway_mac.map(str::to_owned),
+            ssid: ssid.map(str::to_owned),
+        }
+    }
+
+    #[test]
+    fn test_parse_mac_address() {
+        assert_eq!(
+            parse_mac_address("AA-bb-CC-01-02-03").as_deref(),
+            Some("aa:bb:cc:01:02:03")
+        );
+        assert_eq!(
+            parse_mac_address("aa:bb:cc:01:02:03").as_deref(),
+            Some("aa:bb:cc:01:02:03")
+        );
+        assert_eq!(parse_mac_address("aa:bb:cc:01:02"), None);
+        assert_eq!(parse_mac_address("aa:bb:cc:01:02:0g"), None);
+        assert_eq!(parse_mac_address("aabbcc010203"), None);
+    }
+
+    #[test]
+    fn test_is_trusted() {
+        let office = vec![
+            TrustedNetwork::gateway_mac("aa:bb:cc:01:02:03").unwrap(),
+            TrustedNetwork::Ssid("Office".to_owned()),
+        ];
+
+        let wired = connection("eth0", Some("aa:bb:cc:01:02:03"), None);
+        let wireless = connection("wlan0", Some("aa:bb:cc:04:05:06"), Some("Office"));
+        let cafe = connection("wlan0", Some("aa:bb:cc:04:05:06"), Some("Cafe"));
+
+        let identity = NetworkIdentity {
+            connections: vec![wired.clone(), wireless],
+        };
+        assert!(identity.is_trusted(&office));
+
+        // Every connection must be on a trusted network
+        let identity = NetworkIdentity {
+            connections: vec![wired, cafe],
+        };
+        assert!(!identity.is_trusted(&office));
+
+        assert!(!NetworkIdentity::default().is_trusted(&office));
+        assert!(
+            !NetworkIdentity::default().is_trusted(&[TrustedNetwork::Interface("eth0".to_owned())])
+        );
+    }
+}